@@ -537,7 +537,13 @@ pub fn idle() -> bool {
             };
             let new_pid = SystemServices::with_mut(|ss| ss.create_process(init)).unwrap();
             println!(" {:^5} |  {}", new_pid, arg);
-            let process_args = xous_kernel::ProcessArgs::new("program", arg);
+            // Name the process after the program it runs, as the loader does with `PNam`
+            let name = std::path::Path::new(arg.split_whitespace().next().unwrap_or_default())
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            crate::arch::process::Process::set_name(new_pid, &name);
+            let process_args = xous_kernel::ProcessArgs::new(&name, arg);
             xous_kernel::arch::create_process_post(process_args, init, new_pid)
                 .expect("couldn't spawn");
         }
@@ -38,6 +38,9 @@ struct ProcessImpl {
 
     /// The currently-active thread for this process
     current_thread: TID,
+
+    /// The name of the program this process was started from
+    name: Option<String>,
 }
 
 impl PartialEq for Process {
@@ -349,6 +352,7 @@ impl Process {
                 memory_to_return: filled_array![None; 32 /* MAX_THREAD */],
                 current_thread: INITIAL_TID,
                 threads: [Thread { allocated: false }; MAX_THREAD + 1],
+                name: None,
             };
 
             process_table.total += 1;
@@ -363,6 +367,31 @@ impl Process {
         })
     }
 
+    /// Record the name of the program a process was started from. This stands
+    /// in for the `PNam` tag the loader provides when running natively.
+    pub fn set_name(pid: PID, name: &str) {
+        PROCESS_TABLE.with(|pt| {
+            let mut process_table = pt.borrow_mut();
+            let pid_idx = pid.get() as usize - 1;
+            if let Some(Some(process)) = process_table.table.get_mut(pid_idx) {
+                process.name = Some(name.to_owned());
+            }
+        })
+    }
+
+    /// Return the name of the program a process was started from, if any.
+    pub fn name(pid: PID) -> Option<String> {
+        PROCESS_TABLE.with(|pt| {
+            let process_table = pt.borrow();
+            let pid_idx = pid.get() as usize - 1;
+            process_table
+                .table
+                .get(pid_idx)
+                .and_then(|process| process.as_ref())
+                .and_then(|process| process.name.clone())
+        })
+    }
+
     pub fn destroy(pid: PID) -> Result<(), xous_kernel::Error> {
         PROCESS_TABLE.with(|pt| {
             let mut process_table = pt.borrow_mut();
@@ -2253,7 +2253,8 @@ impl SystemServices {
                 core::slice::from_raw_parts(ptr as *const u8, len * 4)
             };
             let mut offset = 0;
-            while offset <= arg.size {
+            // `arg.size` counts words, but `offset` counts bytes
+            while offset + 8 <= data.len() {
                 let check_pid = u32::from_le_bytes([
                     data[offset],
                     data[offset + 1],
@@ -2279,4 +2280,10 @@ impl SystemServices {
         }
         None
     }
+
+    /// Returns the process name, if any, of a given PID
+    #[cfg(not(baremetal))]
+    pub fn process_name(&self, pid: PID) -> Option<String> {
+        crate::arch::process::Process::name(pid)
+    }
 }
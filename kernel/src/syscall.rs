@@ -878,6 +878,28 @@ pub fn handle_inner(pid: PID, tid: TID, in_irq: bool, call: SysCall) -> SysCallR
         SysCall::DestroyServer(sid) => SystemServices::with_mut(|ss| {
             ss.destroy_server(pid, sid).and(Ok(xous_kernel::Result::Ok))
         }),
+        SysCall::GetProcessName(target, offset) => SystemServices::with(|ss| {
+            let name = ss
+                .process_name(target)
+                .ok_or(xous_kernel::Error::ProcessNotFound)?;
+            let name = name.as_bytes();
+            let mut words = [0usize; 6];
+            let rest = name.get(offset..).unwrap_or(&[]);
+            for (word, bytes) in words.iter_mut().zip(rest.chunks(mem::size_of::<usize>())) {
+                let mut le = [0u8; mem::size_of::<usize>()];
+                le[..bytes.len()].copy_from_slice(bytes);
+                *word = usize::from_le_bytes(le);
+            }
+            Ok(xous_kernel::Result::ProcessName(
+                name.len(),
+                words[0],
+                words[1],
+                words[2],
+                words[3],
+                words[4],
+                words[5],
+            ))
+        }),
         SysCall::JoinThread(other_tid) => {
             SystemServices::with_mut(|ss| ss.join_thread(pid, tid, other_tid)).map(|ret| {
                 unsafe { SWITCHTO_CALLER = None };
@@ -90,11 +90,24 @@ impl Default for SsidReturn {
         }
     }
 }
+/// Whether a joined network reaches the internet. The EC doesn't know this; it's filled in by the
+/// connection manager in the Net crate, which probes the network once DHCP has bound.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, num_derive::FromPrimitive, num_derive::ToPrimitive)]
+pub enum Connectivity {
+    /// not connected, or the probe hasn't finished yet
+    Unknown = 0,
+    Online = 1,
+    /// the network answers for the internet with a login page until the user signs in
+    CaptivePortal = 2,
+    /// the probe got no answer at all
+    Offline = 3,
+}
 #[derive(Debug, Copy, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
 pub struct WlanStatusIpc {
     pub ssid: Option<SsidRecord>,
     pub link_state: u16, // this is slung around as a u16 to avoid pulling rkyv into the EC dependency tree
     pub ipv4: [u16; com_rs_ref::ComState::WLAN_GET_IPV4_CONF.r_words as usize],
+    pub connectivity: u16,
 }
 impl WlanStatusIpc {
     #[allow(dead_code)]
@@ -103,6 +116,7 @@ impl WlanStatusIpc {
             ssid: status.ssid,
             link_state: status.link_state as u16,
             ipv4: status.ipv4.encode_u16(),
+            connectivity: status.connectivity as u16,
         }
     }
 }
@@ -112,6 +126,7 @@ impl Default for WlanStatusIpc {
             ssid: None,
             link_state: com_rs_ref::LinkState::Unknown as u16,
             ipv4: [0u16; com_rs_ref::ComState::WLAN_GET_IPV4_CONF.r_words as usize],
+            connectivity: Connectivity::Unknown as u16,
         }
     }
 }
@@ -120,6 +135,7 @@ pub struct WlanStatus {
     pub ssid: Option<SsidRecord>,
     pub link_state: com_rs_ref::LinkState, // converted back into LinkState once it's across the IPC boundary
     pub ipv4: Ipv4Conf,
+    pub connectivity: Connectivity,
 }
 impl WlanStatus {
     #[allow(dead_code)]
@@ -128,6 +144,8 @@ impl WlanStatus {
             ssid: status.ssid,
             link_state: com_rs_ref::LinkState::decode_u16(status.link_state),
             ipv4: com_rs_ref::serdes::Ipv4Conf::decode_u16(&status.ipv4),
+            connectivity: num_traits::FromPrimitive::from_u16(status.connectivity)
+                .unwrap_or(Connectivity::Unknown),
         }
     }
 }
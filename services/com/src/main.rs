@@ -950,6 +950,7 @@ fn xmain() -> ! {
                         },
                         link_state,
                         ipv4: ipv4_raw,
+                        connectivity: Connectivity::Unknown as u16,
                     };
                     buffer.replace(status).unwrap();
                 }
@@ -0,0 +1,327 @@
+//! Reads the records out of the answer section of a reply, and follows the CNAME chains in it.
+
+use crate::api::*;
+use num_traits::FromPrimitive;
+use std::convert::TryInto;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Longer CNAME chains than this are taken to be loops
+pub(crate) const MAX_CNAME_HOPS: usize = 8;
+/// A name can't take more pointers than this to read, unless the pointers go round in circles
+const MAX_NAME_POINTERS: usize = 16;
+const NAME_MAX_LEN: usize = 255;
+const CLASS_IN: u16 = 1;
+
+/// A record from the answer section
+pub(crate) struct Answer {
+    /// the name the record belongs to
+    pub(crate) name: String,
+    pub(crate) ttl: u32,
+    pub(crate) record: DnsRecord,
+}
+
+fn u16_at(msg: &[u8], index: usize) -> Result<u16, DnsResponseCode> {
+    msg.get(index..index + 2)
+        .and_then(|b| b.try_into().ok())
+        .map(u16::from_be_bytes)
+        .ok_or(DnsResponseCode::FormatError)
+}
+
+fn u32_at(msg: &[u8], index: usize) -> Result<u32, DnsResponseCode> {
+    msg.get(index..index + 4)
+        .and_then(|b| b.try_into().ok())
+        .map(u32::from_be_bytes)
+        .ok_or(DnsResponseCode::FormatError)
+}
+
+/// Reads the name at `index`, following the pointers in it. Returns the name, and the index just past it.
+fn read_name(msg: &[u8], mut index: usize) -> Result<(String, usize), DnsResponseCode> {
+    use DnsResponseCode::FormatError;
+    let mut name = String::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *msg.get(index).ok_or(FormatError)? as usize;
+        if len & 0xc0 == 0xc0 {
+            pointers += 1;
+            if pointers > MAX_NAME_POINTERS {
+                return Err(FormatError);
+            }
+            // the name carries on past the first pointer only
+            end.get_or_insert(index + 2);
+            index = (len & 0x3f) << 8 | *msg.get(index + 1).ok_or(FormatError)? as usize;
+        } else if len & 0xc0 != 0 {
+            // the other label types were never taken up
+            return Err(FormatError);
+        } else if len == 0 {
+            return Ok((name, end.unwrap_or(index + 1)));
+        } else {
+            let label = msg.get(index + 1..index + 1 + len).ok_or(FormatError)?;
+            if !name.is_empty() {
+                name.push('.');
+            }
+            name.push_str(&String::from_utf8_lossy(label));
+            if name.len() > NAME_MAX_LEN {
+                return Err(FormatError);
+            }
+            index += 1 + len;
+        }
+    }
+}
+
+/// The character-strings of a TXT record, joined together
+fn read_text(mut rdata: &[u8]) -> Result<Vec<u8>, DnsResponseCode> {
+    let mut text = Vec::new();
+    while let Some((&len, rest)) = rdata.split_first() {
+        let len = len as usize;
+        text.extend_from_slice(rest.get(..len).ok_or(DnsResponseCode::FormatError)?);
+        rdata = &rest[len..];
+    }
+    Ok(text)
+}
+
+/// Reads the answer section of `msg`. Records of the types we don't know about are skipped.
+pub(crate) fn parse_answers(msg: &[u8]) -> Result<Vec<Answer>, DnsResponseCode> {
+    let qdcount = u16_at(msg, 4)?;
+    let ancount = u16_at(msg, 6)?;
+    let mut index = 12;
+    for _ in 0..qdcount {
+        // the name, then the type and class
+        index = read_name(msg, index)?.1 + 4;
+    }
+    let mut answers = Vec::new();
+    for _ in 0..ancount {
+        let (name, next) = read_name(msg, index)?;
+        let rtype = u16_at(msg, next)?;
+        let class = u16_at(msg, next + 2)?;
+        let ttl = u32_at(msg, next + 4)?;
+        let rdlength = u16_at(msg, next + 8)? as usize;
+        let start = next + 10;
+        let rdata = msg
+            .get(start..start + rdlength)
+            .ok_or(DnsResponseCode::FormatError)?;
+        index = start + rdlength;
+        if class != CLASS_IN {
+            continue;
+        }
+        let record = match FromPrimitive::from_u16(rtype) {
+            Some(RecordType::A) if rdlength == 4 => {
+                DnsRecord::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))
+            }
+            Some(RecordType::Aaaa) if rdlength == 16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                DnsRecord::Aaaa(Ipv6Addr::from(octets))
+            }
+            // names in the record data can point back into the rest of the message
+            Some(RecordType::Cname) => DnsRecord::Cname(read_name(msg, start)?.0),
+            Some(RecordType::Txt) => DnsRecord::Txt(read_text(rdata)?),
+            Some(RecordType::Srv) if rdlength > 6 => DnsRecord::Srv {
+                priority: u16_at(rdata, 0)?,
+                weight: u16_at(rdata, 2)?,
+                port: u16_at(rdata, 4)?,
+                target: read_name(msg, start + 6)?.0,
+            },
+            Some(rtype) => {
+                log::error!("malformed {:?} record for {}", rtype, name);
+                return Err(DnsResponseCode::FormatError);
+            }
+            None => {
+                log::trace!("skipping a record of type {} for {}", rtype, name);
+                continue;
+            }
+        };
+        answers.push(Answer { name, ttl, record });
+    }
+    Ok(answers)
+}
+
+/// Names are compared without regard to case, or to the root label
+pub(crate) fn same_name(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+/// Where the CNAMEs for a name lead in a reply
+pub(crate) struct Chain {
+    /// the name at the end of the chain; the name itself, if it isn't an alias
+    pub(crate) target: String,
+    /// the least TTL of the CNAMEs along the chain, as none of it holds once one of them expires
+    pub(crate) ttl: u32,
+    /// the records of the type asked for that belong to `target`, with the least of their own TTL and
+    /// the TTL of the chain
+    pub(crate) records: Vec<(DnsRecord, u32)>,
+}
+
+/// Follows the CNAMEs in `answers` from `name`, and picks out the records of type `rtype` at the end
+/// of the chain. When it's the CNAMEs themselves that are wanted, nothing is followed.
+pub(crate) fn follow_chain(
+    name: &str,
+    rtype: RecordType,
+    answers: &[Answer],
+) -> Result<Chain, DnsResponseCode> {
+    let mut target = name;
+    let mut ttl = u32::MAX;
+    if rtype != RecordType::Cname {
+        let mut hops = 0;
+        while let Some(answer) = answers
+            .iter()
+            .find(|a| a.record.record_type() == RecordType::Cname && same_name(&a.name, target))
+        {
+            hops += 1;
+            if hops > MAX_CNAME_HOPS {
+                log::warn!("CNAME loop at {}", name);
+                return Err(DnsResponseCode::ServerFailure);
+            }
+            if let DnsRecord::Cname(next) = &answer.record {
+                target = next;
+            }
+            ttl = ttl.min(answer.ttl);
+        }
+    }
+    let records = answers
+        .iter()
+        .filter(|a| a.record.record_type() == rtype && same_name(&a.name, target))
+        .map(|a| (a.record.clone(), a.ttl.min(ttl)))
+        .collect();
+    Ok(Chain {
+        target: String::from(target),
+        ttl,
+        records,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A reply to a query for `qname`, with the given answers, each a name, a type, a TTL and the
+    /// record data. Names that start with `@` are pointers to the offset that follows.
+    fn reply(qname: &str, qtype: u16, answers: &[(&str, u16, u32, Vec<u8>)]) -> Vec<u8> {
+        let mut msg = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 0, 0, 0, 0, 0];
+        msg[7] = answers.len() as u8;
+        msg.extend_from_slice(&name(qname));
+        msg.extend_from_slice(&qtype.to_be_bytes());
+        msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+        for (owner, rtype, ttl, rdata) in answers {
+            msg.extend_from_slice(&name(owner));
+            msg.extend_from_slice(&rtype.to_be_bytes());
+            msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+            msg.extend_from_slice(&ttl.to_be_bytes());
+            msg.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            msg.extend_from_slice(rdata);
+        }
+        msg
+    }
+
+    fn name(name: &str) -> Vec<u8> {
+        if let Some(offset) = name.strip_prefix('@') {
+            return vec![0xc0, offset.parse().unwrap()];
+        }
+        let mut wire = Vec::new();
+        for label in name.split('.') {
+            wire.push(label.len() as u8);
+            wire.extend_from_slice(label.as_bytes());
+        }
+        wire.push(0);
+        wire
+    }
+
+    #[test]
+    fn cname_chains() {
+        // www.example.com -> example.net -> cdn.example.org, with the target's name given in full
+        // once, and pointed at after that
+        let msg = reply(
+            "www.example.com",
+            1,
+            &[
+                ("@12", 5, 3600, name("example.net")),
+                ("@45", 5, 60, name("cdn.example.org")),
+                ("CDN.example.org", 1, 300, vec![93, 184, 216, 34]),
+                ("@70", 1, 30, vec![93, 184, 216, 35]),
+                (
+                    "@70",
+                    28,
+                    300,
+                    vec![0x20, 1, 0xd, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+                ),
+            ],
+        );
+        let answers = parse_answers(&msg).unwrap();
+        assert_eq!(answers.len(), 5);
+        assert_eq!(answers[1].name, "example.net");
+
+        let chain = follow_chain("www.example.com", RecordType::A, &answers).unwrap();
+        assert!(same_name(&chain.target, "cdn.example.org"));
+        assert_eq!(chain.ttl, 60);
+        assert_eq!(
+            chain.records,
+            vec![
+                (DnsRecord::A(Ipv4Addr::new(93, 184, 216, 34)), 60),
+                (DnsRecord::A(Ipv4Addr::new(93, 184, 216, 35)), 30),
+            ]
+        );
+
+        // asking for the CNAME doesn't follow it
+        let chain = follow_chain("www.example.com.", RecordType::Cname, &answers).unwrap();
+        assert_eq!(chain.target, "www.example.com.");
+        assert_eq!(
+            chain.records,
+            vec![(DnsRecord::Cname(String::from("example.net")), 3600)]
+        );
+
+        // a chain that the server didn't follow all the way leaves nothing but where it ended up
+        let chain = follow_chain("www.example.com", RecordType::A, &answers[..2]).unwrap();
+        assert_eq!(chain.target, "cdn.example.org");
+        assert!(chain.records.is_empty());
+    }
+
+    #[test]
+    fn cname_loops() {
+        let msg = reply(
+            "a.example",
+            1,
+            &[
+                ("@12", 5, 60, name("b.example")),
+                ("@39", 5, 60, name("a.example")),
+            ],
+        );
+        let answers = parse_answers(&msg).unwrap();
+        assert!(follow_chain("a.example", RecordType::A, &answers).is_err());
+    }
+
+    #[test]
+    fn txt_and_srv() {
+        let mut srv = vec![0, 10, 0, 5, 0x14, 0x66];
+        srv.extend_from_slice(&name("xmpp.example.com"));
+        let msg = reply(
+            "_xmpp-client._tcp.example.com",
+            33,
+            &[
+                ("@12", 33, 86400, srv),
+                ("@12", 16, 300, b"\x05v=spf\x011".to_vec()),
+                // a type that isn't parsed
+                ("@12", 99, 300, vec![1, 2, 3]),
+            ],
+        );
+        let answers = parse_answers(&msg).unwrap();
+        assert_eq!(answers.len(), 2);
+        assert_eq!(
+            answers[0].record,
+            DnsRecord::Srv {
+                priority: 10,
+                weight: 5,
+                port: 5222,
+                target: String::from("xmpp.example.com"),
+            }
+        );
+        assert_eq!(answers[1].record, DnsRecord::Txt(b"v=spf1".to_vec()));
+
+        // a truncated reply, and a pointer to itself
+        assert!(parse_answers(&msg[..msg.len() - 4]).is_err());
+        let mut looped = reply("x", 1, &[]);
+        looped.extend_from_slice(&[0xc0, 19, 0, 1, 0, 1, 0, 0, 0, 1, 0, 4, 1, 2, 3, 4]);
+        looped[7] = 1;
+        assert!(parse_answers(&looped).is_err());
+    }
+}
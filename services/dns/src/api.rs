@@ -2,6 +2,12 @@
 pub(crate) const SERVER_NAME_DNS: &str = "_DNS Resolver Middleware_";
 use net::NetIpAddr;
 use rkyv::{Archive, Deserialize, Serialize};
+use xous_ipc::String;
+
+mod records;
+pub use records::*;
+mod upstream;
+pub use upstream::*;
 
 #[allow(dead_code)]
 pub(crate) const DNS_NAME_LENGTH_LIMIT: usize = 256;
@@ -15,7 +21,7 @@ pub(crate) enum Opcode {
     Lookup = 0,
     Flush = 1,
 
-    /// used internally to update the TTL field, and eventually expire the cache (unless cache is frozen)
+    /// used internally to drop expired entries from the cache (unless cache is frozen)
     UpdateTtl = 2,
 
     /// issuing this opcode causes all future attempts to change the DNS server configs to be ignored. This also freezes the cache.
@@ -31,6 +37,9 @@ pub(crate) enum Opcode {
     /// The query should be a `MutableBorrow` and should be a `&str` with the
     /// `valid` parameter set to the length of the query.
     ///
+    /// The `offset` parameter selects the `RecordType` to look up. If it is `0`,
+    /// the A and AAAA records of the name are looked up, and cached.
+    ///
     /// The result will be a `&[u8]` with the first field being `0` and the second
     /// field indicating the number of results.
     ///
@@ -47,7 +56,26 @@ pub(crate) enum Opcode {
     ///
     ///     * 4: Ipv4 Address -- 4 octets follow, for a total of 5 bytes
     ///     * 6: Ipv6 Address -- 16 octets follow, for a total of 17 bytes
+    ///     * 5: CNAME -- a length octet follows, then the name
+    ///     * 16: TXT -- a big-endian u16 length follows, then the text
+    ///     * 33: SRV -- the priority, weight and port follow as big-endian u16s,
+    ///       then a length octet and the target name
+    ///
+    /// CNAMEs are followed to the records they lead to, unless CNAMEs are what was asked for.
     RawLookup = 6,
+
+    /// Describes what the cache holds for a name, in a `DnsCacheDump`
+    CacheDump = 7,
+
+    /// Drops a name from the cache. Takes a `DnsCacheDump`, whose `cached` field is set to whether the
+    /// name was in the cache.
+    FlushName = 8,
+
+    /// Sets where queries are sent, with an `UpstreamConfig`. The settings are kept in the PDDB.
+    SetUpstream = 9,
+
+    /// Returns where queries are sent, in an `UpstreamConfig`
+    GetUpstream = 10,
 }
 
 #[derive(
@@ -59,6 +87,8 @@ pub(crate) enum Opcode {
     Deserialize,
     Copy,
     Clone,
+    PartialEq,
+    Eq,
 )]
 #[repr(u16)]
 pub enum DnsResponseCode {
@@ -79,3 +109,48 @@ pub struct DnsResponse {
     pub addr: Option<NetIpAddr>,
     pub code: DnsResponseCode,
 }
+
+/// How many of the addresses of a name fit in a `DnsCacheDump`
+pub(crate) const CACHE_DUMP_MAX_ADDRS: usize = 16;
+
+#[derive(Debug, Archive, Serialize, Deserialize, Copy, Clone)]
+pub(crate) struct DnsCacheAddr {
+    pub addr: NetIpAddr,
+    /// seconds left before the address expires
+    pub ttl: u32,
+}
+
+#[derive(Debug, Archive, Serialize, Deserialize, Copy, Clone)]
+pub(crate) struct DnsCacheDump {
+    pub name: String<DNS_NAME_LENGTH_LIMIT>,
+    /// whether the name is in the cache at all
+    pub cached: bool,
+    /// `NoError` if the addresses of the name are cached; otherwise, the failed lookup that's cached in their place
+    pub code: DnsResponseCode,
+    pub addrs: [Option<DnsCacheAddr>; CACHE_DUMP_MAX_ADDRS],
+    /// seconds left before a failed lookup expires
+    pub ttl: u32,
+}
+impl DnsCacheDump {
+    #[allow(dead_code)]
+    pub fn new(name: &str) -> DnsCacheDump {
+        DnsCacheDump {
+            name: String::from_str(name),
+            cached: false,
+            code: DnsResponseCode::NoError,
+            addrs: [None; CACHE_DUMP_MAX_ADDRS],
+            ttl: 0,
+        }
+    }
+}
+
+/// A name in the DNS cache, as reported by `Dns::cache_dump()`
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub enum CachedName {
+    /// The addresses of the name, with the seconds each has left in the cache
+    Addresses(Vec<(std::net::IpAddr, u32)>),
+    /// The lookup failed with `code`, and won't be tried again for `ttl` seconds. `NoError` means the name
+    /// exists, but has no addresses.
+    Negative { code: DnsResponseCode, ttl: u32 },
+}
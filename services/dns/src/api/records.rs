@@ -0,0 +1,184 @@
+use std::convert::TryInto;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// The types of record that can be looked up with `Dns::lookup_records()`
+#[derive(Debug, num_derive::FromPrimitive, num_derive::ToPrimitive, Copy, Clone, PartialEq, Eq)]
+#[repr(u16)]
+pub enum RecordType {
+    A = 1,
+    Cname = 5,
+    Txt = 16,
+    Aaaa = 28,
+    Srv = 33,
+}
+
+/// A record from a DNS reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsRecord {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    /// The name that this one is an alias for
+    Cname(std::string::String),
+    /// The text of the record. Its character-strings are joined together, as SPF and DKIM records
+    /// split long strings up that way.
+    Txt(Vec<u8>),
+    /// Where a service is to be found, per RFC 2782
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: std::string::String,
+    },
+}
+
+// The tags of the entries in a `RawLookup` reply. The tags of the addresses predate the others, which use
+// the number of their record type instead.
+const TAG_IPV4: u8 = 4;
+const TAG_IPV6: u8 = 6;
+const TAG_CNAME: u8 = 5;
+const TAG_TXT: u8 = 16;
+const TAG_SRV: u8 = 33;
+
+impl DnsRecord {
+    pub fn record_type(&self) -> RecordType {
+        match self {
+            DnsRecord::A(_) => RecordType::A,
+            DnsRecord::Aaaa(_) => RecordType::Aaaa,
+            DnsRecord::Cname(_) => RecordType::Cname,
+            DnsRecord::Txt(_) => RecordType::Txt,
+            DnsRecord::Srv { .. } => RecordType::Srv,
+        }
+    }
+    /// Appends the record to `out`, as an entry of a `RawLookup` reply
+    #[allow(dead_code)]
+    pub(crate) fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            DnsRecord::A(addr) => {
+                out.push(TAG_IPV4);
+                out.extend_from_slice(&addr.octets());
+            }
+            DnsRecord::Aaaa(addr) => {
+                out.push(TAG_IPV6);
+                out.extend_from_slice(&addr.octets());
+            }
+            DnsRecord::Cname(name) => {
+                out.push(TAG_CNAME);
+                encode_name(name, out);
+            }
+            DnsRecord::Txt(text) => {
+                // the text came out of a single record, so it can't be longer than a record can be
+                out.push(TAG_TXT);
+                out.extend_from_slice(&(text.len() as u16).to_be_bytes());
+                out.extend_from_slice(text);
+            }
+            DnsRecord::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                out.push(TAG_SRV);
+                out.extend_from_slice(&priority.to_be_bytes());
+                out.extend_from_slice(&weight.to_be_bytes());
+                out.extend_from_slice(&port.to_be_bytes());
+                encode_name(target, out);
+            }
+        }
+    }
+    /// Reads an entry of a `RawLookup` reply, returning the record along with the length of the entry
+    #[allow(dead_code)]
+    pub(crate) fn decode(entry: &[u8]) -> Option<(DnsRecord, usize)> {
+        let (&tag, body) = entry.split_first()?;
+        match tag {
+            TAG_IPV4 => {
+                let octets: [u8; 4] = body.get(..4)?.try_into().ok()?;
+                Some((DnsRecord::A(Ipv4Addr::from(octets)), 5))
+            }
+            TAG_IPV6 => {
+                let octets: [u8; 16] = body.get(..16)?.try_into().ok()?;
+                Some((DnsRecord::Aaaa(Ipv6Addr::from(octets)), 17))
+            }
+            TAG_CNAME => {
+                let (name, len) = decode_name(body)?;
+                Some((DnsRecord::Cname(name), 1 + len))
+            }
+            TAG_TXT => {
+                let len = u16::from_be_bytes(body.get(..2)?.try_into().ok()?) as usize;
+                let text = body.get(2..2 + len)?;
+                Some((DnsRecord::Txt(text.to_vec()), 3 + len))
+            }
+            TAG_SRV => {
+                let field = |i: usize| -> Option<u16> {
+                    Some(u16::from_be_bytes(body.get(i..i + 2)?.try_into().ok()?))
+                };
+                let (target, len) = decode_name(body.get(6..)?)?;
+                Some((
+                    DnsRecord::Srv {
+                        priority: field(0)?,
+                        weight: field(2)?,
+                        port: field(4)?,
+                        target,
+                    },
+                    7 + len,
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Names are written out with their length in front. A name can't be longer than 255 bytes.
+fn encode_name(name: &str, out: &mut Vec<u8>) {
+    let name = &name.as_bytes()[..name.len().min(255)];
+    out.push(name.len() as u8);
+    out.extend_from_slice(name);
+}
+
+fn decode_name(entry: &[u8]) -> Option<(std::string::String, usize)> {
+    let (&len, body) = entry.split_first()?;
+    let name = std::str::from_utf8(body.get(..len as usize)?).ok()?;
+    Some((std::string::String::from(name), 1 + len as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_entries_round_trip() {
+        let records = vec![
+            DnsRecord::A(Ipv4Addr::new(185, 199, 111, 153)),
+            DnsRecord::Aaaa(Ipv6Addr::new(0x2606, 0x50c0, 0x8000, 0, 0, 0, 0, 0x153)),
+            DnsRecord::Cname(std::string::String::from("betrusted.github.io")),
+            DnsRecord::Txt(b"v=spf1 include:_spf.example.com ~all".to_vec()),
+            DnsRecord::Srv {
+                priority: 5,
+                weight: 10,
+                port: 5222,
+                target: std::string::String::from("xmpp.example.com"),
+            },
+        ];
+        let mut raw = Vec::new();
+        for record in records.iter() {
+            record.encode(&mut raw);
+        }
+        // the addresses are laid out the way they always have been
+        assert_eq!(&raw[..5], &[4, 185, 199, 111, 153]);
+        assert_eq!(raw[5], 6);
+
+        let mut decoded = Vec::new();
+        let mut rest = &raw[..];
+        while !rest.is_empty() {
+            let (record, len) = DnsRecord::decode(rest).unwrap();
+            decoded.push(record);
+            rest = &rest[len..];
+        }
+        assert_eq!(decoded, records);
+
+        // a truncated entry, and an entry of some unknown kind
+        let mut srv = Vec::new();
+        records[4].encode(&mut srv);
+        assert!(DnsRecord::decode(&srv[..srv.len() - 1]).is_none());
+        assert!(DnsRecord::decode(&[99, 0, 0]).is_none());
+    }
+}
@@ -0,0 +1,155 @@
+use rkyv::{Archive, Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+
+/// PDDB dictionary holding the upstream settings, each stored as text
+pub const DNS_DICT: &str = "net.dns";
+/// How queries are sent; one of the names of `UpstreamMode`
+pub const DNS_KEY_UPSTREAM: &str = "upstream";
+/// The DNS-over-TLS servers, one per line: an address, with an optional port, then the name that the
+/// server's certificate is checked against. For example, `1.1.1.1 cloudflare-dns.com`.
+pub const DNS_KEY_TLS_SERVERS: &str = "tls_servers";
+
+/// The port of DNS-over-TLS (RFC 7858)
+pub const DOT_PORT: u16 = 853;
+/// The most DNS-over-TLS servers that can be configured
+pub const MAX_TLS_SERVERS: usize = 4;
+/// Room for `MAX_TLS_SERVERS` lines of the `DNS_KEY_TLS_SERVERS` setting
+pub(crate) const TLS_SERVERS_TEXT_LEN: usize = 512;
+
+/// Where the DNS server sends its queries
+#[derive(
+    Debug,
+    num_derive::FromPrimitive,
+    num_derive::ToPrimitive,
+    Archive,
+    Serialize,
+    Deserialize,
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+)]
+pub enum UpstreamMode {
+    /// Plaintext UDP, to the servers handed out by DHCP. This is the default.
+    Udp = 0,
+    /// DNS-over-TLS, to the configured servers only. Lookups fail when none of them can be reached,
+    /// which includes before the RTC has been set, unless the servers are pinned.
+    Tls = 1,
+    /// DNS-over-TLS, falling back to plaintext UDP while none of the TLS servers can be reached
+    TlsWithFallback = 2,
+}
+impl UpstreamMode {
+    /// The name of the mode, as it's stored in the PDDB
+    pub fn name(&self) -> &'static str {
+        match self {
+            UpstreamMode::Udp => "udp",
+            UpstreamMode::Tls => "tls",
+            UpstreamMode::TlsWithFallback => "tls-fallback",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<UpstreamMode> {
+        match name {
+            "udp" => Some(UpstreamMode::Udp),
+            "tls" => Some(UpstreamMode::Tls),
+            "tls-fallback" => Some(UpstreamMode::TlsWithFallback),
+            _ => None,
+        }
+    }
+}
+
+/// A DNS-over-TLS server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsServer {
+    pub addr: SocketAddr,
+    /// The name that the server's certificate is checked against
+    pub name: String,
+}
+impl TlsServer {
+    /// Parses a line of the `DNS_KEY_TLS_SERVERS` setting
+    pub fn parse(line: &str) -> Option<TlsServer> {
+        let mut fields = line.split_whitespace();
+        let addr = fields.next()?;
+        let name = fields.next()?;
+        if fields.next().is_some() {
+            return None;
+        }
+        let addr = addr
+            .parse::<SocketAddr>()
+            .or_else(|_| {
+                addr.parse::<IpAddr>()
+                    .map(|ip| SocketAddr::new(ip, DOT_PORT))
+            })
+            .ok()?;
+        Some(TlsServer {
+            addr,
+            name: String::from(name),
+        })
+    }
+    /// Parses the whole of the `DNS_KEY_TLS_SERVERS` setting. Lines that can't be parsed are skipped.
+    pub fn parse_list(text: &str) -> Vec<TlsServer> {
+        text.lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| {
+                let server = TlsServer::parse(line);
+                if server.is_none() {
+                    log::warn!("ignoring invalid DNS-over-TLS server: {}", line);
+                }
+                server
+            })
+            .take(MAX_TLS_SERVERS)
+            .collect()
+    }
+    /// The inverse of `parse_list()`
+    pub fn format_list(servers: &[TlsServer]) -> String {
+        servers
+            .iter()
+            .map(|server| format!("{} {}\n", server.addr, server.name))
+            .collect()
+    }
+}
+
+#[derive(Debug, Archive, Serialize, Deserialize, Copy, Clone)]
+pub(crate) struct UpstreamConfig {
+    pub mode: UpstreamMode,
+    /// The DNS-over-TLS servers, in the format of the `DNS_KEY_TLS_SERVERS` setting
+    pub servers: xous_ipc::String<TLS_SERVERS_TEXT_LEN>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_lists() {
+        let servers = TlsServer::parse_list(
+            "1.1.1.1 cloudflare-dns.com\n\
+             \n\
+             9.9.9.9:8853 dns.quad9.net\n\
+             [2606:4700:4700::1111]:853 one.one.one.one\n\
+             2620:fe::fe dns.quad9.net\n\
+             not-an-address example.com\n\
+             8.8.8.8\n",
+        );
+        assert_eq!(servers.len(), 4);
+        assert_eq!(servers[0].addr, "1.1.1.1:853".parse().unwrap());
+        assert_eq!(servers[0].name, "cloudflare-dns.com");
+        assert_eq!(servers[1].addr.port(), 8853);
+        assert!(servers[2].addr.is_ipv6());
+        assert_eq!(servers[3].addr, "[2620:fe::fe]:853".parse().unwrap());
+        assert_eq!(
+            TlsServer::parse_list(&TlsServer::format_list(&servers)),
+            servers
+        );
+
+        for mode in [
+            UpstreamMode::Udp,
+            UpstreamMode::Tls,
+            UpstreamMode::TlsWithFallback,
+        ]
+        .iter()
+        {
+            assert_eq!(UpstreamMode::from_name(mode.name()), Some(*mode));
+        }
+        assert_eq!(UpstreamMode::from_name("doh"), None);
+    }
+}
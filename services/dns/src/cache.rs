@@ -0,0 +1,280 @@
+//! The cache of lookups. Each address expires when its own TTL runs out, names that don't exist are
+//! remembered for as long as their zone says they may be (RFC 2308), and once the cache is full, the name
+//! that was used least recently makes way for a new one.
+
+use crate::api::*;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// The most names the cache holds
+pub(crate) const CACHE_MAX_NAMES: usize = 128;
+/// Nothing is cached for longer than this, whatever its TTL says
+const MAX_TTL_SECS: u32 = 86_400;
+/// RFC 2308 recommends caching negative answers for no more than a few hours
+const MAX_NEGATIVE_TTL_SECS: u32 = 10_800;
+
+/// What the cache knows about a name
+#[derive(Debug, Clone)]
+pub(crate) enum Cached {
+    /// The addresses of the name, with the seconds each has left to live
+    Addresses(HashMap<IpAddr, u32>),
+    /// The lookup came back with `code` (`NameError` if the name doesn't exist, `NoError` if it has no
+    /// addresses), and shouldn't be repeated for `ttl` more seconds
+    Negative { code: DnsResponseCode, ttl: u32 },
+}
+
+enum Record {
+    /// each address, with the ticktimer time it expires
+    Addresses(HashMap<IpAddr, u64>),
+    Negative {
+        code: DnsResponseCode,
+        expires: u64,
+    },
+}
+
+struct Entry {
+    record: Record,
+    /// the value of `DnsCache::uses` when the name was last looked up
+    last_used: u64,
+}
+
+pub(crate) struct DnsCache {
+    entries: HashMap<String, Entry>,
+    capacity: usize,
+    /// counts lookups, to find the least recently used name
+    uses: u64,
+    /// while frozen, nothing expires
+    frozen: bool,
+}
+
+fn remaining_secs(expires: u64, now: u64) -> u32 {
+    (expires.saturating_sub(now) / 1000) as u32
+}
+
+impl DnsCache {
+    pub(crate) fn new(capacity: usize) -> DnsCache {
+        DnsCache {
+            entries: HashMap::new(),
+            capacity,
+            uses: 0,
+            frozen: false,
+        }
+    }
+    pub(crate) fn set_frozen(&mut self, frozen: bool) {
+        self.frozen = frozen;
+    }
+    /// Looks up `name` as of `now` (in ms, by the ticktimer), dropping whatever has expired
+    pub(crate) fn get(&mut self, name: &str, now: u64) -> Option<Cached> {
+        if !self.frozen {
+            self.expire_name(name, now);
+        }
+        self.uses += 1;
+        let entry = self.entries.get_mut(name)?;
+        entry.last_used = self.uses;
+        Some(DnsCache::describe(&entry.record, now))
+    }
+    /// Like `get`, but doesn't count as a use of the name, or drop anything that has expired
+    pub(crate) fn peek(&self, name: &str, now: u64) -> Option<Cached> {
+        self.entries
+            .get(name)
+            .map(|entry| DnsCache::describe(&entry.record, now))
+    }
+    fn describe(record: &Record, now: u64) -> Cached {
+        match record {
+            Record::Addresses(addrs) => Cached::Addresses(
+                addrs
+                    .iter()
+                    .map(|(addr, &expires)| (*addr, remaining_secs(expires, now)))
+                    .collect(),
+            ),
+            Record::Negative { code, expires } => Cached::Negative {
+                code: *code,
+                ttl: remaining_secs(*expires, now),
+            },
+        }
+    }
+    /// Caches the addresses of `name`, each for its TTL in seconds. Addresses with a TTL of 0 aren't cached.
+    pub(crate) fn insert_addresses(&mut self, name: &str, addrs: &HashMap<IpAddr, u32>, now: u64) {
+        let addrs: HashMap<IpAddr, u64> = addrs
+            .iter()
+            .filter(|(_, &ttl)| ttl > 0)
+            .map(|(addr, &ttl)| (*addr, now + ttl.min(MAX_TTL_SECS) as u64 * 1000))
+            .collect();
+        if !addrs.is_empty() {
+            self.insert(name, Record::Addresses(addrs), now);
+        }
+    }
+    /// Caches a negative answer for `name`, for `ttl` seconds
+    pub(crate) fn insert_negative(
+        &mut self,
+        name: &str,
+        code: DnsResponseCode,
+        ttl: u32,
+        now: u64,
+    ) {
+        if ttl > 0 {
+            let expires = now + ttl.min(MAX_NEGATIVE_TTL_SECS) as u64 * 1000;
+            self.insert(name, Record::Negative { code, expires }, now);
+        }
+    }
+    fn insert(&mut self, name: &str, record: Record, now: u64) {
+        if !self.entries.contains_key(name) && self.entries.len() >= self.capacity {
+            if !self.frozen {
+                self.expire(now);
+            }
+            if self.entries.len() >= self.capacity {
+                let lru = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(name, _)| name.to_string());
+                if let Some(lru) = lru {
+                    log::debug!("DNS cache full, dropping {}", lru);
+                    self.entries.remove(&lru);
+                }
+            }
+        }
+        self.uses += 1;
+        let last_used = self.uses;
+        self.entries
+            .insert(name.to_string(), Entry { record, last_used });
+    }
+    /// Drops the records of `name` that have expired, and the name too if nothing is left of it
+    fn expire_name(&mut self, name: &str, now: u64) {
+        let gone = match self.entries.get_mut(name) {
+            Some(Entry {
+                record: Record::Addresses(addrs),
+                ..
+            }) => {
+                addrs.retain(|_, &mut expires| expires > now);
+                addrs.is_empty()
+            }
+            Some(Entry {
+                record: Record::Negative { expires, .. },
+                ..
+            }) => *expires <= now,
+            None => false,
+        };
+        if gone {
+            log::debug!("DNS cache expiring {}", name);
+            self.entries.remove(name);
+        }
+    }
+    /// Drops everything that has expired, unless the cache is frozen
+    pub(crate) fn expire(&mut self, now: u64) {
+        if self.frozen {
+            return;
+        }
+        let names: Vec<String> = self.entries.keys().cloned().collect();
+        for name in names {
+            self.expire_name(&name, now);
+        }
+    }
+    /// Drops `name` from the cache, returning whether it was there
+    pub(crate) fn remove(&mut self, name: &str) -> bool {
+        self.entries.remove(name).is_some()
+    }
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn v4(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, last))
+    }
+
+    #[test]
+    fn records_expire_on_their_own_ttl() {
+        let mut cache = DnsCache::new(CACHE_MAX_NAMES);
+        let mut addrs = HashMap::new();
+        addrs.insert(v4(1), 60);
+        addrs.insert(v4(2), 300);
+        addrs.insert(v4(3), 0);
+        cache.insert_addresses("example.com", &addrs, 1_000);
+        match cache.get("example.com", 31_000) {
+            Some(Cached::Addresses(left)) => {
+                assert_eq!(left.len(), 2);
+                assert_eq!(left[&v4(1)], 30);
+                assert_eq!(left[&v4(2)], 270);
+            }
+            other => panic!("{:?}", other),
+        }
+        match cache.get("example.com", 61_000) {
+            Some(Cached::Addresses(left)) => {
+                assert_eq!(left.keys().collect::<Vec<_>>(), vec![&v4(2)])
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(cache.get("example.com", 301_000).is_none());
+        assert_eq!(cache.len(), 0);
+        // a reply where nothing may be cached
+        addrs.clear();
+        addrs.insert(v4(3), 0);
+        cache.insert_addresses("example.com", &addrs, 0);
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn negative_answers() {
+        let mut cache = DnsCache::new(CACHE_MAX_NAMES);
+        cache.insert_negative("nope.example", DnsResponseCode::NameError, 900, 0);
+        match cache.get("nope.example", 100_000) {
+            Some(Cached::Negative {
+                code: DnsResponseCode::NameError,
+                ttl: 800,
+            }) => {}
+            other => panic!("{:?}", other),
+        }
+        assert!(cache.get("nope.example", 900_000).is_none());
+        // overly long negative TTLs are cut down to size
+        cache.insert_negative("nope.example", DnsResponseCode::NameError, 7 * 86_400, 0);
+        match cache.peek("nope.example", 0) {
+            Some(Cached::Negative { ttl, .. }) => assert_eq!(ttl, MAX_NEGATIVE_TTL_SECS),
+            other => panic!("{:?}", other),
+        }
+        assert!(cache.remove("nope.example"));
+        assert!(!cache.remove("nope.example"));
+    }
+
+    #[test]
+    fn least_recently_used_goes_first() {
+        let mut cache = DnsCache::new(3);
+        let mut addrs = HashMap::new();
+        addrs.insert(v4(1), 600);
+        for name in ["a", "b", "c"].iter() {
+            cache.insert_addresses(name, &addrs, 0);
+        }
+        assert!(cache.get("a", 0).is_some());
+        cache.insert_addresses("d", &addrs, 0);
+        assert_eq!(cache.len(), 3);
+        assert!(cache.peek("b", 0).is_none());
+        assert!(cache.peek("a", 0).is_some());
+        // expired names make way before anything that's still good
+        cache.insert_negative("e", DnsResponseCode::NameError, 1, 0);
+        assert!(cache.peek("c", 0).is_none());
+        cache.insert_addresses("f", &addrs, 2_000);
+        assert!(cache.peek("e", 2_000).is_none());
+        assert!(cache.peek("a", 2_000).is_some());
+        assert!(cache.peek("d", 2_000).is_some());
+    }
+
+    #[test]
+    fn frozen_cache_keeps_everything() {
+        let mut cache = DnsCache::new(CACHE_MAX_NAMES);
+        cache.insert_negative("nope.example", DnsResponseCode::NameError, 10, 0);
+        cache.set_frozen(true);
+        cache.expire(60_000);
+        assert!(cache.get("nope.example", 60_000).is_some());
+        cache.set_frozen(false);
+        cache.expire(60_000);
+        assert_eq!(cache.len(), 0);
+    }
+}
@@ -34,8 +34,36 @@ impl Dns {
             }
         }
     }
+    pub fn lookup_records(
+        &self,
+        _name: &str,
+        _rtype: crate::RecordType,
+    ) -> Result<Vec<crate::DnsRecord>, DnsResponseCode> {
+        log::warn!("DNS record lookups not implemented in hosted mode!");
+        Err(DnsResponseCode::NotImplemented)
+    }
     pub fn flush_cache(&self) -> Result<(), xous::Error> {
         log::warn!("DNS cache flush not implemented in hosted mode!");
         Ok(())
     }
+    pub fn cache_dump(&self, _name: &str) -> Result<Option<crate::CachedName>, xous::Error> {
+        log::warn!("DNS cache dump not implemented in hosted mode!");
+        Ok(None)
+    }
+    pub fn cache_flush_name(&self, _name: &str) -> Result<bool, xous::Error> {
+        log::warn!("DNS cache flush not implemented in hosted mode!");
+        Ok(false)
+    }
+    pub fn set_upstream(
+        &self,
+        _mode: crate::UpstreamMode,
+        _servers: &[crate::TlsServer],
+    ) -> Result<(), xous::Error> {
+        log::warn!("DNS upstream settings not implemented in hosted mode!");
+        Ok(())
+    }
+    pub fn upstream(&self) -> Result<(crate::UpstreamMode, Vec<crate::TlsServer>), xous::Error> {
+        log::warn!("DNS upstream settings not implemented in hosted mode!");
+        Ok((crate::UpstreamMode::Udp, Vec::new()))
+    }
 }
@@ -1,7 +1,7 @@
 #![cfg_attr(target_os = "none", no_std)]
 use xous::CID;
 use xous_ipc::{Buffer, String};
-use num_traits::ToPrimitive;
+use num_traits::{FromPrimitive, ToPrimitive};
 
 use net::NetIpAddr;
 use std::net::IpAddr;
@@ -39,12 +39,112 @@ impl Dns {
             }
         }
     }
+    /// Looks up the records of type `rtype` for `name`. CNAMEs are followed to the records they lead to,
+    /// unless it's the CNAMEs that are asked for. A name with no records of the type gives an empty list.
+    pub fn lookup_records(
+        &self,
+        name: &str,
+        rtype: RecordType,
+    ) -> Result<Vec<DnsRecord>, DnsResponseCode> {
+        if name.is_empty() || name.len() > DNS_NAME_LENGTH_LIMIT {
+            return Err(DnsResponseCode::FormatError);
+        }
+        let mut buf = xous::map_memory(
+            None,
+            None,
+            4096,
+            xous::MemoryFlags::R | xous::MemoryFlags::W,
+        )
+        .or(Err(DnsResponseCode::UnknownError))?;
+        buf.as_slice_mut::<u8>()[..name.len()].copy_from_slice(name.as_bytes());
+        // the record type goes in the offset field, the length of the name in the valid field
+        let result = xous::send_message(
+            self.conn,
+            xous::Message::new_lend_mut(
+                Opcode::RawLookup.to_usize().unwrap(),
+                buf,
+                xous::MemoryAddress::new(rtype.to_usize().unwrap()),
+                xous::MemorySize::new(name.len()),
+            ),
+        )
+        .or(Err(DnsResponseCode::UnknownError))
+        .and_then(|_| {
+            let reply = buf.as_slice::<u8>();
+            if reply[0] != 0 {
+                return Err(
+                    FromPrimitive::from_u8(reply[1]).unwrap_or(DnsResponseCode::UnknownError)
+                );
+            }
+            let mut records = Vec::new();
+            let mut index = 2;
+            for _ in 0..reply[1] {
+                let (record, len) =
+                    DnsRecord::decode(&reply[index..]).ok_or(DnsResponseCode::FormatError)?;
+                records.push(record);
+                index += len;
+            }
+            Ok(records)
+        });
+        xous::unmap_memory(buf).ok();
+        result
+    }
     pub fn flush_cache(&self) -> Result<(), xous::Error> {
         xous::send_message(
             self.conn,
             xous::Message::new_scalar(Opcode::Flush.to_usize().unwrap(), 0, 0, 0, 0)
         ).map(|_| ())
     }
+    /// Reports what the cache holds for `name`, or `None` if it holds nothing for it
+    pub fn cache_dump(&self, name: &str) -> Result<Option<CachedName>, xous::Error> {
+        let mut buf = Buffer::into_buf(DnsCacheDump::new(name)).or(Err(xous::Error::InternalError))?;
+        buf.lend_mut(self.conn, Opcode::CacheDump.to_u32().unwrap())
+            .or(Err(xous::Error::InternalError))?;
+        let dump = buf.to_original::<DnsCacheDump,_>().or(Err(xous::Error::InternalError))?;
+        if !dump.cached {
+            Ok(None)
+        } else if dump.code == DnsResponseCode::NoError && dump.addrs.iter().any(|a| a.is_some()) {
+            Ok(Some(CachedName::Addresses(
+                dump.addrs.iter().filter_map(|a| a.map(|a| (IpAddr::from(a.addr), a.ttl))).collect()
+            )))
+        } else {
+            Ok(Some(CachedName::Negative { code: dump.code, ttl: dump.ttl }))
+        }
+    }
+    /// Drops `name` from the cache, so the next lookup goes to the servers. Returns whether it was cached.
+    pub fn cache_flush_name(&self, name: &str) -> Result<bool, xous::Error> {
+        let mut buf = Buffer::into_buf(DnsCacheDump::new(name)).or(Err(xous::Error::InternalError))?;
+        buf.lend_mut(self.conn, Opcode::FlushName.to_u32().unwrap())
+            .or(Err(xous::Error::InternalError))?;
+        let dump = buf.to_original::<DnsCacheDump,_>().or(Err(xous::Error::InternalError))?;
+        Ok(dump.cached)
+    }
+    /// Sets where queries are sent. `servers` are the DNS-over-TLS servers, which are only used if `mode`
+    /// calls for them. The setting is kept in the PDDB, and the cache is flushed.
+    pub fn set_upstream(&self, mode: UpstreamMode, servers: &[TlsServer]) -> Result<(), xous::Error> {
+        let text = TlsServer::format_list(servers);
+        if servers.len() > MAX_TLS_SERVERS || text.len() > TLS_SERVERS_TEXT_LEN {
+            return Err(xous::Error::OutOfMemory);
+        }
+        let config = UpstreamConfig {
+            mode,
+            servers: String::from_str(&text),
+        };
+        let buf = Buffer::into_buf(config).or(Err(xous::Error::InternalError))?;
+        buf.lend(self.conn, Opcode::SetUpstream.to_u32().unwrap())
+            .map(|_| ())
+    }
+    /// Returns where queries are sent, along with the DNS-over-TLS servers
+    pub fn upstream(&self) -> Result<(UpstreamMode, Vec<TlsServer>), xous::Error> {
+        let config = UpstreamConfig {
+            mode: UpstreamMode::Udp,
+            servers: String::new(),
+        };
+        let mut buf = Buffer::into_buf(config).or(Err(xous::Error::InternalError))?;
+        buf.lend_mut(self.conn, Opcode::GetUpstream.to_u32().unwrap())
+            .or(Err(xous::Error::InternalError))?;
+        let config = buf.to_original::<UpstreamConfig,_>().or(Err(xous::Error::InternalError))?;
+        Ok((config.mode, TlsServer::parse_list(config.servers.as_str().unwrap_or(""))))
+    }
 }
 
 use core::sync::atomic::{AtomicU32, Ordering};
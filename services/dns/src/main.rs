@@ -3,6 +3,12 @@
 
 mod api;
 use api::*;
+mod answers;
+use answers::*;
+mod cache;
+use cache::*;
+mod upstream;
+use upstream::*;
 
 use net::{Duration, NetIpAddr};
 use num_traits::*;
@@ -11,7 +17,7 @@ use xous::msg_scalar_unpack;
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::io::ErrorKind;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::net::{IpAddr, SocketAddr};
 use std::thread;
 use xous_ipc::{Buffer, String};
 
@@ -21,17 +27,7 @@ use xous_ipc::{Buffer, String};
 // MOROS is MIT licensed.
 // See RFC 1035 for implementation details
 
-#[repr(u16)]
-enum QueryType {
-    A = 1,
-    // NS = 2,
-    // MD = 3,
-    // MF = 4,
-    // CNAME = 5,
-    // SOA = 6,
-    // MX = 15,
-    // TXT = 16,
-}
+const TYPE_SOA: u16 = 6;
 
 #[repr(u16)]
 enum QueryClass {
@@ -51,7 +47,7 @@ impl Message {
         }
     }
 
-    pub fn query(qname: &str, qtype: QueryType, qclass: QueryClass, id: u16) -> Self {
+    pub fn query(qname: &str, qtype: RecordType, qclass: QueryClass, id: u16) -> Self {
         let mut datagram = Vec::new();
 
         for b in id.to_be_bytes().iter() {
@@ -99,138 +95,6 @@ impl Message {
         }
     }
 
-    fn fast_foward_name(&self, start: usize) -> Result<usize, DnsResponseCode> {
-        use DnsResponseCode::FormatError;
-        let mut index = start;
-        loop {
-            log::trace!("cname index: {}", index);
-            if *(self.datagram.get(index).ok_or(FormatError)?) == 0 {
-                index += 1;
-                break;
-            } else {
-                index += *(self.datagram.get(index).ok_or(FormatError)?) as usize;
-                index += 1;
-            }
-        }
-        Ok(index)
-    }
-
-    pub fn parse_response(&self) -> Result<HashMap<IpAddr, u32>, DnsResponseCode> {
-        use DnsResponseCode::FormatError;
-        log::trace!("parsing packet: {:?}", self.datagram);
-
-        let mut map = HashMap::<IpAddr, u32>::new();
-        // ASSUME: the query ID and response bit fields have already been checked
-        // and that the rcode is valid
-        let qdcount = u16::from_be_bytes(self.datagram[4..6].try_into().unwrap());
-        let ancount = u16::from_be_bytes(self.datagram[6..8].try_into().unwrap());
-
-        let mut index = 12;
-        // fast forward past the qname
-        for queries in 0..qdcount {
-            log::trace!("parsing query{}, index {}", queries, index);
-            index = self.fast_foward_name(index)?;
-            log::trace!("fast forward through qname to {}", index);
-            // index is now at qtype
-            let qtype = u16::from_be_bytes(self.datagram[index..index + 2].try_into().unwrap());
-            // A = 1, AAAA = 28
-            if qtype != 1 && qtype != 28 {
-                log::error!("Problem parsing qname, qtype is not 1 or 28: {}", qtype);
-                return Err(FormatError);
-            }
-            index += 2;
-            let qclass = u16::from_be_bytes(self.datagram[index..index + 2].try_into().unwrap());
-            if qclass != 1 {
-                log::error!("Problem parsing qname, qclass is not 1: {}", qclass);
-                return Err(FormatError);
-            }
-            index += 2;
-        }
-        // index is now at the aname section
-        for aname in 0..ancount {
-            log::trace!("parsing aname{}, index {}", aname, index);
-            // first check to see if we're dealing with a pointer or a name
-            if self.datagram[index] >= 0xc0 {
-                // pointer
-                index += 1;
-                if self.datagram[index] != 0xc {
-                    log::error!(
-                        "Found aname pointer, but value does not conform to length of aname header"
-                    );
-                    return Err(FormatError);
-                }
-                index += 1;
-            } else {
-                // name, fast forward past the name
-                index = self.fast_foward_name(index)?;
-                log::trace!("fast forward aname to {}", index);
-            }
-            // index is now at type
-            let atype = u16::from_be_bytes(self.datagram[index..index + 2].try_into().unwrap());
-            // A = 1, AAAA = 28
-            if atype != 1 && atype != 28 {
-                log::error!("Problem parsing aname, type is not 1 or 28: {}", atype);
-                return Err(FormatError);
-            }
-            index += 2;
-            let aclass = u16::from_be_bytes(self.datagram[index..index + 2].try_into().unwrap());
-            if aclass != 1 {
-                log::error!("Problem parsing aname, aclass is not 1: {}", aclass);
-                return Err(FormatError);
-            }
-            index += 2;
-            // this is our TTL
-            let ttl = u32::from_be_bytes(self.datagram[index..index + 4].try_into().unwrap());
-            log::trace!("got ttl: {}", ttl);
-            index += 4;
-            // this is the payload length
-            let addr_len = u16::from_be_bytes(self.datagram[index..index + 2].try_into().unwrap());
-            index += 2;
-            match addr_len {
-                // ipv4
-                4 => {
-                    if atype != 1 {
-                        log::error!("Got a 4-byte address, but ATYPE != A (1)");
-                        return Err(FormatError);
-                    }
-                    // this copy happens because I can't figure out how to get Ipv4Addr::from() to realize it's casting from a [u8;4]
-                    let mut rdata: [u8; 4] = [0; 4];
-                    for (&src, dst) in self.datagram[index..index + 4].iter().zip(rdata.iter_mut())
-                    {
-                        *dst = src;
-                    }
-                    let addr = IpAddr::V4(Ipv4Addr::from(rdata));
-                    index += 4;
-                    map.insert(addr, ttl);
-                }
-                // ipv6
-                16 => {
-                    if atype != 28 {
-                        log::error!("Got a 16-byte address, but ATYPE != AAAA (28)");
-                        return Err(FormatError);
-                    }
-                    // this copy happens because I can't figure out how to get Ipv6Addr::from() to realize it's casting from a [u8;4]
-                    let mut rdata: [u8; 16] = [0; 16];
-                    for (&src, dst) in self.datagram[index..index + 16]
-                        .iter()
-                        .zip(rdata.iter_mut())
-                    {
-                        *dst = src;
-                    }
-                    let addr = IpAddr::V6(Ipv6Addr::from(rdata));
-                    index += 16;
-                    map.insert(addr, ttl);
-                }
-                _ => {
-                    log::error!("Length field does not match a known record type");
-                    return Err(FormatError);
-                }
-            }
-        }
-
-        Ok(map)
-    }
-
     /*
          example response for: betrusted.io->185.199.111.153
     Header:
@@ -290,6 +154,58 @@ impl Message {
     }
     */
 
+    fn u16_at(&self, index: usize) -> Option<u16> {
+        Some(u16::from_be_bytes(
+            self.datagram.get(index..index + 2)?.try_into().ok()?,
+        ))
+    }
+
+    fn u32_at(&self, index: usize) -> Option<u32> {
+        Some(u32::from_be_bytes(
+            self.datagram.get(index..index + 4)?.try_into().ok()?,
+        ))
+    }
+
+    /// Skips over the name at `index`, which may end in a pointer to another name
+    fn skip_name(&self, mut index: usize) -> Option<usize> {
+        loop {
+            let len = *self.datagram.get(index)? as usize;
+            if len >= 0xc0 {
+                return Some(index + 2);
+            } else if len == 0 {
+                return Some(index + 1);
+            }
+            index += len + 1;
+        }
+    }
+
+    /// How long a reply without addresses can be cached for, per RFC 2308: the lesser of the TTL of the SOA
+    /// record in the authority section, and the MINIMUM field of that record. Replies without an SOA
+    /// record aren't to be cached at all.
+    pub fn negative_ttl(&self) -> Option<u32> {
+        let qdcount = self.u16_at(4)?;
+        let ancount = self.u16_at(6)?;
+        let nscount = self.u16_at(8)?;
+        let mut index = 12;
+        for _ in 0..qdcount {
+            index = self.skip_name(index)? + 4;
+        }
+        // the answer section can hold the CNAMEs that led to the name that doesn't exist
+        for record in 0..(ancount as usize + nscount as usize) {
+            index = self.skip_name(index)?;
+            let rtype = self.u16_at(index)?;
+            let ttl = self.u32_at(index + 4)?;
+            let rdlength = self.u16_at(index + 8)? as usize;
+            index += 10;
+            if record >= ancount as usize && rtype == TYPE_SOA && rdlength >= 4 {
+                // MINIMUM is the last field of the SOA record
+                return Some(ttl.min(self.u32_at(index + rdlength - 4)?));
+            }
+            index += rdlength;
+        }
+        None
+    }
+
     pub fn rcode(&self) -> DnsResponseCode {
         match (self.header() >> 11) & 0xF {
             0 => DnsResponseCode::NoError,
@@ -303,13 +219,55 @@ impl Message {
     }
 }
 
+/// What the servers have to say about a name
+enum Resolution {
+    /// The addresses of the name, with their TTLs
+    Addresses(HashMap<IpAddr, u32>),
+    /// The name doesn't exist (`NameError`), or has no addresses (`NoError`). `ttl` is how long that
+    /// may be cached for, if the servers said.
+    Negative {
+        code: DnsResponseCode,
+        ttl: Option<u32>,
+    },
+}
+
+/// What a server had to say to a query, once the CNAMEs in its reply are followed
+enum Reply {
+    /// The records of the type asked for, with their TTLs
+    Records(Vec<(DnsRecord, u32)>),
+    /// As for `Resolution::Negative`
+    Negative {
+        code: DnsResponseCode,
+        ttl: Option<u32>,
+    },
+}
+
+impl From<Reply> for Resolution {
+    fn from(reply: Reply) -> Resolution {
+        match reply {
+            Reply::Records(records) => Resolution::Addresses(
+                records
+                    .into_iter()
+                    .filter_map(|(record, ttl)| match record {
+                        DnsRecord::A(addr) => Some((IpAddr::V4(addr), ttl)),
+                        DnsRecord::Aaaa(addr) => Some((IpAddr::V6(addr), ttl)),
+                        _ => None,
+                    })
+                    .collect(),
+            ),
+            Reply::Negative { code, ttl } => Resolution::Negative { code, ttl },
+        }
+    }
+}
+
 pub struct Resolver {
     /// DnsServerManager is a service of the Net crate that automatically updates the DNS server list
     mgr: net::DnsServerManager,
     socket: net::UdpSocket,
     buf: [u8; DNS_PKT_MAX_LEN],
     trng: trng::Trng,
-    freeze: bool,
+    /// the DNS-over-TLS servers, which are used instead of the UDP ones when so configured
+    upstream: Upstream,
 }
 impl Resolver {
     pub fn new(xns: &xous_names::XousNames) -> Resolver {
@@ -332,7 +290,7 @@ impl Resolver {
             socket,
             buf: [0; DNS_PKT_MAX_LEN],
             trng,
-            freeze: false,
+            upstream: Upstream::new(&xns),
         }
     }
     pub fn add_server(&mut self, addr: IpAddr) {
@@ -345,49 +303,171 @@ impl Resolver {
         self.mgr.clear();
     }
     pub fn set_freeze_config(&mut self, freeze: bool) {
-        self.freeze = freeze;
         self.mgr.set_freeze(freeze);
     }
-    pub fn get_freeze(&self) -> bool {
-        self.freeze
+    pub fn upstream(&mut self) -> (UpstreamMode, &[TlsServer]) {
+        self.upstream.settings()
+    }
+    pub fn set_upstream(&mut self, mode: UpstreamMode, servers: Vec<TlsServer>) {
+        self.upstream.set(mode, servers);
     }
     /// this allows us to re-use the TRNG object
     pub fn trng_u32(&self) -> u32 {
         self.trng.get_u32().unwrap()
     }
-    pub fn resolve(&mut self, name: &str) -> Result<HashMap<IpAddr, u32>, DnsResponseCode> {
-        if let Some(dns_address) = self.mgr.get_random() {
-            let dns_port = 53;
-            let server = SocketAddr::new(dns_address, dns_port);
-
-            let qname = name;
-            let qtype = QueryType::A;
-            let qclass = QueryClass::IN;
-            let query = Message::query(qname, qtype, qclass, self.trng.get_u32().unwrap() as u16);
-
-            self.socket
-                .send_to(&query.datagram, &server)
-                .map_err(|_| DnsResponseCode::NetworkError)?;
-
-            match self.socket.recv(&mut self.buf) {
-                Ok(len) => {
-                    let message = Message::from(&self.buf[..len]);
-                    if message.id() == query.id() && message.is_response() {
-                        return match message.rcode() {
-                            DnsResponseCode::NoError => message.parse_response(),
-                            rcode => Err(rcode),
-                        };
-                    } else {
-                        Err(DnsResponseCode::NetworkError)
+    /// Looks up both the A and AAAA records for `name`. The lookup only fails if both queries fail; it only
+    /// comes back negative if both queries do.
+    fn resolve(&mut self, name: &str) -> Result<Resolution, DnsResponseCode> {
+        let v4 = self.query(name, RecordType::A).map(Resolution::from);
+        let v6 = self.query(name, RecordType::Aaaa).map(Resolution::from);
+        match (v4, v6) {
+            (Ok(Resolution::Addresses(mut map)), Ok(Resolution::Addresses(v6_map))) => {
+                map.extend(v6_map);
+                Ok(Resolution::Addresses(map))
+            }
+            (Ok(Resolution::Addresses(map)), other) | (other, Ok(Resolution::Addresses(map))) => {
+                log::debug!("partial DNS result for {}: {:?}", name, other.err());
+                Ok(Resolution::Addresses(map))
+            }
+            (
+                Ok(Resolution::Negative {
+                    code: v4_code,
+                    ttl: v4_ttl,
+                }),
+                Ok(Resolution::Negative {
+                    code: v6_code,
+                    ttl: v6_ttl,
+                }),
+            ) => Ok(Resolution::Negative {
+                // NXDOMAIN from either query means there's no such name at all
+                code: if v4_code == DnsResponseCode::NameError {
+                    v4_code
+                } else {
+                    v6_code
+                },
+                ttl: match (v4_ttl, v6_ttl) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    _ => None,
+                },
+            }),
+            (Err(e), _) | (_, Err(e)) => Err(e),
+        }
+    }
+    /// Looks up the records of type `rtype` for `name`. Unlike addresses, these aren't cached.
+    fn lookup(&mut self, name: &str, rtype: RecordType) -> Result<Reply, DnsResponseCode> {
+        self.query(name, rtype)
+    }
+    /// Queries the servers for the records of type `qtype` for `qname`. When the reply has a CNAME for the
+    /// name but not the records it leads to, the name at the end of the chain is queried in turn.
+    fn query(&mut self, qname: &str, qtype: RecordType) -> Result<Reply, DnsResponseCode> {
+        let mut name = std::string::String::from(qname);
+        let mut chain_ttl = u32::MAX;
+        for _ in 0..MAX_CNAME_HOPS {
+            let message = self.exchange(&name, qtype)?;
+            match message.rcode() {
+                DnsResponseCode::NoError => {
+                    let chain = follow_chain(&name, qtype, &parse_answers(&message.datagram)?)?;
+                    chain_ttl = chain_ttl.min(chain.ttl);
+                    if !chain.records.is_empty() {
+                        return Ok(Reply::Records(
+                            chain
+                                .records
+                                .into_iter()
+                                .map(|(record, ttl)| (record, ttl.min(chain_ttl)))
+                                .collect(),
+                        ));
+                    } else if same_name(&chain.target, &name) {
+                        return Ok(Reply::Negative {
+                            code: DnsResponseCode::NoError,
+                            ttl: message.negative_ttl().map(|ttl| ttl.min(chain_ttl)),
+                        });
                     }
+                    log::debug!("following CNAME from {} to {}", name, chain.target);
+                    name = chain.target;
                 }
-                Err(e) => match e.kind() {
-                    ErrorKind::WouldBlock => Err(DnsResponseCode::NetworkError),
-                    _ => Err(DnsResponseCode::UnknownError),
-                },
+                DnsResponseCode::NameError => {
+                    return Ok(Reply::Negative {
+                        code: DnsResponseCode::NameError,
+                        ttl: message.negative_ttl().map(|ttl| ttl.min(chain_ttl)),
+                    })
+                }
+                rcode => return Err(rcode),
             }
+        }
+        log::warn!("gave up following the CNAMEs from {}", qname);
+        Err(DnsResponseCode::ServerFailure)
+    }
+    /// Sends a single query, over TLS or UDP as configured, and returns the reply
+    fn exchange(&mut self, qname: &str, qtype: RecordType) -> Result<Message, DnsResponseCode> {
+        let qclass = QueryClass::IN;
+        let query = Message::query(qname, qtype, qclass, self.trng.get_u32().unwrap() as u16);
+
+        let message = match self.upstream.exchange(&query.datagram)? {
+            Some(reply) => Message::from(&reply),
+            None => self.exchange_udp(&query.datagram)?,
+        };
+        // a reply too short to hold a header is as good as no reply at all
+        if message.datagram.len() >= 12 && message.id() == query.id() && message.is_response() {
+            Ok(message)
         } else {
-            Err(DnsResponseCode::NoServerSpecified)
+            Err(DnsResponseCode::NetworkError)
+        }
+    }
+    /// Sends `datagram` to one of the servers handed out by DHCP, and returns the reply
+    fn exchange_udp(&mut self, datagram: &[u8]) -> Result<Message, DnsResponseCode> {
+        let dns_address = self
+            .mgr
+            .get_random()
+            .ok_or(DnsResponseCode::NoServerSpecified)?;
+        self.socket
+            .send_to(datagram, &SocketAddr::new(dns_address, 53))
+            .map_err(|_| DnsResponseCode::NetworkError)?;
+
+        match self.socket.recv(&mut self.buf) {
+            Ok(len) => Ok(Message::from(&self.buf[..len])),
+            Err(e) => match e.kind() {
+                ErrorKind::WouldBlock => Err(DnsResponseCode::NetworkError),
+                _ => Err(DnsResponseCode::UnknownError),
+            },
+        }
+    }
+}
+
+/// Picks one of the addresses of a name at random. IPv4 addresses are preferred, as not every
+/// network routes IPv6, so IPv6 addresses are only picked for names that have no IPv4 address.
+fn pick_address(entries: &HashMap<IpAddr, u32>, rand: usize) -> Option<IpAddr> {
+    let mut candidates: Vec<&IpAddr> = entries.keys().filter(|addr| addr.is_ipv4()).collect();
+    if candidates.len() == 0 {
+        candidates = entries.keys().collect();
+    }
+    if candidates.len() == 0 {
+        None
+    } else {
+        Some(*candidates[rand % candidates.len()])
+    }
+}
+
+/// Looks `name` up with the servers, and caches what they say, unless the lookup failed outright
+fn resolve_and_cache(
+    resolver: &mut Resolver,
+    cache: &mut DnsCache,
+    name: &str,
+    now: u64,
+) -> Result<Cached, DnsResponseCode> {
+    match resolver.resolve(name)? {
+        Resolution::Addresses(entries) => {
+            cache.insert_addresses(name, &entries, now);
+            Ok(Cached::Addresses(entries))
+        }
+        Resolution::Negative { code, ttl } => {
+            // without a TTL from the servers, there's no telling how long the answer holds, so it's not cached
+            if let Some(ttl) = ttl {
+                cache.insert_negative(name, code, ttl, now);
+            }
+            Ok(Cached::Negative {
+                code,
+                ttl: ttl.unwrap_or(0),
+            })
         }
     }
 }
@@ -441,8 +521,10 @@ fn fill_response(mut env: xous::MessageEnvelope, entries: &HashMap<IpAddr, u32>)
     }
     *i.next()? = entry_count.try_into().ok()?;
 
-    // Start filling in the addreses
-    for addr in entries.keys() {
+    // Start filling in the addreses. IPv4 goes first, as callers generally try the addresses in order.
+    let v4 = entries.keys().filter(|addr| addr.is_ipv4());
+    let v6 = entries.keys().filter(|addr| addr.is_ipv6());
+    for addr in v4.chain(v6).take(entry_count) {
         match addr {
             &IpAddr::V4(a) => {
                 // IPv4
@@ -453,10 +535,10 @@ fn fill_response(mut env: xous::MessageEnvelope, entries: &HashMap<IpAddr, u32>)
             }
             &IpAddr::V6(a) => {
                 // IPv6
+                *i.next()? = 6;
                 for entry in a.octets() {
                     *i.next()? = entry;
                 }
-                *i.next()? = 6;
             }
         }
     }
@@ -468,6 +550,33 @@ fn fill_response(mut env: xous::MessageEnvelope, entries: &HashMap<IpAddr, u32>)
     None
 }
 
+fn fill_records(mut env: xous::MessageEnvelope, records: &[DnsRecord]) -> Option<()> {
+    let mem = env.body.memory_message_mut()?;
+
+    let s: &mut [u8] = mem.buf.as_slice_mut();
+    // "Error" until the records are all in, as with `fill_response()`
+    *s.get_mut(0)? = 1;
+
+    // Records that don't fit in the buffer are left out, as are any past the 255 that can be counted
+    let mut index = 2;
+    let mut count = 0;
+    let mut entry = Vec::new();
+    for record in records.iter().take(u8::MAX as usize) {
+        entry.clear();
+        record.encode(&mut entry);
+        match s.get_mut(index..index + entry.len()) {
+            Some(dest) => dest.copy_from_slice(&entry),
+            None => break,
+        }
+        index += entry.len();
+        count += 1;
+    }
+    *s.get_mut(1)? = count;
+    s[0] = 0;
+
+    None
+}
+
 fn fill_error(mut env: xous::MessageEnvelope, code: DnsResponseCode) -> Option<()> {
     let mem = env.body.memory_message_mut()?;
 
@@ -496,9 +605,11 @@ fn xmain() -> ! {
     // if you wanted to force a server into the initial config, you can do it here, for example:
     // resolver.add_server(IpAddr::V4(Ipv4Addr::new(1,1,1,1)));
 
-    let mut dns_cache = HashMap::<std::string::String, HashMap<IpAddr, u32>>::new();
+    let mut dns_cache = DnsCache::new(CACHE_MAX_NAMES);
+    let tt = ticktimer_server::Ticktimer::new().unwrap();
 
-    // build a thread that pings the UpdateTtl function once every few minutes to expire the DNS cache
+    // build a thread that pings the UpdateTtl function once every few minutes to expire the DNS cache.
+    // Records are also checked for expiry as they're looked up, so this just keeps dead entries from piling up.
     thread::spawn({
         let local_cid = xous::connect(dns_sid).unwrap();
         move || {
@@ -508,13 +619,7 @@ fn xmain() -> ! {
                 tt.sleep_ms(TTL_INTERVAL_SECS * 1000).unwrap();
                 xous::send_message(
                     local_cid,
-                    xous::Message::new_scalar(
-                        Opcode::UpdateTtl.to_usize().unwrap(),
-                        TTL_INTERVAL_SECS,
-                        0,
-                        0,
-                        0,
-                    ),
+                    xous::Message::new_scalar(Opcode::UpdateTtl.to_usize().unwrap(), 0, 0, 0, 0),
                 )
                 .expect("couldn't increment DNS cache");
             }
@@ -526,29 +631,71 @@ fn xmain() -> ! {
         let mut msg = xous::receive_message(dns_sid).unwrap();
         match FromPrimitive::from_usize(msg.body.id()) {
             Some(Opcode::RawLookup) => {
-                match name_from_msg(&msg).map(|s| s.to_owned()) {
-                    Ok(owned_name) => {
-                        log::trace!("performing a lookup of {}", owned_name);
-                        // Try to get the result out of the DNS cache
-                        if let Some(entries) = dns_cache.get(&owned_name) {
-                            fill_response(msg, entries);
-                            continue;
+                // the offset field selects the type of record; without one, it's the addresses that are wanted
+                let rtype = msg
+                    .body
+                    .memory_message()
+                    .and_then(|m| m.offset)
+                    .map(|o| o.get());
+                match (name_from_msg(&msg).map(|s| s.to_owned()), rtype) {
+                    (Ok(owned_name), Some(rtype)) => {
+                        log::trace!(
+                            "performing a lookup of {} records for {}",
+                            rtype,
+                            owned_name
+                        );
+                        let result = match FromPrimitive::from_usize(rtype) {
+                            Some(rtype) => resolver.lookup(&owned_name, rtype),
+                            None => Err(DnsResponseCode::NotImplemented),
+                        };
+                        match result {
+                            Ok(Reply::Records(records)) => {
+                                let records: Vec<DnsRecord> =
+                                    records.into_iter().map(|(record, _)| record).collect();
+                                fill_records(msg, &records);
+                            }
+                            Ok(Reply::Negative {
+                                code: DnsResponseCode::NoError,
+                                ..
+                            }) => {
+                                fill_records(msg, &[]);
+                            }
+                            Ok(Reply::Negative { code: e, .. }) | Err(e) => {
+                                fill_error(msg, e);
+                            }
                         }
-
-                        // This entry is not in the cache, so perform a lookup
-                        match resolver.resolve(&owned_name) {
-                            Ok(cache_entry) => {
-                                fill_response(msg, &cache_entry);
-                                dns_cache.insert(owned_name, cache_entry);
-                                continue;
+                        continue;
+                    }
+                    (Ok(owned_name), None) => {
+                        log::trace!("performing a lookup of {}", owned_name);
+                        // Try to get the result out of the DNS cache, and failing that, perform a lookup
+                        let result = match dns_cache.get(&owned_name, tt.elapsed_ms()) {
+                            Some(cached) => Ok(cached),
+                            None => resolve_and_cache(
+                                &mut resolver,
+                                &mut dns_cache,
+                                &owned_name,
+                                tt.elapsed_ms(),
+                            ),
+                        };
+                        match result {
+                            Ok(Cached::Addresses(entries)) => {
+                                fill_response(msg, &entries);
                             }
-                            Err(e) => {
+                            // the name exists, but has no addresses
+                            Ok(Cached::Negative {
+                                code: DnsResponseCode::NoError,
+                                ..
+                            }) => {
+                                fill_response(msg, &HashMap::new());
+                            }
+                            Ok(Cached::Negative { code: e, .. }) | Err(e) => {
                                 fill_error(msg, e);
-                                continue;
                             }
                         }
+                        continue;
                     }
-                    Err(e) => {
+                    (Err(e), _) => {
                         log::error!("unable to do name lookup: {:?}", e);
                         fill_error(msg, DnsResponseCode::NameError);
                         continue;
@@ -562,110 +709,122 @@ fn xmain() -> ! {
                 let name = buf
                     .to_original::<String<DNS_NAME_LENGTH_LIMIT>, _>()
                     .unwrap();
-                let name_std = std::string::String::from(name.as_str().unwrap());
-                if let Some(cache_entry) = dns_cache.get(&name_std) {
-                    // pick a random entry
-                    let rand = resolver.trng_u32() as usize % cache_entry.len();
-                    for (index, (ip_addr, _)) in cache_entry.iter().enumerate() {
-                        if rand == index {
-                            log::debug!("DNS cached: {}->{:?}", name, ip_addr);
-                            let response = DnsResponse {
-                                addr: Some(NetIpAddr::from(*ip_addr)),
-                                code: DnsResponseCode::NoError,
-                            };
-                            buf.replace(response).unwrap();
-                            break;
-                        }
+                let name_str = name.as_str().unwrap();
+                let cached = dns_cache.get(name_str, tt.elapsed_ms());
+                let from_cache = cached.is_some();
+                let result = match cached {
+                    Some(cached) => Ok(cached),
+                    None => {
+                        resolve_and_cache(&mut resolver, &mut dns_cache, name_str, tt.elapsed_ms())
                     }
-                } else {
-                    match resolver.resolve(name.as_str().unwrap()) {
-                        Ok(cache_entry) => {
-                            if cache_entry.len() > 0 {
-                                dns_cache.insert(name_std, cache_entry);
-
-                                // now pick the entry back out again, as it was consumed...
-                                let name_std = std::string::String::from(name.as_str().unwrap());
-                                let cache_entry = dns_cache.get(&name_std).unwrap();
-
-                                // pick a random entry from the query response
-                                let rand = resolver.trng_u32() as usize % cache_entry.len();
-                                for (index, (ip_addr, _)) in cache_entry.iter().enumerate() {
-                                    if rand == index {
-                                        let response = DnsResponse {
-                                            addr: Some(NetIpAddr::from(*ip_addr)),
-                                            code: DnsResponseCode::NoError,
-                                        };
-                                        buf.replace(response).unwrap();
-                                        break;
-                                    }
+                };
+                let response = match result {
+                    // pick a random entry
+                    Ok(Cached::Addresses(entries)) => {
+                        match pick_address(&entries, resolver.trng_u32() as usize) {
+                            Some(ip_addr) => {
+                                if from_cache {
+                                    log::debug!("DNS cached: {}->{:?}", name, ip_addr);
+                                }
+                                DnsResponse {
+                                    addr: Some(NetIpAddr::from(ip_addr)),
+                                    code: DnsResponseCode::NoError,
                                 }
-                            } else {
-                                // no names found
-                                let response = DnsResponse {
-                                    addr: None,
-                                    code: DnsResponseCode::NameError,
-                                };
-                                buf.replace(response).unwrap();
                             }
-                        }
-                        Err(e) => {
-                            log::debug!("DNS query failed: {}->{:?}", name, e);
-                            let response = DnsResponse {
+                            None => DnsResponse {
                                 addr: None,
-                                code: e,
-                            };
-                            buf.replace(response).unwrap();
+                                code: DnsResponseCode::NameError,
+                            },
                         }
                     }
-                }
+                    // no names found
+                    Ok(Cached::Negative { .. }) => DnsResponse {
+                        addr: None,
+                        code: DnsResponseCode::NameError,
+                    },
+                    Err(e) => {
+                        log::debug!("DNS query failed: {}->{:?}", name, e);
+                        DnsResponse {
+                            addr: None,
+                            code: e,
+                        }
+                    }
+                };
+                buf.replace(response).unwrap();
             }
-            Some(Opcode::UpdateTtl) => msg_scalar_unpack!(msg, incr_secs, _, _, _, {
-                let increment = if incr_secs < u32::MAX as usize {
-                    incr_secs as u32
-                } else {
-                    u32::MAX
+            Some(Opcode::UpdateTtl) => msg_scalar_unpack!(msg, _, _, _, _, {
+                dns_cache.expire(tt.elapsed_ms());
+                log::debug!("DNS cache holds {} names", dns_cache.len());
+            }),
+            Some(Opcode::Flush) => {
+                dns_cache.clear();
+            }
+            Some(Opcode::CacheDump) => {
+                let mut buf = unsafe {
+                    Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap())
                 };
-                if !resolver.get_freeze() {
-                    let mut expired_names = Vec::<std::string::String>::new();
-                    for (name, cache_map) in dns_cache.iter_mut() {
-                        // each entry can have multiple names with a different TTL
-                        // decrement the TTL, and note which go to zero
-                        let mut expired_entries = Vec::<IpAddr>::new();
-                        for (entry, ttl) in cache_map.iter_mut() {
-                            log::debug!("entry: {:?}, ttl: {}, incr: {}", entry, ttl, increment);
-                            if *ttl < increment {
-                                *ttl = 0;
-                                expired_entries.push(*entry);
-                            } else {
-                                *ttl = *ttl - increment as u32;
-                            }
-                        }
-                        // remove the entries that are 0
-                        for entry in expired_entries {
-                            log::debug!("DNS cache expiring {:?}", entry);
-                            cache_map.remove(&entry);
-                        }
-                        // if all the entries are removed, mark for removal from the cache entirely
-                        if cache_map.len() == 0 {
-                            // have to copy the name to a new object to track it
-                            let name = std::string::String::from(name.as_str());
-                            expired_names.push(name);
+                let mut dump = buf.to_original::<DnsCacheDump, _>().unwrap();
+                let name = std::string::String::from(dump.name.as_str().unwrap_or(""));
+                match dns_cache.peek(&name, tt.elapsed_ms()) {
+                    Some(Cached::Addresses(entries)) => {
+                        dump.cached = true;
+                        dump.code = DnsResponseCode::NoError;
+                        for (slot, (addr, ttl)) in dump.addrs.iter_mut().zip(entries.iter()) {
+                            *slot = Some(DnsCacheAddr {
+                                addr: NetIpAddr::from(*addr),
+                                ttl: *ttl,
+                            });
                         }
                     }
-                    for name in expired_names {
-                        log::debug!("DNS cache removing {}", &name);
-                        dns_cache.remove(&name);
+                    Some(Cached::Negative { code, ttl }) => {
+                        dump.cached = true;
+                        dump.code = code;
+                        dump.ttl = ttl;
                     }
+                    None => dump.cached = false,
                 }
-            }),
-            Some(Opcode::Flush) => {
+                buf.replace(dump).unwrap();
+            }
+            Some(Opcode::FlushName) => {
+                let mut buf = unsafe {
+                    Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap())
+                };
+                let mut dump = buf.to_original::<DnsCacheDump, _>().unwrap();
+                dump.cached = dns_cache.remove(dump.name.as_str().unwrap_or(""));
+                buf.replace(dump).unwrap();
+            }
+            Some(Opcode::SetUpstream) => {
+                let buffer =
+                    unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                let config = buffer.to_original::<UpstreamConfig, _>().unwrap();
+                let servers = TlsServer::parse_list(config.servers.as_str().unwrap_or(""));
+                log::info!(
+                    "DNS upstream set to {} with {} TLS servers",
+                    config.mode.name(),
+                    servers.len()
+                );
+                resolver.set_upstream(config.mode, servers);
+                // what's cached came from the old upstream, which may not be trusted the way the new one is
                 dns_cache.clear();
             }
+            Some(Opcode::GetUpstream) => {
+                let mut buf = unsafe {
+                    Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap())
+                };
+                let (mode, servers) = resolver.upstream();
+                let config = UpstreamConfig {
+                    mode,
+                    servers: String::from_str(&TlsServer::format_list(servers)),
+                };
+                buf.replace(config).unwrap();
+            }
             Some(Opcode::FreezeConfig) => {
                 resolver.set_freeze_config(true);
+                dns_cache.set_frozen(true);
             }
             Some(Opcode::ThawConfig) => {
                 resolver.set_freeze_config(false);
+                dns_cache.set_frozen(false);
             }
             Some(Opcode::Quit) => {
                 log::warn!("got quit!");
@@ -0,0 +1,287 @@
+use crate::api::*;
+use std::io::{self, Error, ErrorKind, Read, Write};
+
+/// How long to wait on a DNS-over-TLS server, whether connecting or for a reply
+const TLS_TIMEOUT_MS: u64 = 10_000;
+/// In the fallback mode, once every TLS server has failed, they're passed over for this long before
+/// being tried again, so that lookups don't each sit through the timeouts while they're down.
+const TLS_RETRY_MS: u64 = 60_000;
+
+/// Sends queries to the DNS-over-TLS servers, according to the upstream settings.
+///
+/// Server certificates are checked against the roots and pins in the TLS certificate store, and against
+/// the RTC. `sntp` sets the RTC, but needs a lookup to do so, so until the RTC has been set (by `sntp` over
+/// the UDP fallback, or by hand) only pinned servers can be used.
+pub(crate) struct Upstream {
+    mode: UpstreamMode,
+    servers: Vec<TlsServer>,
+    tls: tls::Tls,
+    /// The connection used last, with the index of its server. It's kept open for the queries that follow.
+    conn: Option<(usize, tls::TlsStream<net::TcpStream>)>,
+    pddb: pddb::Pddb,
+    tt: ticktimer_server::Ticktimer,
+    /// set once the settings have been read out of the PDDB
+    settings_loaded: bool,
+    /// in the fallback mode, the ticktimer time until which the TLS servers are passed over
+    down_until_ms: u64,
+}
+
+impl Upstream {
+    pub fn new(xns: &xous_names::XousNames) -> Upstream {
+        Upstream {
+            mode: UpstreamMode::Udp,
+            servers: Vec::new(),
+            tls: tls::Tls::new(xns),
+            conn: None,
+            pddb: pddb::Pddb::new().expect("couldn't connect to the PDDB"),
+            tt: ticktimer_server::Ticktimer::new().unwrap(),
+            settings_loaded: false,
+            down_until_ms: 0,
+        }
+    }
+
+    fn read_setting(&mut self, key: &str) -> Option<String> {
+        let mut setting = self
+            .pddb
+            .get(DNS_DICT, key, None, false, false, None, Some(|| {}))
+            .ok()?;
+        let mut value = String::new();
+        setting.read_to_string(&mut value).ok()?;
+        Some(value)
+    }
+    /// The settings can only be read once the PDDB is mounted; until then, queries go out over UDP.
+    fn load_settings(&mut self) {
+        if self.settings_loaded || !self.pddb.is_mounted() {
+            return;
+        }
+        if let Some(mode) = self.read_setting(DNS_KEY_UPSTREAM) {
+            match UpstreamMode::from_name(mode.trim()) {
+                Some(mode) => self.mode = mode,
+                None => log::warn!("ignoring invalid DNS upstream setting: {}", mode),
+            }
+        }
+        if let Some(servers) = self.read_setting(DNS_KEY_TLS_SERVERS) {
+            self.servers = TlsServer::parse_list(&servers);
+        }
+        self.settings_loaded = true;
+    }
+    fn save_setting(&mut self, key: &str, value: &str) {
+        if !self.pddb.is_mounted() {
+            log::warn!("PDDB not mounted, {} setting will not be kept", key);
+            return;
+        }
+        // writing into an existing key doesn't truncate it, so start from scratch
+        self.pddb.delete_key(DNS_DICT, key, None, false).ok();
+        match self.pddb.get(
+            DNS_DICT,
+            key,
+            None,
+            true,
+            true,
+            Some(value.len()),
+            Some(|| {}),
+        ) {
+            Ok(mut setting) => {
+                if let Err(e) = setting
+                    .write_all(value.as_bytes())
+                    .and_then(|_| setting.flush())
+                {
+                    log::error!("couldn't save {} setting: {:?}", key, e);
+                }
+            }
+            Err(e) => log::error!("couldn't save {} setting: {:?}", key, e),
+        }
+    }
+
+    pub fn settings(&mut self) -> (UpstreamMode, &[TlsServer]) {
+        self.load_settings();
+        (self.mode, &self.servers)
+    }
+    pub fn set(&mut self, mode: UpstreamMode, servers: Vec<TlsServer>) {
+        self.load_settings();
+        self.mode = mode;
+        self.servers = servers;
+        self.conn = None;
+        self.down_until_ms = 0;
+        self.save_setting(DNS_KEY_UPSTREAM, mode.name());
+        let servers = TlsServer::format_list(&self.servers);
+        self.save_setting(DNS_KEY_TLS_SERVERS, &servers);
+    }
+
+    /// Sends `query` to the TLS servers, and returns the reply. `Ok(None)` means the query is to go out
+    /// over UDP instead.
+    pub fn exchange(&mut self, query: &[u8]) -> Result<Option<Vec<u8>>, DnsResponseCode> {
+        self.load_settings();
+        match self.mode {
+            UpstreamMode::Udp => Ok(None),
+            UpstreamMode::Tls => {
+                if self.servers.is_empty() {
+                    return Err(DnsResponseCode::NoServerSpecified);
+                }
+                self.exchange_tls(query).map(Some).map_err(|e| {
+                    log::warn!("no DNS-over-TLS server could be reached: {:?}", e);
+                    DnsResponseCode::NetworkError
+                })
+            }
+            UpstreamMode::TlsWithFallback => {
+                if self.servers.is_empty() || self.tt.elapsed_ms() < self.down_until_ms {
+                    return Ok(None);
+                }
+                match self.exchange_tls(query) {
+                    Ok(reply) => Ok(Some(reply)),
+                    Err(e) => {
+                        log::warn!(
+                            "no DNS-over-TLS server could be reached, falling back to UDP: {:?}",
+                            e
+                        );
+                        self.down_until_ms = self.tt.elapsed_ms() + TLS_RETRY_MS;
+                        Ok(None)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tries the servers in order, starting with the connection kept open from the last query
+    fn exchange_tls(&mut self, query: &[u8]) -> io::Result<Vec<u8>> {
+        if let Some((index, mut conn)) = self.conn.take() {
+            // the server may have closed the connection since, in which case a fresh one is made
+            match exchange_framed(&mut conn, query) {
+                Ok(reply) => {
+                    self.conn = Some((index, conn));
+                    return Ok(reply);
+                }
+                Err(e) => log::debug!(
+                    "DNS-over-TLS connection to {:?} dropped: {:?}",
+                    self.servers[index].addr,
+                    e
+                ),
+            }
+        }
+        let mut last_err = Error::new(ErrorKind::NotFound, "no DNS-over-TLS servers configured");
+        for index in 0..self.servers.len() {
+            match self
+                .connect(index)
+                .and_then(|mut conn| exchange_framed(&mut conn, query).map(|reply| (conn, reply)))
+            {
+                Ok((conn, reply)) => {
+                    self.conn = Some((index, conn));
+                    return Ok(reply);
+                }
+                Err(e) => {
+                    log::warn!(
+                        "DNS-over-TLS server {} ({:?}) failed: {:?}",
+                        self.servers[index].name,
+                        self.servers[index].addr,
+                        e
+                    );
+                    last_err = e;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    fn connect(&mut self, index: usize) -> io::Result<tls::TlsStream<net::TcpStream>> {
+        let server = &self.servers[index];
+        // without the time, only a pin can authenticate the server, so don't bother connecting
+        if !self.tls.time_known() && self.tls.store().get_pin(&server.name)?.is_none() {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "the time isn't known yet, and the server isn't pinned",
+            ));
+        }
+        let timeout = net::Duration::from_millis(TLS_TIMEOUT_MS);
+        // the server is connected to by address, as looking its name up would mean asking ourselves
+        let mut tcp = net::TcpStream::connect_xous(server.addr, Some(timeout), None)?;
+        tcp.set_read_timeout(Some(timeout))?;
+        tcp.set_write_timeout(Some(timeout))?;
+        self.tls.connect(&server.name, tcp)
+    }
+}
+
+/// Sends a query over a stream, and reads back the reply. Over a stream, each message has its length in
+/// front of it (RFC 1035, section 4.2.2).
+pub(crate) fn exchange_framed<S: Read + Write>(
+    stream: &mut S,
+    query: &[u8],
+) -> io::Result<Vec<u8>> {
+    let mut framed = Vec::with_capacity(2 + query.len());
+    framed.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    framed.extend_from_slice(query);
+    stream.write_all(&framed)?;
+    stream.flush()?;
+
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let mut reply = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut reply)?;
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stands in for a resolver at the far end of a stream. It answers each query with the query itself,
+    /// marked as a response, and hands its replies out a few bytes at a time.
+    struct StubResolver {
+        received: Vec<u8>,
+        replies: Vec<u8>,
+        /// the resolver hangs up after this many queries
+        queries_left: usize,
+    }
+    impl Read for StubResolver {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.replies.len()).min(3);
+            buf[..len].copy_from_slice(&self.replies[..len]);
+            self.replies.drain(..len);
+            Ok(len)
+        }
+    }
+    impl Write for StubResolver {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.received.extend_from_slice(buf);
+            while self.received.len() >= 2 {
+                let len = u16::from_be_bytes([self.received[0], self.received[1]]) as usize;
+                if self.received.len() < 2 + len || self.queries_left == 0 {
+                    break;
+                }
+                let mut reply: Vec<u8> = self.received.drain(..2 + len).skip(2).collect();
+                reply[2] |= 0x80;
+                self.replies
+                    .extend_from_slice(&(reply.len() as u16).to_be_bytes());
+                self.replies.extend_from_slice(&reply);
+                self.queries_left -= 1;
+            }
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn framed_exchanges() {
+        let mut stub = StubResolver {
+            received: Vec::new(),
+            replies: Vec::new(),
+            queries_left: 2,
+        };
+        let query = [
+            0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1,
+        ];
+        for _ in 0..2 {
+            let reply = exchange_framed(&mut stub, &query).unwrap();
+            assert_eq!(reply.len(), query.len());
+            assert_eq!(&reply[..2], &query[..2]);
+            assert_eq!(reply[2], 0x81);
+            assert_eq!(&reply[3..], &query[3..]);
+        }
+        // once the resolver has hung up, the exchange fails rather than returning a partial reply
+        assert_eq!(
+            exchange_framed(&mut stub, &query).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+    }
+}
@@ -0,0 +1,642 @@
+//! A minimal HTTP/1.1 client.
+//!
+//! ```ignore
+//! let mut client = http_client::Client::new(&xns);
+//! let response = client.get("https://example.com/index.html")?;
+//! log::info!("{} {}: {}", response.status, response.reason, response.text());
+//! ```
+//!
+//! Every request goes out on a fresh connection with `Connection: close`, and the whole response body
+//! is read into memory, up to a configurable limit. Bodies may be sent with a `Content-Length` or with
+//! chunked transfer encoding, or delimited by the server closing the connection. Redirects are followed,
+//! except from `https` to `http` unless that's allowed, and headers supplied by the caller are only sent
+//! on to the same scheme, host and port. `https` URLs are fetched over the `tls` crate, so the server has to be trusted by the TLS root
+//! store or pinned there. Certificates are checked against the RTC, which `sntp` keeps in sync; until
+//! the RTC has been set, only pinned `https` servers can be reached.
+
+mod url;
+pub use url::*;
+
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write};
+use std::net::IpAddr;
+use std::time::Duration;
+
+const CONNECT_TIMEOUT_MS: u64 = 5000;
+/// Default timeout for every read or write on the connection
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(10_000);
+pub const DEFAULT_MAX_REDIRECTS: usize = 5;
+pub const DEFAULT_MAX_BODY_LEN: usize = 256 * 1024;
+/// Limit on the length of the status line and of each header line
+const MAX_LINE_LEN: usize = 4096;
+const MAX_HEADERS: usize = 64;
+const USER_AGENT: &str = "Precursor/0.9.6";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+}
+impl Method {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    /// Header names and values, in the order they were received
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Where the response came from, after following any redirects
+    pub url: Url,
+}
+impl Response {
+    /// The value of the first header called `name`, which is matched case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+    /// The body as text; invalid UTF-8 is replaced
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+}
+
+/// Everything needed to write out one request
+struct Request<'a> {
+    method: Method,
+    url: Url,
+    headers: &'a [(&'a str, &'a str)],
+    body: Option<&'a [u8]>,
+}
+
+pub struct Client {
+    dns: dns::Dns,
+    tls: tls::Tls,
+    timeout: Option<Duration>,
+    max_redirects: usize,
+    allow_downgrade: bool,
+    max_body_len: usize,
+}
+impl Client {
+    pub fn new(xns: &xous_names::XousNames) -> Client {
+        Client {
+            dns: dns::Dns::new(&xns).expect("couldn't connect to DNS"),
+            tls: tls::Tls::new(&xns),
+            timeout: Some(DEFAULT_TIMEOUT),
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            allow_downgrade: false,
+            max_body_len: DEFAULT_MAX_BODY_LEN,
+        }
+    }
+    /// Sets the timeout for each read and write on the connection; `None` waits forever.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+    /// Sets how many redirects are followed before giving up; 0 returns redirect responses as they are.
+    pub fn set_max_redirects(&mut self, max_redirects: usize) {
+        self.max_redirects = max_redirects;
+    }
+    /// Sets whether a redirect from `https` to `http` is followed. By default it isn't, and the request fails
+    /// with `ErrorKind::PermissionDenied`, as the rest of the exchange would go out in the clear.
+    pub fn set_allow_https_downgrade(&mut self, allow: bool) {
+        self.allow_downgrade = allow;
+    }
+    /// Sets the largest response body that will be accepted
+    pub fn set_max_body_len(&mut self, max_body_len: usize) {
+        self.max_body_len = max_body_len;
+    }
+    /// The TLS client used for `https` URLs, e.g. to manage its root store or set its time
+    pub fn tls(&mut self) -> &mut tls::Tls {
+        &mut self.tls
+    }
+
+    pub fn get(&mut self, url: &str) -> Result<Response> {
+        self.request(Method::Get, url, &[], None)
+    }
+    pub fn post(&mut self, url: &str, content_type: &str, body: &[u8]) -> Result<Response> {
+        self.request(
+            Method::Post,
+            url,
+            &[("Content-Type", content_type)],
+            Some(body),
+        )
+    }
+    /// Sends a request with extra `headers`. `Host`, `Connection`, and `Content-Length` are filled in
+    /// automatically, and must not be part of `headers`. Header names and values can't contain CR or LF.
+    /// The headers are dropped when a redirect leads to another scheme, host, or port.
+    pub fn request(
+        &mut self,
+        method: Method,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: Option<&[u8]>,
+    ) -> Result<Response> {
+        let request = Request {
+            method,
+            url: Url::parse(url)?,
+            headers,
+            body,
+        };
+        let (max_redirects, allow_downgrade) = (self.max_redirects, self.allow_downgrade);
+        follow_redirects(request, max_redirects, allow_downgrade, |request| {
+            self.send(request)
+        })
+    }
+
+    fn send(&mut self, request: &Request) -> Result<Response> {
+        let url = &request.url;
+        if url.scheme == Scheme::Https && !self.tls.time_known() {
+            let host = url.host.trim_end_matches('.');
+            if self.tls.store().get_pin(host)?.is_none() {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    "the time isn't known yet, so https servers can't be authenticated",
+                ));
+            }
+        }
+        let ipaddr = self.dns.lookup(&url.host).map_err(|e| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("couldn't resolve {}: {:?}", url.host, e),
+            )
+        })?;
+        log::debug!("{} {} ({:?})", request.method.as_str(), url, ipaddr);
+        let mut tcp = net::TcpStream::connect_xous(
+            (IpAddr::from(ipaddr), url.port),
+            Some(net::Duration::from_millis(CONNECT_TIMEOUT_MS)),
+            None,
+        )?;
+        let timeout = self
+            .timeout
+            .map(|t| net::Duration::from_millis(t.as_millis() as u64));
+        tcp.set_read_timeout(timeout)?;
+        tcp.set_write_timeout(timeout)?;
+        match url.scheme {
+            Scheme::Http => exchange(tcp, request, self.max_body_len),
+            Scheme::Https => {
+                let mut stream = self.tls.connect(&url.host, tcp)?;
+                let response = exchange(&mut stream, request, self.max_body_len);
+                stream.close().ok();
+                response
+            }
+        }
+    }
+}
+
+/// Sends `request` with `send`, following redirects until a final response arrives. A redirect from
+/// `https` to `http` is an error, unless `allow_downgrade` is set.
+fn follow_redirects<F>(
+    mut request: Request,
+    max_redirects: usize,
+    allow_downgrade: bool,
+    mut send: F,
+) -> Result<Response>
+where
+    F: FnMut(&Request) -> Result<Response>,
+{
+    let mut redirects = 0;
+    loop {
+        let response = send(&request)?;
+        let location = match response.status {
+            301 | 302 | 303 | 307 | 308 => match response.header("Location") {
+                Some(location) => location,
+                None => return Ok(response),
+            },
+            _ => return Ok(response),
+        };
+        if redirects == max_redirects {
+            if max_redirects == 0 {
+                return Ok(response);
+            }
+            return Err(Error::new(ErrorKind::Other, "too many redirects"));
+        }
+        redirects += 1;
+        let next = request.url.join(location)?;
+        log::debug!("{} redirects to {}", request.url, next);
+        if request.url.scheme == Scheme::Https && next.scheme == Scheme::Http && !allow_downgrade {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "refusing to follow a redirect from https to http",
+            ));
+        }
+        // 307 and 308 repeat the request as is; otherwise, like browsers do, follow up with a GET
+        if response.status != 307 && response.status != 308 && request.method != Method::Head {
+            request.method = Method::Get;
+            request.body = None;
+        }
+        // don't hand credentials meant for one origin to another, nor send them in the clear
+        if next.scheme != request.url.scheme
+            || next.host != request.url.host
+            || next.port != request.url.port
+        {
+            request.headers = &[];
+        }
+        request.url = next;
+    }
+}
+
+/// Writes out `request` on `stream`, and reads back the response
+fn exchange<S: Read + Write>(
+    mut stream: S,
+    request: &Request,
+    max_body_len: usize,
+) -> Result<Response> {
+    // a CR or LF would end the header line early, and let whatever follows in as headers of its own
+    let breaks_line = |s: &str| s.contains(&['\r', '\n'][..]);
+    if request
+        .headers
+        .iter()
+        .any(|(name, value)| breaks_line(name) || breaks_line(value))
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "HTTP header names and values can't contain CR or LF",
+        ));
+    }
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: {}\r\nAccept: */*\r\nConnection: close\r\n",
+        request.method.as_str(),
+        request.url.path,
+        request.url.host_header(),
+        USER_AGENT
+    );
+    for (name, value) in request.headers.iter() {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if let Some(body) = request.body {
+        head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    head.push_str("\r\n");
+    let mut message = head.into_bytes();
+    if let Some(body) = request.body {
+        message.extend_from_slice(body);
+    }
+    stream.write_all(&message)?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let (status, reason, headers) = loop {
+        let (status, reason, headers) = read_head(&mut reader)?;
+        // informational responses (e.g. 100 Continue) come ahead of the real one
+        if status >= 200 || status == 101 {
+            break (status, reason, headers);
+        }
+    };
+    let mut response = Response {
+        status,
+        reason,
+        headers,
+        body: Vec::new(),
+        url: request.url.clone(),
+    };
+    if request.method == Method::Head || status == 204 || status == 304 {
+        return Ok(response);
+    }
+    response.body = if response
+        .header("Transfer-Encoding")
+        .map(|te| te.to_ascii_lowercase().contains("chunked"))
+        .unwrap_or(false)
+    {
+        read_chunked(&mut reader, max_body_len)?
+    } else if let Some(len) = response.header("Content-Length") {
+        let len = len
+            .trim()
+            .parse::<usize>()
+            .or(Err(invalid_data("invalid Content-Length")))?;
+        if len > max_body_len {
+            return Err(invalid_data("response body is too long"));
+        }
+        let mut body = vec![0u8; len];
+        reader.read_exact(&mut body)?;
+        body
+    } else {
+        // the body runs until the server closes the connection
+        let mut body = Vec::new();
+        (&mut reader)
+            .take(max_body_len as u64 + 1)
+            .read_to_end(&mut body)?;
+        if body.len() > max_body_len {
+            return Err(invalid_data("response body is too long"));
+        }
+        body
+    };
+    Ok(response)
+}
+
+fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Reads one CRLF-terminated line, without the line ending
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_LINE_LEN as u64)
+        .read_until(b'\n', &mut line)?;
+    if line.last() != Some(&b'\n') {
+        return Err(if line.len() >= MAX_LINE_LEN {
+            invalid_data("HTTP line is too long")
+        } else {
+            Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed in the middle of an HTTP response",
+            )
+        });
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).or(Err(invalid_data("HTTP line is not valid UTF-8")))
+}
+
+/// The status code, reason phrase, and headers of a response
+type Head = (u16, String, Vec<(String, String)>);
+
+/// Reads the status line and headers of a response
+fn read_head<R: BufRead>(reader: &mut R) -> Result<Head> {
+    let status_line = read_line(reader)?;
+    let mut parts = status_line.splitn(3, ' ');
+    if !parts.next().unwrap_or("").starts_with("HTTP/1.") {
+        return Err(invalid_data("not an HTTP/1.x response"));
+    }
+    let status = parts
+        .next()
+        .and_then(|s| s.parse::<u16>().ok())
+        .filter(|s| (100..1000).contains(s))
+        .ok_or_else(|| invalid_data("invalid HTTP status"))?;
+    let reason = parts.next().unwrap_or("").to_string();
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            return Ok((status, reason, headers));
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(invalid_data("too many HTTP headers"));
+        }
+        match line.split_once(':') {
+            Some((name, value)) => {
+                headers.push((name.trim().to_string(), value.trim().to_string()))
+            }
+            None => return Err(invalid_data("malformed HTTP header")),
+        }
+    }
+}
+
+/// Reads a body sent with chunked transfer encoding, discarding any trailers
+fn read_chunked<R: BufRead>(reader: &mut R, max_body_len: usize) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16).or(Err(invalid_data("invalid chunk size")))?;
+        if size == 0 {
+            break;
+        }
+        if body.len() + size > max_body_len {
+            return Err(invalid_data("response body is too long"));
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        if !read_line(reader)?.is_empty() {
+            return Err(invalid_data("chunk is longer than its size"));
+        }
+    }
+    while !read_line(reader)?.is_empty() {}
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    //! These run against a stand-in HTTP server on the host's loopback interface, which answers
+    //! each connection it gets with the next of a list of canned responses.
+    use super::*;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::thread::JoinHandle;
+
+    /// Serves `responses`, one per connection, and returns the requests that were received.
+    fn serve(responses: Vec<&'static str>) -> (SocketAddr, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request = String::new();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(len) = line.strip_prefix("Content-Length: ") {
+                        content_length = len.trim().parse().unwrap();
+                    }
+                    request.push_str(&line);
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0u8; content_length];
+                reader.read_exact(&mut body).unwrap();
+                request.push_str(&String::from_utf8(body).unwrap());
+                requests.push(request);
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+        (addr, handle)
+    }
+
+    fn fetch(
+        addr: SocketAddr,
+        method: Method,
+        path: &str,
+        body: Option<&[u8]>,
+    ) -> Result<Response> {
+        let request = Request {
+            method,
+            url: Url::parse(&format!("http://{}{}", addr, path)).unwrap(),
+            headers: &[("Authorization", "secret")],
+            body,
+        };
+        follow_redirects(request, DEFAULT_MAX_REDIRECTS, false, |request| {
+            let stream = TcpStream::connect((request.url.host.as_str(), request.url.port))?;
+            stream.set_read_timeout(Some(Duration::from_millis(500)))?;
+            exchange(stream, request, 1024)
+        })
+    }
+
+    #[test]
+    fn content_length() {
+        let (addr, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nX-Test: a:b\r\n\r\nhello",
+        ]);
+        let response = fetch(addr, Method::Get, "/index.html", None).unwrap();
+        assert_eq!((response.status, response.reason.as_str()), (200, "OK"));
+        assert_eq!(response.header("x-test"), Some("a:b"));
+        assert_eq!(response.text(), "hello");
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /index.html HTTP/1.1\r\n"));
+        assert!(requests[0].contains(&format!("Host: {}\r\n", addr)));
+    }
+
+    #[test]
+    fn chunked_and_continue() {
+        let (addr, _) = serve(vec![
+            "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\n\r\n\
+             5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nTrailer: x\r\n\r\n",
+        ]);
+        let response = fetch(addr, Method::Post, "/", Some(b"data")).unwrap();
+        assert_eq!(response.status, 201);
+        assert_eq!(response.text(), "hello, world");
+    }
+
+    #[test]
+    fn body_until_close() {
+        let (addr, _) = serve(vec!["HTTP/1.0 200 OK\r\n\r\nuntil the end"]);
+        assert_eq!(
+            fetch(addr, Method::Get, "/", None).unwrap().text(),
+            "until the end"
+        );
+        let (addr, _) = serve(vec!["HTTP/1.1 200 OK\r\nContent-Length: 5000\r\n\r\n"]);
+        assert_eq!(
+            fetch(addr, Method::Get, "/", None).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn redirects() {
+        let (addr, server) = serve(vec![
+            "HTTP/1.1 302 Found\r\nLocation: next?a=b\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 308 Permanent Redirect\r\nLocation: /final\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+        ]);
+        let response = fetch(addr, Method::Post, "/dir/form", Some(b"x=1")).unwrap();
+        assert_eq!(response.text(), "ok");
+        assert_eq!(response.url.path, "/final");
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("POST /dir/form ") && requests[0].ends_with("x=1"));
+        // the POST becomes a GET after a 302, which the 308 then preserves
+        assert!(
+            requests[1].starts_with("GET /dir/next?a=b ")
+                && !requests[1].contains("Content-Length")
+        );
+        assert!(
+            requests[2].starts_with("GET /final ") && requests[2].contains("Authorization: secret")
+        );
+
+        let (addr, _) = serve(vec![
+            "HTTP/1.1 301 Moved\r\nLocation: /loop\r\n\r\n";
+            DEFAULT_MAX_REDIRECTS + 1
+        ]);
+        assert!(fetch(addr, Method::Get, "/loop", None).is_err());
+    }
+
+    /// Follows redirects to each of `locations` in turn, starting from `url`, without any network. Returns
+    /// the outcome, and the URL and headers of each request made.
+    fn follow(
+        url: &str,
+        locations: &[&str],
+        allow_downgrade: bool,
+    ) -> (Result<Response>, Vec<(String, usize)>) {
+        let request = Request {
+            method: Method::Get,
+            url: Url::parse(url).unwrap(),
+            headers: &[("Authorization", "secret")],
+            body: None,
+        };
+        let mut sent = Vec::new();
+        let result = follow_redirects(request, DEFAULT_MAX_REDIRECTS, allow_downgrade, |request| {
+            sent.push((request.url.to_string(), request.headers.len()));
+            let headers = match locations.get(sent.len() - 1) {
+                Some(location) => vec![("Location".to_string(), location.to_string())],
+                None => Vec::new(),
+            };
+            Ok(Response {
+                status: if headers.is_empty() { 200 } else { 302 },
+                reason: String::new(),
+                headers,
+                body: Vec::new(),
+                url: request.url.clone(),
+            })
+        });
+        (result, sent)
+    }
+
+    #[test]
+    fn redirect_origins() {
+        // headers survive a redirect on the same origin, but not one to another port, scheme or host
+        let (result, sent) = follow(
+            "http://example.com/",
+            &["/a", "http://example.com:8080/b"],
+            false,
+        );
+        assert_eq!(result.unwrap().status, 200);
+        assert_eq!(sent[1], ("http://example.com/a".to_string(), 1));
+        assert_eq!(sent[2], ("http://example.com:8080/b".to_string(), 0));
+        let (_, sent) = follow("http://example.com/", &["https://example.com/"], false);
+        assert_eq!(sent[1], ("https://example.com/".to_string(), 0));
+        let (_, sent) = follow("https://example.com/", &["https://example.org/"], false);
+        assert_eq!(sent[1], ("https://example.org/".to_string(), 0));
+
+        // a downgrade to http is refused, unless allowed, and then the headers don't come along
+        let (result, sent) = follow("https://example.com/", &["http://example.com/"], false);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(sent.len(), 1);
+        let (result, sent) = follow("https://example.com/", &["http://example.com/"], true);
+        assert_eq!(result.unwrap().status, 200);
+        assert_eq!(sent[1], ("http://example.com/".to_string(), 0));
+    }
+
+    #[test]
+    fn header_injection() {
+        for headers in [
+            [("X-Test", "a\r\nInjected: yes")],
+            [("X-Test", "a\nb")],
+            [("X-Test\r\nInjected", "yes")],
+        ]
+        .iter()
+        {
+            let request = Request {
+                method: Method::Get,
+                url: Url::parse("http://example.com/").unwrap(),
+                headers,
+                body: None,
+            };
+            let mut stream = std::io::Cursor::new(Vec::new());
+            let err = exchange(&mut stream, &request, 1024).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            // nothing may go out on the wire
+            assert!(stream.get_ref().is_empty());
+        }
+    }
+
+    #[test]
+    fn timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _server = std::thread::spawn(move || {
+            let (_stream, _) = listener.accept().unwrap();
+            std::thread::sleep(Duration::from_millis(2000));
+        });
+        let kind = fetch(addr, Method::Get, "/", None).unwrap_err().kind();
+        assert!(kind == ErrorKind::WouldBlock || kind == ErrorKind::TimedOut);
+    }
+}
@@ -9,11 +9,22 @@ pub use ping::NetPingCallback;
 // needed to keep hosted mode quiet, since the Tcp implementation is a bodge
 #[allow(unused_imports)]
 pub(crate) use tcp::*;
+pub(crate) mod poll;
+pub use poll::POLL_MAX_SOCKETS;
+#[allow(unused_imports)]
+pub(crate) use poll::*;
+pub(crate) mod sockets;
+// `SocketInfo` is only used by the library
+#[allow(unused_imports)]
+pub use sockets::{SocketInfo, SocketKind, MAX_SOCKETS_PER_PROCESS};
+#[allow(unused_imports)]
+pub(crate) use sockets::*;
+pub(crate) mod networks;
+pub use networks::{SavedNetwork, AP_META_DICT_NAME};
 
 use com::SsidRecord;
 use rkyv::{Archive, Deserialize, Serialize};
 use smoltcp::wire::IpAddress;
-use std::convert::TryInto;
 use std::fmt;
 use std::fmt::Debug;
 use std::io::Write;
@@ -147,6 +158,55 @@ pub(crate) enum Opcode {
     /// of the opcode. This may be any kind of message (scalar, blockingscalar, memory,
     /// etc.)
     StdTcpClose = 33,
+
+    /// UDP broadcast and multicast options. Like the TTL calls, these are keyed by the local port of the socket.
+    /// The setters are scalars of (value, port); the getters are blocking scalars of (port) that return
+    /// the value, or `usize::MAX` if the port isn't bound.
+    UdpSetBroadcast = 34,
+    UdpGetBroadcast = 35,
+    UdpSetMulticastLoopV4 = 36,
+    UdpGetMulticastLoopV4 = 37,
+    UdpSetMulticastTtlV4 = 38,
+    UdpGetMulticastTtlV4 = 39,
+    /// Join or leave an IPv4 multicast group. Blocking scalar of (port, group, interface), where the
+    /// addresses are packed as big-endian u32s. Returns a `NetError` code.
+    UdpJoinMulticastV4 = 40,
+    UdpLeaveMulticastV4 = 41,
+
+    /// IPv6 link management. Both take an `Ipv6Conf`: `GetIpv6Config` fills it in, and `SetIpv6Static`
+    /// applies its `static_addr`, `static_prefix_len` and `gateway` fields, returning a `NetMemResponse`.
+    GetIpv6Config = 42,
+    SetIpv6Static = 43,
+
+    /// Waits until any of a set of sockets is ready. Takes a `NetPoll`, which is returned with the `ready`
+    /// flags of its entries filled in once at least one of them is ready, or its timeout has passed.
+    Poll = 44,
+
+    /// Copies data at the head of a TCP connection's receive buffer without consuming it. Takes a
+    /// `NetTcpPeek`, which is returned with `data` and `len` filled in.
+    TcpPeek = 45,
+
+    /// Describes the sockets held by the Net server and the processes that own them. Takes a `NetSocketList`.
+    SocketList = 46,
+    /// Closes every socket the calling process has opened through this crate. Sent when the last `NetConn` of
+    /// a process is dropped, so sockets that were leaked don't stay open. Blocking scalar that returns the
+    /// number of sockets closed.
+    ReleaseProcess = 47,
+}
+
+/// IPv6 addressing. The EC only provides an IPv4 config, so IPv6 addresses are worked out by the Net crate:
+/// a link-local address from the MAC address, a global address by SLAAC from router advertisements, and
+/// optionally a static address. Addresses are in network byte order.
+#[derive(Debug, Archive, Serialize, Deserialize, Copy, Clone, Default)]
+pub struct Ipv6Conf {
+    pub link_local: Option<[u8; 16]>,
+    /// address configured by SLAAC, always with a 64-bit prefix
+    pub slaac: Option<[u8; 16]>,
+    /// statically configured address; it is preferred over the SLAAC address as a source address
+    pub static_addr: Option<[u8; 16]>,
+    pub static_prefix_len: u8,
+    /// default router. A static router takes precedence over one learned from router advertisements.
+    pub gateway: Option<[u8; 16]>,
 }
 
 #[derive(Debug, Archive, Serialize, Deserialize, Copy, Clone, Default)]
@@ -209,6 +269,7 @@ pub(crate) enum NetMemResponse {
     AlreadyUsed,
 }
 
+#[derive(Debug, num_derive::FromPrimitive, num_derive::ToPrimitive, PartialEq, Eq)]
 #[repr(C)]
 pub enum NetError {
     Ok = 0,
@@ -282,16 +343,7 @@ impl From<NetIpAddr> for IpAddress {
             NetIpAddr::Ipv4([a, b, c, d]) => {
                 IpAddress::Ipv4(smoltcp::wire::Ipv4Address::new(a, b, c, d))
             }
-            NetIpAddr::Ipv6(ipv6) => IpAddress::Ipv6(smoltcp::wire::Ipv6Address::new(
-                u16::from_be_bytes(ipv6[0..1].try_into().unwrap()),
-                u16::from_be_bytes(ipv6[2..3].try_into().unwrap()),
-                u16::from_be_bytes(ipv6[4..5].try_into().unwrap()),
-                u16::from_be_bytes(ipv6[6..7].try_into().unwrap()),
-                u16::from_be_bytes(ipv6[8..9].try_into().unwrap()),
-                u16::from_be_bytes(ipv6[10..11].try_into().unwrap()),
-                u16::from_be_bytes(ipv6[12..13].try_into().unwrap()),
-                u16::from_be_bytes(ipv6[14..15].try_into().unwrap()),
-            )),
+            NetIpAddr::Ipv6(ipv6) => IpAddress::Ipv6(smoltcp::wire::Ipv6Address(ipv6)),
         }
     }
 }
@@ -306,7 +358,7 @@ pub fn ipaddress_to_ipaddr(other: IpAddress) -> IpAddr {
             let octets = ipv6.0;
             IpAddr::V6(Ipv6Addr::from(octets))
         }
-        _ => unimplemented!(),
+        _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
     }
 }
 
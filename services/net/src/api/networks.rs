@@ -0,0 +1,97 @@
+use std::io::{Read, Write};
+
+/// What the connection manager knows about each saved network, beyond its password. Keyed by SSID, like
+/// the passwords in `AP_DICT_NAME`; a network with no entry here has the default settings.
+pub const AP_META_DICT_NAME: &'static str = "wlan.network_meta";
+
+/// The length of an encoded `SavedNetwork`
+const SAVED_NETWORK_LEN: usize = 16;
+/// Bumped whenever the encoding changes; entries with another version are treated as missing
+const SAVED_NETWORK_VERSION: u8 = 1;
+const FLAG_HIDDEN: u8 = 0x1;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct SavedNetwork {
+    /// Networks with a higher priority are joined first, when more than one is in range
+    pub priority: u8,
+    /// The network doesn't broadcast its SSID, so it's tried even when a scan doesn't turn it up
+    pub hidden: bool,
+    /// When the network was last joined, in seconds since 2000-01-01 by the RTC; 0 if it never was.
+    /// Among networks of the same priority, the one joined most recently is tried first.
+    pub last_success: u64,
+}
+impl SavedNetwork {
+    pub(crate) fn encode(&self) -> [u8; SAVED_NETWORK_LEN] {
+        let mut record = [0u8; SAVED_NETWORK_LEN];
+        record[0] = SAVED_NETWORK_VERSION;
+        record[1] = self.priority;
+        record[2] = if self.hidden { FLAG_HIDDEN } else { 0 };
+        record[8..].copy_from_slice(&self.last_success.to_le_bytes());
+        record
+    }
+    pub(crate) fn decode(record: &[u8]) -> Option<SavedNetwork> {
+        if record.len() != SAVED_NETWORK_LEN || record[0] != SAVED_NETWORK_VERSION {
+            return None;
+        }
+        let mut last_success = [0u8; 8];
+        last_success.copy_from_slice(&record[8..]);
+        Some(SavedNetwork {
+            priority: record[1],
+            hidden: record[2] & FLAG_HIDDEN != 0,
+            last_success: u64::from_le_bytes(last_success),
+        })
+    }
+    /// Reads the settings for `ssid`, falling back to the defaults if there are none
+    pub fn read(pddb: &mut pddb::Pddb, ssid: &str) -> SavedNetwork {
+        let mut record = Vec::new();
+        match pddb.get(
+            AP_META_DICT_NAME,
+            ssid,
+            None,
+            false,
+            false,
+            None,
+            Some(|| {}),
+        ) {
+            Ok(mut key) if key.read_to_end(&mut record).is_ok() => {
+                SavedNetwork::decode(&record).unwrap_or_default()
+            }
+            _ => SavedNetwork::default(),
+        }
+    }
+    pub fn write(&self, pddb: &mut pddb::Pddb, ssid: &str) -> std::io::Result<()> {
+        let mut key = pddb.get(
+            AP_META_DICT_NAME,
+            ssid,
+            None,
+            true,
+            true,
+            Some(SAVED_NETWORK_LEN),
+            Some(|| {}),
+        )?;
+        key.write_all(&self.encode())?;
+        key.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_network_encoding() {
+        let network = SavedNetwork {
+            priority: 7,
+            hidden: true,
+            last_success: 719_163_201,
+        };
+        assert_eq!(SavedNetwork::decode(&network.encode()), Some(network));
+        let default = SavedNetwork::default();
+        assert_eq!(SavedNetwork::decode(&default.encode()), Some(default));
+        // short records, and records from some other version, are ignored
+        assert_eq!(SavedNetwork::decode(&network.encode()[..8]), None);
+        let mut record = network.encode();
+        record[0] = SAVED_NETWORK_VERSION + 1;
+        assert_eq!(SavedNetwork::decode(&record), None);
+    }
+}
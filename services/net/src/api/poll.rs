@@ -0,0 +1,54 @@
+use rkyv::{Archive, Deserialize, Serialize};
+use crate::api::*;
+
+/// The most sockets that can be waited on with a single call to `poll()`
+pub const POLL_MAX_SOCKETS: usize = 16;
+/// The most polls the Net server holds on to at once, and the most of those that can come from one process
+pub(crate) const POLL_MAX_WAITING: usize = 16;
+pub(crate) const POLL_MAX_WAITING_PER_PROCESS: usize = 4;
+
+/// Readiness flags, used both for the events a caller is interested in and for the ones that are reported
+pub(crate) const POLL_READABLE: u8 = 0x1;
+pub(crate) const POLL_WRITABLE: u8 = 0x2;
+/// The connection is closed, or the peer has closed its end. Always reported, whether asked for or not.
+pub(crate) const POLL_CLOSED: u8 = 0x4;
+/// The socket isn't known to the Net server. Always reported, whether asked for or not.
+pub(crate) const POLL_INVALID: u8 = 0x8;
+
+/// Sockets are identified the same way as in the calls that manage them
+#[derive(Debug, Archive, Serialize, Deserialize, Copy, Clone)]
+pub(crate) enum NetPollSocket {
+    TcpStream {
+        ip_addr: NetIpAddr,
+        remote_port: u16,
+        local_port: u16,
+    },
+    TcpListener {
+        local_port: u16,
+    },
+    /// Clones of a UDP socket share its port, so they are told apart by the SID of their callback server
+    UdpSocket {
+        local_port: u16,
+        cb_sid: [u32; 4],
+    },
+}
+
+#[derive(Debug, Archive, Serialize, Deserialize, Copy, Clone)]
+pub(crate) struct NetPollEntry {
+    pub(crate) socket: NetPollSocket,
+    /// The number of deliveries (TCP data, incoming connections, or UDP datagrams) the caller has
+    /// received from the Net server on this socket. While the Net server has sent more than that, some
+    /// are still on their way and the socket is readable.
+    pub(crate) rx_seen: u32,
+    pub(crate) interest: u8,
+    pub(crate) ready: u8,
+}
+
+#[derive(Debug, Archive, Serialize, Deserialize, Copy, Clone)]
+pub(crate) struct NetPoll {
+    pub(crate) entries: [Option<NetPollEntry>; POLL_MAX_SOCKETS],
+    /// `None` waits forever; `Some(0)` returns right away
+    pub(crate) timeout_ms: Option<u64>,
+    /// filled in by the Net server; `OutOfMemory` if it has too many polls waiting to take on another
+    pub(crate) result: Option<NetMemResponse>,
+}
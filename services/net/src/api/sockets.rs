@@ -0,0 +1,84 @@
+use rkyv::{Archive, Deserialize, Serialize};
+use smoltcp::socket::TcpState;
+use std::net::{IpAddr, SocketAddr};
+use crate::api::*;
+
+/// The most sockets a process can hold open at once. TCP streams, TCP listeners, UDP sockets and the TCP
+/// connections made through libstd all count; clones of a UDP socket share its socket, so they don't.
+pub const MAX_SOCKETS_PER_PROCESS: usize = 16;
+
+/// The most sockets that are described by a single `Opcode::SocketList` call
+pub(crate) const SOCKET_LIST_MAX: usize = 32;
+
+#[derive(Debug, Archive, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+pub enum SocketKind {
+    TcpStream,
+    TcpListener,
+    UdpSocket,
+    /// A TCP connection made through libstd
+    StdTcpStream,
+}
+
+/// TCP states, in the order they are encoded in a `NetSocketRecord`
+pub(crate) const TCP_STATES: [TcpState; 11] = [
+    TcpState::Closed,
+    TcpState::Listen,
+    TcpState::SynSent,
+    TcpState::SynReceived,
+    TcpState::Established,
+    TcpState::FinWait1,
+    TcpState::FinWait2,
+    TcpState::CloseWait,
+    TcpState::Closing,
+    TcpState::LastAck,
+    TcpState::TimeWait,
+];
+
+#[derive(Debug, Archive, Serialize, Deserialize, Copy, Clone)]
+pub(crate) struct NetSocketRecord {
+    pub(crate) kind: SocketKind,
+    /// PID of the process that owns the socket, or 0 if it isn't known
+    pub(crate) pid: u8,
+    pub(crate) local_port: u16,
+    pub(crate) remote_addr: Option<NetIpAddr>,
+    pub(crate) remote_port: u16,
+    /// index into `TCP_STATES`; not used for UDP sockets
+    pub(crate) tcp_state: u8,
+}
+
+#[derive(Debug, Archive, Serialize, Deserialize, Copy, Clone, Default)]
+pub(crate) struct NetSocketList {
+    pub(crate) list: [Option<NetSocketRecord>; SOCKET_LIST_MAX],
+    /// the number of sockets the Net server holds, which can be more than fit in `list`
+    pub(crate) total: u32,
+}
+
+/// A socket held by the Net server, as reported by `NetManager::socket_list()`
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone)]
+pub struct SocketInfo {
+    pub kind: SocketKind,
+    /// the process that owns the socket
+    pub pid: Option<xous::PID>,
+    pub local_port: u16,
+    /// the other end of a TCP connection
+    pub remote: Option<SocketAddr>,
+    /// `None` for UDP sockets
+    pub tcp_state: Option<TcpState>,
+}
+impl From<&NetSocketRecord> for SocketInfo {
+    fn from(record: &NetSocketRecord) -> SocketInfo {
+        SocketInfo {
+            kind: record.kind,
+            pid: xous::PID::new(record.pid),
+            local_port: record.local_port,
+            remote: record
+                .remote_addr
+                .map(|addr| SocketAddr::new(IpAddr::from(addr), record.remote_port)),
+            tcp_state: match record.kind {
+                SocketKind::UdpSocket => None,
+                _ => TCP_STATES.get(record.tcp_state as usize).copied(),
+            },
+        }
+    }
+}
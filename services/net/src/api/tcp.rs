@@ -48,6 +48,8 @@ pub(crate) enum NetTcpCallback {
     RxData,
     ListenerActive,
     Drop,
+    /// sent by the Net server to check that the callback server is still around; there is nothing to do
+    Ping,
 }
 
 /// The data field for a UDP response is limited to less than the theoretical
@@ -76,3 +78,17 @@ pub(crate) struct NetTcpTransmit {
     pub data: [u8; TCP_BUFFER_SIZE],
     pub result: Option<NetMemResponse>,
 }
+
+#[derive(Debug, Archive, Serialize, Deserialize, Copy, Clone)]
+pub(crate) struct NetTcpPeek {
+    pub(crate) ip_addr: NetIpAddr,
+    pub(crate) remote_port: u16,
+    pub(crate) local_port: u16,
+    /// The number of Rx deliveries the caller has received. While the Net server has sent more than that,
+    /// the data at the head of the stream is still on its way to the caller, so nothing is peeked.
+    pub(crate) rx_seen: u32,
+    /// The most bytes wanted; changed to the number of bytes copied into `data` on return
+    pub(crate) len: u16,
+    pub(crate) data: [u8; TCP_BUFFER_SIZE],
+    pub(crate) result: Option<NetMemResponse>,
+}
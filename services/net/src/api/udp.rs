@@ -49,5 +49,7 @@ pub(crate) struct NetUdpBind {
 pub(crate) enum NetUdpCallback {
     RxData,
     Drop,
+    /// sent by the Net server to check that the callback server is still around; there is nothing to do
+    Ping,
 }
 
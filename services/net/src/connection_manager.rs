@@ -1,15 +1,17 @@
 use crate::api::*;
 use std::sync::Arc;
 use core::sync::atomic::{AtomicU32, AtomicBool, Ordering};
-use com::{WlanStatus, WlanStatusIpc, SsidRecord};
+use com::{Connectivity, WlanStatus, WlanStatusIpc, SsidRecord};
 use com_rs_ref::{ConnectResult, LinkState};
 use net::MIN_EC_REV;
 use xous::{msg_blocking_scalar_unpack, msg_scalar_unpack, send_message, try_send_message, Message};
 use xous_ipc::Buffer;
 use num_traits::*;
 use std::io::Read;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
 use crate::ComIntSources;
+use crate::connectivity;
 #[cfg(any(target_os = "none", target_os = "xous"))]
 use locales::t;
 
@@ -20,6 +22,11 @@ const BOOT_POLL_INTERVAL_MS: usize = 4_758; // a slightly faster poll during boo
 const POLL_INTERVAL_MS: usize = 7_151; // stagger slightly off of an integer-seconds interval to even out loads. impacts rssi update frequency.
 const INTERVALS_BEFORE_RETRY: usize = 3; // how many poll intervals we'll wait before we give up and try a new AP
 const SCAN_COUNT_MAX: usize = 5;
+/// how long we wait to try a network again after it fails to join. This doubles with every failure in a row, up to `BACKOFF_MAX_MS`.
+const BACKOFF_BASE_MS: u64 = 10_000;
+const BACKOFF_MAX_MS: u64 = 600_000;
+/// how many poll intervals we wait before probing again, while a network doesn't reach the internet (e.g. until the user signs in to a captive portal)
+const PROBE_RETRY_INTERVALS: usize = 8;
 
 #[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug)]
 pub(crate) enum ConnectionManagerOpcode {
@@ -31,6 +38,7 @@ pub(crate) enum ConnectionManagerOpcode {
     FetchSsidList,
     ComInt,
     SuspendResume,
+    ProbeResult,
     Quit,
 }
 #[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug)]
@@ -56,13 +64,29 @@ enum SsidScanState {
     Idle,
     Scanning,
 }
+/// Tracks the failed joins of a saved network, so that one that's out of reach or has the wrong password
+/// doesn't keep us from the others.
+#[derive(Default, Debug, Copy, Clone)]
+struct JoinBackoff {
+    failures: u32,
+    /// the ticktimer time before which the network isn't tried again
+    retry_at: u64,
+}
+impl JoinBackoff {
+    fn failed(&mut self, now: u64) {
+        self.failures = self.failures.saturating_add(1);
+        let delay = (BACKOFF_BASE_MS << (self.failures - 1).min(16)).min(BACKOFF_MAX_MS);
+        self.retry_at = now + delay;
+    }
+}
 
 pub(crate) fn connection_manager(sid: xous::SID, activity_interval: Arc<AtomicU32>) {
     let tt = ticktimer_server::Ticktimer::new().unwrap();
     let xns = xous_names::XousNames::new().unwrap();
-    let mut com = com::Com::new(&xns).unwrap();
+    let mut com = crate::device::Com::new(&xns).unwrap();
     let netmgr = net::NetManager::new();
-    let mut pddb = pddb::Pddb::new();
+    let mut pddb = pddb::Pddb::new().expect("couldn't connect to the PDDB");
+    let llio = llio::Llio::new(&xns);
     let self_cid = xous::connect(sid).unwrap();
     // give the system some time to boot before trying to run a check on the EC minimum version, as it is in reset on boot
     tt.sleep_ms(POLL_INTERVAL_MS).unwrap();
@@ -91,9 +115,16 @@ pub(crate) fn connection_manager(sid: xous::SID, activity_interval: Arc<AtomicU3
     let mut wifi_state = WifiState::Unknown;
     let mut last_wifi_state = wifi_state;
     let mut ssid_list = HashMap::<String, u8>::new();
-    let mut ssid_attempted = HashSet::<String>::new();
+    let mut backoff = HashMap::<String, JoinBackoff>::new();
+    // the saved network we're in the middle of joining
+    let mut joining: Option<String> = None;
     let mut wait_count = 0;
     let mut scan_count = 0;
+    let mut connectivity = Connectivity::Unknown;
+    // bumped on every new connection, so that a probe of an earlier connection can't report on this one
+    let mut probe_generation: usize = 0;
+    let mut probing = false;
+    let mut probe_wait = 0;
 
     let run_sid = xous::create_server().unwrap();
     let run_cid = xous::connect(run_sid).unwrap();
@@ -106,7 +137,7 @@ pub(crate) fn connection_manager(sid: xous::SID, activity_interval: Arc<AtomicU3
         let pumping = pumping.clone();
         move || {
             let tt = ticktimer_server::Ticktimer::new().unwrap();
-            let pddb = pddb::Pddb::new();
+            let pddb = pddb::Pddb::new().expect("couldn't connect to the PDDB");
             while !pddb.is_mounted() {
                 tt.sleep_ms(1103).unwrap(); // don't pump the loop until the PDDB has been mounted, but check back regularly
             }
@@ -177,10 +208,8 @@ pub(crate) fn connection_manager(sid: xous::SID, activity_interval: Arc<AtomicU3
                     LinkState::WFXError => {
                         // reset the stats cache, and update subscribers that we're disconnected
                         wifi_stats_cache = WlanStatus::from_ipc(WlanStatusIpc::default());
-                        for &sub in status_subscribers.keys() {
-                            let buf = Buffer::into_buf(com::WlanStatusIpc::from_status(wifi_stats_cache)).or(Err(xous::Error::InternalError)).unwrap();
-                            buf.send(sub, WifiStateCallback::Update.to_u32().unwrap()).or(Err(xous::Error::InternalError)).unwrap();
-                        }
+                        connectivity = Connectivity::Unknown;
+                        notify_subscribers(&status_subscribers, wifi_stats_cache);
                         wifi_state = WifiState::Error;
                     }
                     _ => { // should approximately be a "disconnected" state.
@@ -190,10 +219,8 @@ pub(crate) fn connection_manager(sid: xous::SID, activity_interval: Arc<AtomicU3
                                 netmgr.reset();
                                 // reset the stats cache, and update subscribers that we're disconnected
                                 wifi_stats_cache = WlanStatus::from_ipc(WlanStatusIpc::default());
-                                for &sub in status_subscribers.keys() {
-                                    let buf = Buffer::into_buf(com::WlanStatusIpc::from_status(wifi_stats_cache)).or(Err(xous::Error::InternalError)).unwrap();
-                                    buf.send(sub, WifiStateCallback::Update.to_u32().unwrap()).or(Err(xous::Error::InternalError)).unwrap();
-                                }
+                                connectivity = Connectivity::Unknown;
+                                notify_subscribers(&status_subscribers, wifi_stats_cache);
                                 wifi_state = WifiState::Disconnected;
                                 // kick off an SSID scan
                                 if scan_state == SsidScanState::Idle {
@@ -234,11 +261,15 @@ pub(crate) fn connection_manager(sid: xous::SID, activity_interval: Arc<AtomicU3
                                 ConnectResult::Error => WifiState::Error,
                                 ConnectResult::Pending => WifiState::Error,
                             };
+                            if wifi_state != WifiState::WaitDhcp {
+                                join_failed(&mut backoff, &mut joining, tt.elapsed_ms());
+                            }
                             log::info!("comint new wifi state: {:?}", wifi_state);
                         }
                         ComIntSources::Disconnect => {
                             log::info!("{:?}", source);
                             ssid_list.clear(); // clear the ssid list because a likely cause of disconnect is we've moved out of range
+                            connectivity = Connectivity::Unknown;
                             com.set_ssid_scanning(true).unwrap();
                             scan_state = SsidScanState::Scanning;
                             wifi_state = WifiState::Disconnected;
@@ -273,16 +304,30 @@ pub(crate) fn connection_manager(sid: xous::SID, activity_interval: Arc<AtomicU3
                             // this is the "first" path -- it's hit immediately on connect.
                             // relay status updates to any subscribers that want to know if a state has changed
                             wifi_stats_cache = com.wlan_status().unwrap();
-                            log::debug!("stats update: {:?}", wifi_stats_cache);
-                            for &sub in status_subscribers.keys() {
-                                let buf = Buffer::into_buf(com::WlanStatusIpc::from_status(wifi_stats_cache)).or(Err(xous::Error::InternalError)).unwrap();
-                                buf.send(sub, WifiStateCallback::Update.to_u32().unwrap()).or(Err(xous::Error::InternalError)).unwrap();
-                            }
                             if wifi_stats_cache.ipv4.dhcp == com_rs_ref::DhcpState::Bound {
+                                if wifi_state != WifiState::Connected {
+                                    // a new connection: note the success, and check that it actually reaches the internet
+                                    if let Some(ssid) = joining.take() {
+                                        backoff.remove(&ssid);
+                                        let mut network = SavedNetwork::read(&mut pddb, &ssid);
+                                        if let Ok(dt) = llio.read_rtc_blocking() {
+                                            network.last_success = rtc_secs(&dt);
+                                        }
+                                        network.write(&mut pddb, &ssid).unwrap_or_else(|e| log::warn!("couldn't update {}: {:?}", ssid, e));
+                                    }
+                                    connectivity = Connectivity::Unknown;
+                                    probe_generation += 1;
+                                    probing = true;
+                                    probe_wait = 0;
+                                    start_probe(self_cid, wifi_stats_cache.ipv4.dns1, probe_generation);
+                                }
                                 wifi_state = WifiState::Connected;
                             } else {
                                 wifi_state = WifiState::WaitDhcp;
                             }
+                            wifi_stats_cache.connectivity = connectivity;
+                            log::debug!("stats update: {:?}", wifi_stats_cache);
+                            notify_subscribers(&status_subscribers, wifi_stats_cache);
                             log::debug!("comint new wifi state: {:?}", wifi_state);
                         }
                         ComIntSources::WfxErr => {
@@ -305,23 +350,22 @@ pub(crate) fn connection_manager(sid: xous::SID, activity_interval: Arc<AtomicU3
                             log::debug!("sending disconnect update to subscribers");
                             // reset the stats cache, and update subscribers that we're disconnected
                             wifi_stats_cache = WlanStatus::from_ipc(WlanStatusIpc::default());
-                            for &sub in status_subscribers.keys() {
-                                let buf = Buffer::into_buf(com::WlanStatusIpc::from_status(wifi_stats_cache)).or(Err(xous::Error::InternalError)).unwrap();
-                                buf.send(sub, WifiStateCallback::Update.to_u32().unwrap()).or(Err(xous::Error::InternalError)).unwrap();
-                            }
+                            connectivity = Connectivity::Unknown;
+                            notify_subscribers(&status_subscribers, wifi_stats_cache);
                         }
 
-                        if let Ok(ap_list_vec) = pddb.list_keys(AP_DICT_NAME, None) {
-                            let mut ap_list = HashSet::<String>::new();
-                            for ap in ap_list_vec {
-                                ap_list.insert(ap);
-                            }
+                        if let Ok(ap_list) = pddb.list_keys(AP_DICT_NAME, None) {
                             match wifi_state {
                                 WifiState::Unknown | WifiState::Disconnected | WifiState::InvalidAp | WifiState::InvalidAuth => {
                                     if (scan_state == SsidScanState::Idle) || scan_count > SCAN_COUNT_MAX {
                                         scan_count = 0;
+                                        let mut saved = HashMap::<String, SavedNetwork>::new();
+                                        for ap in ap_list {
+                                            let network = SavedNetwork::read(&mut pddb, &ap);
+                                            saved.insert(ap, network);
+                                        }
                                         // wait until we're done scanning before trying to connect
-                                        if let Some(ssid) = get_next_ssid(&mut ssid_list, &mut ssid_attempted, ap_list) {
+                                        if let Some(ssid) = get_next_ssid(&saved, &ssid_list, &backoff, tt.elapsed_ms()) {
                                             let mut wpa_pw_file = pddb.get(AP_DICT_NAME, &ssid, None, false, false, None, Some(||{})).expect("couldn't retrieve AP password");
                                             let mut wp_pw_raw = [0u8; com::api::WF200_PASS_MAX_LEN];
                                             if let Ok(readlen) = wpa_pw_file.read(&mut wp_pw_raw) {
@@ -331,6 +375,7 @@ pub(crate) fn connection_manager(sid: xous::SID, activity_interval: Arc<AtomicU3
                                                 com.wlan_set_pass(pw).expect("couldn't set password");
                                                 com.wlan_join().expect("couldn't issue join command");
                                                 wifi_state = WifiState::Connecting;
+                                                joining = Some(ssid);
                                             }
                                         } else {
                                             // no SSIDs available, scan again
@@ -346,6 +391,7 @@ pub(crate) fn connection_manager(sid: xous::SID, activity_interval: Arc<AtomicU3
                                     wait_count += 1;
                                     if wait_count > INTERVALS_BEFORE_RETRY {
                                         wait_count = 0;
+                                        join_failed(&mut backoff, &mut joining, tt.elapsed_ms());
                                         wifi_state = WifiState::Retry;
                                     }
                                 }
@@ -376,11 +422,9 @@ pub(crate) fn connection_manager(sid: xous::SID, activity_interval: Arc<AtomicU3
                                     log::debug!("connected, updating stats cache");
                                     // relay status updates to any subscribers that want to know if a state has changed
                                     wifi_stats_cache = com.wlan_status().unwrap();
+                                    wifi_stats_cache.connectivity = connectivity;
                                     log::debug!("stats update: {:?}", wifi_stats_cache);
-                                    for &sub in status_subscribers.keys() {
-                                        let buf = Buffer::into_buf(com::WlanStatusIpc::from_status(wifi_stats_cache)).or(Err(xous::Error::InternalError)).unwrap();
-                                        buf.send(sub, WifiStateCallback::Update.to_u32().unwrap()).or(Err(xous::Error::InternalError)).unwrap();
-                                    }
+                                    notify_subscribers(&status_subscribers, wifi_stats_cache);
                                 }
                             }
                         }
@@ -395,10 +439,15 @@ pub(crate) fn connection_manager(sid: xous::SID, activity_interval: Arc<AtomicU3
                         if ssid_stats.rssi != rssi_u8 {
                             ssid_stats.rssi = rssi_u8;
                             log::debug!("stats update: {:?}", wifi_stats_cache);
-                            for &sub in status_subscribers.keys() {
-                                let buf = Buffer::into_buf(com::WlanStatusIpc::from_status(wifi_stats_cache)).or(Err(xous::Error::InternalError)).unwrap();
-                                buf.send(sub, WifiStateCallback::Update.to_u32().unwrap()).or(Err(xous::Error::InternalError)).unwrap();
-                            }
+                            notify_subscribers(&status_subscribers, wifi_stats_cache);
+                        }
+                    }
+                    if connectivity != Connectivity::Online && !probing {
+                        probe_wait += 1;
+                        if probe_wait >= PROBE_RETRY_INTERVALS {
+                            probe_wait = 0;
+                            probing = true;
+                            start_probe(self_cid, wifi_stats_cache.ipv4.dns1, probe_generation);
                         }
                     }
                 }
@@ -409,6 +458,21 @@ pub(crate) fn connection_manager(sid: xous::SID, activity_interval: Arc<AtomicU3
                     current_interval.store(POLL_INTERVAL_MS as u32, Ordering::SeqCst);
                 }
             }),
+            Some(ConnectionManagerOpcode::ProbeResult) => msg_scalar_unpack!(msg, result, generation, _, _, {
+                if generation == probe_generation {
+                    probing = false;
+                    let result = FromPrimitive::from_usize(result).unwrap_or(Connectivity::Unknown);
+                    if wifi_state == WifiState::Connected && result != connectivity {
+                        if result == Connectivity::CaptivePortal {
+                            log::warn!("network is behind a captive portal; sign in with a browser on another device");
+                        }
+                        log::info!("connectivity: {:?}", result);
+                        connectivity = result;
+                        wifi_stats_cache.connectivity = connectivity;
+                        notify_subscribers(&status_subscribers, wifi_stats_cache);
+                    }
+                }
+            }),
             Some(ConnectionManagerOpcode::SubscribeWifiStats) => {
                 let buffer = unsafe {
                     Buffer::from_memory_message(msg.body.memory_message().unwrap())
@@ -471,57 +535,158 @@ pub(crate) fn connection_manager(sid: xous::SID, activity_interval: Arc<AtomicU3
     xous::destroy_server(sid).unwrap();
 }
 
-fn get_next_ssid(ssid_list_map: &mut HashMap<String, u8>, ssid_attempted: &mut HashSet<String>, ap_list: HashSet::<String>) -> Option<String> {
-    log::trace!("ap_list: {:?}", ap_list);
-    log::trace!("ssid_list: {:?}", ssid_list_map);
-    // 0. convert the HashMap of ssid_list into a HashSet
-    let mut ssid_list = HashSet::<String>::new();
-    for ssid in ssid_list_map.keys() {
-        ssid_list.insert(ssid.to_string());
+fn notify_subscribers(subscribers: &HashMap<xous::CID, WifiStateSubscription>, status: WlanStatus) {
+    for &sub in subscribers.keys() {
+        let buf = Buffer::into_buf(WlanStatusIpc::from_status(status)).or(Err(xous::Error::InternalError)).unwrap();
+        buf.send(sub, WifiStateCallback::Update.to_u32().unwrap()).or(Err(xous::Error::InternalError)).unwrap();
     }
-    // 1. find the intersection of ap_list and ssid_list to create a candidate_list
-    let all_candidate_list_ref = ap_list.intersection(&ssid_list).collect::<HashSet<_>>();
-    // this copy is required to perform the next set computation
-    let mut all_candidate_list = HashSet::<String>::new();
-    for c in all_candidate_list_ref {
-        all_candidate_list.insert(String::from(c));
+}
+
+/// Runs the connectivity probe on a thread of its own, as it blocks for as long as the network takes to answer.
+/// The result comes back to the connection manager as a `ProbeResult` message.
+fn start_probe(cm_cid: xous::CID, dns_server: [u8; 4], generation: usize) {
+    std::thread::spawn(move || {
+        let xns = xous_names::XousNames::new().unwrap();
+        let trng = trng::Trng::new(&xns).unwrap();
+        let result = connectivity::probe(Ipv4Addr::from(dns_server), &trng);
+        send_message(cm_cid,
+            Message::new_scalar(ConnectionManagerOpcode::ProbeResult.to_usize().unwrap(), result as usize, generation, 0, 0)
+        ).ok();
+    });
+}
+
+fn join_failed(backoff: &mut HashMap<String, JoinBackoff>, joining: &mut Option<String>, now: u64) {
+    if let Some(ssid) = joining.take() {
+        let entry = backoff.entry(ssid.clone()).or_default();
+        entry.failed(now);
+        log::info!("couldn't join {} ({} failures in a row), backing off for {}ms", ssid, entry.failures, entry.retry_at - now);
     }
-    log::trace!("intersection: {:?}", all_candidate_list);
+}
 
-    log::trace!("ssids already attempted: {:?}", ssid_attempted);
-    // 2. find the complement of ssid_attempted and candidate_list
-    let untried_candidate_list_ref = all_candidate_list.difference(ssid_attempted).collect::<HashSet<_>>();
-    // this copy breaks the mutability issue with changing ssid_attempted after the difference is computed
-    let mut untried_candidate_list = HashSet::<String>::new();
-    for c in untried_candidate_list_ref {
-        untried_candidate_list.insert(String::from(c));
+/// Seconds since 2000-01-01 by the RTC, which is how `SavedNetwork::last_success` is kept. The RTC only
+/// counts the years 2000-2099, in which every fourth year is a leap year.
+fn rtc_secs(dt: &llio::DateTime) -> u64 {
+    const DAYS_BEFORE_MONTH: [u64; 12] = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
+    let years = dt.years as u64;
+    let month = (dt.months.max(1) as usize - 1).min(11);
+    // one leap day for each of the leap years before this one, and another if this is a leap year and February is done
+    let mut days = years * 365 + ((years + 3) >> 2) + DAYS_BEFORE_MONTH[month] + dt.days.max(1) as u64 - 1;
+    if years & 3 == 0 && month >= 2 {
+        days += 1;
     }
-    log::trace!("untried_candidates: {:?}", untried_candidate_list);
+    ((days * 24 + dt.hours as u64) * 60 + dt.minutes as u64) * 60 + dt.seconds as u64
+}
 
-    if untried_candidate_list.len() > 0 {
-        if let Some(candidate) = untried_candidate_list.into_iter().next() {
-            ssid_attempted.insert(candidate.to_string());
-            log::debug!("SSID connect attempt: {:?}", candidate);
-            Some(candidate.to_string())
-        } else {
-            log::error!("We should have had at least one item in the candidate list, but found none.");
-            None
-        }
+/// Picks the saved network to join next. Of the networks that are in range -- or hidden, since those don't
+/// turn up in a scan -- and aren't backing off, this is the one with the highest priority, then the one
+/// joined most recently, then the one with the strongest signal.
+fn get_next_ssid(
+    saved: &HashMap<String, SavedNetwork>,
+    ssid_list: &HashMap<String, u8>,
+    backoff: &HashMap<String, JoinBackoff>,
+    now: u64,
+) -> Option<String> {
+    log::trace!("saved: {:?}", saved);
+    log::trace!("ssid_list: {:?}", ssid_list);
+    let mut candidates: Vec<(&String, &SavedNetwork, u8)> = saved
+        .iter()
+        .filter(|(ssid, _)| backoff.get(*ssid).map(|b| b.retry_at <= now).unwrap_or(true))
+        .filter_map(|(ssid, network)| match ssid_list.get(ssid) {
+            Some(&rssi) => Some((ssid, network, rssi)),
+            // rssi is the negative of the signal strength, so a hidden network is tried after a visible one that's otherwise equal
+            None if network.hidden => Some((ssid, network, u8::MAX)),
+            None => None,
+        })
+        .collect();
+    candidates.sort_by(|a, b| {
+        b.1.priority.cmp(&a.1.priority)
+            .then(b.1.last_success.cmp(&a.1.last_success))
+            .then(a.2.cmp(&b.2))
+            .then(a.0.cmp(b.0))
+    });
+    log::trace!("candidates: {:?}", candidates);
+    if let Some((ssid, _, _)) = candidates.first() {
+        log::debug!("SSID connect attempt: {:?}", ssid);
+        Some(ssid.to_string())
     } else {
-        // clear the ssid_attempted list and start from scratch
-        log::debug!("Exhausted all candidates, starting over again...");
-        ssid_attempted.clear();
-        if let Some(candidate) = all_candidate_list.iter().next() {
-            ssid_attempted.insert(candidate.to_string());
-            log::debug!("SSID connect attempt: {:?}", candidate);
-            Some(candidate.to_string())
-        } else {
-            log::info!("No SSID candidates visible. Debug dump:");
-            log::info!("ap_list: {:?}", ap_list);
-            log::info!("ssid_list: {:?}", ssid_list_map);
-            log::info!("candidate list: {:?}", all_candidate_list);
-            log::info!("untried candidate list: {:?}", untried_candidate_list);
-            None
+        log::info!("No SSID candidates available. Debug dump:");
+        log::info!("saved: {:?}", saved.keys());
+        log::info!("ssid_list: {:?}", ssid_list);
+        log::info!("backing off: {:?}", backoff);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(priority: u8, hidden: bool, last_success: u64) -> SavedNetwork {
+        SavedNetwork { priority, hidden, last_success }
+    }
+
+    #[test]
+    fn candidate_ranking() {
+        let mut saved = HashMap::new();
+        saved.insert("home".to_string(), network(0, false, 100));
+        saved.insert("cafe".to_string(), network(0, false, 200));
+        saved.insert("office".to_string(), network(5, false, 0));
+        saved.insert("lab".to_string(), network(5, true, 0));
+        let mut visible = HashMap::new();
+        visible.insert("home".to_string(), 40);
+        visible.insert("cafe".to_string(), 70);
+        visible.insert("neighbour".to_string(), 30);
+        let mut backoff = HashMap::new();
+
+        // the hidden network doesn't show up in scans, but is tried all the same
+        assert_eq!(get_next_ssid(&saved, &visible, &backoff, 0).as_deref(), Some("lab"));
+        // a visible network beats a hidden one of the same priority
+        visible.insert("office".to_string(), 80);
+        assert_eq!(get_next_ssid(&saved, &visible, &backoff, 0).as_deref(), Some("office"));
+        // the one joined most recently beats the stronger signal
+        visible.remove("office");
+        saved.remove("lab");
+        assert_eq!(get_next_ssid(&saved, &visible, &backoff, 0).as_deref(), Some("cafe"));
+        // until it fails to join
+        let mut cafe = JoinBackoff::default();
+        cafe.failed(1_000);
+        backoff.insert("cafe".to_string(), cafe);
+        assert_eq!(get_next_ssid(&saved, &visible, &backoff, 1_000).as_deref(), Some("home"));
+        assert_eq!(get_next_ssid(&saved, &visible, &backoff, 1_000 + BACKOFF_BASE_MS).as_deref(), Some("cafe"));
+        // nothing saved is in range
+        visible.clear();
+        assert_eq!(get_next_ssid(&saved, &visible, &backoff, 0), None);
+    }
+
+    #[test]
+    fn backoff_grows_to_a_limit() {
+        let mut backoff = JoinBackoff::default();
+        let mut delays = Vec::new();
+        for _ in 0..40 {
+            backoff.failed(5);
+            delays.push(backoff.retry_at - 5);
         }
+        assert_eq!(&delays[..3], &[BACKOFF_BASE_MS, 2 * BACKOFF_BASE_MS, 4 * BACKOFF_BASE_MS]);
+        assert!(delays.windows(2).all(|d| d[0] <= d[1]));
+        assert_eq!(delays[39], BACKOFF_MAX_MS);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn rtc_time() {
+        let dt = |years, months, days, hours, minutes, seconds| llio::DateTime {
+            seconds,
+            minutes,
+            hours,
+            days,
+            months,
+            years,
+            weekday: llio::Weekday::Monday,
+        };
+        assert_eq!(rtc_secs(&dt(0, 1, 1, 0, 0, 0)), 0);
+        // 2022-03-01 12:34:56 is 1_646_138_096 in Unix time, and 2000-01-01 is 946_684_800
+        assert_eq!(rtc_secs(&dt(22, 3, 1, 12, 34, 56)), 1_646_138_096 - 946_684_800);
+        // 2024-03-01, the day after a leap day
+        assert_eq!(rtc_secs(&dt(24, 3, 1, 0, 0, 0)), 1_709_251_200 - 946_684_800);
+        assert_eq!(rtc_secs(&dt(24, 2, 29, 0, 0, 0)), 1_709_164_800 - 946_684_800);
+    }
+}
@@ -0,0 +1,220 @@
+//! Checks whether a network we've joined reaches the internet. Networks in hotels, airports and the like
+//! often hold every connection at a login page until the user signs in -- a "captive portal" -- so having
+//! an address from DHCP doesn't mean much. We fetch a page that is known to come back empty; a portal
+//! answers for it with its login page instead.
+//!
+//! The DNS server can't be used for the lookup, as it's built on top of this crate, so the probe sends
+//! its own query straight to the DNS server that DHCP handed out.
+
+use com::Connectivity;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+pub(crate) const PROBE_HOST: &str = "connectivitycheck.gstatic.com";
+const PROBE_PATH: &str = "/generate_204";
+const PROBE_TIMEOUT_MS: u64 = 5_000;
+/// enough for the status line of any reasonable response
+const STATUS_LINE_MAX: usize = 256;
+const DNS_PKT_MAX_LEN: usize = 512;
+const DNS_TYPE_A: u16 = 1;
+const DNS_CLASS_IN: u16 = 1;
+
+/// Probes the network through `dns_server`. This blocks for up to a few timeouts, so it's run on a thread of its own.
+pub(crate) fn probe(dns_server: Ipv4Addr, trng: &trng::Trng) -> Connectivity {
+    let addr = match resolve(dns_server, PROBE_HOST, trng) {
+        Ok(addr) => addr,
+        Err(e) => {
+            log::info!(
+                "connectivity probe couldn't resolve {}: {:?}",
+                PROBE_HOST,
+                e
+            );
+            return Connectivity::Offline;
+        }
+    };
+    match fetch_status(addr) {
+        Ok(204) => Connectivity::Online,
+        Ok(status) => {
+            log::info!(
+                "connectivity probe got status {} instead of 204, assuming a captive portal",
+                status
+            );
+            Connectivity::CaptivePortal
+        }
+        Err(e) => {
+            log::info!("connectivity probe couldn't reach {}: {:?}", addr, e);
+            Connectivity::Offline
+        }
+    }
+}
+
+fn resolve(dns_server: Ipv4Addr, host: &str, trng: &trng::Trng) -> std::io::Result<Ipv4Addr> {
+    let rand = trng.get_u32().unwrap();
+    let local_port = (49152 + (rand >> 16) % 16384) as u16;
+    let id = rand as u16;
+    let mut socket = net::UdpSocket::bind_xous(
+        format!("127.0.0.1:{}", local_port),
+        Some(DNS_PKT_MAX_LEN as u16),
+    )?;
+    socket.set_read_timeout(Some(net::Duration::from_millis(PROBE_TIMEOUT_MS)))?;
+    socket.set_nonblocking(false)?;
+    socket.send_to(
+        &dns_query(id, host),
+        &SocketAddr::new(IpAddr::V4(dns_server), 53),
+    )?;
+    let mut buf = [0u8; DNS_PKT_MAX_LEN];
+    let len = socket.recv(&mut buf)?;
+    dns_answer(id, &buf[..len]).ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "no A record in the reply")
+    })
+}
+
+fn fetch_status(addr: Ipv4Addr) -> std::io::Result<u16> {
+    let mut tcp = net::TcpStream::connect_xous(
+        (IpAddr::V4(addr), 80),
+        Some(net::Duration::from_millis(PROBE_TIMEOUT_MS)),
+        None,
+    )?;
+    tcp.set_read_timeout(Some(net::Duration::from_millis(PROBE_TIMEOUT_MS)))?;
+    tcp.set_write_timeout(Some(net::Duration::from_millis(PROBE_TIMEOUT_MS)))?;
+    write!(
+        tcp,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        PROBE_PATH, PROBE_HOST
+    )?;
+    let mut response = [0u8; STATUS_LINE_MAX];
+    let mut len = 0;
+    while len < response.len() && !response[..len].contains(&b'\n') {
+        match tcp.read(&mut response[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    tcp.shutdown(std::net::Shutdown::Both).ok();
+    http_status(&response[..len])
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "not an HTTP response"))
+}
+
+/// A query for the A records of `host`, asking for recursion
+pub(crate) fn dns_query(id: u16, host: &str) -> Vec<u8> {
+    let mut query = Vec::with_capacity(18 + host.len());
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00]); // a standard query, recursion desired
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // one question, and nothing else
+    for label in host.split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&DNS_TYPE_A.to_be_bytes());
+    query.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+    query
+}
+
+/// Skips over the (possibly compressed) name at `pos`, returning where the name ends
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)? as usize;
+        if len & 0xc0 == 0xc0 {
+            return Some(pos + 2);
+        } else if len == 0 {
+            return Some(pos + 1);
+        }
+        pos += 1 + len;
+    }
+}
+
+fn be16(msg: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*msg.get(pos)?, *msg.get(pos + 1)?]))
+}
+
+/// The first A record in the reply to the query `id`
+pub(crate) fn dns_answer(id: u16, msg: &[u8]) -> Option<Ipv4Addr> {
+    // the ID has to match, it has to be a response, and the response code has to be "no error"
+    if be16(msg, 0)? != id || msg.get(2)? & 0x80 == 0 || msg.get(3)? & 0x0f != 0 {
+        return None;
+    }
+    let questions = be16(msg, 4)?;
+    let answers = be16(msg, 6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(msg, pos)? + 4;
+    }
+    for _ in 0..answers {
+        pos = skip_name(msg, pos)?;
+        let rtype = be16(msg, pos)?;
+        let rclass = be16(msg, pos + 2)?;
+        let rdlen = be16(msg, pos + 8)? as usize;
+        pos += 10;
+        let rdata = msg.get(pos..pos + rdlen)?;
+        if rtype == DNS_TYPE_A && rclass == DNS_CLASS_IN && rdlen == 4 {
+            return Some(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]));
+        }
+        pos += rdlen;
+    }
+    None
+}
+
+/// The status code of an HTTP response, from its status line
+pub(crate) fn http_status(response: &[u8]) -> Option<u16> {
+    let line = response.split(|&b| b == b'\n').next()?;
+    let line = std::str::from_utf8(line).ok()?;
+    let mut fields = line.trim_end().split(' ');
+    if !fields.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    let status = fields.next()?;
+    if status.len() != 3 {
+        return None;
+    }
+    status.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dns_round_trip() {
+        let query = dns_query(0x1234, "example.com");
+        assert_eq!(&query[..4], &[0x12, 0x34, 0x01, 0x00]);
+        assert_eq!(&query[12..25], b"\x07example\x03com\x00");
+        assert_eq!(&query[25..], &[0, 1, 0, 1]);
+
+        // the question comes back, followed by a CNAME and then the A record, with compressed names
+        let mut reply = query.clone();
+        reply[2] = 0x81;
+        reply[3] = 0x80;
+        reply[7] = 2;
+        reply.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 6]);
+        reply.extend_from_slice(b"\x03www\xc0\x0c");
+        reply.extend_from_slice(&[0xc0, 41, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 216, 34]);
+        assert_eq!(
+            dns_answer(0x1234, &reply),
+            Some(Ipv4Addr::new(93, 184, 216, 34))
+        );
+
+        // a reply to some other query
+        assert_eq!(dns_answer(0x1235, &reply), None);
+        // a truncated reply
+        assert_eq!(dns_answer(0x1234, &reply[..reply.len() - 2]), None);
+        // NXDOMAIN
+        reply[3] = 0x83;
+        assert_eq!(dns_answer(0x1234, &reply), None);
+        // our own query echoed back
+        assert_eq!(dns_answer(0x1234, &query), None);
+    }
+
+    #[test]
+    fn status_lines() {
+        assert_eq!(
+            http_status(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n"),
+            Some(204)
+        );
+        assert_eq!(http_status(b"HTTP/1.0 302 Found\r\n"), Some(302));
+        assert_eq!(http_status(b"HTTP/1.1 200 OK"), Some(200));
+        assert_eq!(http_status(b"SSH-2.0-OpenSSH\r\n"), None);
+        assert_eq!(http_status(b"HTTP/1.1 20"), None);
+        assert_eq!(http_status(b""), None);
+    }
+}
@@ -1,18 +1,38 @@
-use com::Com;
+#[cfg(any(target_os = "none", target_os = "xous"))]
+pub use com::Com;
+// there's no EC in hosted mode, so packets are swapped with a simulated network instead
+#[cfg(not(any(target_os = "none", target_os = "xous")))]
+pub use crate::sim::SimCom as Com;
 use com::api::NET_MTU;
 
 use smoltcp::Result;
-use smoltcp::phy::{self, DeviceCapabilities, Medium};
+use smoltcp::phy::{self, ChecksumCapabilities, DeviceCapabilities, Medium};
 
 use smoltcp::{
-    time::Instant,
+    time::{Duration, Instant},
+    wire::{EthernetAddress, EthernetFrame, EthernetProtocol, EthernetRepr},
+    wire::{Icmpv6Message, Icmpv6Packet, Icmpv6Repr, IpAddress, IpProtocol},
+    wire::{Ipv6Address, Ipv6Packet, Ipv6Repr, NdiscPrefixInfoFlags, NdiscRepr},
 };
 
+/// The parts of an ICMPv6 Router Advertisement that are needed for SLAAC. smoltcp does its own
+/// neighbor discovery, but it drops router advertisements, so they are picked out of the receive path here.
+#[derive(Copy, Clone, Debug)]
+pub struct RouterAdvert {
+    /// link-local address of the advertising router
+    pub router: Ipv6Address,
+    /// how long the router may be used as a default router; zero if it shouldn't be
+    pub router_lifetime: Duration,
+    /// prefix, prefix length and valid lifetime of a prefix that may be used for address autoconfiguration
+    pub prefix: Option<(Ipv6Address, u8, Duration)>,
+}
+
 pub struct NetPhy {
     rx_buffer: [u8; NET_MTU],
     tx_buffer: [u8; NET_MTU],
     com: Com,
     rx_avail: Option<u16>,
+    router_advert: Option<RouterAdvert>,
 }
 
 impl<'a> NetPhy {
@@ -22,8 +42,55 @@ impl<'a> NetPhy {
             tx_buffer: [0; NET_MTU],
             com: Com::new(&xns).unwrap(),
             rx_avail: None,
+            router_advert: None,
+        }
+    }
+    /// a device that swaps packets through `com`, so tests can keep a handle on the link
+    #[cfg(all(test, not(any(target_os = "none", target_os = "xous"))))]
+    pub fn from_com(com: Com) -> NetPhy {
+        NetPhy {
+            rx_buffer: [0; NET_MTU],
+            tx_buffer: [0; NET_MTU],
+            com,
+            rx_avail: None,
+            router_advert: None,
         }
     }
+    /// returns the most recent router advertisement received since the last call
+    pub fn take_router_advert(&mut self) -> Option<RouterAdvert> {
+        self.router_advert.take()
+    }
+    /// Sends an ICMPv6 Router Solicitation from `src`, so routers respond with an advertisement
+    /// right away instead of at their next periodic interval.
+    pub fn send_router_solicit(&mut self, mac: EthernetAddress, src: Ipv6Address) -> Result<()> {
+        let dst = Ipv6Address::new(0xff02, 0, 0, 0, 0, 0, 0, 2); // all-routers multicast
+        let icmp_repr = Icmpv6Repr::Ndisc(NdiscRepr::RouterSolicit { lladdr: Some(mac) });
+        let ip_repr = Ipv6Repr {
+            src_addr: src,
+            dst_addr: dst,
+            next_header: IpProtocol::Icmpv6,
+            payload_len: icmp_repr.buffer_len(),
+            hop_limit: 255,
+        };
+        let eth_repr = EthernetRepr {
+            src_addr: mac,
+            dst_addr: EthernetAddress([0x33, 0x33, 0, 0, 0, 2]),
+            ethertype: EthernetProtocol::Ipv6,
+        };
+        let len = eth_repr.buffer_len() + ip_repr.buffer_len() + icmp_repr.buffer_len();
+        let mut frame = EthernetFrame::new_unchecked(&mut self.tx_buffer[..len]);
+        eth_repr.emit(&mut frame);
+        let mut ip_packet = Ipv6Packet::new_unchecked(frame.payload_mut());
+        ip_repr.emit(&mut ip_packet);
+        let mut icmp_packet = Icmpv6Packet::new_unchecked(ip_packet.payload_mut());
+        icmp_repr.emit(
+            &IpAddress::Ipv6(src),
+            &IpAddress::Ipv6(dst),
+            &mut icmp_packet,
+            &ChecksumCapabilities::default(),
+        );
+        self.com.wlan_send_packet(&self.tx_buffer[..len]).map_err(|_| smoltcp::Error::Dropped)
+    }
     // returns None if there was a slot to put the availability into
     // returns Some(len) if not
     pub fn push_rx_avail(&mut self, len: u16) -> Option<u16> {
@@ -36,6 +103,32 @@ impl<'a> NetPhy {
     }
 }
 
+fn parse_router_advert(frame: &[u8]) -> Option<RouterAdvert> {
+    let frame = EthernetFrame::new_checked(frame).ok()?;
+    if frame.ethertype() != EthernetProtocol::Ipv6 {
+        return None;
+    }
+    let ip_packet = Ipv6Packet::new_checked(frame.payload()).ok()?;
+    // a hop limit of 255 guarantees the advertisement came from our own link (RFC 4861 section 6.1.2)
+    if ip_packet.next_header() != IpProtocol::Icmpv6 || ip_packet.hop_limit() != 255 {
+        return None;
+    }
+    let icmp_packet = Icmpv6Packet::new_checked(ip_packet.payload()).ok()?;
+    if icmp_packet.msg_type() != Icmpv6Message::RouterAdvert {
+        return None;
+    }
+    match NdiscRepr::parse(&icmp_packet).ok()? {
+        NdiscRepr::RouterAdvert { router_lifetime, prefix_info, .. } => Some(RouterAdvert {
+            router: ip_packet.src_addr(),
+            router_lifetime,
+            prefix: prefix_info
+                .filter(|info| info.flags.contains(NdiscPrefixInfoFlags::ADDRCONF))
+                .map(|info| (info.prefix, info.prefix_len, info.valid_lifetime)),
+        }),
+        _ => None,
+    }
+}
+
 impl<'a> phy::Device<'a> for NetPhy {
     type RxToken = NetPhyRxToken<'a>;
     type TxToken = NetPhyTxToken<'a>;
@@ -43,6 +136,9 @@ impl<'a> phy::Device<'a> for NetPhy {
     fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
         if let Some(rx_len) = self.rx_avail.take() {
             self.com.wlan_fetch_packet(&mut self.rx_buffer[..rx_len as usize]).expect("Couldn't call wlan_fetch_packet in device adapter");
+            if let Some(advert) = parse_router_advert(&self.rx_buffer[..rx_len as usize]) {
+                self.router_advert = Some(advert);
+            }
 
             Some((NetPhyRxToken{buf: &mut self.rx_buffer[..rx_len as usize]},
             NetPhyTxToken{buf: &mut self.tx_buffer[..], com: & self.com}))
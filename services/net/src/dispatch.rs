@@ -0,0 +1,769 @@
+//! Checks that an incoming message is the kind its opcode calls for, before the main loop acts on it.
+//! The handlers take the shape of their messages for granted, so without this a malformed message
+//! from any process could take down the network stack.
+//!
+//! rkyv 0.4 doesn't validate archives, and reading an enum or an `Option` whose tag is out of range, or a
+//! `bool` that isn't 0 or 1, is undefined behaviour. So the bytes of every archive a handler reads are
+//! checked here too.
+
+use crate::api::*;
+use num_traits::FromPrimitive;
+use rkyv::Archive;
+use std::alloc::Layout;
+use std::mem::size_of;
+use xous::{MemoryMessage, Message};
+
+/// An archived value a handler reads
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Archived {
+    layout: Layout,
+    valid: fn(&[u8]) -> bool,
+}
+
+/// How the messages for an opcode are sent
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum MessageKind {
+    /// Lent with `lend_mut`, holding an archived value at the message's offset
+    LendMut(Archived),
+    /// Lent with `lend_mut`, with a buffer at least this many bytes long. Used where the buffer only
+    /// carries a reply, and by the calls from libstd, which don't use rkyv.
+    LendMutRaw(usize),
+    /// Sent with `send`, holding an archived value at the message's offset
+    Send(Archived),
+    Scalar,
+    BlockingScalar,
+    /// Anything goes; the handler works out what it was given
+    Any,
+}
+
+fn archived<T: Validate>() -> Archived {
+    Archived {
+        layout: Layout::new::<T::Archived>(),
+        valid: T::valid,
+    }
+}
+
+/// A buffer that only carries a reply of type `T`
+fn reply<T: Archive>() -> MessageKind {
+    MessageKind::LendMutRaw(size_of::<T::Archived>())
+}
+
+pub(crate) fn message_kind(opcode: &Opcode) -> MessageKind {
+    use MessageKind::*;
+    match opcode {
+        Opcode::UdpBind | Opcode::UdpClose => LendMut(archived::<NetUdpBind>()),
+        Opcode::UdpTx => LendMut(archived::<NetUdpTransmit>()),
+        Opcode::UdpSetTtl
+        | Opcode::UdpSetBroadcast
+        | Opcode::UdpSetMulticastLoopV4
+        | Opcode::UdpSetMulticastTtlV4 => Scalar,
+        Opcode::UdpGetTtl
+        | Opcode::UdpGetBroadcast
+        | Opcode::UdpGetMulticastLoopV4
+        | Opcode::UdpGetMulticastTtlV4
+        | Opcode::UdpJoinMulticastV4
+        | Opcode::UdpLeaveMulticastV4 => BlockingScalar,
+
+        Opcode::TcpConnect | Opcode::TcpClose | Opcode::TcpManage | Opcode::TcpManageListener => {
+            LendMut(archived::<NetTcpManage>())
+        }
+        Opcode::TcpTx => LendMut(archived::<NetTcpTransmit>()),
+        Opcode::TcpListen => LendMut(archived::<NetTcpListen>()),
+        Opcode::TcpPeek => LendMut(archived::<NetTcpPeek>()),
+
+        Opcode::DnsHookAddIpv4 | Opcode::DnsHookAddIpv6 | Opcode::DnsHookAllClear => {
+            LendMut(archived::<XousPrivateServerHook>())
+        }
+        Opcode::DnsUnhookAll => BlockingScalar,
+
+        Opcode::Ping => LendMut(archived::<NetPingPacket>()),
+        Opcode::PingSetTtl | Opcode::PingSetTimeout => Scalar,
+        Opcode::PingGetTtl | Opcode::PingGetTimeout => BlockingScalar,
+
+        Opcode::GetIpv4Config => LendMutRaw(0),
+        Opcode::GetIpv6Config => reply::<Ipv6Conf>(),
+        Opcode::SetIpv6Static => LendMut(archived::<Ipv6Conf>()),
+        Opcode::Reset => BlockingScalar,
+        Opcode::SubscribeWifiStats => Send(archived::<WifiStateSubscription>()),
+        Opcode::UnsubWifiStats => BlockingScalar,
+        Opcode::FetchSsidList => reply::<SsidList>(),
+        Opcode::ConnMgrStartStop => Scalar,
+        Opcode::Poll => LendMut(archived::<NetPoll>()),
+        Opcode::SocketList => reply::<NetSocketList>(),
+        Opcode::ReleaseProcess => BlockingScalar,
+
+        Opcode::ComInterrupt | Opcode::NetPump | Opcode::SuspendResume => Scalar,
+        Opcode::Quit => Any,
+
+        // the connect request is a port and an address of up to 17 bytes, and the reply is four u16s
+        Opcode::StdTcpConnect => LendMutRaw(19),
+        // the reply is two u32s
+        Opcode::StdTcpTx => LendMutRaw(8),
+        Opcode::StdTcpRx => LendMutRaw(0),
+        Opcode::StdTcpClose => Any,
+    }
+}
+
+/// Checks the bytes of an archived value before it is read
+pub(crate) trait Validate: Archive {
+    /// `bytes` starts at the archived value, and holds all of it
+    fn valid(bytes: &[u8]) -> bool;
+}
+
+/// Archived enums, `Option` among them, are `repr(u8)`: the tag is the first byte. Returns the tag if it
+/// is one of the first `variants`.
+fn tag(bytes: &[u8], variants: u8) -> Option<u8> {
+    bytes.first().copied().filter(|&tag| tag < variants)
+}
+
+/// The bytes of the first field of an enum variant, which follows the tag as in a `repr(C)` struct
+fn first_field<T: Archive>(bytes: &[u8]) -> &[u8] {
+    let (_, offset) = Layout::new::<u8>()
+        .extend(Layout::new::<T::Archived>())
+        .unwrap();
+    &bytes[offset..]
+}
+
+/// Types where any bit pattern will do
+macro_rules! any_bits {
+    ($($t:ty),*) => {
+        $(impl Validate for $t {
+            fn valid(_bytes: &[u8]) -> bool {
+                true
+            }
+        })*
+    };
+}
+any_bits!(u8, u16, u32, u64, usize, WifiStateSubscription);
+
+/// Structs are checked field by field; fields where any bit pattern will do can be left out
+macro_rules! fields {
+    ($t:ty, $archived:ident { $($field:ident: $ft:ty),* }) => {
+        impl Validate for $t {
+            fn valid(bytes: &[u8]) -> bool {
+                $(<$ft>::valid(&bytes[rkyv::offset_of!($archived, $field)..]))&&*
+            }
+        }
+    };
+}
+
+impl Validate for bool {
+    fn valid(bytes: &[u8]) -> bool {
+        matches!(bytes.first(), Some(0) | Some(1))
+    }
+}
+
+impl<T: Validate> Validate for Option<T> {
+    fn valid(bytes: &[u8]) -> bool {
+        match tag(bytes, 2) {
+            Some(0) => true,
+            Some(_) => T::valid(first_field::<T>(bytes)),
+            None => false,
+        }
+    }
+}
+
+impl<T: Validate, const N: usize> Validate for [T; N] {
+    fn valid(bytes: &[u8]) -> bool {
+        let size = size_of::<T::Archived>();
+        (0..N).all(|i| T::valid(&bytes[i * size..]))
+    }
+}
+
+impl Validate for NetIpAddr {
+    fn valid(bytes: &[u8]) -> bool {
+        tag(bytes, 2).is_some()
+    }
+}
+
+impl Validate for NetMemResponse {
+    fn valid(bytes: &[u8]) -> bool {
+        tag(bytes, 9).is_some()
+    }
+}
+
+impl Validate for TcpMgmtCode {
+    fn valid(bytes: &[u8]) -> bool {
+        match tag(bytes, 8) {
+            // SetNoDelay, GetNoDelay, Flush
+            Some(1) | Some(2) | Some(6) => bool::valid(first_field::<bool>(bytes)),
+            // ErrorCheck
+            Some(5) => NetMemResponse::valid(first_field::<NetMemResponse>(bytes)),
+            Some(_) => true,
+            None => false,
+        }
+    }
+}
+
+/// Only a private SID is taken: a server name is held out of line, behind a relative pointer that could
+/// point anywhere, and the Net crate's own calls never send one.
+impl Validate for XousServerId {
+    fn valid(bytes: &[u8]) -> bool {
+        tag(bytes, 2) == Some(0)
+    }
+}
+
+impl Validate for NetPollSocket {
+    fn valid(bytes: &[u8]) -> bool {
+        match tag(bytes, 3) {
+            // TcpStream
+            Some(0) => NetIpAddr::valid(first_field::<NetIpAddr>(bytes)),
+            Some(_) => true,
+            None => false,
+        }
+    }
+}
+
+fields!(NetSocketAddr, ArchivedNetSocketAddr { addr: NetIpAddr });
+fields!(NetUdpBind, ArchivedNetUdpBind { ip_addr: NetIpAddr, max_payload: Option<u16> });
+fields!(NetUdpTransmit, ArchivedNetUdpTransmit { dest_socket: Option<NetSocketAddr> });
+fields!(
+    NetTcpManage,
+    ArchivedNetTcpManage {
+        ip_addr: NetIpAddr,
+        local_port: Option<u16>,
+        timeout_ms: Option<u64>,
+        keepalive_ms: Option<u64>,
+        result: Option<NetMemResponse>,
+        mgmt_code: Option<TcpMgmtCode>
+    }
+);
+fields!(NetTcpListen, ArchivedNetTcpListen { result: Option<NetMemResponse> });
+fields!(
+    NetTcpTransmit,
+    ArchivedNetTcpTransmit { remote_addr: NetIpAddr, result: Option<NetMemResponse> }
+);
+fields!(NetTcpPeek, ArchivedNetTcpPeek { ip_addr: NetIpAddr, result: Option<NetMemResponse> });
+fields!(XousPrivateServerHook, ArchivedXousPrivateServerHook { args: [Option<usize>; 4] });
+fields!(
+    NetPingPacket,
+    ArchivedNetPingPacket { endpoint: NetIpAddr, server: XousServerId, sent_ok: Option<bool> }
+);
+fields!(
+    Ipv6Conf,
+    ArchivedIpv6Conf {
+        link_local: Option<[u8; 16]>,
+        slaac: Option<[u8; 16]>,
+        static_addr: Option<[u8; 16]>,
+        gateway: Option<[u8; 16]>
+    }
+);
+fields!(
+    NetPollEntry,
+    ArchivedNetPollEntry {
+        socket: NetPollSocket
+    }
+);
+fields!(
+    NetPoll,
+    ArchivedNetPoll {
+        entries: [Option<NetPollEntry>; POLL_MAX_SOCKETS],
+        timeout_ms: Option<u64>,
+        result: Option<NetMemResponse>
+    }
+);
+
+/// The offset of the archived value in the buffer of `mem`
+fn offset(mem: &MemoryMessage) -> usize {
+    mem.offset.map(|o| o.get()).unwrap_or(0)
+}
+
+/// Whether the buffer of `mem` holds a valid `archived` value at the message's offset. The buffer is
+/// mapped into this process for as long as the message is held.
+fn holds(mem: &MemoryMessage, archived: Archived) -> bool {
+    let offset = offset(mem);
+    let layout = archived.layout;
+    // alignments are always a power of two
+    offset & (layout.align() - 1) == 0
+        && matches!(offset.checked_add(layout.size()), Some(end) if end <= mem.buf.len())
+        && (archived.valid)(&mem.buf.as_slice::<u8>()[offset..])
+}
+
+/// Checks `msg` against what its opcode calls for. The opcode is in the lower 16 bits of the message ID;
+/// the calls from libstd put a connection index in the upper bits.
+pub(crate) fn check_message(msg: &Message) -> Result<Opcode, NetError> {
+    let opcode: Opcode = FromPrimitive::from_usize(msg.id() & 0xffff).ok_or(NetError::Invalid)?;
+    let ok = match (message_kind(&opcode), msg) {
+        (MessageKind::LendMut(archived), Message::MutableBorrow(mem))
+        | (MessageKind::Send(archived), Message::Move(mem)) => holds(mem, archived),
+        (MessageKind::LendMutRaw(len), Message::MutableBorrow(mem)) => mem.buf.len() >= len,
+        (MessageKind::Scalar, Message::Scalar(_))
+        | (MessageKind::BlockingScalar, Message::BlockingScalar(_))
+        | (MessageKind::Any, _) => true,
+        _ => false,
+    };
+    if ok {
+        Ok(opcode)
+    } else {
+        Err(NetError::Invalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_traits::ToPrimitive;
+    use rkyv::ser::{serializers::BufferSerializer, Serializer};
+    use xous::{MemoryAddress, MemoryRange, MemorySize, ScalarMessage};
+    use xous_ipc::Buffer;
+
+    const PAGE: usize = 4096;
+    /// one more than the highest opcode
+    const OPCODES: usize = 48;
+
+    /// Page-aligned memory behind the buffer of a message
+    struct Pages(&'static mut [u8]);
+    impl Pages {
+        fn new(len: usize) -> Pages {
+            let layout = Layout::from_size_align(len, PAGE).unwrap();
+            Pages(unsafe { std::slice::from_raw_parts_mut(std::alloc::alloc_zeroed(layout), len) })
+        }
+        fn memory(&self, id: usize, offset: usize) -> MemoryMessage {
+            MemoryMessage {
+                id,
+                buf: unsafe { MemoryRange::new(self.0.as_ptr() as usize, self.0.len()).unwrap() },
+                offset: MemoryAddress::new(offset),
+                valid: MemorySize::new(self.0.len()),
+            }
+        }
+    }
+    impl Drop for Pages {
+        fn drop(&mut self) {
+            let layout = Layout::from_size_align(self.0.len(), PAGE).unwrap();
+            unsafe { std::alloc::dealloc(self.0.as_mut_ptr(), layout) }
+        }
+    }
+
+    fn scalar(id: usize) -> ScalarMessage {
+        ScalarMessage::from_usize(id, 0, 0, 0, 0)
+    }
+
+    /// Buffers are made of whole pages
+    fn pages(len: usize) -> usize {
+        ((len + PAGE - 1) & !(PAGE - 1)).max(PAGE)
+    }
+
+    /// Archives `value` into `bytes` the way `Buffer::into_buf` does, and returns its offset
+    fn archive<'a, T>(value: &T, bytes: &'a mut [u8]) -> usize
+    where
+        T: rkyv::Serialize<BufferSerializer<&'a mut [u8]>>,
+    {
+        BufferSerializer::new(bytes).serialize_value(value).unwrap()
+    }
+
+    const SID: [u32; 4] = [1, 2, 3, 4];
+    const IPV6: NetIpAddr = NetIpAddr::Ipv6([0xfe; 16]);
+
+    fn tcp_manage(mgmt_code: Option<TcpMgmtCode>) -> NetTcpManage {
+        NetTcpManage {
+            cb_sid: SID,
+            ip_addr: IPV6,
+            remote_port: 80,
+            local_port: Some(49152),
+            timeout_ms: Some(1000),
+            keepalive_ms: None,
+            result: Some(NetMemResponse::AlreadyUsed),
+            mgmt_code,
+        }
+    }
+
+    fn ping(sent_ok: Option<bool>) -> NetPingPacket {
+        NetPingPacket {
+            endpoint: IPV6,
+            server: XousServerId::PrivateSid(SID),
+            return_opcode: 1,
+            sent_ok,
+        }
+    }
+
+    /// Archives what the client library sends for `opcode` into `bytes`, using the last variant of each
+    /// enum, and returns its offset
+    fn archive_request(opcode: &Opcode, bytes: &mut [u8]) -> usize {
+        match opcode {
+            Opcode::UdpBind | Opcode::UdpClose => archive(
+                &NetUdpBind {
+                    cb_sid: SID,
+                    ip_addr: IPV6,
+                    port: 53,
+                    max_payload: Some(512),
+                },
+                bytes,
+            ),
+            Opcode::UdpTx => archive(
+                &NetUdpTransmit {
+                    dest_socket: Some(NetSocketAddr {
+                        addr: IPV6,
+                        port: 53,
+                    }),
+                    local_port: 53,
+                    len: 0,
+                    data: [0; UDP_RESPONSE_MAX_LEN],
+                },
+                bytes,
+            ),
+            Opcode::TcpConnect
+            | Opcode::TcpClose
+            | Opcode::TcpManage
+            | Opcode::TcpManageListener => {
+                archive(&tcp_manage(Some(TcpMgmtCode::CloseListener)), bytes)
+            }
+            Opcode::TcpTx => archive(
+                &NetTcpTransmit {
+                    remote_addr: IPV6,
+                    remote_port: 80,
+                    local_port: 49152,
+                    len: 0,
+                    data: [0; TCP_BUFFER_SIZE],
+                    result: Some(NetMemResponse::AlreadyUsed),
+                },
+                bytes,
+            ),
+            Opcode::TcpListen => archive(
+                &NetTcpListen {
+                    cb_sid: SID,
+                    local_port: 80,
+                    result: Some(NetMemResponse::AlreadyUsed),
+                },
+                bytes,
+            ),
+            Opcode::TcpPeek => archive(
+                &NetTcpPeek {
+                    ip_addr: IPV6,
+                    remote_port: 80,
+                    local_port: 49152,
+                    rx_seen: 0,
+                    len: 0,
+                    data: [0; TCP_BUFFER_SIZE],
+                    result: Some(NetMemResponse::AlreadyUsed),
+                },
+                bytes,
+            ),
+            Opcode::DnsHookAddIpv4 | Opcode::DnsHookAddIpv6 | Opcode::DnsHookAllClear => archive(
+                &XousPrivateServerHook {
+                    one_time_sid: SID,
+                    op: 1,
+                    args: [Some(1), None, Some(3), None],
+                },
+                bytes,
+            ),
+            Opcode::Ping => archive(&ping(Some(true)), bytes),
+            Opcode::SetIpv6Static => archive(
+                &Ipv6Conf {
+                    static_addr: Some([0xfe; 16]),
+                    static_prefix_len: 64,
+                    ..Default::default()
+                },
+                bytes,
+            ),
+            Opcode::SubscribeWifiStats => archive(
+                &WifiStateSubscription {
+                    sid: SID,
+                    opcode: 1,
+                },
+                bytes,
+            ),
+            Opcode::Poll => {
+                let mut entries = [None; POLL_MAX_SOCKETS];
+                let sockets = [
+                    NetPollSocket::TcpStream {
+                        ip_addr: IPV6,
+                        remote_port: 80,
+                        local_port: 49152,
+                    },
+                    NetPollSocket::TcpListener { local_port: 80 },
+                    NetPollSocket::UdpSocket {
+                        local_port: 53,
+                        cb_sid: SID,
+                    },
+                ];
+                for (entry, &socket) in entries.iter_mut().zip(sockets.iter()) {
+                    *entry = Some(NetPollEntry {
+                        socket,
+                        rx_seen: 0,
+                        interest: POLL_READABLE,
+                        ready: 0,
+                    });
+                }
+                archive(
+                    &NetPoll {
+                        entries,
+                        timeout_ms: Some(10),
+                        result: Some(NetMemResponse::AlreadyUsed),
+                    },
+                    bytes,
+                )
+            }
+            _ => 0,
+        }
+    }
+
+    /// A message of the kind `opcode` calls for, the way the client library sends it
+    fn well_formed(opcode: &Opcode) -> (Pages, Message) {
+        let id = opcode.to_usize().unwrap();
+        match message_kind(opcode) {
+            MessageKind::LendMut(archived) => {
+                let mut pages = Pages::new(pages(archived.layout.size()));
+                let offset = archive_request(opcode, pages.0);
+                let mem = pages.memory(id, offset);
+                (pages, Message::MutableBorrow(mem))
+            }
+            MessageKind::LendMutRaw(len) => {
+                let pages = Pages::new(pages(len));
+                let mem = pages.memory(id, 0);
+                (pages, Message::MutableBorrow(mem))
+            }
+            MessageKind::Send(archived) => {
+                let mut pages = Pages::new(pages(archived.layout.size()));
+                let offset = archive_request(opcode, pages.0);
+                let mem = pages.memory(id, offset);
+                (pages, Message::Move(mem))
+            }
+            MessageKind::Scalar => (Pages::new(PAGE), Message::Scalar(scalar(id))),
+            MessageKind::BlockingScalar | MessageKind::Any => {
+                (Pages::new(PAGE), Message::BlockingScalar(scalar(id)))
+            }
+        }
+    }
+
+    /// Reads the archive in `mem` the way the handler for `opcode` does
+    fn read(opcode: &Opcode, mem: &mut MemoryMessage) {
+        let buf = unsafe { Buffer::from_memory_message_mut(mem) };
+        match opcode {
+            Opcode::UdpBind | Opcode::UdpClose => {
+                buf.to_original::<NetUdpBind, _>().unwrap();
+            }
+            Opcode::UdpTx => {
+                buf.to_original::<NetUdpTransmit, _>().unwrap();
+            }
+            Opcode::TcpConnect
+            | Opcode::TcpClose
+            | Opcode::TcpManage
+            | Opcode::TcpManageListener => {
+                buf.to_original::<NetTcpManage, _>().unwrap();
+            }
+            Opcode::TcpTx => {
+                buf.to_original::<NetTcpTransmit, _>().unwrap();
+            }
+            Opcode::TcpListen => {
+                buf.to_original::<NetTcpListen, _>().unwrap();
+            }
+            Opcode::TcpPeek => {
+                buf.to_original::<NetTcpPeek, _>().unwrap();
+            }
+            Opcode::DnsHookAddIpv4 | Opcode::DnsHookAddIpv6 | Opcode::DnsHookAllClear => {
+                buf.to_original::<XousPrivateServerHook, _>().unwrap();
+            }
+            Opcode::Ping => {
+                buf.to_original::<NetPingPacket, _>().unwrap();
+            }
+            Opcode::SetIpv6Static => {
+                buf.to_original::<Ipv6Conf, _>().unwrap();
+            }
+            Opcode::SubscribeWifiStats => {
+                buf.to_original::<WifiStateSubscription, _>().unwrap();
+            }
+            Opcode::Poll => {
+                buf.to_original::<NetPoll, _>().unwrap();
+            }
+            _ => (),
+        }
+    }
+
+    /// The position of the first byte where `a` and `b` differ
+    fn first_difference(a: &[u8], b: &[u8]) -> usize {
+        a.iter().zip(b.iter()).position(|(a, b)| a != b).unwrap()
+    }
+
+    /// Archives `a` and `b`, which differ only in one tag or `bool`, and returns the archive of `a` and
+    /// the position of that byte in it
+    fn tag_of<T>(a: T, b: T) -> (Pages, usize, usize)
+    where
+        T: for<'a> rkyv::Serialize<BufferSerializer<&'a mut [u8]>>,
+    {
+        let mut pages = Pages::new(PAGE);
+        let mut other = Pages::new(PAGE);
+        let offset = archive(&a, pages.0);
+        assert_eq!(archive(&b, other.0), offset);
+        let position = first_difference(pages.0, other.0);
+        (pages, offset, position)
+    }
+
+    /// xorshift, so that failures can be reproduced
+    struct Rng(u64);
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    #[test]
+    fn well_formed_messages_are_accepted() {
+        for id in 0..OPCODES {
+            let opcode: Opcode = FromPrimitive::from_usize(id).unwrap();
+            let (_pages, mut msg) = well_formed(&opcode);
+            assert_eq!(
+                check_message(&msg).map(|op| op.to_usize().unwrap()),
+                Ok(id),
+                "{:?}",
+                opcode
+            );
+            if let Some(mem) = msg.memory_message_mut() {
+                read(&opcode, mem);
+            }
+        }
+        assert!(<Opcode as FromPrimitive>::from_usize(OPCODES).is_none());
+        // libstd puts the connection index in the upper bits
+        let pages = Pages::new(PAGE);
+        let msg = Message::MutableBorrow(pages.memory(0x3_0000 | Opcode::StdTcpRx as usize, 0));
+        assert!(check_message(&msg).is_ok());
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        // a scalar where a buffer is expected
+        let msg = Message::BlockingScalar(scalar(Opcode::TcpConnect as usize));
+        assert_eq!(check_message(&msg).err(), Some(NetError::Invalid));
+        // a buffer that has been lent, but can't be written
+        let pages = Pages::new(PAGE);
+        let msg = Message::Borrow(pages.memory(Opcode::Poll as usize, 0));
+        assert_eq!(check_message(&msg).err(), Some(NetError::Invalid));
+        // a non-blocking scalar for a call that returns a value
+        let msg = Message::Scalar(scalar(Opcode::Reset as usize));
+        assert_eq!(check_message(&msg).err(), Some(NetError::Invalid));
+        // the archive starts too close to the end of the buffer
+        let msg = Message::MutableBorrow(pages.memory(Opcode::TcpTx as usize, PAGE - 8));
+        assert_eq!(check_message(&msg).err(), Some(NetError::Invalid));
+        // an opcode that doesn't exist
+        let msg = Message::Scalar(scalar(0xffff));
+        assert_eq!(check_message(&msg).err(), Some(NetError::Invalid));
+    }
+
+    #[test]
+    fn archives_with_bad_tags_are_rejected() {
+        let id = Opcode::TcpManage as usize;
+        let check = |pages: &Pages, offset: usize, id: usize| {
+            check_message(&Message::MutableBorrow(pages.memory(id, offset)))
+        };
+        // an `Option` that is neither `None` nor `Some`
+        let mut unset = tcp_manage(None);
+        unset.result = None;
+        let (mut pages, offset, position) = tag_of(tcp_manage(None), unset);
+        assert!(check(&pages, offset, id).is_ok());
+        pages.0[position] = 2;
+        assert_eq!(check(&pages, offset, id).err(), Some(NetError::Invalid));
+        // an address that is neither IPv4 nor IPv6
+        let mut ipv4 = tcp_manage(None);
+        ipv4.ip_addr = NetIpAddr::Ipv4([0xfe; 4]);
+        let (mut pages, offset, position) = tag_of(tcp_manage(None), ipv4);
+        pages.0[position] = 2;
+        assert_eq!(check(&pages, offset, id).err(), Some(NetError::Invalid));
+        // a response past the last one
+        let mut ok = tcp_manage(None);
+        ok.result = Some(NetMemResponse::Ok);
+        let (mut pages, offset, position) = tag_of(tcp_manage(None), ok);
+        pages.0[position] = 9;
+        assert_eq!(check(&pages, offset, id).err(), Some(NetError::Invalid));
+        // a `bool` inside an enum inside an `Option`
+        let (mut pages, offset, position) = tag_of(
+            tcp_manage(Some(TcpMgmtCode::SetNoDelay(true))),
+            tcp_manage(Some(TcpMgmtCode::SetNoDelay(false))),
+        );
+        assert!(check(&pages, offset, id).is_ok());
+        pages.0[position] = 2;
+        assert_eq!(check(&pages, offset, id).err(), Some(NetError::Invalid));
+        // a `bool` in a ping
+        let id = Opcode::Ping as usize;
+        let (mut pages, offset, position) = tag_of(ping(Some(true)), ping(Some(false)));
+        assert!(check(&pages, offset, id).is_ok());
+        pages.0[position] = 0xff;
+        assert_eq!(check(&pages, offset, id).err(), Some(NetError::Invalid));
+        // a ping that names its callback server
+        let mut named = ping(None);
+        named.server = XousServerId::ServerName(xous_ipc::String::from_str("_net callback_"));
+        let mut pages = Pages::new(PAGE);
+        let offset = archive(&named, pages.0);
+        assert_eq!(check(&pages, offset, id).err(), Some(NetError::Invalid));
+    }
+
+    #[test]
+    fn fuzz_check_message() {
+        let mut rng = Rng(0x5eed_1234_abcd_0001);
+        for _ in 0..100_000 {
+            // mostly valid opcodes, with junk in the upper bits now and then
+            let id = match rng.below(4) {
+                0 => rng.next() as usize,
+                1 => rng.below(OPCODES) | (rng.below(0x1_0000) << 16),
+                _ => rng.below(OPCODES + 2),
+            };
+            let len = match rng.below(3) {
+                0 => 1 + rng.below(64),
+                1 => PAGE,
+                _ => PAGE * (1 + rng.below(4)),
+            };
+            let mut pages = Pages::new(len);
+            // a well-formed archive with a few bytes changed, or junk
+            let opcode: Option<Opcode> = FromPrimitive::from_usize(id & 0xffff);
+            let offset = match opcode.map(|opcode| message_kind(&opcode)) {
+                Some(MessageKind::LendMut(archived)) | Some(MessageKind::Send(archived))
+                    if rng.below(2) == 0 && len >= archived.layout.size() =>
+                {
+                    let offset = archive_request(opcode.as_ref().unwrap(), pages.0);
+                    for _ in 0..rng.below(4) {
+                        let byte = offset + rng.below(archived.layout.size());
+                        pages.0[byte] = rng.next() as u8;
+                    }
+                    offset
+                }
+                _ => {
+                    pages.0.iter_mut().for_each(|byte| *byte = rng.next() as u8);
+                    match rng.below(3) {
+                        0 => 0,
+                        1 => rng.below(len + 64),
+                        _ => rng.next() as usize,
+                    }
+                }
+            };
+            let mut msg = match rng.below(5) {
+                0 => Message::MutableBorrow(pages.memory(id, offset)),
+                1 => Message::Borrow(pages.memory(id, offset)),
+                2 => Message::Move(pages.memory(id, offset)),
+                3 => Message::Scalar(scalar(id)),
+                _ => Message::BlockingScalar(scalar(id)),
+            };
+            let opcode = match check_message(&msg) {
+                Ok(opcode) => opcode,
+                Err(e) => {
+                    assert_eq!(e, NetError::Invalid);
+                    continue;
+                }
+            };
+            // whatever gets through must be exactly what the handler expects
+            assert_eq!(opcode.to_usize().unwrap(), id & 0xffff);
+            match (message_kind(&opcode), &msg) {
+                (MessageKind::LendMut(archived), Message::MutableBorrow(mem))
+                | (MessageKind::Send(archived), Message::Move(mem)) => {
+                    let start = mem.offset.map(|o| o.get()).unwrap_or(0);
+                    assert_eq!(start & (archived.layout.align() - 1), 0);
+                    assert!(start + archived.layout.size() <= mem.buf.len());
+                }
+                (MessageKind::LendMutRaw(min), Message::MutableBorrow(mem)) => {
+                    assert!(mem.buf.len() >= min)
+                }
+                (MessageKind::Scalar, Message::Scalar(_))
+                | (MessageKind::BlockingScalar, Message::BlockingScalar(_))
+                | (MessageKind::Any, _) => {}
+                (kind, msg) => panic!("{:?} accepted for {:?}", msg, kind),
+            }
+            // and the handler can read it
+            if let Some(mem) = msg.memory_message_mut() {
+                read(&opcode, mem);
+            }
+        }
+    }
+}
@@ -39,6 +39,11 @@ impl Drop for NetConn {
         // a single process do not end up de-allocating the CID on other threads before they go out of scope.
         // Note to future me: you want this. Don't get rid of it because you think, "nah, nobody will ever make more than one copy of this object".
         if REFCOUNT.fetch_sub(1, Ordering::Relaxed) == 1 {
+            // this was our last socket or manager, so have the Net server close anything we leaked along the way
+            send_message(
+                self.conn,
+                Message::new_blocking_scalar(Opcode::ReleaseProcess.to_usize().unwrap(), 0, 0, 0, 0),
+            ).ok();
             unsafe{xous::disconnect(self.conn).unwrap();}
         }
         // if there was object-specific state (such as a one-time use server for async callbacks, specific to the object instance),
@@ -72,6 +77,30 @@ impl NetManager {
             None
         }
     }
+    pub fn get_ipv6_config(&self) -> Ipv6Conf {
+        let mut buf = Buffer::into_buf(Ipv6Conf::default()).expect("Couldn't convert to memory structure");
+        buf.lend_mut(self.netconn.conn(), Opcode::GetIpv6Config.to_u32().unwrap()).expect("Couldn't execute GetIpv6Config opcode");
+        buf.to_original().expect("couldn't restore config structure")
+    }
+    /// Sets a static IPv6 address with its prefix length, and a default router. These take precedence over the
+    /// ones learned by SLAAC; passing `None` clears them.
+    pub fn set_ipv6_static(&self, addr: Option<(std::net::Ipv6Addr, u8)>, gateway: Option<std::net::Ipv6Addr>) -> std::io::Result<()> {
+        use std::io::{Error, ErrorKind};
+        let conf = Ipv6Conf {
+            static_addr: addr.map(|(a, _)| a.octets()),
+            static_prefix_len: addr.map(|(_, len)| len).unwrap_or(0),
+            gateway: gateway.map(|g| g.octets()),
+            ..Default::default()
+        };
+        let mut buf = Buffer::into_buf(conf)
+            .or(Err(Error::new(ErrorKind::Other, "can't send to Net server")))?;
+        buf.lend_mut(self.netconn.conn(), Opcode::SetIpv6Static.to_u32().unwrap())
+            .or(Err(Error::new(ErrorKind::Other, "can't send to Net server")))?;
+        match buf.to_original::<NetMemResponse, _>() {
+            Ok(NetMemResponse::Ok) => Ok(()),
+            _ => Err(Error::new(ErrorKind::InvalidInput, "invalid static IPv6 address, prefix length or gateway")),
+        }
+    }
     pub fn reset(&self) {
         send_message(
             self.netconn.conn(),
@@ -160,6 +189,16 @@ impl NetManager {
         }
         Ok(ret)
     }
+    /// Describes the sockets held by the Net server, for all processes. Only the first `SOCKET_LIST_MAX`
+    /// are described; the second value is the total number of sockets.
+    pub fn socket_list(&self) -> Result<(Vec::<SocketInfo>, usize), xous::Error> {
+        let alloc = NetSocketList::default();
+        let mut buf = Buffer::into_buf(alloc).map_err(|_| xous::Error::InternalError)?;
+        buf.lend_mut(self.netconn.conn(), Opcode::SocketList.to_u32().unwrap())?;
+        let socket_list = buf.to_original::<NetSocketList, _>().map_err(|_| xous::Error::InternalError)?;
+        let ret = socket_list.list.iter().flatten().map(SocketInfo::from).collect();
+        Ok((ret, socket_list.total as usize))
+    }
     pub fn connection_manager_stop(&self) -> Result<(), xous::Error> {
         send_message(self.netconn.conn(),
             Message::new_scalar(Opcode::ConnMgrStartStop.to_usize().unwrap(), 0, 0,0, 0)
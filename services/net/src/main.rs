@@ -7,23 +7,34 @@ use com::api::{ComIntSources, Ipv4Conf, NET_MTU};
 use num_traits::*;
 
 mod connection_manager;
+mod connectivity;
 mod device;
+mod dispatch;
+mod release;
+use release::ClientSockets;
+#[cfg(not(any(target_os = "none", target_os = "xous")))]
+#[macro_use]
+extern crate lazy_static;
+#[cfg(not(any(target_os = "none", target_os = "xous")))]
+mod sim;
 
 use std::collections::{BTreeMap, HashMap};
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use xous::{msg_blocking_scalar_unpack, msg_scalar_unpack, send_message, Message, CID, SID};
 use xous_ipc::Buffer;
 
 use byteorder::{ByteOrder, NetworkEndian};
 use smoltcp::iface::{Interface, InterfaceBuilder, NeighborCache, Routes};
-use smoltcp::phy::{Device, Medium};
+use smoltcp::phy::{ChecksumCapabilities, Device, Medium};
 use smoltcp::socket::{IcmpEndpoint, IcmpPacketMetadata, IcmpSocket, IcmpSocketBuffer, SocketSet};
 use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, IpEndpoint, Ipv4Address, Ipv4Cidr};
+use smoltcp::wire::{Ipv6Address, Ipv6Cidr};
 use smoltcp::wire::{Icmpv4Packet, Icmpv4Repr, Icmpv6Packet, Icmpv6Repr};
 
 use core::sync::atomic::{AtomicU32, Ordering};
 use smoltcp::socket::{
-    SocketHandle, TcpSocket, TcpSocketBuffer, UdpPacketMetadata, UdpSocket, UdpSocketBuffer,
+    SocketHandle, TcpSocket, TcpSocketBuffer, TcpState as TcpSocketState, UdpPacketMetadata,
+    UdpSocket, UdpSocketBuffer,
 };
 use smoltcp::time::{Duration, Instant};
 use std::sync::Arc;
@@ -44,6 +55,144 @@ where
     });
 }
 
+/// Builds an interface on `device`, with no addresses or routes; `mac` is the hardware address on the link.
+fn build_iface(device: device::NetPhy, mac: EthernetAddress) -> Interface<'static, device::NetPhy> {
+    let neighbor_cache = NeighborCache::new(BTreeMap::new());
+    let ip_addrs = [IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0)];
+    let routes = Routes::new(BTreeMap::new());
+    let medium = device.capabilities().medium;
+    let mut builder = InterfaceBuilder::new(device)
+        .ip_addrs(ip_addrs)
+        .routes(routes)
+        .ipv4_multicast_groups(BTreeMap::new());
+    if medium == Medium::Ethernet {
+        builder = builder.ethernet_addr(mac).neighbor_cache(neighbor_cache);
+    }
+    builder.finalize()
+}
+
+/// Applies the IPv4 address and default gateway handed out to the EC.
+fn set_ipv4_config<DeviceT>(iface: &mut Interface<'_, DeviceT>, config: &Ipv4Conf)
+where
+    DeviceT: for<'d> Device<'d>,
+{
+    let ip_addr = Ipv4Cidr::new(Ipv4Address::from_bytes(&config.addr), 24);
+    set_ipv4_addr(iface, ip_addr);
+    let default_v4_gw = Ipv4Address::from_bytes(&config.gtwy);
+
+    // reset the default route, in case it has changed
+    iface.routes_mut().remove_default_ipv4_route();
+    match iface.routes_mut().add_default_ipv4_route(default_v4_gw) {
+        Ok(route) => log::info!("routing table updated successfully [{:?}]", route),
+        Err(e) => log::error!("routing table update error: {}", e),
+    }
+}
+
+/// IPv6 addressing state; see `Ipv6Conf` for how the addresses are arrived at.
+#[derive(Default)]
+struct Ipv6State {
+    link_local: Option<Ipv6Cidr>,
+    /// SLAAC address, and the `elapsed_ms()` at which it expires
+    slaac: Option<(Ipv6Cidr, u64)>,
+    /// router learned from advertisements, and the `elapsed_ms()` at which it expires
+    router: Option<(Ipv6Address, u64)>,
+    static_addr: Option<Ipv6Cidr>,
+    static_gateway: Option<Ipv6Address>,
+}
+impl Ipv6State {
+    /// Our addresses, in order of preference as the source address of outgoing packets
+    fn cidrs(&self) -> Vec<Ipv6Cidr> {
+        self.static_addr
+            .iter()
+            .chain(self.slaac.iter().map(|(cidr, _)| cidr))
+            .chain(self.link_local.iter())
+            .cloned()
+            .collect()
+    }
+    fn gateway(&self) -> Option<Ipv6Address> {
+        self.static_gateway.or(self.router.map(|(router, _)| router))
+    }
+    /// Applies a router advertisement received at `now`. Returns true if the addressing changed.
+    fn router_advert(&mut self, advert: &device::RouterAdvert, now: u64) -> bool {
+        let before = (self.slaac.map(|(cidr, _)| cidr), self.gateway());
+        if advert.router_lifetime.total_millis() != 0 {
+            self.router = Some((advert.router, now + advert.router_lifetime.total_millis()));
+        } else if self.router.map(|(router, _)| router) == Some(advert.router) {
+            self.router = None;
+        }
+        // the interface identifier is shared with the link-local address, so only 64-bit prefixes can be used
+        if let (Some(link_local), Some((prefix, 64, valid_lifetime))) = (self.link_local, advert.prefix) {
+            let mut addr = link_local.address().0;
+            addr[..8].copy_from_slice(&prefix.0[..8]);
+            let cidr = Ipv6Cidr::new(Ipv6Address(addr), 64);
+            if valid_lifetime.total_millis() != 0 {
+                self.slaac = Some((cidr, now + valid_lifetime.total_millis()));
+            } else if self.slaac.map(|(slaac, _)| slaac) == Some(cidr) {
+                self.slaac = None;
+            }
+        }
+        before != (self.slaac.map(|(cidr, _)| cidr), self.gateway())
+    }
+    /// Drops the SLAAC address and router once their lifetimes run out. Returns true if the addressing changed.
+    fn expire(&mut self, now: u64) -> bool {
+        let mut changed = false;
+        if matches!(self.slaac, Some((_, expiry)) if now >= expiry) {
+            self.slaac = None;
+            changed = true;
+        }
+        if matches!(self.router, Some((_, expiry)) if now >= expiry) {
+            self.router = None;
+            changed = true;
+        }
+        changed
+    }
+    fn conf(&self) -> Ipv6Conf {
+        Ipv6Conf {
+            link_local: self.link_local.map(|cidr| cidr.address().0),
+            slaac: self.slaac.map(|(cidr, _)| cidr.address().0),
+            static_addr: self.static_addr.map(|cidr| cidr.address().0),
+            static_prefix_len: self.static_addr.map(|cidr| cidr.prefix_len()).unwrap_or(0),
+            gateway: self.gateway().map(|addr| addr.0),
+        }
+    }
+}
+
+/// Builds the link-local address for `mac`, using the modified EUI-64 of the MAC as the interface identifier.
+fn link_local_from_mac(mac: &EthernetAddress) -> Ipv6Cidr {
+    let m = mac.0;
+    Ipv6Cidr::new(
+        Ipv6Address([
+            0xfe, 0x80, 0, 0, 0, 0, 0, 0, m[0] ^ 0x02, m[1], m[2], 0xff, 0xfe, m[3], m[4], m[5],
+        ]),
+        64,
+    )
+}
+
+/// Replaces the IPv6 addresses and default route of the interface. The IPv4 address stays in the first
+/// slot, where `set_ipv4_addr()` expects it; smoltcp uses the first address of the matching family as the
+/// source of outgoing packets, so the IPv6 addresses follow in order of preference.
+fn set_ipv6<DeviceT>(iface: &mut Interface<'_, DeviceT>, ipv6: &Ipv6State)
+where
+    DeviceT: for<'d> Device<'d>,
+{
+    let cidrs = ipv6.cidrs();
+    iface.update_ip_addrs(|addrs| {
+        let mut updated: Vec<IpCidr> = addrs
+            .iter()
+            .filter(|cidr| matches!(cidr, IpCidr::Ipv4(_)))
+            .cloned()
+            .collect();
+        updated.extend(cidrs.iter().map(|&cidr| IpCidr::Ipv6(cidr)));
+        *addrs = updated.into();
+    });
+    iface.routes_mut().remove_default_ipv6_route();
+    if let Some(gateway) = ipv6.gateway() {
+        if let Err(e) = iface.routes_mut().add_default_ipv6_route(gateway) {
+            log::error!("ipv6 routing table update error: {}", e);
+        }
+    }
+}
+
 #[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug)]
 enum WaitOp {
     WaitMs,
@@ -57,6 +206,27 @@ pub struct UdpState {
     handle: SocketHandle,
     cid: CID,
     sid: SID,
+    /// the process that bound the socket; clones can only be made by the same process
+    owner: Option<xous::PID>,
+    /// unicast TTL; `None` leaves it at the smoltcp default
+    ttl: Option<u8>,
+    /// TTL for datagrams sent to a multicast group
+    multicast_ttl: u8,
+    /// datagrams to a broadcast address are refused unless this is set (`SO_BROADCAST`)
+    broadcast: bool,
+    /// when set, datagrams sent to a group we are a member of are also delivered to our own listeners
+    multicast_loop: bool,
+    /// multicast groups joined through this socket
+    multicast_groups: Vec<Ipv4Address>,
+}
+impl UdpState {
+    /// Whether the socket has joined the multicast group `addr`
+    fn joined(&self, addr: IpAddress) -> bool {
+        match addr {
+            IpAddress::Ipv4(group) => self.multicast_groups.contains(&group),
+            _ => false,
+        }
+    }
 }
 
 /// PingConnection can return a Scalar: because of the simplicity of the return data
@@ -90,6 +260,295 @@ pub struct TcpState {
     handle: SocketHandle,
     cid: CID,
     shutdown_rx: bool,
+    /// number of `RxData` messages sent to the callback server, for `Opcode::Poll`
+    rx_count: u32,
+    /// the process that opened the socket. Only it may use or close the socket.
+    owner: Option<xous::PID>,
+}
+
+/// True if `addr` is the limited broadcast address, or the directed broadcast address of our subnet.
+fn is_broadcast<DeviceT>(iface: &Interface<'_, DeviceT>, addr: IpAddress) -> bool
+where
+    DeviceT: for<'d> Device<'d>,
+{
+    addr.is_broadcast()
+        || iface.ip_addrs().iter().any(|cidr| match cidr {
+            IpCidr::Ipv4(v4) => v4.broadcast().map(IpAddress::Ipv4) == Some(addr),
+            _ => false,
+        })
+}
+
+/// Drops a socket's memberships from the reference count of joined multicast groups. Groups
+/// that no longer have any member sockets are left on the interface.
+fn release_multicast_groups<DeviceT>(
+    iface: &mut Interface<'_, DeviceT>,
+    multicast_groups: &mut HashMap<Ipv4Address, usize>,
+    groups: &[Ipv4Address],
+    timestamp: Instant,
+) where
+    DeviceT: for<'d> Device<'d>,
+{
+    for group in groups {
+        if let Some(count) = multicast_groups.get_mut(group) {
+            *count -= 1;
+            if *count == 0 {
+                multicast_groups.remove(group);
+                if let Err(e) = iface.leave_multicast_group(*group, timestamp) {
+                    log::warn!("couldn't leave multicast group {}: {:?}", group, e);
+                }
+            }
+        }
+    }
+}
+
+/// Sends a received UDP datagram to the socket's listener, and copies of it to any clones.
+/// `rx_counts` tracks the number of datagrams sent to each of them, for `Opcode::Poll`.
+fn udp_deliver(
+    response: NetUdpResponse,
+    udpstate: &UdpState,
+    clone_map: Option<&HashMap<[u32; 4], CID>>,
+    rx_counts: &mut HashMap<[u32; 4], u32>,
+) {
+    // a listener that has gone away is cleaned up by the next sweep, so just skip it for now
+    let buf =
+        Buffer::into_buf(response).expect("couldn't convert UDP response to memory message");
+    match buf.send(udpstate.cid, NetUdpCallback::RxData.to_u32().unwrap()) {
+        Ok(_) => {
+            let count = rx_counts.entry(udpstate.sid.to_array()).or_default();
+            *count = count.wrapping_add(1);
+        }
+        Err(e) => log::warn!("couldn't send UDP response: {:?}", e),
+    }
+    // now send copies to the cloned receiver array, if they exist
+    if let Some(clone_map) = clone_map {
+        for (sid, &cids) in clone_map.iter() {
+            let buf = Buffer::into_buf(response)
+                .expect("couldn't convert UDP response to memory message");
+            match buf.send(cids, NetUdpCallback::RxData.to_u32().unwrap()) {
+                Ok(_) => {
+                    let count = rx_counts.entry(*sid).or_default();
+                    *count = count.wrapping_add(1);
+                }
+                Err(e) => log::warn!("couldn't send UDP response to a clone: {:?}", e),
+            }
+        }
+    }
+}
+
+/// Closes the sockets that were taken away from their clients, and drops the callback connections they leave
+/// unused. TCP sockets linger in `tcp_closing` to say goodbye to their peer, so the caller should pump the stack.
+fn close_released<DeviceT>(
+    released: release::Released,
+    sockets: &mut SocketSet,
+    iface: &mut Interface<'_, DeviceT>,
+    multicast_groups: &mut HashMap<Ipv4Address, usize>,
+    tcp_closing: &mut Vec<(SocketHandle, u64)>,
+    now: u64,
+) where
+    DeviceT: for<'d> Device<'d>,
+{
+    for &handle in released.tcp.iter() {
+        release::tcp_close(sockets, tcp_closing, handle, now);
+    }
+    for &handle in released.udp.iter() {
+        sockets.get::<UdpSocket>(handle).close();
+        sockets.remove(handle);
+    }
+    release_multicast_groups(
+        iface,
+        multicast_groups,
+        &released.multicast_groups,
+        Instant::from_millis(now as i64),
+    );
+    for &cid in released.cids.iter() {
+        // the server may already be gone, and that's fine
+        unsafe { xous::disconnect(cid).ok() };
+    }
+}
+
+/// Closes the sockets of clients whose callback server has gone away. Returns the number of sockets closed.
+fn sweep_clients<DeviceT>(
+    mut clients: ClientSockets,
+    sockets: &mut SocketSet,
+    iface: &mut Interface<'_, DeviceT>,
+    multicast_groups: &mut HashMap<Ipv4Address, usize>,
+    tcp_closing: &mut Vec<(SocketHandle, u64)>,
+    now: u64,
+) -> usize
+where
+    DeviceT: for<'d> Device<'d>,
+{
+    let dead = clients.dead_callbacks();
+    if dead.is_empty() {
+        return 0;
+    }
+    let released = clients.release(|_, cid| dead.contains(&cid));
+    let count = released.count();
+    log::info!("closing {} sockets whose clients have gone away", count);
+    close_released(released, sockets, iface, multicast_groups, tcp_closing, now);
+    count
+}
+
+/// Fills in the `ready` flags of every entry of a `NetPoll` made by `pid`, and returns true if any of them is
+/// ready. Closed and invalid sockets count as ready whatever the caller is interested in. Sockets that belong to
+/// another process are reported as invalid, so a poll can't be used to watch other processes' traffic.
+fn poll_ready(
+    poll: &mut NetPoll,
+    pid: Option<xous::PID>,
+    sockets: &mut SocketSet,
+    clients: &ClientSockets,
+) -> bool {
+    let mut any_ready = false;
+    for entry in poll.entries.iter_mut().flatten() {
+        entry.ready = match entry.socket {
+            NetPollSocket::TcpStream {
+                ip_addr,
+                remote_port,
+                local_port,
+            } => {
+                let connection = TcpConnection {
+                    remote: IpAddress::from(ip_addr),
+                    remote_port,
+                    local_port,
+                };
+                match clients
+                    .tcp_handles
+                    .get(&connection)
+                    .filter(|s| s.owner == pid)
+                {
+                    Some(tcp_state) => {
+                        let socket = sockets.get::<TcpSocket>(tcp_state.handle);
+                        // once the peer has sent a FIN, no more data can arrive
+                        let closed = !socket.may_recv()
+                            && !matches!(
+                                socket.state(),
+                                TcpSocketState::SynSent | TcpSocketState::SynReceived
+                            );
+                        let mut ready = 0;
+                        if tcp_state.rx_count != entry.rx_seen || socket.can_recv() {
+                            ready |= POLL_READABLE;
+                        }
+                        if socket.can_send() {
+                            ready |= POLL_WRITABLE;
+                        }
+                        if closed {
+                            ready |= POLL_CLOSED;
+                        }
+                        ready
+                    }
+                    None => POLL_INVALID,
+                }
+            }
+            NetPollSocket::TcpListener { local_port } => {
+                let accepts = clients.tcp_listener_accepts.get(&local_port);
+                // all the listeners on a port belong to one process; once they have all been handed over,
+                // the port belongs to whoever got the connections
+                let owner = clients
+                    .tcp_listeners
+                    .get(&local_port)
+                    .and_then(|listeners| listeners.first())
+                    .map(|listener| listener.owner)
+                    .or(accepts.map(|&(owner, _)| owner));
+                match (owner, accepts) {
+                    (Some(owner), _) if owner != pid => POLL_INVALID,
+                    (_, Some(&(_, accepted))) if accepted != entry.rx_seen => POLL_READABLE,
+                    _ if clients.tcp_listeners.contains_key(&local_port) => 0,
+                    _ => POLL_INVALID,
+                }
+            }
+            NetPollSocket::UdpSocket { local_port, cb_sid } => {
+                let is_listener = |udpstate: &UdpState| {
+                    udpstate.owner == pid
+                        && (udpstate.sid.to_array() == cb_sid
+                            || clients
+                                .udp_clones
+                                .get(&local_port)
+                                .map(|clones| clones.contains_key(&cb_sid))
+                                .unwrap_or(false))
+                };
+                match clients
+                    .udp_handles
+                    .get(&local_port)
+                    .filter(|&u| is_listener(u))
+                {
+                    Some(udpstate) => {
+                        let mut ready = 0;
+                        if clients.udp_rx_counts.get(&cb_sid).copied().unwrap_or(0) != entry.rx_seen
+                        {
+                            ready |= POLL_READABLE;
+                        }
+                        if sockets.get::<UdpSocket>(udpstate.handle).can_send() {
+                            ready |= POLL_WRITABLE;
+                        }
+                        ready
+                    }
+                    None => POLL_INVALID,
+                }
+            }
+        };
+        if entry.ready & (entry.interest | POLL_CLOSED | POLL_INVALID) != 0 {
+            any_ready = true;
+        }
+    }
+    any_ready
+}
+
+/// Whether a poll from `pid` can be held on to, given the senders of the polls that are already `waiting`
+fn poll_admit<I>(waiting: I, pid: Option<xous::PID>) -> bool
+where
+    I: Iterator<Item = Option<xous::PID>>,
+{
+    let (total, theirs) = waiting.fold((0, 0), |(total, theirs), sender| {
+        (total + 1, theirs + (sender == pid) as usize)
+    });
+    total < POLL_MAX_WAITING && theirs < POLL_MAX_WAITING_PER_PROCESS
+}
+
+/// Number of sockets held by `pid`, for enforcing `MAX_SOCKETS_PER_PROCESS`
+fn sockets_owned(
+    pid: Option<xous::PID>,
+    tcp_handles: &HashMap<TcpConnection, TcpState>,
+    tcp_listeners: &HashMap<u16, Vec<TcpState>>,
+    udp_handles: &HashMap<u16, UdpState>,
+    process_sockets: &HashMap<Option<xous::PID>, Vec<Option<SocketHandle>>>,
+) -> usize {
+    tcp_handles.values().filter(|s| s.owner == pid).count()
+        + tcp_listeners
+            .values()
+            .flatten()
+            .filter(|s| s.owner == pid)
+            .count()
+        + udp_handles.values().filter(|s| s.owner == pid).count()
+        + process_sockets
+            .get(&pid)
+            .map(|handles| handles.iter().flatten().count())
+            .unwrap_or(0)
+}
+
+/// Describes a TCP socket for `Opcode::SocketList`
+fn tcp_socket_record(
+    sockets: &mut SocketSet,
+    handle: SocketHandle,
+    kind: SocketKind,
+    owner: Option<xous::PID>,
+) -> NetSocketRecord {
+    let socket = sockets.get::<TcpSocket>(handle);
+    let remote = socket.remote_endpoint();
+    NetSocketRecord {
+        kind,
+        pid: owner.map(|pid| pid.get()).unwrap_or(0),
+        local_port: socket.local_endpoint().port,
+        remote_addr: if remote.is_specified() {
+            Some(NetIpAddr::from(remote.addr))
+        } else {
+            None
+        },
+        remote_port: remote.port,
+        tcp_state: TCP_STATES
+            .iter()
+            .position(|&state| state == socket.state())
+            .unwrap_or(0) as u8,
+    }
 }
 
 fn set_com_ints(com_int_list: &mut Vec<ComIntSources>) {
@@ -373,7 +832,7 @@ fn xmain() -> ! {
 
     // bring the EC into a sane state for the network -- that is, reset the EC
     let mut llio = llio::Llio::new(&xns);
-    let com = com::Com::new(&xns).unwrap();
+    let com = device::Com::new(&xns).unwrap();
     let timer = ticktimer_server::Ticktimer::new().unwrap();
 
     // we need a trng for port numbers
@@ -384,6 +843,9 @@ fn xmain() -> ! {
     llio.hook_com_event_callback(Opcode::ComInterrupt.to_u32().unwrap(), net_cid)
         .unwrap();
     llio.com_event_enable(true).unwrap();
+    // in hosted mode, the interrupts come from the simulated network instead
+    #[cfg(not(any(target_os = "none", target_os = "xous")))]
+    com.hook_event_callback(Opcode::ComInterrupt.to_u32().unwrap(), net_cid);
     // setup the interrupt masks
     let mut com_int_list: Vec<ComIntSources> = vec![];
     com.ints_get_active(&mut com_int_list).ok();
@@ -395,6 +857,10 @@ fn xmain() -> ! {
     log::debug!("COM pending interrupts after enabling: {:?}", com_int_list);
     const MAX_DELAY_THREADS: u32 = 10; // limit the number of concurrent delay threads. Typically we have 1-2 running at any time, but DoS conditions could lead to many more.
     let delay_threads = Arc::new(AtomicU32::new(0));
+    // threads that wake the stack up when a waiting poll times out. Each lives for the whole timeout, even if its
+    // poll is answered early, so they are limited separately from the delay threads.
+    const MAX_POLL_TIMERS: u32 = 2 * POLL_MAX_WAITING as u32;
+    let poll_timers = Arc::new(AtomicU32::new(0));
     let mut net_config: Option<Ipv4Conf> = None;
 
     // storage for all our sockets
@@ -449,29 +915,33 @@ fn xmain() -> ! {
     // for Rx, copies of a CID,SID tuple are kept for every clone is kept in a HashMap. This
     // allows for the Rx data to be cc:'d to each clone, and identified by SID upon drop
     let mut udp_clones = HashMap::<u16, HashMap<[u32; 4], CID>>::new(); // additional clones for UDP responders
+    // multicast group memberships are per-interface, so they are reference counted across all the UDP sockets
+    let mut multicast_groups = HashMap::<Ipv4Address, usize>::new();
+    // datagrams delivered to each UDP callback server, primary or clone, keyed by its SID
+    let mut udp_rx_counts = HashMap::<[u32; 4], u32>::new();
+
+    // ipv6 addressing is managed here, as the EC only knows about ipv4
+    let mut ipv6 = Ipv6State::default();
 
     // tcp storage
     let mut tcp_handles = HashMap::<TcpConnection, TcpState>::new();
     let mut tcp_listeners = HashMap::<u16, Vec<TcpState>>::new();
+    // connections handed over by the listeners on each port, for `Opcode::Poll`, and the process they went to
+    let mut tcp_listener_accepts = HashMap::<u16, (Option<xous::PID>, u32)>::new();
+    // closed sockets that are finishing up with their peer, and when to give up on them
+    let mut tcp_closing: Vec<(SocketHandle, u64)> = Vec::new();
+    // when to next check for clients that have gone away without closing their sockets
+    let mut next_sweep: u64 = 0;
 
-    // other link storage
-    let neighbor_cache = NeighborCache::new(BTreeMap::new());
-    let ip_addrs = [IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0)];
-    let routes = Routes::new(BTreeMap::new());
+    // `Opcode::Poll` requests that are waiting for a socket to become ready, with their deadlines
+    let mut poll_waiting: Vec<(xous::MessageEnvelope, Option<u64>)> = Vec::new();
 
+    // other link storage
     let device = device::NetPhy::new(&xns);
     // needed by ICMP to determine if we should compute checksums
     let device_caps = device.capabilities();
     let medium = device.capabilities().medium;
-    let mut builder = InterfaceBuilder::new(device)
-        .ip_addrs(ip_addrs)
-        .routes(routes);
-    if medium == Medium::Ethernet {
-        builder = builder
-            .ethernet_addr(EthernetAddress::from_bytes(&[0; 6]))
-            .neighbor_cache(neighbor_cache);
-    }
-    let mut iface = builder.finalize();
+    let mut iface = build_iface(device, EthernetAddress::from_bytes(&[0; 6]));
 
     // DNS hooks - the DNS server can ask the Net crate to tickle it when IP configs change using these hooks
     // Currently, we assume there is only one DNS server in Xous. I suppose you could
@@ -525,6 +995,46 @@ fn xmain() -> ! {
                 }
             }
         }
+        if let Err(e) = dispatch::check_message(&msg.body) {
+            log::warn!("rejecting malformed message: {:?}", msg);
+            respond_with_error(msg, e);
+            continue;
+        }
+        // a client that has gone away shouldn't hold on to ports, or count against the quota of whoever
+        // gets its PID next, so check on the clients before handing out a new socket
+        if matches!(
+            FromPrimitive::from_usize(msg.body.id() & 0xffff),
+            Some(Opcode::StdTcpConnect)
+                | Some(Opcode::TcpConnect)
+                | Some(Opcode::TcpListen)
+                | Some(Opcode::UdpBind)
+        ) {
+            let now = timer.elapsed_ms();
+            next_sweep = now.saturating_add(release::SWEEP_INTERVAL_MS);
+            let clients = ClientSockets {
+                tcp_handles: &mut tcp_handles,
+                tcp_listeners: &mut tcp_listeners,
+                tcp_listener_accepts: &mut tcp_listener_accepts,
+                udp_handles: &mut udp_handles,
+                udp_clones: &mut udp_clones,
+                udp_rx_counts: &mut udp_rx_counts,
+            };
+            if sweep_clients(
+                clients,
+                &mut sockets,
+                &mut iface,
+                &mut multicast_groups,
+                &mut tcp_closing,
+                now,
+            ) > 0
+            {
+                xous::try_send_message(
+                    net_conn,
+                    Message::new_scalar(Opcode::NetPump.to_usize().unwrap(), 0, 0, 0, 0),
+                )
+                .ok();
+            }
+        }
         match FromPrimitive::from_usize(msg.body.id() & 0xffff) {
             Some(Opcode::Ping) => {
                 let mut buf = unsafe {
@@ -568,58 +1078,79 @@ fn xmain() -> ! {
                         cid,
                         pkt.return_opcode
                     );
-                    // this code will guarantee the sequence number goes up, but if multiple concurrent
-                    // pings are in progress, they may not be directly in sequence. This is OK.
                     let now = timer.elapsed_ms();
-                    if let Some(queue) = ping_destinations.get_mut(&conn) {
-                        queue.insert(seq, now);
-                    } else {
-                        let mut new_queue = HashMap::<u16, u64>::new();
-                        new_queue.insert(seq, now);
-                        ping_destinations.insert(conn, new_queue);
-                    };
-
                     // now emit the actual packet
                     let mut echo_payload = [0xffu8; 40];
                     NetworkEndian::write_i64(&mut echo_payload, now as i64);
-                    match remote {
+                    // sending fails if the address can't be sent to, e.g. if it's unspecified
+                    let sent = match remote {
                         IpAddress::Ipv4(_) => {
                             let icmp_repr = Icmpv4Repr::EchoRequest {
                                 ident,
                                 seq_no: seq,
                                 data: &echo_payload,
                             };
-                            let icmp_payload = socket.send(icmp_repr.buffer_len(), remote).unwrap();
-                            let mut icmp_packet = Icmpv4Packet::new_unchecked(icmp_payload);
-                            icmp_repr.emit(&mut icmp_packet, &device_caps.checksum);
+                            match socket.send(icmp_repr.buffer_len(), remote) {
+                                Ok(icmp_payload) => {
+                                    let mut icmp_packet = Icmpv4Packet::new_unchecked(icmp_payload);
+                                    icmp_repr.emit(&mut icmp_packet, &device_caps.checksum);
+                                    true
+                                }
+                                Err(e) => {
+                                    log::warn!("couldn't send ping to {:?}: {:?}", remote, e);
+                                    false
+                                }
+                            }
                         }
                         IpAddress::Ipv6(_) => {
-                            // not sure if this is a valid thing to do, to just assign the source some number like this??
-                            let src_ipv6 = IpAddress::v6(0xfdaa, 0, 0, 0, 0, 0, 0, 1);
+                            // smoltcp fills in the source address and re-computes the checksum when the packet is dispatched,
+                            // but emit the packet with the source it will end up with anyways
+                            let src_ipv6 = IpAddress::Ipv6(
+                                ipv6.cidrs().first().map(|cidr| cidr.address()).unwrap_or(Ipv6Address::UNSPECIFIED),
+                            );
                             let icmp_repr = Icmpv6Repr::EchoRequest {
                                 ident,
                                 seq_no: seq,
                                 data: &echo_payload,
                             };
-                            let icmp_payload = socket.send(icmp_repr.buffer_len(), remote).unwrap();
-                            let mut icmp_packet = Icmpv6Packet::new_unchecked(icmp_payload);
-                            icmp_repr.emit(
-                                &src_ipv6,
-                                &remote,
-                                &mut icmp_packet,
-                                &device_caps.checksum,
-                            );
+                            match socket.send(icmp_repr.buffer_len(), remote) {
+                                Ok(icmp_payload) => {
+                                    let mut icmp_packet = Icmpv6Packet::new_unchecked(icmp_payload);
+                                    icmp_repr.emit(
+                                        &src_ipv6,
+                                        &remote,
+                                        &mut icmp_packet,
+                                        &device_caps.checksum,
+                                    );
+                                    true
+                                }
+                                Err(e) => {
+                                    log::warn!("couldn't send ping to {:?}: {:?}", remote, e);
+                                    false
+                                }
+                            }
                         }
-                        _ => unimplemented!(),
+                        _ => false,
+                    };
+                    if sent {
+                        // this code will guarantee the sequence number goes up, but if multiple concurrent
+                        // pings are in progress, they may not be directly in sequence. This is OK.
+                        if let Some(queue) = ping_destinations.get_mut(&conn) {
+                            queue.insert(seq, now);
+                        } else {
+                            let mut new_queue = HashMap::<u16, u64>::new();
+                            new_queue.insert(seq, now);
+                            ping_destinations.insert(conn, new_queue);
+                        };
+                        seq += 1;
+                        // fire off a Pump to get the stack to actually transmit the ping; this call merely queues it for sending
+                        xous::try_send_message(
+                            net_conn,
+                            Message::new_scalar(Opcode::NetPump.to_usize().unwrap(), 0, 0, 0, 0),
+                        )
+                        .ok();
                     }
-                    seq += 1;
-                    // fire off a Pump to get the stack to actually transmit the ping; this call merely queues it for sending
-                    xous::try_send_message(
-                        net_conn,
-                        Message::new_scalar(Opcode::NetPump.to_usize().unwrap(), 0, 0, 0, 0),
-                    )
-                    .ok();
-                    pkt.sent_ok = Some(true);
+                    pkt.sent_ok = Some(sent);
                 } else {
                     pkt.sent_ok = Some(false);
                 }
@@ -653,13 +1184,11 @@ fn xmain() -> ! {
                 let hook = buf.to_original::<XousPrivateServerHook, _>().unwrap();
                 if dns_ipv4_hook.is_set() {
                     buf.replace(NetMemResponse::AlreadyUsed).unwrap();
-                } else {
-                    dns_ipv4_hook.set(
-                        xous::connect(SID::from_array(hook.one_time_sid)).unwrap(),
-                        hook.op,
-                        hook.args,
-                    );
+                } else if let Ok(cid) = xous::connect(SID::from_array(hook.one_time_sid)) {
+                    dns_ipv4_hook.set(cid, hook.op, hook.args);
                     buf.replace(NetMemResponse::Ok).unwrap();
+                } else {
+                    buf.replace(NetMemResponse::Invalid).unwrap();
                 }
             }
             Some(Opcode::DnsHookAddIpv6) => {
@@ -669,13 +1198,11 @@ fn xmain() -> ! {
                 let hook = buf.to_original::<XousPrivateServerHook, _>().unwrap();
                 if dns_ipv6_hook.is_set() {
                     buf.replace(NetMemResponse::AlreadyUsed).unwrap();
-                } else {
-                    dns_ipv6_hook.set(
-                        xous::connect(SID::from_array(hook.one_time_sid)).unwrap(),
-                        hook.op,
-                        hook.args,
-                    );
+                } else if let Ok(cid) = xous::connect(SID::from_array(hook.one_time_sid)) {
+                    dns_ipv6_hook.set(cid, hook.op, hook.args);
                     buf.replace(NetMemResponse::Ok).unwrap();
+                } else {
+                    buf.replace(NetMemResponse::Invalid).unwrap();
                 }
             }
             Some(Opcode::DnsHookAllClear) => {
@@ -685,13 +1212,11 @@ fn xmain() -> ! {
                 let hook = buf.to_original::<XousPrivateServerHook, _>().unwrap();
                 if dns_allclear_hook.is_set() {
                     buf.replace(NetMemResponse::AlreadyUsed).unwrap();
-                } else {
-                    dns_allclear_hook.set(
-                        xous::connect(SID::from_array(hook.one_time_sid)).unwrap(),
-                        hook.op,
-                        hook.args,
-                    );
+                } else if let Ok(cid) = xous::connect(SID::from_array(hook.one_time_sid)) {
+                    dns_allclear_hook.set(cid, hook.op, hook.args);
                     buf.replace(NetMemResponse::Ok).unwrap();
+                } else {
+                    buf.replace(NetMemResponse::Invalid).unwrap();
                 }
             }
             Some(Opcode::DnsUnhookAll) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
@@ -705,6 +1230,18 @@ fn xmain() -> ! {
                 // Pick a random locak port using the system's TRNG
                 let local_port = (trng.get_u32().unwrap() % 16384 + 49152) as u16;
                 let pid = msg.sender.pid();
+                if sockets_owned(
+                    pid,
+                    &tcp_handles,
+                    &tcp_listeners,
+                    &udp_handles,
+                    &process_sockets,
+                ) >= MAX_SOCKETS_PER_PROCESS
+                {
+                    log::warn!("{:?} has too many sockets open, refusing to connect", pid);
+                    respond_with_error(msg, NetError::OutOfMemory);
+                    continue;
+                }
 
                 std_tcp_connect(
                     msg,
@@ -752,8 +1289,13 @@ fn xmain() -> ! {
                     respond_with_error(msg, NetError::Invalid);
                     continue;
                 };
-                sockets.get::<TcpSocket>(handle).close();
-                sockets.remove(handle);
+                release::tcp_close(&mut sockets, &mut tcp_closing, handle, timer.elapsed_ms());
+                // get the FIN out
+                xous::try_send_message(
+                    net_conn,
+                    Message::new_scalar(Opcode::NetPump.to_usize().unwrap(), 0, 0, 0, 0),
+                )
+                .ok();
                 if let Some(response) = msg.body.memory_message_mut() {
                     response.buf.as_slice_mut::<u8>()[0] = 0;
                 } else if !msg.body.is_blocking() && msg.body.is_blocking() {
@@ -762,12 +1304,35 @@ fn xmain() -> ! {
             }
 
             Some(Opcode::TcpConnect) => {
+                let pid = msg.sender.pid();
                 let mut buf = unsafe {
                     Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap())
                 };
                 let mut tcpspec = buf.to_original::<NetTcpManage, _>().unwrap(); // need to define this
                 let address = IpAddress::from(tcpspec.ip_addr);
                 let remote_port = tcpspec.remote_port;
+                if sockets_owned(
+                    pid,
+                    &tcp_handles,
+                    &tcp_listeners,
+                    &udp_handles,
+                    &process_sockets,
+                ) >= MAX_SOCKETS_PER_PROCESS
+                {
+                    log::warn!("{:?} has too many sockets open, refusing to connect", pid);
+                    tcpspec.result = Some(NetMemResponse::OutOfMemory);
+                    buf.replace(tcpspec).unwrap();
+                    continue;
+                }
+                let cid = match xous::connect(SID::from_array(tcpspec.cb_sid)) {
+                    Ok(cid) => cid,
+                    Err(e) => {
+                        log::warn!("TcpConnect callback server can't be reached: {:?}", e);
+                        tcpspec.result = Some(NetMemResponse::Invalid);
+                        buf.replace(tcpspec).unwrap();
+                        continue;
+                    }
+                };
 
                 // initiates a new connection to a remote server consisting of an (Address:Port) tuple.
                 // multiple connections can exist to a server, and they are further differentiated by the return port
@@ -789,12 +1354,12 @@ fn xmain() -> ! {
                             local_port,
                         };
                         let handle = sockets.add(tcp_socket);
-                        let sid = tcpspec.cb_sid;
-                        let cid = xous::connect(SID::from_array(sid)).unwrap();
                         let tcp_cb_state = TcpState {
                             handle,
                             cid,
                             shutdown_rx: false,
+                            rx_count: 0,
+                            owner: pid,
                         };
                         tcp_handles.insert(connection, tcp_cb_state);
                         tcpspec.local_port = Some(local_port);
@@ -815,6 +1380,7 @@ fn xmain() -> ! {
                 buf.replace(tcpspec).unwrap();
             }
             Some(Opcode::TcpManage) => {
+                let pid = msg.sender.pid();
                 let mut buf = unsafe {
                     Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap())
                 };
@@ -825,7 +1391,9 @@ fn xmain() -> ! {
                         remote_port: tcpspec.remote_port,
                         local_port,
                     };
-                    if let Some(tcp_state) = tcp_handles.get_mut(&connection) {
+                    if let Some(tcp_state) =
+                        tcp_handles.get_mut(&connection).filter(|s| s.owner == pid)
+                    {
                         if let Some(code) = tcpspec.mgmt_code {
                             match code {
                                 TcpMgmtCode::SetRxShutdown => {
@@ -902,6 +1470,7 @@ fn xmain() -> ! {
             }
 
             Some(Opcode::TcpTx) => {
+                let pid = msg.sender.pid();
                 let mut buf = unsafe {
                     Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap())
                 };
@@ -911,7 +1480,12 @@ fn xmain() -> ! {
                     remote_port: tcp_tx.remote_port,
                     local_port: tcp_tx.local_port,
                 };
-                if let Some(tcp_state) = tcp_handles.get(&connection) {
+                if tcp_tx.len as usize > tcp_tx.data.len() {
+                    log::warn!("tx length {} is longer than the buffer", tcp_tx.len);
+                    tcp_tx.result = Some(NetMemResponse::Invalid);
+                } else if let Some(tcp_state) =
+                    tcp_handles.get(&connection).filter(|s| s.owner == pid)
+                {
                     let mut socket = sockets.get::<TcpSocket>(tcp_state.handle);
                     if socket.may_send() {
                         tcp_tx.result = match socket.send_slice(&tcp_tx.data[..tcp_tx.len as usize])
@@ -934,7 +1508,50 @@ fn xmain() -> ! {
                 }
                 buf.replace(tcp_tx).unwrap();
             }
+            Some(Opcode::TcpPeek) => {
+                let pid = msg.sender.pid();
+                let mut buf = unsafe {
+                    Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap())
+                };
+                let mut peek = buf.to_original::<NetTcpPeek, _>().unwrap();
+                let connection = TcpConnection {
+                    remote: IpAddress::from(peek.ip_addr),
+                    remote_port: peek.remote_port,
+                    local_port: peek.local_port,
+                };
+                let wanted = (peek.len as usize).min(peek.data.len());
+                peek.len = 0;
+                if let Some(tcp_state) = tcp_handles.get(&connection).filter(|s| s.owner == pid) {
+                    let mut socket = sockets.get::<TcpSocket>(tcp_state.handle);
+                    peek.result = if tcp_state.rx_count != peek.rx_seen {
+                        // data is still on its way to the caller, and it comes before anything we're holding
+                        Some(NetMemResponse::Ok)
+                    } else if socket.can_recv() {
+                        match socket.peek_slice(&mut peek.data[..wanted]) {
+                            Ok(octets) => {
+                                peek.len = octets as u16;
+                                Some(NetMemResponse::Ok)
+                            }
+                            Err(_) => Some(NetMemResponse::LibraryError),
+                        }
+                    } else if !socket.may_recv()
+                        && !matches!(
+                            socket.state(),
+                            TcpSocketState::SynSent | TcpSocketState::SynReceived
+                        )
+                    {
+                        // the peer has closed its end, and everything it sent has been delivered
+                        Some(NetMemResponse::Finished)
+                    } else {
+                        Some(NetMemResponse::Ok)
+                    };
+                } else {
+                    peek.result = Some(NetMemResponse::Invalid);
+                }
+                buf.replace(peek).unwrap();
+            }
             Some(Opcode::TcpClose) => {
+                let pid = msg.sender.pid();
                 let mut buf = unsafe {
                     Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap())
                 };
@@ -945,9 +1562,20 @@ fn xmain() -> ! {
                         remote_port: tcpspec.remote_port,
                         local_port,
                     };
-                    if let Some(tcp_state) = tcp_handles.remove(&connection) {
-                        sockets.get::<TcpSocket>(tcp_state.handle).close();
-                        sockets.remove(tcp_state.handle);
+                    if tcp_handles.get(&connection).map(|s| s.owner) == Some(pid) {
+                        let tcp_state = tcp_handles.remove(&connection).unwrap();
+                        release::tcp_close(
+                            &mut sockets,
+                            &mut tcp_closing,
+                            tcp_state.handle,
+                            timer.elapsed_ms(),
+                        );
+                        // get the FIN out
+                        xous::try_send_message(
+                            net_conn,
+                            Message::new_scalar(Opcode::NetPump.to_usize().unwrap(), 0, 0, 0, 0),
+                        )
+                        .ok();
                         tcpspec.result = Some(NetMemResponse::Ok);
                     } else {
                         tcpspec.result = Some(NetMemResponse::Invalid);
@@ -958,10 +1586,46 @@ fn xmain() -> ! {
                 buf.replace(tcpspec).unwrap();
             }
             Some(Opcode::TcpListen) => {
+                let pid = msg.sender.pid();
                 let mut buf = unsafe {
                     Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap())
                 };
                 let mut tcpspec = buf.to_original::<NetTcpListen, _>().unwrap();
+                let sid = tcpspec.cb_sid;
+                if matches!(tcp_listeners.get(&tcpspec.local_port).and_then(|list| list.first()),
+                    Some(listener) if listener.owner != pid)
+                {
+                    log::warn!(
+                        "{:?} can't listen on port {}, another process is listening on it",
+                        pid,
+                        tcpspec.local_port
+                    );
+                    tcpspec.result = Some(NetMemResponse::SocketInUse);
+                    buf.replace(tcpspec).unwrap();
+                    continue;
+                }
+                if sockets_owned(
+                    pid,
+                    &tcp_handles,
+                    &tcp_listeners,
+                    &udp_handles,
+                    &process_sockets,
+                ) >= MAX_SOCKETS_PER_PROCESS
+                {
+                    log::warn!("{:?} has too many sockets open, refusing to listen", pid);
+                    tcpspec.result = Some(NetMemResponse::OutOfMemory);
+                    buf.replace(tcpspec).unwrap();
+                    continue;
+                }
+                let cid = match xous::connect(SID::from_array(sid)) {
+                    Ok(cid) => cid,
+                    Err(e) => {
+                        log::warn!("TcpListen callback server can't be reached: {:?}", e);
+                        tcpspec.result = Some(NetMemResponse::Invalid);
+                        buf.replace(tcpspec).unwrap();
+                        continue;
+                    }
+                };
 
                 let tcp_rx_buffer = TcpSocketBuffer::new(vec![0; TCP_BUFFER_SIZE]);
                 let tcp_tx_buffer = TcpSocketBuffer::new(vec![0; TCP_BUFFER_SIZE]);
@@ -978,14 +1642,19 @@ fn xmain() -> ! {
                             }
                         }
                         let handle = sockets.add(tcp_socket);
-                        let sid = tcpspec.cb_sid;
-                        let cid = xous::connect(SID::from_array(sid)).unwrap();
                         log::trace!("Listener with cid {}, sid {:x?} registered", cid, sid);
                         let tcp_cb_state = TcpState {
                             handle,
                             cid,
                             shutdown_rx: false,
+                            rx_count: 0,
+                            owner: pid,
                         };
+                        if matches!(tcp_listener_accepts.get(&tcpspec.local_port), Some(&(owner, _)) if owner != pid)
+                        {
+                            // the port has changed hands, so start counting the connections over
+                            tcp_listener_accepts.remove(&tcpspec.local_port);
+                        }
                         if let Some(list) = tcp_listeners.get_mut(&tcpspec.local_port) {
                             list.push(tcp_cb_state);
                             log::trace!(
@@ -1014,32 +1683,54 @@ fn xmain() -> ! {
                 buf.replace(tcpspec).unwrap();
             }
             Some(Opcode::TcpManageListener) => {
+                let pid = msg.sender.pid();
                 let mut buf = unsafe {
                     Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap())
                 };
                 let mut tcpspec = buf.to_original::<NetTcpManage, _>().unwrap();
+                let local_port = match tcpspec.local_port {
+                    Some(port) => port,
+                    None => {
+                        tcpspec.result = Some(NetMemResponse::Invalid);
+                        buf.replace(tcpspec).unwrap();
+                        continue;
+                    }
+                };
+                if tcp_listeners
+                    .get(&local_port)
+                    .map(|list| list.iter().any(|listener| listener.owner != pid))
+                    .unwrap_or(false)
+                {
+                    tcpspec.result = Some(NetMemResponse::AccessDenied);
+                    buf.replace(tcpspec).unwrap();
+                    continue;
+                }
                 match tcpspec.mgmt_code {
                     Some(TcpMgmtCode::CloseListener) => {
-                        if let Some(listener) = tcp_listeners.get_mut(&tcpspec.local_port.unwrap())
-                        {
+                        if let Some(listener) = tcp_listeners.get_mut(&local_port) {
                             if let Some(tcp_state) = listener.pop() {
-                                sockets.get::<TcpSocket>(tcp_state.handle).close();
                                 log::debug!(
                                     "closing one listener on port {:?}",
                                     tcpspec.local_port
                                 );
-                                sockets.remove(tcp_state.handle);
+                                release::tcp_close(
+                                    &mut sockets,
+                                    &mut tcp_closing,
+                                    tcp_state.handle,
+                                    timer.elapsed_ms(),
+                                );
                                 tcpspec.result = Some(NetMemResponse::Ok);
                                 // this may leave an empty vector in the tcp_listeners structure, but I think that's OK
                             }
+                            if listener.is_empty() {
+                                tcp_listener_accepts.remove(&local_port);
+                            }
                         } else {
                             tcpspec.result = Some(NetMemResponse::Invalid);
                         }
                     }
                     Some(TcpMgmtCode::SetTtl(mut ttl)) => {
-                        if let Some(listener_vec) =
-                            tcp_listeners.get_mut(&tcpspec.local_port.unwrap())
-                        {
+                        if let Some(listener_vec) = tcp_listeners.get_mut(&local_port) {
                             for listener in listener_vec.iter_mut() {
                                 let mut socket = sockets.get::<TcpSocket>(listener.handle);
                                 if ttl > 255 {
@@ -1057,7 +1748,7 @@ fn xmain() -> ! {
                         }
                     }
                     Some(TcpMgmtCode::GetTtl(_)) => {
-                        if let Some(listener) = tcp_listeners.get(&tcpspec.local_port.unwrap()) {
+                        if let Some(listener) = tcp_listeners.get(&local_port) {
                             if listener.len() > 0 {
                                 // all listeners "should" have an identical setting, so, just return the setting of the 0th one
                                 let socket = sockets.get::<TcpSocket>(listener[0].handle);
@@ -1076,10 +1767,44 @@ fn xmain() -> ! {
                 buf.replace(tcpspec).unwrap();
             }
             Some(Opcode::UdpBind) => {
+                let pid = msg.sender.pid();
                 let mut buf = unsafe {
                     Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap())
                 };
                 let udpspec = buf.to_original::<NetUdpBind, _>().unwrap();
+                let sid = udpspec.cb_sid;
+                match udp_handles.get(&udpspec.port) {
+                    Some(udpstate) if udpstate.owner != pid => {
+                        log::warn!(
+                            "{:?} can't bind to udp:{}, another process has it",
+                            pid,
+                            udpspec.port
+                        );
+                        buf.replace(NetMemResponse::SocketInUse).unwrap();
+                        continue;
+                    }
+                    None if sockets_owned(
+                        pid,
+                        &tcp_handles,
+                        &tcp_listeners,
+                        &udp_handles,
+                        &process_sockets,
+                    ) >= MAX_SOCKETS_PER_PROCESS =>
+                    {
+                        log::warn!("{:?} has too many sockets open, refusing to bind", pid);
+                        buf.replace(NetMemResponse::OutOfMemory).unwrap();
+                        continue;
+                    }
+                    _ => {}
+                }
+                let cid = match xous::connect(SID::from_array(sid)) {
+                    Ok(cid) => cid,
+                    Err(e) => {
+                        log::warn!("UdpBind callback server can't be reached: {:?}", e);
+                        buf.replace(NetMemResponse::Invalid).unwrap();
+                        continue;
+                    }
+                };
 
                 let buflen = if let Some(maxlen) = udpspec.max_payload {
                     maxlen as usize
@@ -1088,8 +1813,6 @@ fn xmain() -> ! {
                 };
                 if udp_handles.contains_key(&udpspec.port) {
                     // if we're already connected, just register the extra listener in the clones array
-                    let sid = udpspec.cb_sid;
-                    let cid = xous::connect(SID::from_array(sid)).unwrap();
                     if let Some(clone_map) = udp_clones.get_mut(&udpspec.port) {
                         // if a clone already exists, put the additional clone into the map
                         match clone_map.insert(sid, cid) {
@@ -1114,11 +1837,16 @@ fn xmain() -> ! {
                     let mut udp_socket = UdpSocket::new(udp_rx_buffer, udp_tx_buffer);
                     match udp_socket.bind(udpspec.port) {
                         Ok(_) => {
-                            let sid = SID::from_array(udpspec.cb_sid);
                             let udpstate = UdpState {
                                 handle: sockets.add(udp_socket),
-                                cid: xous::connect(sid).unwrap(),
-                                sid,
+                                cid,
+                                sid: SID::from_array(sid),
+                                owner: pid,
+                                ttl: None,
+                                multicast_ttl: 1,
+                                broadcast: false,
+                                multicast_loop: true,
+                                multicast_groups: Vec::new(),
                             };
                             udp_handles.insert(udpspec.port, udpstate);
                             buf.replace(NetMemResponse::Ok).unwrap();
@@ -1135,6 +1863,7 @@ fn xmain() -> ! {
                     Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap())
                 };
                 let udpspec = buf.to_original::<NetUdpBind, _>().unwrap();
+                udp_rx_counts.remove(&udpspec.cb_sid);
                 // need to find the SID that matches either in the clone array, or the primary binding.
                 // first check the clone array, then fall back to the primary binding
                 match udp_clones.get_mut(&udpspec.port) {
@@ -1156,6 +1885,12 @@ fn xmain() -> ! {
                                 None => {
                                     sockets.get::<UdpSocket>(udpstate.handle).close();
                                     sockets.remove(udpstate.handle);
+                                    release_multicast_groups(
+                                        &mut iface,
+                                        &mut multicast_groups,
+                                        &udpstate.multicast_groups,
+                                        Instant::from_millis(timer.elapsed_ms() as i64),
+                                    );
                                     buf.replace(NetMemResponse::Ok).unwrap();
                                 }
                                 // if the clone map has entries, promote an arbitrary map entry to the primary handle
@@ -1165,14 +1900,21 @@ fn xmain() -> ! {
                                         udp_clones.remove(&udpspec.port);
                                         sockets.get::<UdpSocket>(udpstate.handle).close();
                                         sockets.remove(udpstate.handle);
+                                        release_multicast_groups(
+                                            &mut iface,
+                                            &mut multicast_groups,
+                                            &udpstate.multicast_groups,
+                                            Instant::from_millis(timer.elapsed_ms() as i64),
+                                        );
                                         buf.replace(NetMemResponse::Ok).unwrap();
                                     } else {
                                         // take an arbitrary key, re-insert it into the handles map.
                                         let new_primary_sid = *clone_map.keys().next().unwrap(); // unwrap is appropriate because len already checked as not 0
+                                        // the socket options belong to the port, so they carry over to the new primary
                                         let udpstate = UdpState {
-                                            handle: udpstate.handle,
                                             cid: *clone_map.get(&new_primary_sid).unwrap(),
                                             sid: SID::from_array(new_primary_sid),
+                                            ..udpstate
                                         };
                                         udp_handles.insert(udpspec.port, udpstate);
                                         // now remove it from the clone map
@@ -1191,21 +1933,49 @@ fn xmain() -> ! {
                 }
             }
             Some(Opcode::UdpTx) => {
-                use std::convert::TryInto;
                 let mut buf = unsafe {
                     Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap())
                 };
                 let udp_tx = buf.to_original::<NetUdpTransmit, _>().unwrap();
+                let mut loopback: Option<IpEndpoint> = None;
                 match udp_handles.get_mut(&udp_tx.local_port) {
                     Some(udpstate) => {
-                        if let Some(dest_socket) = udp_tx.dest_socket {
-                            let endpoint = IpEndpoint::new(
-                                dest_socket.addr.try_into().unwrap(),
-                                dest_socket.port,
-                            );
+                        if udp_tx.len as usize > udp_tx.data.len() {
+                            log::warn!("udp:{} tx length {} is longer than the buffer", udp_tx.local_port, udp_tx.len);
+                            buf.replace(NetMemResponse::Invalid).unwrap();
+                        } else if let Some(dest_socket) = udp_tx.dest_socket {
+                            let dest_addr = IpAddress::from(dest_socket.addr);
+                            let endpoint = IpEndpoint::new(dest_addr, dest_socket.port);
+                            if !udpstate.broadcast && is_broadcast(&iface, dest_addr) {
+                                log::warn!(
+                                    "udp:{} refusing to send to broadcast address {} without SO_BROADCAST",
+                                    udp_tx.local_port,
+                                    dest_addr
+                                );
+                                buf.replace(NetMemResponse::AccessDenied).unwrap();
+                                continue;
+                            }
                             let mut socket = sockets.get::<UdpSocket>(udpstate.handle);
+                            // smoltcp has a single hop limit per socket, which is applied when the datagram is dispatched.
+                            // The tx buffer holds just one datagram, so setting it here (only if the buffer is empty)
+                            // ensures the datagram goes out with the TTL that matches its destination.
+                            if socket.can_send() {
+                                if dest_addr.is_multicast() {
+                                    socket.set_hop_limit(Some(udpstate.multicast_ttl));
+                                } else {
+                                    socket.set_hop_limit(udpstate.ttl);
+                                }
+                            }
                             match socket.send_slice(&udp_tx.data[..udp_tx.len as usize], endpoint) {
-                                Ok(_) => buf.replace(NetMemResponse::Sent(udp_tx.len)).unwrap(),
+                                Ok(_) => {
+                                    buf.replace(NetMemResponse::Sent(udp_tx.len)).unwrap();
+                                    if dest_addr.is_multicast()
+                                        && udpstate.multicast_loop
+                                        && iface.has_multicast_group(dest_addr)
+                                    {
+                                        loopback = Some(endpoint);
+                                    }
+                                }
                                 _ => buf.replace(NetMemResponse::LibraryError).unwrap(),
                             }
                             // fire off a Pump to get the stack to actually transmit the ping; the send call merely queues it for sending
@@ -1226,12 +1996,40 @@ fn xmain() -> ! {
                     }
                     _ => buf.replace(NetMemResponse::Invalid).unwrap(),
                 }
+                // smoltcp doesn't loop multicast back to the host, so hand a copy straight to the local listener. As with
+                // datagrams from the network, it only goes to a socket that has joined the group.
+                if let Some(endpoint) = loopback {
+                    if let Some(listener) = udp_handles
+                        .get(&endpoint.port)
+                        .filter(|listener| listener.joined(endpoint.addr))
+                    {
+                        let src_addr = iface
+                            .ip_addrs()
+                            .iter()
+                            .map(|cidr| cidr.address())
+                            .find(|addr| matches!(addr, IpAddress::Ipv4(_)))
+                            .unwrap_or(IpAddress::Ipv4(Ipv4Address::UNSPECIFIED));
+                        let response = NetUdpResponse {
+                            endpoint_ip_addr: NetIpAddr::from(src_addr),
+                            len: udp_tx.len,
+                            endpoint_port: udp_tx.local_port,
+                            data: udp_tx.data,
+                        };
+                        udp_deliver(
+                            response,
+                            listener,
+                            udp_clones.get(&endpoint.port),
+                            &mut udp_rx_counts,
+                        );
+                    }
+                }
             }
             Some(Opcode::UdpSetTtl) => msg_scalar_unpack!(msg, ttl, port, _, _, {
                 match udp_handles.get_mut(&(port as u16)) {
                     Some(udpstate) => {
                         let mut socket = sockets.get::<UdpSocket>(udpstate.handle);
                         let checked_ttl = if ttl > 255 || ttl == 0 { 64 } else { ttl as u8 };
+                        udpstate.ttl = Some(checked_ttl);
                         socket.set_hop_limit(Some(checked_ttl));
                     }
                     None => {
@@ -1246,8 +2044,8 @@ fn xmain() -> ! {
             Some(Opcode::UdpGetTtl) => msg_blocking_scalar_unpack!(msg, port, _, _, _, {
                 match udp_handles.get_mut(&(port as u16)) {
                     Some(udpstate) => {
-                        let socket = sockets.get::<UdpSocket>(udpstate.handle);
-                        let ttl = socket.hop_limit().unwrap_or(64); // 64 is the value used by smoltcp if hop limit isn't set
+                        // the socket's hop limit tracks the last destination, so report the unicast TTL from our copy
+                        let ttl = udpstate.ttl.unwrap_or(64); // 64 is the value used by smoltcp if hop limit isn't set
                         xous::return_scalar(msg.sender, ttl as usize).expect("couldn't return TTL");
                     }
                     None => {
@@ -1259,6 +2057,110 @@ fn xmain() -> ! {
                     }
                 }
             }),
+            Some(Opcode::UdpSetBroadcast) => msg_scalar_unpack!(msg, broadcast, port, _, _, {
+                match udp_handles.get_mut(&(port as u16)) {
+                    Some(udpstate) => udpstate.broadcast = broadcast != 0,
+                    None => log::error!("Set broadcast message received, but no port was bound! port {}", port),
+                }
+            }),
+            Some(Opcode::UdpGetBroadcast) => msg_blocking_scalar_unpack!(msg, port, _, _, _, {
+                let broadcast = match udp_handles.get(&(port as u16)) {
+                    Some(udpstate) => udpstate.broadcast as usize,
+                    None => usize::MAX,
+                };
+                xous::return_scalar(msg.sender, broadcast).expect("couldn't return broadcast");
+            }),
+            Some(Opcode::UdpSetMulticastLoopV4) => msg_scalar_unpack!(msg, multicast_loop, port, _, _, {
+                match udp_handles.get_mut(&(port as u16)) {
+                    Some(udpstate) => udpstate.multicast_loop = multicast_loop != 0,
+                    None => log::error!("Set multicast loop message received, but no port was bound! port {}", port),
+                }
+            }),
+            Some(Opcode::UdpGetMulticastLoopV4) => msg_blocking_scalar_unpack!(msg, port, _, _, _, {
+                let multicast_loop = match udp_handles.get(&(port as u16)) {
+                    Some(udpstate) => udpstate.multicast_loop as usize,
+                    None => usize::MAX,
+                };
+                xous::return_scalar(msg.sender, multicast_loop).expect("couldn't return multicast loop");
+            }),
+            Some(Opcode::UdpSetMulticastTtlV4) => msg_scalar_unpack!(msg, ttl, port, _, _, {
+                match udp_handles.get_mut(&(port as u16)) {
+                    // smoltcp can't emit a hop limit of 0, so fall back to the link-local default of 1
+                    Some(udpstate) => udpstate.multicast_ttl = if ttl > 255 || ttl == 0 { 1 } else { ttl as u8 },
+                    None => log::error!("Set multicast TTL message received, but no port was bound! port {}", port),
+                }
+            }),
+            Some(Opcode::UdpGetMulticastTtlV4) => msg_blocking_scalar_unpack!(msg, port, _, _, _, {
+                let ttl = match udp_handles.get(&(port as u16)) {
+                    Some(udpstate) => udpstate.multicast_ttl as usize,
+                    None => usize::MAX,
+                };
+                xous::return_scalar(msg.sender, ttl).expect("couldn't return multicast TTL");
+            }),
+            Some(Opcode::UdpJoinMulticastV4) => msg_blocking_scalar_unpack!(msg, port, group, interface, _, {
+                let group = Ipv4Address::from_bytes(&(group as u32).to_be_bytes());
+                let interface = Ipv4Address::from_bytes(&(interface as u32).to_be_bytes());
+                let code = match udp_handles.get_mut(&(port as u16)) {
+                    Some(udpstate) => {
+                        if !group.is_multicast() || !(interface.is_unspecified() || iface.has_ip_addr(interface)) {
+                            NetError::Invalid
+                        } else if udpstate.multicast_groups.contains(&group) {
+                            NetError::AlreadyUsed
+                        } else {
+                            let joined = if multicast_groups.contains_key(&group) {
+                                true
+                            } else {
+                                let timestamp = Instant::from_millis(timer.elapsed_ms() as i64);
+                                match iface.join_multicast_group(group, timestamp) {
+                                    Ok(_) => true,
+                                    // the group is recorded before the IGMP report goes out, so if only the report failed we
+                                    // are still a member, and the membership will be reported on the router's next query.
+                                    Err(e) => {
+                                        log::warn!("error joining multicast group {}: {:?}", group, e);
+                                        iface.has_multicast_group(group)
+                                    }
+                                }
+                            };
+                            if joined {
+                                *multicast_groups.entry(group).or_insert(0) += 1;
+                                udpstate.multicast_groups.push(group);
+                                NetError::Ok
+                            } else {
+                                NetError::OutOfMemory
+                            }
+                        }
+                    }
+                    None => {
+                        log::error!("Join multicast message received, but no port was bound! port {}", port);
+                        NetError::Invalid
+                    }
+                };
+                xous::return_scalar(msg.sender, code as usize).expect("couldn't return multicast join result");
+            }),
+            Some(Opcode::UdpLeaveMulticastV4) => msg_blocking_scalar_unpack!(msg, port, group, _, _, {
+                let group = Ipv4Address::from_bytes(&(group as u32).to_be_bytes());
+                let code = match udp_handles.get_mut(&(port as u16)) {
+                    Some(udpstate) => {
+                        if let Some(index) = udpstate.multicast_groups.iter().position(|&g| g == group) {
+                            udpstate.multicast_groups.remove(index);
+                            release_multicast_groups(
+                                &mut iface,
+                                &mut multicast_groups,
+                                &[group],
+                                Instant::from_millis(timer.elapsed_ms() as i64),
+                            );
+                            NetError::Ok
+                        } else {
+                            NetError::Invalid
+                        }
+                    }
+                    None => {
+                        log::error!("Leave multicast message received, but no port was bound! port {}", port);
+                        NetError::Invalid
+                    }
+                };
+                xous::return_scalar(msg.sender, code as usize).expect("couldn't return multicast leave result");
+            }),
 
             Some(Opcode::ComInterrupt) => {
                 com_int_list.clear();
@@ -1312,47 +2214,24 @@ fn xmain() -> ! {
                                     // be able to route responses back. I can't seem to find a function in smoltcp 0.7.5 that allows us
                                     // to neatly clear the ARP cache as the BTreeMap that underlies it is moved into the container and
                                     // no "clear" API is exposed, so let's just rebuild the whole interface if we get a DHCP renewal.
-                                    let neighbor_cache = NeighborCache::new(BTreeMap::new());
-                                    let ip_addrs =
-                                        [IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0)];
-                                    let routes = Routes::new(BTreeMap::new());
-                                    let device = device::NetPhy::new(&xns);
-                                    let medium = device.capabilities().medium;
-                                    let mut builder = InterfaceBuilder::new(device)
-                                        .ip_addrs(ip_addrs)
-                                        .routes(routes);
+                                    iface = build_iface(device::NetPhy::new(&xns), mac);
+                                    set_ipv4_config(&mut iface, &config);
+                                    // the link-local address comes from the MAC. A router solicitation gets the routers on
+                                    // the new link to advertise right away, so SLAAC doesn't have to wait for their next interval.
+                                    let link_local = link_local_from_mac(&mac);
+                                    ipv6.link_local = Some(link_local);
+                                    set_ipv6(&mut iface, &ipv6);
                                     if medium == Medium::Ethernet {
-                                        builder = builder
-                                            .ethernet_addr(mac)
-                                            .neighbor_cache(neighbor_cache);
+                                        if let Err(e) = iface.device_mut().send_router_solicit(mac, link_local.address()) {
+                                            log::warn!("couldn't send router solicitation: {:?}", e);
+                                        }
                                     }
-                                    iface = builder.finalize();
-
-                                    let ip_addr = Ipv4Cidr::new(
-                                        Ipv4Address::new(
-                                            config.addr[0],
-                                            config.addr[1],
-                                            config.addr[2],
-                                            config.addr[3],
-                                        ),
-                                        24,
-                                    );
-                                    set_ipv4_addr(&mut iface, ip_addr);
-                                    let default_v4_gw = Ipv4Address::new(
-                                        config.gtwy[0],
-                                        config.gtwy[1],
-                                        config.gtwy[2],
-                                        config.gtwy[3],
-                                    );
-
-                                    // reset the default route, in case it has changed
-                                    iface.routes_mut().remove_default_ipv4_route();
-                                    match iface.routes_mut().add_default_ipv4_route(default_v4_gw) {
-                                        Ok(route) => log::info!(
-                                            "routing table updated successfully [{:?}]",
-                                            route
-                                        ),
-                                        Err(e) => log::error!("routing table update error: {}", e),
+                                    // the rebuilt interface has no multicast memberships: re-join the groups our sockets are in
+                                    let timestamp = Instant::from_millis(timer.elapsed_ms() as i64);
+                                    for &group in multicast_groups.keys() {
+                                        if let Err(e) = iface.join_multicast_group(group, timestamp) {
+                                            log::warn!("couldn't re-join multicast group {}: {:?}", group, e);
+                                        }
                                     }
                                     dns_allclear_hook.notify();
                                     dns_ipv4_hook.notify_custom_args([
@@ -1439,9 +2318,62 @@ fn xmain() -> ! {
                     }
                 }
 
+                // retire the closed sockets that are done with their peers, and check on the clients now and then
+                {
+                    let now = timer.elapsed_ms();
+                    release::tcp_reap(&mut sockets, &mut tcp_closing, now);
+                    if now >= next_sweep {
+                        next_sweep = now.saturating_add(release::SWEEP_INTERVAL_MS);
+                        let clients = ClientSockets {
+                            tcp_handles: &mut tcp_handles,
+                            tcp_listeners: &mut tcp_listeners,
+                            tcp_listener_accepts: &mut tcp_listener_accepts,
+                            udp_handles: &mut udp_handles,
+                            udp_clones: &mut udp_clones,
+                            udp_rx_counts: &mut udp_rx_counts,
+                        };
+                        if sweep_clients(
+                            clients,
+                            &mut sockets,
+                            &mut iface,
+                            &mut multicast_groups,
+                            &mut tcp_closing,
+                            now,
+                        ) > 0
+                        {
+                            xous::try_send_message(
+                                net_conn,
+                                Message::new_scalar(
+                                    Opcode::NetPump.to_usize().unwrap(),
+                                    0,
+                                    0,
+                                    0,
+                                    0,
+                                ),
+                            )
+                            .ok();
+                        }
+                    }
+                }
+
+                // SLAAC: apply router advertisements picked up by the device, and retire whatever has expired
+                {
+                    let now = timer.elapsed_ms();
+                    let mut changed = false;
+                    if let Some(advert) = iface.device_mut().take_router_advert() {
+                        log::debug!("router advertisement: {:?}", advert);
+                        changed |= ipv6.router_advert(&advert, now);
+                    }
+                    changed |= ipv6.expire(now);
+                    if changed {
+                        log::info!("IPv6 addresses: {:?}, gateway: {:?}", ipv6.cidrs(), ipv6.gateway());
+                        set_ipv6(&mut iface, &ipv6);
+                    }
+                }
+
                 // this block handles TCP rx
                 {
-                    for (_connection, tcp_state) in tcp_handles.iter() {
+                    for (_connection, tcp_state) in tcp_handles.iter_mut() {
                         if !tcp_state.shutdown_rx {
                             let mut socket = sockets.get::<TcpSocket>(tcp_state.handle);
                             if socket.can_recv() {
@@ -1455,14 +2387,18 @@ fn xmain() -> ! {
                                     }
                                     let buf = Buffer::into_buf(response)
                                         .expect("couldn't convert TCP response to memory message");
-                                    buf.send(
+                                    // a client that has gone away is cleaned up by the next sweep
+                                    if let Err(e) = buf.send(
                                         tcp_state.cid,
                                         NetTcpCallback::RxData.to_u32().unwrap(),
-                                    )
-                                    .expect("couldn't send TCP response");
+                                    ) {
+                                        log::warn!("couldn't send TCP response: {:?}", e);
+                                    }
                                     (data.len(), ())
                                 }) {
-                                    Ok(_) => (),
+                                    Ok(_) => {
+                                        tcp_state.rx_count = tcp_state.rx_count.wrapping_add(1)
+                                    }
                                     Err(e) => match e {
                                         smoltcp::Error::Illegal => {
                                             log::warn!("TCP fast open not supported");
@@ -1578,6 +2514,10 @@ fn xmain() -> ! {
                                     local_port,
                                 };
                                 tcp_handles.insert(connection, *tcp_state);
+                                let accepts = tcp_listener_accepts
+                                    .entry(local_port)
+                                    .or_insert((tcp_state.owner, 0));
+                                accepts.1 = accepts.1.wrapping_add(1);
 
                                 // 2. notify the listener
                                 let note = NetTcpListenCallback {
@@ -1592,11 +2532,12 @@ fn xmain() -> ! {
                                 );
                                 let buf =
                                     Buffer::into_buf(note).expect("can't transform memory message");
-                                buf.send(
+                                if let Err(e) = buf.send(
                                     tcp_state.cid,
                                     NetTcpCallback::ListenerActive.to_u32().unwrap(),
-                                )
-                                .expect("can't inform callback of active status");
+                                ) {
+                                    log::warn!("can't inform callback of active status: {:?}", e);
+                                }
                                 log::trace!("listener index {} to remove", index);
                                 remove_indices.push(index);
                                 break;
@@ -1642,20 +2583,12 @@ fn xmain() -> ! {
                                 for (&src, dst) in data.iter().zip(response.data.iter_mut()) {
                                     *dst = src;
                                 }
-                                let buf = Buffer::into_buf(response)
-                                    .expect("couldn't convert UDP response to memory message");
-                                buf.send(udpstate.cid, NetUdpCallback::RxData.to_u32().unwrap())
-                                    .expect("couldn't send UDP response");
-                                // now send copies to the cloned receiver array, if they exist
-                                if let Some(clone_map) = udp_clones.get(port) {
-                                    for &cids in clone_map.values() {
-                                        let buf = Buffer::into_buf(response).expect(
-                                            "couldn't convert UDP response to memory message",
-                                        );
-                                        buf.send(cids, NetUdpCallback::RxData.to_u32().unwrap())
-                                            .expect("couldn't send UDP response");
-                                    }
-                                }
+                                udp_deliver(
+                                    response,
+                                    udpstate,
+                                    udp_clones.get(port),
+                                    &mut udp_rx_counts,
+                                );
                             }
                             Err(_) => {
                                 // do nothing
@@ -1682,17 +2615,23 @@ fn xmain() -> ! {
                             let remote_addr = connection.remote;
                             match remote_addr {
                                 IpAddress::Ipv4(_) => {
-                                    let icmp_packet = Icmpv4Packet::new_checked(&payload).unwrap();
-                                    let icmp_repr =
+                                    let icmp_repr = match Icmpv4Packet::new_checked(&payload).and_then(|icmp_packet| {
                                         Icmpv4Repr::parse(&icmp_packet, &device_caps.checksum)
-                                            .unwrap();
+                                    }) {
+                                        Ok(repr) => repr,
+                                        Err(e) => {
+                                            log::warn!("couldn't parse ICMPv4 packet: {:?}", e);
+                                            continue;
+                                        }
+                                    };
                                     if let Icmpv4Repr::EchoReply { seq_no, data, .. } = icmp_repr {
                                         log::trace!(
                                             "got icmp seq no {} / data: {:x?}",
                                             seq_no,
                                             data
                                         );
-                                        if let Some(_) = waiting_queue.get(&seq_no) {
+                                        // the timestamp we sent is in the first 8 bytes, so anything shorter isn't a reply to us
+                                        if waiting_queue.get(&seq_no).is_some() && data.len() >= 8 {
                                             let packet_timestamp_ms = NetworkEndian::read_i64(data);
                                             waiting_queue.remove(&seq_no);
                                             // use try_send_message because we don't want to block if the recipient's queue is full;
@@ -1762,19 +2701,29 @@ fn xmain() -> ! {
                                 }
 
                                 IpAddress::Ipv6(_) => {
-                                    // NOTE: actually not sure what src_ipv6 should be. This is just from an example.
-                                    let src_ipv6 = IpAddress::v6(0xfdaa, 0, 0, 0, 0, 0, 0, 1);
-                                    let icmp_packet = Icmpv6Packet::new_checked(&payload).unwrap();
-                                    let icmp_repr = Icmpv6Repr::parse(
-                                        &remote_addr,
-                                        &src_ipv6,
-                                        &icmp_packet,
-                                        &device_caps.checksum,
-                                    )
-                                    .unwrap();
+                                    // the interface already verified the checksum when the packet came in, and our
+                                    // address may have changed since, so don't check it again here.
+                                    let local_ipv6 = IpAddress::Ipv6(
+                                        ipv6.cidrs().first().map(|cidr| cidr.address()).unwrap_or(Ipv6Address::UNSPECIFIED),
+                                    );
+                                    let icmp_repr = match Icmpv6Packet::new_checked(&payload).and_then(|icmp_packet| {
+                                        Icmpv6Repr::parse(
+                                            &remote_addr,
+                                            &local_ipv6,
+                                            &icmp_packet,
+                                            &ChecksumCapabilities::ignored(),
+                                        )
+                                    }) {
+                                        Ok(repr) => repr,
+                                        Err(e) => {
+                                            log::warn!("couldn't parse ICMPv6 packet: {:?}", e);
+                                            continue;
+                                        }
+                                    };
                                     let ra = remote_addr.as_bytes();
                                     if let Icmpv6Repr::EchoReply { seq_no, data, .. } = icmp_repr {
-                                        if let Some(_) = waiting_queue.get(&seq_no) {
+                                        // the timestamp we sent is in the first 8 bytes, so anything shorter isn't a reply to us
+                                        if waiting_queue.get(&seq_no).is_some() && data.len() >= 8 {
                                             let packet_timestamp_ms = NetworkEndian::read_i64(data);
                                             waiting_queue.remove(&seq_no);
                                             match xous::try_send_message(
@@ -1839,7 +2788,8 @@ fn xmain() -> ! {
                                         log::error!("got unhandled ICMP type, ignoring!");
                                     }
                                 }
-                                _ => unimplemented!(),
+                                // ping records are only ever made for IPv4 and IPv6 addresses
+                                _ => {}
                             }
                         }
                     }
@@ -1868,7 +2818,7 @@ fn xmain() -> ! {
                                     log::debug!("Server already dropped before we could send it a drop message. Ignoring.");
                                 }
                                 Err(e) => {
-                                    panic!("couldn't send Drop on empty queue from Ping server: {:?}", e);
+                                    log::error!("couldn't send Drop on empty queue from Ping server: {:?}", e);
                                 }
                             }
                             match unsafe{xous::disconnect(conn.cid)} {
@@ -1877,7 +2827,7 @@ fn xmain() -> ! {
                                     log::debug!("Disconnected from a server that has already disappeared. Moving on.");
                                 }
                                 Err(e) => {
-                                    panic!("Unhandled error disconnecting from ping server: {:?}", e);
+                                    log::error!("Unhandled error disconnecting from ping server: {:?}", e);
                                 }
                             }
                             false
@@ -1920,6 +2870,37 @@ fn xmain() -> ! {
                     }
                 }
 
+                // this block answers the polls that are waiting on sockets which have become ready, or that have timed out
+                if poll_waiting.len() > 0 {
+                    let now = timer.elapsed_ms();
+                    let clients = ClientSockets {
+                        tcp_handles: &mut tcp_handles,
+                        tcp_listeners: &mut tcp_listeners,
+                        tcp_listener_accepts: &mut tcp_listener_accepts,
+                        udp_handles: &mut udp_handles,
+                        udp_clones: &mut udp_clones,
+                        udp_rx_counts: &mut udp_rx_counts,
+                    };
+                    let mut still_waiting = Vec::new();
+                    for (mut env, deadline) in poll_waiting.drain(..) {
+                        let pid = env.sender.pid();
+                        let mut buf = unsafe {
+                            Buffer::from_memory_message_mut(env.body.memory_message_mut().unwrap())
+                        };
+                        let mut poll = buf.to_original::<NetPoll, _>().unwrap();
+                        if poll_ready(&mut poll, pid, &mut sockets, &clients)
+                            || deadline.map_or(false, |d| now >= d)
+                        {
+                            // dropping the envelope returns the poll to the caller
+                            buf.replace(poll).unwrap();
+                        } else {
+                            drop(buf);
+                            still_waiting.push((env, deadline));
+                        }
+                    }
+                    poll_waiting = still_waiting;
+                }
+
                 // establish our next check-up interval
                 let timestamp = Instant::from_millis(timer.elapsed_ms() as i64);
                 if let Some(delay) = iface.poll_delay(&sockets, timestamp) {
@@ -1979,6 +2960,188 @@ fn xmain() -> ! {
                 };
                 buffer.replace(ser).expect("couldn't return config");
             }
+            Some(Opcode::GetIpv6Config) => {
+                let mut buffer = unsafe {
+                    Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap())
+                };
+                buffer.replace(ipv6.conf()).expect("couldn't return config");
+            }
+            Some(Opcode::SetIpv6Static) => {
+                let mut buf = unsafe {
+                    Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap())
+                };
+                let conf = buf.to_original::<Ipv6Conf, _>().unwrap();
+                let static_addr = conf.static_addr.map(Ipv6Address);
+                let static_gateway = conf.gateway.map(Ipv6Address);
+                if conf.static_prefix_len > 128
+                    || static_addr.map_or(false, |addr| !addr.is_unicast())
+                    || static_gateway.map_or(false, |addr| !addr.is_unicast())
+                {
+                    buf.replace(NetMemResponse::Invalid).unwrap();
+                } else {
+                    ipv6.static_addr =
+                        static_addr.map(|addr| Ipv6Cidr::new(addr, conf.static_prefix_len));
+                    ipv6.static_gateway = static_gateway;
+                    log::info!("IPv6 addresses: {:?}, gateway: {:?}", ipv6.cidrs(), ipv6.gateway());
+                    set_ipv6(&mut iface, &ipv6);
+                    buf.replace(NetMemResponse::Ok).unwrap();
+                }
+            }
+            Some(Opcode::Poll) => {
+                let pid = msg.sender.pid();
+                let mut buf = unsafe {
+                    Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap())
+                };
+                let mut poll = buf.to_original::<NetPoll, _>().unwrap();
+                let clients = ClientSockets {
+                    tcp_handles: &mut tcp_handles,
+                    tcp_listeners: &mut tcp_listeners,
+                    tcp_listener_accepts: &mut tcp_listener_accepts,
+                    udp_handles: &mut udp_handles,
+                    udp_clones: &mut udp_clones,
+                    udp_rx_counts: &mut udp_rx_counts,
+                };
+                poll.result = Some(NetMemResponse::Ok);
+                if poll_ready(&mut poll, pid, &mut sockets, &clients) || poll.timeout_ms == Some(0)
+                {
+                    buf.replace(poll).unwrap();
+                    continue;
+                }
+                if !poll_admit(poll_waiting.iter().map(|(env, _)| env.sender.pid()), pid) {
+                    log::warn!("too many polls waiting, refusing one from {:?}", pid);
+                    poll.result = Some(NetMemResponse::OutOfMemory);
+                    buf.replace(poll).unwrap();
+                    continue;
+                }
+                let deadline = poll
+                    .timeout_ms
+                    .map(|ms| timer.elapsed_ms().saturating_add(ms));
+                if let Some(timeout_ms) = poll.timeout_ms {
+                    // make sure the stack gets pumped when the poll times out, even if the network is idle. A poll
+                    // that can't be woken up in time is refused, rather than left to wait past its deadline.
+                    if poll_timers.load(Ordering::SeqCst) >= MAX_POLL_TIMERS {
+                        log::warn!("Could not queue poll timeout of {}ms in net stack due to thread exhaustion.", timeout_ms);
+                        poll.result = Some(NetMemResponse::OutOfMemory);
+                        buf.replace(poll).unwrap();
+                        continue;
+                    }
+                    poll_timers.fetch_add(1, Ordering::SeqCst);
+                    thread::spawn({
+                        let parent_conn = net_conn.clone();
+                        let poll_timers = poll_timers.clone();
+                        move || {
+                            let tt = ticktimer_server::Ticktimer::new().unwrap();
+                            tt.sleep_ms(usize::try_from(timeout_ms).unwrap_or(usize::MAX))
+                                .unwrap();
+                            xous::try_send_message(
+                                parent_conn,
+                                Message::new_scalar(
+                                    Opcode::NetPump.to_usize().unwrap(),
+                                    0,
+                                    0,
+                                    0,
+                                    0,
+                                ),
+                            )
+                            .ok();
+                            poll_timers.fetch_sub(1, Ordering::SeqCst);
+                        }
+                    });
+                }
+                // hold on to the message until one of the sockets is ready; the response goes out from `NetPump`
+                buf.replace(poll).unwrap();
+                drop(buf);
+                poll_waiting.push((msg, deadline));
+            }
+            Some(Opcode::SocketList) => {
+                let mut buf = unsafe {
+                    Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap())
+                };
+                let mut records = Vec::<NetSocketRecord>::new();
+                for (connection, tcp_state) in tcp_handles.iter() {
+                    records.push(NetSocketRecord {
+                        local_port: connection.local_port,
+                        ..tcp_socket_record(
+                            &mut sockets,
+                            tcp_state.handle,
+                            SocketKind::TcpStream,
+                            tcp_state.owner,
+                        )
+                    });
+                }
+                for (&local_port, listeners) in tcp_listeners.iter() {
+                    for tcp_state in listeners.iter() {
+                        records.push(NetSocketRecord {
+                            local_port,
+                            ..tcp_socket_record(
+                                &mut sockets,
+                                tcp_state.handle,
+                                SocketKind::TcpListener,
+                                tcp_state.owner,
+                            )
+                        });
+                    }
+                }
+                for (&local_port, udpstate) in udp_handles.iter() {
+                    records.push(NetSocketRecord {
+                        kind: SocketKind::UdpSocket,
+                        pid: udpstate.owner.map(|pid| pid.get()).unwrap_or(0),
+                        local_port,
+                        remote_addr: None,
+                        remote_port: 0,
+                        tcp_state: 0,
+                    });
+                }
+                for (&pid, handles) in process_sockets.iter() {
+                    for &handle in handles.iter().flatten() {
+                        records.push(tcp_socket_record(
+                            &mut sockets,
+                            handle,
+                            SocketKind::StdTcpStream,
+                            pid,
+                        ));
+                    }
+                }
+                records.sort_by_key(|record| (record.pid, record.local_port));
+                let mut socket_list = NetSocketList::default();
+                socket_list.total = records.len() as u32;
+                for (dst, &src) in socket_list.list.iter_mut().zip(records.iter()) {
+                    *dst = Some(src);
+                }
+                buf.replace(socket_list).unwrap();
+            }
+            Some(Opcode::ReleaseProcess) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                let pid = msg.sender.pid();
+                let released = ClientSockets {
+                    tcp_handles: &mut tcp_handles,
+                    tcp_listeners: &mut tcp_listeners,
+                    tcp_listener_accepts: &mut tcp_listener_accepts,
+                    udp_handles: &mut udp_handles,
+                    udp_clones: &mut udp_clones,
+                    udp_rx_counts: &mut udp_rx_counts,
+                }
+                .release(|owner, _| owner == pid);
+                let count = released.count();
+                if count > 0 {
+                    log::info!("closing {} sockets left open by {:?}", count, pid);
+                }
+                // the process has no sockets left to wait on, so hand back any polls it left behind
+                poll_waiting.retain(|(env, _)| env.sender.pid() != pid);
+                close_released(
+                    released,
+                    &mut sockets,
+                    &mut iface,
+                    &mut multicast_groups,
+                    &mut tcp_closing,
+                    timer.elapsed_ms(),
+                );
+                xous::try_send_message(
+                    net_conn,
+                    Message::new_scalar(Opcode::NetPump.to_usize().unwrap(), 0, 0, 0, 0),
+                )
+                .ok();
+                xous::return_scalar(msg.sender, count).expect("couldn't ack ReleaseProcess");
+            }),
             Some(Opcode::SubscribeWifiStats) => {
                 let buffer =
                     unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
@@ -2069,21 +3232,14 @@ fn xmain() -> ! {
             }),
             Some(Opcode::Reset) => {
                 net_config = None;
-                let neighbor_cache = NeighborCache::new(BTreeMap::new());
-                let ip_addrs = [IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0)];
-                let routes = Routes::new(BTreeMap::new());
-                let device = device::NetPhy::new(&xns);
-                let medium = device.capabilities().medium;
-                let mut builder = InterfaceBuilder::new(device)
-                    .ip_addrs(ip_addrs)
-                    .routes(routes);
-                if medium == Medium::Ethernet {
-                    builder = builder
-                        .ethernet_addr(EthernetAddress::from_bytes(&[0; 6]))
-                        .neighbor_cache(neighbor_cache);
-                }
-                iface = builder.finalize();
+                iface = build_iface(device::NetPhy::new(&xns), EthernetAddress::from_bytes(&[0; 6]));
                 iface.routes_mut().remove_default_ipv4_route();
+                // addresses learned from the link are stale now; the static config is re-applied on the next config update
+                ipv6 = Ipv6State {
+                    static_addr: ipv6.static_addr,
+                    static_gateway: ipv6.static_gateway,
+                    ..Default::default()
+                };
                 dns_allclear_hook.notify();
                 // question: do we need to clear the UDP and ICMP states?
                 xous::return_scalar(msg.sender, 1).unwrap();
@@ -2137,3 +3293,456 @@ fn xmain() -> ! {
     log::trace!("quitting");
     xous::terminate_process(0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Tables {
+        tcp_handles: HashMap<TcpConnection, TcpState>,
+        tcp_listeners: HashMap<u16, Vec<TcpState>>,
+        tcp_listener_accepts: HashMap<u16, (Option<xous::PID>, u32)>,
+        udp_handles: HashMap<u16, UdpState>,
+        udp_clones: HashMap<u16, HashMap<[u32; 4], CID>>,
+        udp_rx_counts: HashMap<[u32; 4], u32>,
+    }
+    impl Tables {
+        fn clients(&mut self) -> ClientSockets {
+            ClientSockets {
+                tcp_handles: &mut self.tcp_handles,
+                tcp_listeners: &mut self.tcp_listeners,
+                tcp_listener_accepts: &mut self.tcp_listener_accepts,
+                udp_handles: &mut self.udp_handles,
+                udp_clones: &mut self.udp_clones,
+                udp_rx_counts: &mut self.udp_rx_counts,
+            }
+        }
+    }
+
+    /// process 2 has a stream, a listener that has handed over one connection, and a UDP socket with a clone
+    fn tables(sockets: &mut SocketSet) -> Tables {
+        let mut tcp_socket = || {
+            sockets.add(TcpSocket::new(
+                TcpSocketBuffer::new(vec![0; 64]),
+                TcpSocketBuffer::new(vec![0; 64]),
+            ))
+        };
+        let tcp_state = |handle| TcpState {
+            handle,
+            cid: 10,
+            shutdown_rx: false,
+            rx_count: 1,
+            owner: xous::PID::new(2),
+        };
+        let mut tcp_handles = HashMap::new();
+        tcp_handles.insert(
+            TcpConnection {
+                remote: IpAddress::v4(10, 0, 0, 1),
+                remote_port: 80,
+                local_port: 50000,
+            },
+            tcp_state(tcp_socket()),
+        );
+        let mut tcp_listeners = HashMap::new();
+        tcp_listeners.insert(8080, vec![tcp_state(tcp_socket())]);
+        let mut tcp_listener_accepts = HashMap::new();
+        tcp_listener_accepts.insert(8080, (xous::PID::new(2), 1));
+        let udp_handle = sockets.add(UdpSocket::new(
+            UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY], vec![0; 64]),
+            UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY], vec![0; 64]),
+        ));
+        let mut udp_handles = HashMap::new();
+        udp_handles.insert(
+            5353,
+            UdpState {
+                handle: udp_handle,
+                cid: 11,
+                sid: SID::from_array([11, 0, 0, 0]),
+                owner: xous::PID::new(2),
+                ttl: None,
+                multicast_ttl: 1,
+                broadcast: false,
+                multicast_loop: true,
+                multicast_groups: Vec::new(),
+            },
+        );
+        let mut clones = HashMap::new();
+        clones.insert([12, 0, 0, 0], 12);
+        let mut udp_clones = HashMap::new();
+        udp_clones.insert(5353, clones);
+        let mut udp_rx_counts = HashMap::new();
+        udp_rx_counts.insert([12, 0, 0, 0], 1);
+        Tables {
+            tcp_handles,
+            tcp_listeners,
+            tcp_listener_accepts,
+            udp_handles,
+            udp_clones,
+            udp_rx_counts,
+        }
+    }
+
+    fn entry(socket: NetPollSocket) -> Option<NetPollEntry> {
+        Some(NetPollEntry {
+            socket,
+            rx_seen: 0,
+            interest: POLL_READABLE,
+            ready: 0,
+        })
+    }
+
+    fn request() -> NetPoll {
+        let mut poll = NetPoll {
+            entries: [None; POLL_MAX_SOCKETS],
+            timeout_ms: None,
+            result: None,
+        };
+        poll.entries[0] = entry(NetPollSocket::TcpStream {
+            ip_addr: NetIpAddr::from(IpAddress::v4(10, 0, 0, 1)),
+            remote_port: 80,
+            local_port: 50000,
+        });
+        poll.entries[1] = entry(NetPollSocket::TcpListener { local_port: 8080 });
+        poll.entries[2] = entry(NetPollSocket::UdpSocket {
+            local_port: 5353,
+            cb_sid: [12, 0, 0, 0],
+        });
+        poll
+    }
+
+    fn ready(poll: &NetPoll) -> Vec<u8> {
+        poll.entries.iter().flatten().map(|e| e.ready).collect()
+    }
+
+    #[test]
+    fn poll_reports_the_callers_sockets() {
+        let mut sockets = SocketSet::new(vec![]);
+        let mut t = tables(&mut sockets);
+        let mut poll = request();
+        assert!(poll_ready(
+            &mut poll,
+            xous::PID::new(2),
+            &mut sockets,
+            &t.clients()
+        ));
+        // deliveries are waiting on all three; the clone is known by its own SID
+        assert!(ready(&poll).iter().all(|&r| r & POLL_READABLE != 0));
+        // once every listener on the port has been handed over, the port still belongs to its process
+        t.tcp_listeners.get_mut(&8080).unwrap().clear();
+        let mut poll = request();
+        poll_ready(&mut poll, xous::PID::new(2), &mut sockets, &t.clients());
+        assert_eq!(ready(&poll)[1], POLL_READABLE);
+    }
+
+    #[test]
+    fn poll_hides_other_processes_sockets() {
+        let mut sockets = SocketSet::new(vec![]);
+        let mut t = tables(&mut sockets);
+        let mut poll = request();
+        assert!(poll_ready(
+            &mut poll,
+            xous::PID::new(3),
+            &mut sockets,
+            &t.clients()
+        ));
+        assert_eq!(ready(&poll), vec![POLL_INVALID; 3]);
+        t.tcp_listeners.get_mut(&8080).unwrap().clear();
+        let mut poll = request();
+        poll_ready(&mut poll, xous::PID::new(3), &mut sockets, &t.clients());
+        assert_eq!(ready(&poll)[1], POLL_INVALID);
+        // a SID that isn't listening on the port
+        let mut poll = request();
+        poll.entries[2] = entry(NetPollSocket::UdpSocket {
+            local_port: 5353,
+            cb_sid: [13, 0, 0, 0],
+        });
+        poll_ready(&mut poll, xous::PID::new(2), &mut sockets, &t.clients());
+        assert_eq!(ready(&poll)[2], POLL_INVALID);
+    }
+
+    #[test]
+    fn multicast_membership() {
+        let mut sockets = SocketSet::new(vec![]);
+        let mut t = tables(&mut sockets);
+        let udpstate = t.udp_handles.get_mut(&5353).unwrap();
+        let group = Ipv4Address::new(224, 0, 0, 251);
+        assert!(!udpstate.joined(IpAddress::Ipv4(group)));
+        udpstate.multicast_groups.push(group);
+        assert!(udpstate.joined(IpAddress::Ipv4(group)));
+        assert!(!udpstate.joined(IpAddress::v4(239, 255, 255, 250)));
+        assert!(!udpstate.joined(IpAddress::Ipv6(Ipv6Address([
+            0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xfb
+        ]))));
+    }
+
+    #[test]
+    fn poll_waiting_limits() {
+        let pid = |p| xous::PID::new(p);
+        let theirs = vec![pid(2); POLL_MAX_WAITING_PER_PROCESS];
+        assert!(!poll_admit(theirs.iter().copied(), pid(2)));
+        assert!(poll_admit(theirs.iter().copied(), pid(3)));
+        assert!(poll_admit(theirs[1..].iter().copied(), pid(2)));
+        let everyone: Vec<Option<xous::PID>> =
+            (0..POLL_MAX_WAITING).map(|i| pid(10 + i as u8)).collect();
+        assert!(!poll_admit(everyone.iter().copied(), pid(3)));
+        assert!(poll_admit(everyone[1..].iter().copied(), pid(3)));
+    }
+
+    /// the interface and the connection manager's calls, run against the simulated network
+    #[cfg(not(any(target_os = "none", target_os = "xous")))]
+    mod sim_network {
+        use super::*;
+        use com_rs_ref::{ConnectResult, DhcpState};
+
+        /// joins the simulated access point, and builds an interface on the link the way `ComInterrupt` does
+        fn join() -> (device::Com, Interface<'static, device::NetPhy>) {
+            let mut com = device::Com::isolated();
+            com.wlan_set_ssid(sim::SIM_SSID).unwrap();
+            com.wlan_set_pass("password").unwrap();
+            com.wlan_join().unwrap();
+            let mut ints = Vec::new();
+            let (_, _, raw_arg) = com.ints_get_active(&mut ints).unwrap();
+            assert!(ints.contains(&ComIntSources::Connect));
+            assert!(ints.contains(&ComIntSources::WlanIpConfigUpdate));
+            assert!(matches!(
+                ConnectResult::decode_u16(raw_arg as u16),
+                ConnectResult::Success
+            ));
+            com.ints_ack(&ints);
+
+            let config = com.wlan_get_config().unwrap();
+            assert!(config.dhcp == DhcpState::Bound);
+            let mut iface = build_iface(
+                device::NetPhy::from_com(com.clone()),
+                EthernetAddress::from_bytes(&config.mac),
+            );
+            set_ipv4_config(&mut iface, &config);
+            (com, iface)
+        }
+
+        /// passes frames back and forth the way `ComInterrupt` and `NetPump` do, for `steps` polls 10ms apart
+        fn pump(
+            com: &device::Com,
+            iface: &mut Interface<'static, device::NetPhy>,
+            sockets: &mut SocketSet,
+            now: &mut i64,
+            steps: usize,
+        ) {
+            let mut ints = Vec::new();
+            for _ in 0..steps {
+                *now += 10;
+                iface.poll(sockets, Instant::from_millis(*now)).ok();
+                ints.clear();
+                let (maybe_rxlen, _, _) = com.ints_get_active(&mut ints).unwrap();
+                if let Some(rxlen) = maybe_rxlen {
+                    assert!(iface.device_mut().push_rx_avail(rxlen).is_none());
+                }
+                com.ints_ack(&ints);
+            }
+        }
+
+        fn tcp_socket(sockets: &mut SocketSet) -> SocketHandle {
+            sockets.add(TcpSocket::new(
+                TcpSocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+                TcpSocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+            ))
+        }
+
+        #[test]
+        fn join_unknown_ssid() {
+            let mut com = device::Com::isolated();
+            com.wlan_set_ssid("elsewhere").unwrap();
+            com.wlan_join().unwrap();
+            let mut ints = Vec::new();
+            let (_, _, raw_arg) = com.ints_get_active(&mut ints).unwrap();
+            assert_eq!(ints, vec![ComIntSources::Connect]);
+            assert!(matches!(
+                ConnectResult::decode_u16(raw_arg as u16),
+                ConnectResult::NoMatchingAp
+            ));
+            assert!(com.wlan_get_config().unwrap().dhcp != DhcpState::Bound);
+        }
+
+        #[test]
+        fn ping() {
+            let (com, mut iface) = join();
+            let mut sockets = SocketSet::new(vec![]);
+            let mut icmp_socket = IcmpSocket::new(
+                IcmpSocketBuffer::new(vec![IcmpPacketMetadata::EMPTY], vec![0; 256]),
+                IcmpSocketBuffer::new(vec![IcmpPacketMetadata::EMPTY], vec![0; 256]),
+            );
+            icmp_socket.bind(IcmpEndpoint::Ident(0x22b)).unwrap();
+            let handle = sockets.add(icmp_socket);
+            let remote = IpAddress::v4(192, 0, 2, 1);
+            let echo_payload = [0x5au8; 40];
+            let icmp_repr = Icmpv4Repr::EchoRequest {
+                ident: 0x22b,
+                seq_no: 1,
+                data: &echo_payload,
+            };
+            {
+                let mut socket = sockets.get::<IcmpSocket>(handle);
+                let icmp_payload = socket.send(icmp_repr.buffer_len(), remote).unwrap();
+                icmp_repr.emit(
+                    &mut Icmpv4Packet::new_unchecked(icmp_payload),
+                    &ChecksumCapabilities::default(),
+                );
+            }
+            let mut now = 0;
+            pump(&com, &mut iface, &mut sockets, &mut now, 10);
+            let mut socket = sockets.get::<IcmpSocket>(handle);
+            let (payload, from) = socket.recv().unwrap();
+            assert_eq!(from, remote);
+            let reply = Icmpv4Repr::parse(
+                &Icmpv4Packet::new_checked(payload).unwrap(),
+                &ChecksumCapabilities::default(),
+            )
+            .unwrap();
+            assert_eq!(
+                reply,
+                Icmpv4Repr::EchoReply {
+                    ident: 0x22b,
+                    seq_no: 1,
+                    data: &echo_payload
+                }
+            );
+        }
+
+        #[test]
+        fn dns() {
+            let (com, mut iface) = join();
+            let mut sockets = SocketSet::new(vec![]);
+            let handle = sockets.add(UdpSocket::new(
+                UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY], vec![0; 512]),
+                UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY], vec![0; 512]),
+            ));
+            let dns_server = IpEndpoint::new(IpAddress::Ipv4(sim::SIM_GATEWAY_IPV4), 53);
+            {
+                let mut socket = sockets.get::<UdpSocket>(handle);
+                socket.bind(50000).unwrap();
+                socket
+                    .send_slice(
+                        &connectivity::dns_query(0x1234, connectivity::PROBE_HOST),
+                        dns_server,
+                    )
+                    .unwrap();
+            }
+            let mut now = 0;
+            pump(&com, &mut iface, &mut sockets, &mut now, 10);
+            let mut socket = sockets.get::<UdpSocket>(handle);
+            let (reply, from) = socket.recv().unwrap();
+            assert_eq!(from, dns_server);
+            assert_eq!(
+                connectivity::dns_answer(0x1234, reply),
+                Some(std::net::Ipv4Addr::from(sim::SIM_GATEWAY_IPV4.0))
+            );
+        }
+
+        #[test]
+        fn tcp_echo() {
+            let (com, mut iface) = join();
+            let mut sockets = SocketSet::new(vec![]);
+            let handle = tcp_socket(&mut sockets);
+            sockets
+                .get::<TcpSocket>(handle)
+                .connect((IpAddress::v4(192, 0, 2, 1), 7), 49152)
+                .unwrap();
+            let mut now = 0;
+            pump(&com, &mut iface, &mut sockets, &mut now, 10);
+            assert_eq!(
+                sockets.get::<TcpSocket>(handle).state(),
+                TcpSocketState::Established
+            );
+
+            sockets
+                .get::<TcpSocket>(handle)
+                .send_slice(b"hello, sim")
+                .unwrap();
+            pump(&com, &mut iface, &mut sockets, &mut now, 10);
+            let mut echo = [0u8; 32];
+            let len = sockets
+                .get::<TcpSocket>(handle)
+                .recv_slice(&mut echo)
+                .unwrap();
+            assert_eq!(&echo[..len], b"hello, sim");
+
+            // the gateway closes its end when we close ours
+            sockets.get::<TcpSocket>(handle).close();
+            pump(&com, &mut iface, &mut sockets, &mut now, 10);
+            assert_eq!(
+                sockets.get::<TcpSocket>(handle).state(),
+                TcpSocketState::TimeWait
+            );
+        }
+
+        #[test]
+        fn connectivity_probe() {
+            let (com, mut iface) = join();
+            let mut sockets = SocketSet::new(vec![]);
+            let handle = tcp_socket(&mut sockets);
+            sockets
+                .get::<TcpSocket>(handle)
+                .connect((IpAddress::Ipv4(sim::SIM_GATEWAY_IPV4), 80), 49153)
+                .unwrap();
+            let mut now = 0;
+            pump(&com, &mut iface, &mut sockets, &mut now, 10);
+            let request = format!(
+                "GET /generate_204 HTTP/1.1\r\nHost: {}\r\n\r\n",
+                connectivity::PROBE_HOST
+            );
+            sockets
+                .get::<TcpSocket>(handle)
+                .send_slice(request.as_bytes())
+                .unwrap();
+            pump(&com, &mut iface, &mut sockets, &mut now, 10);
+            let mut response = [0u8; 256];
+            let mut socket = sockets.get::<TcpSocket>(handle);
+            let len = socket.recv_slice(&mut response).unwrap();
+            assert_eq!(connectivity::http_status(&response[..len]), Some(204));
+            // the response is followed by the gateway's FIN
+            assert!(!socket.may_recv());
+        }
+
+        #[test]
+        fn tcp_other_ports_reset() {
+            let (com, mut iface) = join();
+            let mut sockets = SocketSet::new(vec![]);
+            let handle = tcp_socket(&mut sockets);
+            sockets
+                .get::<TcpSocket>(handle)
+                .connect((IpAddress::v4(192, 0, 2, 1), 443), 49154)
+                .unwrap();
+            let mut now = 0;
+            pump(&com, &mut iface, &mut sockets, &mut now, 10);
+            assert_eq!(
+                sockets.get::<TcpSocket>(handle).state(),
+                TcpSocketState::Closed
+            );
+        }
+
+        #[test]
+        fn leave() {
+            let (mut com, mut iface) = join();
+            com.wlan_leave().unwrap();
+            let mut ints = Vec::new();
+            com.ints_get_active(&mut ints).unwrap();
+            assert!(ints.contains(&ComIntSources::Disconnect));
+            com.ints_ack(&ints);
+            assert!(com.wlan_get_config().unwrap().dhcp != DhcpState::Bound);
+
+            // nothing answers once the link is down
+            let mut sockets = SocketSet::new(vec![]);
+            let handle = tcp_socket(&mut sockets);
+            sockets
+                .get::<TcpSocket>(handle)
+                .connect((IpAddress::v4(192, 0, 2, 1), 7), 49155)
+                .unwrap();
+            let mut now = 0;
+            pump(&com, &mut iface, &mut sockets, &mut now, 10);
+            assert_eq!(
+                sockets.get::<TcpSocket>(handle).state(),
+                TcpSocketState::SynSent
+            );
+        }
+    }
+}
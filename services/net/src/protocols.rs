@@ -1,34 +1,38 @@
-#[cfg(any(target_os = "none", target_os = "xous"))]
+// in hosted mode, clients use the host's sockets, unless `hosted-sim` sends them through the Net server
+#[cfg(any(target_os = "none", target_os = "xous", feature = "hosted-sim"))]
 pub mod udp;
-#[cfg(any(target_os = "none", target_os = "xous"))]
+#[cfg(any(target_os = "none", target_os = "xous", feature = "hosted-sim"))]
 pub use udp::*;
-#[cfg(not(any(target_os = "none", target_os = "xous")))]
+#[cfg(not(any(target_os = "none", target_os = "xous", feature = "hosted-sim")))]
 pub mod udp_hosted;
-#[cfg(not(any(target_os = "none", target_os = "xous")))]
+#[cfg(not(any(target_os = "none", target_os = "xous", feature = "hosted-sim")))]
 pub use udp_hosted::*;
 
-#[cfg(any(target_os = "none", target_os = "xous"))]
+#[cfg(any(target_os = "none", target_os = "xous", feature = "hosted-sim"))]
 pub mod dns;
-#[cfg(any(target_os = "none", target_os = "xous"))]
+#[cfg(any(target_os = "none", target_os = "xous", feature = "hosted-sim"))]
 pub use dns::*;
-#[cfg(not(any(target_os = "none", target_os = "xous")))]
+#[cfg(not(any(target_os = "none", target_os = "xous", feature = "hosted-sim")))]
 pub mod dns_hosted;
-#[cfg(not(any(target_os = "none", target_os = "xous")))]
+#[cfg(not(any(target_os = "none", target_os = "xous", feature = "hosted-sim")))]
 pub use dns_hosted::*;
 
 pub mod ping;
 pub use ping::*;
 
-#[cfg(any(target_os = "none", target_os = "xous"))]
+#[cfg(any(target_os = "none", target_os = "xous", feature = "hosted-sim"))]
 pub mod tcp_stream;
-#[cfg(any(target_os = "none", target_os = "xous"))]
+#[cfg(any(target_os = "none", target_os = "xous", feature = "hosted-sim"))]
 pub use tcp_stream::*;
-#[cfg(any(target_os = "none", target_os = "xous"))]
+#[cfg(any(target_os = "none", target_os = "xous", feature = "hosted-sim"))]
 pub mod tcp_listener;
-#[cfg(any(target_os = "none", target_os = "xous"))]
+#[cfg(any(target_os = "none", target_os = "xous", feature = "hosted-sim"))]
 pub use tcp_listener::*;
 
-#[cfg(not(any(target_os = "none", target_os = "xous")))]
+#[cfg(not(any(target_os = "none", target_os = "xous", feature = "hosted-sim")))]
 pub mod tcp_hosted;
-#[cfg(not(any(target_os = "none", target_os = "xous")))]
+#[cfg(not(any(target_os = "none", target_os = "xous", feature = "hosted-sim")))]
 pub use tcp_hosted::*;
+
+pub mod poll;
+pub use poll::*;
@@ -183,7 +183,7 @@ impl Drop for DnsServerManager {
     fn drop(&mut self) {
         xous::send_message(
             self.net.conn(),
-            Message::new_blocking_scalar(Opcode::DnsHookAllClear.to_usize().unwrap(), 0, 0, 0, 0)
+            Message::new_blocking_scalar(Opcode::DnsUnhookAll.to_usize().unwrap(), 0, 0, 0, 0)
         ).expect("couldn't send unhook to Net crate");
 
         let drop_cid = xous::connect(self.cb_sid).unwrap();
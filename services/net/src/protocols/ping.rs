@@ -207,9 +207,17 @@ impl Ping {
                                         log::info!("Ping to {:?} timed out", remote);
                                     }
                                     Some(NetPingCallback::Unreachable) => {
-                                        let code = smoltcp::wire::Icmpv4DstUnreachable::from((op >> 24) as u8);
                                         reachable.store(false, Ordering::SeqCst);
-                                        log::info!("Ping to {:?} unreachable: {:?}", remote, code);
+                                        match remote {
+                                            IpAddr::V4(_) => {
+                                                let code = smoltcp::wire::Icmpv4DstUnreachable::from((op >> 24) as u8);
+                                                log::info!("Ping to {:?} unreachable: {:?}", remote, code);
+                                            }
+                                            IpAddr::V6(_) => {
+                                                let code = smoltcp::wire::Icmpv6DstUnreachable::from((op >> 24) as u8);
+                                                log::info!("Ping to {:?} unreachable: {:?}", remote, code);
+                                            }
+                                        }
                                     }
                                     None => {
                                         log::error!("Unknown opcode received in one-time server: {:?}", op);
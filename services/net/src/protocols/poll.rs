@@ -0,0 +1,178 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+
+use smoltcp::time::Duration;
+
+use crate::{TcpListener, TcpStream, UdpSocket};
+use crate::api::*;
+
+/// The events a socket is ready for, or that a caller wants to wait for
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Readiness {
+    /// A read would return data right away. For a `TcpListener`, there is a connection waiting to be accepted.
+    pub readable: bool,
+    /// A write would be accepted right away
+    pub writable: bool,
+    /// The connection is closed, or the peer has closed its end so no more data will arrive. This is
+    /// always reported, whether it was asked for or not.
+    pub closed: bool,
+    /// The socket isn't known to the Net server, for example because it was shut down. This is always
+    /// reported, whether it was asked for or not.
+    pub invalid: bool,
+}
+impl Readiness {
+    pub const READABLE: Readiness = Readiness {
+        readable: true,
+        writable: false,
+        closed: false,
+        invalid: false,
+    };
+    pub const WRITABLE: Readiness = Readiness {
+        readable: false,
+        writable: true,
+        closed: false,
+        invalid: false,
+    };
+    pub const ANY: Readiness = Readiness {
+        readable: true,
+        writable: true,
+        closed: false,
+        invalid: false,
+    };
+
+    pub fn is_ready(&self) -> bool {
+        self.readable || self.writable || self.closed || self.invalid
+    }
+    #[cfg(any(target_os = "none", target_os = "xous", feature = "hosted-sim"))]
+    fn to_flags(&self) -> u8 {
+        (if self.readable { POLL_READABLE } else { 0 })
+            | (if self.writable { POLL_WRITABLE } else { 0 })
+            | (if self.closed { POLL_CLOSED } else { 0 })
+            | (if self.invalid { POLL_INVALID } else { 0 })
+    }
+    #[cfg(any(target_os = "none", target_os = "xous", feature = "hosted-sim"))]
+    fn from_flags(flags: u8) -> Readiness {
+        Readiness {
+            readable: flags & POLL_READABLE != 0,
+            writable: flags & POLL_WRITABLE != 0,
+            closed: flags & POLL_CLOSED != 0,
+            invalid: flags & POLL_INVALID != 0,
+        }
+    }
+}
+
+/// A socket that can be waited on with `poll()`
+#[derive(Copy, Clone)]
+pub enum PollSocket<'a> {
+    TcpStream(&'a TcpStream),
+    TcpListener(&'a TcpListener),
+    UdpSocket(&'a UdpSocket),
+}
+impl<'a> From<&'a TcpStream> for PollSocket<'a> {
+    fn from(stream: &'a TcpStream) -> Self {
+        PollSocket::TcpStream(stream)
+    }
+}
+impl<'a> From<&'a TcpListener> for PollSocket<'a> {
+    fn from(listener: &'a TcpListener) -> Self {
+        PollSocket::TcpListener(listener)
+    }
+}
+impl<'a> From<&'a UdpSocket> for PollSocket<'a> {
+    fn from(socket: &'a UdpSocket) -> Self {
+        PollSocket::UdpSocket(socket)
+    }
+}
+
+/// One socket in a call to `poll()`: the events to wait for go in `interest`, and the events the
+/// socket is ready for come back in `ready`.
+pub struct PollEntry<'a> {
+    pub socket: PollSocket<'a>,
+    pub interest: Readiness,
+    pub ready: Readiness,
+}
+impl<'a> PollEntry<'a> {
+    pub fn new<S: Into<PollSocket<'a>>>(socket: S, interest: Readiness) -> PollEntry<'a> {
+        PollEntry {
+            socket: socket.into(),
+            interest,
+            ready: Readiness::default(),
+        }
+    }
+}
+
+/// Blocks until at least one of the sockets in `entries` is ready for the events it is interested in,
+/// or has been closed, or until `timeout` passes. `None` waits forever, and a zero timeout just checks
+/// the sockets without waiting.
+///
+/// Fills in the `ready` field of every entry, and returns the number of entries that are ready for
+/// something; zero means the timeout passed. Up to `POLL_MAX_SOCKETS` sockets can be waited on at once.
+/// The Net server only holds on to a limited number of polls, so this fails if too many are already waiting.
+#[cfg(any(target_os = "none", target_os = "xous", feature = "hosted-sim"))]
+pub fn poll(entries: &mut [PollEntry], timeout: Option<Duration>) -> io::Result<usize> {
+    use crate::NetConn;
+    use num_traits::*;
+    use xous_ipc::Buffer;
+
+    if entries.len() > POLL_MAX_SOCKETS {
+        return Err(Error::new(ErrorKind::InvalidInput, "too many sockets to poll"));
+    }
+    let mut request = NetPoll {
+        entries: [None; POLL_MAX_SOCKETS],
+        timeout_ms: timeout.map(|t| t.total_millis()),
+        result: None,
+    };
+    // data that has already been delivered to a socket is only known about on this side
+    let mut buffered = [false; POLL_MAX_SOCKETS];
+    for (i, entry) in entries.iter().enumerate() {
+        let (socket, rx_seen, pending) = match entry.socket {
+            PollSocket::TcpStream(stream) => stream.poll_state(),
+            PollSocket::TcpListener(listener) => listener.poll_state(),
+            PollSocket::UdpSocket(socket) => socket.poll_state(),
+        };
+        buffered[i] = pending;
+        if pending && entry.interest.readable {
+            // no need to wait, but still ask the Net server about the other events
+            request.timeout_ms = Some(0);
+        }
+        request.entries[i] = Some(NetPollEntry {
+            socket,
+            rx_seen,
+            interest: entry.interest.to_flags(),
+            ready: 0,
+        });
+    }
+
+    let xns = xous_names::XousNames::new().unwrap();
+    let net = NetConn::new(&xns)
+        .or(Err(Error::new(ErrorKind::Other, "can't connect to Net server")))?;
+    let mut buf = Buffer::into_buf(request)
+        .or(Err(Error::new(ErrorKind::Other, "can't send to Net server")))?;
+    buf.lend_mut(net.conn(), Opcode::Poll.to_u32().unwrap())
+        .or(Err(Error::new(ErrorKind::Other, "can't send to Net server")))?;
+    let response = buf.to_original::<NetPoll, _>()
+        .or(Err(Error::new(ErrorKind::Other, "invalid response from Net server")))?;
+    if let Some(NetMemResponse::OutOfMemory) = response.result {
+        return Err(Error::new(ErrorKind::Other, "the Net server has too many polls waiting"));
+    }
+
+    let mut count = 0;
+    for (i, entry) in entries.iter_mut().enumerate() {
+        let mut ready = Readiness::from_flags(response.entries[i].map(|e| e.ready).unwrap_or(0));
+        ready.readable |= buffered[i];
+        // only report the events that were asked for, plus the ones that always are
+        ready.readable &= entry.interest.readable;
+        ready.writable &= entry.interest.writable;
+        entry.ready = ready;
+        if ready.is_ready() {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// dev note: if you need this function in hosted mode, contact bunnie.
+#[cfg(not(any(target_os = "none", target_os = "xous", feature = "hosted-sim")))]
+pub fn poll(_entries: &mut [PollEntry], _timeout: Option<Duration>) -> io::Result<usize> {
+    Err(Error::new(ErrorKind::Other, "poll is not supported in hosted mode"))
+}
@@ -1,5 +1,4 @@
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
-use std::unimplemented;
 use std::io;
 use std::io::{Error, ErrorKind, Result};
 use std::sync::{Arc, Mutex};
@@ -30,7 +29,10 @@ pub struct TcpListener {
     rx_buf: Arc::<Mutex::<VecDeque::<u8>>>,
     /// xous-specific feature that allows for more efficent Rx than blocking. Caller must set_scalar_notification() to use it.
     rx_notify: Arc<Mutex<XousScalarEndpoint>>,
+    rx_count: Arc<AtomicU32>,
     listener_info: Arc<Mutex<Option<NetTcpListenCallback>>>,
+    /// number of connections accepted so far, used by `poll()`
+    accepted: u32,
 }
 
 impl TcpListener {
@@ -65,11 +67,13 @@ impl TcpListener {
         rx_buf.lock().unwrap().reserve(TCP_BUFFER_SIZE);
         let notify = Arc::new(Mutex::new(XousScalarEndpoint::new()));
         let listener_info = Arc::new(Mutex::new(None::<NetTcpListenCallback>));
+        let rx_count = Arc::new(AtomicU32::new(0));
         log::info!("TcpListener creating first thread with listener object: {:?}", listener_info);
         let _handle = crate::tcp_rx_thread(
             cb_sid.clone(),
             Arc::clone(&rx_buf),
             Arc::clone(&notify),
+            Arc::clone(&rx_count),
             // this is not used by TcpStream
             Arc::clone(&listener_info)
         );
@@ -96,11 +100,19 @@ impl TcpListener {
                     port: socket.port(),
                     rx_buf,
                     rx_notify: notify,
+                    rx_count,
                     listener_info,
+                    accepted: 0,
                     nonblocking: false,
                     original_notify: XousScalarEndpoint::new(),
                 })
             }
+            Some(NetMemResponse::SocketInUse) => {
+                Err(Error::new(ErrorKind::AddrInUse, "port is in use"))
+            }
+            Some(NetMemResponse::OutOfMemory) => {
+                Err(Error::new(ErrorKind::Other, "too many sockets open"))
+            }
             _ => {
                 Err(Error::new(ErrorKind::Other, "can't create Listener with Net server"))
             }
@@ -130,7 +142,9 @@ impl TcpListener {
                     std::mem::replace(&mut self.rx_buf, Arc::new(Mutex::new(VecDeque::<u8>::new()))),
                     // we keep our notifier for ourself, create a new one for the passed-on thread
                     Arc::new(Mutex::new(XousScalarEndpoint::new())),
+                    std::mem::replace(&mut self.rx_count, Arc::new(AtomicU32::new(0))),
                 );
+                self.accepted = self.accepted.wrapping_add(1);
                 Some((stream, remote))
             } else {
                 log::trace!("Accept: found no incoming TCP, waiting... {:?}, {:?}", self.listener_info, self.cb_sid);
@@ -154,6 +168,7 @@ impl TcpListener {
                     self.cb_sid.clone(),
                     Arc::clone(&self.rx_buf),
                     Arc::clone(&self.rx_notify),
+                    Arc::clone(&self.rx_count),
                     Arc::clone(&self.listener_info)
                 );
                 log::trace!("Accept created new thread with listener object: {:?}", self.listener_info);
@@ -213,6 +228,15 @@ impl TcpListener {
         Err(Error::new(ErrorKind::Other, "Xous does not support cloned listeners"))
     }
 
+    /// Identifies the listener to the Net server for `poll()`, along with the number of connections accepted
+    /// so far, and whether there is an incoming connection waiting to be accepted.
+    pub(crate) fn poll_state(&self) -> (NetPollSocket, u32, bool) {
+        let socket = NetPollSocket::TcpListener {
+            local_port: self.port,
+        };
+        (socket, self.accepted, self.listener_info.lock().unwrap().is_some())
+    }
+
     fn tcp_manage(&self, code: TcpMgmtCode) -> io::Result<TcpMgmtCode> {
         // only local_port is needed
         let request = NetTcpManage {
@@ -239,8 +263,11 @@ impl TcpListener {
 }
 
 impl fmt::Debug for TcpListener {
-    fn fmt(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        unimplemented!()
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TcpListener")
+            .field("port", &self.port)
+            .field("nonblocking", &self.nonblocking)
+            .finish()
     }
 }
 
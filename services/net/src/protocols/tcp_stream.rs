@@ -1,5 +1,4 @@
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
-use std::unimplemented;
 use std::io;
 use std::io::{Error, ErrorKind, Result};
 use std::io::{Read, Write};
@@ -35,6 +34,8 @@ pub struct TcpStream {
     /// xous-specific feature that allows for more efficent Rx than blocking. Caller must set_scalar_notification() to use it.
     rx_notify: Arc<Mutex<XousScalarEndpoint>>,
     rx_refcount: Arc<AtomicU32>,
+    /// number of Rx deliveries from the Net server, used by `poll()`
+    rx_count: Arc<AtomicU32>,
 
     nonblocking: bool,
     read_timeout: Option<Duration>,
@@ -45,7 +46,7 @@ pub struct TcpStream {
 
 impl TcpStream {
     /// This is routine that allows a TcpListener to create a TcpStream object. The following must be observed:
-    /// 1. The `cb_sid`, `rx_buf` and `rx_notify` are the memory locations mapped into the already-allocated Rx thread
+    /// 1. The `cb_sid`, `rx_buf`, `rx_notify` and `rx_count` are the memory locations mapped into the already-allocated Rx thread
     /// 2. These fields are not to be re-used in the Listener object that succeeds the current one
     pub(crate) fn build_from_listener(
         net: NetConn,
@@ -54,6 +55,7 @@ impl TcpStream {
         local_port: u16,
         rx_buf: Arc::<Mutex::<VecDeque::<u8>>>,
         rx_notify: Arc<Mutex<XousScalarEndpoint>>,
+        rx_count: Arc<AtomicU32>,
     ) -> TcpStream {
         TcpStream {
             net,
@@ -62,6 +64,7 @@ impl TcpStream {
             local_port,
             rx_buf,
             rx_notify,
+            rx_count,
             read_timeout: None,
             write_timeout: None,
             ticktimer: ticktimer_server::Ticktimer::new().unwrap(),
@@ -110,11 +113,13 @@ impl TcpStream {
         let rx_buf = Arc::new(Mutex::new(VecDeque::<u8>::new()));
         rx_buf.lock().unwrap().reserve(TCP_BUFFER_SIZE);
         let notify = Arc::new(Mutex::new(XousScalarEndpoint::new()));
+        let rx_count = Arc::new(AtomicU32::new(0));
 
         let _handle = tcp_rx_thread(
             cb_sid.clone(),
             Arc::clone(&rx_buf),
             Arc::clone(&notify),
+            Arc::clone(&rx_count),
             // this is not used by TcpStream
             Arc::new(Mutex::new(None::<NetTcpListenCallback>)),
         );
@@ -144,6 +149,7 @@ impl TcpStream {
                         local_port,
                         rx_buf,
                         rx_notify: notify,
+                        rx_count,
                         read_timeout: None,
                         write_timeout: None,
                         ticktimer,
@@ -156,6 +162,9 @@ impl TcpStream {
                     Err(Error::new(ErrorKind::Other, "Net server failed to assign us a local port"))
                 }
             },
+            Some(NetMemResponse::OutOfMemory) => {
+                Err(Error::new(ErrorKind::Other, "too many sockets open"))
+            }
             _ => {
                 Err(Error::new(ErrorKind::Other, "can't register with Net server"))
             }
@@ -311,6 +320,7 @@ impl TcpStream {
             ticktimer: ticktimer_server::Ticktimer::new().unwrap(),
             rx_buf: self.rx_buf.clone(),
             rx_notify: self.rx_notify.clone(),
+            rx_count: self.rx_count.clone(),
             nonblocking: self.nonblocking,
             read_timeout: self.read_timeout,
             write_timeout: self.write_timeout,
@@ -322,23 +332,86 @@ impl TcpStream {
         Ok(cloned_stream)
     }
 
+    /// Copies received data into `buf` without consuming it. Like `read()`, this waits for data to arrive
+    /// unless the stream is nonblocking; it returns `Ok(0)` once the peer has closed the connection and
+    /// everything it sent has been read.
     pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
-        if self.rx_buf.lock().unwrap().len() == 0 {
+        if buf.is_empty() {
             return Ok(0)
-        } else {
-            let rx_buf = self.rx_buf.lock().unwrap();
-            let readlen = rx_buf.len().min(buf.len());
-            for (&src, dst) in rx_buf.range(..readlen).zip(buf.iter_mut()) {
-                *dst = src;
+        }
+        let start = self.ticktimer.elapsed_ms();
+        loop {
+            // the count has to be taken before looking at the buffer, as the Rx thread fills the buffer first
+            let rx_seen = self.rx_count.load(Ordering::SeqCst);
+            {
+                let rx_buf = self.rx_buf.lock().unwrap();
+                if rx_buf.len() > 0 {
+                    let readlen = rx_buf.len().min(buf.len());
+                    for (&src, dst) in rx_buf.range(..readlen).zip(buf.iter_mut()) {
+                        *dst = src;
+                    }
+                    return Ok(readlen)
+                }
             }
-            return Ok(readlen)
+            // nothing has been delivered to us yet, so look at what the Net server is holding on to
+            let request = NetTcpPeek {
+                ip_addr: NetIpAddr::from(self.socket_addr),
+                remote_port: self.socket_addr.port(),
+                local_port: self.local_port,
+                rx_seen,
+                len: buf.len().min(TCP_BUFFER_SIZE) as u16,
+                data: [0u8; TCP_BUFFER_SIZE],
+                result: None,
+            };
+            let mut peek = Buffer::into_buf(request)
+                .or(Err(Error::new(ErrorKind::Other, "internal error handling peek")))?;
+            peek.lend_mut(self.net.conn(), Opcode::TcpPeek.to_u32().unwrap())
+                .or(Err(Error::new(ErrorKind::Other, "internal error handling peek")))?;
+            let ret = peek.to_original::<NetTcpPeek, _>()
+                .or(Err(Error::new(ErrorKind::Other, "internal error handling peek")))?;
+            match ret.result {
+                Some(NetMemResponse::Ok) => {
+                    if ret.len > 0 {
+                        let readlen = (ret.len as usize).min(buf.len());
+                        buf[..readlen].copy_from_slice(&ret.data[..readlen]);
+                        return Ok(readlen)
+                    }
+                }
+                Some(NetMemResponse::Finished) => return Ok(0),
+                _ => return Err(Error::new(ErrorKind::Other, "internal error handling peek")),
+            }
+            if self.nonblocking {
+                return Err(Error::new(ErrorKind::WouldBlock, "Peek would block"));
+            }
+            if self.ticktimer.elapsed_ms() - start >=
+                self.read_timeout.unwrap_or(Duration::from_millis(u64::MAX)).total_millis() {
+                return Err(Error::new(ErrorKind::WouldBlock, "Peek timed out"));
+            }
+            self.ticktimer.sleep_ms(RX_POLL_INTERVAL_MS).unwrap();
         }
     }
+
+    /// Identifies the stream to the Net server for `poll()`, along with the number of Rx deliveries seen
+    /// so far, and whether there is received data that hasn't been read yet.
+    pub(crate) fn poll_state(&self) -> (NetPollSocket, u32, bool) {
+        // the count has to be taken before looking at the buffer, as the Rx thread fills the buffer first
+        let rx_seen = self.rx_count.load(Ordering::SeqCst);
+        let socket = NetPollSocket::TcpStream {
+            ip_addr: NetIpAddr::from(self.socket_addr),
+            remote_port: self.socket_addr.port(),
+            local_port: self.local_port,
+        };
+        (socket, rx_seen, self.rx_buf.lock().unwrap().len() > 0)
+    }
 }
 
 impl fmt::Debug for TcpStream {
-    fn fmt(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        unimplemented!("work in progress")
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TcpStream")
+            .field("local_port", &self.local_port)
+            .field("peer", &self.socket_addr)
+            .field("nonblocking", &self.nonblocking)
+            .finish()
     }
 }
 
@@ -521,6 +594,7 @@ pub(crate) fn tcp_rx_thread(
     cb_sid_clone: SID,
     rx_buf: Arc::<Mutex::<VecDeque::<u8>>>,
     notify: Arc::<Mutex::<XousScalarEndpoint>>,
+    rx_count: Arc<AtomicU32>,
     listener: Arc::<Mutex::<Option<NetTcpListenCallback>>>,
 ) -> std::thread::JoinHandle<()> {
     thread::spawn({
@@ -538,6 +612,7 @@ pub(crate) fn tcp_rx_thread(
                                 rx_locked.push_back(d);
                             }
                         }
+                        rx_count.fetch_add(1, Ordering::SeqCst);
                         notify.lock().unwrap().notify(); // this will only notify if a destination has been set
                     }
                     Some(NetTcpCallback::ListenerActive) => {
@@ -554,6 +629,7 @@ pub(crate) fn tcp_rx_thread(
                         xous::return_scalar(msg.sender, 1).unwrap();
                         break;
                     }),
+                    Some(NetTcpCallback::Ping) => (),
                     None => {
                         log::error!("got unknown message type on Tcp callback: {:?}", msg);
                     }
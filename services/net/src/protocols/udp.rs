@@ -1,9 +1,8 @@
-use std::convert::TryInto;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
-use std::unimplemented;
 use std::io;
 use std::io::{Error, ErrorKind, Result};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::thread;
 use std::thread::JoinHandle;
 
@@ -35,6 +34,8 @@ pub struct UdpSocket{
     rx_buf: Arc<Mutex<Vec<UdpRx>>>,
     handle: Option<JoinHandle::<()>>,
     notify: Arc<Mutex<XousScalarEndpoint>>,
+    /// number of datagrams delivered by the Net server, used by `poll()`
+    rx_count: Arc<AtomicU32>,
     read_timeout: Option<Duration>,
     write_timeout: Option<Duration>,
     ticktimer: ticktimer_server::Ticktimer,
@@ -72,11 +73,13 @@ impl UdpSocket {
 
         let rx_buf = Arc::new(Mutex::new(Vec::new()));
         let notify = Arc::new(Mutex::new(XousScalarEndpoint::new()));
+        let rx_count = Arc::new(AtomicU32::new(0));
 
         let handle = thread::spawn({
             let cb_sid_clone = cb_sid.clone();
             let rx_buf = Arc::clone(&rx_buf);
             let notify = Arc::clone(&notify);
+            let rx_count = Arc::clone(&rx_count);
             move || {
                 loop {
                     let msg = xous::receive_message(cb_sid_clone).unwrap();
@@ -106,6 +109,7 @@ impl UdpSocket {
                                 rx.data.push(d);
                             }
                             rx_buf.lock().unwrap().push(rx);
+                            rx_count.fetch_add(1, Ordering::SeqCst);
                             notify.lock().unwrap().notify(); // this will only notify if a destination has been set
                         },
                         Some(NetUdpCallback::Drop) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
@@ -113,6 +117,7 @@ impl UdpSocket {
                             xous::return_scalar(msg.sender, 1).unwrap(); // actual return value doesn't matter -- it's that there is a return value
                             break;
                         }),
+                        Some(NetUdpCallback::Ping) => (),
                         None => {
                             log::error!("got unknown message type on Udp callback: {:?}", msg);
                         }
@@ -140,6 +145,7 @@ impl UdpSocket {
                     rx_buf,
                     handle: Some(handle),
                     notify,
+                    rx_count,
                     read_timeout: None,
                     write_timeout: None,
                     ticktimer: ticktimer_server::Ticktimer::new().unwrap(),
@@ -148,6 +154,12 @@ impl UdpSocket {
                     nonblocking: false,
                 })
             },
+            NetMemResponse::SocketInUse => {
+                Err(Error::new(ErrorKind::AddrInUse, "port is in use"))
+            }
+            NetMemResponse::OutOfMemory => {
+                Err(Error::new(ErrorKind::Other, "too many sockets open"))
+            }
             _ => {
                 Err(Error::new(ErrorKind::Other, "can't register with Net server"))
             }
@@ -227,18 +239,7 @@ impl UdpSocket {
                     IpAddress::Ipv4(ipv4) => {
                         IpAddr::V4(Ipv4Addr::new(ipv4.0[0], ipv4.0[1], ipv4.0[2], ipv4.0[3]))
                     },
-                    IpAddress::Ipv6(ipv6) => {
-                        IpAddr::V6(Ipv6Addr::new(
-                            u16::from_be_bytes(ipv6.0[0..1].try_into().unwrap()),
-                            u16::from_be_bytes(ipv6.0[2..3].try_into().unwrap()),
-                            u16::from_be_bytes(ipv6.0[4..5].try_into().unwrap()),
-                            u16::from_be_bytes(ipv6.0[6..7].try_into().unwrap()),
-                            u16::from_be_bytes(ipv6.0[8..9].try_into().unwrap()),
-                            u16::from_be_bytes(ipv6.0[10..11].try_into().unwrap()),
-                            u16::from_be_bytes(ipv6.0[12..13].try_into().unwrap()),
-                            u16::from_be_bytes(ipv6.0[14..15].try_into().unwrap()),
-                        ))
-                    },
+                    IpAddress::Ipv6(ipv6) => IpAddr::V6(Ipv6Addr::from(ipv6.0)),
                     _ => {
                         panic!("malformed endpoint record");
                     }
@@ -394,60 +395,122 @@ impl UdpSocket {
         Ok(())
     }
 
-    //////// the remaining functions are currently unimplemented: we don't have multicast or broadcast support //////////
-    pub fn set_broadcast(&self, _: bool) -> io::Result<()> {
-        unimplemented!("work in progress")
+    /// Identifies the socket to the Net server for `poll()`, along with the number of datagrams delivered
+    /// so far, and whether there is a received datagram that hasn't been read yet.
+    pub(crate) fn poll_state(&self) -> (NetPollSocket, u32, bool) {
+        // the count has to be taken before looking at the buffer, as the Rx thread fills the buffer first
+        let rx_seen = self.rx_count.load(Ordering::SeqCst);
+        let socket = NetPollSocket::UdpSocket {
+            local_port: self.socket_addr.port(),
+            cb_sid: self.cb_sid.to_array(),
+        };
+        (socket, rx_seen, self.rx_buf.lock().unwrap().len() > 0)
+    }
+
+    pub fn set_broadcast(&self, broadcast: bool) -> io::Result<()> {
+        self.set_option(Opcode::UdpSetBroadcast, broadcast as usize)
     }
 
     pub fn broadcast(&self) -> io::Result<bool> {
-        unimplemented!("work in progress")
+        self.get_option(Opcode::UdpGetBroadcast).map(|broadcast| broadcast != 0)
     }
 
-    pub fn set_multicast_loop_v4(&self, _: bool) -> io::Result<()> {
-        unimplemented!("work in progress")
+    pub fn set_multicast_loop_v4(&self, multicast_loop_v4: bool) -> io::Result<()> {
+        self.set_option(Opcode::UdpSetMulticastLoopV4, multicast_loop_v4 as usize)
     }
 
     pub fn multicast_loop_v4(&self) -> io::Result<bool> {
-        unimplemented!("work in progress")
+        self.get_option(Opcode::UdpGetMulticastLoopV4).map(|multicast_loop| multicast_loop != 0)
     }
 
-    pub fn set_multicast_ttl_v4(&self, _: u32) -> io::Result<()> {
-        unimplemented!("work in progress")
+    pub fn set_multicast_ttl_v4(&self, multicast_ttl_v4: u32) -> io::Result<()> {
+        // smoltcp can't emit a hop limit of 0, so datagrams can't be restricted to the local host with a TTL of 0
+        if multicast_ttl_v4 == 0 || multicast_ttl_v4 > 255 {
+            return Err(Error::new(ErrorKind::InvalidInput, "multicast TTL must be between 1 and 255"))
+        }
+        self.set_option(Opcode::UdpSetMulticastTtlV4, multicast_ttl_v4 as usize)
     }
 
     pub fn multicast_ttl_v4(&self) -> io::Result<u32> {
-        unimplemented!("work in progress")
+        self.get_option(Opcode::UdpGetMulticastTtlV4).map(|ttl| ttl as u32)
+    }
+
+    /// Joins the multicast group `multiaddr`. There is only one interface, so `interface` must be either
+    /// `Ipv4Addr::UNSPECIFIED` or our own address.
+    pub fn join_multicast_v4(&self, multiaddr: &Ipv4Addr, interface: &Ipv4Addr) -> io::Result<()> {
+        self.multicast_membership_v4(Opcode::UdpJoinMulticastV4, multiaddr, interface)
     }
 
-    pub fn join_multicast_v4(&self, _: &Ipv4Addr, _: &Ipv4Addr) -> io::Result<()> {
-        unimplemented!("work in progress")
+    pub fn leave_multicast_v4(&self, multiaddr: &Ipv4Addr, interface: &Ipv4Addr) -> io::Result<()> {
+        self.multicast_membership_v4(Opcode::UdpLeaveMulticastV4, multiaddr, interface)
     }
 
-    pub fn leave_multicast_v4(&self, _: &Ipv4Addr, _: &Ipv4Addr) -> io::Result<()> {
-        unimplemented!("work in progress")
+    fn set_option(&self, op: Opcode, value: usize) -> io::Result<()> {
+        send_message(
+            self.net.conn(),
+            Message::new_scalar(op.to_usize().unwrap(), value, self.socket_addr.port() as usize, 0, 0)
+        ).map(|_| ()).or(Err(Error::new(ErrorKind::ConnectionRefused, "can't send socket option to Net server")))
+    }
+
+    fn get_option(&self, op: Opcode) -> io::Result<usize> {
+        match send_message(
+            self.net.conn(),
+            Message::new_blocking_scalar(op.to_usize().unwrap(), self.socket_addr.port() as usize, 0, 0, 0)
+        ) {
+            Ok(xous::Result::Scalar1(usize::MAX)) => Err(Error::new(ErrorKind::NotConnected, "socket isn't bound in the Net server")),
+            Ok(xous::Result::Scalar1(value)) => Ok(value),
+            _ => Err(Error::new(ErrorKind::ConnectionRefused, "can't get socket option from Net server")),
+        }
+    }
+
+    fn multicast_membership_v4(&self, op: Opcode, multiaddr: &Ipv4Addr, interface: &Ipv4Addr) -> io::Result<()> {
+        match send_message(
+            self.net.conn(),
+            Message::new_blocking_scalar(
+                op.to_usize().unwrap(),
+                self.socket_addr.port() as usize,
+                u32::from(*multiaddr) as usize,
+                u32::from(*interface) as usize,
+                0,
+            )
+        ) {
+            Ok(xous::Result::Scalar1(code)) => match FromPrimitive::from_usize(code) {
+                Some(NetError::Ok) => Ok(()),
+                Some(NetError::AlreadyUsed) => Err(Error::new(ErrorKind::AddrInUse, "already a member of the multicast group")),
+                Some(NetError::OutOfMemory) => Err(Error::new(ErrorKind::OutOfMemory, "couldn't join the multicast group")),
+                Some(NetError::Invalid) => Err(Error::new(ErrorKind::InvalidInput, "invalid multicast group or interface")),
+                _ => Err(Error::new(ErrorKind::Other, "multicast membership change failed")),
+            },
+            _ => Err(Error::new(ErrorKind::ConnectionRefused, "can't change multicast membership with Net server")),
+        }
     }
 
+    // smoltcp doesn't implement MLD, so there's no way to join IPv6 multicast groups
     pub fn set_multicast_loop_v6(&self, _: bool) -> io::Result<()> {
-        unimplemented!("ipv6 not implemented")
+        Err(Error::new(ErrorKind::Other, "IPv6 multicast is not supported"))
     }
 
     pub fn multicast_loop_v6(&self) -> io::Result<bool> {
-        unimplemented!("ipv6 not implemented")
+        Err(Error::new(ErrorKind::Other, "IPv6 multicast is not supported"))
     }
 
     pub fn join_multicast_v6(&self, _: &Ipv6Addr, _: u32) -> io::Result<()> {
-        unimplemented!("ipv6 not implemented")
+        Err(Error::new(ErrorKind::Other, "IPv6 multicast is not supported"))
     }
 
     pub fn leave_multicast_v6(&self, _: &Ipv6Addr, _: u32) -> io::Result<()> {
-        unimplemented!("ipv6 not implemented")
+        Err(Error::new(ErrorKind::Other, "IPv6 multicast is not supported"))
     }
 
 }
 
 impl std::fmt::Debug for UdpSocket {
-    fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        unimplemented!("work in progress")
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UdpSocket")
+            .field("addr", &self.socket_addr)
+            .field("peer", &self.dest_socket)
+            .field("nonblocking", &self.nonblocking)
+            .finish()
     }
 }
 
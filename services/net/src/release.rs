@@ -0,0 +1,419 @@
+//! Takes sockets away from the clients that hold them, when a client asks for its leftovers to be closed
+//! with `Opcode::ReleaseProcess`, or when it goes away without closing them. A client can exit, crash, or drop
+//! a socket's callback server without ever talking to us again, so the server side checks on the callback
+//! servers of the sockets it holds (`dead_callbacks()`), and releases the sockets whose server is gone.
+//!
+//! Sockets opened through libstd (`Opcode::StdTcpConnect`) have no callback server, so they can only be
+//! closed by their owner.
+
+use crate::api::*;
+use crate::{TcpConnection, TcpState, UdpState};
+use num_traits::*;
+use smoltcp::socket::{SocketHandle, SocketSet, TcpSocket, TcpState as TcpSocketState};
+use smoltcp::wire::Ipv4Address;
+use std::collections::HashMap;
+use xous::{CID, SID};
+
+/// How long a closed TCP socket gets to finish its close handshake with the peer, before it is reset
+pub(crate) const TCP_LINGER_MS: u64 = 10_000;
+/// How often the callback servers of the sockets we hold are checked on
+pub(crate) const SWEEP_INTERVAL_MS: u64 = 10_000;
+
+/// The tables of sockets that are held for clients through a callback server
+pub(crate) struct ClientSockets<'a> {
+    pub(crate) tcp_handles: &'a mut HashMap<TcpConnection, TcpState>,
+    pub(crate) tcp_listeners: &'a mut HashMap<u16, Vec<TcpState>>,
+    pub(crate) tcp_listener_accepts: &'a mut HashMap<u16, (Option<xous::PID>, u32)>,
+    pub(crate) udp_handles: &'a mut HashMap<u16, UdpState>,
+    pub(crate) udp_clones: &'a mut HashMap<u16, HashMap<[u32; 4], CID>>,
+    pub(crate) udp_rx_counts: &'a mut HashMap<[u32; 4], u32>,
+}
+
+/// Sockets that have been taken out of the tables, and are left for the caller to close
+#[derive(Default, Debug)]
+pub(crate) struct Released {
+    pub(crate) tcp: Vec<SocketHandle>,
+    pub(crate) udp: Vec<SocketHandle>,
+    /// multicast memberships of the UDP sockets
+    pub(crate) multicast_groups: Vec<Ipv4Address>,
+    /// callback connections that no remaining socket is delivered through
+    pub(crate) cids: Vec<CID>,
+}
+impl Released {
+    pub(crate) fn count(&self) -> usize {
+        self.tcp.len() + self.udp.len()
+    }
+}
+
+impl<'a> ClientSockets<'a> {
+    /// Takes every socket for which `select(owner, callback)` is true out of the tables. A UDP socket whose
+    /// primary listener is selected is handed over to one of its remaining clones, as `Opcode::UdpClose` does.
+    pub(crate) fn release<F>(&mut self, select: F) -> Released
+    where
+        F: Fn(Option<xous::PID>, CID) -> bool,
+    {
+        let mut released = Released::default();
+        let mut cids = Vec::<CID>::new();
+        self.tcp_handles.retain(|_, tcp_state| {
+            if select(tcp_state.owner, tcp_state.cid) {
+                released.tcp.push(tcp_state.handle);
+                cids.push(tcp_state.cid);
+                false
+            } else {
+                true
+            }
+        });
+        for (local_port, listeners) in self.tcp_listeners.iter_mut() {
+            listeners.retain(|tcp_state| {
+                if select(tcp_state.owner, tcp_state.cid) {
+                    released.tcp.push(tcp_state.handle);
+                    cids.push(tcp_state.cid);
+                    false
+                } else {
+                    true
+                }
+            });
+            if listeners.is_empty() {
+                self.tcp_listener_accepts.remove(local_port);
+            }
+        }
+        let ports: Vec<u16> = self.udp_handles.keys().copied().collect();
+        for port in ports {
+            let (owner, primary_selected) = match self.udp_handles.get(&port) {
+                Some(udpstate) => (udpstate.owner, select(udpstate.owner, udpstate.cid)),
+                None => continue,
+            };
+            // clones are only ever made by the process that owns the socket
+            let mut clones = self.udp_clones.remove(&port).unwrap_or_default();
+            let udp_rx_counts = &mut *self.udp_rx_counts;
+            clones.retain(|sid, &mut cid| {
+                if select(owner, cid) {
+                    udp_rx_counts.remove(sid);
+                    cids.push(cid);
+                    false
+                } else {
+                    true
+                }
+            });
+            if primary_selected {
+                let udpstate = self.udp_handles.remove(&port).unwrap();
+                self.udp_rx_counts.remove(&udpstate.sid.to_array());
+                cids.push(udpstate.cid);
+                if let Some(&new_primary_sid) = clones.keys().next() {
+                    let cid = clones.remove(&new_primary_sid).unwrap();
+                    self.udp_handles.insert(
+                        port,
+                        UdpState {
+                            cid,
+                            sid: SID::from_array(new_primary_sid),
+                            ..udpstate
+                        },
+                    );
+                } else {
+                    released.udp.push(udpstate.handle);
+                    released.multicast_groups.extend(udpstate.multicast_groups);
+                }
+            }
+            if !clones.is_empty() {
+                self.udp_clones.insert(port, clones);
+            }
+        }
+        cids.sort_unstable();
+        cids.dedup();
+        // accepted connections share the callback server of their listener
+        cids.retain(|&cid| !self.uses(cid));
+        released.cids = cids;
+        released
+    }
+
+    /// Whether any socket is delivered through `cid`
+    fn uses(&self, cid: CID) -> bool {
+        self.tcp_handles.values().any(|s| s.cid == cid)
+            || self.tcp_listeners.values().flatten().any(|s| s.cid == cid)
+            || self.udp_handles.values().any(|s| s.cid == cid)
+            || self
+                .udp_clones
+                .values()
+                .any(|clones| clones.values().any(|&c| c == cid))
+    }
+
+    /// The callback connections whose server has gone away, because its process exited or dropped it
+    /// without closing the socket. A server whose queue is full is still there.
+    pub(crate) fn dead_callbacks(&self) -> Vec<CID> {
+        let tcp = self
+            .tcp_handles
+            .values()
+            .chain(self.tcp_listeners.values().flatten())
+            .map(|s| (s.cid, NetTcpCallback::Ping.to_usize().unwrap()));
+        let udp = self
+            .udp_handles
+            .values()
+            .map(|s| s.cid)
+            .chain(
+                self.udp_clones
+                    .values()
+                    .flat_map(|clones| clones.values().copied()),
+            )
+            .map(|cid| (cid, NetUdpCallback::Ping.to_usize().unwrap()));
+        let mut dead = Vec::<CID>::new();
+        for (cid, ping) in tcp.chain(udp) {
+            if dead.contains(&cid) {
+                continue;
+            }
+            if matches!(
+                xous::try_send_message(cid, xous::Message::new_scalar(ping, 0, 0, 0, 0)),
+                Err(xous::Error::ServerNotFound) | Err(xous::Error::ProcessTerminated)
+            ) {
+                dead.push(cid);
+            }
+        }
+        dead
+    }
+}
+
+/// Closes a TCP socket. The socket stays in the set, in `closing`, until the close handshake with the peer
+/// is done or `TCP_LINGER_MS` have passed; `tcp_reap()` removes it after that.
+pub(crate) fn tcp_close(
+    sockets: &mut SocketSet,
+    closing: &mut Vec<(SocketHandle, u64)>,
+    handle: SocketHandle,
+    now: u64,
+) {
+    sockets.get::<TcpSocket>(handle).close();
+    closing.push((handle, now.saturating_add(TCP_LINGER_MS)));
+}
+
+/// Removes the closed sockets that are done with their peer. Sockets that are still at it after their linger
+/// time are reset; that goes out with the next `Interface::poll()`, so they are removed on the call after.
+pub(crate) fn tcp_reap(sockets: &mut SocketSet, closing: &mut Vec<(SocketHandle, u64)>, now: u64) {
+    closing.retain(|&(handle, deadline)| {
+        let done = {
+            let mut socket = sockets.get::<TcpSocket>(handle);
+            match socket.state() {
+                TcpSocketState::Closed | TcpSocketState::TimeWait => true,
+                _ => {
+                    if now >= deadline {
+                        log::debug!(
+                            "resetting a socket that didn't close in time: {:?}",
+                            socket.remote_endpoint()
+                        );
+                        socket.abort();
+                    }
+                    false
+                }
+            }
+        };
+        if done {
+            sockets.remove(handle);
+        }
+        !done
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::socket::{TcpSocketBuffer, UdpPacketMetadata, UdpSocket, UdpSocketBuffer};
+    use smoltcp::wire::IpAddress;
+
+    fn tcp_socket(sockets: &mut SocketSet) -> SocketHandle {
+        sockets.add(TcpSocket::new(
+            TcpSocketBuffer::new(vec![0; 64]),
+            TcpSocketBuffer::new(vec![0; 64]),
+        ))
+    }
+
+    fn udp_socket(sockets: &mut SocketSet) -> SocketHandle {
+        sockets.add(UdpSocket::new(
+            UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY], vec![0; 64]),
+            UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY], vec![0; 64]),
+        ))
+    }
+
+    fn tcp_state(handle: SocketHandle, cid: CID, owner: u8) -> TcpState {
+        TcpState {
+            handle,
+            cid,
+            shutdown_rx: false,
+            rx_count: 0,
+            owner: xous::PID::new(owner),
+        }
+    }
+
+    fn udp_state(handle: SocketHandle, cid: CID, sid: u32, owner: u8) -> UdpState {
+        UdpState {
+            handle,
+            cid,
+            sid: SID::from_array([sid, 0, 0, 0]),
+            owner: xous::PID::new(owner),
+            ttl: None,
+            multicast_ttl: 1,
+            broadcast: false,
+            multicast_loop: true,
+            multicast_groups: vec![Ipv4Address::new(239, 1, 2, 3)],
+        }
+    }
+
+    fn connection(local_port: u16) -> TcpConnection {
+        TcpConnection {
+            remote: IpAddress::v4(10, 0, 0, 1),
+            remote_port: 80,
+            local_port,
+        }
+    }
+
+    #[derive(Default)]
+    struct Tables {
+        tcp_handles: HashMap<TcpConnection, TcpState>,
+        tcp_listeners: HashMap<u16, Vec<TcpState>>,
+        tcp_listener_accepts: HashMap<u16, (Option<xous::PID>, u32)>,
+        udp_handles: HashMap<u16, UdpState>,
+        udp_clones: HashMap<u16, HashMap<[u32; 4], CID>>,
+        udp_rx_counts: HashMap<[u32; 4], u32>,
+        process_sockets: HashMap<Option<xous::PID>, Vec<Option<SocketHandle>>>,
+    }
+    impl Tables {
+        fn client_sockets(&mut self) -> ClientSockets {
+            ClientSockets {
+                tcp_handles: &mut self.tcp_handles,
+                tcp_listeners: &mut self.tcp_listeners,
+                tcp_listener_accepts: &mut self.tcp_listener_accepts,
+                udp_handles: &mut self.udp_handles,
+                udp_clones: &mut self.udp_clones,
+                udp_rx_counts: &mut self.udp_rx_counts,
+            }
+        }
+        fn owned(&self, pid: u8) -> usize {
+            crate::sockets_owned(
+                xous::PID::new(pid),
+                &self.tcp_handles,
+                &self.tcp_listeners,
+                &self.udp_handles,
+                &self.process_sockets,
+            )
+        }
+    }
+
+    /// process 2 has a stream accepted from its listener, another listener on the same port, and a UDP socket
+    /// with a clone; process 3 has a stream and a libstd stream
+    fn tables(sockets: &mut SocketSet) -> Tables {
+        let mut t = Tables::default();
+        let accepted = tcp_socket(sockets);
+        t.tcp_handles
+            .insert(connection(8080), tcp_state(accepted, 10, 2));
+        let listener = tcp_socket(sockets);
+        t.tcp_listeners
+            .insert(8080, vec![tcp_state(listener, 10, 2)]);
+        t.tcp_listener_accepts.insert(8080, (xous::PID::new(2), 1));
+        let udp = udp_socket(sockets);
+        t.udp_handles.insert(5353, udp_state(udp, 11, 11, 2));
+        let mut clones = HashMap::new();
+        clones.insert([12, 0, 0, 0], 12);
+        t.udp_clones.insert(5353, clones);
+        t.udp_rx_counts.insert([11, 0, 0, 0], 4);
+        t.udp_rx_counts.insert([12, 0, 0, 0], 4);
+        let stream = tcp_socket(sockets);
+        t.tcp_handles
+            .insert(connection(50000), tcp_state(stream, 20, 3));
+        let std_stream = tcp_socket(sockets);
+        t.process_sockets
+            .insert(xous::PID::new(3), vec![Some(std_stream), None]);
+        t
+    }
+
+    #[test]
+    fn quotas_count_every_kind_of_socket() {
+        let mut sockets = SocketSet::new(vec![]);
+        let mut t = tables(&mut sockets);
+        assert_eq!(t.owned(2), 3);
+        assert_eq!(t.owned(3), 2);
+        assert_eq!(t.owned(4), 0);
+        let pid = xous::PID::new(2);
+        t.client_sockets().release(|owner, _| owner == pid);
+        assert_eq!(t.owned(2), 0);
+        assert_eq!(t.owned(3), 2);
+    }
+
+    #[test]
+    fn release_by_process() {
+        let mut sockets = SocketSet::new(vec![]);
+        let mut t = tables(&mut sockets);
+        let pid = xous::PID::new(2);
+        let released = t.client_sockets().release(|owner, _| owner == pid);
+        assert_eq!(released.count(), 3);
+        assert_eq!(released.tcp.len(), 2);
+        assert_eq!(released.udp.len(), 1);
+        assert_eq!(
+            released.multicast_groups,
+            vec![Ipv4Address::new(239, 1, 2, 3)]
+        );
+        // the clone's connection goes too, and the shared listener connection only once
+        assert_eq!(released.cids, vec![10, 11, 12]);
+        assert!(t.tcp_listeners[&8080].is_empty());
+        assert!(t.tcp_listener_accepts.is_empty());
+        assert!(t.udp_handles.is_empty());
+        assert!(t.udp_clones.is_empty());
+        assert!(t.udp_rx_counts.is_empty());
+        // other processes keep their sockets
+        assert_eq!(t.tcp_handles.len(), 1);
+        assert_eq!(t.process_sockets[&xous::PID::new(3)].len(), 2);
+    }
+
+    #[test]
+    fn release_by_callback() {
+        let mut sockets = SocketSet::new(vec![]);
+        let mut t = tables(&mut sockets);
+        // the listener's callback is still shared with the accepted stream, so it's kept
+        let released = t.client_sockets().release(|_, cid| cid == 11);
+        assert_eq!(released.count(), 0);
+        assert_eq!(released.cids, vec![11]);
+        // the clone took over the socket, with its options
+        let udpstate = &t.udp_handles[&5353];
+        assert_eq!(udpstate.cid, 12);
+        assert_eq!(udpstate.sid, SID::from_array([12, 0, 0, 0]));
+        assert_eq!(udpstate.multicast_groups.len(), 1);
+        assert!(t.udp_clones.is_empty());
+        assert_eq!(t.udp_rx_counts.len(), 1);
+        let released = t.client_sockets().release(|_, cid| cid == 10);
+        assert_eq!(released.tcp.len(), 2);
+        assert_eq!(released.cids, vec![10]);
+        assert_eq!(t.owned(2), 1);
+        // nothing selected, nothing released
+        let released = t.client_sockets().release(|_, _| false);
+        assert_eq!(released.count(), 0);
+        assert!(released.cids.is_empty());
+    }
+
+    #[test]
+    fn closed_sockets_linger() {
+        let mut sockets = SocketSet::new(vec![]);
+        let listener = tcp_socket(&mut sockets);
+        sockets.get::<TcpSocket>(listener).listen(8080).unwrap();
+        let stream = tcp_socket(&mut sockets);
+        sockets
+            .get::<TcpSocket>(stream)
+            .connect((IpAddress::v4(10, 0, 0, 1), 80), 50000)
+            .unwrap();
+        let mut closing = Vec::new();
+        // neither has a peer to say goodbye to yet, so closing them is immediate
+        tcp_close(&mut sockets, &mut closing, listener, 0);
+        tcp_reap(&mut sockets, &mut closing, 1);
+        assert!(closing.is_empty());
+        // one that is still talking to its peer is reset once its time is up, and removed after the reset has gone out
+        closing.push((stream, TCP_LINGER_MS));
+        tcp_reap(&mut sockets, &mut closing, 1);
+        assert_eq!(
+            sockets.get::<TcpSocket>(stream).state(),
+            TcpSocketState::SynSent
+        );
+        tcp_reap(&mut sockets, &mut closing, TCP_LINGER_MS);
+        assert_eq!(
+            sockets.get::<TcpSocket>(stream).state(),
+            TcpSocketState::Closed
+        );
+        assert_eq!(closing.len(), 1);
+        tcp_reap(&mut sockets, &mut closing, TCP_LINGER_MS + 1);
+        assert!(closing.is_empty());
+        assert_eq!(sockets.iter().count(), 0);
+    }
+}
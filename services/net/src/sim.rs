@@ -0,0 +1,845 @@
+//! A simulated network for hosted mode.
+//!
+//! In hosted mode there is no EC, and so no WLAN chip to exchange packets with. `SimCom` stands in for
+//! the parts of `com::Com` that the net server and the connection manager use, and swaps packets with an
+//! in-process virtual network instead. This lets the full smoltcp stack in `main.rs` and the connection
+//! manager run on the host, without touching the host's network. Clients only reach the simulated network
+//! if the `hosted-sim` feature is enabled (`cargo xtask net-sim`); otherwise, they use the host's sockets.
+//!
+//! The virtual network has a single access point, `SIM_SSID`, which takes any password. Once it's joined,
+//! the link comes up with a fixed IPv4 configuration, and a single gateway at `SIM_GATEWAY_IPV4`, which:
+//!   - answers ARP requests for its own address, and IPv6 neighbor solicitations for its link-local address
+//!   - answers router solicitations with an advertisement for `SIM_IPV6_PREFIX`, so SLAAC can be exercised
+//!   - answers ICMP echo requests to any address, as if the whole internet were reachable
+//!   - answers DNS queries on port 53 for any address, out of a hosts table
+//!   - echoes UDP datagrams sent to port 7 of any address
+//!   - answers SNTP requests on port 123 of any address, with the host's clock
+//!   - accepts TCP connections to port 7 of any address, and echoes what is sent to them
+//!   - accepts TCP connections to port 80 of any address, and answers every HTTP request with a 204,
+//!     so the connection manager's connectivity probe finds the network online
+//!   - resets any other TCP connection attempt
+//!
+//! Apart from the time in SNTP replies, a given sequence of packets from the device always gets the same
+//! responses. The hosts table holds `gateway.sim`, `pool.ntp.org` and the connectivity probe's host, plus
+//! any entries listed in the `XOUS_NET_SIM_HOSTS` environment variable, in the form `name=a.b.c.d,othername=e.f.g.h`.
+//!
+//! Traffic can also be captured to, or replayed from, a pcap file:
+//!   - if `XOUS_NET_PCAP` is set, every frame crossing the link is appended to the pcap file it names.
+//!   - if `XOUS_NET_PCAP_REPLAY` is set, the gateway is replaced by the capture it names. Frames in the capture that
+//!     weren't sent by the device are delivered to the device in lockstep: the ones leading up to the first frame from
+//!     the device are delivered when the link comes up, and every frame sent by the device releases the ones that
+//!     follow it in the capture. As the device always gets `SIM_DEVICE_MAC`, captures taken with `XOUS_NET_PCAP`
+//!     can be replayed directly.
+//!
+//! Like the EC, the network is shared by every `SimCom` in the process: the net server's, which moves the
+//! packets, and the connection manager's, which joins and leaves the access point.
+
+use com::api::{ComIntSources, Ipv4Conf, NET_MTU};
+use com::{SsidRecord, WlanStatus, WlanStatusIpc};
+use com_rs_ref::{ConnectResult, DhcpState, LinkState};
+use xous::{Message, CID};
+
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::time::Duration;
+use smoltcp::wire::{ArpOperation, ArpPacket, ArpRepr};
+use smoltcp::wire::{EthernetAddress, EthernetFrame, EthernetProtocol, EthernetRepr, IpAddress, IpProtocol};
+use smoltcp::wire::{Icmpv4Packet, Icmpv4Repr, Ipv4Address, Ipv4Packet, Ipv4Repr};
+use smoltcp::wire::{Icmpv6Packet, Icmpv6Repr, Ipv6Address, Ipv6Packet, Ipv6Repr};
+use smoltcp::wire::{NdiscNeighborFlags, NdiscPrefixInfoFlags, NdiscPrefixInformation, NdiscRepr, NdiscRouterFlags};
+use smoltcp::wire::{TcpPacket, TcpSeqNumber, UdpPacket};
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+
+/// the one access point on the simulated network
+pub const SIM_SSID: &str = "xous-sim";
+/// signal strength of the access point, in -dBm
+pub const SIM_RSSI: u8 = 42;
+pub const SIM_DEVICE_MAC: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0x02]);
+pub const SIM_DEVICE_IPV4: Ipv4Address = Ipv4Address([10, 0, 245, 2]);
+pub const SIM_GATEWAY_MAC: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0x01]);
+pub const SIM_GATEWAY_IPV4: Ipv4Address = Ipv4Address([10, 0, 245, 1]);
+/// link-local address of the gateway, derived from `SIM_GATEWAY_MAC`
+pub const SIM_GATEWAY_IPV6: Ipv6Address = Ipv6Address([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xfe, 0, 0, 0x01]);
+/// the /64 prefix advertised by the gateway for SLAAC
+pub const SIM_IPV6_PREFIX: Ipv6Address = Ipv6Address([0xfd, 0, 0, 0, 0, 0, 0x02, 0x45, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+const DNS_PORT: u16 = 53;
+const ECHO_PORT: u16 = 7;
+const HTTP_PORT: u16 = 80;
+const NTP_PORT: u16 = 123;
+/// seconds from the NTP epoch (1900) to the Unix epoch (1970)
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+const DNS_TTL: u32 = 300;
+/// the gateway's initial sequence number, for every connection
+const TCP_ISN: TcpSeqNumber = TcpSeqNumber(0x5157_0000);
+const TCP_WINDOW: u16 = 8192;
+const HTTP_RESPONSE: &[u8] = b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+lazy_static! {
+    static ref NETWORK: Arc<Mutex<SimNetwork>> = Arc::new(Mutex::new(SimNetwork::new()));
+}
+
+/// Stands in for `com::Com` in hosted mode; see the module documentation.
+#[derive(Clone)]
+pub struct SimCom {
+    net: Arc<Mutex<SimNetwork>>,
+    /// the SSID to try on the next `wlan_join()`
+    ssid: Option<String>,
+}
+
+impl SimCom {
+    pub fn new(_xns: &xous_names::XousNames) -> Result<SimCom, xous::Error> {
+        Ok(SimCom { net: NETWORK.clone(), ssid: None })
+    }
+    /// A `SimCom` on a network of its own, so tests don't share the network with each other.
+    #[cfg(test)]
+    pub fn isolated() -> SimCom {
+        SimCom { net: Arc::new(Mutex::new(SimNetwork::new())), ssid: None }
+    }
+    /// Takes the place of the LLIO COM event callback: `opcode` is sent to `cid` whenever
+    /// there are interrupts pending.
+    pub fn hook_event_callback(&self, opcode: u32, cid: CID) {
+        let mut net = self.net.lock().unwrap();
+        net.callback = Some((cid, opcode));
+        net.notify();
+    }
+    /// All interrupts are always enabled on the simulated link.
+    pub fn ints_enable(&self, _int_list: &[ComIntSources]) {}
+    pub fn ints_ack(&self, int_list: &[ComIntSources]) {
+        let mut net = self.net.lock().unwrap();
+        for &item in int_list.iter() {
+            let item_as_u16: u16 = item.into();
+            net.ints &= !item_as_u16;
+        }
+        // the EC keeps RxReady asserted for as long as it has packets buffered
+        if net.rx_queue.len() > 0 {
+            net.interrupt(ComIntSources::WlanRxReady);
+        }
+        net.notify();
+    }
+    pub fn ints_get_active(&self, int_list: &mut Vec<ComIntSources>) -> Result<(Option<u16>, usize, usize), xous::Error> {
+        let mut net = self.net.lock().unwrap();
+        net.callback_pending = false;
+        let rx_ready: u16 = ComIntSources::WlanRxReady.into();
+        let connect: u16 = ComIntSources::Connect.into();
+        // the EC has a single argument to go with the interrupts, so a connect result and a packet length
+        // can't be reported together. The packet stays pending, and is reported on the next fetch.
+        let ints = if net.ints & connect != 0 { net.ints & !rx_ready } else { net.ints };
+        let mut mask_bit: u16 = 1;
+        for _ in 0..16 {
+            let int_src = ComIntSources::from(mask_bit & ints);
+            if int_src != ComIntSources::Invalid {
+                int_list.push(int_src);
+            }
+            mask_bit <<= 1;
+        }
+        if ints & connect != 0 {
+            return Ok((Some(net.connect_result), ints as usize, net.connect_result as usize));
+        }
+        let rxlen = if ints & rx_ready != 0 { net.rx_queue.front().map(|frame| frame.len() as u16) } else { None };
+        Ok((rxlen, ints as usize, rxlen.unwrap_or(0) as usize))
+    }
+    /// Reports the EC firmware the net server was written against.
+    pub fn get_ec_sw_tag(&self) -> Result<(u8, u8, u8, u8), xous::Error> {
+        let [maj, min, rev, commits] = crate::api::MIN_EC_REV.to_be_bytes();
+        Ok((maj, min, rev, commits))
+    }
+    /// A scan finishes right away, and always finds `SIM_SSID`.
+    pub fn set_ssid_scanning(&self, enable: bool) -> Result<(), xous::Error> {
+        if enable {
+            let mut net = self.net.lock().unwrap();
+            net.interrupt(ComIntSources::WlanSsidScanFinished);
+            net.notify();
+        }
+        Ok(())
+    }
+    pub fn ssid_fetch_as_list(&self) -> Result<Vec<(u8, String)>, xous::Error> {
+        Ok(vec![(SIM_RSSI, SIM_SSID.to_string())])
+    }
+    pub fn wlan_set_ssid(&mut self, s: &str) -> Result<xous::Result, xous::Error> {
+        self.ssid = Some(s.to_string());
+        Ok(xous::Result::Ok)
+    }
+    pub fn wlan_set_pass(&mut self, _s: &str) -> Result<xous::Result, xous::Error> {
+        Ok(xous::Result::Ok)
+    }
+    /// Joining `SIM_SSID` brings the link up, and gets an address right away. Any other SSID is out of range.
+    pub fn wlan_join(&mut self) -> Result<xous::Result, xous::Error> {
+        let mut net = self.net.lock().unwrap();
+        if self.ssid.as_deref() == Some(SIM_SSID) {
+            net.connect_result = ConnectResult::Success as u16;
+            net.interrupt(ComIntSources::Connect);
+            net.link_up();
+        } else {
+            net.connect_result = ConnectResult::NoMatchingAp as u16;
+            net.interrupt(ComIntSources::Connect);
+        }
+        net.notify();
+        Ok(xous::Result::Ok)
+    }
+    pub fn wlan_leave(&mut self) -> Result<xous::Result, xous::Error> {
+        let mut net = self.net.lock().unwrap();
+        if net.link {
+            net.link_down();
+            net.interrupt(ComIntSources::Disconnect);
+            net.notify();
+        }
+        Ok(xous::Result::Ok)
+    }
+    pub fn wifi_reset(&self) -> Result<usize, xous::Error> {
+        self.net.lock().unwrap().link_down();
+        Ok(0)
+    }
+    pub fn wlan_status(&mut self) -> Result<WlanStatus, xous::Error> {
+        let mut status = WlanStatus::from_ipc(WlanStatusIpc::default());
+        if self.net.lock().unwrap().link {
+            status.ssid = Some(SsidRecord { name: xous_ipc::String::<32>::from_str(SIM_SSID), rssi: SIM_RSSI });
+            status.link_state = LinkState::Connected;
+            status.ipv4 = self.wlan_get_config()?;
+        }
+        Ok(status)
+    }
+    pub fn wlan_sync_state(&self) -> Result<(LinkState, DhcpState), xous::Error> {
+        let config = self.wlan_get_config()?;
+        if self.net.lock().unwrap().link {
+            Ok((LinkState::Connected, config.dhcp))
+        } else {
+            Ok((LinkState::Unknown, config.dhcp))
+        }
+    }
+    pub fn wlan_get_rssi(&self) -> Result<u8, xous::Error> {
+        Ok(SIM_RSSI)
+    }
+    pub fn wlan_get_config(&self) -> Result<Ipv4Conf, xous::Error> {
+        let mut config = Ipv4Conf::default();
+        config.mac = SIM_DEVICE_MAC.0;
+        if self.net.lock().unwrap().link {
+            config.dhcp = DhcpState::Bound;
+            config.addr = SIM_DEVICE_IPV4.0;
+            config.gtwy = SIM_GATEWAY_IPV4.0;
+            config.dns1 = SIM_GATEWAY_IPV4.0;
+        }
+        Ok(config)
+    }
+    pub fn wlan_fetch_packet(&self, pkt: &mut [u8]) -> Result<(), xous::Error> {
+        let frame = self.net.lock().unwrap().rx_queue.pop_front().ok_or(xous::Error::InternalError)?;
+        if frame.len() != pkt.len() {
+            log::error!("fetch of {} bytes, but the next frame is {} bytes", pkt.len(), frame.len());
+            return Err(xous::Error::InternalError);
+        }
+        pkt.copy_from_slice(&frame);
+        Ok(())
+    }
+    pub fn wlan_send_packet(&self, pkt: &[u8]) -> Result<(), xous::Error> {
+        if pkt.len() > NET_MTU {
+            return Err(xous::Error::OutOfMemory);
+        }
+        let mut net = self.net.lock().unwrap();
+        net.transmit(pkt);
+        net.notify();
+        Ok(())
+    }
+}
+
+/// Identifies a TCP connection by the gateway's address and port, and the device's port
+type TcpKey = (IpAddress, u16, u16);
+
+/// The gateway's end of a TCP connection
+struct TcpPeer {
+    /// next sequence number the gateway sends
+    snd_nxt: TcpSeqNumber,
+    /// next sequence number expected from the device
+    rcv_nxt: TcpSeqNumber,
+    /// what has been received of an HTTP request so far
+    request: Vec<u8>,
+    /// set once the gateway has sent its FIN
+    closed: bool,
+    /// set once the device has sent its FIN
+    peer_closed: bool,
+}
+
+struct SimNetwork {
+    /// frames waiting to be fetched by the device
+    rx_queue: VecDeque<Vec<u8>>,
+    /// pending interrupt bits, in the format used by the EC
+    ints: u16,
+    /// where to send interrupt notifications: (connection, opcode)
+    callback: Option<(CID, u32)>,
+    /// set while a notification is in flight, so the net server's queue isn't flooded with them
+    callback_pending: bool,
+    /// result of the last join, reported along with the `Connect` interrupt
+    connect_result: u16,
+    /// set while the access point is joined
+    link: bool,
+    hosts: HashMap<String, Ipv4Address>,
+    tcp: HashMap<TcpKey, TcpPeer>,
+    capture: Option<PcapWriter>,
+    /// remaining frames of a capture being replayed
+    replay: Option<VecDeque<Vec<u8>>>,
+}
+
+impl SimNetwork {
+    fn new() -> SimNetwork {
+        let mut hosts = HashMap::new();
+        hosts.insert("gateway.sim".to_string(), SIM_GATEWAY_IPV4);
+        // the default SNTP server, so the time syncs out of the box
+        hosts.insert("pool.ntp.org".to_string(), SIM_GATEWAY_IPV4);
+        hosts.insert(crate::connectivity::PROBE_HOST.to_string(), SIM_GATEWAY_IPV4);
+        if let Ok(list) = std::env::var("XOUS_NET_SIM_HOSTS") {
+            for entry in list.split(',').filter(|e| e.len() > 0) {
+                let parsed = entry
+                    .split_once('=')
+                    .and_then(|(name, addr)| addr.parse::<std::net::Ipv4Addr>().ok().map(|a| (name, a)));
+                match parsed {
+                    Some((name, addr)) => {
+                        hosts.insert(name.to_lowercase(), Ipv4Address::from_bytes(&addr.octets()));
+                    }
+                    None => log::warn!("ignoring malformed XOUS_NET_SIM_HOSTS entry: {}", entry),
+                }
+            }
+        }
+        let capture = std::env::var("XOUS_NET_PCAP").ok().and_then(|path| match PcapWriter::new(&path) {
+            Ok(writer) => {
+                log::info!("capturing simulated network traffic to {}", path);
+                Some(writer)
+            }
+            Err(e) => {
+                log::error!("couldn't create capture file {}: {:?}", path, e);
+                None
+            }
+        });
+        let replay = std::env::var("XOUS_NET_PCAP_REPLAY").ok().and_then(|path| match pcap_read(&path) {
+            Ok(frames) => {
+                log::info!("replaying {} frames from {}", frames.len(), path);
+                Some(frames)
+            }
+            Err(e) => {
+                log::error!("couldn't read capture file {}: {:?}", path, e);
+                None
+            }
+        });
+        SimNetwork {
+            rx_queue: VecDeque::new(),
+            ints: 0,
+            callback: None,
+            callback_pending: false,
+            connect_result: ConnectResult::Pending as u16,
+            link: false,
+            hosts,
+            tcp: HashMap::new(),
+            capture,
+            replay,
+        }
+    }
+
+    fn interrupt(&mut self, source: ComIntSources) {
+        let source_as_u16: u16 = source.into();
+        self.ints |= source_as_u16;
+    }
+
+    /// lets the net server know there are interrupts pending
+    fn notify(&mut self) {
+        if self.ints == 0 || self.callback_pending {
+            return;
+        }
+        if let Some((cid, opcode)) = self.callback {
+            match xous::try_send_message(cid, Message::new_scalar(opcode as usize, 0, 0, 0, 0)) {
+                Ok(_) => self.callback_pending = true,
+                Err(e) => log::warn!("couldn't deliver simulated COM interrupt: {:?}", e),
+            }
+        }
+    }
+
+    fn link_up(&mut self) {
+        self.link = true;
+        self.release_replay();
+        self.interrupt(ComIntSources::WlanIpConfigUpdate);
+    }
+
+    fn link_down(&mut self) {
+        self.link = false;
+        self.tcp.clear();
+    }
+
+    /// queues a frame for the device
+    fn deliver(&mut self, frame: Vec<u8>) {
+        if let Some(capture) = self.capture.as_mut() {
+            capture.write(&frame);
+        }
+        self.rx_queue.push_back(frame);
+        self.interrupt(ComIntSources::WlanRxReady);
+    }
+
+    /// handles a frame sent by the device
+    fn transmit(&mut self, frame: &[u8]) {
+        if !self.link {
+            // nothing is listening until the access point is joined
+            return;
+        }
+        if let Some(capture) = self.capture.as_mut() {
+            capture.write(frame);
+        }
+        if self.replay.is_some() {
+            // skip over the device's own frame in the capture, and release the responses that follow it
+            if let Some(replay) = self.replay.as_mut() {
+                if let Some(index) = replay.iter().position(|f| sent_by_device(f)) {
+                    replay.remove(index);
+                }
+            }
+            self.release_replay();
+        } else {
+            for response in self.respond(frame) {
+                self.deliver(response);
+            }
+        }
+    }
+
+    /// delivers the captured frames up to the next one sent by the device
+    fn release_replay(&mut self) {
+        loop {
+            let next = match self.replay.as_mut() {
+                Some(replay) if replay.front().map(|f| !sent_by_device(f)).unwrap_or(false) => replay.pop_front(),
+                _ => None,
+            };
+            match next {
+                Some(frame) => self.deliver(frame),
+                None => break,
+            }
+        }
+    }
+
+    /// Computes the gateway's responses to a frame from the device.
+    fn respond(&mut self, frame: &[u8]) -> Vec<Vec<u8>> {
+        let frame = match EthernetFrame::new_checked(frame) {
+            Ok(frame) => frame,
+            Err(_) => return vec![],
+        };
+        let response = match frame.ethertype() {
+            EthernetProtocol::Arp => self.respond_arp(frame.payload()),
+            EthernetProtocol::Ipv4 => self.respond_ipv4(frame.payload()),
+            EthernetProtocol::Ipv6 => self.respond_ipv6(frame.payload()),
+            _ => None,
+        };
+        response.into_iter().collect()
+    }
+
+    fn respond_arp(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        let packet = ArpPacket::new_checked(payload).ok()?;
+        match ArpRepr::parse(&packet).ok()? {
+            ArpRepr::EthernetIpv4 {
+                operation: ArpOperation::Request,
+                source_hardware_addr,
+                source_protocol_addr,
+                target_protocol_addr,
+                ..
+            } if target_protocol_addr == SIM_GATEWAY_IPV4 => {
+                let reply = ArpRepr::EthernetIpv4 {
+                    operation: ArpOperation::Reply,
+                    source_hardware_addr: SIM_GATEWAY_MAC,
+                    source_protocol_addr: SIM_GATEWAY_IPV4,
+                    target_hardware_addr: source_hardware_addr,
+                    target_protocol_addr: source_protocol_addr,
+                };
+                Some(ethernet_frame(source_hardware_addr, EthernetProtocol::Arp, reply.buffer_len(), |buf| {
+                    reply.emit(&mut ArpPacket::new_unchecked(buf))
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    fn respond_ipv4(&mut self, payload: &[u8]) -> Option<Vec<u8>> {
+        let caps = ChecksumCapabilities::default();
+        let packet = Ipv4Packet::new_checked(payload).ok()?;
+        let ip_repr = Ipv4Repr::parse(&packet, &caps).ok()?;
+        // the gateway answers for every address, so the reply comes from wherever the request was sent
+        let (src, dst) = (ip_repr.dst_addr, ip_repr.src_addr);
+        match ip_repr.protocol {
+            IpProtocol::Icmp => {
+                let icmp_packet = Icmpv4Packet::new_checked(packet.payload()).ok()?;
+                match Icmpv4Repr::parse(&icmp_packet, &caps).ok()? {
+                    Icmpv4Repr::EchoRequest { ident, seq_no, data } => {
+                        let reply = Icmpv4Repr::EchoReply { ident, seq_no, data };
+                        Some(ipv4_frame(src, dst, IpProtocol::Icmp, reply.buffer_len(), |buf| {
+                            reply.emit(&mut Icmpv4Packet::new_unchecked(buf), &caps)
+                        }))
+                    }
+                    _ => None,
+                }
+            }
+            IpProtocol::Udp => {
+                let udp_packet = UdpPacket::new_checked(packet.payload()).ok()?;
+                let reply = match udp_packet.dst_port() {
+                    DNS_PORT => self.dns_reply(udp_packet.payload())?,
+                    ECHO_PORT => udp_packet.payload().to_vec(),
+                    NTP_PORT => ntp_reply(udp_packet.payload())?,
+                    _ => return None,
+                };
+                let (src_port, dst_port) = (udp_packet.dst_port(), udp_packet.src_port());
+                Some(ipv4_frame(src, dst, IpProtocol::Udp, 8 + reply.len(), |buf| {
+                    udp_emit(buf, &IpAddress::Ipv4(src), &IpAddress::Ipv4(dst), src_port, dst_port, &reply)
+                }))
+            }
+            IpProtocol::Tcp => {
+                let tcp_packet = TcpPacket::new_checked(packet.payload()).ok()?;
+                let segment = self.respond_tcp(IpAddress::Ipv4(src), &tcp_packet)?;
+                Some(ipv4_frame(src, dst, IpProtocol::Tcp, 20 + segment.payload.len(), |buf| {
+                    segment.emit(buf, &IpAddress::Ipv4(src), &IpAddress::Ipv4(dst))
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    fn respond_ipv6(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        let caps = ChecksumCapabilities::default();
+        let packet = Ipv6Packet::new_checked(payload).ok()?;
+        let ip_repr = Ipv6Repr::parse(&packet).ok()?;
+        if ip_repr.next_header != IpProtocol::Icmpv6 {
+            return None;
+        }
+        let (src_addr, dst_addr) = (IpAddress::Ipv6(ip_repr.src_addr), IpAddress::Ipv6(ip_repr.dst_addr));
+        let icmp_packet = Icmpv6Packet::new_checked(packet.payload()).ok()?;
+        let (reply, src, dst, hop_limit) = match Icmpv6Repr::parse(&src_addr, &dst_addr, &icmp_packet, &caps).ok()? {
+            Icmpv6Repr::EchoRequest { ident, seq_no, data } => {
+                (Icmpv6Repr::EchoReply { ident, seq_no, data }, ip_repr.dst_addr, ip_repr.src_addr, 64)
+            }
+            Icmpv6Repr::Ndisc(NdiscRepr::RouterSolicit { .. }) => {
+                let advert = NdiscRepr::RouterAdvert {
+                    hop_limit: 64,
+                    flags: NdiscRouterFlags::empty(),
+                    router_lifetime: Duration::from_secs(1800),
+                    reachable_time: Duration::from_millis(0),
+                    retrans_time: Duration::from_millis(0),
+                    lladdr: Some(SIM_GATEWAY_MAC),
+                    mtu: None,
+                    prefix_info: Some(NdiscPrefixInformation {
+                        prefix_len: 64,
+                        flags: NdiscPrefixInfoFlags::ON_LINK | NdiscPrefixInfoFlags::ADDRCONF,
+                        valid_lifetime: Duration::from_secs(86400),
+                        preferred_lifetime: Duration::from_secs(14400),
+                        prefix: SIM_IPV6_PREFIX,
+                    }),
+                };
+                (Icmpv6Repr::Ndisc(advert), SIM_GATEWAY_IPV6, Ipv6Address::LINK_LOCAL_ALL_NODES, 255)
+            }
+            Icmpv6Repr::Ndisc(NdiscRepr::NeighborSolicit { target_addr, .. }) if target_addr == SIM_GATEWAY_IPV6 => {
+                let advert = NdiscRepr::NeighborAdvert {
+                    flags: NdiscNeighborFlags::ROUTER | NdiscNeighborFlags::SOLICITED | NdiscNeighborFlags::OVERRIDE,
+                    target_addr,
+                    lladdr: Some(SIM_GATEWAY_MAC),
+                };
+                (Icmpv6Repr::Ndisc(advert), SIM_GATEWAY_IPV6, ip_repr.src_addr, 255)
+            }
+            _ => return None,
+        };
+        let reply_repr = Ipv6Repr {
+            src_addr: src,
+            dst_addr: dst,
+            next_header: IpProtocol::Icmpv6,
+            payload_len: reply.buffer_len(),
+            hop_limit,
+        };
+        let dst_mac = if dst.is_multicast() {
+            let octets = dst.as_bytes();
+            EthernetAddress([0x33, 0x33, octets[12], octets[13], octets[14], octets[15]])
+        } else {
+            SIM_DEVICE_MAC
+        };
+        Some(ethernet_frame(dst_mac, EthernetProtocol::Ipv6, reply_repr.buffer_len() + reply.buffer_len(), |buf| {
+            let mut ip_packet = Ipv6Packet::new_unchecked(buf);
+            reply_repr.emit(&mut ip_packet);
+            reply.emit(
+                &IpAddress::Ipv6(src),
+                &IpAddress::Ipv6(dst),
+                &mut Icmpv6Packet::new_unchecked(ip_packet.payload_mut()),
+                &caps,
+            );
+        }))
+    }
+
+    /// Runs the gateway's end of a TCP connection from the device to `remote`. There is no retransmission,
+    /// as nothing is ever lost on the simulated link: a segment that isn't the next one expected just gets
+    /// the current acknowledgement back.
+    fn respond_tcp(&mut self, remote: IpAddress, packet: &TcpPacket<&[u8]>) -> Option<TcpSegment> {
+        let key = (remote, packet.dst_port(), packet.src_port());
+        let reply = |seq_number, ack_number| TcpSegment {
+            src_port: packet.dst_port(),
+            dst_port: packet.src_port(),
+            seq_number,
+            ack_number,
+            syn: false,
+            fin: false,
+            rst: false,
+            payload: Vec::new(),
+        };
+        if packet.rst() {
+            self.tcp.remove(&key);
+            return None;
+        }
+        let seq_len = packet.payload().len() + packet.syn() as usize + packet.fin() as usize;
+        if packet.syn() && !packet.ack() {
+            if packet.dst_port() != ECHO_PORT && packet.dst_port() != HTTP_PORT {
+                return Some(TcpSegment { rst: true, ..reply(TcpSeqNumber(0), packet.seq_number() + seq_len) });
+            }
+            let rcv_nxt = packet.seq_number() + 1;
+            self.tcp.insert(key, TcpPeer {
+                snd_nxt: TCP_ISN + 1,
+                rcv_nxt,
+                request: Vec::new(),
+                closed: false,
+                peer_closed: false,
+            });
+            return Some(TcpSegment { syn: true, ..reply(TCP_ISN, rcv_nxt) });
+        }
+        let peer = match self.tcp.get_mut(&key) {
+            Some(peer) => peer,
+            None => return Some(TcpSegment { rst: true, ..reply(TcpSeqNumber(0), packet.seq_number() + seq_len) }),
+        };
+        if packet.seq_number() != peer.rcv_nxt {
+            return Some(reply(peer.snd_nxt, peer.rcv_nxt));
+        }
+        if seq_len == 0 {
+            // a bare acknowledgement: once both ends have closed and our FIN is acknowledged, the connection is done with
+            if peer.closed && peer.peer_closed && packet.ack() && packet.ack_number() == peer.snd_nxt {
+                self.tcp.remove(&key);
+            }
+            return None;
+        }
+        peer.rcv_nxt += seq_len;
+        peer.peer_closed |= packet.fin();
+        let mut segment = reply(peer.snd_nxt, peer.rcv_nxt);
+        if peer.closed {
+            // all that's left is to acknowledge the device's FIN
+            if peer.peer_closed {
+                self.tcp.remove(&key);
+            }
+        } else {
+            if packet.dst_port() == ECHO_PORT {
+                segment.payload = packet.payload().to_vec();
+            } else {
+                peer.request.extend_from_slice(packet.payload());
+                if peer.request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    segment.payload = HTTP_RESPONSE.to_vec();
+                    segment.fin = true;
+                }
+            }
+            // the device is done sending, so we are too
+            segment.fin |= packet.fin();
+            peer.closed = segment.fin;
+            peer.snd_nxt += segment.payload.len() + segment.fin as usize;
+        }
+        Some(segment)
+    }
+
+    /// Answers a DNS query out of the hosts table. Only A records are known: other queries
+    /// for a known name get an empty answer, and queries for an unknown name get NXDOMAIN.
+    fn dns_reply(&self, query: &[u8]) -> Option<Vec<u8>> {
+        if query.len() < 12 || query[2] & 0x80 != 0 {
+            return None;
+        }
+        // walk the name labels of the (single) question
+        let mut labels = Vec::<String>::new();
+        let mut i = 12;
+        loop {
+            let len = *query.get(i)? as usize;
+            i += 1;
+            if len == 0 {
+                break;
+            }
+            labels.push(String::from_utf8_lossy(query.get(i..i + len)?).to_lowercase());
+            i += len;
+        }
+        let qtype = u16::from_be_bytes([*query.get(i)?, *query.get(i + 1)?]);
+        let question = &query[12..i + 4];
+        let name = labels.join(".");
+
+        let addr = self.hosts.get(&name);
+        let rcode = if addr.is_some() { 0 } else { 3 };
+        let answer = addr.filter(|_| qtype == 1);
+        let mut reply = Vec::new();
+        reply.extend_from_slice(&query[0..2]); // id
+        reply.push(0x80 | (query[2] & 0x01)); // QR, opcode QUERY, RD copied over
+        reply.push(0x80 | rcode); // RA
+        reply.extend_from_slice(&[0, 1, 0, answer.is_some() as u8, 0, 0, 0, 0]);
+        reply.extend_from_slice(question);
+        if let Some(addr) = answer {
+            reply.extend_from_slice(&[0xc0, 12]); // pointer to the name in the question
+            reply.extend_from_slice(&[0, 1, 0, 1]); // A, IN
+            reply.extend_from_slice(&DNS_TTL.to_be_bytes());
+            reply.extend_from_slice(&[0, 4]);
+            reply.extend_from_slice(addr.as_bytes());
+        }
+        log::debug!("sim DNS: {} (type {}) -> {:?}", name, qtype, answer);
+        Some(reply)
+    }
+}
+
+/// Answers an SNTP client request as a stratum 1 server, using the host's clock for both the
+/// receive and transmit timestamps.
+fn ntp_reply(request: &[u8]) -> Option<Vec<u8>> {
+    if request.len() < 48 || request[0] & 0x7 != 3 {
+        return None;
+    }
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).ok()?;
+    // NTP timestamps wrap around in 2036, which the truncation to 32 bits takes care of
+    let secs = (now.as_secs() + NTP_UNIX_OFFSET) as u32;
+    let frac = ((now.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    let mut timestamp = [0u8; 8];
+    timestamp[..4].copy_from_slice(&secs.to_be_bytes());
+    timestamp[4..].copy_from_slice(&(frac as u32).to_be_bytes());
+
+    let mut reply = vec![0u8; 48];
+    reply[0] = (request[0] & 0x38) | 4; // no leap warning, the client's version, server
+    reply[1] = 1; // stratum
+    reply[2] = request[2]; // poll interval
+    reply[3] = (-20i8) as u8; // precision, about a microsecond
+    reply[12..16].copy_from_slice(b"SIM\0"); // reference ID
+    reply[16..24].copy_from_slice(&timestamp); // reference timestamp
+    reply[24..32].copy_from_slice(&request[40..48]); // originate timestamp
+    reply[32..40].copy_from_slice(&timestamp); // receive timestamp
+    reply[40..48].copy_from_slice(&timestamp); // transmit timestamp
+    log::debug!("sim SNTP: {}.{:03}", now.as_secs(), now.subsec_millis());
+    Some(reply)
+}
+
+fn sent_by_device(frame: &[u8]) -> bool {
+    EthernetFrame::new_checked(frame).map(|f| f.src_addr() == SIM_DEVICE_MAC).unwrap_or(false)
+}
+
+/// builds a frame from the gateway, calling `fill` to write its `payload_len` bytes of payload
+fn ethernet_frame<F>(dst_addr: EthernetAddress, ethertype: EthernetProtocol, payload_len: usize, fill: F) -> Vec<u8>
+where
+    F: FnOnce(&mut [u8]),
+{
+    let eth_repr = EthernetRepr { src_addr: SIM_GATEWAY_MAC, dst_addr, ethertype };
+    let mut buf = vec![0u8; eth_repr.buffer_len() + payload_len];
+    let mut frame = EthernetFrame::new_unchecked(&mut buf[..]);
+    eth_repr.emit(&mut frame);
+    fill(frame.payload_mut());
+    buf
+}
+
+fn ipv4_frame<F>(src_addr: Ipv4Address, dst_addr: Ipv4Address, protocol: IpProtocol, payload_len: usize, fill: F) -> Vec<u8>
+where
+    F: FnOnce(&mut [u8]),
+{
+    let ip_repr = Ipv4Repr { src_addr, dst_addr, protocol, payload_len, hop_limit: 64 };
+    ethernet_frame(SIM_DEVICE_MAC, EthernetProtocol::Ipv4, ip_repr.buffer_len() + payload_len, |buf| {
+        let mut packet = Ipv4Packet::new_unchecked(buf);
+        ip_repr.emit(&mut packet, &ChecksumCapabilities::default());
+        fill(packet.payload_mut());
+    })
+}
+
+fn udp_emit(buf: &mut [u8], src: &IpAddress, dst: &IpAddress, src_port: u16, dst_port: u16, payload: &[u8]) {
+    let mut packet = UdpPacket::new_unchecked(buf);
+    packet.set_src_port(src_port);
+    packet.set_dst_port(dst_port);
+    packet.set_len((8 + payload.len()) as u16);
+    packet.payload_mut().copy_from_slice(payload);
+    packet.fill_checksum(src, dst);
+}
+
+/// A segment from the gateway, which always acknowledges what it has received
+struct TcpSegment {
+    src_port: u16,
+    dst_port: u16,
+    seq_number: TcpSeqNumber,
+    ack_number: TcpSeqNumber,
+    syn: bool,
+    fin: bool,
+    rst: bool,
+    payload: Vec<u8>,
+}
+impl TcpSegment {
+    fn emit(&self, buf: &mut [u8], src: &IpAddress, dst: &IpAddress) {
+        let mut packet = TcpPacket::new_unchecked(buf);
+        packet.set_src_port(self.src_port);
+        packet.set_dst_port(self.dst_port);
+        packet.set_seq_number(self.seq_number);
+        packet.set_ack_number(self.ack_number);
+        packet.set_header_len(20);
+        packet.clear_flags();
+        packet.set_syn(self.syn);
+        packet.set_fin(self.fin);
+        packet.set_rst(self.rst);
+        packet.set_psh(self.payload.len() > 0);
+        packet.set_ack(true);
+        packet.set_window_len(if self.rst { 0 } else { TCP_WINDOW });
+        packet.set_urgent_at(0);
+        packet.payload_mut().copy_from_slice(&self.payload);
+        packet.fill_checksum(src, dst);
+    }
+}
+
+/// Appends frames to a pcap file, with Ethernet framing
+struct PcapWriter {
+    file: File,
+    start: std::time::Instant,
+}
+impl PcapWriter {
+    fn new(path: &str) -> std::io::Result<PcapWriter> {
+        let mut file = File::create(path)?;
+        let mut header = Vec::new();
+        header.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes()); // magic
+        header.extend_from_slice(&2u16.to_le_bytes()); // major version
+        header.extend_from_slice(&4u16.to_le_bytes()); // minor version
+        header.extend_from_slice(&0i32.to_le_bytes()); // GMT offset
+        header.extend_from_slice(&0u32.to_le_bytes()); // timestamp accuracy
+        header.extend_from_slice(&65535u32.to_le_bytes()); // snaplen
+        header.extend_from_slice(&1u32.to_le_bytes()); // LINKTYPE_ETHERNET
+        file.write_all(&header)?;
+        Ok(PcapWriter { file, start: std::time::Instant::now() })
+    }
+    fn write(&mut self, frame: &[u8]) {
+        let elapsed = self.start.elapsed();
+        let mut record = Vec::new();
+        record.extend_from_slice(&(elapsed.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&elapsed.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(frame);
+        if let Err(e) = self.file.write_all(&record) {
+            log::warn!("couldn't write to capture file: {:?}", e);
+        }
+    }
+}
+
+/// Reads all the frames out of a pcap file with Ethernet framing, in either byte order.
+fn pcap_read(path: &str) -> std::io::Result<VecDeque<Vec<u8>>> {
+    use std::io::{Error, ErrorKind};
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    if data.len() < 24 {
+        return Err(Error::new(ErrorKind::InvalidData, "capture file is truncated"));
+    }
+    let magic: [u8; 4] = data[0..4].try_into().unwrap();
+    let read_u32: fn([u8; 4]) -> u32 = match u32::from_le_bytes(magic) {
+        0xa1b2_c3d4 | 0xa1b2_3c4d => u32::from_le_bytes,
+        0xd4c3_b2a1 | 0x4d3c_b2a1 => u32::from_be_bytes,
+        _ => return Err(Error::new(ErrorKind::InvalidData, "not a pcap file")),
+    };
+    if read_u32(data[20..24].try_into().unwrap()) != 1 {
+        return Err(Error::new(ErrorKind::InvalidData, "capture doesn't have Ethernet framing"));
+    }
+    let mut frames = VecDeque::new();
+    let mut offset = 24;
+    while offset + 16 <= data.len() {
+        let incl_len = read_u32(data[offset + 8..offset + 12].try_into().unwrap()) as usize;
+        let frame = data
+            .get(offset + 16..offset + 16 + incl_len)
+            .ok_or(Error::new(ErrorKind::InvalidData, "capture file is truncated"))?;
+        if frame.len() <= NET_MTU {
+            frames.push_back(frame.to_vec());
+        } else {
+            log::warn!("skipping {}-byte frame in capture, as it exceeds the MTU", frame.len());
+        }
+        offset += 16 + incl_len;
+    }
+    Ok(frames)
+}
@@ -92,6 +92,9 @@ pub(crate) enum Opcode {
     GetKeyNameAtIndex,
     DictCountInBasis,
     GetDictNameAtIndex,
+    /// bulk listing: returns a page of (optionally filtered) key or dictionary names in a single buffer
+    ListKeyPage,
+    ListDictPage,
 
     /// primary method for accessing the database
     KeyRequest,
@@ -104,8 +107,39 @@ pub(crate) enum Opcode {
     /// drops any connection state associated with a given key
     KeyDrop,
 
+    /// defragments the dictionaries in all open bases, returning emptied pages to the FastSpace pool
+    Compact,
+
+    /// periodic check of the basis retention policies
+    RetentionPoll,
+
+    /// subscribe to basis mount/unmount and dict/key change notifications
+    Subscribe,
+    /// cancels the subscription with the ID in arg1; blocking scalar
+    Unsubscribe,
+
+    /// passphrase-encrypted basis archives. The archive itself is streamed with ArchiveRead/ArchiveWrite,
+    /// and the server-side copy is released with ArchiveClose.
+    ArchiveExport,
+    ArchiveImport,
+    ArchiveRead,
+    ArchiveWrite,
+    ArchiveCommit,
+    ArchiveClose,
+
+    /// atomic multi-key transactions. The staged operations are streamed with TransactionWrite, and
+    /// applied with TransactionCommit; TransactionAbort releases the server-side copy without applying it.
+    TransactionBegin,
+    TransactionWrite,
+    TransactionCommit,
+    TransactionAbort,
+
+    /// consistency check of all the open bases, optionally repairing the problems found
+    Check,
+
     /// Menu opcodes
     MenuListBasis,
+    MenuCompact,
 
     /// Suspend/resume callback
     SuspendResume,
@@ -113,7 +147,85 @@ pub(crate) enum Opcode {
     Quit,
 }
 
+/// Opcodes for the callback server that a `Pddb` object runs on the client side.
+#[allow(dead_code)]
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug)]
+pub(crate) enum CbOp {
+    /// the data behind a key may have changed, or the key's basis was locked
+    Change,
+    Quit,
+    /// sent by the server to check that the client is still around; there is nothing to do
+    Ping,
+}
+
+/// Opcodes for the server that a `Pddb` object runs on the client side to receive the notifications for all of
+/// its subscriptions.
+#[allow(dead_code)]
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug)]
+pub(crate) enum SubscriptionCb {
+    /// a `PddbEvent` happened that matches the subscription
+    Event,
+    Drop,
+}
+/// Events that are reported to subscribers, in a `PddbEventReport`
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub enum PddbEvent {
+    /// a basis was mounted
+    BasisMount,
+    /// a basis was unmounted, either explicitly or due to its retention policy
+    BasisUnmount,
+    /// a watched dictionary was created or deleted
+    DictChange,
+    /// a watched key was written to, created or deleted
+    KeyChange,
+}
+/// The most subscriptions that a process can hold at once
+#[allow(dead_code)]
+pub(crate) const MAX_SUBSCRIPTIONS_PER_PROCESS: usize = 8;
+/// The most notification servers, across all processes, that the PDDB keeps a connection to. Each `Pddb` object
+/// with subscriptions has one.
+#[allow(dead_code)]
+pub(crate) const MAX_SUBSCRIBERS: usize = 8;
+/// A `PddbEvent`, as it is sent to the `CID`/opcode pair registered with `Pddb::subscribe()`. This is delivered
+/// as a memory message; decode it with `Buffer::from_memory_message()`.
+#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct PddbEventReport {
+    pub event: PddbEvent,
+    /// the subscription ID returned by `subscribe()`
+    pub subscription: u32,
+    /// the basis that was mounted or unmounted, or that the dict or key change was made in. `None` if the
+    /// change was made without naming a basis.
+    pub basis: Option<xous_ipc::String::<BASIS_NAME_LEN>>,
+    /// the dict that changed, or that holds the key that changed
+    pub dict: Option<xous_ipc::String::</*DICT_NAME_LEN*/ 111>>, // pending https://github.com/rust-lang/rust/issues/90195
+    /// the key that changed
+    pub key: Option<xous_ipc::String::</*KEY_NAME_LEN*/ 95>>, // pending https://github.com/rust-lang/rust/issues/90195
+}
+/// A request to subscribe to `PddbEvent`s. Basis events are always reported; dict and key events are
+/// reported if they match the dict/key filter. An unspecified dict or key matches everything.
+#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct PddbSubscription {
+    /// the server that the event notifications are sent to; all the subscriptions of a `Pddb` object share one
+    pub sid: [u32; 4],
+    /// the ID that the server assigned to the subscription, which it tags the notifications with
+    pub id: u32,
+    pub dict_specified: bool,
+    pub dict: xous_ipc::String::</*DICT_NAME_LEN*/ 111>, // pending https://github.com/rust-lang/rust/issues/90195
+    pub key_specified: bool,
+    pub key: xous_ipc::String::</*KEY_NAME_LEN*/ 95>, // pending https://github.com/rust-lang/rust/issues/90195
+    /// `NoErr` once the subscription is in place; `NoFreeSpace` if the process already holds
+    /// `MAX_SUBSCRIPTIONS_PER_PROCESS` subscriptions, or the server would be one more than `MAX_SUBSCRIBERS`
+    pub code: PddbRequestCode,
+}
+
 pub type ApiToken = [u32; 3];
+/// The most data the server will hold for archives and transactions being staged, across all clients
+#[allow(dead_code)]
+pub(crate) const MAX_STAGING_LEN: usize = 4 * 1024 * 1024;
+/// The most archives and transactions that a process can be staging at once
+#[allow(dead_code)]
+pub(crate) const MAX_STAGED_PER_PROCESS: usize = 2;
 #[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
 pub struct PddbBasisList {
     /// the first 63 that fit in the list -- generally we anticipate not more than a few being open at a time, so this should be enough.
@@ -140,17 +252,21 @@ pub enum PddbRequestCode {
 pub enum BasisRetentionPolicy {
     Persist,
     ClearAfterSleeps(u32),
-    //TimeOutSecs(u32),
+    /// Lock the basis after it has not been accessed for the given number of seconds
+    TimeOutSecs(u32),
 }
 impl BasisRetentionPolicy {
     pub fn derive_init_state(&self) -> u32 {
         match self {
             BasisRetentionPolicy::Persist => 0,
             BasisRetentionPolicy::ClearAfterSleeps(sleeps) => *sleeps,
-            //BasisRetentionPolicy::TimeOutSecs(secs) => *secs,
+            BasisRetentionPolicy::TimeOutSecs(secs) => *secs,
         }
     }
 }
+/// How often the retention policy timers are checked. This sets the resolution of `TimeOutSecs`.
+#[allow(dead_code)]
+pub(crate) const RETENTION_POLL_MS: usize = 1000;
 #[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
 pub struct PddbBasisRequest {
     pub name: xous_ipc::String::<BASIS_NAME_LEN>,
@@ -168,6 +284,100 @@ pub struct PddbDictRequest {
     pub code: PddbRequestCode,
 }
 
+/// A structure for exporting a basis to, or importing it from, a passphrase-encrypted archive
+#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct PddbArchiveRequest {
+    pub basis: xous_ipc::String::<BASIS_NAME_LEN>,
+    pub passphrase: xous_ipc::String::<PASSWORD_LEN>,
+    /// callback server of the requesting `Pddb`, so the server can tell when the client has gone away
+    pub cb_sid: [u32; 4],
+    /// handle to the server-side copy of the archive
+    pub token: ApiToken,
+    /// total length of the archive in bytes
+    pub len: u64,
+    /// number of keys imported
+    pub count: u32,
+    pub code: PddbRequestCode,
+}
+
+/// A structure for staging and committing a `PddbTransaction`
+#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct PddbTransactionRequest {
+    pub basis_specified: bool,
+    pub basis: xous_ipc::String::<BASIS_NAME_LEN>,
+    /// callback server of the requesting `Pddb`, so the server can tell when the client has gone away
+    pub cb_sid: [u32; 4],
+    /// handle to the server-side copy of the staged operations
+    pub token: ApiToken,
+    /// total length of the encoded operations in bytes
+    pub len: u64,
+    /// number of operations
+    pub count: u32,
+    pub code: PddbRequestCode,
+}
+/// Operations that can be staged in a `PddbTransaction`. The operations are encoded back to back, each as:
+/// - the `PddbTransactionOp`: u8
+/// - dictionary name, key name: u8 length + utf-8 bytes, each
+/// - for `Write` only: data length (u64, little-endian) + data bytes
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PddbTransactionOp {
+    /// replaces the contents of a key, creating the key and its dictionary as needed
+    Write = 0,
+    /// removes a key
+    Delete = 1,
+}
+
+/// Space for names in a `PddbListRequest`. Sized so the whole request fits in a single page of memory.
+pub(crate) const LIST_PAGE_LEN: usize = 3584;
+/// A structure for listing a page of key or dictionary names. Names are returned in sorted order, so a
+/// listing can be walked one page at a time by advancing `start` by `count` until `total` is reached.
+#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct PddbListRequest {
+    pub basis_specified: bool,
+    pub basis: xous_ipc::String::<BASIS_NAME_LEN>,
+    /// dictionary to list the keys of; ignored when listing dictionaries
+    pub dict: xous_ipc::String::</*DICT_NAME_LEN*/ 111>, // pending https://github.com/rust-lang/rust/issues/90195
+    /// prefix or glob that names must match. Matches everything if empty.
+    pub filter: xous_ipc::String::</*DICT_NAME_LEN*/ 111>, // pending https://github.com/rust-lang/rust/issues/90195
+    /// index of the first name to return, out of all the names that match the filter
+    pub start: u32,
+    /// total number of names that match the filter
+    pub total: u32,
+    /// number of names returned in `data`
+    pub count: u32,
+    /// the names, packed back to back as a u8 length + utf-8 bytes
+    pub data: [u8; LIST_PAGE_LEN],
+    pub code: PddbRequestCode,
+}
+
+/// A structure for requesting a consistency check, and reporting the problems found
+#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct PddbCheckReport {
+    /// set by the caller to repair the problems found
+    pub repair: bool,
+    /// number of bases checked
+    pub bases: u32,
+    /// number of mapped pages checked
+    pub pages: u32,
+    /// mapped pages that fail to decrypt
+    pub bad_pages: u32,
+    /// page table entries on disk that disagree with the cache
+    pub stale_ptes: u32,
+    /// mapped pages that aren't referenced by any dictionary or key
+    pub orphaned_pages: u32,
+    /// keys whose data is missing or unreadable
+    pub dangling_keys: u32,
+    /// pages that are in the free space pool, but also in use
+    pub fastspace_conflicts: u32,
+    pub code: PddbRequestCode,
+}
+impl PddbCheckReport {
+    /// total number of problems found
+    pub fn problems(&self) -> u32 {
+        self.bad_pages + self.stale_ptes + self.orphaned_pages + self.dangling_keys + self.fastspace_conflicts
+    }
+}
+
 /// A structure for requesting a token to access a particular key/value pair
 #[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
 pub struct PddbKeyRequest {
@@ -180,6 +390,8 @@ pub struct PddbKeyRequest {
     pub create_key: bool,
     pub alloc_hint: Option<u64>, // this is a usize but for IPC we must have defined memory sizes, so we pick the big option.
     pub cb_sid: [u32; 4],
+    /// for delete requests: overwrite the deleted data on disk immediately
+    pub paranoid: bool,
     pub result: PddbRequestCode,
 }
 
@@ -12,6 +12,12 @@ mod types;
 pub use types::*;
 mod bcrypt;
 pub use bcrypt::*;
+mod archive;
+pub(crate) use archive::*;
+mod transaction;
+pub(crate) use transaction::*;
+mod fsck;
+pub(crate) use fsck::*;
 
 // local to the backend
 mod murmur3;
@@ -0,0 +1,263 @@
+use crate::*;
+use aes_gcm_siv::{Aes256GcmSiv, Nonce, Key};
+use aes_gcm_siv::aead::{Aead, NewAead, Payload};
+use core::convert::{TryFrom, TryInto};
+use core::mem::size_of;
+use std::io::{Result, Error, ErrorKind};
+
+/// Portable basis archives
+///
+/// An archive is a snapshot of the dictionaries and keys of a single basis, encrypted under a
+/// user-supplied passphrase instead of the device root keys, so that it can be restored on another device.
+///
+/// The archive structure is as follows (all integers are little-endian):
+/// - `ARCHIVE_MAGIC` - 8 bytes
+/// - version - u32
+/// - bcrypt cost - u32
+/// - bcrypt salt - 16 bytes, freshly generated for every archive
+/// - AES-GCM-SIV nonce - 12 bytes
+/// - ciphertext of the payload, with the 16-byte tag appended
+///
+/// The header (everything up to the ciphertext) is the AAD of the ciphertext. The payload is:
+/// - the name of the basis the archive was made from: u8 length + utf-8 bytes
+/// - number of dictionaries: u32
+///   - dictionary name: u8 length + utf-8 bytes
+///   - number of keys: u32
+///     - key name: u8 length + utf-8 bytes
+///     - reserved space: u64
+///     - data length: u64 + data bytes
+///
+/// The archive version must be bumped whenever this layout changes; imports of versions
+/// that are not known to this code are refused.
+pub(crate) const ARCHIVE_MAGIC: [u8; 8] = *b"PDDBARC\0";
+pub(crate) const ARCHIVE_VERSION: u32 = 1;
+const SALT_LEN: usize = 16;
+/// Archives are made with `BCRYPT_COST`. The cost in an archive header is bounded, so a crafted archive
+/// can't tie up the server deriving its key.
+const MAX_ARCHIVE_COST: u32 = 12;
+const HEADER_LEN: usize = ARCHIVE_MAGIC.len() + size_of::<u32>() + size_of::<u32>() + SALT_LEN + 12;
+
+/// Serializes every valid key in `basis_name` into an archive encrypted with `passphrase`.
+pub(crate) fn basis_export(hw: &mut PddbOs, basis_cache: &mut BasisCache, basis_name: &str, passphrase: &str) -> Result<Vec<u8>> {
+    if !basis_cache.basis_list().iter().any(|b| b == basis_name) {
+        return Err(Error::new(ErrorKind::NotFound, "Requested basis not found, or PDDB not mounted."));
+    }
+    let mut payload = Vec::<u8>::new();
+    record_push_str(&mut payload, basis_name);
+    // sort the names so that the same basis always produces the same payload
+    let mut dicts: Vec<String> = basis_cache.dict_list(hw, Some(basis_name)).into_iter().collect();
+    dicts.sort();
+    payload.extend_from_slice(&(dicts.len() as u32).to_le_bytes());
+    for dict in dicts.iter() {
+        record_push_str(&mut payload, dict);
+        let mut keys: Vec<String> = basis_cache.key_list(hw, dict, Some(basis_name), None)?.into_iter().collect();
+        keys.sort();
+        payload.extend_from_slice(&(keys.len() as u32).to_le_bytes());
+        for key in keys.iter() {
+            let attr = basis_cache.key_attributes(hw, dict, key, Some(basis_name))?;
+            let mut data = vec![0u8; attr.len];
+            if attr.len > 0 {
+                let readlen = basis_cache.key_read(hw, dict, key, &mut data, None, Some(basis_name))?;
+                if readlen != attr.len {
+                    log::error!("short read exporting {}:{} ({}/{})", dict, key, readlen, attr.len);
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "key data could not be read in full"));
+                }
+            }
+            record_push_str(&mut payload, key);
+            payload.extend_from_slice(&(attr.reserved as u64).to_le_bytes());
+            payload.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            payload.extend_from_slice(&data);
+            archive_erase(&mut data);
+        }
+    }
+
+    let mut header = Vec::<u8>::with_capacity(HEADER_LEN);
+    header.extend_from_slice(&ARCHIVE_MAGIC);
+    header.extend_from_slice(&ARCHIVE_VERSION.to_le_bytes());
+    header.extend_from_slice(&BCRYPT_COST.to_le_bytes());
+    let mut salt = [0u8; SALT_LEN];
+    hw.trng_slice(&mut salt);
+    header.extend_from_slice(&salt);
+    let mut nonce = [0u8; 12];
+    hw.trng_slice(&mut nonce);
+    header.extend_from_slice(&nonce);
+
+    let mut key = archive_derive_key(BCRYPT_COST, &salt, passphrase);
+    let cipher = Aes256GcmSiv::new(Key::from_slice(&key));
+    archive_erase(&mut key);
+    let ciphertext = cipher.encrypt(
+        Nonce::from_slice(&nonce),
+        Payload {
+            aad: &header,
+            msg: &payload,
+        }
+    ).or(Err(Error::new(ErrorKind::Other, "couldn't encrypt archive")))?;
+    archive_erase(&mut payload);
+
+    let mut archive = header;
+    archive.extend_from_slice(&ciphertext);
+    Ok(archive)
+}
+
+/// Decrypts `archive` with `passphrase` and writes its contents into `basis_name`, which must already
+/// be mounted. Dictionaries are created as needed, and keys that already exist are overwritten.
+/// Returns the number of keys imported, and the names of the dictionaries they were imported into.
+///
+/// The import is applied as a single transaction: if it fails, or power is lost part way through,
+/// the basis is left as it was.
+pub(crate) fn basis_import(hw: &mut PddbOs, basis_cache: &mut BasisCache, basis_name: &str, passphrase: &str, archive: &[u8])
+-> Result<(usize, Vec<String>)> {
+    if !basis_cache.basis_list().iter().any(|b| b == basis_name) {
+        return Err(Error::new(ErrorKind::NotFound, "Requested basis not found, or PDDB not mounted."));
+    }
+    if archive.len() < HEADER_LEN || archive[..ARCHIVE_MAGIC.len()] != ARCHIVE_MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "not a PDDB archive"));
+    }
+    let header = &archive[..HEADER_LEN];
+    let mut pos = ARCHIVE_MAGIC.len();
+    let version = u32::from_le_bytes(header[pos..pos + 4].try_into().unwrap());
+    pos += 4;
+    if version != ARCHIVE_VERSION {
+        log::error!("archive version {} is not supported (expected {})", version, ARCHIVE_VERSION);
+        return Err(Error::new(ErrorKind::InvalidData, "unsupported archive version"));
+    }
+    let cost = u32::from_le_bytes(header[pos..pos + 4].try_into().unwrap());
+    pos += 4;
+    if cost < BCRYPT_COST || cost > MAX_ARCHIVE_COST {
+        return Err(Error::new(ErrorKind::InvalidData, "invalid bcrypt cost in archive header"));
+    }
+    let salt = &header[pos..pos + SALT_LEN];
+    pos += SALT_LEN;
+    let nonce = &header[pos..HEADER_LEN];
+
+    let mut key = archive_derive_key(cost, salt, passphrase);
+    let cipher = Aes256GcmSiv::new(Key::from_slice(&key));
+    archive_erase(&mut key);
+    let mut payload = cipher.decrypt(
+        Nonce::from_slice(nonce),
+        Payload {
+            aad: header,
+            msg: &archive[HEADER_LEN..],
+        }
+    ).or(Err(Error::new(ErrorKind::PermissionDenied, "wrong passphrase, or the archive is corrupted")))?;
+
+    // the whole payload is decoded before anything is written, so a malformed archive changes nothing
+    let result = import_decode(&payload).and_then(|ops| {
+        transaction_commit(hw, basis_cache, basis_name, &ops)?;
+        let count = ops.iter().filter(|op| matches!(op, TxOp::Write{..})).count();
+        let dicts = ops.iter().filter_map(|op| match op {
+            TxOp::AddDict{dict} => Some(String::from(*dict)),
+            _ => None,
+        }).collect();
+        Ok((count, dicts))
+    });
+    archive_erase(&mut payload);
+    result
+}
+
+/// Turns an archive payload into the operations that import it.
+fn import_decode(payload: &[u8]) -> Result<Vec<TxOp>> {
+    let mut rd = RecordReader::new(payload);
+    let source = rd.str()?;
+    log::info!("importing archive of basis '{}'", source);
+    let mut ops = Vec::<TxOp>::new();
+    let dict_count = rd.u32()?;
+    for _ in 0..dict_count {
+        let dict = rd.str()?;
+        if dict.len() == 0 || dict.len() > DICT_NAME_LEN - 1 || dict == TX_DICT {
+            return Err(Error::new(ErrorKind::InvalidData, "archive contains an invalid dictionary name"));
+        }
+        ops.push(TxOp::AddDict{dict});
+        let key_count = rd.u32()?;
+        for _ in 0..key_count {
+            let key = rd.str()?;
+            if key.len() == 0 || key.len() > KEY_NAME_LEN - 1 {
+                return Err(Error::new(ErrorKind::InvalidData, "archive contains an invalid key name"));
+            }
+            let reserved = rd.usize()?;
+            let len = rd.usize()?;
+            ops.push(TxOp::Write{dict, key, data: rd.bytes(len)?, reserved: Some(reserved)});
+        }
+    }
+    if !rd.is_empty() {
+        log::warn!("{} trailing bytes in archive payload ignored", rd.remaining());
+    }
+    Ok(ops)
+}
+
+/// Derives the archive key from a passphrase: bcrypt, expanded to 256 bits with Sha512Trunc256.
+/// This mirrors `basis_derive_key()`, except the salt is random and stored in the archive, as there
+/// is no device-specific salt base to draw from on the importing device.
+fn archive_derive_key(cost: u32, salt: &[u8], passphrase: &str) -> [u8; AES_KEYSIZE] {
+    use sha2::{FallbackStrategy, Sha512Trunc256};
+    use digest::Digest;
+    use backend::bcrypt::*;
+
+    let mut hashed_password: [u8; 24] = [0; 24];
+    bcrypt(cost, salt, passphrase, &mut hashed_password); // note: this internally makes a copy of the passphrase, and destroys it
+    let mut expander = Sha512Trunc256::new_with_strategy(FallbackStrategy::SoftwareOnly);
+    expander.update(hashed_password);
+    archive_erase(&mut hashed_password);
+    let final_key = expander.finalize();
+    let mut key = [0u8; AES_KEYSIZE];
+    for (&src, dst) in final_key.iter().zip(key.iter_mut()) {
+        *dst = src;
+    }
+    key
+}
+
+/// wipes plaintext and archive copies in a way that shouldn't be optimized out or re-ordered
+pub(crate) fn archive_erase(data: &mut [u8]) {
+    let ptr = data.as_mut_ptr();
+    for i in 0..data.len() {
+        unsafe{ptr.add(i).write_volatile(core::mem::zeroed());}
+    }
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+}
+
+/// Appends a name to an archive payload or transaction record, as a u8 length + utf-8 bytes
+pub(crate) fn record_push_str(record: &mut Vec<u8>, s: &str) {
+    // names are bounded by DICT_NAME_LEN/KEY_NAME_LEN/BASIS_NAME_LEN, all of which fit in a u8
+    record.push(s.len() as u8);
+    record.extend_from_slice(s.as_bytes());
+}
+
+/// Reads back the fields of an archive payload or transaction record. Records may come from clients, so
+/// every read is bounds checked, and a record that ends early gives an `InvalidData` error.
+pub(crate) struct RecordReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+impl<'a> RecordReader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        RecordReader { data, pos: 0 }
+    }
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.pos.checked_add(len).map_or(true, |end| end > self.data.len()) {
+            return Err(Error::new(ErrorKind::InvalidData, "record is truncated"));
+        }
+        let slice = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+    pub(crate) fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+    /// A u64 length, which has to fit in a `usize`
+    pub(crate) fn usize(&mut self) -> Result<usize> {
+        usize::try_from(self.u64()?).or(Err(Error::new(ErrorKind::InvalidData, "record contains an out of range length")))
+    }
+    pub(crate) fn str(&mut self) -> Result<&'a str> {
+        let len = self.bytes(1)?[0] as usize;
+        std::str::from_utf8(self.bytes(len)?).or(Err(Error::new(ErrorKind::InvalidData, "record contains a malformed name")))
+    }
+    pub(crate) fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+    pub(crate) fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+}
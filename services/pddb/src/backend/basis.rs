@@ -255,11 +255,40 @@ impl BasisCache {
             log::error!("Can't select basis: PDDB is not mounted");
             return None
         }
-        if let Some(n) = basis_name {
+        let selected = if let Some(n) = basis_name {
             self.cache.iter().position(|bc| bc.name == n)
         } else {
             Some(self.cache.len() - 1)
+        };
+        selected
+    }
+    /// Selects a basis on behalf of a client reading or writing its contents. This counts as activity for the
+    /// purpose of a time-out policy; the PDDB's own housekeeping (syncs, compaction, flushes) doesn't.
+    fn select_basis_for_access(&mut self, basis_name: Option<&str>) -> Option<usize> {
+        let selected = self.select_basis(basis_name);
+        if let Some(index) = selected {
+            self.cache[index].touch();
         }
+        selected
+    }
+    /// Selects the basis that serves `key` on behalf of a client, and counts it as accessed. In union mode
+    /// (`basis_name` is None) that's the most recently opened basis that has the key, which isn't necessarily
+    /// the top one; if none of them has it, the top basis is selected, as for any other access.
+    fn select_basis_for_key(&mut self, hw: &mut PddbOs, dict: &str, key: &str, basis_name: Option<&str>) -> Option<usize> {
+        if basis_name.is_none() {
+            for index in (0..self.cache.len()).rev() {
+                let basis = &mut self.cache[index];
+                if !basis.ensure_dict_in_cache(hw, dict) {
+                    continue;
+                }
+                let dict_entry = basis.dicts.get_mut(dict).expect("Entry was assured, but not there!");
+                if dict_entry.ensure_key_entry(hw, &mut basis.v2p_map, &basis.cipher, key) {
+                    basis.touch();
+                    return Some(index);
+                }
+            }
+        }
+        self.select_basis_for_access(basis_name)
     }
     pub(crate) fn basis_count(&self) -> usize {self.cache.len()}
 
@@ -271,7 +300,7 @@ impl BasisCache {
         if !hw.ensure_fast_space_alloc(2, &self.cache) {
             return Err(Error::new(ErrorKind::OutOfMemory, "No free space to allocate dict"));
         }
-        if let Some(basis_index) = self.select_basis(basis_name) {
+        if let Some(basis_index) = self.select_basis_for_access(basis_name) {
             let basis = &mut self.cache[basis_index];
             basis.age = basis.age.saturating_add(1);
 
@@ -314,6 +343,9 @@ impl BasisCache {
                 small_pool: Vec::<KeySmallPool>::new(),
                 small_pool_free: BinaryHeap::<KeySmallPoolOrd>::new(),
                 aad: my_aad,
+                large_cache_pages: 0,
+                large_cache_stamp: 0,
+                journal_floor: 0,
             };
             log::debug!("adding dictionary {}", name);
             basis.dicts.insert(String::from(name), dict_cache);
@@ -332,24 +364,26 @@ impl BasisCache {
 
     /// Returns a list of all the known dictionaries, across all the basis. A HashSet is returned
     /// because you can have the same-named dictionary in multiple basis, and what we're asking for
-    /// is the union of all the dictionary names, without duplicates.
+    /// is the union of all the dictionary names, without duplicates. The transaction log is
+    /// internal book-keeping, and is left out, so it doesn't show up in listings or archives.
     pub(crate) fn dict_list(&mut self, hw: &mut PddbOs, basis_name: Option<&str>) -> HashSet::<String> {
         let mut dict_set = HashSet::<String>::new();
         if basis_name.is_some() {
-            if let Some(basis_index) = self.select_basis(basis_name) {
+            if let Some(basis_index) = self.select_basis_for_access(basis_name) {
                 let basis = &mut self.cache[basis_index];
                 basis.populate_caches(hw);
                 for (key, dcache) in basis.dicts.iter() {
-                    if dcache.flags.valid() {
+                    if dcache.flags.valid() && key != TX_DICT {
                         dict_set.insert(String::from(key));
                     }
                 }
             }
         } else {
             for basis in self.cache.iter_mut() {
+                basis.touch();
                 basis.populate_caches(hw);
                 for (key, dcache) in basis.dicts.iter() {
-                    if dcache.flags.valid() {
+                    if dcache.flags.valid() && key != TX_DICT {
                         dict_set.insert(String::from(key));
                     }
                 }
@@ -357,23 +391,26 @@ impl BasisCache {
         }
         dict_set
     }
-    pub(crate) fn key_list(&mut self, hw: &mut PddbOs, dict: &str, basis_name: Option<&str>) -> Result<HashSet::<String>> {
+    /// Returns the union of the keys in `dict` across the open bases, or just in `basis_name` if it's specified.
+    /// If `filter` is specified, only the keys matching it are returned; see `name_matches()`.
+    pub(crate) fn key_list(&mut self, hw: &mut PddbOs, dict: &str, basis_name: Option<&str>, filter: Option<&str>) -> Result<HashSet::<String>> {
         let mut merge_list = HashSet::<String>::new();
         let mut found_dict = false;
         if basis_name.is_some() {
-            if let Some(basis_index) = self.select_basis(basis_name) {
+            if let Some(basis_index) = self.select_basis_for_access(basis_name) {
                 let basis = &mut self.cache[basis_index];
                 basis.populate_caches(hw);
                 if let Some(dcache) = basis.dicts.get_mut(dict) {
-                    dcache.key_list(hw, &basis.v2p_map, &basis.cipher, &mut merge_list);
+                    dcache.key_list(hw, &basis.v2p_map, &basis.cipher, &mut merge_list, filter);
                     found_dict = true;
                 }
             }
         } else {
             for basis in self.cache.iter_mut() {
+                basis.touch();
                 basis.populate_caches(hw);
                 if let Some(dcache) = basis.dicts.get_mut(dict) {
-                    dcache.key_list(hw, &basis.v2p_map, &basis.cipher, &mut merge_list);
+                    dcache.key_list(hw, &basis.v2p_map, &basis.cipher, &mut merge_list, filter);
                     found_dict = true;
                 }
             }
@@ -392,7 +429,7 @@ impl BasisCache {
     pub(crate) fn dict_remove(&mut self,
         hw: &mut PddbOs, dict: &str, basis_name: Option<&str>, paranoid: bool
     ) -> Result<()> {
-        if let Some(basis_index) = self.select_basis(basis_name) {
+        if let Some(basis_index) = self.select_basis_for_access(basis_name) {
             log::debug!("deleting dict {}", dict);
             let basis = &mut self.cache[basis_index];
 
@@ -410,7 +447,7 @@ impl BasisCache {
     pub(crate) fn key_read(&mut self, hw: &mut PddbOs, dict: &str, key: &str, data: &mut [u8],
         offset: Option<usize>, basis_name:Option<&str>
     ) -> Result<usize> {
-        if let Some(basis_index) = self.select_basis(basis_name) {
+        if let Some(basis_index) = self.select_basis_for_key(hw, dict, key, basis_name) {
             let basis = &mut self.cache[basis_index];
             if !basis.ensure_dict_in_cache(hw, dict) {
                 return Err(Error::new(ErrorKind::NotFound, "dictionary not found"));
@@ -439,74 +476,9 @@ impl BasisCache {
                             panic!("Key allocated to small area but its cache data was not of the small type");
                         }
                     } else {
-                        if offset.unwrap_or(0) as u64 > kcache.len {
-                            return Err(Error::new(ErrorKind::UnexpectedEof, "offest requested is beyond the key length"));
-                        }
-                        if data.len() == 0 { // mostly because i don't want to have to think about this case in the later logic.
-                            return Ok(0)
-                        }
-                        // large pool fetch
-                        let mut cur_offset = offset.unwrap_or(0) as u64;
-                        let mut blocks_read = 0;
-                        loop {
-                            let start_vpage_addr = ((kcache.start + cur_offset) / VPAGE_SIZE as u64) * VPAGE_SIZE as u64;
-                            // handle the case of terminating the last block read nicely:
-                            // we can either end because we hit the end of the key data (so the data readback buffer is too large)
-                            let key_endstop = if (kcache.len - cur_offset) < VPAGE_SIZE as u64 {
-                                (kcache.len - cur_offset) as usize + size_of::<JournalType>()
-                            } else {
-                                VPAGE_SIZE + size_of::<JournalType>()
-                            };
-                            // or we can hit the end because we ran out of datareadback buffer
-                            let data_endstop = if (data.len() as u64 - cur_offset) < VPAGE_SIZE as u64 {
-                                (data.len() as u64 - cur_offset) as usize + size_of::<JournalType>()
-                            } else {
-                                VPAGE_SIZE + size_of::<JournalType>()
-                            };
-                            // of the two possible end criteria, pick the nearest one
-                            let endstop = if key_endstop < data_endstop {
-                                key_endstop
-                            } else {
-                                data_endstop
-                            };
-                            log::debug!("reading offset {}->{}/{}:{}", key, cur_offset, endstop, kcache.len);
-                            let mut read_offset = (cur_offset % VPAGE_SIZE as u64) as usize;
-                            if let Some(pp) = basis.v2p_map.get(&VirtAddr::new(start_vpage_addr).unwrap()) {
-                                assert!(pp.valid(), "v2p returned an invalid page");
-                                let pt_data = hw.data_decrypt_page(&basis.cipher, &basis.aad, pp).expect("Decryption auth error");
-                                if blocks_read != 0 {
-                                    assert!(read_offset == 0, "algorithm error in handling offset data");
-                                }
-                                for (&src, dst) in
-                                pt_data[
-                                    size_of::<JournalType>() // always this fixed offset per block
-                                    + read_offset // this should be 0 after the first block
-                                    ..endstop
-                                ].iter().zip(data[cur_offset as usize..].iter_mut()) {
-                                    *dst = src;
-                                    cur_offset += 1;
-                                    read_offset += 1;
-                                }
-                                blocks_read += 1;
-                            } else {
-                                log::warn!("Not enough bytes available to read for key {}:{} ({}/{})", dict, key, cur_offset, data.len());
-                                return Ok(cur_offset as usize)
-                            }
-                            // cur_offset won't hit (start + len) if we're hitting an endstop, so the loop won't exit.
-                            // if we hit an endstop, we should exit the read loop.
-                            if endstop != VPAGE_SIZE + size_of::<JournalType>() {
-                                break;
-                            }
-                            // break if we've hit or exceeded our endstop
-                            if read_offset >= endstop {
-                                break;
-                            }
-                            // also exit if we're "just nice", e.g. the size of the data happened to be exactly a multiple of the length of our block size
-                            if cur_offset == kcache.len {
-                                break;
-                            }
-                        }
-                        return Ok(cur_offset as usize)
+                        // large pool fetch; this goes through the large pool cache, so that data which has been
+                        // written but not yet committed to disk is also visible.
+                        return dict_entry.key_read_large(hw, &basis.v2p_map, &basis.cipher, key, data, offset.unwrap_or(0))
                     }
 
                 } else {
@@ -523,11 +495,19 @@ impl BasisCache {
     pub(crate) fn key_remove(&mut self,
         hw: &mut PddbOs, dict: &str, key: &str, basis_name: Option<&str>, paranoid: bool
     ) -> Result<()> {
-        if let Some(basis_index) = self.select_basis(basis_name) {
-            let basis = &mut self.cache[basis_index];
-            if !basis.ensure_dict_in_cache(hw, dict) {
+        if let Some(basis_index) = self.select_basis_for_access(basis_name) {
+            if !self.cache[basis_index].ensure_dict_in_cache(hw, dict) {
                 return Err(Error::new(ErrorKind::NotFound, "dictionary not found"));
             }
+            if paranoid {
+                // the erase re-writes the small pool right away, so make sure there is space to do that
+                if let Some(dict_entry) = self.cache[basis_index].dicts.get(dict) {
+                    if !hw.ensure_fast_space_alloc(dict_entry.alloc_estimate_small(), &self.cache) {
+                        return Err(Error::new(ErrorKind::OutOfMemory, "No free space to erase key"));
+                    }
+                }
+            }
+            let basis = &mut self.cache[basis_index];
             if let Some(dict_entry) = basis.dicts.get_mut(dict) {
                 basis.age = basis.age.saturating_add(1);
                 basis.clean = false;
@@ -535,10 +515,10 @@ impl BasisCache {
                     if !paranoid {
                         dict_entry.key_remove(hw, &mut basis.v2p_map, &basis.cipher, key, false);
                     } else {
-                        // this implementation is still in progress
-                        dict_entry.key_erase(key);
-
-                        // encrypt and write the dict entry to disk
+                        if !dict_entry.key_erase(hw, &mut basis.v2p_map, &basis.cipher, key) {
+                            return Err(Error::new(ErrorKind::OutOfMemory, "Ran out of memory syncing small pool"));
+                        }
+                        // encrypt and write the dict entry to disk; this also blanks out the key's descriptor
                         basis.dict_sync(hw, dict)?;
                         // sync the root basis structure as well, while we're at it...
                         basis.basis_sync(hw);
@@ -577,7 +557,7 @@ impl BasisCache {
         } else {
             (reserved / VPAGE_SIZE) + 1
         };
-        if let Some(basis_index) = self.select_basis(basis_name) {
+        if let Some(basis_index) = self.select_basis_for_access(basis_name) {
             let basis = &mut self.cache[basis_index];
             if !basis.ensure_dict_in_cache(hw, dict) {
                 pages_needed += 1;
@@ -646,7 +626,7 @@ impl BasisCache {
             return Err(Error::new(ErrorKind::OutOfMemory, "No free space to allocate dict"));
         }
         // now actually do the update
-        if let Some(basis_index) = self.select_basis(basis_name) {
+        if let Some(basis_index) = self.select_basis_for_access(basis_name) {
             let dict_found = (&mut self.cache[basis_index]).ensure_dict_in_cache(hw, dict);
             if !dict_found { // now that we're clear of the deep search, mutate the basis if we are sure it's not there
                 self.dict_add(hw, dict, basis_name).expect("couldn't add dictionary");
@@ -655,7 +635,8 @@ impl BasisCache {
             // pre-flight & allocatefree space requirements
             if let Some(dict_entry) = self.cache[basis_index].dicts.get(dict) {
                 hw.ensure_fast_space_alloc(dict_entry.alloc_estimate_small(), &self.cache);
-                // large pools don't have caching implemented, so we don't have to check for free space for them
+                // large pool pages are allocated when the key's space is reserved, so the large pool cache never needs
+                // to allocate more space when it is committed.
             }
             // refetch the basis here to avoid the re-borrow problem, now that all the potential dict cache mutations are done
             let basis = &mut self.cache[basis_index];
@@ -679,9 +660,9 @@ impl BasisCache {
                 if !dict_entry.sync_small_pool(hw, &mut basis.v2p_map, &basis.cipher) {
                     return Err(Error::new(ErrorKind::OutOfMemory, "Ran out of memory syncing small pool"));
                 }
-                // we don't have large pool caches yet, but this is a placeholder to remember to do "something" at this point,
-                // once we do have them.
-                dict_entry.sync_large_pool();
+                // the descriptor written below covers the new large pool data, so that data has to be on disk
+                // first. The cache still spares partial page updates from having to read the page back in.
+                dict_entry.sync_large_pool(hw, &basis.v2p_map, &basis.cipher);
 
                 // encrypt and write the dict entry to disk
                 basis.dict_sync(hw, dict)?;
@@ -705,6 +686,7 @@ impl BasisCache {
                 if !basis.ensure_dict_in_cache(hw, dict) {
                     continue;
                 } else {
+                    basis.touch();
                     let dict_entry = basis.dicts.get_mut(dict).expect("Entry was assured, but not there!");
                     if dict_entry.ensure_key_entry(hw, &mut basis.v2p_map, &basis.cipher, key) {
                         let kcache = match dict_entry.keys.get_mut(key) {
@@ -727,7 +709,7 @@ impl BasisCache {
             }
             Err(Error::new(ErrorKind::NotFound, "key not found"))
         } else {
-            if let Some(basis_index) = self.select_basis(basis_name) {
+            if let Some(basis_index) = self.select_basis_for_access(basis_name) {
                 let basis = &mut self.cache[basis_index];
                 if !basis.ensure_dict_in_cache(hw, dict) {
                     return Err(Error::new(ErrorKind::NotFound, "dictionary not found"));
@@ -760,13 +742,14 @@ impl BasisCache {
                 if !basis.ensure_dict_in_cache(hw, dict) {
                     continue;
                 } else {
+                    basis.touch();
                     let dict_entry = basis.dicts.get_mut(dict).expect("Entry was assured, but not there!");
                     return Ok(dict_entry.to_dict_attributes(dict, &basis.name));
                 }
             }
             return Err(Error::new(ErrorKind::NotFound, "dictionary not found"));
         } else {
-            if let Some(basis_index) = self.select_basis(basis_name) {
+            if let Some(basis_index) = self.select_basis_for_access(basis_name) {
                 let basis = &mut self.cache[basis_index];
                 if !basis.ensure_dict_in_cache(hw, dict) {
                     return Err(Error::new(ErrorKind::NotFound, "dictionary not found"));
@@ -938,7 +921,106 @@ impl BasisCache {
         Ok(())
     }
 
-    pub(crate) fn suspend(&mut self, hw: &mut PddbOs) {
+    /// Runs through the dictionary listing in a basis and compacts them. If `basis_name` is None,
+    /// every open basis is compacted. Returns the total number of pages that were returned to the FastSpace pool.
+    pub(crate) fn compact(&mut self, hw: &mut PddbOs, basis_name: Option<&str>) -> Result<usize> {
+        let targets: Vec<usize> = if basis_name.is_some() {
+            if let Some(basis_index) = self.select_basis(basis_name) {
+                vec![basis_index]
+            } else {
+                return Err(Error::new(ErrorKind::NotFound, "Requested basis not found, or PDDB not mounted."));
+            }
+        } else {
+            (0..self.cache.len()).collect()
+        };
+        let mut freed = 0;
+        for basis_index in targets {
+            // copy the dictionary names out, because we need to re-borrow the cache to allocate space for each compaction
+            let mut dict_names = Vec::<String>::new();
+            {
+                let basis = &mut self.cache[basis_index];
+                basis.populate_caches(hw);
+                for (name, dcache) in basis.dicts.iter() {
+                    if dcache.flags.valid() {
+                        dict_names.push(name.to_string());
+                    }
+                }
+            }
+            for dict in dict_names {
+                self.compact_reserve(hw, basis_index, &dict)?;
+                freed += self.cache[basis_index].dict_compact(hw, &dict)?;
+            }
+        }
+        Ok(freed)
+    }
+    /// Reserves enough FastSpace to write out a compacted copy of `dict`.
+    fn compact_reserve(&mut self, hw: &mut PddbOs, basis_index: usize, dict: &str) -> Result<()> {
+        let pages_needed = {
+            let basis = &mut self.cache[basis_index];
+            let dcache = basis.dicts.get_mut(dict).ok_or(Error::new(ErrorKind::NotFound, "Dictionary not found"))?;
+            dcache.fill(hw, &basis.v2p_map, &basis.cipher);
+            dcache.alloc_estimate_compact()
+        };
+        if !hw.ensure_fast_space_alloc(pages_needed, &self.cache) {
+            return Err(Error::new(ErrorKind::OutOfMemory, "No free space to compact dictionary"));
+        }
+        Ok(())
+    }
+    /// Does the first half of a compaction of `dict`: the compacted copy is committed, but the old pages are
+    /// left mapped and are returned for `BasisCacheEntry::dict_compact_retire()`. This is used to check that a
+    /// compaction interrupted by a power loss comes back intact.
+    #[allow(dead_code)]
+    pub(crate) fn dict_compact_copy(&mut self, hw: &mut PddbOs, dict: &str, basis_name: Option<&str>) -> Result<Vec<PhysPage>> {
+        let basis_index = self.select_basis(basis_name)
+            .ok_or(Error::new(ErrorKind::NotFound, "Requested basis not found, or PDDB not mounted."))?;
+        self.cache[basis_index].populate_caches(hw);
+        self.compact_reserve(hw, basis_index, dict)?;
+        let (retired, _new_pages) = self.cache[basis_index].dict_compact_copy(hw, dict)?;
+        Ok(retired)
+    }
+
+    /// Checks the consistency of every open basis, and of the FastSpace pool against them. If `repair` is
+    /// set, the problems found are fixed. See `fsck.rs` for the details of what is checked.
+    pub(crate) fn check(&mut self, hw: &mut PddbOs, repair: bool) -> Result<CheckReport> {
+        let mut report = CheckReport::default();
+        // the disk should agree with the cache after a sync, so anything that disagrees is a real problem
+        self.sync(hw, None)?;
+        let mut in_use = HashSet::<u32>::new();
+        for basis in self.cache.iter() {
+            for pp in basis.v2p_map.values() {
+                in_use.insert(pp.page_number());
+            }
+        }
+        // prune the FastSpace pool first, so the repairs below can't be handed a page that is in use
+        report.fastspace_conflicts = hw.fast_space_check(&self.cache, repair) as u32;
+        for basis_index in 0..self.cache.len() {
+            if repair {
+                let mut pages_needed = 0;
+                for dict in self.cache[basis_index].dicts.values() {
+                    pages_needed += dict.alloc_estimate_compact();
+                }
+                if !hw.ensure_fast_space_alloc(pages_needed, &self.cache) {
+                    return Err(Error::new(ErrorKind::OutOfMemory, "No free space to repair basis"));
+                }
+            }
+            self.cache[basis_index].check(hw, &in_use, repair, &mut report)?;
+        }
+        if report.problems() == 0 {
+            log::info!("PDDB check: {} bases, {} pages, no problems found", report.bases, report.pages);
+        } else {
+            log::warn!("PDDB check: {:?}{}", report, if repair {", repaired"} else {""});
+        }
+        Ok(report)
+    }
+
+    /// Direct access to the cache entry of a basis, so the tests can inject faults.
+    #[cfg(not(any(target_os = "none", target_os = "xous")))]
+    pub(crate) fn basis_entry_mut(&mut self, basis_name: &str) -> Option<&mut BasisCacheEntry> {
+        self.cache.iter_mut().find(|b| b.name == basis_name)
+    }
+
+    /// Syncs all the bases, and applies the sleep-based retention policies. Returns the names of the bases that were unmounted.
+    pub(crate) fn suspend(&mut self, hw: &mut PddbOs) -> Vec<String> {
         self.sync(hw, None).expect("couldn't sync on suspend");
         let mut lock_list = Vec::<String>::new();
         for basis in self.cache.iter_mut() {
@@ -950,18 +1032,39 @@ impl BasisCache {
                         lock_list.push(basis.name.clone());
                     }
                 }
-                /*
-                BasisRetentionPolicy::TimeOutSecs(secs) => {
-                    if secs < basis.policy_state {
-                        lock_list.push(basis.name.clone());
-                    }
-                }*/
+                // time-outs are handled by `retention_poll()`
+                BasisRetentionPolicy::TimeOutSecs(_) => (),
+            }
+        }
+        for basis in lock_list.iter() {
+            log::info!("unmounting basis on sleep: {}", basis);
+            self.basis_unmount(hw, basis).ok();
+        }
+        lock_list
+    }
+
+    /// Whether any mounted basis has a `TimeOutSecs` retention policy, and so needs `retention_poll()` to run
+    pub(crate) fn has_time_out_policy(&self) -> bool {
+        self.cache.iter().any(|basis| matches!(basis.policy, BasisRetentionPolicy::TimeOutSecs(_)))
+    }
+
+    /// Advances the inactivity timers of the bases with a `TimeOutSecs` retention policy by `elapsed_secs`, and
+    /// unmounts any basis that has timed out. Returns the names of the bases that were unmounted.
+    pub(crate) fn retention_poll(&mut self, hw: &mut PddbOs, elapsed_secs: u32) -> Vec<String> {
+        let mut lock_list = Vec::<String>::new();
+        for basis in self.cache.iter_mut() {
+            if let BasisRetentionPolicy::TimeOutSecs(_) = basis.policy {
+                basis.policy_state = basis.policy_state.saturating_sub(elapsed_secs);
+                if basis.policy_state == 0 {
+                    lock_list.push(basis.name.clone());
+                }
             }
         }
-        for basis in lock_list {
-            log::info!("unmounting basis on sleep: {}", &basis);
-            self.basis_unmount(hw, &basis).ok();
+        for basis in lock_list.iter() {
+            log::info!("unmounting basis on inactivity time-out: {}", basis);
+            self.basis_unmount(hw, basis).ok();
         }
+        lock_list
     }
 }
 
@@ -1003,6 +1106,13 @@ pub(crate) struct BasisCacheEntry {
     pub policy_state: u32,
 }
 impl BasisCacheEntry {
+    /// Notes that the basis was accessed. This re-arms the inactivity timer of a `TimeOutSecs` retention policy.
+    pub(crate) fn touch(&mut self) {
+        if let BasisRetentionPolicy::TimeOutSecs(_) = self.policy {
+            self.policy_state = self.policy.derive_init_state();
+        }
+    }
+
     /// given a pointer to the hardware, name of the basis, and its cryptographic key, try to derive
     /// the basis. If `lazy` is true, it stops with the minimal amount of effort to respond to a query.
     /// If it `lazy` is false, it will populate the dictionary cache and key cache entries, as well as
@@ -1147,12 +1257,12 @@ impl BasisCacheEntry {
         }
     }
 
-    /// If `paranoid` is true, it recurses through each key and replaces its data with random junk.
-    /// Otherwise, it does a "shallow" delete and just removes the directory entry, which is much
-    /// more performant. Note that the intended "fast" way to secure-erase data is to store sensitive
-    /// data in its own Basis, and then remove the Basis itself. This is much faster than picking
-    /// through compounded data and re-writing partias sectors, and because of this, initially,
-    /// the `paranoid` erase is `unimplemented`.
+    /// Removes a dictionary and all of its keys. The key descriptor pages, the small pool pages and the
+    /// large pool pages of the dictionary are all overwritten with random junk as they are de-allocated,
+    /// so unlike `key_remove`, a dictionary delete is always "paranoid": `paranoid` only controls
+    /// whether the keys are additionally erased one at a time before the pages are wiped, which is much
+    /// slower and adds little. Note that the intended "fast" way to secure-erase data is to store sensitive
+    /// data in its own Basis, and then remove the Basis itself.
     pub(crate) fn dict_delete(&mut self, hw: &mut PddbOs, name: &str, paranoid: bool) -> Result<()> {
         if self.ensure_dict_in_cache(hw, name) {
             let dcache = self.dicts.get_mut(name).expect("entry was ensured, but somehow missing");
@@ -1335,6 +1445,16 @@ impl BasisCacheEntry {
         self.last_sync = Some(hw.timestamp_now());
         if let Some(dict) = self.dicts.get_mut(&String::from(name)) {
             let dict_offset = VirtAddr::new(dict.index.get() as u64 * DICT_VSIZE).unwrap();
+            // Deleted keys have their descriptors blanked out on disk, but if the descriptor's page was never
+            // allocated, the key never made it to disk, and there is nothing to blank out.
+            for key in dict.keys.values_mut() {
+                if !key.clean && !key.flags.valid() {
+                    let dk_vpage = VirtAddr::new(dict_offset.get() + (key.descriptor_vpage_num() as u64 * VPAGE_SIZE as u64)).unwrap();
+                    if !self.v2p_map.contains_key(&dk_vpage) {
+                        key.clean = true;
+                    }
+                }
+            }
             if !dict.clean {
                 let dict_name = DictName::try_from_str(name).or(Err(Error::new(ErrorKind::InvalidInput, "dictionary name invalid: invalid utf-8 or length")))?;
                 let dict_disk = Dictionary {
@@ -1396,7 +1516,19 @@ impl BasisCacheEntry {
                                 key.start, key.clean, key.flags, key.descriptor_index,
                             );
                         }*/
-                        if !key.clean && key.flags.valid() {
+                        if !key.clean && !key.flags.valid() {
+                            if key.descriptor_vaddr(dict_offset) >= cur_vpage &&
+                            key.descriptor_vaddr(dict_offset) < next_vpage {
+                                // the key was deleted: blank out its descriptor, unless its index has already been
+                                // re-used by another key (valid keys always take precedence over the blank entry)
+                                log::debug!("blanking out deleted key {}", key_name);
+                                let slot = key.descriptor_index.get() as usize % DK_PER_VPAGE;
+                                if dk_vpage.elements[slot].is_none() {
+                                    dk_vpage.elements[slot] = Some(DictKeyEntry::default());
+                                }
+                                key.clean = true;
+                            }
+                        } else if !key.clean && key.flags.valid() {
                             if key.descriptor_vaddr(dict_offset) >= cur_vpage &&
                             key.descriptor_vaddr(dict_offset) < next_vpage {
                                 log::debug!("merging in key {}", key_name);
@@ -1430,7 +1562,7 @@ impl BasisCacheEntry {
                         log::trace!("existing data invalid, creating a new page");
                         // the existing data was invalid (this happens e.g. on the first time a dict is created). Just overwrite the whole page.
                         let mut d = vec![0u8; VPAGE_SIZE + size_of::<JournalType>()];
-                        for (&src, dst) in (hw.trng_u32() % JOURNAL_RAND_RANGE).to_le_bytes().iter().zip(d[..size_of::<JournalType>()].iter_mut()) {
+                        for (&src, dst) in (hw.trng_u32() % JOURNAL_RAND_RANGE).max(dict.journal_floor).to_le_bytes().iter().zip(d[..size_of::<JournalType>()].iter_mut()) {
                             *dst = src;
                         }
                         d
@@ -1449,7 +1581,7 @@ impl BasisCacheEntry {
                     // exit the loop
                     let mut found_next = false;
                     for key in dict.keys.values() {
-                        if !key.clean && key.flags.valid() {
+                        if !key.clean {
                             found_next = true;
                             // note: we don't care *which* vpage we do next -- so we just break after finding the first one
                             vpage_num = key.descriptor_vpage_num();
@@ -1471,12 +1603,74 @@ impl BasisCacheEntry {
         }
     }
 
-    /// Runs through the dictionary listing in a basis and compacts them. Call when the
-    /// the dictionary space becomes sufficiently fragmented that accesses are becoming
-    /// inefficient.
-    #[allow(dead_code)]
-    pub(crate) fn dict_compact(&self, _basis_name: Option<&str>) -> Result<()> {
-        unimplemented!();
+    /// Compacts a dictionary. Call when the dictionary space becomes sufficiently fragmented that
+    /// accesses are becoming inefficient. Key descriptors are renumbered so they are contiguous, and
+    /// the small pool is re-packed, so that pages emptied out by deletions can be given back to the FastSpace pool.
+    ///
+    /// This is done in two steps, `dict_compact_copy()` followed by `dict_compact_retire()`; see those
+    /// routines for what happens if power is lost in between. The caller must ensure there is enough FastSpace
+    /// for a complete copy of the dictionary by passing `DictCacheEntry::alloc_estimate_compact()` to
+    /// `ensure_fast_space_alloc()` prior to calling this.
+    ///
+    /// Returns the number of pages that were freed by the compaction.
+    pub(crate) fn dict_compact(&mut self, hw: &mut PddbOs, name: &str) -> Result<usize> {
+        let (retired, new_pages) = self.dict_compact_copy(hw, name)?;
+        let freed = retired.len().saturating_sub(new_pages);
+        self.dict_compact_retire(hw, retired);
+        log::info!("compacted dict {}: {} pages freed", name, freed);
+        Ok(freed)
+    }
+    /// Writes a compacted copy of the dictionary into freshly allocated pages and commits it to the page table.
+    /// The copy re-uses the virtual addresses of the old one, so once this returns, the page table maps each of
+    /// those addresses to both an old and a new page. `pt_scan_key()` resolves such duplicates in favor of the
+    /// higher journal number, so the new pages are written with journal numbers strictly above any of the old
+    /// ones: if power is lost before the old pages are retired, the compacted copy is the one that is mounted.
+    ///
+    /// Returns the physical pages of the old copy, and the number of pages in the new copy.
+    pub(crate) fn dict_compact_copy(&mut self, hw: &mut PddbOs, name: &str) -> Result<(Vec<PhysPage>, usize)> {
+        if !self.ensure_dict_in_cache(hw, name) {
+            return Err(Error::new(ErrorKind::NotFound, "Dictionary not found"));
+        }
+        let dict = self.dicts.get_mut(name).expect("entry was ensured, but somehow missing");
+        // ensure all the keys are in RAM
+        dict.fill(hw, &self.v2p_map, &self.cipher);
+        let old_extents = dict.compact()?;
+
+        // pull the old pages out of the v2p map, so the syncs below allocate new pages for the compacted data
+        let mut retired = Vec::<PhysPage>::new();
+        let mut journal_floor: JournalType = 0;
+        for vaddr in old_extents {
+            if let Some(pp) = self.v2p_map.remove(&vaddr) {
+                if let Some(data) = hw.data_decrypt_page(&self.cipher, &self.aad, &pp) {
+                    journal_floor = journal_floor.max(JournalType::from_le_bytes(data[..size_of::<JournalType>()].try_into().unwrap()));
+                }
+                retired.push(pp);
+            }
+        }
+        dict.journal_floor = journal_floor;
+        if !dict.sync_small_pool(hw, &mut self.v2p_map, &self.cipher) {
+            dict.journal_floor = 0;
+            log::error!("ran out of FastSpace while compacting {}; old pages not retired", name);
+            return Err(Error::new(ErrorKind::OutOfMemory, "No free space to compact dictionary"));
+        }
+        let new_pages = 1 + dict.last_disk_key_index as usize / DK_PER_VPAGE + dict.small_pool.len();
+        let synced = self.dict_sync(hw, name);
+        self.dicts.get_mut(name).expect("dictionary disappeared during compaction").journal_floor = 0;
+        synced?;
+        self.pt_sync(hw);
+        Ok((retired, new_pages))
+    }
+    /// Retires the pages of the old copy of a dictionary, as returned by `dict_compact_copy()`. Retired pages are
+    /// overwritten with random data and their PTEs are erased, so they are indistinguishable from any other free page.
+    pub(crate) fn dict_compact_retire(&mut self, hw: &mut PddbOs, retired: Vec<PhysPage>) {
+        for mut pp in retired {
+            assert!(pp.valid(), "v2p returned an invalid page");
+            let mut random = [0u8; PAGE_SIZE];
+            hw.trng_slice(&mut random);
+            hw.patch_data(&random, pp.page_number() * PAGE_SIZE as u32);
+            hw.pt_erase(pp.page_number());
+            hw.fast_space_free(&mut pp);
+        }
     }
 
     /// Syncs *only* the basis header to disk.
@@ -1544,6 +1738,12 @@ impl BasisCacheEntry {
                 dictnames.push(dict.to_string());
             }
         }
+        // commit any large pool data that is only in cache
+        for entry in self.dicts.values_mut() {
+            if entry.flags.valid() {
+                entry.sync_large_pool(hw, &self.v2p_map, &self.cipher);
+            }
+        }
         for dict in dictnames {
             match self.dict_sync(hw, &dict) {
                 Ok(_) => {},
@@ -10,6 +10,13 @@ use std::io::{Result, Error, ErrorKind};
 use bitfield::bitfield;
 use std::cmp::{Ordering, Reverse};
 
+/// Maximum number of large pool pages that a dictionary will hold in its cache. Large keys are streamed
+/// through this cache in VPAGE-sized chunks, so that multi-megabyte keys can be read and written without
+/// having to hold the entire key in RAM. Dirty pages are committed before the key's descriptor is written,
+/// so the length on disk never covers data that isn't there. Once the limit is hit, the least recently
+/// used page is committed to disk (if dirty) and dropped from the cache.
+pub(crate) const LARGE_CACHE_MAX_PAGES: usize = 16;
+
 bitfield! {
     #[derive(Copy, Clone, PartialEq, Eq)]
     pub struct DictFlags(u32);
@@ -46,8 +53,8 @@ pub(crate) struct DictCacheEntry {
     /// goes completely empty, the entry should still exist but indicate that it's got space. Thus if a key was found allocated
     /// to the Nth index position, but the previous N-1 positions are empty, the only way we could have gotten there was if we
     /// had allocated lots of small data, filled upo the pool to the Nth position, and then deleted all of that prior data.
-    /// This situation could create pathologies in the memory usage overhead of the small_pool, which until we have a "defrag"
-    /// operation for the small pool, we may just have to live with.
+    /// This situation could create pathologies in the memory usage overhead of the small_pool; `compact()` re-packs the pool
+    /// to get rid of them.
     pub(crate) small_pool: Vec<KeySmallPool>,
     /// free space of each small pool element. It's a collection of free space along with the Vec index of the small_pool.
     /// We don't keep the KeySmallPool itself in the small_pool_free directly because it's presumed to be more common
@@ -58,6 +65,13 @@ pub(crate) struct DictCacheEntry {
     pub(crate) small_pool_free: BinaryHeap<KeySmallPoolOrd>,
     /// copy of our AAD, for convenience
     pub(crate) aad: Vec::<u8>,
+    /// total number of large pool pages currently held in the key caches of this dictionary
+    pub(crate) large_cache_pages: usize,
+    /// monotonic counter used to stamp large pool pages as they are accessed, for LRU eviction
+    pub(crate) large_cache_stamp: u64,
+    /// lower bound for the journal number of freshly allocated descriptor and small pool pages. Only set
+    /// during compaction, so the new copy of a page always wins over the old copy it replaces.
+    pub(crate) journal_floor: JournalType,
 }
 impl DictCacheEntry {
     pub fn new(dict: Dictionary, index: usize, aad: &Vec<u8>) -> DictCacheEntry {
@@ -79,6 +93,9 @@ impl DictCacheEntry {
             small_pool: Vec::<KeySmallPool>::new(),
             small_pool_free: BinaryHeap::<KeySmallPoolOrd>::new(),
             aad: my_aad,
+            large_cache_pages: 0,
+            large_cache_stamp: 0,
+            journal_floor: 0,
         }
     }
     /// Populates cache entries, reporting the maximum extent of large alloc data seen so far.
@@ -188,13 +205,15 @@ impl DictCacheEntry {
     }
     /// merges the list of keys in this dict cache entry into a merge_list.
     /// The `merge_list` is used because keys are presented as a union across all open basis.
-    pub(crate) fn key_list(&mut self, hw: &mut PddbOs, v2p_map: &HashMap::<VirtAddr, PhysPage>, cipher: &Aes256GcmSiv, merge_list: &mut HashSet<String>) {
+    /// If `filter` is specified, only the keys that match it are merged; see `name_matches()`.
+    pub(crate) fn key_list(&mut self, hw: &mut PddbOs, v2p_map: &HashMap::<VirtAddr, PhysPage>, cipher: &Aes256GcmSiv,
+        merge_list: &mut HashSet<String>, filter: Option<&str>) {
         // ensure that the key cache is filled
         if self.keys.len() < self.key_count as usize {
             self.fill(hw, v2p_map, cipher);
         }
         for (key, kcache) in self.keys.iter() {
-            if kcache.flags.valid() {
+            if kcache.flags.valid() && filter.map_or(true, |f| name_matches(f, key)) {
                 merge_list.insert(key.to_string());
             }
         }
@@ -432,111 +451,51 @@ impl DictCacheEntry {
                 self.small_pool[pool_index].clean = false;
                 // note: there is no need to update small_pool_free because the reserved size did not change.
             } else {
-                // it's a large key
-                if let Some(_kcd) = &kcache.data {
-                    unimplemented!("caching is not yet implemented for large data sets");
-                } else {
-                    kcache.age = kcache.age.saturating_add(1);
-                    kcache.clean = false;
-                    /* // this was for debugging a patching bug -- OK to remove
-                    if data.len() == 4 {
-                        use std::convert::TryInto;
-                        log::info!("patching checksum: {:x} at offset {}", u32::from_le_bytes(data.try_into().unwrap()), offset);
-                    }*/
-                    // 1. handle unaligned start offsets
-                    let mut written: usize = 0;
-                    if ((kcache.start + offset as u64 + written as u64) % VPAGE_SIZE as u64) != 0 {
-                        let start_vpage_addr = ((kcache.start + offset as u64) / VPAGE_SIZE as u64) * VPAGE_SIZE as u64;
-                        let pp = v2p_map.get(&VirtAddr::new(start_vpage_addr).unwrap()).expect("large key data allocation missing");
-                        assert!(pp.valid(), "v2p returned an invalid page");
-                        let mut pt_data = match hw.data_decrypt_page(&cipher, &self.aad, pp) {
-                            Some(data) => data,
-                            None => {
-                                // this case is triggered by the following circumstance:
-                                //  - we reserved data that includes this current page
-                                //  - up until now, we've only written data into the previous page (so this page is not initialized -- it's garbage)
-                                //  - we just issued an update that causes the data to touch this page for the first time
-                                // in response to this, we allocate a fresh page of 0's.
-                                log::debug!("Reserved and uninitialized page encountered updating large block: {} {:x}..{}->{}; update @{}..{}",
-                                    name, kcache.start, kcache.len, kcache.reserved, offset, data.len());
-                                let mut d = vec![0u8; VPAGE_SIZE + size_of::<JournalType>()];
-                                for (&src, dst) in (hw.trng_u32() % JOURNAL_RAND_RANGE).to_le_bytes().iter().zip(d[..size_of::<JournalType>()].iter_mut()) {
-                                    *dst = src;
-                                }
-                                d
-                            }
-                        };
-                        if offset > 0 {
-                            log::trace!("patching offset {}, total length {}, data length {}", offset % VPAGE_SIZE, kcache.len, data.len());
-                        }
-                        for (&src, dst) in data[written..].iter().zip(pt_data[size_of::<JournalType>() + (offset % VPAGE_SIZE)..].iter_mut()) {
-                            *dst = src;
-                            written += 1;
-                        }
-                        if written < data.len() {
-                            assert!((kcache.start + offset as u64 + written as u64) % VPAGE_SIZE as u64 == 0, "alignment algorithm failed");
-                        }
-                        hw.data_encrypt_and_patch_page(cipher, &self.aad, &mut pt_data, &pp);
+                // it's a large key. Data is written into the large pool cache, and committed to disk when
+                // the page is evicted, or when `sync_large_pool()` is called, which the basis does before
+                // it writes out the key's descriptor.
+                kcache.age = kcache.age.saturating_add(1);
+                kcache.clean = false;
+                let key_start = kcache.start;
+                let mut written: usize = 0;
+                while written < data.len() {
+                    let abs_addr = key_start + (offset + written) as u64;
+                    let vpage_addr = (abs_addr / VPAGE_SIZE as u64) * VPAGE_SIZE as u64;
+                    let page_offset = (abs_addr % VPAGE_SIZE as u64) as usize;
+                    // whole pages that are going to be overwritten don't need to be decrypted first
+                    let whole_page = page_offset == 0 && (data.len() - written) >= VPAGE_SIZE;
+                    let page = self.large_page_get(hw, v2p_map, cipher, name, vpage_addr, !whole_page);
+                    for (&src, dst) in data[written..].iter().zip(page.data[size_of::<JournalType>() + page_offset..].iter_mut()) {
+                        *dst = src;
+                        written += 1;
                     }
-                    // 2. do the rest
-                    while written < data.len() {
-                        let vpage_addr = ((kcache.start + written as u64 + offset as u64) / VPAGE_SIZE as u64) * VPAGE_SIZE as u64;
-                        let pp = v2p_map.get(&VirtAddr::new(vpage_addr).unwrap()).expect("large key data allocation missing");
-                        assert!(pp.valid(), "v2p returned an invalid page");
-                        if data.len() - written >= VPAGE_SIZE {
-                            // overwrite whole pages without decryption
-                            let mut block = [0u8; VPAGE_SIZE + size_of::<JournalType>()];
-                            for (&src, dst) in (hw.trng_u32() % JOURNAL_RAND_RANGE).to_le_bytes().iter().zip(block[..size_of::<JournalType>()].iter_mut()) {
-                                *dst = src;
-                            }
-                            for (&src, dst) in data[written..].iter().zip(block[size_of::<JournalType>()..].iter_mut()) {
-                                *dst = src;
-                                written += 1;
-                            }
-                            hw.data_encrypt_and_patch_page(cipher, &self.aad, &mut block, pp);
-                        } else {
-                            // handle partial trailing pages
-                            if let Some(pt_data) = hw.data_decrypt_page(&cipher, &self.aad, pp).as_mut() {
-                                for (&src, dst) in data[written..].iter().zip(pt_data[size_of::<JournalType>()..].iter_mut()) {
-                                    *dst = src;
-                                    written += 1;
-                                }
-                                hw.data_encrypt_and_patch_page(cipher, &self.aad, pt_data, pp);
-                            } else {
-                                // page didn't exist, initialize it with 0's and merge the tail end.
-                                let mut pt_data = [0u8; VPAGE_SIZE + size_of::<JournalType>()];
-                                for (&src, dst) in (hw.trng_u32() % JOURNAL_RAND_RANGE).to_le_bytes().iter().zip(pt_data[..size_of::<JournalType>()].iter_mut()) {
-                                    *dst = src;
-                                }
-                                for (&src, dst) in data[written..].iter().zip(pt_data[size_of::<JournalType>()..].iter_mut()) {
-                                    *dst = src;
-                                    written += 1;
+                    page.clean = false;
+                }
+                log::trace!("data written: {}, data requested to write: {}", written, data.len());
+                // check if we grew the length; extend the length by exactly enough if so.
+                let kcache = self.keys.get_mut(name).expect("Entry was assured, but then not there!");
+                if kcache.len < (data.len() + offset) as u64 {
+                    kcache.len = (data.len() + offset) as u64;
+                } else if truncate {
+                    kcache.len = (data.len() + offset) as u64;
+                    // discard all whole pages after the new end of the key, and reset the reserved field to the smaller size.
+                    let new_reserved = if kcache.len == 0 { 0 } else { PageAlignedVa::from(kcache.len).as_u64() };
+                    if new_reserved < kcache.reserved {
+                        let mut dropped = 0;
+                        for vpage in (kcache.start + new_reserved..kcache.start + kcache.reserved).step_by(VPAGE_SIZE) {
+                            if let Some(KeyCacheData::Large(cache_data)) = kcache.data.as_mut() {
+                                if cache_data.pages.remove(&vpage).is_some() {
+                                    dropped += 1;
                                 }
-                                hw.data_encrypt_and_patch_page(cipher, &self.aad, &mut pt_data, pp);
                             }
-                        }
-                    }
-                    log::trace!("data written: {}, data requested to write: {}", written, data.len());
-                    assert!(written == data.len(), "algorithm problem -- didn't write all the data we thought we would");
-                    // 3. truncate or extend
-                    // check if we grew the length; extend the length by exactly enough if so.
-                    if kcache.len < (data.len() + offset) as u64 {
-                        kcache.len = (data.len() + offset) as u64;
-                    } else if truncate {
-                        // discard all whole pages after written+offset, and reset the reserved field to the smaller size.
-                        let vpage_end_offset = PageAlignedVa::from((written + offset) as u64);
-                        if (vpage_end_offset.as_u64() - kcache.start) > kcache.reserved {
-                            for vpage in (vpage_end_offset.as_u64()..kcache.start + kcache.reserved).step_by(VPAGE_SIZE) {
-                                if let Some(pp) = v2p_map.get_mut(&VirtAddr::new(vpage).unwrap()) {
-                                    assert!(pp.valid(), "v2p returned an invalid page");
-                                    hw.fast_space_free(pp);
-                                    assert!(pp.valid() == false, "pp is still marked as valid!");
-                                }
+                            if let Some(pp) = v2p_map.get_mut(&VirtAddr::new(vpage).unwrap()) {
+                                assert!(pp.valid(), "v2p returned an invalid page");
+                                hw.fast_space_free(pp);
+                                assert!(pp.valid() == false, "pp is still marked as valid!");
                             }
-                            kcache.reserved = vpage_end_offset.as_u64() - kcache.start;
-                            kcache.clean = false;
-                            kcache.len = (data.len() + offset) as u64;
                         }
+                        kcache.reserved = new_reserved;
+                        self.large_cache_pages -= dropped;
                     }
                 }
             }
@@ -655,6 +614,133 @@ impl DictCacheEntry {
         }
         Ok(large_alloc_ptr)
     }
+    /// Returns the cached copy of the large pool page at `vpage_addr` belonging to key `name`, pulling it
+    /// in from disk if it isn't already in cache. Pages that were reserved but never written come back as a
+    /// page of 0's. If `fill` is false, the disk is not consulted on a cache miss and a blank page is returned;
+    /// use this when the caller is going to overwrite the entire page anyways, as it saves a decryption.
+    ///
+    /// The caller is responsible for marking the page as dirty if it modifies the data.
+    fn large_page_get(&mut self, hw: &mut PddbOs, v2p_map: &HashMap::<VirtAddr, PhysPage>, cipher: &Aes256GcmSiv,
+        name: &str, vpage_addr: u64, fill: bool) -> &mut KeyLargePage {
+        self.large_cache_stamp += 1;
+        let stamp = self.large_cache_stamp;
+        let cached = if let Some(KeyCacheData::Large(cache_data)) =
+            self.keys.get(name).expect("large page requested for a key that isn't in cache").data.as_ref() {
+            cache_data.pages.contains_key(&vpage_addr)
+        } else {
+            false
+        };
+        if !cached {
+            // make room for the incoming page
+            self.large_cache_evict(hw, v2p_map, cipher, LARGE_CACHE_MAX_PAGES - 1);
+            let maybe_data = if fill {
+                if let Some(pp) = v2p_map.get(&VirtAddr::new(vpage_addr).unwrap()) {
+                    assert!(pp.valid(), "v2p returned an invalid page");
+                    hw.data_decrypt_page(cipher, &self.aad, pp)
+                } else {
+                    None
+                }
+            } else {
+                None
+            };
+            let data = match maybe_data {
+                Some(data) => data,
+                None => {
+                    // this case is triggered either when we are about to overwrite the whole page, or when
+                    // we reserved a page but never wrote to it (so what's on disk is garbage). Either way,
+                    // start from a fresh page of 0's.
+                    let mut d = vec![0u8; VPAGE_SIZE + size_of::<JournalType>()];
+                    for (&src, dst) in (hw.trng_u32() % JOURNAL_RAND_RANGE).to_le_bytes().iter().zip(d[..size_of::<JournalType>()].iter_mut()) {
+                        *dst = src;
+                    }
+                    d
+                }
+            };
+            let kcache = self.keys.get_mut(name).expect("large page requested for a key that isn't in cache");
+            if kcache.data.is_none() {
+                kcache.data = Some(KeyCacheData::Large(KeyLargeData::new()));
+            }
+            if let Some(KeyCacheData::Large(cache_data)) = kcache.data.as_mut() {
+                cache_data.pages.insert(vpage_addr, KeyLargePage { data, clean: true, last_access: stamp });
+            } else {
+                panic!("Key allocated to large area but its cache data was not of the large type");
+            }
+            self.large_cache_pages += 1;
+        }
+        if let Some(KeyCacheData::Large(cache_data)) = self.keys.get_mut(name).unwrap().data.as_mut() {
+            let page = cache_data.pages.get_mut(&vpage_addr).expect("large page was just cached, but isn't there");
+            page.last_access = stamp;
+            page
+        } else {
+            panic!("Key allocated to large area but its cache data was not of the large type");
+        }
+    }
+    /// Evicts the least recently used large pool pages until no more than `target` pages remain in cache.
+    /// Dirty pages are committed to disk as they are evicted. Physical pages for the large pool are allocated
+    /// when the key's space is reserved, so eviction never needs to allocate space.
+    fn large_cache_evict(&mut self, hw: &mut PddbOs, v2p_map: &HashMap::<VirtAddr, PhysPage>, cipher: &Aes256GcmSiv, target: usize) {
+        while self.large_cache_pages > target {
+            let mut victim: Option<(&String, u64)> = None;
+            let mut oldest = u64::MAX;
+            for (name, kcache) in self.keys.iter() {
+                if let Some(KeyCacheData::Large(cache_data)) = kcache.data.as_ref() {
+                    for (&vpage_addr, page) in cache_data.pages.iter() {
+                        if page.last_access < oldest {
+                            oldest = page.last_access;
+                            victim = Some((name, vpage_addr));
+                        }
+                    }
+                }
+            }
+            let (name, vpage_addr) = victim.expect("large pool cache bookkeeping error: pages are counted, but none are in cache");
+            let name = name.to_string();
+            if let Some(KeyCacheData::Large(cache_data)) = self.keys.get_mut(&name).unwrap().data.as_mut() {
+                if let Some(mut page) = cache_data.pages.remove(&vpage_addr) {
+                    if !page.clean {
+                        log::debug!("evicting dirty large page {}:{:x}", name, vpage_addr);
+                        large_page_commit(hw, v2p_map, cipher, &self.aad, vpage_addr, &mut page);
+                    }
+                }
+            }
+            self.large_cache_pages -= 1;
+        }
+    }
+    /// Reads data out of a large key, starting at `offset` bytes into the key. Reads go through the large
+    /// pool cache, so data that was written but not yet committed to disk is returned correctly.
+    /// Returns the number of bytes read, which can be less than the length of `data` if the key is shorter.
+    pub(crate) fn key_read_large(&mut self, hw: &mut PddbOs, v2p_map: &HashMap::<VirtAddr, PhysPage>, cipher: &Aes256GcmSiv,
+        name: &str, data: &mut [u8], offset: usize) -> Result<usize> {
+        let (key_start, key_len) = if let Some(kcache) = self.keys.get(name) {
+            (kcache.start, kcache.len)
+        } else {
+            return Err(Error::new(ErrorKind::NotFound, "key not found"));
+        };
+        if offset as u64 > key_len {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "offest requested is beyond the key length"));
+        }
+        let readable = if ((key_len - offset as u64) as usize) < data.len() {
+            (key_len - offset as u64) as usize
+        } else {
+            data.len()
+        };
+        let mut read = 0;
+        while read < readable {
+            let abs_addr = key_start + (offset + read) as u64;
+            let vpage_addr = (abs_addr / VPAGE_SIZE as u64) * VPAGE_SIZE as u64;
+            let page_offset = (abs_addr % VPAGE_SIZE as u64) as usize;
+            if !v2p_map.contains_key(&VirtAddr::new(vpage_addr).unwrap()) {
+                log::warn!("Not enough bytes available to read for key {} ({}/{})", name, offset + read, key_len);
+                break;
+            }
+            let page = self.large_page_get(hw, v2p_map, cipher, name, vpage_addr, true);
+            for (&src, dst) in page.data[size_of::<JournalType>() + page_offset..].iter().zip(data[read..readable].iter_mut()) {
+                *dst = src;
+                read += 1;
+            }
+        }
+        log::debug!("read {}->{}/{}:{}", name, offset, read, key_len);
+        Ok(read)
+    }
     #[allow(dead_code)]
     pub fn key_contains(&mut self, name: &str) -> bool {
         self.keys.contains_key(&String::from(name))
@@ -666,14 +752,114 @@ impl DictCacheEntry {
             self.small_pool_free.push(KeySmallPoolOrd{index, avail: ksp.avail})
         }
     }
+    /// Returns the virtual addresses of all the key descriptor and small pool pages currently in use by the dictionary.
+    pub(crate) fn extent_vaddrs(&self) -> Vec::<VirtAddr> {
+        let mut extents = Vec::<VirtAddr>::new();
+        // the descriptor region runs up to the last index ever written to disk, or any index in cache beyond that
+        let mut top_index = self.last_disk_key_index;
+        for kcache in self.keys.values() {
+            top_index = top_index.max(kcache.descriptor_index.get());
+        }
+        for page in 0..(1 + top_index as usize / DK_PER_VPAGE) {
+            // note: check this code against dict_indices_to_vaddr() -- it's recoded here because we go by page, not by index
+            extents.push(VirtAddr::new(self.index.get() as u64 * DICT_VSIZE + page as u64 * VPAGE_SIZE as u64).unwrap());
+        }
+        for index in 0..self.small_pool.len() {
+            extents.push(VirtAddr::new(small_storage_base_vaddr_from_indices(self.index, index)).unwrap());
+        }
+        extents
+    }
+    /// Estimates the number of pages required to write out a compacted copy of the dictionary. The compacted
+    /// copy is written out in full before the old pages are released, so the new pages have to be reserved up front.
+    /// Pass this to ensure_fast_space_alloc() before calling `compact()`.
+    pub(crate) fn alloc_estimate_compact(&self) -> usize {
+        self.extent_vaddrs().len() + 1
+    }
+    /// Defragments the dictionary in RAM. Key descriptors are renumbered so they are contiguous from index 1,
+    /// and small keys are re-packed into as few small pool pages as possible. Keys that were deleted but
+    /// not yet synced are dropped from the cache. Nothing is written to disk by this routine: every key and small
+    /// pool page is marked dirty, so the caller must remap the returned extents and then do a `sync_small_pool`
+    /// followed by a `dict_sync`.
+    ///
+    /// The dictionary must be `fill()`ed before calling this, because the new layout is computed out of the cache.
+    ///
+    /// Returns the virtual addresses of the descriptor and small pool pages that were in use prior to compaction.
+    pub(crate) fn compact(&mut self) -> Result<Vec::<VirtAddr>> {
+        // check that everything we need is in cache before touching anything
+        let mut valid_keys = 0;
+        for (name, kcache) in self.keys.iter() {
+            if kcache.flags.valid() {
+                valid_keys += 1;
+                if kcache.start < SMALL_POOL_END && kcache.data.is_none() {
+                    log::error!("small key {} has no data in cache, can't compact", name);
+                    return Err(Error::new(ErrorKind::InvalidData, "small key data missing from cache"));
+                }
+            }
+        }
+        if valid_keys != self.key_count {
+            return Err(Error::new(ErrorKind::InvalidData, "not all keys are in cache; fill() the dictionary before compacting"));
+        }
+        let old_extents = self.extent_vaddrs();
+
+        // drop any records of deleted keys; their descriptors get overwritten as part of the compaction
+        self.keys.retain(|_, kcache| kcache.flags.valid());
+
+        // renumber the key descriptors, preserving their relative order
+        let mut by_index = Vec::<(u32, String)>::new();
+        for (name, kcache) in self.keys.iter() {
+            by_index.push((kcache.descriptor_index.get(), name.to_string()));
+        }
+        by_index.sort();
+        for (new_index, (_, name)) in by_index.iter().enumerate() {
+            let kcache = self.keys.get_mut(name).expect("key disappeared during compaction");
+            kcache.descriptor_index = NonZeroU32::new(new_index as u32 + 1).unwrap();
+            kcache.clean = false;
+        }
+        let free_key_index = by_index.len() as u32 + 1;
+        self.free_keys.clear();
+        self.free_keys.push(Reverse(FreeKeyRange{start: free_key_index, run: KEY_MAXCOUNT as u32 - 1 - free_key_index}));
+        self.last_disk_key_index = free_key_index;
+
+        // re-pack the small pool: biggest keys go first, into the first pool page that has room for them
+        let mut small_keys = Vec::<(u64, String)>::new();
+        for (name, kcache) in self.keys.iter() {
+            if kcache.start < SMALL_POOL_END {
+                small_keys.push((kcache.reserved, name.to_string()));
+            }
+        }
+        small_keys.sort_by(|a, b| b.cmp(a));
+        let mut small_pool = Vec::<KeySmallPool>::new();
+        for (reserved, name) in small_keys {
+            let reserved = reserved as u16;
+            let target = if let Some(index) = small_pool.iter().position(|ksp| ksp.avail >= reserved) {
+                index
+            } else {
+                small_pool.push(KeySmallPool::new());
+                small_pool.len() - 1
+            };
+            small_pool[target].contents.push(name);
+            small_pool[target].avail -= reserved;
+        }
+        // the new pool entries are all marked as dirty, so the next sync_small_pool() will assign the new `start` addresses
+        self.small_pool = small_pool;
+        self.rebuild_free_pool();
+
+        self.age = self.age.saturating_add(1);
+        self.clean = false;
+        Ok(old_extents)
+    }
     /// Used to remove a key from the dictionary. If you call it with a non-existent key,
-    /// the routine has no effect, and does not report an error. Small keys are not immediately
-    /// overwritten in paranoid mode, but large keys are.
+    /// the routine has no effect, and does not report an error. Large keys are always overwritten
+    /// with noise as they are removed; in paranoid mode, small keys are also immediately erased from
+    /// disk (see `key_erase()`).
     pub fn key_remove(&mut self, hw: &mut PddbOs, v2p_map: &mut HashMap::<VirtAddr, PhysPage>, cipher: &Aes256GcmSiv,
         name_str: &str, paranoid: bool) {
         if paranoid {
             // large records are paranoid-erased, by default, because of the pool-reuse problem.
-            unimplemented!("Paranoid erase for small records not yet implemented. Calling sync after an update, however, effectively does a paranoid erase.");
+            if !self.key_erase(hw, v2p_map, cipher, name_str) {
+                log::error!("Couldn't immediately erase {}; its data will be overwritten on the next sync", name_str);
+            }
+            return;
         }
         // this call will check the disk to see if there's key data that's not in cache.
         if self.ensure_key_entry(hw, v2p_map, cipher, name_str) {
@@ -705,6 +891,10 @@ impl DictCacheEntry {
 
                 } else {
                     // handle the large pool case
+                    // drop any cached data without committing it, the pages are about to be erased anyways.
+                    if let Some(KeyCacheData::Large(cache_data)) = kcache.data.take() {
+                        self.large_cache_pages -= cache_data.pages.len();
+                    }
                     // mark the entry as invalid and dirty; virtual space is one huge memory leak...
                     // ...but we remove the virtual pages from the page pool, effectively reclaiming the physical space.
                     for vpage in kcache.large_pool_vpages() {
@@ -744,12 +934,18 @@ impl DictCacheEntry {
         }
         // if there's no key....we're done!
     }
-    /// used to remove a key from the dictionary, syncing 0's to the disk in the key's place
-    /// sort of less relevant now that the large keys have a paranoid mode; probably this routine should actually
-    /// be a higher-level function that catches the paranoid request and does an "update" of 0's to the key
-    /// then does a disk sync and then calls remove
-    pub fn key_erase(&mut self, _name: &str) {
-        unimplemented!();
+    /// Used to remove a key from the dictionary, overwriting its data on disk right away. Large pool pages
+    /// are filled with noise as part of the removal. The small pool page that held the key is re-encrypted
+    /// in place, without the key in it, so the old ciphertext is gone as soon as this returns.
+    ///
+    /// The key's descriptor is blanked out by the next `dict_sync()`, which should be called immediately
+    /// after this, followed by a `pt_sync()`. Returns `false` if there wasn't enough FastSpace to re-write the
+    /// small pool; call `ensure_fast_space_alloc()` with `alloc_estimate_small()` beforehand to avoid this.
+    pub fn key_erase(&mut self, hw: &mut PddbOs, v2p_map: &mut HashMap::<VirtAddr, PhysPage>, cipher: &Aes256GcmSiv,
+        name: &str) -> bool {
+        self.key_remove(hw, v2p_map, cipher, name, false);
+        // the removal marked the key's pool page as dirty; a sync re-writes the page with only the remaining keys in it.
+        self.sync_small_pool(hw, v2p_map, cipher)
     }
     /// estimates the amount of space needed to sync the dict cache. Pass this to ensure_fast_space_alloc() before calling a sync.
     /// estimate can be inaccurate under pathological allocation conditions.
@@ -809,7 +1005,7 @@ impl DictCacheEntry {
                 //   5. sync the page tables
                 // This implementation just skips to step 3.
                 let mut page = [0u8; VPAGE_SIZE + size_of::<JournalType>()];
-                for (&src, dst) in (hw.trng_u32() % JOURNAL_RAND_RANGE).to_le_bytes().iter().zip(page[..size_of::<JournalType>()].iter_mut()) {
+                for (&src, dst) in (hw.trng_u32() % JOURNAL_RAND_RANGE).max(self.journal_floor).to_le_bytes().iter().zip(page[..size_of::<JournalType>()].iter_mut()) {
                     *dst = src;
                 }
                 let mut pool_offset = 0;
@@ -844,8 +1040,20 @@ impl DictCacheEntry {
         true
     }
 
-    /// No data cache to flush yet...large pool caches not implemented!
-    pub(crate) fn sync_large_pool(&self) {
+    /// Commits all the dirty pages in the large pool cache to disk. The pages stay in the cache afterwards,
+    /// so subsequent reads don't have to go back to the disk. Physical pages for the large pool are allocated
+    /// when a key's space is reserved, so unlike the small pool, this routine can't run out of space.
+    pub(crate) fn sync_large_pool(&mut self, hw: &mut PddbOs, v2p_map: &HashMap::<VirtAddr, PhysPage>, cipher: &Aes256GcmSiv) {
+        for (name, kcache) in self.keys.iter_mut() {
+            if let Some(KeyCacheData::Large(cache_data)) = kcache.data.as_mut() {
+                for (&vpage_addr, page) in cache_data.pages.iter_mut() {
+                    if !page.clean {
+                        log::debug!("sync large page {}:{:x}", name, vpage_addr);
+                        large_page_commit(hw, v2p_map, cipher, &self.aad, vpage_addr, page);
+                    }
+                }
+            }
+        }
     }
 
     /// Finds the next available slot to store the key metadata (not the data itself). It also
@@ -956,6 +1164,16 @@ impl DictCacheEntry {
     }
 }
 
+/// Encrypts a cached large pool page and writes it back to its physical page on disk.
+fn large_page_commit(hw: &mut PddbOs, v2p_map: &HashMap::<VirtAddr, PhysPage>, cipher: &Aes256GcmSiv, aad: &[u8],
+    vpage_addr: u64, page: &mut KeyLargePage) {
+    let pp = v2p_map.get(&VirtAddr::new(vpage_addr).unwrap()).expect("large key data allocation missing");
+    assert!(pp.valid(), "v2p returned an invalid page");
+    // this also bumps the journal number in our cached copy, so it stays in step with the disk
+    hw.data_encrypt_and_patch_page(cipher, aad, &mut page.data, pp);
+    page.clean = true;
+}
+
 /// converts a dictionary index -- which is a 1-offset number of dictionaries -- plus a key
 /// metadata index (not the key number; but, the enumerated set of potential key slots, also
 /// 1-offset), and creates a virtual address for the location of this combination
@@ -982,6 +1200,37 @@ pub(crate) fn small_storage_index_from_key(kcache: &KeyCacheEntry, dict_index: N
 pub(crate) fn small_storage_base_vaddr_from_indices(dict_index: NonZeroU32, base_index: usize) -> u64 {
     SMALL_POOL_START + (dict_index.get()-1) as u64 * DICT_VSIZE + base_index as u64 * SMALL_CAPACITY as u64
 }
+/// Matches a dictionary or key name against a listing filter. A filter containing `*` (any run of characters,
+/// including none) or `?` (any single character) is a glob that has to match the whole name; any other
+/// filter is a plain prefix.
+pub(crate) fn name_matches(filter: &str, name: &str) -> bool {
+    if !filter.contains(|c| c == '*' || c == '?') {
+        return name.starts_with(filter);
+    }
+    let pattern: Vec<char> = filter.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let mut p = 0;
+    let mut n = 0;
+    // position of the last `*` seen, and the position in the name it is currently matched up to
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // backtrack: let the last `*` swallow one more character
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
 
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
 #[repr(C, align(8))]
@@ -1189,6 +1438,23 @@ impl PlaintextCache {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_name_matches() {
+        // plain filters are prefixes
+        assert!(name_matches("wlan.", "wlan.ssid"));
+        assert!(name_matches("", "anything"));
+        assert!(!name_matches("wlan.", "wlan"));
+        // globs match the whole name
+        assert!(name_matches("*.ssid", "wlan.ssid"));
+        assert!(!name_matches("*.ssid", "wlan.ssid.old"));
+        assert!(name_matches("key?", "key1"));
+        assert!(!name_matches("key?", "key10"));
+        assert!(name_matches("a*b*c", "aXXbYYbc"));
+        assert!(!name_matches("a*b*c", "aXXbYYb"));
+        assert!(name_matches("*", ""));
+        assert!(name_matches("ключ*", "ключ1"));
+    }
+
     fn clone_bheap<T: Clone + Ord + Copy>(heap: &mut BinaryHeap<Reverse<T>>) -> BinaryHeap<Reverse<T>> {
         let heap_copy = std::mem::replace(heap, BinaryHeap::<Reverse<T>>::new());
         let mut heap_clone = BinaryHeap::<Reverse<T>>::new();
@@ -0,0 +1,253 @@
+use crate::*;
+use std::collections::{HashMap, HashSet};
+use std::io::{Result, Error, ErrorKind};
+
+/// Consistency checking
+///
+/// An ungraceful reboot can leave the structures of a basis out of step with each other: a PTE can
+/// point at a page whose contents never made it to disk, a page can be mapped that nothing refers to
+/// anymore, or a key descriptor can refer to data that isn't there. The check walks every open basis
+/// and cross-references the following:
+/// - the page table on disk against the basis' `v2p_map`. Entries that disagree are "stale PTEs".
+/// - every mapped page against its AES-GCM-SIV tag. Pages that fail to decrypt are "bad pages".
+/// - the mapped virtual pages against the root, dictionary, key descriptor, small pool and large pool
+///   pages that are actually referenced by the basis. Mapped pages referenced by nothing are "orphaned pages".
+/// - every valid key descriptor against the pages holding its data. Keys whose data is unmapped,
+///   unreadable, or out of bounds are "dangling keys".
+/// - the FastSpace free pool against the pages mapped by the open bases. Pages that are both free
+///   and in use are "FastSpace conflicts".
+///
+/// Only open bases can be checked, as the page table can't be decrypted without the basis key. A page
+/// in use by a closed basis is indistinguishable from a free page, so unlike the other checks, a
+/// FastSpace conflict with a closed basis will go unnoticed.
+///
+/// When repairing, the RAM cache is taken as the point of truth, because the cache is populated from
+/// whatever could be successfully decrypted. Bad and orphaned pages are overwritten with noise and
+/// returned to the FastSpace pool, dangling keys are removed, and any dictionary metadata that lived on
+/// a bad page is re-written from the cache. Data that was on a bad page is lost.
+
+/// Tally of the problems found by a consistency check.
+#[derive(Default, Debug, Copy, Clone)]
+pub(crate) struct CheckReport {
+    /// number of bases checked
+    pub(crate) bases: u32,
+    /// number of mapped pages checked
+    pub(crate) pages: u32,
+    /// mapped pages that fail to decrypt
+    pub(crate) bad_pages: u32,
+    /// page table entries on disk that disagree with the RAM cache
+    pub(crate) stale_ptes: u32,
+    /// mapped pages that aren't referenced by any structure in the basis
+    pub(crate) orphaned_pages: u32,
+    /// valid key descriptors that point to missing or unreadable data
+    pub(crate) dangling_keys: u32,
+    /// pages that are in the FastSpace free pool, but also mapped by an open basis
+    pub(crate) fastspace_conflicts: u32,
+}
+impl CheckReport {
+    pub(crate) fn problems(&self) -> u32 {
+        self.bad_pages + self.stale_ptes + self.orphaned_pages + self.dangling_keys + self.fastspace_conflicts
+    }
+}
+
+impl BasisCacheEntry {
+    /// Checks the consistency of the basis, tallying the problems found into `report`. If `repair` is set, the
+    /// problems are fixed as they are found. `in_use` is the set of physical pages mapped by every open basis,
+    /// so that pages claimed by another basis are never freed.
+    ///
+    /// The caller should ensure there is enough FastSpace to re-write the dictionaries of the basis, by
+    /// passing the sum of `DictCacheEntry::alloc_estimate_compact()` to `ensure_fast_space_alloc()` beforehand.
+    pub(crate) fn check(&mut self, hw: &mut PddbOs, in_use: &HashSet<u32>, repair: bool, report: &mut CheckReport) -> Result<()> {
+        report.bases += 1;
+        // load every dictionary and key, so the cache reflects everything that can be read off the disk
+        self.populate_caches(hw);
+        for dict in self.dicts.values_mut() {
+            if dict.flags.valid() {
+                dict.fill(hw, &self.v2p_map, &self.cipher);
+            }
+        }
+
+        // 1. the page table on disk should agree exactly with the v2p_map, as all the bases were synced prior to the check.
+        let mut basis_key = [0u8; AES_KEYSIZE];
+        basis_key.copy_from_slice(self.key.as_slice());
+        let disk_map = hw.pt_scan_key(&basis_key, &self.name).unwrap_or_default();
+        let mut stale_pages = Vec::<PhysPage>::new();
+        for (va, disk_pp) in disk_map.iter() {
+            let matches = if let Some(pp) = self.v2p_map.get_mut(va) {
+                if pp.page_number() == disk_pp.page_number() {
+                    true
+                } else {
+                    // the cache is the point of truth; marking the entry dirty causes pt_sync() to re-write it
+                    if repair { pp.set_clean(false); }
+                    false
+                }
+            } else {
+                false
+            };
+            if !matches {
+                log::warn!("{}: stale PTE va {:x} -> pp {:x}", self.name, va.get(), disk_pp.page_number());
+                report.stale_ptes += 1;
+                if !in_use.contains(&disk_pp.page_number()) {
+                    stale_pages.push(*disk_pp);
+                }
+            }
+        }
+        for (va, pp) in self.v2p_map.iter_mut() {
+            if !disk_map.contains_key(va) {
+                log::warn!("{}: PTE va {:x} -> pp {:x} is missing on disk", self.name, va.get(), pp.page_number());
+                report.stale_ptes += 1;
+                if repair { pp.set_clean(false); }
+            }
+        }
+
+        // 2. every mapped page should decrypt. The root page is special-cased because it also carries a key commitment.
+        let root_va = VirtAddr::new(VPAGE_SIZE as u64).unwrap();
+        let mut bad = HashSet::<VirtAddr>::new();
+        for (&va, pp) in self.v2p_map.iter() {
+            report.pages += 1;
+            let readable = if va == root_va {
+                hw.data_decrypt_page_with_commit(self.key.as_slice(), &self.aad, pp).is_some()
+            } else {
+                hw.data_decrypt_page(&self.cipher, &self.aad, pp).is_some()
+            };
+            if !readable {
+                log::warn!("{}: page at va {:x} -> pp {:x} fails to decrypt", self.name, va.get(), pp.page_number());
+                report.bad_pages += 1;
+                bad.insert(va);
+            }
+        }
+
+        // 3. every mapped page should be referenced by something
+        let mut referenced = HashSet::<VirtAddr>::new();
+        referenced.insert(root_va);
+        for dict in self.dicts.values() {
+            if dict.flags.valid() {
+                referenced.extend(dict.extent_vaddrs());
+                for key in dict.keys.values() {
+                    if key.flags.valid() {
+                        referenced.extend(key.large_pool_vpages());
+                    }
+                }
+            }
+        }
+        let mut orphans = Vec::<VirtAddr>::new();
+        for &va in self.v2p_map.keys() {
+            if !referenced.contains(&va) && !bad.contains(&va) {
+                log::warn!("{}: orphaned page at va {:x}", self.name, va.get());
+                report.orphaned_pages += 1;
+                orphans.push(va);
+            }
+        }
+
+        // 4. every valid key should point at mapped, readable data
+        let mut dangling = HashMap::<String, Vec<String>>::new();
+        for (dict_name, dict) in self.dicts.iter() {
+            if !dict.flags.valid() {
+                continue;
+            }
+            for (key_name, key) in dict.keys.iter() {
+                if !key.flags.valid() {
+                    continue;
+                }
+                let page_ok = |vaddr: u64| {
+                    let va = VirtAddr::new((vaddr / VPAGE_SIZE as u64) * VPAGE_SIZE as u64).unwrap();
+                    self.v2p_map.contains_key(&va) && !bad.contains(&va)
+                };
+                let ok = if key.len > key.reserved {
+                    false
+                } else if key.start >= LARGE_POOL_START {
+                    (key.start..key.start + key.len).step_by(VPAGE_SIZE).all(page_ok)
+                } else if let Some(pool_index) = small_storage_index_from_key(key, dict.index) {
+                    key.data.is_some() && page_ok(small_storage_base_vaddr_from_indices(dict.index, pool_index))
+                } else {
+                    false
+                };
+                if !ok {
+                    log::warn!("{}: dangling key {}:{} (start {:x}, len {}, reserved {})",
+                        self.name, dict_name, key_name, key.start, key.len, key.reserved);
+                    report.dangling_keys += 1;
+                    dangling.entry(dict_name.to_string()).or_insert_with(Vec::new).push(key_name.to_string());
+                }
+            }
+        }
+
+        if !repair {
+            return Ok(());
+        }
+        // stale pages are no longer in the v2p_map: wipe them, and drop their PTEs directly
+        for mut pp in stale_pages {
+            let mut random = [0u8; PAGE_SIZE];
+            hw.trng_slice(&mut random);
+            hw.patch_data(&random, pp.page_number() * PAGE_SIZE as u32);
+            hw.pt_erase(pp.page_number());
+            hw.fast_space_free(&mut pp);
+        }
+        // bad and orphaned pages are wiped and freed in place; the pt_sync() below removes their PTEs.
+        // The root page is never freed: it is re-written in place by basis_sync().
+        for va in bad.iter().chain(orphans.iter()) {
+            if *va == root_va {
+                continue;
+            }
+            if let Some(pp) = self.v2p_map.get_mut(va) {
+                let mut random = [0u8; PAGE_SIZE];
+                hw.trng_slice(&mut random);
+                hw.patch_data(&random, pp.page_number() * PAGE_SIZE as u32);
+                hw.fast_space_free(pp);
+            }
+        }
+        self.pt_sync(hw);
+
+        // remove the dangling keys; their descriptors are blanked out by the dict_sync() in the final sync
+        for (dict_name, keys) in dangling.iter() {
+            if let Some(dict) = self.dicts.get_mut(dict_name) {
+                for key in keys {
+                    dict.key_remove(hw, &mut self.v2p_map, &self.cipher, key, false);
+                }
+            }
+        }
+        // re-write the metadata and small pools that lived on bad pages out of the cache
+        if bad.contains(&root_va) {
+            self.clean = false;
+        }
+        let mut num_valid = 0;
+        for dict in self.dicts.values_mut() {
+            if !dict.flags.valid() {
+                continue;
+            }
+            num_valid += 1;
+            let dict_base = dict.index.get() as u64 * DICT_VSIZE;
+            let mut rewrite = bad.iter().any(|va| va.get() >= dict_base && va.get() < dict_base + DICT_VSIZE);
+            // keys whose descriptors were lost to a bad page can't be recovered; drop them from the count
+            let valid_keys = dict.keys.values().filter(|k| k.flags.valid()).count() as u32;
+            if valid_keys != dict.key_count {
+                log::warn!("{}: dict at index {} claims {} keys, but only {} could be read", self.name, dict.index, dict.key_count, valid_keys);
+                dict.key_count = valid_keys;
+                rewrite = true;
+            }
+            if rewrite {
+                dict.clean = false;
+                for key in dict.keys.values_mut() {
+                    if key.flags.valid() {
+                        key.clean = false;
+                    }
+                }
+            }
+            for index in 0..dict.small_pool.len() {
+                let pool_va = VirtAddr::new(small_storage_base_vaddr_from_indices(dict.index, index)).unwrap();
+                if bad.contains(&pool_va) || orphans.contains(&pool_va) {
+                    dict.small_pool[index].clean = false;
+                }
+            }
+            if !dict.sync_small_pool(hw, &mut self.v2p_map, &self.cipher) {
+                return Err(Error::new(ErrorKind::OutOfMemory, "No free space to repair small pool"));
+            }
+        }
+        // dictionaries whose headers were lost to a bad page can't be recovered either
+        if num_valid != self.num_dicts {
+            log::warn!("{}: basis claims {} dicts, but only {} could be read", self.name, self.num_dicts, num_valid);
+            self.num_dicts = num_valid;
+            self.clean = false;
+        }
+        self.sync(hw)
+    }
+}
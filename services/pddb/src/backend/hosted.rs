@@ -84,6 +84,11 @@ impl EmuStorage {
         }
         f.flush().unwrap();
     }
+    pub fn dump_archive(&self, archive: &[u8], name: &str) {
+        let mut f = File::create(format!("../tools/pddb-images/{}.pdba", name)).unwrap();
+        f.write_all(archive).unwrap();
+        f.flush().unwrap();
+    }
 }
 
 pub struct HostedSpinor {
@@ -213,6 +213,12 @@ impl PddbOs {
     pub fn dbg_dump(&self, _name: Option<String>) {
         // placeholder
     }
+    /// writes a basis archive next to the image dumps, so it can be examined or imported on the host
+    #[allow(dead_code)]
+    #[cfg(not(any(target_os = "none", target_os = "xous")))]
+    pub fn dbg_archive_dump(&self, archive: &[u8], name: &str) {
+        self.pddb_mr.dump_archive(archive, name);
+    }
     #[allow(dead_code)]
     #[cfg(not(any(target_os = "none", target_os = "xous")))]
     /// used to reset the hardware structure for repeated runs of testing within a single invocation
@@ -1063,27 +1069,55 @@ impl PddbOs {
                 // log regenration is faster & less intrusive than fastspace regeneration, and we would have
                 // to do this more often. So we have a separate path for this outcome.
                 log::warn!("FastSpace alloc forced by lack of log space");
-                let mut fast_space = FastSpace {
-                    free_pool: [PhysPage(0); FASTSPACE_FREE_POOL_LEN],
-                };
-                for pp in fast_space.free_pool.iter_mut() {
-                    pp.set_journal(self.trng_u8() % FSCB_JOURNAL_RAND_RANGE)
-                }
-                // regenerate from the existing fast space cache
-                for (&src, dst) in self.fspace_cache.iter().zip(fast_space.free_pool.iter_mut()) {
-                    *dst = src;
-                }
-                // write just commits a new record to disk, but doesn't update our internal data cache
-                // this also clears the fast space log.
-                self.fast_space_write(&fast_space);
-                // this will re-read back in the data, shuffle the alloc order a bit, and ensure the data cache is fully in sync
-                self.fast_space_read();
-                // this will locate the next fast space log point.
-                self.fast_space_ensure_next_log();
+                self.fast_space_rewrite();
                 true
             }
         }
     }
+    /// Commits the fast space cache to a new FastSpace record, clearing the fast space log.
+    fn fast_space_rewrite(&mut self) {
+        let mut fast_space = FastSpace {
+            free_pool: [PhysPage(0); FASTSPACE_FREE_POOL_LEN],
+        };
+        for pp in fast_space.free_pool.iter_mut() {
+            pp.set_journal(self.trng_u8() % FSCB_JOURNAL_RAND_RANGE)
+        }
+        // regenerate from the existing fast space cache
+        for (&src, dst) in self.fspace_cache.iter().zip(fast_space.free_pool.iter_mut()) {
+            *dst = src;
+        }
+        // write just commits a new record to disk, but doesn't update our internal data cache
+        // this also clears the fast space log.
+        self.fast_space_write(&fast_space);
+        // this will re-read back in the data, shuffle the alloc order a bit, and ensure the data cache is fully in sync
+        self.fast_space_read();
+        // this will locate the next fast space log point.
+        self.fast_space_ensure_next_log();
+    }
+    /// Checks the fast space cache against the pages mapped by the open bases. A page that is both mapped
+    /// and in the free pool will eventually be handed out a second time, clobbering the data that's there.
+    /// If `repair` is set, the conflicting pages are dropped from the free pool, and the pruned pool is
+    /// committed to the FSCB so that they don't come back on the next mount. Returns the number of conflicts found.
+    pub(crate) fn fast_space_check(&mut self, cache: &Vec::<BasisCacheEntry>, repair: bool) -> usize {
+        let mut mapped = std::collections::HashSet::<u32>::new();
+        for entry in cache {
+            for pp in entry.v2p_map.values() {
+                mapped.insert(pp.page_number());
+            }
+        }
+        let conflicts: Vec::<PhysPage> = self.fspace_cache.iter()
+            .filter(|pp| mapped.contains(&pp.page_number())).cloned().collect();
+        for pp in conflicts.iter() {
+            log::warn!("FastSpace page {:x} is also mapped by an open basis", pp.page_number());
+            if repair {
+                self.fspace_cache.remove(pp);
+            }
+        }
+        if repair && conflicts.len() > 0 {
+            self.fast_space_rewrite();
+        }
+        conflicts.len()
+    }
 
     pub(crate) fn data_aad(&self, name: &str) -> Vec::<u8> {
         let mut aad = Vec::<u8>::new();
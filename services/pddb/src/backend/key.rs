@@ -4,6 +4,7 @@ use super::*;
 use std::num::NonZeroU32;
 use core::ops::{Deref, DerefMut};
 use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::io::{Result, Error, ErrorKind};
 
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
@@ -137,7 +138,6 @@ impl KeyCacheEntry {
 pub (crate) enum KeyCacheData {
     Small(KeySmallData),
     // the "Medium" type has a region reserved for it, but we haven't coded a handler for it.
-    #[allow(dead_code)] // Large data caching isn't implemented, so of course, we don't ever create this type
     Large(KeyLargeData),
 }
 /// Small data is optimized for low overhead, and always represent a complete copy of the data.
@@ -145,14 +145,29 @@ pub(crate) struct KeySmallData {
     pub clean: bool,
     pub(crate) data: Vec::<u8>,
 }
-/// This can hold just a portion of a large key's data. For now, we now essentially manually
-/// encode a sub-slice in parts, but, later on we could get more clever and start to cache
-/// multiple disjoint portions of a large key's data...
-#[allow(dead_code)] // large key caching is not yet implemented
+/// This holds just a portion of a large key's data, as a sparse set of virtual pages. The
+/// pages are keyed by their virtual address, so a key can have multiple disjoint portions of
+/// its data in cache at once. How many pages are held in total is bounded by the containing
+/// dictionary, which evicts the least recently used pages across all of its keys.
 pub(crate) struct KeyLargeData {
-    pub clean: bool,
-    pub(crate) start: u64,
+    pub(crate) pages: HashMap::<u64, KeyLargePage>,
+}
+impl KeyLargeData {
+    pub(crate) fn new() -> KeyLargeData {
+        KeyLargeData {
+            pages: HashMap::<u64, KeyLargePage>::new(),
+        }
+    }
+}
+/// A single decrypted virtual page of a large key.
+pub(crate) struct KeyLargePage {
+    /// decrypted page contents. Includes the journal number at the very beginning, so it can be
+    /// handed directly back to `data_encrypt_and_patch_page()` when the page is committed.
     pub(crate) data: Vec::<u8>,
+    /// set if the page is synchronized with what's on disk
+    pub(crate) clean: bool,
+    /// access stamp from the containing dictionary; the lowest stamp is the next to be evicted
+    pub(crate) last_access: u64,
 }
 
 /// A storage pool for data that is strictly smaller than one VPAGE. These element are serialized
@@ -0,0 +1,272 @@
+use crate::*;
+use core::convert::TryInto;
+use num_traits::FromPrimitive;
+use std::collections::HashMap;
+use std::io::{Result, Error, ErrorKind};
+
+/// Atomic multi-key transactions
+///
+/// A transaction is a list of key writes and deletes, across any number of dictionaries in one basis.
+/// Individual key updates are already journaled page by page, but nothing ties the updates of several
+/// keys together: a power loss in the middle of a batch would leave some keys updated and others not.
+///
+/// Transactions are made atomic with an undo record. Before any of the operations are applied, the
+/// prior state of every key the transaction touches is written to `TX_UNDO_KEY` in `TX_DICT` of the
+/// same basis, and the basis is sync'd. The operations are then applied and sync'd, and finally the
+/// undo record is removed. If the undo record is found when a basis is mounted, the transaction did
+/// not finish, and the keys are rolled back to their prior state.
+///
+/// The undo record is structured as follows (all integers are little-endian):
+/// - number of dictionaries created by the transaction: u32
+///   - dictionary name: u8 length + utf-8 bytes
+/// - number of keys touched by the transaction: u32
+///   - dictionary name, key name: u8 length + utf-8 bytes, each
+///   - 0 if the key did not exist; otherwise 1, followed by reserved space (u64), data length (u64) + data bytes
+/// - zero padding to a multiple of 4 bytes
+/// - murmur3 checksum of all of the above: u32
+///
+/// The checksum catches an undo record that was torn by a power loss while it was being written. Such a
+/// record is discarded, which is safe, because none of the operations were applied at that point.
+pub(crate) const TX_DICT: &'static str = ".txlog";
+pub(crate) const TX_UNDO_KEY: &'static str = "undo";
+const TX_CHECKSUM_SEED: u32 = 0x5458_4c47;
+
+/// One operation of a transaction. Clients stage writes and deletes; `basis_import()` also adds
+/// dictionaries, which may end up empty, and restores the reserved space of the keys it writes.
+pub(crate) enum TxOp<'a> {
+    Write{dict: &'a str, key: &'a str, data: &'a [u8], reserved: Option<usize>},
+    Delete{dict: &'a str, key: &'a str},
+    AddDict{dict: &'a str},
+}
+impl<'a> TxOp<'a> {
+    /// The key the operation touches, if any
+    fn names(&self) -> Option<(&'a str, &'a str)> {
+        match self {
+            TxOp::Write{dict, key, ..} => Some((*dict, *key)),
+            TxOp::Delete{dict, key} => Some((*dict, *key)),
+            TxOp::AddDict{..} => None,
+        }
+    }
+}
+
+/// Decodes the transaction record that `PddbTransaction::commit()` sends, in the format documented on
+/// `PddbTransactionOp`.
+pub(crate) fn transaction_decode(record: &[u8], count: usize) -> Result<Vec<TxOp>> {
+    let mut rd = RecordReader::new(record);
+    let mut ops = Vec::<TxOp>::new();
+    for _ in 0..count {
+        let op: Option<PddbTransactionOp> = FromPrimitive::from_u8(rd.bytes(1)?[0]);
+        let dict = rd.str()?;
+        let key = rd.str()?;
+        if dict.len() == 0 || dict.len() > DICT_NAME_LEN - 1 || key.len() == 0 || key.len() > KEY_NAME_LEN - 1 {
+            return Err(Error::new(ErrorKind::InvalidInput, "dictionary or key name has an invalid length"));
+        }
+        if dict == TX_DICT {
+            return Err(Error::new(ErrorKind::InvalidInput, "the transaction log dictionary is reserved"));
+        }
+        match op {
+            Some(PddbTransactionOp::Write) => {
+                let len = rd.usize()?;
+                ops.push(TxOp::Write{dict, key, data: rd.bytes(len)?, reserved: None});
+            }
+            Some(PddbTransactionOp::Delete) => ops.push(TxOp::Delete{dict, key}),
+            None => return Err(Error::new(ErrorKind::InvalidInput, "unknown transaction operation")),
+        }
+    }
+    if !rd.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "transaction record has trailing data"));
+    }
+    Ok(ops)
+}
+
+/// Applies `ops` to `basis_name` atomically. On success, returns the (dict, key) pairs that were modified.
+/// If any operation fails, the ones that were already applied are rolled back before the error is returned.
+pub(crate) fn transaction_commit(hw: &mut PddbOs, basis_cache: &mut BasisCache, basis_name: &str, ops: &[TxOp])
+-> Result<Vec<(String, String)>> {
+    let mut undo = transaction_log(hw, basis_cache, basis_name, ops)?;
+    let result = transaction_apply(hw, basis_cache, basis_name, ops);
+    if let Err(e) = result {
+        log::warn!("transaction failed, rolling back: {:?}", e);
+        transaction_rollback(hw, basis_cache, basis_name, &undo)?;
+        archive_erase(&mut undo);
+        return Err(e);
+    }
+    archive_erase(&mut undo);
+    transaction_finish(hw, basis_cache, basis_name)?;
+    let mut touched = Vec::<(String, String)>::new();
+    for (dict, key) in ops.iter().filter_map(|op| op.names()) {
+        if !touched.iter().any(|(d, k)| d == dict && k == key) {
+            touched.push((String::from(dict), String::from(key)));
+        }
+    }
+    Ok(touched)
+}
+
+/// Checks a freshly mounted basis for a transaction that was interrupted, and rolls it back if there is one.
+/// Returns `true` if a transaction was rolled back.
+pub(crate) fn transaction_recover(hw: &mut PddbOs, basis_cache: &mut BasisCache, basis_name: &str) -> Result<bool> {
+    if basis_cache.dict_attributes(hw, TX_DICT, Some(basis_name)).is_err() {
+        return Ok(false);
+    }
+    let rolled_back = match basis_cache.key_attributes(hw, TX_DICT, TX_UNDO_KEY, Some(basis_name)) {
+        Ok(attr) => {
+            let mut undo = vec![0u8; attr.len];
+            let readlen = basis_cache.key_read(hw, TX_DICT, TX_UNDO_KEY, &mut undo, None, Some(basis_name))?;
+            let valid = readlen == attr.len && undo_checksum_ok(&undo);
+            if valid {
+                log::warn!("basis {} has an interrupted transaction, rolling it back", basis_name);
+                transaction_rollback(hw, basis_cache, basis_name, &undo)?;
+            } else {
+                log::warn!("basis {} has a torn transaction log; no changes were applied, discarding it", basis_name);
+            }
+            archive_erase(&mut undo);
+            valid
+        }
+        Err(_) => false,
+    };
+    transaction_finish(hw, basis_cache, basis_name)?;
+    Ok(rolled_back)
+}
+
+/// Records the prior state of every key that `ops` touch, and commits the record to disk.
+/// Returns a copy of the record, so the caller can roll back without reading it back.
+pub(crate) fn transaction_log(hw: &mut PddbOs, basis_cache: &mut BasisCache, basis_name: &str, ops: &[TxOp]) -> Result<Vec<u8>> {
+    if !basis_cache.basis_list().iter().any(|b| b == basis_name) {
+        return Err(Error::new(ErrorKind::NotFound, "Requested basis not found, or PDDB not mounted."));
+    }
+    let mut new_dicts = Vec::<&str>::new();
+    // whether each key exists, as of the operations processed so far
+    let mut live = HashMap::<(&str, &str), bool>::new();
+    let mut keys = Vec::<u8>::new();
+    let mut key_count = 0u32;
+    for op in ops.iter() {
+        let (dict, key) = match op {
+            TxOp::Write{dict, key, ..} | TxOp::Delete{dict, key} => (*dict, *key),
+            TxOp::AddDict{dict} => {
+                // only dictionaries that the transaction creates need to be recorded
+                if !new_dicts.contains(dict) && basis_cache.dict_attributes(hw, dict, Some(basis_name)).is_err() {
+                    new_dicts.push(dict);
+                }
+                continue;
+            }
+        };
+        let exists = match live.get(&(dict, key)) {
+            Some(&exists) => exists,
+            None => {
+                // first operation on this key: record its prior state
+                let dict_exists = !new_dicts.contains(&dict) && basis_cache.dict_attributes(hw, dict, Some(basis_name)).is_ok();
+                record_push_str(&mut keys, dict);
+                record_push_str(&mut keys, key);
+                key_count += 1;
+                match if dict_exists {basis_cache.key_attributes(hw, dict, key, Some(basis_name)).ok()} else {None} {
+                    Some(attr) => {
+                        let mut data = vec![0u8; attr.len];
+                        if attr.len > 0 {
+                            basis_cache.key_read(hw, dict, key, &mut data, None, Some(basis_name))?;
+                        }
+                        keys.push(1);
+                        keys.extend_from_slice(&(attr.reserved as u64).to_le_bytes());
+                        keys.extend_from_slice(&(data.len() as u64).to_le_bytes());
+                        keys.extend_from_slice(&data);
+                        archive_erase(&mut data);
+                        true
+                    }
+                    None => {
+                        keys.push(0);
+                        if !dict_exists && !new_dicts.contains(&dict) {
+                            new_dicts.push(dict);
+                        }
+                        false
+                    }
+                }
+            }
+        };
+        match op {
+            TxOp::Delete{..} => {
+                if !exists {
+                    archive_erase(&mut keys);
+                    return Err(Error::new(ErrorKind::NotFound, "key not found"));
+                }
+                live.insert((dict, key), false)
+            }
+            _ => live.insert((dict, key), true),
+        };
+    }
+    let mut undo = Vec::<u8>::new();
+    undo.extend_from_slice(&(new_dicts.len() as u32).to_le_bytes());
+    for dict in new_dicts.iter() {
+        record_push_str(&mut undo, dict);
+    }
+    undo.extend_from_slice(&key_count.to_le_bytes());
+    undo.append(&mut keys);
+    // murmur3 works on whole words
+    while undo.len() % 4 != 0 {
+        undo.push(0);
+    }
+    let checksum = murmur3_32(&undo, TX_CHECKSUM_SEED);
+    undo.extend_from_slice(&checksum.to_le_bytes());
+
+    basis_cache.key_update(hw, TX_DICT, TX_UNDO_KEY, &undo, None, None, Some(basis_name), true)?;
+    basis_cache.sync(hw, Some(basis_name))?;
+    Ok(undo)
+}
+
+/// Applies the operations of a transaction whose undo record has been committed.
+pub(crate) fn transaction_apply(hw: &mut PddbOs, basis_cache: &mut BasisCache, basis_name: &str, ops: &[TxOp]) -> Result<()> {
+    for op in ops.iter() {
+        match op {
+            TxOp::Write{dict, key, data, reserved} => basis_cache.key_update(hw, dict, key, data, None, *reserved, Some(basis_name), true)?,
+            TxOp::Delete{dict, key} => basis_cache.key_remove(hw, dict, key, Some(basis_name), false)?,
+            TxOp::AddDict{dict} => match basis_cache.dict_add(hw, dict, Some(basis_name)) {
+                Err(e) if e.kind() != ErrorKind::AlreadyExists => return Err(e),
+                _ => (),
+            },
+        }
+    }
+    basis_cache.sync(hw, Some(basis_name))
+}
+
+/// Restores the keys listed in an undo record to their prior state.
+fn transaction_rollback(hw: &mut PddbOs, basis_cache: &mut BasisCache, basis_name: &str, undo: &[u8]) -> Result<()> {
+    let mut rd = RecordReader::new(&undo[..undo.len() - 4]);
+    let mut new_dicts = Vec::<&str>::new();
+    for _ in 0..rd.u32()? {
+        new_dicts.push(rd.str()?);
+    }
+    for _ in 0..rd.u32()? {
+        let dict = rd.str()?;
+        let key = rd.str()?;
+        if rd.bytes(1)?[0] != 0 {
+            let reserved = rd.usize()?;
+            let len = rd.usize()?;
+            let data = rd.bytes(len)?;
+            basis_cache.key_update(hw, dict, key, data, None, Some(reserved), Some(basis_name), true)?;
+        } else if !new_dicts.contains(&dict) {
+            match basis_cache.key_remove(hw, dict, key, Some(basis_name), false) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+    }
+    for dict in new_dicts {
+        match basis_cache.dict_remove(hw, dict, Some(basis_name), false) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+    }
+    basis_cache.sync(hw, Some(basis_name))
+}
+
+/// Removes the undo record, which marks the transaction as done.
+fn transaction_finish(hw: &mut PddbOs, basis_cache: &mut BasisCache, basis_name: &str) -> Result<()> {
+    basis_cache.dict_remove(hw, TX_DICT, Some(basis_name), false)?;
+    basis_cache.sync(hw, Some(basis_name))
+}
+
+fn undo_checksum_ok(undo: &[u8]) -> bool {
+    if undo.len() < 4 || undo.len() % 4 != 0 {
+        return false;
+    }
+    let (body, checksum) = undo.split_at(undo.len() - 4);
+    murmur3_32(body, TX_CHECKSUM_SEED) == u32::from_le_bytes(checksum.try_into().unwrap())
+}
@@ -4,7 +4,7 @@ use xous_ipc::Buffer;
 
 use num_traits::*;
 use std::io::{Result, Error, ErrorKind};
-use std::io::{Read, Write};
+use std::io::{Read, Write, Seek, SeekFrom};
 
 pub struct PddbKey<'a> {
     pub(crate) token: ApiToken,
@@ -70,7 +70,7 @@ impl<'a> Read for PddbKey<'a> {
                             *dst = src;
                         }
                         assert!(pbuf.len <= readlen, "More data returned than we requested");
-                        self.pos += buf.len() as u64;
+                        self.pos += pbuf.len as u64;
                         Ok(pbuf.len as usize)
                     }
                     PddbRetcode::BasisLost => Err(Error::new(ErrorKind::BrokenPipe, "Basis lost")),
@@ -143,6 +143,34 @@ impl<'a> Write for PddbKey<'a> {
     }
 }
 
+impl<'a> Seek for PddbKey<'a> {
+    /// Seeking is purely a client-side operation: the position is passed along with every read and write,
+    /// so no message is sent to the server unless the end of the key has to be looked up.
+    /// Seeking past the end of a key is allowed; a subsequent write will extend the key to that point.
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(self.pos)
+            }
+            SeekFrom::Current(delta) => (self.pos, delta),
+            SeekFrom::End(delta) => (self.attributes()?.len as u64, delta),
+        };
+        let new_pos = if delta >= 0 {
+            base.checked_add(delta as u64)
+        } else {
+            base.checked_sub(delta.unsigned_abs())
+        };
+        match new_pos {
+            Some(p) => {
+                self.pos = p;
+                Ok(self.pos)
+            }
+            None => Err(Error::new(ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position")),
+        }
+    }
+}
+
 use core::sync::atomic::Ordering;
 impl<'a> Drop for PddbKey<'a> {
     fn drop(&mut self) {
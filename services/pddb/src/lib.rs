@@ -18,12 +18,6 @@ use std::thread::JoinHandle;
 use core::sync::atomic::{AtomicU32, Ordering};
 pub(crate) static REFCOUNT: AtomicU32 = AtomicU32::new(0);
 
-#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug)]
-enum CbOp {
-    Change,
-    Quit
-}
-
 /// The intention is that one Pddb management object is made per process, and this serves
 /// as the gateway for parcelling out PddbKey objects, which are the equivalent of a File
 /// in a convention system that implements read/write operations.
@@ -46,13 +40,18 @@ pub struct Pddb {
     /// in the case of a basis change. Basis changes are thought to be rare; so, big changes
     /// like this are probably OK.
     keys: Arc<Mutex<HashMap<ApiToken, Box<dyn Fn() + 'static + Send> >>>,
-    trng: trng::Trng,
+    /// event subscriptions, by the ID the server assigned: the CID/opcode pair that the notifications are forwarded to
+    subscriptions: Arc<Mutex<HashMap<u32, (CID, u32)>>>,
+    /// the server that receives the notifications for all of our subscriptions, and a connection to it. It is
+    /// started with the first subscription, and stopped once the last one is cancelled, so the PDDB holds one
+    /// connection for us, however many subscriptions we make.
+    events: Option<(SID, CID)>,
 }
 impl Pddb {
-    pub fn new() -> Self {
-        REFCOUNT.fetch_add(1, Ordering::Relaxed);
+    pub fn new() -> Result<Self, xous::Error> {
         let xns = xous_names::XousNames::new().unwrap();
-        let conn = xns.request_connection_blocking(api::SERVER_NAME_PDDB).expect("can't connect to Pddb server");
+        let conn = xns.request_connection_blocking(api::SERVER_NAME_PDDB)?;
+        REFCOUNT.fetch_add(1, Ordering::Relaxed);
         let sid = xous::create_server().unwrap();
         let keys: Arc<Mutex<HashMap<ApiToken, Box<dyn Fn() + 'static + Send> >>> = Arc::new(Mutex::new(HashMap::new()));
         let handle = thread::spawn({
@@ -74,19 +73,21 @@ impl Pddb {
                             xous::return_scalar(msg.sender, 0).unwrap();
                             break;
                         },
+                        Some(CbOp::Ping) => (),
                         _ =>log::warn!("Got unknown opcode: {:?}", msg),
                     }
                 }
                 xous::destroy_server(sid).unwrap();
             }
         });
-        Pddb {
+        Ok(Pddb {
             conn,
             cb_sid: sid,
             cb_handle: Some(handle),
             keys,
-            trng: trng::Trng::new(&xns).unwrap(),
-        }
+            subscriptions: Arc::new(Mutex::new(HashMap::new())),
+            events: None,
+        })
     }
     pub fn is_mounted(&self) -> bool {
         let ret = send_message(self.conn, Message::new_blocking_scalar(
@@ -175,6 +176,9 @@ impl Pddb {
             }
         }
     }
+    /// Unlocks a basis. If `policy` is `None`, the basis stays unlocked until it is explicitly locked.
+    /// With `TimeOutSecs`, the basis locks itself once it has gone that many seconds without being accessed;
+    /// when that happens, the `key_changed_cb` of every key handle that could be affected is called.
     pub fn unlock_basis(&self, basis_name: &str, policy: Option<BasisRetentionPolicy>) -> Result<()> {
         if basis_name.len() > BASIS_NAME_LEN - 1 {
             return Err(Error::new(ErrorKind::InvalidInput, "basis name too long"));
@@ -276,6 +280,7 @@ impl Pddb {
             result: PddbRequestCode::Uninit,
             cb_sid: self.cb_sid.to_array(),
             alloc_hint: if let Some(a) = alloc_hint {Some(a as u64)} else {None},
+            paranoid: false,
         };
         let mut buf = Buffer::into_buf(request)
             .or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
@@ -318,8 +323,9 @@ impl Pddb {
         }
     }
 
-    /// deletes a key within the dictionary
-    pub fn delete_key(&mut self, dict_name: &str, key_name: &str, basis_name: Option<&str>) -> Result<()> {
+    /// deletes a key within the dictionary. If `paranoid` is set, the key's data is overwritten on disk
+    /// before this call returns; otherwise, the data is overwritten at some point after the next sync.
+    pub fn delete_key(&mut self, dict_name: &str, key_name: &str, basis_name: Option<&str>, paranoid: bool) -> Result<()> {
         if key_name.len() > (KEY_NAME_LEN - 1) {
             return Err(Error::new(ErrorKind::InvalidInput, "key name too long"));
         }
@@ -346,6 +352,7 @@ impl Pddb {
             result: PddbRequestCode::Uninit,
             cb_sid: self.cb_sid.to_array(),
             alloc_hint: None,
+            paranoid,
         };
         let mut buf = Buffer::into_buf(request)
             .or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
@@ -356,11 +363,13 @@ impl Pddb {
         match response.result {
             PddbRequestCode::NoErr => Ok(()),
             PddbRequestCode::NotFound => Err(Error::new(ErrorKind::NotFound, "Dictionary or key was not found")),
+            PddbRequestCode::NoFreeSpace => Err(Error::new(ErrorKind::OutOfMemory, "No free space to erase data")),
             _ => Err(Error::new(ErrorKind::Other, "Internal error"))
         }
     }
-    /// deletes the entire dictionary
-    pub fn delete_dict(&mut self, dict_name: &str, basis_name: Option<&str>) -> Result<()> {
+    /// deletes the entire dictionary. The dictionary's pages are always overwritten with noise as they are
+    /// freed; setting `paranoid` additionally erases each key individually before the dictionary is removed.
+    pub fn delete_dict(&mut self, dict_name: &str, basis_name: Option<&str>, paranoid: bool) -> Result<()> {
         if dict_name.len() > (DICT_NAME_LEN - 1) {
             return Err(Error::new(ErrorKind::InvalidInput, "dictionary name too long"));
         }
@@ -384,141 +393,556 @@ impl Pddb {
             result: PddbRequestCode::Uninit,
             cb_sid: self.cb_sid.to_array(),
             alloc_hint: None,
+            paranoid,
         };
         let mut buf = Buffer::into_buf(request)
             .or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
-        buf.lend_mut(self.conn, Opcode::DeleteKey.to_u32().unwrap())
+        buf.lend_mut(self.conn, Opcode::DeleteDict.to_u32().unwrap())
             .or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
 
         let response = buf.to_original::<PddbKeyRequest, _>().unwrap();
         match response.result {
             PddbRequestCode::NoErr => Ok(()),
             PddbRequestCode::NotFound => Err(Error::new(ErrorKind::NotFound, "Dictionary or key was not found")),
+            PddbRequestCode::NoFreeSpace => Err(Error::new(ErrorKind::OutOfMemory, "No free space to erase data")),
             _ => Err(Error::new(ErrorKind::Other, "Internal error"))
         }
     }
 
+    /// Returns the names of all the keys in `dict_name`, in sorted order. If `basis_name` is None, the
+    /// union of the keys across all the open bases is returned.
     pub fn list_keys(&mut self, dict_name: &str, basis_name: Option<&str>) -> Result<Vec::<String>> {
+        self.list_keys_matching(dict_name, None, basis_name)
+    }
+    /// Returns the names of all the keys in `dict_name` that match `filter`, in sorted order. A filter
+    /// containing `*` (any run of characters) or `?` (any single character) is a glob that has to match the
+    /// whole key name; any other filter is a prefix. The filtering is done by the server.
+    pub fn list_keys_matching(&mut self, dict_name: &str, filter: Option<&str>, basis_name: Option<&str>) -> Result<Vec::<String>> {
+        let mut key_list = Vec::<String>::new();
+        loop {
+            let (page, total) = self.list_keys_page(dict_name, filter, basis_name, key_list.len())?;
+            if page.len() == 0 {
+                break;
+            }
+            key_list.extend(page);
+            if key_list.len() >= total {
+                break;
+            }
+        }
+        Ok(key_list)
+    }
+    /// Returns one page of the names of the keys in `dict_name` that match `filter`, starting at the
+    /// `start`th name in sorted order, along with the total number of names that match. Pass `start`
+    /// plus the length of the page to get the next page. This avoids holding the whole listing of a
+    /// large dictionary in memory at once.
+    pub fn list_keys_page(&self, dict_name: &str, filter: Option<&str>, basis_name: Option<&str>, start: usize) -> Result<(Vec::<String>, usize)> {
         if dict_name.len() > (DICT_NAME_LEN - 1) {
             return Err(Error::new(ErrorKind::InvalidInput, "dictionary name too long"));
         }
-        let bname = if let Some(bname) = basis_name {
+        self.list_page(Opcode::ListKeyPage, dict_name, filter, basis_name, start)
+    }
+
+    /// Returns the names of all the dictionaries, in sorted order. If `basis_name` is None, the union of
+    /// the dictionaries across all the open bases is returned.
+    pub fn list_dict(&mut self, basis_name: Option<&str>) -> Result<Vec::<String>> {
+        self.list_dict_matching(None, basis_name)
+    }
+    /// Returns the names of all the dictionaries that match `filter`, in sorted order. See `list_keys_matching()`
+    /// for the filter syntax.
+    pub fn list_dict_matching(&mut self, filter: Option<&str>, basis_name: Option<&str>) -> Result<Vec::<String>> {
+        let mut dict_list = Vec::<String>::new();
+        loop {
+            let (page, total) = self.list_dict_page(filter, basis_name, dict_list.len())?;
+            if page.len() == 0 {
+                break;
+            }
+            dict_list.extend(page);
+            if dict_list.len() >= total {
+                break;
+            }
+        }
+        Ok(dict_list)
+    }
+    /// Returns one page of the names of the dictionaries that match `filter`. See `list_keys_page()`.
+    pub fn list_dict_page(&self, filter: Option<&str>, basis_name: Option<&str>, start: usize) -> Result<(Vec::<String>, usize)> {
+        self.list_page(Opcode::ListDictPage, "", filter, basis_name, start)
+    }
+    fn list_page(&self, op: Opcode, dict_name: &str, filter: Option<&str>, basis_name: Option<&str>, start: usize) -> Result<(Vec::<String>, usize)> {
+        if let Some(bname) = basis_name {
             if bname.len() > BASIS_NAME_LEN - 1 {
                 return Err(Error::new(ErrorKind::InvalidInput, "basis name too long"));
             }
-            xous_ipc::String::<BASIS_NAME_LEN>::from_str(bname)
-        } else {
-            xous_ipc::String::<BASIS_NAME_LEN>::new()
-        };
-        // this is a two-phase query, because it's quite likely that the number of keys can be very large in a dict.
-        let token = [self.trng.get_u32().unwrap(), self.trng.get_u32().unwrap(), self.trng.get_u32().unwrap(), self.trng.get_u32().unwrap()];
-        let request = PddbDictRequest {
+        }
+        let filter = filter.unwrap_or("");
+        if filter.len() > DICT_NAME_LEN - 1 {
+            return Err(Error::new(ErrorKind::InvalidInput, "filter too long"));
+        }
+        let request = PddbListRequest {
             basis_specified: basis_name.is_some(),
-            basis: xous_ipc::String::<BASIS_NAME_LEN>::from_str(&bname),
+            basis: xous_ipc::String::<BASIS_NAME_LEN>::from_str(basis_name.unwrap_or("")),
             dict: xous_ipc::String::<DICT_NAME_LEN>::from_str(dict_name),
-            key: xous_ipc::String::<KEY_NAME_LEN>::new(),
-            index: 0,
+            filter: xous_ipc::String::<DICT_NAME_LEN>::from_str(filter),
+            start: start as u32,
+            total: 0,
+            count: 0,
+            data: [0u8; LIST_PAGE_LEN],
             code: PddbRequestCode::Uninit,
-            token,
         };
         let mut buf = Buffer::into_buf(request)
             .or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
-        buf.lend_mut(self.conn, Opcode::KeyCountInDict.to_u32().unwrap())
+        buf.lend_mut(self.conn, op.to_u32().unwrap())
             .or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
-
-        let response = buf.to_original::<PddbDictRequest, _>().unwrap();
-        let count = match response.code {
-            PddbRequestCode::NoErr => response.index,
+        let response = buf.to_original::<PddbListRequest, _>().unwrap();
+        match response.code {
+            PddbRequestCode::NoErr => (),
+            PddbRequestCode::NotFound => return Err(Error::new(ErrorKind::NotFound, "Dictionary not found")),
             _ => return Err(Error::new(ErrorKind::Other, "Internal error")),
-        };
-        // very non-optimal, slow way of doing this, but let's just get it working first and optimize later.
-        // it's absolutely important that you access every entry, and the highest index last, because
-        // that is how the server knows you've finished with the list-out.
-        let mut key_list = Vec::<String>::new();
-        for index in 0..count {
-            let request = PddbDictRequest {
-                basis_specified: basis_name.is_some(),
-                basis: xous_ipc::String::<BASIS_NAME_LEN>::from_str(&bname),
-                dict: xous_ipc::String::<DICT_NAME_LEN>::from_str(dict_name),
-                key: xous_ipc::String::<KEY_NAME_LEN>::new(),
-                index,
-                code: PddbRequestCode::Uninit,
-                token,
-            };
-            let mut buf = Buffer::into_buf(request)
-                .or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
-            buf.lend_mut(self.conn, Opcode::GetKeyNameAtIndex.to_u32().unwrap())
-                .or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
-            let response = buf.to_original::<PddbDictRequest, _>().unwrap();
-            match response.code {
-                PddbRequestCode::NoErr => key_list.push(String::from(response.key.as_str().expect("utf-8 parse error in key name"))),
-                _ => return Err(Error::new(ErrorKind::Other, "Internal error")),
-            }
         }
-        Ok(key_list)
+        let mut names = Vec::<String>::new();
+        let mut offset = 0;
+        for _ in 0..response.count {
+            let len = response.data[offset] as usize;
+            names.push(String::from(std::str::from_utf8(&response.data[offset + 1..offset + 1 + len])
+                .or(Err(Error::new(ErrorKind::InvalidData, "utf-8 parse error in name")))?));
+            offset += 1 + len;
+        }
+        Ok((names, response.total as usize))
     }
 
-
-    pub fn list_dict(&mut self, basis_name: Option<&str>) -> Result<Vec::<String>> {
-        let bname = if let Some(bname) = basis_name {
-            if bname.len() > BASIS_NAME_LEN - 1 {
-                return Err(Error::new(ErrorKind::InvalidInput, "basis name too long"));
+    /// Defragments the dictionaries in all the open bases. Key descriptors are packed together and
+    /// small keys are consolidated into as few pages as possible, returning the emptied pages to the
+    /// free space pool. This can take a while on a large database, and is generally only worth doing
+    /// after a lot of keys have been deleted. Returns the number of pages that were freed.
+    pub fn compact(&self) -> Result<usize> {
+        let response = send_message(
+            self.conn,
+            Message::new_blocking_scalar(Opcode::Compact.to_usize().unwrap(), 0, 0, 0, 0)
+        ).or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        if let xous::Result::Scalar2(rcode, freed) = response {
+            match FromPrimitive::from_u8(rcode as u8) {
+                Some(PddbRetcode::Ok) => Ok(freed),
+                Some(PddbRetcode::BasisLost) => Err(Error::new(ErrorKind::BrokenPipe, "Basis lost")),
+                Some(PddbRetcode::DiskFull) => Err(Error::new(ErrorKind::OutOfMemory, "Out of disk space, compaction not completed")),
+                _ => Err(Error::new(ErrorKind::Interrupted, "Compaction failed for unspecified reasons")),
             }
-            xous_ipc::String::<BASIS_NAME_LEN>::from_str(bname)
         } else {
-            xous_ipc::String::<BASIS_NAME_LEN>::new()
+            Err(Error::new(ErrorKind::Other, "Xous internal error"))
+        }
+    }
+    /// Checks the consistency of all the open bases: every page table entry is verified against its page,
+    /// and the dictionaries are walked to find pages that are mapped but unused, and keys whose data is
+    /// missing. If `repair` is set, the problems found are fixed; note that data on pages that fail to
+    /// decrypt is lost in the process. Closed bases can't be checked. This can take a long time on a large database.
+    pub fn check(&self, repair: bool) -> Result<PddbCheckReport> {
+        let req = PddbCheckReport {
+            repair,
+            bases: 0,
+            pages: 0,
+            bad_pages: 0,
+            stale_ptes: 0,
+            orphaned_pages: 0,
+            dangling_keys: 0,
+            fastspace_conflicts: 0,
+            code: PddbRequestCode::Uninit,
         };
-        // this is a two-phase query, because it's quite likely that the number of keys can be very large in a dict.
-        let token = [self.trng.get_u32().unwrap(), self.trng.get_u32().unwrap(), self.trng.get_u32().unwrap(), self.trng.get_u32().unwrap()];
-        let request = PddbDictRequest {
-            basis_specified: basis_name.is_some(),
-            basis: xous_ipc::String::<BASIS_NAME_LEN>::from_str(&bname),
-            dict: xous_ipc::String::<DICT_NAME_LEN>::new(),
-            key: xous_ipc::String::<KEY_NAME_LEN>::new(),
-            index: 0,
+        let mut buf = Buffer::into_buf(req).or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        buf.lend_mut(self.conn, Opcode::Check.to_u32().unwrap()).or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        let ret = buf.to_original::<PddbCheckReport, _>().or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        match ret.code {
+            PddbRequestCode::NoErr => Ok(ret),
+            PddbRequestCode::NoFreeSpace => Err(Error::new(ErrorKind::OutOfMemory, "Out of disk space, repair not completed")),
+            _ => Err(Error::new(ErrorKind::Other, "Internal error checking the PDDB")),
+        }
+    }
+    /// Exports the dictionaries and keys of `basis_name`, which must be mounted, into a portable archive
+    /// that is encrypted with `passphrase`. The archive does not depend on the device's keys, so it can be
+    /// restored on any other device with `import_basis()`. Archives are limited to `MAX_STAGING_LEN` bytes.
+    pub fn export_basis(&self, basis_name: &str, passphrase: &str) -> Result<Vec<u8>> {
+        if basis_name.len() > BASIS_NAME_LEN - 1 {
+            return Err(Error::new(ErrorKind::InvalidInput, "basis name too long"));
+        }
+        if passphrase.len() > PASSWORD_LEN - 1 {
+            return Err(Error::new(ErrorKind::InvalidInput, "passphrase too long"));
+        }
+        let req = PddbArchiveRequest {
+            basis: xous_ipc::String::<BASIS_NAME_LEN>::from_str(basis_name),
+            passphrase: xous_ipc::String::<PASSWORD_LEN>::from_str(passphrase),
+            cb_sid: self.cb_sid.to_array(),
+            token: [0; 3],
+            len: 0,
+            count: 0,
+            code: PddbRequestCode::Uninit,
+        };
+        let mut buf = Buffer::into_buf(req).or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        buf.lend_mut(self.conn, Opcode::ArchiveExport.to_u32().unwrap()).or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        let ret = buf.to_original::<PddbArchiveRequest, _>().or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        buf.volatile_clear();
+        match ret.code {
+            PddbRequestCode::NoErr => (),
+            PddbRequestCode::NotFound => return Err(Error::new(ErrorKind::NotFound, "Basis not found")),
+            PddbRequestCode::NoFreeSpace => return Err(Error::new(ErrorKind::OutOfMemory, "Basis is too large to export")),
+            _ => return Err(Error::new(ErrorKind::Other, "Internal error exporting basis")),
+        }
+        let mut archive = Vec::<u8>::with_capacity(ret.len as usize);
+        let mut xfer = Buffer::new(core::mem::size_of::<PddbBuf>());
+        let result = loop {
+            if archive.len() as u64 >= ret.len {
+                break Ok(());
+            }
+            {
+                let pbuf = PddbBuf::from_slice_mut(xfer.as_mut());
+                pbuf.token = ret.token;
+                pbuf.position = archive.len() as u64;
+                pbuf.len = pbuf.data.len() as u16;
+                pbuf.retcode = PddbRetcode::Uninit;
+            }
+            if xfer.lend_mut(self.conn, Opcode::ArchiveRead.to_u32().unwrap()).is_err() {
+                break Err(Error::new(ErrorKind::Other, "Xous internal error"));
+            }
+            let pbuf = PddbBuf::from_slice_mut(xfer.as_mut());
+            match pbuf.retcode {
+                PddbRetcode::Ok if pbuf.len > 0 => archive.extend_from_slice(&pbuf.data[..pbuf.len as usize]),
+                _ => break Err(Error::new(ErrorKind::UnexpectedEof, "Archive transfer failed")),
+            }
+        };
+        self.archive_close(ret.token)?;
+        result.map(|_| archive)
+    }
+    /// Decrypts an archive made by `export_basis()` with `passphrase`, and writes its dictionaries and keys into
+    /// `basis_name`. The basis has to be created and unlocked beforehand; it does not need to be the basis the
+    /// archive was made from. Keys that already exist in the basis are overwritten. Returns the number of keys imported.
+    pub fn import_basis(&self, basis_name: &str, passphrase: &str, archive: &[u8]) -> Result<usize> {
+        if basis_name.len() > BASIS_NAME_LEN - 1 {
+            return Err(Error::new(ErrorKind::InvalidInput, "basis name too long"));
+        }
+        if passphrase.len() > PASSWORD_LEN - 1 {
+            return Err(Error::new(ErrorKind::InvalidInput, "passphrase too long"));
+        }
+        if archive.len() > MAX_STAGING_LEN {
+            return Err(Error::new(ErrorKind::OutOfMemory, "archive too large"));
+        }
+        // stage the archive on the server side. The passphrase is only sent along once the archive is complete.
+        let req = PddbArchiveRequest {
+            basis: xous_ipc::String::<BASIS_NAME_LEN>::new(),
+            passphrase: xous_ipc::String::<PASSWORD_LEN>::new(),
+            cb_sid: self.cb_sid.to_array(),
+            token: [0; 3],
+            len: archive.len() as u64,
+            count: 0,
             code: PddbRequestCode::Uninit,
+        };
+        let mut buf = Buffer::into_buf(req).or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        buf.lend_mut(self.conn, Opcode::ArchiveImport.to_u32().unwrap()).or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        let ret = buf.to_original::<PddbArchiveRequest, _>().or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        match ret.code {
+            PddbRequestCode::NoErr => (),
+            PddbRequestCode::NoFreeSpace => return Err(Error::new(ErrorKind::OutOfMemory, "No room to stage the archive, try again later")),
+            _ => return Err(Error::new(ErrorKind::Other, "Internal error staging archive")),
+        }
+        let token = ret.token;
+        let mut xfer = Buffer::new(core::mem::size_of::<PddbBuf>());
+        let mut position = 0;
+        for chunk in archive.chunks(PddbBuf::from_slice_mut(xfer.as_mut()).data.len()) {
+            {
+                let pbuf = PddbBuf::from_slice_mut(xfer.as_mut());
+                pbuf.token = token;
+                pbuf.position = position as u64;
+                pbuf.len = chunk.len() as u16;
+                pbuf.retcode = PddbRetcode::Uninit;
+                pbuf.data[..chunk.len()].copy_from_slice(chunk);
+            }
+            let sent = xfer.lend_mut(self.conn, Opcode::ArchiveWrite.to_u32().unwrap()).is_ok();
+            match PddbBuf::from_slice_mut(xfer.as_mut()).retcode {
+                PddbRetcode::Ok if sent => position += chunk.len(),
+                _ => {
+                    self.archive_close(token)?;
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "Archive transfer failed"));
+                }
+            }
+        }
+        // the server releases its copy of the archive once the commit is done, whether or not it succeeded
+        let req = PddbArchiveRequest {
+            basis: xous_ipc::String::<BASIS_NAME_LEN>::from_str(basis_name),
+            passphrase: xous_ipc::String::<PASSWORD_LEN>::from_str(passphrase),
+            cb_sid: self.cb_sid.to_array(),
             token,
+            len: archive.len() as u64,
+            count: 0,
+            code: PddbRequestCode::Uninit,
         };
-        let mut buf = Buffer::into_buf(request)
-            .or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
-        buf.lend_mut(self.conn, Opcode::DictCountInBasis.to_u32().unwrap())
+        let mut buf = Buffer::into_buf(req).or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        buf.lend_mut(self.conn, Opcode::ArchiveCommit.to_u32().unwrap()).or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        let ret = buf.to_original::<PddbArchiveRequest, _>().or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        buf.volatile_clear();
+        match ret.code {
+            PddbRequestCode::NoErr => Ok(ret.count as usize),
+            PddbRequestCode::NotFound => Err(Error::new(ErrorKind::NotFound, "Basis not found")),
+            PddbRequestCode::AccessDenied => Err(Error::new(ErrorKind::PermissionDenied, "Wrong passphrase, or the archive is corrupted")),
+            PddbRequestCode::NoFreeSpace => Err(Error::new(ErrorKind::OutOfMemory, "No free space to import the archive")),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Archive could not be imported")),
+        }
+    }
+    fn archive_close(&self, token: ApiToken) -> Result<()> {
+        send_message(self.conn,
+            Message::new_blocking_scalar(Opcode::ArchiveClose.to_usize().unwrap(),
+            token[0] as usize, token[1] as usize, token[2] as usize, 0)
+        ).or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        Ok(())
+    }
+    /// Subscribes to `PddbEvent`s. Notifications are delivered as a memory message with `opcode` to `return_cid`,
+    /// holding a `PddbEventReport` that names the basis, dict and key concerned, and carries the subscription ID
+    /// returned by this call. Bases being mounted and unmounted are always reported. Changes to dicts and keys are
+    /// reported if they match the `dict_name` and `key_name` filters; a filter that is `None` matches everything.
+    ///
+    /// Key writes are reported when the writer flushes or drops its `PddbKey`, not on every write. A process can
+    /// hold up to `MAX_SUBSCRIPTIONS_PER_PROCESS` subscriptions at once, and the PDDB notifies at most
+    /// `MAX_SUBSCRIBERS` `Pddb` objects; past either limit, this fails with `ErrorKind::OutOfMemory`.
+    pub fn subscribe(&mut self, return_cid: CID, opcode: u32, dict_name: Option<&str>, key_name: Option<&str>) -> Result<u32> {
+        if dict_name.unwrap_or("").len() > (DICT_NAME_LEN - 1) {
+            return Err(Error::new(ErrorKind::InvalidInput, "dictionary name too long"));
+        }
+        if key_name.unwrap_or("").len() > (KEY_NAME_LEN - 1) {
+            return Err(Error::new(ErrorKind::InvalidInput, "key name too long"));
+        }
+        let events_sid = match self.events {
+            Some((sid, _)) => sid,
+            None => self.events_start()?,
+        };
+        let sub = PddbSubscription {
+            sid: events_sid.to_array(),
+            id: 0,
+            dict_specified: dict_name.is_some(),
+            dict: xous_ipc::String::<DICT_NAME_LEN>::from_str(dict_name.unwrap_or("")),
+            key_specified: key_name.is_some(),
+            key: xous_ipc::String::<KEY_NAME_LEN>::from_str(key_name.unwrap_or("")),
+            code: PddbRequestCode::Uninit,
+        };
+        let mut buf = Buffer::into_buf(sub)
             .or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        // the first notification can arrive before we know the ID it's for, so hold the routes until we do
+        let mut routes = self.subscriptions.lock().unwrap();
+        let result = match buf.lend_mut(self.conn, Opcode::Subscribe.to_u32().unwrap()) {
+            Ok(_) => {
+                let response = buf.to_original::<PddbSubscription, _>().unwrap();
+                match response.code {
+                    PddbRequestCode::NoErr => {
+                        routes.insert(response.id, (return_cid, opcode));
+                        Ok(response.id)
+                    }
+                    PddbRequestCode::NoFreeSpace => Err(Error::new(ErrorKind::OutOfMemory, "Too many subscriptions")),
+                    _ => Err(Error::new(ErrorKind::Other, "Internal error")),
+                }
+            }
+            Err(_) => Err(Error::new(ErrorKind::Other, "Xous internal error")),
+        };
+        drop(routes);
+        if result.is_err() {
+            self.events_stop_if_idle();
+        }
+        result
+    }
+    /// Starts the server that receives the notifications for our subscriptions, and forwards each to the
+    /// CID/opcode pair of the subscription it's for
+    fn events_start(&mut self) -> Result<SID> {
+        let sid = xous::create_server().or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        let _ = thread::spawn({
+            let routes = Arc::clone(&self.subscriptions);
+            let sid = sid.clone();
+            move || {
+                loop {
+                    let msg = xous::receive_message(sid).unwrap();
+                    match FromPrimitive::from_usize(msg.body.id()) {
+                        Some(SubscriptionCb::Event) => {
+                            let buffer = unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                            let report = buffer.to_original::<PddbEventReport, _>().unwrap();
+                            // a notification can cross paths with the cancellation of its subscription
+                            if let Some(&(return_cid, opcode)) = routes.lock().unwrap().get(&report.subscription) {
+                                let buf = Buffer::into_buf(report).expect("couldn't forward PDDB event");
+                                buf.send(return_cid, opcode).expect("couldn't forward PDDB event");
+                            }
+                        }
+                        Some(SubscriptionCb::Drop) => {
+                            xous::return_scalar(msg.sender, 1).unwrap();
+                            break;
+                        }
+                        _ => log::error!("got unknown opcode: {:?}", msg),
+                    }
+                }
+                xous::destroy_server(sid).unwrap();
+            }
+        });
+        let cid = xous::connect(sid).or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        self.events = Some((sid, cid));
+        Ok(sid)
+    }
+    /// Stops the notification server, if no subscription is left that reports to it
+    fn events_stop_if_idle(&mut self) {
+        if self.subscriptions.lock().unwrap().is_empty() {
+            if let Some((_sid, cid)) = self.events.take() {
+                send_message(cid, Message::new_blocking_scalar(SubscriptionCb::Drop.to_usize().unwrap(), 0, 0, 0, 0)).ok();
+                unsafe{xous::disconnect(cid).ok()};
+            }
+        }
+    }
+    /// Starts a transaction on `basis_name`, or on the most recently unlocked basis if `None`. Writes and
+    /// deletes staged on the transaction are applied together by `PddbTransaction::commit()`.
+    pub fn transaction(&self, basis_name: Option<&str>) -> Result<PddbTransaction> {
+        if basis_name.unwrap_or("").len() > BASIS_NAME_LEN - 1 {
+            return Err(Error::new(ErrorKind::InvalidInput, "basis name too long"));
+        }
+        Ok(PddbTransaction {
+            pddb: self,
+            basis: basis_name.map(String::from),
+            record: Vec::new(),
+            count: 0,
+        })
+    }
+    /// Cancels a subscription made with `subscribe()`. If there is no such subscription, returns without error.
+    pub fn unsubscribe(&mut self, id: u32) -> Result<()> {
+        let removed = self.subscriptions.lock().unwrap().remove(&id).is_some();
+        if removed {
+            let result = send_message(self.conn,
+                Message::new_blocking_scalar(Opcode::Unsubscribe.to_usize().unwrap(), id as usize, 0, 0, 0)
+            ).map(|_| ()).or(Err(Error::new(ErrorKind::Other, "Xous internal error")));
+            // the PDDB lets go of the notification server along with its last subscription, so it can go now
+            self.events_stop_if_idle();
+            return result;
+        }
+        Ok(())
+    }
+}
 
-        let response = buf.to_original::<PddbDictRequest, _>().unwrap();
-        let count = match response.code {
-            PddbRequestCode::NoErr => response.index,
-            _ => return Err(Error::new(ErrorKind::Other, "Internal error")),
+/// A set of key writes and deletes, across any number of dictionaries in one basis, that is applied atomically:
+/// if power is lost while the transaction is being committed, none of its operations take effect.
+///
+/// Operations are only staged locally until `commit()` is called, so the keys are unchanged until then, and
+/// dropping a transaction without committing it simply discards it. Staged data is wiped when the transaction
+/// is dropped.
+pub struct PddbTransaction<'a> {
+    pddb: &'a Pddb,
+    basis: Option<String>,
+    /// the staged operations, encoded as described on `PddbTransactionOp`
+    record: Vec<u8>,
+    count: u32,
+}
+impl<'a> PddbTransaction<'a> {
+    /// Stages replacing the contents of `key_name` with `data`. The key and its dictionary are created if needed.
+    pub fn write(&mut self, dict_name: &str, key_name: &str, data: &[u8]) -> Result<()> {
+        self.push(PddbTransactionOp::Write, dict_name, key_name)?;
+        self.record.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        self.record.extend_from_slice(data);
+        Ok(())
+    }
+    /// Stages removing `key_name`. The commit fails with `NotFound` if the key doesn't exist at that point.
+    pub fn delete(&mut self, dict_name: &str, key_name: &str) -> Result<()> {
+        self.push(PddbTransactionOp::Delete, dict_name, key_name)
+    }
+    /// The number of operations staged so far
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+    fn push(&mut self, op: PddbTransactionOp, dict_name: &str, key_name: &str) -> Result<()> {
+        if key_name.len() == 0 || key_name.len() > (KEY_NAME_LEN - 1) {
+            return Err(Error::new(ErrorKind::InvalidInput, "key name has an invalid length"));
+        }
+        if dict_name.len() == 0 || dict_name.len() > (DICT_NAME_LEN - 1) {
+            return Err(Error::new(ErrorKind::InvalidInput, "dictionary name has an invalid length"));
+        }
+        self.record.push(op.to_u8().unwrap());
+        self.record.push(dict_name.len() as u8);
+        self.record.extend_from_slice(dict_name.as_bytes());
+        self.record.push(key_name.len() as u8);
+        self.record.extend_from_slice(key_name.as_bytes());
+        self.count += 1;
+        Ok(())
+    }
+    /// Applies all the staged operations. Either all of them take effect, or, if an error is returned or
+    /// power is lost before the commit is done, none of them do.
+    pub fn commit(self) -> Result<()> {
+        if self.count == 0 {
+            return Ok(());
+        }
+        if self.record.len() > MAX_STAGING_LEN {
+            return Err(Error::new(ErrorKind::OutOfMemory, "transaction too large"));
+        }
+        let conn = self.pddb.conn;
+        let req = PddbTransactionRequest {
+            basis_specified: self.basis.is_some(),
+            basis: xous_ipc::String::<BASIS_NAME_LEN>::from_str(self.basis.as_deref().unwrap_or("")),
+            cb_sid: self.pddb.cb_sid.to_array(),
+            token: [0; 3],
+            len: self.record.len() as u64,
+            count: self.count,
+            code: PddbRequestCode::Uninit,
         };
-        // very non-optimal, slow way of doing this, but let's just get it working first and optimize later.
-        // it's absolutely important that you access every entry, and the highest index last, because
-        // that is how the server knows you've finished with the list-out.
-        let mut dict_list = Vec::<String>::new();
-        for index in 0..count {
-            let request = PddbDictRequest {
-                basis_specified: basis_name.is_some(),
-                basis: xous_ipc::String::<BASIS_NAME_LEN>::from_str(&bname),
-                dict: xous_ipc::String::<DICT_NAME_LEN>::new(),
-                key: xous_ipc::String::<KEY_NAME_LEN>::new(),
-                index,
-                code: PddbRequestCode::Uninit,
-                token,
-            };
-            let mut buf = Buffer::into_buf(request)
-                .or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
-            buf.lend_mut(self.conn, Opcode::GetDictNameAtIndex.to_u32().unwrap())
-                .or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
-            let response = buf.to_original::<PddbDictRequest, _>().unwrap();
-            match response.code {
-                PddbRequestCode::NoErr => dict_list.push(String::from(response.dict.as_str().expect("utf-8 parse error in key name"))),
-                _ => return Err(Error::new(ErrorKind::Other, "Internal error")),
+        let mut buf = Buffer::into_buf(req).or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        buf.lend_mut(conn, Opcode::TransactionBegin.to_u32().unwrap()).or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        let mut req = buf.to_original::<PddbTransactionRequest, _>().or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        match req.code {
+            PddbRequestCode::NoErr => (),
+            PddbRequestCode::NoFreeSpace => return Err(Error::new(ErrorKind::OutOfMemory, "No room to stage the transaction, try again later")),
+            _ => return Err(Error::new(ErrorKind::Other, "Internal error staging transaction")),
+        }
+        let token = req.token;
+        let mut xfer = Buffer::new(core::mem::size_of::<PddbBuf>());
+        let mut position = 0;
+        for chunk in self.record.chunks(PddbBuf::from_slice_mut(xfer.as_mut()).data.len()) {
+            {
+                let pbuf = PddbBuf::from_slice_mut(xfer.as_mut());
+                pbuf.token = token;
+                pbuf.position = position as u64;
+                pbuf.len = chunk.len() as u16;
+                pbuf.retcode = PddbRetcode::Uninit;
+                pbuf.data[..chunk.len()].copy_from_slice(chunk);
+            }
+            let sent = xfer.lend_mut(conn, Opcode::TransactionWrite.to_u32().unwrap()).is_ok();
+            match PddbBuf::from_slice_mut(xfer.as_mut()).retcode {
+                PddbRetcode::Ok if sent => position += chunk.len(),
+                _ => {
+                    xfer.volatile_clear();
+                    send_message(conn,
+                        Message::new_blocking_scalar(Opcode::TransactionAbort.to_usize().unwrap(),
+                        token[0] as usize, token[1] as usize, token[2] as usize, 0)
+                    ).or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "Transaction transfer failed"));
+                }
             }
         }
-        Ok(dict_list)
+        xfer.volatile_clear();
+        // the server releases its copy of the operations once the commit is done, whether or not it succeeded
+        req.code = PddbRequestCode::Uninit;
+        let mut buf = Buffer::into_buf(req).or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        buf.lend_mut(conn, Opcode::TransactionCommit.to_u32().unwrap()).or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        let ret = buf.to_original::<PddbTransactionRequest, _>().or(Err(Error::new(ErrorKind::Other, "Xous internal error")))?;
+        match ret.code {
+            PddbRequestCode::NoErr => Ok(()),
+            PddbRequestCode::NotFound => Err(Error::new(ErrorKind::NotFound, "Basis, dictionary or key not found")),
+            PddbRequestCode::NoFreeSpace => Err(Error::new(ErrorKind::OutOfMemory, "No free space to commit the transaction")),
+            PddbRequestCode::AccessDenied => Err(Error::new(ErrorKind::BrokenPipe, "Transaction was lost by the server")),
+            _ => Err(Error::new(ErrorKind::Other, "Transaction could not be committed")),
+        }
+    }
+}
+impl<'a> Drop for PddbTransaction<'a> {
+    fn drop(&mut self) {
+        // staged data is often secret (passwords, keys), so don't leave it lying around on the heap
+        let ptr = self.record.as_mut_ptr();
+        for i in 0..self.record.len() {
+            unsafe{ptr.add(i).write_volatile(0);}
+        }
+        core::sync::atomic::compiler_fence(Ordering::SeqCst);
     }
 }
 
 impl Drop for Pddb {
     fn drop(&mut self) {
+        let ids: Vec<u32> = self.subscriptions.lock().unwrap().keys().copied().collect();
+        for id in ids {
+            self.unsubscribe(id).ok();
+        }
         let cid = xous::connect(self.cb_sid).unwrap();
         send_message(cid, Message::new_blocking_scalar(CbOp::Quit.to_usize().unwrap(), 0, 0, 0, 0)).unwrap();
         unsafe{xous::disconnect(cid).unwrap();}
@@ -372,6 +372,10 @@ mod ux;
 use ux::*;
 mod menu;
 use menu::*;
+mod subscriptions;
+use subscriptions::*;
+mod staging;
+use staging::*;
 
 #[cfg(not(any(target_os = "none", target_os = "xous")))]
 mod tests;
@@ -385,7 +389,7 @@ use xous_ipc::Buffer;
 use core::cell::RefCell;
 use std::rc::Rc;
 use std::thread;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::ErrorKind;
 use core::fmt::Write;
 
@@ -409,6 +413,65 @@ struct TokenRecord {
     pub basis: Option<String>,
     pub alloc_hint: Option<usize>,
     pub conn: xous::CID, // callback connection
+    /// set when the key is written to, so subscribers can be notified once the writer is done with it
+    pub dirty: bool,
+}
+
+/// Tells the owners of any key handles that could be affected by a basis being locked: the ones opened
+/// on that basis specifically, and the ones opened on the union of all bases.
+fn notify_basis_locked(token_dict: &HashMap<ApiToken, TokenRecord>, basis_name: &str) {
+    for (token, rec) in token_dict.iter() {
+        let affected = if let Some(basis) = &rec.basis {
+            basis == basis_name
+        } else {
+            true
+        };
+        if affected {
+            send_message(rec.conn,
+                Message::new_scalar(CbOp::Change.to_usize().unwrap(), token[0] as usize, token[1] as usize, token[2] as usize, 0)
+            ).map_err(|e| log::warn!("couldn't notify key handle of basis lock: {:?}", e)).ok();
+        }
+    }
+}
+
+/// Disconnects from a client's callback server, unless a key handle or a staged transfer still holds the
+/// connection. This matters because we can only have 32 outgoing connections...
+fn release_callback(conn: xous::CID, token_dict: &HashMap<ApiToken, TokenRecord>, staging: &Staging) {
+    if !token_dict.values().any(|r| r.conn == conn) && !staging.uses(conn) {
+        unsafe{xous::disconnect(conn).expect("couldn't disconnect from callback server")};
+    }
+}
+
+/// Starts the retention polls, if a basis with a time-out policy is mounted and they aren't running yet. Once
+/// started, each `RetentionPoll` schedules the next, for as long as such a basis stays mounted.
+fn retention_arm(retention_cid: xous::CID, armed: &mut bool, last_poll: &mut u64, basis_cache: &BasisCache, pddb_os: &PddbOs) {
+    if *armed || !basis_cache.has_time_out_policy() {
+        return;
+    }
+    *last_poll = pddb_os.timestamp_now();
+    send_message(retention_cid, Message::new_scalar(0, 0, 0, 0, 0)).expect("couldn't schedule retention poll");
+    *armed = true;
+}
+
+/// Fills `req` with the page of `names` that starts at `req.start`. The names are sorted, so that the
+/// pages line up from one request to the next, as long as the list doesn't change in between.
+fn list_page_fill(req: &mut PddbListRequest, names: HashSet<String>) {
+    let mut sorted: Vec<String> = names.into_iter().collect();
+    sorted.sort();
+    req.total = sorted.len() as u32;
+    req.count = 0;
+    let mut offset = 0;
+    for name in sorted.iter().skip(req.start as usize) {
+        let bytes = name.as_bytes();
+        if offset + 1 + bytes.len() > req.data.len() {
+            break;
+        }
+        req.data[offset] = bytes.len() as u8;
+        req.data[offset + 1..offset + 1 + bytes.len()].copy_from_slice(bytes);
+        offset += 1 + bytes.len();
+        req.count += 1;
+    }
+    req.code = PddbRequestCode::NoErr;
 }
 
 #[xous::xous_main]
@@ -435,6 +498,10 @@ fn xmain() -> ! {
     let mut basis_cache = BasisCache::new();
     // storage for the token lookup: given an ApiToken, return a dict/key/basis set. Basis can be None or specified.
     let mut token_dict = HashMap::<ApiToken, TokenRecord>::new();
+    // clients that want to be told about changes to the bases, dicts and keys
+    let mut subscribers = Subscribers::new();
+    // basis archives and transactions being streamed to or from clients
+    let mut staging = Staging::new();
 
     // run the CI tests if the option has been selected
     #[cfg(all(
@@ -479,6 +546,26 @@ fn xmain() -> ! {
             ).expect("couldn't send mount request");
         }
     });
+    // poll for the basis retention policies. Each message sent to this thread results in one `RetentionPoll`,
+    // `RETENTION_POLL_MS` later; polls are only scheduled while a basis with a time-out policy is mounted, so
+    // that an idle PDDB isn't woken up every second for nothing.
+    let retention_sid = xous::create_server().unwrap();
+    let retention_cid = xous::connect(retention_sid).unwrap();
+    let _ = thread::spawn({
+        let my_cid = my_cid.clone();
+        move || {
+            let tt = ticktimer_server::Ticktimer::new().unwrap();
+            loop {
+                let _msg = xous::receive_message(retention_sid).unwrap();
+                tt.sleep_ms(RETENTION_POLL_MS).unwrap();
+                send_message(my_cid,
+                    Message::new_scalar(Opcode::RetentionPoll.to_usize().unwrap(), 0, 0, 0, 0)
+                ).expect("couldn't send retention poll");
+            }
+        }
+    });
+    let mut retention_armed = false;
+    let mut last_retention_poll = pddb_os.timestamp_now();
     // main server loop
     let mut key_list = Vec::<String>::new(); // storage for key lists
     let mut key_token: Option<[u32; 4]> = None;
@@ -492,9 +579,28 @@ fn xmain() -> ! {
         let mut msg = xous::receive_message(pddb_sid).unwrap();
         match FromPrimitive::from_usize(msg.body.id()) {
             Some(Opcode::SuspendResume) => xous::msg_scalar_unpack!(msg, token, _, _, _, {
-                basis_cache.suspend(&mut pddb_os);
+                for basis in basis_cache.suspend(&mut pddb_os) {
+                    notify_basis_locked(&token_dict, &basis);
+                    subscribers.notify(PddbEvent::BasisUnmount, Some(&basis), None, None);
+                }
                 susres.suspend_until_resume(token).expect("couldn't execute suspend/resume");
             }),
+            Some(Opcode::RetentionPoll) => {
+                // only whole seconds are consumed; the remainder carries over to the next poll
+                let elapsed_secs = (pddb_os.timestamp_now() - last_retention_poll) / 1000;
+                if elapsed_secs > 0 {
+                    last_retention_poll += elapsed_secs * 1000;
+                    for basis in basis_cache.retention_poll(&mut pddb_os, elapsed_secs as u32) {
+                        notify_basis_locked(&token_dict, &basis);
+                        subscribers.notify(PddbEvent::BasisUnmount, Some(&basis), None, None);
+                    }
+                }
+                if basis_cache.has_time_out_policy() {
+                    send_message(retention_cid, Message::new_scalar(0, 0, 0, 0, 0)).expect("couldn't schedule retention poll");
+                } else {
+                    retention_armed = false;
+                }
+            },
             Some(Opcode::IsMounted) => xous::msg_blocking_scalar_unpack!(msg, _, _, _, _, {
                 if basis_cache.basis_count() > 0 { // if there's anything in the cache, we're mounted.
                     xous::return_scalar(msg.sender, 1).expect("couldn't return scalar");
@@ -513,6 +619,7 @@ fn xmain() -> ! {
                         match ensure_password(&modals, &mut pddb_os) {
                             PasswordState::Correct => {
                                 if try_mount_or_format(&modals, &mut pddb_os, &mut basis_cache, PasswordState::Correct) {
+                                    subscribers.notify(PddbEvent::BasisMount, Some(PDDB_DEFAULT_SYSTEM_BASIS), None, None);
                                     xous::return_scalar(msg.sender, 1).expect("couldn't return scalar");
                                 } else {
                                     xous::return_scalar(msg.sender, 0).expect("couldn't return scalar");
@@ -520,6 +627,7 @@ fn xmain() -> ! {
                             },
                             PasswordState::Uninit => {
                                 if try_mount_or_format(&modals, &mut pddb_os, &mut basis_cache, PasswordState::Uninit) {
+                                    subscribers.notify(PddbEvent::BasisMount, Some(PDDB_DEFAULT_SYSTEM_BASIS), None, None);
                                     xous::return_scalar(msg.sender, 1).expect("couldn't return scalar");
                                 } else {
                                     xous::return_scalar(msg.sender, 0).expect("couldn't return scalar");
@@ -601,6 +709,11 @@ fn xmain() -> ! {
                                     mgmt.policy.unwrap_or(BasisRetentionPolicy::Persist)
                                 ) {
                                     basis_cache.basis_add(basis);
+                                    retention_arm(retention_cid, &mut retention_armed, &mut last_retention_poll, &basis_cache, &pddb_os);
+                                    let bname = mgmt.name.as_str().unwrap();
+                                    transaction_recover(&mut pddb_os, &mut basis_cache, bname)
+                                        .map_err(|e| log::error!("couldn't recover interrupted transaction in {}: {:?}", bname, e)).ok();
+                                    subscribers.notify(PddbEvent::BasisMount, Some(bname), None, None);
                                     finished = true;
                                     mgmt.code = PddbRequestCode::NoErr;
                                 }
@@ -636,7 +749,11 @@ fn xmain() -> ! {
                 match mgmt.code {
                     PddbRequestCode::Close => {
                         match basis_cache.basis_unmount(&mut pddb_os, mgmt.name.as_str().expect("name is not valid utf-8")) {
-                            Ok(_) => mgmt.code = PddbRequestCode::NoErr,
+                            Ok(_) => {
+                                notify_basis_locked(&token_dict, mgmt.name.as_str().unwrap());
+                                subscribers.notify(PddbEvent::BasisUnmount, mgmt.name.as_str().ok(), None, None);
+                                mgmt.code = PddbRequestCode::NoErr;
+                            }
                             Err(e) => match e.kind() {
                                 ErrorKind::NotFound => mgmt.code = PddbRequestCode::NotFound,
                                 _ => mgmt.code = PddbRequestCode::InternalError,
@@ -655,7 +772,11 @@ fn xmain() -> ! {
                 match mgmt.code {
                     PddbRequestCode::Delete => {
                         match basis_cache.basis_delete(&mut pddb_os, mgmt.name.as_str().expect("name is not valid utf-8")) {
-                            Ok(_) => mgmt.code = PddbRequestCode::NoErr,
+                            Ok(_) => {
+                                notify_basis_locked(&token_dict, mgmt.name.as_str().unwrap());
+                                subscribers.notify(PddbEvent::BasisUnmount, mgmt.name.as_str().ok(), None, None);
+                                mgmt.code = PddbRequestCode::NoErr;
+                            }
                             Err(e) => match e.kind() {
                                 ErrorKind::NotFound => mgmt.code = PddbRequestCode::NotFound,
                                 _ => mgmt.code = PddbRequestCode::InternalError,
@@ -681,7 +802,7 @@ fn xmain() -> ! {
                 if basis_cache.dict_attributes(&mut pddb_os, dict, bname).is_err() {
                     if req.create_dict {
                         match basis_cache.dict_add(&mut pddb_os, dict, bname) {
-                            Ok(_) => (),
+                            Ok(_) => subscribers.notify(PddbEvent::DictChange, bname, Some(dict), None),
                             Err(e) => {
                                 match e.kind() {
                                     std::io::ErrorKind::OutOfMemory => {req.result = PddbRequestCode::NoFreeSpace; buffer.replace(req).unwrap(); continue}
@@ -712,6 +833,7 @@ fn xmain() -> ! {
                     basis: if let Some(name) = bname {Some(String::from(name))} else {None},
                     conn: cid,
                     alloc_hint: if let Some(hint) = req.alloc_hint {Some(hint as usize)} else {None},
+                    dirty: false,
                 };
                 token_dict.insert(token, token_record);
                 req.token = Some(token);
@@ -721,18 +843,11 @@ fn xmain() -> ! {
             Some(Opcode::KeyDrop) => msg_blocking_scalar_unpack!(msg, t0, t1, t2, _, {
                 let token: ApiToken = [t0 as u32, t1 as u32, t2 as u32];
                 if let Some(rec) = token_dict.remove(&token) {
-                    // now check if we can safely disconnect and recycle our connection number.
-                    // This is important because we can only have 32 outgoing connections...
-                    let mut has_cid = false;
-                    for r in token_dict.values() {
-                        if r.conn == rec.conn {
-                            has_cid = true;
-                            break;
-                        }
-                    }
-                    if !has_cid {
-                        unsafe{xous::disconnect(rec.conn).expect("couldn't disconnect from callback server")};
+                    if rec.dirty {
+                        subscribers.notify(PddbEvent::KeyChange, rec.basis.as_deref(), Some(&rec.dict), Some(&rec.key));
                     }
+                    // now check if we can safely disconnect and recycle our connection number.
+                    release_callback(rec.conn, &token_dict, &staging);
                 }
                 xous::return_scalar(msg.sender, 1).expect("couldn't ack KeyDrop");
             }),
@@ -746,8 +861,9 @@ fn xmain() -> ! {
                 };
                 let dict = req.dict.as_str().expect("dict utf-8 decode error");
                 let key = req.key.as_str().expect("key utf-8 decode error");
-                match basis_cache.key_remove(&mut pddb_os, dict, key, bname, false) {
+                match basis_cache.key_remove(&mut pddb_os, dict, key, bname, req.paranoid) {
                     Ok(_) => {
+                        subscribers.notify(PddbEvent::KeyChange, bname, Some(dict), Some(key));
                         let mut evict_list = Vec::<ApiToken>::new();
                         // check to see if we need to eliminate any ApiTokens as a result of this.
                         for (token, rec) in token_dict.iter() {
@@ -780,6 +896,7 @@ fn xmain() -> ! {
                     Err(e) => {
                         match e.kind() {
                             std::io::ErrorKind::NotFound => req.result = PddbRequestCode::NotFound,
+                            std::io::ErrorKind::OutOfMemory => req.result = PddbRequestCode::NoFreeSpace,
                             _ => req.result = PddbRequestCode::InternalError,
                         }
                     }
@@ -795,8 +912,9 @@ fn xmain() -> ! {
                     None
                 };
                 let dict = req.dict.as_str().expect("dict utf-8 decode error");
-                match basis_cache.dict_remove(&mut pddb_os, dict, bname, false) {
+                match basis_cache.dict_remove(&mut pddb_os, dict, bname, req.paranoid) {
                     Ok(_) => {
+                        subscribers.notify(PddbEvent::DictChange, bname, Some(dict), None);
                         let mut evict_list = Vec::<ApiToken>::new();
                         // check to see if we need to eliminate any ApiTokens as a result of this.
                         for (token, rec) in token_dict.iter() {
@@ -829,6 +947,7 @@ fn xmain() -> ! {
                     Err(e) => {
                         match e.kind() {
                             std::io::ErrorKind::NotFound => req.result = PddbRequestCode::NotFound,
+                            std::io::ErrorKind::OutOfMemory => req.result = PddbRequestCode::NoFreeSpace,
                             _ => req.result = PddbRequestCode::InternalError,
                         }
                     }
@@ -873,7 +992,7 @@ fn xmain() -> ! {
                 };
                 let dict = req.dict.as_str().expect("dict utf-8 decode error");
                 log::debug!("counting keys in dict {} basis {:?}", dict, bname);
-                match basis_cache.key_list(&mut pddb_os, dict, bname) {
+                match basis_cache.key_list(&mut pddb_os, dict, bname, None) {
                     Ok(list) => {
                         log::debug!("count: {}", list.len());
                         if list.len() > 0 {
@@ -980,6 +1099,41 @@ fn xmain() -> ! {
                 }
                 buffer.replace(req).unwrap();
             }
+            Some(Opcode::ListKeyPage) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut req = buffer.to_original::<PddbListRequest, _>().unwrap();
+                let bname = if req.basis_specified {
+                    Some(req.basis.as_str().expect("basis utf-8 decode error"))
+                } else {
+                    None
+                };
+                let dict = req.dict.as_str().expect("dict utf-8 decode error");
+                let filter = req.filter.as_str().expect("filter utf-8 decode error");
+                match basis_cache.key_list(&mut pddb_os, dict, bname, if filter.len() > 0 {Some(filter)} else {None}) {
+                    Ok(list) => list_page_fill(&mut req, list),
+                    Err(e) => match e.kind() {
+                        std::io::ErrorKind::NotFound => req.code = PddbRequestCode::NotFound,
+                        _ => req.code = PddbRequestCode::InternalError,
+                    }
+                }
+                buffer.replace(req).unwrap();
+            }
+            Some(Opcode::ListDictPage) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut req = buffer.to_original::<PddbListRequest, _>().unwrap();
+                let bname = if req.basis_specified {
+                    Some(req.basis.as_str().expect("basis utf-8 decode error"))
+                } else {
+                    None
+                };
+                let filter = req.filter.as_str().expect("filter utf-8 decode error");
+                let mut list = basis_cache.dict_list(&mut pddb_os, bname);
+                if filter.len() > 0 {
+                    list.retain(|name| name_matches(filter, name));
+                }
+                list_page_fill(&mut req, list);
+                buffer.replace(req).unwrap();
+            }
             Some(Opcode::ReadKey) => {
                 let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
                 let pbuf = PddbBuf::from_slice_mut(buffer.as_mut()); // direct translation, no serialization necessary for performance
@@ -1009,7 +1163,8 @@ fn xmain() -> ! {
                 let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
                 let pbuf = PddbBuf::from_slice_mut(buffer.as_mut()); // direct translation, no serialization necessary for performance
                 let token = pbuf.token;
-                if let Some(rec) = token_dict.get(&token) {
+                if let Some(rec) = token_dict.get_mut(&token) {
+                    rec.dirty = true;
                     match basis_cache.key_update(&mut pddb_os,
                         &rec.dict, &rec.key,
                         &pbuf.data[..pbuf.len as usize], Some(pbuf.position as usize),
@@ -1032,6 +1187,12 @@ fn xmain() -> ! {
                 // we don't nede a "replace" operation because all ops happen in-place
             }
             Some(Opcode::WriteKeyFlush) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                for rec in token_dict.values_mut() {
+                    if rec.dirty {
+                        subscribers.notify(PddbEvent::KeyChange, rec.basis.as_deref(), Some(&rec.dict), Some(&rec.key));
+                        rec.dirty = false;
+                    }
+                }
                 match basis_cache.sync(&mut pddb_os, None) {
                     Ok(_) => xous::return_scalar(msg.sender, PddbRetcode::Ok.to_usize().unwrap()).unwrap(),
                     Err(e) => match e.kind() {
@@ -1041,6 +1202,273 @@ fn xmain() -> ! {
                     }
                 };
             }),
+            Some(Opcode::Compact) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                match basis_cache.compact(&mut pddb_os, None) {
+                    Ok(freed) => xous::return_scalar2(msg.sender, PddbRetcode::Ok.to_usize().unwrap(), freed).unwrap(),
+                    Err(e) => match e.kind() {
+                        std::io::ErrorKind::OutOfMemory => xous::return_scalar2(msg.sender, PddbRetcode::DiskFull.to_usize().unwrap(), 0).unwrap(),
+                        std::io::ErrorKind::NotFound => xous::return_scalar2(msg.sender, PddbRetcode::BasisLost.to_usize().unwrap(), 0).unwrap(),
+                        _ => xous::return_scalar2(msg.sender, PddbRetcode::InternalError.to_usize().unwrap(), 0).unwrap(),
+                    }
+                };
+            }),
+            Some(Opcode::Subscribe) => {
+                let pid = msg.sender.pid();
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut sub = buffer.to_original::<PddbSubscription, _>().unwrap();
+                sub.code = match subscribers.subscribe(pid, &sub) {
+                    Ok(id) => {
+                        sub.id = id;
+                        PddbRequestCode::NoErr
+                    }
+                    Err(code) => code,
+                };
+                buffer.replace(sub).unwrap();
+            }
+            Some(Opcode::Unsubscribe) => msg_blocking_scalar_unpack!(msg, id, _, _, _, {
+                if subscribers.unsubscribe(msg.sender.pid(), id as u32) {
+                    xous::return_scalar(msg.sender, 1).unwrap();
+                } else {
+                    xous::return_scalar(msg.sender, 0).unwrap();
+                }
+            }),
+            Some(Opcode::ArchiveExport) => {
+                let pid = msg.sender.pid();
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut req = buffer.to_original::<PddbArchiveRequest, _>().unwrap();
+                for conn in staging.sweep() {
+                    release_callback(conn, &token_dict, &staging);
+                }
+                match basis_export(&mut pddb_os, &mut basis_cache,
+                    req.basis.as_str().expect("name is not valid utf-8"),
+                    req.passphrase.as_str().expect("passphrase is not valid utf-8")
+                ) {
+                    Ok(mut archive) => {
+                        let token: ApiToken = [pddb_os.trng_u32(), pddb_os.trng_u32(), pddb_os.trng_u32()];
+                        let len = archive.len() as u64;
+                        match staging.reserve(pid, len) {
+                            Ok(_) => match staging.open(token, StagedKind::Export, pid, req.cb_sid, archive) {
+                                Ok(()) => {
+                                    req.token = token;
+                                    req.len = len;
+                                    req.code = PddbRequestCode::NoErr;
+                                }
+                                Err(code) => req.code = code,
+                            },
+                            Err(code) => {
+                                archive_erase(&mut archive);
+                                req.code = code;
+                            }
+                        }
+                    }
+                    Err(e) => match e.kind() {
+                        ErrorKind::NotFound => req.code = PddbRequestCode::NotFound,
+                        _ => {
+                            log::error!("couldn't export basis: {:?}", e);
+                            req.code = PddbRequestCode::InternalError;
+                        }
+                    }
+                }
+                req.passphrase.volatile_clear();
+                buffer.replace(req).unwrap();
+            }
+            Some(Opcode::ArchiveImport) => {
+                let pid = msg.sender.pid();
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut req = buffer.to_original::<PddbArchiveRequest, _>().unwrap();
+                for conn in staging.sweep() {
+                    release_callback(conn, &token_dict, &staging);
+                }
+                let token: ApiToken = [pddb_os.trng_u32(), pddb_os.trng_u32(), pddb_os.trng_u32()];
+                match staging.reserve(pid, req.len)
+                .and_then(|len| staging.open(token, StagedKind::Import, pid, req.cb_sid, vec![0u8; len])) {
+                    Ok(()) => {
+                        req.token = token;
+                        req.code = PddbRequestCode::NoErr;
+                    }
+                    Err(code) => req.code = code,
+                }
+                buffer.replace(req).unwrap();
+            }
+            Some(Opcode::ArchiveRead) => {
+                let pid = msg.sender.pid();
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                staging.read(pid, StagedKind::Export, PddbBuf::from_slice_mut(buffer.as_mut()));
+            }
+            Some(Opcode::ArchiveWrite) => {
+                let pid = msg.sender.pid();
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                staging.write(pid, StagedKind::Import, PddbBuf::from_slice_mut(buffer.as_mut()));
+            }
+            Some(Opcode::ArchiveCommit) => {
+                let pid = msg.sender.pid();
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut req = buffer.to_original::<PddbArchiveRequest, _>().unwrap();
+                if let Some(archive) = staging.remove(&req.token, pid, StagedKind::Import) {
+                    let basis_name = req.basis.as_str().expect("name is not valid utf-8");
+                    match basis_import(&mut pddb_os, &mut basis_cache, basis_name,
+                        req.passphrase.as_str().expect("passphrase is not valid utf-8"), archive.data()
+                    ) {
+                        Ok((count, dicts)) => {
+                            for (token, rec) in token_dict.iter() {
+                                if dicts.contains(&rec.dict) && rec.basis.as_deref().map_or(true, |b| b == basis_name) {
+                                    send_message(rec.conn,
+                                        Message::new_scalar(CbOp::Change.to_usize().unwrap(), token[0] as usize, token[1] as usize, token[2] as usize, 0)
+                                    ).map_err(|e| log::warn!("couldn't notify key handle of import: {:?}", e)).ok();
+                                }
+                            }
+                            for dict in dicts.iter() {
+                                subscribers.notify(PddbEvent::DictChange, Some(basis_name), Some(dict), None);
+                            }
+                            req.count = count as u32;
+                            req.code = PddbRequestCode::NoErr;
+                        }
+                        Err(e) => match e.kind() {
+                            ErrorKind::NotFound => req.code = PddbRequestCode::NotFound,
+                            ErrorKind::PermissionDenied => req.code = PddbRequestCode::AccessDenied,
+                            ErrorKind::OutOfMemory => req.code = PddbRequestCode::NoFreeSpace,
+                            _ => {
+                                log::error!("couldn't import basis archive: {:?}", e);
+                                req.code = PddbRequestCode::InternalError;
+                            }
+                        }
+                    }
+                    let conn = archive.conn;
+                    drop(archive);
+                    release_callback(conn, &token_dict, &staging);
+                } else {
+                    req.code = PddbRequestCode::AccessDenied;
+                }
+                req.passphrase.volatile_clear();
+                buffer.replace(req).unwrap();
+            }
+            Some(Opcode::ArchiveClose) => msg_blocking_scalar_unpack!(msg, t0, t1, t2, _, {
+                let token: ApiToken = [t0 as u32, t1 as u32, t2 as u32];
+                // a client closes both the archives it exported and the ones it gave up importing
+                let pid = msg.sender.pid();
+                if let Some(archive) = staging.remove(&token, pid, StagedKind::Export)
+                .or_else(|| staging.remove(&token, pid, StagedKind::Import)) {
+                    let conn = archive.conn;
+                    drop(archive);
+                    release_callback(conn, &token_dict, &staging);
+                }
+                xous::return_scalar(msg.sender, 1).unwrap();
+            }),
+            Some(Opcode::TransactionBegin) => {
+                let pid = msg.sender.pid();
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut req = buffer.to_original::<PddbTransactionRequest, _>().unwrap();
+                for conn in staging.sweep() {
+                    release_callback(conn, &token_dict, &staging);
+                }
+                let token: ApiToken = [pddb_os.trng_u32(), pddb_os.trng_u32(), pddb_os.trng_u32()];
+                match staging.reserve(pid, req.len)
+                .and_then(|len| staging.open(token, StagedKind::Transaction, pid, req.cb_sid, vec![0u8; len])) {
+                    Ok(()) => {
+                        req.token = token;
+                        req.code = PddbRequestCode::NoErr;
+                    }
+                    Err(code) => req.code = code,
+                }
+                buffer.replace(req).unwrap();
+            }
+            Some(Opcode::TransactionWrite) => {
+                let pid = msg.sender.pid();
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                staging.write(pid, StagedKind::Transaction, PddbBuf::from_slice_mut(buffer.as_mut()));
+            }
+            Some(Opcode::TransactionCommit) => {
+                let pid = msg.sender.pid();
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut req = buffer.to_original::<PddbTransactionRequest, _>().unwrap();
+                if let Some(record) = staging.remove(&req.token, pid, StagedKind::Transaction) {
+                    // a transaction always applies to a single basis, so resolve the default basis up front
+                    let bname = if req.basis_specified {
+                        Some(String::from(req.basis.as_str().expect("name is not valid utf-8")))
+                    } else {
+                        basis_cache.basis_latest()
+                    };
+                    let result = match (bname.as_ref(), transaction_decode(record.data(), req.count as usize)) {
+                        (Some(basis_name), Ok(ops)) => transaction_commit(&mut pddb_os, &mut basis_cache, basis_name, &ops),
+                        (None, _) => Err(std::io::Error::new(ErrorKind::NotFound, "PDDB not mounted")),
+                        (_, Err(e)) => Err(e),
+                    };
+                    match result {
+                        Ok(touched) => {
+                            let basis_name = bname.as_deref().unwrap();
+                            for (token, rec) in token_dict.iter() {
+                                if touched.iter().any(|(d, k)| d == &rec.dict && k == &rec.key)
+                                && rec.basis.as_deref().map_or(true, |b| b == basis_name) {
+                                    send_message(rec.conn,
+                                        Message::new_scalar(CbOp::Change.to_usize().unwrap(), token[0] as usize, token[1] as usize, token[2] as usize, 0)
+                                    ).map_err(|e| log::warn!("couldn't notify key handle of transaction: {:?}", e)).ok();
+                                }
+                            }
+                            for (dict, key) in touched.iter() {
+                                subscribers.notify(PddbEvent::KeyChange, bname.as_deref(), Some(dict), Some(key));
+                            }
+                            req.code = PddbRequestCode::NoErr;
+                        }
+                        Err(e) => match e.kind() {
+                            ErrorKind::NotFound => req.code = PddbRequestCode::NotFound,
+                            ErrorKind::OutOfMemory => req.code = PddbRequestCode::NoFreeSpace,
+                            _ => {
+                                log::error!("couldn't commit transaction: {:?}", e);
+                                req.code = PddbRequestCode::InternalError;
+                            }
+                        }
+                    }
+                    let conn = record.conn;
+                    drop(record);
+                    release_callback(conn, &token_dict, &staging);
+                } else {
+                    req.code = PddbRequestCode::AccessDenied;
+                }
+                buffer.replace(req).unwrap();
+            }
+            Some(Opcode::TransactionAbort) => msg_blocking_scalar_unpack!(msg, t0, t1, t2, _, {
+                let token: ApiToken = [t0 as u32, t1 as u32, t2 as u32];
+                if let Some(record) = staging.remove(&token, msg.sender.pid(), StagedKind::Transaction) {
+                    let conn = record.conn;
+                    drop(record);
+                    release_callback(conn, &token_dict, &staging);
+                }
+                xous::return_scalar(msg.sender, 1).unwrap();
+            }),
+            Some(Opcode::Check) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut req = buffer.to_original::<PddbCheckReport, _>().unwrap();
+                match basis_cache.check(&mut pddb_os, req.repair) {
+                    Ok(report) => {
+                        req.bases = report.bases;
+                        req.pages = report.pages;
+                        req.bad_pages = report.bad_pages;
+                        req.stale_ptes = report.stale_ptes;
+                        req.orphaned_pages = report.orphaned_pages;
+                        req.dangling_keys = report.dangling_keys;
+                        req.fastspace_conflicts = report.fastspace_conflicts;
+                        req.code = PddbRequestCode::NoErr;
+                    }
+                    Err(e) => match e.kind() {
+                        ErrorKind::OutOfMemory => req.code = PddbRequestCode::NoFreeSpace,
+                        _ => {
+                            log::error!("PDDB check failed: {:?}", e);
+                            req.code = PddbRequestCode::InternalError;
+                        }
+                    }
+                }
+                buffer.replace(req).unwrap();
+            }
+            Some(Opcode::MenuCompact) => {
+                let note = match basis_cache.compact(&mut pddb_os, None) {
+                    Ok(freed) => format!("{}{}", t!("pddb.menu.compact_response", xous::LANG), freed),
+                    Err(e) => {
+                        log::error!("couldn't compact the PDDB: {:?}", e);
+                        String::from(t!("pddb.internalerror", xous::LANG))
+                    }
+                };
+                modals.show_notification(&note).expect("couldn't show compaction result");
+            },
             Some(Opcode::MenuListBasis) => {
                 let bases = basis_cache.basis_list();
                 let mut note = String::from(t!("pddb.menu.listbasis_response", xous::LANG));
@@ -1109,6 +1537,8 @@ fn try_mount_or_format(modals: &modals::Modals, pddb_os: &mut PddbOs, basis_cach
         if let Some(sys_basis) = pddb_os.pddb_mount() {
             log::info!("PDDB mount operation finished successfully");
             basis_cache.basis_add(sys_basis);
+            transaction_recover(pddb_os, basis_cache, PDDB_DEFAULT_SYSTEM_BASIS)
+                .map_err(|e| log::error!("couldn't recover interrupted transaction in the system basis: {:?}", e)).ok();
             return true
         }
     }
@@ -0,0 +1,235 @@
+use crate::api::*;
+use crate::backend::archive_erase;
+use core::convert::TryFrom;
+use num_traits::*;
+use std::collections::HashMap;
+
+/// What a staging buffer is for. A token handed out for one kind of transfer can't be used for another.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) enum StagedKind {
+    /// an archive made by `ArchiveExport`, waiting to be read out by the client
+    Export,
+    /// an archive being written in by the client, ahead of `ArchiveCommit`
+    Import,
+    /// transaction operations being written in by the client, ahead of `TransactionCommit`
+    Transaction,
+}
+
+pub(crate) struct Staged {
+    kind: StagedKind,
+    pid: Option<xous::PID>,
+    /// connection to the owner's `Pddb` callback server. The kernel hands out one connection per server,
+    /// so this is shared with any key handles the owner holds.
+    pub(crate) conn: xous::CID,
+    data: Vec<u8>,
+}
+impl Staged {
+    pub(crate) fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+impl Drop for Staged {
+    fn drop(&mut self) {
+        // archives and transactions hold the plaintext of keys, so don't leave them lying around on the heap
+        archive_erase(&mut self.data);
+    }
+}
+
+/// Buffers that clients stream archives and transactions into and out of, in `PddbBuf`-sized chunks.
+///
+/// A buffer belongs to the process that opened it: other processes can't read, write or remove it, even
+/// if they learn its token. Staged data is limited to `MAX_STAGING_LEN` in total, and a process can have
+/// at most `MAX_STAGED_PER_PROCESS` buffers open. A client that exits, or drops its `Pddb`, in the middle
+/// of a transfer never closes its buffer, so the buffers of owners whose callback server has gone away
+/// are reclaimed by `sweep()`.
+pub(crate) struct Staging {
+    list: HashMap<ApiToken, Staged>,
+}
+impl Staging {
+    pub(crate) fn new() -> Self {
+        Staging { list: HashMap::new() }
+    }
+
+    /// Checks that `pid` may stage `len` more bytes, and returns the length as a `usize`.
+    pub(crate) fn reserve(&self, pid: Option<xous::PID>, len: u64) -> Result<usize, PddbRequestCode> {
+        let staged: usize = self.list.values().map(|s| s.data.len()).sum();
+        let len = match usize::try_from(len) {
+            Ok(len) if len <= MAX_STAGING_LEN.saturating_sub(staged) => len,
+            _ => {
+                log::warn!("can't stage {} bytes, {} of {} are in use", len, staged, MAX_STAGING_LEN);
+                return Err(PddbRequestCode::NoFreeSpace);
+            }
+        };
+        if self.list.values().filter(|s| s.pid == pid).count() >= MAX_STAGED_PER_PROCESS {
+            log::warn!("process {:?} is at its limit of {} staged transfers", pid, MAX_STAGED_PER_PROCESS);
+            return Err(PddbRequestCode::NoFreeSpace);
+        }
+        Ok(len)
+    }
+
+    /// Stages `data` under `token`, for `pid`, whose `Pddb` callback server is `cb_sid`. `reserve()` must
+    /// have admitted the data first. If the data can't be staged, it is wiped.
+    pub(crate) fn open(&mut self, token: ApiToken, kind: StagedKind, pid: Option<xous::PID>, cb_sid: [u32; 4], data: Vec<u8>)
+    -> Result<(), PddbRequestCode> {
+        let mut staged = Staged { kind, pid, conn: 0, data };
+        staged.conn = xous::try_connect(xous::SID::from_array(cb_sid)).map_err(|e| {
+            log::warn!("couldn't connect to the callback server of {:?}: {:?}", pid, e);
+            PddbRequestCode::InternalError
+        })?;
+        self.list.insert(token, staged);
+        Ok(())
+    }
+
+    /// Copies the data that `pbuf` asks for out of its buffer.
+    pub(crate) fn read(&self, pid: Option<xous::PID>, kind: StagedKind, pbuf: &mut PddbBuf) {
+        let token = pbuf.token;
+        pbuf.retcode = match self.list.get(&token) {
+            Some(staged) if staged.kind == kind && staged.pid == pid => {
+                match usize::try_from(pbuf.position) {
+                    Ok(start) if start <= staged.data.len() => {
+                        let len = (pbuf.len as usize).min(pbuf.data.len()).min(staged.data.len() - start);
+                        pbuf.data[..len].copy_from_slice(&staged.data[start..start + len]);
+                        pbuf.len = len as u16;
+                        PddbRetcode::Ok
+                    }
+                    _ => PddbRetcode::UnexpectedEof,
+                }
+            }
+            _ => PddbRetcode::AccessDenied,
+        };
+    }
+
+    /// Copies the data in `pbuf` into its buffer. The data has to fit entirely within the length that was staged.
+    pub(crate) fn write(&mut self, pid: Option<xous::PID>, kind: StagedKind, pbuf: &mut PddbBuf) {
+        let token = pbuf.token;
+        let len = pbuf.len as usize;
+        pbuf.retcode = match self.list.get_mut(&token) {
+            Some(staged) if staged.kind == kind && staged.pid == pid => {
+                let range = usize::try_from(pbuf.position).ok()
+                    .and_then(|start| start.checked_add(len).map(|end| start..end));
+                match range {
+                    Some(range) if len <= pbuf.data.len() && range.end <= staged.data.len() => {
+                        staged.data[range].copy_from_slice(&pbuf.data[..len]);
+                        PddbRetcode::Ok
+                    }
+                    _ => PddbRetcode::UnexpectedEof,
+                }
+            }
+            _ => PddbRetcode::AccessDenied,
+        };
+    }
+
+    /// Takes the buffer staged under `token` out of the staging area. Returns `None` if there is no such buffer,
+    /// or it isn't `pid`'s. The data is wiped when the returned `Staged` is dropped.
+    pub(crate) fn remove(&mut self, token: &ApiToken, pid: Option<xous::PID>, kind: StagedKind) -> Option<Staged> {
+        match self.list.get(token) {
+            Some(staged) if staged.kind == kind && staged.pid == pid => self.list.remove(token),
+            _ => None,
+        }
+    }
+
+    /// Whether any staged buffer holds `conn`
+    pub(crate) fn uses(&self, conn: xous::CID) -> bool {
+        self.list.values().any(|s| s.conn == conn)
+    }
+
+    /// Drops the buffers of owners whose callback server can no longer be reached, because the owner has exited
+    /// or dropped its `Pddb`. Returns the connections that no remaining buffer holds, for the caller to release.
+    pub(crate) fn sweep(&mut self) -> Vec<xous::CID> {
+        let mut dead = Vec::<xous::CID>::new();
+        self.list.retain(|_, staged| {
+            let alive = !matches!(
+                xous::try_send_message(staged.conn, xous::Message::new_scalar(CbOp::Ping.to_usize().unwrap(), 0, 0, 0, 0)),
+                Err(xous::Error::ServerNotFound) | Err(xous::Error::ProcessTerminated)
+            );
+            if !alive {
+                log::info!("dropping a staged {:?} of {:?}, which has gone away", staged.kind, staged.pid);
+                if !dead.contains(&staged.conn) {
+                    dead.push(staged.conn);
+                }
+            }
+            alive
+        });
+        dead.retain(|&conn| !self.uses(conn));
+        dead
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn staging_with(pid: u8, kind: StagedKind, len: usize) -> (Staging, ApiToken) {
+        let mut staging = Staging::new();
+        let token = [1, 2, 3];
+        staging.list.insert(token, Staged { kind, pid: xous::PID::new(pid), conn: 0, data: vec![0u8; len] });
+        (staging, token)
+    }
+
+    fn pbuf(token: ApiToken, position: u64, len: u16) -> Box<PddbBuf> {
+        let mut pbuf = Box::new(PddbBuf { token, position, len, retcode: PddbRetcode::Uninit, reserved: 0, data: [0u8; 4072] });
+        for (i, b) in pbuf.data.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        pbuf
+    }
+
+    #[test]
+    fn test_staging_limits() {
+        let (mut staging, _) = staging_with(2, StagedKind::Export, MAX_STAGING_LEN / 2);
+        assert!(matches!(staging.reserve(xous::PID::new(3), u64::MAX), Err(PddbRequestCode::NoFreeSpace)));
+        assert!(matches!(staging.reserve(xous::PID::new(3), (MAX_STAGING_LEN / 2 + 1) as u64), Err(PddbRequestCode::NoFreeSpace)));
+        assert!(matches!(staging.reserve(xous::PID::new(3), (MAX_STAGING_LEN / 2) as u64), Ok(len) if len == MAX_STAGING_LEN / 2));
+        for i in 1..MAX_STAGED_PER_PROCESS {
+            staging.list.insert([i as u32, 0, 0], Staged { kind: StagedKind::Import, pid: xous::PID::new(2), conn: 0, data: Vec::new() });
+        }
+        assert!(matches!(staging.reserve(xous::PID::new(2), 1), Err(PddbRequestCode::NoFreeSpace)));
+        assert!(staging.reserve(xous::PID::new(3), 1).is_ok());
+    }
+
+    #[test]
+    fn test_staging_bounds() {
+        let (mut staging, token) = staging_with(2, StagedKind::Import, 5000);
+        let pid = xous::PID::new(2);
+        let mut buf = pbuf(token, 0, 4072);
+        staging.write(pid, StagedKind::Import, &mut buf);
+        assert!(matches!(buf.retcode, PddbRetcode::Ok));
+        // runs past the end of the buffer
+        let mut buf = pbuf(token, 4072, 4072);
+        staging.write(pid, StagedKind::Import, &mut buf);
+        assert!(matches!(buf.retcode, PddbRetcode::UnexpectedEof));
+        // position + len overflows
+        let mut buf = pbuf(token, u64::MAX, 16);
+        staging.write(pid, StagedKind::Import, &mut buf);
+        assert!(matches!(buf.retcode, PddbRetcode::UnexpectedEof));
+        // len is longer than the data field
+        let mut buf = pbuf(token, 0, 5000);
+        staging.write(pid, StagedKind::Import, &mut buf);
+        assert!(matches!(buf.retcode, PddbRetcode::UnexpectedEof));
+        // reads are cut short at the end of the buffer
+        let mut buf = pbuf(token, 4000, u16::MAX);
+        staging.read(pid, StagedKind::Import, &mut buf);
+        assert!(matches!(buf.retcode, PddbRetcode::Ok));
+        let len = buf.len;
+        assert_eq!(len, 1000);
+        assert_eq!(buf.data[71], 4071u16 as u8);
+        assert_eq!(buf.data[72], 0);
+        let mut buf = pbuf(token, 5001, 16);
+        staging.read(pid, StagedKind::Import, &mut buf);
+        assert!(matches!(buf.retcode, PddbRetcode::UnexpectedEof));
+    }
+
+    #[test]
+    fn test_staging_ownership() {
+        let (mut staging, token) = staging_with(2, StagedKind::Import, 16);
+        let mut buf = pbuf(token, 0, 16);
+        staging.write(xous::PID::new(3), StagedKind::Import, &mut buf);
+        assert!(matches!(buf.retcode, PddbRetcode::AccessDenied));
+        staging.read(xous::PID::new(2), StagedKind::Export, &mut buf);
+        assert!(matches!(buf.retcode, PddbRetcode::AccessDenied));
+        assert!(staging.remove(&token, xous::PID::new(3), StagedKind::Import).is_none());
+        assert!(staging.remove(&token, xous::PID::new(2), StagedKind::Export).is_none());
+        assert!(staging.remove(&token, xous::PID::new(2), StagedKind::Import).is_some());
+        assert!(!staging.uses(0));
+    }
+}
@@ -0,0 +1,199 @@
+use crate::api::*;
+use num_traits::*;
+use xous_ipc::Buffer;
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+
+/// A client that has asked to be told about `PddbEvent`s
+pub(crate) struct Subscriber {
+    /// the process that made the subscription
+    pid: Option<xous::PID>,
+    /// the ID that the subscription's notifications are tagged with
+    id: u32,
+    /// the client's notification server, which is shared by all the subscriptions of a `Pddb` object
+    sid: [u32; 4],
+    /// dict and key filters; `None` matches everything
+    dict: Option<String>,
+    key: Option<String>,
+}
+impl Subscriber {
+    /// Whether the subscriber wants to hear about `event`. Basis events go to all subscribers.
+    fn matches(&self, event: PddbEvent, dict: Option<&str>, key: Option<&str>) -> bool {
+        let dict_matches = self.dict.is_none() || self.dict.as_deref() == dict;
+        match event {
+            PddbEvent::BasisMount | PddbEvent::BasisUnmount => true,
+            PddbEvent::DictChange => dict_matches,
+            PddbEvent::KeyChange => dict_matches && (self.key.is_none() || self.key.as_deref() == key),
+        }
+    }
+}
+
+pub(crate) struct Subscribers {
+    list: Vec<Subscriber>,
+    /// our connections to the notification servers, one per server no matter how many subscriptions report to it.
+    /// There are at most `MAX_SUBSCRIBERS` of these, as they come out of the same 32-entry connection table as the
+    /// key callbacks.
+    conns: HashMap<[u32; 4], xous::CID>,
+    next_id: u32,
+}
+impl Subscribers {
+    pub(crate) fn new() -> Self {
+        Subscribers { list: Vec::new(), conns: HashMap::new(), next_id: 0 }
+    }
+
+    /// Checks that `pid` may add a subscription that reports to `sid`: it has room under
+    /// `MAX_SUBSCRIPTIONS_PER_PROCESS`, the server doesn't belong to another process's subscriptions, and if the
+    /// server is a new one, there is room for it under `MAX_SUBSCRIBERS`.
+    fn admit(&self, pid: Option<xous::PID>, sid: &[u32; 4]) -> Result<(), PddbRequestCode> {
+        if self.list.iter().any(|sub| &sub.sid == sid && sub.pid != pid) {
+            return Err(PddbRequestCode::InternalError);
+        }
+        if self.list.iter().filter(|sub| sub.pid == pid).count() >= MAX_SUBSCRIPTIONS_PER_PROCESS {
+            log::warn!("process {:?} is at its limit of {} subscriptions", pid, MAX_SUBSCRIPTIONS_PER_PROCESS);
+            return Err(PddbRequestCode::NoFreeSpace);
+        }
+        if !self.conns.contains_key(sid) && self.conns.len() >= MAX_SUBSCRIBERS {
+            log::warn!("already notifying {} servers, can't take on another", MAX_SUBSCRIBERS);
+            return Err(PddbRequestCode::NoFreeSpace);
+        }
+        Ok(())
+    }
+
+    /// Adds a subscription, and returns the ID assigned to it
+    pub(crate) fn subscribe(&mut self, pid: Option<xous::PID>, sub: &PddbSubscription) -> Result<u32, PddbRequestCode> {
+        self.admit(pid, &sub.sid)?;
+        let filter = |specified: bool, name: Result<&str, _>| -> Result<Option<String>, PddbRequestCode> {
+            if specified {
+                name.map(|n| Some(String::from(n))).or(Err(PddbRequestCode::InternalError))
+            } else {
+                Ok(None)
+            }
+        };
+        let dict = filter(sub.dict_specified, sub.dict.as_str())?;
+        let key = filter(sub.key_specified, sub.key.as_str())?;
+        if let Entry::Vacant(entry) = self.conns.entry(sub.sid) {
+            let conn = xous::connect(xous::SID::from_array(sub.sid)).map_err(|e| {
+                log::warn!("couldn't connect to subscriber: {:?}", e);
+                PddbRequestCode::InternalError
+            })?;
+            entry.insert(conn);
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.list.push(Subscriber { pid, id, sid: sub.sid, dict, key });
+        Ok(id)
+    }
+
+    /// Disconnects from `sid` if no subscription reports to it any more
+    fn release(&mut self, sid: &[u32; 4]) {
+        if !self.list.iter().any(|sub| &sub.sid == sid) {
+            if let Some(conn) = self.conns.remove(sid) {
+                unsafe{xous::disconnect(conn).ok()};
+            }
+        }
+    }
+
+    /// Removes the subscription `id` made by `pid`. Returns `false` if there is no such subscription.
+    pub(crate) fn unsubscribe(&mut self, pid: Option<xous::PID>, id: u32) -> bool {
+        if let Some(index) = self.list.iter().position(|sub| sub.id == id && sub.pid == pid) {
+            let sub = self.list.remove(index);
+            self.release(&sub.sid);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Sends `event` to every subscriber whose filter matches. A notification server that can't be reached, because
+    /// its process has gone away or dropped its subscriptions without cancelling them, is dropped along with all
+    /// of the subscriptions that report to it.
+    pub(crate) fn notify(&mut self, event: PddbEvent, basis: Option<&str>, dict: Option<&str>, key: Option<&str>) {
+        let mut unreachable = Vec::<[u32; 4]>::new();
+        for sub in self.list.iter() {
+            if !sub.matches(event, dict, key) || unreachable.contains(&sub.sid) {
+                continue;
+            }
+            let conn = match self.conns.get(&sub.sid) {
+                Some(&conn) => conn,
+                None => continue,
+            };
+            log::debug!("notifying subscription {} at {:?} of {:?}", sub.id, sub.sid, event);
+            let report = PddbEventReport {
+                event,
+                subscription: sub.id,
+                basis: basis.map(xous_ipc::String::<BASIS_NAME_LEN>::from_str),
+                dict: dict.map(xous_ipc::String::<DICT_NAME_LEN>::from_str),
+                key: key.map(xous_ipc::String::<KEY_NAME_LEN>::from_str),
+            };
+            let sent = Buffer::into_buf(report)
+                .or(Err(xous::Error::InternalError))
+                .and_then(|buf| buf.send(conn, SubscriptionCb::Event.to_u32().unwrap()).map(|_| ()));
+            if let Err(e) = sent {
+                log::warn!("couldn't notify subscriber {:?}, dropping it: {:?}", sub.sid, e);
+                unreachable.push(sub.sid);
+            }
+        }
+        for sid in unreachable.iter() {
+            self.list.retain(|sub| &sub.sid != sid);
+            self.release(sid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscriber(pid: u8, id: u32, dict: Option<&str>, key: Option<&str>) -> Subscriber {
+        Subscriber {
+            pid: xous::PID::new(pid),
+            id,
+            sid: [pid as u32, 0, 0, 0],
+            dict: dict.map(String::from),
+            key: key.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test_subscriber_filters() {
+        let all = subscriber(2, 1, None, None);
+        let dict = subscriber(2, 2, Some("wlan.networks"), None);
+        let key = subscriber(2, 3, Some("wlan.networks"), Some("home"));
+        for sub in [&all, &dict, &key].iter() {
+            assert!(sub.matches(PddbEvent::BasisMount, None, None));
+            assert!(sub.matches(PddbEvent::BasisUnmount, None, None));
+            assert!(sub.matches(PddbEvent::KeyChange, Some("wlan.networks"), Some("home")));
+        }
+        assert!(all.matches(PddbEvent::DictChange, Some("fido.cfg"), None));
+        assert!(!dict.matches(PddbEvent::DictChange, Some("fido.cfg"), None));
+        assert!(dict.matches(PddbEvent::KeyChange, Some("wlan.networks"), Some("work")));
+        assert!(!key.matches(PddbEvent::KeyChange, Some("wlan.networks"), Some("work")));
+        assert!(!key.matches(PddbEvent::KeyChange, Some("fido.cfg"), Some("home")));
+    }
+
+    #[test]
+    fn test_subscription_limits() {
+        let mut subs = Subscribers::new();
+        for i in 0..MAX_SUBSCRIPTIONS_PER_PROCESS {
+            subs.list.push(subscriber(2, i as u32, None, None));
+        }
+        subs.conns.insert([2, 0, 0, 0], 1);
+        // a notification server only takes subscriptions from the process that runs it
+        assert!(matches!(subs.admit(xous::PID::new(3), &[2, 0, 0, 0]), Err(PddbRequestCode::InternalError)));
+        // the limit is per process, whichever server the subscriptions report to
+        assert!(matches!(subs.admit(xous::PID::new(2), &[2, 0, 0, 0]), Err(PddbRequestCode::NoFreeSpace)));
+        assert!(matches!(subs.admit(xous::PID::new(2), &[100, 0, 0, 0]), Err(PddbRequestCode::NoFreeSpace)));
+        assert!(subs.admit(xous::PID::new(3), &[3, 0, 0, 0]).is_ok());
+        subs.list.remove(0);
+        assert!(subs.admit(xous::PID::new(2), &[2, 0, 0, 0]).is_ok());
+
+        // each server takes up a connection, so there's a limit on how many there can be, across all processes
+        for i in 1..MAX_SUBSCRIBERS as u32 {
+            subs.conns.insert([100 + i, 0, 0, 0], 1 + i);
+        }
+        assert!(matches!(subs.admit(xous::PID::new(3), &[3, 0, 0, 0]), Err(PddbRequestCode::NoFreeSpace)));
+        // but more subscriptions can always share a server that's already connected
+        assert!(subs.admit(xous::PID::new(2), &[2, 0, 0, 0]).is_ok());
+    }
+}
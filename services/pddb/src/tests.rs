@@ -3,6 +3,7 @@ use rand_chacha::rand_core::RngCore;
 use rand_chacha::rand_core::SeedableRng;
 use crate::*;
 use core::sync::atomic::{AtomicU64, Ordering};
+use core::mem::size_of;
 use std::collections::{BTreeSet, HashSet};
 use std::io::Result;
 
@@ -161,7 +162,7 @@ pub(crate) fn patch_test(hw: &mut PddbOs, basis_cache: &mut BasisCache,
     #[cfg(not(feature = "deterministic"))]
     let dict_list = basis_cache.dict_list(hw, None);
     for dict in dict_list.iter() {
-        if let Ok(key_list_unord) = basis_cache.key_list(hw, dict, None) {
+        if let Ok(key_list_unord) = basis_cache.key_list(hw, dict, None, None) {
             #[cfg(feature = "deterministic")]
             let mut key_list = BTreeSet::<String>::new();
             #[cfg(feature = "deterministic")]
@@ -244,7 +245,7 @@ pub(crate) fn delete_pattern(hw: &mut PddbOs, basis_cache: &mut BasisCache,
     let mut evict_iter = 0;
     for (evicted, evict_dict) in dict_list.iter().enumerate() {
         if evicted < evict_count {
-            let key_list_unord = basis_cache.key_list(hw, evict_dict, None).unwrap();
+            let key_list_unord = basis_cache.key_list(hw, evict_dict, None, None).unwrap();
             let mut key_list = BTreeSet::<String>::new();
             for s in key_list_unord {
                 key_list.insert(s);
@@ -342,6 +343,342 @@ pub(crate) fn delete_pattern(hw: &mut PddbOs, basis_cache: &mut BasisCache,
     Ok(())
 }
 
+fn large_stream_check(hw: &mut PddbOs, basis_cache: &mut BasisCache, dict: &str, key: &str, total_len: usize) -> Result<()> {
+    const READ_CHUNK: usize = 777; // deliberately not a divisor of VPAGE_SIZE
+    let mut readbuf = [0u8; READ_CHUNK];
+    let mut offset = 0;
+    while offset < total_len {
+        let readlen = basis_cache.key_read(hw, dict, key, &mut readbuf, Some(offset), None)?;
+        assert!(readlen != 0, "large key ended early at {} of {}", offset, total_len);
+        for (i, &b) in readbuf[..readlen].iter().enumerate() {
+            assert!(b == ((offset + i) % 251) as u8, "large key data mismatch at offset {}", offset + i);
+        }
+        offset += readlen;
+    }
+    assert!(offset == total_len, "large key readback length mismatch");
+    Ok(())
+}
+/// Streams a key that is larger than the large pool cache in and out in small, unaligned chunks,
+/// so that pages are evicted and re-read while the key is being written. The data is checked both
+/// while it is still in cache, and after it has been committed to disk.
+pub(crate) fn large_stream_test(hw: &mut PddbOs, basis_cache: &mut BasisCache) -> Result<()> {
+    const DICT: &'static str = "streamtest";
+    const KEY: &'static str = "bigkey";
+    const WRITE_CHUNK: usize = 1000;
+    let total_len = VPAGE_SIZE * (LARGE_CACHE_MAX_PAGES + 4) + 123;
+
+    let mut offset = 0;
+    while offset < total_len {
+        let len = if total_len - offset < WRITE_CHUNK { total_len - offset } else { WRITE_CHUNK };
+        let mut chunk = Vec::<u8>::new();
+        for i in offset..offset + len {
+            chunk.push((i % 251) as u8);
+        }
+        basis_cache.key_update(hw, DICT, KEY, &chunk, Some(offset), Some(total_len), None, false)?;
+        offset += len;
+    }
+    log::info!("checking large key readback from cache");
+    large_stream_check(hw, basis_cache, DICT, KEY, total_len)?;
+    basis_cache.sync(hw, None)?;
+    log::info!("checking large key readback after sync");
+    large_stream_check(hw, basis_cache, DICT, KEY, total_len)?;
+
+    // truncating the key should release the physical pages past the new end
+    let new_len = VPAGE_SIZE + 7;
+    let mut tail = Vec::<u8>::new();
+    for i in VPAGE_SIZE..new_len {
+        tail.push((i % 251) as u8);
+    }
+    basis_cache.key_update(hw, DICT, KEY, &tail, Some(VPAGE_SIZE), None, None, true)?;
+    let attr = basis_cache.key_attributes(hw, DICT, KEY, None)?;
+    assert!(attr.len == new_len, "truncated length is incorrect");
+    assert!(attr.reserved == 2 * VPAGE_SIZE, "truncated reservation is incorrect");
+    large_stream_check(hw, basis_cache, DICT, KEY, new_len)?;
+
+    basis_cache.dict_remove(hw, DICT, None, false)?;
+    basis_cache.sync(hw, None)
+}
+
+const COMPACT_DICT: &'static str = "compacttest";
+const COMPACT_KEYS: usize = 64;
+const COMPACT_KEYLEN: usize = 300;
+fn compact_check(hw: &mut PddbOs, basis_cache: &mut BasisCache) -> Result<()> {
+    let key_list = basis_cache.key_list(hw, COMPACT_DICT, None, None)?;
+    assert!(key_list.len() == COMPACT_KEYS / 4, "wrong number of keys after compaction: {}", key_list.len());
+    for keynum in (0..COMPACT_KEYS).step_by(4) {
+        let keyname = format!("key{}", keynum);
+        assert!(key_list.contains(&keyname), "key {} went missing", keyname);
+        let mut readbuf = [0u8; COMPACT_KEYLEN];
+        let readlen = basis_cache.key_read(hw, COMPACT_DICT, &keyname, &mut readbuf, None, None)?;
+        assert!(readlen == COMPACT_KEYLEN, "key {} has the wrong length", keyname);
+        for (i, &b) in readbuf.iter().enumerate() {
+            assert!(b == (keynum + i) as u8, "key {} data mismatch at offset {}", keyname, i);
+        }
+    }
+    Ok(())
+}
+/// Fills a dictionary with small keys, and deletes most of them, leaving it in need of a compaction.
+fn compact_setup(hw: &mut PddbOs, basis_cache: &mut BasisCache) -> Result<()> {
+    for keynum in 0..COMPACT_KEYS {
+        let mut keydata = Vec::<u8>::new();
+        for i in 0..COMPACT_KEYLEN {
+            keydata.push((keynum + i) as u8);
+        }
+        basis_cache.key_update(hw, COMPACT_DICT, &format!("key{}", keynum), &keydata, None, None, None, false)?;
+    }
+    basis_cache.sync(hw, None)?;
+    for keynum in 0..COMPACT_KEYS {
+        if keynum % 4 != 0 {
+            basis_cache.key_remove(hw, COMPACT_DICT, &format!("key{}", keynum), None, false)?;
+        }
+    }
+    basis_cache.sync(hw, None)
+}
+/// Fills a dictionary with small keys, deletes most of them, and then compacts it. The remaining keys
+/// are checked for integrity, and the compaction is expected to free up some pages.
+pub(crate) fn compact_test(hw: &mut PddbOs, basis_cache: &mut BasisCache) -> Result<()> {
+    compact_setup(hw, basis_cache)?;
+
+    let freed = basis_cache.compact(hw, None)?;
+    log::info!("compaction freed {} pages", freed);
+    assert!(freed > 0, "compaction did not free any pages");
+    compact_check(hw, basis_cache)
+}
+
+/// Stops a compaction after the compacted copy of the dictionary is committed, but before the old pages are
+/// retired, and re-mounts the basis as if the power had been lost. The page table then maps each of the
+/// dictionary's pages twice, and the compacted copy has to win out for every one of them.
+pub(crate) fn compact_interrupt_test(hw: &mut PddbOs, basis_cache: &mut BasisCache, basis_name: &str, basis_pw: &str) -> Result<()> {
+    compact_setup(hw, basis_cache)?;
+    let retired = basis_cache.dict_compact_copy(hw, COMPACT_DICT, Some(basis_name))?;
+    assert!(retired.len() > 0, "compaction did not replace any pages");
+
+    basis_cache.basis_unmount(hw, basis_name)?;
+    if let Some(basis) = basis_cache.basis_unlock(hw, basis_name, basis_pw, BasisRetentionPolicy::Persist) {
+        basis_cache.basis_add(basis);
+    } else {
+        panic!("couldn't re-mount the basis after the interrupted compaction");
+    }
+    compact_check(hw, basis_cache)?;
+    let basis = basis_cache.basis_entry_mut(basis_name).expect("basis not mounted");
+    let dict = basis.dicts.get(COMPACT_DICT).expect("compacted dictionary not in cache");
+    assert!(dict.last_disk_key_index == COMPACT_KEYS as u32 / 4 + 1, "old dictionary descriptor came back after the interrupted compaction");
+    // a real power loss would leave the old pages mapped but shadowed; retire them so later tests have the space
+    basis.dict_compact_retire(hw, retired);
+    Ok(())
+}
+
+const ERASE_DICT: &'static str = "erasetest";
+fn erase_check(hw: &mut PddbOs, basis_cache: &mut BasisCache) -> Result<()> {
+    let key_list = basis_cache.key_list(hw, ERASE_DICT, None, None)?;
+    assert!(!key_list.contains("small_secret"), "small key survived a paranoid erase");
+    assert!(!key_list.contains("large_secret"), "large key survived a paranoid erase");
+    assert!(key_list.contains("keep"), "neighboring key was lost in a paranoid erase");
+    let mut readbuf = [0u8; 64];
+    let readlen = basis_cache.key_read(hw, ERASE_DICT, "keep", &mut readbuf, None, None)?;
+    assert!(readlen == 64 && readbuf.iter().all(|&b| b == 0x55), "neighboring key was corrupted by a paranoid erase");
+    Ok(())
+}
+/// Deletes a small and a large key with the paranoid flag set, and checks that a key sharing the
+/// small pool page with the deleted key is left intact.
+pub(crate) fn erase_test(hw: &mut PddbOs, basis_cache: &mut BasisCache) -> Result<()> {
+    basis_cache.key_update(hw, ERASE_DICT, "keep", &[0x55u8; 64], None, None, None, false)?;
+    basis_cache.key_update(hw, ERASE_DICT, "small_secret", &[0xAAu8; 100], None, None, None, false)?;
+    basis_cache.key_update(hw, ERASE_DICT, "large_secret", &vec![0xAAu8; VPAGE_SIZE * 2 + 10], None, None, None, false)?;
+    basis_cache.sync(hw, None)?;
+
+    basis_cache.key_remove(hw, ERASE_DICT, "small_secret", None, true)?;
+    basis_cache.key_remove(hw, ERASE_DICT, "large_secret", None, true)?;
+    erase_check(hw, basis_cache)
+}
+
+const ARCHIVE_BASIS: &'static str = "Restored";
+const ARCHIVE_BASIS_PW: &'static str = "restored basis password";
+const ARCHIVE_PASSPHRASE: &'static str = "correct horse battery staple";
+/// Exports `source_basis` to an archive, and imports it into a freshly created basis. The restored
+/// basis must contain exactly the same dictionaries and keys as the source basis.
+pub(crate) fn archive_test(hw: &mut PddbOs, basis_cache: &mut BasisCache, source_basis: &str) -> Result<()> {
+    let archive = basis_export(hw, basis_cache, source_basis, ARCHIVE_PASSPHRASE)?;
+    log::info!("archive of {} is {} bytes", source_basis, archive.len());
+    hw.dbg_archive_dump(&archive, "archive");
+
+    basis_cache.basis_create(hw, ARCHIVE_BASIS, ARCHIVE_BASIS_PW)?;
+    if let Some(restored) = basis_cache.basis_unlock(hw, ARCHIVE_BASIS, ARCHIVE_BASIS_PW, BasisRetentionPolicy::Persist) {
+        basis_cache.basis_add(restored);
+    } else {
+        panic!("couldn't unlock the basis to restore into");
+    }
+    let wrong_pw = basis_import(hw, basis_cache, ARCHIVE_BASIS, "incorrect horse", &archive);
+    assert!(wrong_pw.err().map(|e| e.kind()) == Some(std::io::ErrorKind::PermissionDenied), "archive imported with the wrong passphrase");
+    let mut tampered = archive.clone();
+    let last = tampered.len() - 1;
+    tampered[last] ^= 1;
+    let bad_archive = basis_import(hw, basis_cache, ARCHIVE_BASIS, ARCHIVE_PASSPHRASE, &tampered);
+    assert!(bad_archive.is_err(), "tampered archive was imported");
+    assert!(basis_cache.dict_list(hw, Some(ARCHIVE_BASIS)).len() == 0, "failed imports left data behind");
+
+    let (count, _dicts) = basis_import(hw, basis_cache, ARCHIVE_BASIS, ARCHIVE_PASSPHRASE, &archive)?;
+    let source_dicts = basis_cache.dict_list(hw, Some(source_basis));
+    assert!(basis_cache.dict_list(hw, Some(ARCHIVE_BASIS)) == source_dicts, "restored dictionaries do not match the source");
+    let mut total = 0;
+    for dict in source_dicts.iter() {
+        let keys = basis_cache.key_list(hw, dict, Some(source_basis), None)?;
+        assert!(basis_cache.key_list(hw, dict, Some(ARCHIVE_BASIS), None)? == keys, "restored keys in {} do not match the source", dict);
+        for key in keys.iter() {
+            let src_attr = basis_cache.key_attributes(hw, dict, key, Some(source_basis))?;
+            let dst_attr = basis_cache.key_attributes(hw, dict, key, Some(ARCHIVE_BASIS))?;
+            assert!(src_attr.len == dst_attr.len, "restored key {}:{} has the wrong length", dict, key);
+            let mut src_data = vec![0u8; src_attr.len];
+            let mut dst_data = vec![0u8; dst_attr.len];
+            basis_cache.key_read(hw, dict, key, &mut src_data, None, Some(source_basis))?;
+            basis_cache.key_read(hw, dict, key, &mut dst_data, None, Some(ARCHIVE_BASIS))?;
+            assert!(src_data == dst_data, "restored key {}:{} has the wrong data", dict, key);
+            total += 1;
+        }
+    }
+    assert!(count == total, "imported {} keys, but the source has {}", count, total);
+    basis_cache.basis_unmount(hw, ARCHIVE_BASIS)?;
+    Ok(())
+}
+
+fn tx_read(hw: &mut PddbOs, basis_cache: &mut BasisCache, dict: &str, key: &str, basis_name: &str) -> Option<Vec<u8>> {
+    let attr = basis_cache.key_attributes(hw, dict, key, Some(basis_name)).ok()?;
+    let mut data = vec![0u8; attr.len];
+    basis_cache.key_read(hw, dict, key, &mut data, None, Some(basis_name)).ok()?;
+    Some(data)
+}
+/// Commits a transaction that spans two dictionaries, checks that a transaction with a bad operation
+/// leaves everything untouched, and checks that a transaction interrupted after its operations were
+/// applied is rolled back when the basis is mounted again.
+pub(crate) fn transaction_test(hw: &mut PddbOs, basis_cache: &mut BasisCache, basis_name: &str, basis_pw: &str) -> Result<()> {
+    basis_cache.key_update(hw, "tx_wifi", "ssid", b"old ssid", None, None, Some(basis_name), true)?;
+    basis_cache.key_update(hw, "tx_wifi", "stale", &vec![0x33u8; VPAGE_SIZE + 100], None, None, Some(basis_name), true)?;
+    basis_cache.sync(hw, Some(basis_name))?;
+
+    let large = vec![0x77u8; VPAGE_SIZE * 2 + 5];
+    let ops = [
+        TxOp::Write{dict: "tx_wifi", key: "ssid", data: b"new ssid", reserved: None},
+        TxOp::Write{dict: "tx_secrets", key: "password", data: &large, reserved: None},
+        TxOp::Delete{dict: "tx_wifi", key: "stale"},
+    ];
+    let touched = transaction_commit(hw, basis_cache, basis_name, &ops)?;
+    assert!(touched.len() == 3, "transaction reported {} keys touched", touched.len());
+    assert!(tx_read(hw, basis_cache, "tx_wifi", "ssid", basis_name) == Some(b"new ssid".to_vec()), "transaction write was not applied");
+    assert!(tx_read(hw, basis_cache, "tx_secrets", "password", basis_name) == Some(large.clone()), "transaction write to a new dictionary was not applied");
+    assert!(tx_read(hw, basis_cache, "tx_wifi", "stale", basis_name).is_none(), "transaction delete was not applied");
+    assert!(basis_cache.dict_attributes(hw, TX_DICT, Some(basis_name)).is_err(), "transaction log was left behind");
+
+    // a delete of a missing key fails the whole transaction before anything is written
+    let ops = [
+        TxOp::Write{dict: "tx_wifi", key: "ssid", data: b"should not stick", reserved: None},
+        TxOp::Delete{dict: "tx_wifi", key: "missing"},
+    ];
+    let bad = transaction_commit(hw, basis_cache, basis_name, &ops);
+    assert!(bad.err().map(|e| e.kind()) == Some(std::io::ErrorKind::NotFound), "transaction with a missing key was committed");
+    assert!(tx_read(hw, basis_cache, "tx_wifi", "ssid", basis_name) == Some(b"new ssid".to_vec()), "failed transaction modified a key");
+
+    // simulate a power loss after the operations hit the disk, but before the undo record was removed
+    let ops = [
+        TxOp::Write{dict: "tx_wifi", key: "ssid", data: b"interrupted", reserved: None},
+        TxOp::Delete{dict: "tx_secrets", key: "password"},
+        TxOp::Write{dict: "tx_new", key: "fresh", data: b"fresh", reserved: None},
+    ];
+    transaction_log(hw, basis_cache, basis_name, &ops)?;
+    transaction_apply(hw, basis_cache, basis_name, &ops)?;
+    basis_cache.basis_unmount(hw, basis_name)?;
+    if let Some(basis) = basis_cache.basis_unlock(hw, basis_name, basis_pw, BasisRetentionPolicy::Persist) {
+        basis_cache.basis_add(basis);
+    } else {
+        panic!("couldn't re-mount the basis after the interrupted transaction");
+    }
+    assert!(transaction_recover(hw, basis_cache, basis_name)?, "interrupted transaction was not detected");
+    assert!(tx_read(hw, basis_cache, "tx_wifi", "ssid", basis_name) == Some(b"new ssid".to_vec()), "interrupted write was not rolled back");
+    assert!(tx_read(hw, basis_cache, "tx_secrets", "password", basis_name) == Some(large), "interrupted delete was not rolled back");
+    assert!(basis_cache.dict_attributes(hw, "tx_new", Some(basis_name)).is_err(), "dictionary created by an interrupted transaction survived");
+    assert!(basis_cache.dict_attributes(hw, TX_DICT, Some(basis_name)).is_err(), "transaction log survived the recovery");
+    assert!(!transaction_recover(hw, basis_cache, basis_name)?, "recovery ran twice");
+
+    basis_cache.dict_remove(hw, "tx_wifi", Some(basis_name), false)?;
+    basis_cache.dict_remove(hw, "tx_secrets", Some(basis_name), false)?;
+    basis_cache.sync(hw, Some(basis_name))
+}
+
+/// Checks that a consistent PDDB checks clean, then injects a page that fails to decrypt under a large key,
+/// a mapped page that nothing refers to, and a FastSpace entry for a page in use, and checks that all of
+/// them are found and repaired without disturbing the other keys.
+pub(crate) fn check_test(hw: &mut PddbOs, basis_cache: &mut BasisCache, basis_name: &str, basis_pw: &str) -> Result<()> {
+    // earlier tests can leave unused small pool pages behind, which are legitimately reported as orphans
+    basis_cache.check(hw, true)?;
+    let report = basis_cache.check(hw, false)?;
+    assert!(report.problems() == 0, "consistent PDDB reported problems: {:?}", report);
+
+    basis_cache.key_update(hw, "check_dict", "good", b"good data", None, None, Some(basis_name), true)?;
+    basis_cache.key_update(hw, "check_dict", "dangling", &vec![0x55u8; VPAGE_SIZE * 2], None, None, Some(basis_name), true)?;
+    basis_cache.sync(hw, Some(basis_name))?;
+    {
+        let basis = basis_cache.basis_entry_mut(basis_name).expect("basis not mounted");
+        // overwrite the second page of the large key with noise
+        let start = basis.dicts.get("check_dict").unwrap().keys.get("dangling").unwrap().start;
+        let pp = *basis.v2p_map.get(&VirtAddr::new(start + VPAGE_SIZE as u64).unwrap()).expect("large key page not mapped");
+        let mut noise = [0u8; PAGE_SIZE];
+        hw.trng_slice(&mut noise);
+        hw.patch_data(&noise, pp.page_number() * PAGE_SIZE as u32);
+        // map a valid page far past anything allocated in the large pool
+        let mut orphan = hw.try_fast_space_alloc().expect("no free space");
+        orphan.set_valid(true);
+        let mut page = vec![0u8; VPAGE_SIZE + size_of::<JournalType>()];
+        hw.data_encrypt_and_patch_page(&basis.cipher, &basis.aad, &mut page, &orphan);
+        basis.v2p_map.insert(VirtAddr::new(LARGE_POOL_START + 0x1000_0000 * VPAGE_SIZE as u64).unwrap(), orphan);
+        basis.pt_sync(hw);
+        // hand the root page back to the FastSpace pool while it's still in use
+        let mut conflict = *basis.v2p_map.get(&VirtAddr::new(VPAGE_SIZE as u64).unwrap()).unwrap();
+        hw.fast_space_free(&mut conflict);
+    }
+
+    let report = basis_cache.check(hw, true)?;
+    assert!(report.bad_pages == 1, "bad pages: {:?}", report);
+    assert!(report.dangling_keys == 1, "dangling keys: {:?}", report);
+    assert!(report.orphaned_pages == 1, "orphaned pages: {:?}", report);
+    assert!(report.fastspace_conflicts == 1, "fastspace conflicts: {:?}", report);
+    assert!(report.stale_ptes == 0, "stale PTEs: {:?}", report);
+    let report = basis_cache.check(hw, false)?;
+    assert!(report.problems() == 0, "repaired PDDB reported problems: {:?}", report);
+    assert!(tx_read(hw, basis_cache, "check_dict", "good", basis_name) == Some(b"good data".to_vec()), "repair lost a good key");
+    assert!(basis_cache.key_attributes(hw, "check_dict", "dangling", Some(basis_name)).is_err(), "dangling key survived the repair");
+
+    // the repairs have to stick on disk, too
+    basis_cache.basis_unmount(hw, basis_name)?;
+    if let Some(basis) = basis_cache.basis_unlock(hw, basis_name, basis_pw, BasisRetentionPolicy::Persist) {
+        basis_cache.basis_add(basis);
+    } else {
+        panic!("couldn't re-mount the basis after the repair");
+    }
+    let report = basis_cache.check(hw, false)?;
+    assert!(report.problems() == 0, "repaired PDDB reported problems after a re-mount: {:?}", report);
+
+    basis_cache.dict_remove(hw, "check_dict", Some(basis_name), false)?;
+    basis_cache.sync(hw, Some(basis_name))
+}
+
+fn filtered_keys(hw: &mut PddbOs, basis_cache: &mut BasisCache, dict: &str, filter: &str, basis_name: &str) -> BTreeSet<String> {
+    basis_cache.key_list(hw, dict, Some(basis_name), Some(filter)).unwrap().into_iter().collect()
+}
+/// Checks prefix and glob filtering of key listings.
+pub(crate) fn list_filter_test(hw: &mut PddbOs, basis_cache: &mut BasisCache, basis_name: &str) -> Result<()> {
+    for key in ["wlan.home", "wlan.work", "wlan.work.old", "vpn.home"].iter() {
+        basis_cache.key_update(hw, "filter_dict", key, key.as_bytes(), None, None, Some(basis_name), true)?;
+    }
+    let expect = |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<BTreeSet<String>>();
+    assert!(filtered_keys(hw, basis_cache, "filter_dict", "wlan.", basis_name) == expect(&["wlan.home", "wlan.work", "wlan.work.old"]), "prefix filter failed");
+    assert!(filtered_keys(hw, basis_cache, "filter_dict", "*.home", basis_name) == expect(&["wlan.home", "vpn.home"]), "glob filter failed");
+    assert!(filtered_keys(hw, basis_cache, "filter_dict", "wlan.????", basis_name) == expect(&["wlan.home", "wlan.work"]), "single character glob failed");
+    assert!(filtered_keys(hw, basis_cache, "filter_dict", "eth.", basis_name).len() == 0, "filter matched nothing, but keys were returned");
+    assert!(basis_cache.key_list(hw, "no_such_dict", Some(basis_name), Some("wlan.")).is_err(), "listing a missing dictionary succeeded");
+
+    basis_cache.dict_remove(hw, "filter_dict", Some(basis_name), false)?;
+    basis_cache.sync(hw, Some(basis_name))
+}
+
 pub(crate) fn list_all(hw: &mut PddbOs, basis_cache: &mut BasisCache) {
     #[cfg(feature = "deterministic")]
     let mut dict_list = BTreeSet::<String>::new();
@@ -366,7 +703,7 @@ pub(crate) fn list_all(hw: &mut PddbOs, basis_cache: &mut BasisCache) {
         };
         log::debug!("{:?}", da);
         let mut sanity_count = 0;
-        for key in basis_cache.key_list(hw, dict, None).unwrap().iter() {
+        for key in basis_cache.key_list(hw, dict, None, None).unwrap().iter() {
             let attrs = match basis_cache.key_attributes(hw, dict, key, None) {
                 Ok(a) => a,
                 Err(e) => {log::debug!("key not in basis, searching another basis, {:?}", e);  continue},
@@ -468,7 +805,7 @@ pub(crate) fn ci_tests(pddb_os: &mut PddbOs) -> Result<()> {
 
         let mut pre_list = HashSet::<String>::new();
         for dict in basis_cache.dict_list(pddb_os, None).iter() {
-            for key in basis_cache.key_list(pddb_os, dict, None).unwrap().iter() {
+            for key in basis_cache.key_list(pddb_os, dict, None, None).unwrap().iter() {
                 pre_list.insert(key.to_string());
             }
         }
@@ -496,7 +833,7 @@ pub(crate) fn ci_tests(pddb_os: &mut PddbOs) -> Result<()> {
 
         let mut merge_list = HashSet::<String>::new();
         for dict in basis_cache.dict_list(pddb_os, None).iter() {
-            for key in basis_cache.key_list(pddb_os, dict, None).unwrap().iter() {
+            for key in basis_cache.key_list(pddb_os, dict, None, None).unwrap().iter() {
                 merge_list.insert(key.to_string());
             }
         }
@@ -504,7 +841,7 @@ pub(crate) fn ci_tests(pddb_os: &mut PddbOs) -> Result<()> {
 
         let mut b2_list = HashSet::<String>::new();
         for dict in basis_cache.dict_list(pddb_os, Some(EXTRA_BASIS)).iter() {
-            for key in basis_cache.key_list(pddb_os, dict, Some(EXTRA_BASIS)).unwrap().iter() {
+            for key in basis_cache.key_list(pddb_os, dict, Some(EXTRA_BASIS), None).unwrap().iter() {
                 b2_list.insert(key.to_string());
             }
         }
@@ -513,7 +850,7 @@ pub(crate) fn ci_tests(pddb_os: &mut PddbOs) -> Result<()> {
         basis_cache.basis_unmount(pddb_os, EXTRA_BASIS).unwrap();
         let mut post_list = HashSet::<String>::new();
         for dict in basis_cache.dict_list(pddb_os, None).iter() {
-            for key in basis_cache.key_list(pddb_os, dict, None).unwrap().iter() {
+            for key in basis_cache.key_list(pddb_os, dict, None, None).unwrap().iter() {
                 post_list.insert(key.to_string());
             }
         }
@@ -525,7 +862,7 @@ pub(crate) fn ci_tests(pddb_os: &mut PddbOs) -> Result<()> {
         }
         let mut remount_list = HashSet::<String>::new();
         for dict in basis_cache.dict_list(pddb_os, None).iter() {
-            for key in basis_cache.key_list(pddb_os, dict, None).unwrap().iter() {
+            for key in basis_cache.key_list(pddb_os, dict, None, None).unwrap().iter() {
                 remount_list.insert(key.to_string());
             }
         }
@@ -538,13 +875,96 @@ pub(crate) fn ci_tests(pddb_os: &mut PddbOs) -> Result<()> {
         }
         let mut merge2_list = HashSet::<String>::new();
         for dict in basis_cache.dict_list(pddb_os, None).iter() {
-            for key in basis_cache.key_list(pddb_os, dict, None).unwrap().iter() {
+            for key in basis_cache.key_list(pddb_os, dict, None, None).unwrap().iter() {
                 merge2_list.insert(key.to_string());
             }
         }
         assert!(merge2_list.difference(&merge_list).count() == 0, "merged list is different from the original list after remount");
         list_all(pddb_os, &mut basis_cache);
 
+        log::info!("Doing large key streaming test");
+        large_stream_test(pddb_os, &mut basis_cache)?;
+
+        log::info!("Doing dictionary compaction test");
+        compact_test(pddb_os, &mut basis_cache)?;
+        // the compacted dictionary has to come back intact from disk, too
+        basis_cache.basis_unmount(pddb_os, EXTRA_BASIS)?;
+        if let Some(basis2) = basis_cache.basis_unlock(pddb_os,
+            EXTRA_BASIS, EXTRA_BASIS_PW, BasisRetentionPolicy::Persist) {
+            basis_cache.basis_add(basis2);
+        }
+        compact_check(pddb_os, &mut basis_cache)?;
+        basis_cache.dict_remove(pddb_os, COMPACT_DICT, None, false)?;
+        basis_cache.sync(pddb_os, None)?;
+
+        log::info!("Doing interrupted compaction test");
+        compact_interrupt_test(pddb_os, &mut basis_cache, EXTRA_BASIS, EXTRA_BASIS_PW)?;
+        basis_cache.dict_remove(pddb_os, COMPACT_DICT, None, false)?;
+        basis_cache.sync(pddb_os, None)?;
+
+        log::info!("Doing paranoid erase test");
+        erase_test(pddb_os, &mut basis_cache)?;
+        // erased keys must not come back from their descriptors on disk
+        basis_cache.basis_unmount(pddb_os, EXTRA_BASIS)?;
+        if let Some(basis2) = basis_cache.basis_unlock(pddb_os,
+            EXTRA_BASIS, EXTRA_BASIS_PW, BasisRetentionPolicy::Persist) {
+            basis_cache.basis_add(basis2);
+        }
+        erase_check(pddb_os, &mut basis_cache)?;
+        basis_cache.dict_remove(pddb_os, ERASE_DICT, None, true)?;
+        basis_cache.sync(pddb_os, None)?;
+
+        log::info!("Doing basis time-out test");
+        basis_cache.basis_unmount(pddb_os, EXTRA_BASIS)?;
+        if let Some(basis2) = basis_cache.basis_unlock(pddb_os,
+            EXTRA_BASIS, EXTRA_BASIS_PW, BasisRetentionPolicy::TimeOutSecs(3)) {
+            basis_cache.basis_add(basis2);
+        }
+        assert!(basis_cache.retention_poll(pddb_os, 2).len() == 0, "basis timed out too early");
+        // a union read is served by the basis that has the key, and re-arms that basis's timer, even if it
+        // isn't the top one
+        const TOP_BASIS: &'static str = "Basis3";
+        const TOP_BASIS_PW: &'static str = "a basis that goes on top";
+        basis_cache.key_update(pddb_os, "timeout", "lower", b"lower key", None, None, Some(EXTRA_BASIS), true)?;
+        basis_cache.basis_create(pddb_os, TOP_BASIS, TOP_BASIS_PW)?;
+        if let Some(basis3) = basis_cache.basis_unlock(pddb_os,
+            TOP_BASIS, TOP_BASIS_PW, BasisRetentionPolicy::Persist) {
+            basis_cache.basis_add(basis3);
+        }
+        assert!(basis_cache.retention_poll(pddb_os, 2).len() == 0, "basis timed out too early");
+        let mut readback = [0u8; 9];
+        basis_cache.key_read(pddb_os, "timeout", "lower", &mut readback, None, None)?;
+        assert!(&readback == b"lower key", "union read was not served by the lower basis");
+        assert!(basis_cache.retention_poll(pddb_os, 2).len() == 0, "union read did not reset the time-out of the basis that served it");
+        basis_cache.basis_unmount(pddb_os, TOP_BASIS)?;
+        basis_cache.dict_remove(pddb_os, "timeout", Some(EXTRA_BASIS), false)?;
+        // an access re-arms the timer
+        basis_cache.dict_list(pddb_os, Some(EXTRA_BASIS));
+        assert!(basis_cache.retention_poll(pddb_os, 2).len() == 0, "basis access did not reset the time-out");
+        // the PDDB's own housekeeping doesn't count as an access
+        basis_cache.sync(pddb_os, Some(EXTRA_BASIS))?;
+        assert!(basis_cache.has_time_out_policy(), "time-out policy not reported");
+        let locked = basis_cache.retention_poll(pddb_os, 1);
+        assert!(locked.len() == 1 && locked[0] == EXTRA_BASIS, "basis did not time out");
+        assert!(!basis_cache.basis_list().contains(&EXTRA_BASIS.to_string()), "timed-out basis is still mounted");
+        assert!(!basis_cache.has_time_out_policy(), "time-out policy reported with no timed basis mounted");
+
+        log::info!("Doing basis archive export/import test");
+        if let Some(basis2) = basis_cache.basis_unlock(pddb_os,
+            EXTRA_BASIS, EXTRA_BASIS_PW, BasisRetentionPolicy::Persist) {
+            basis_cache.basis_add(basis2);
+        }
+        archive_test(pddb_os, &mut basis_cache, EXTRA_BASIS)?;
+
+        log::info!("Doing multi-key transaction test");
+        transaction_test(pddb_os, &mut basis_cache, EXTRA_BASIS, EXTRA_BASIS_PW)?;
+
+        log::info!("Doing filtered key listing test");
+        list_filter_test(pddb_os, &mut basis_cache, EXTRA_BASIS)?;
+
+        log::info!("Doing consistency check test");
+        check_test(pddb_os, &mut basis_cache, EXTRA_BASIS, EXTRA_BASIS_PW)?;
+
         log::info!("CI done");
         Ok(())
     }
@@ -313,7 +313,15 @@ fn xmain() -> ! {
           2. Main menu -> trigger initialization
           3. PDDB
     */
-    let keys_sid = xns.register_name(api::SERVER_NAME_KEYS, Some(3)).expect("can't register server");
+    let keys_sid = xns.register_name_with_acl(
+        api::SERVER_NAME_KEYS,
+        Some(3),
+        &[
+            xous_names::api::AclEntry::process("shellchat"),
+            xous_names::api::AclEntry::process("status"),
+            xous_names::api::AclEntry::process("pddb"),
+        ],
+    ).expect("can't register server");
 
     let mut keys = RootKeys::new();
     log::info!("Boot FPGA key source: {:?}", keys.fpga_key_source());
@@ -17,6 +17,7 @@ pub struct NetCmd {
     callback_conn: u32,
     udp_count: u32,
     dns: Dns,
+    http: Option<http_client::Client>,
     #[cfg(any(target_os = "none", target_os = "xous"))]
     ping: Option<net::Ping>,
 }
@@ -29,6 +30,7 @@ impl NetCmd {
             callback_conn: xns.request_connection_blocking(crate::SERVER_NAME_SHELLCHAT).unwrap(),
             udp_count: 0,
             dns: dns::Dns::new(&xns).unwrap(),
+            http: None,
             #[cfg(any(target_os = "none", target_os = "xous"))]
             ping: None,
         }
@@ -55,10 +57,10 @@ impl<'a> ShellCmdApi<'a> for NetCmd {
         use core::fmt::Write;
         let mut ret = String::<1024>::new();
         #[cfg(any(target_os = "none", target_os = "xous"))]
-        let helpstring = "net [udp [port]] [udpclose] [udpclone] [udpcloneclose] [ping [host] [count]] [tcpget host/path]";
+        let helpstring = "net [udp [port]] [udpclose] [udpclone] [udpcloneclose] [ping [host] [count]] [tcpget host/path] [fetch url] [sntp [sync|server name|utc +hh:mm]] [ipv6] [sockets] [dns [cache|flush|cname|txt|srv] name] [dns upstream [udp|tls|tls-fallback] [addr name]]";
         // no ping in hosted mode -- why would you need it? we're using the host's network connection.
         #[cfg(not(any(target_os = "none", target_os = "xous")))]
-        let helpstring = "net [udp [port]] [udpclose] [udpclone] [udpcloneclose] [count]] [tcpget host/path]";
+        let helpstring = "net [udp [port]] [udpclose] [udpclone] [udpcloneclose] [count]] [tcpget host/path] [fetch url] [sntp [sync|server name|utc +hh:mm]] [sockets] [dns [cache|flush|cname|txt|srv] name] [dns upstream [udp|tls|tls-fallback] [addr name]]";
 
         let mut tokens = args.as_str().unwrap().split(' ');
 
@@ -120,6 +122,29 @@ impl<'a> ShellCmdApi<'a> for NetCmd {
                         write!(ret, "Usage: tcpget bunniefoo.com/bunnie/test.txt").unwrap();
                     }
                 }
+                "fetch" => {
+                    if let Some(url) = tokens.next() {
+                        if self.http.is_none() {
+                            self.http = Some(http_client::Client::new(&env.xns));
+                        }
+                        match self.http.as_mut().unwrap().get(url) {
+                            Ok(response) => {
+                                log::info!("fetched {} ({} bytes)", response.url, response.body.len());
+                                write!(ret, "{} {}, {} bytes\n", response.status, response.reason, response.body.len()).unwrap();
+                                // only the start of the body fits in a chat bubble
+                                let text = response.text();
+                                let mut end = text.len().min(512);
+                                while !text.is_char_boundary(end) {
+                                    end -= 1;
+                                }
+                                write!(ret, "{}", &text[..end]).unwrap();
+                            }
+                            Err(e) => write!(ret, "Couldn't fetch {}: {:?}", url, e).unwrap(),
+                        }
+                    } else {
+                        write!(ret, "Usage: fetch https://bunniefoo.com/bunnie/test.txt").unwrap();
+                    }
+                }
                 "server" => {
                     // PLEASE NOTE:
                     // Trying to make a TCP server of some kind? Don't be shy to open an issue at
@@ -255,17 +280,213 @@ impl<'a> ShellCmdApi<'a> for NetCmd {
                     write!(ret, "Closed cloned UDP socket").unwrap();
                 }
                 "dns" => {
-                    if let Some(name) = tokens.next() {
-                        match self.dns.lookup(name) {
-                            Ok(ipaddr) => {
-                                write!(ret, "DNS resolved {}->{:?}", name, ipaddr).unwrap();
+                    match tokens.next() {
+                        Some("cache") => {
+                            if let Some(name) = tokens.next() {
+                                match self.dns.cache_dump(name)? {
+                                    Some(dns::api::CachedName::Addresses(addrs)) => {
+                                        write!(ret, "{} cached:", name).unwrap();
+                                        for (addr, ttl) in addrs {
+                                            write!(ret, "\n  {:?} ({}s left)", addr, ttl).unwrap();
+                                        }
+                                    }
+                                    Some(dns::api::CachedName::Negative { code, ttl }) => {
+                                        write!(ret, "{} cached as {:?} ({}s left)", name, code, ttl).unwrap();
+                                    }
+                                    None => {
+                                        write!(ret, "{} is not cached", name).unwrap();
+                                    }
+                                }
+                            } else {
+                                write!(ret, "usage: net dns cache name").unwrap();
                             }
-                            Err(e) => {
-                                write!(ret, "DNS lookup error: {:?}", e).unwrap();
+                        }
+                        Some("flush") => {
+                            if let Some(name) = tokens.next() {
+                                if self.dns.cache_flush_name(name)? {
+                                    write!(ret, "Flushed {} from the DNS cache", name).unwrap();
+                                } else {
+                                    write!(ret, "{} was not in the DNS cache", name).unwrap();
+                                }
+                            } else {
+                                self.dns.flush_cache()?;
+                                write!(ret, "Flushed the DNS cache").unwrap();
                             }
                         }
+                        Some(kind @ "cname") | Some(kind @ "txt") | Some(kind @ "srv") => {
+                            let rtype = match kind {
+                                "cname" => dns::api::RecordType::Cname,
+                                "txt" => dns::api::RecordType::Txt,
+                                _ => dns::api::RecordType::Srv,
+                            };
+                            if let Some(name) = tokens.next() {
+                                match self.dns.lookup_records(name, rtype) {
+                                    Ok(records) if records.is_empty() => {
+                                        write!(ret, "{} has no {} records", name, kind).unwrap();
+                                    }
+                                    Ok(records) => {
+                                        write!(ret, "{} {}:", name, kind).unwrap();
+                                        for record in records {
+                                            match record {
+                                                dns::api::DnsRecord::Txt(text) => {
+                                                    write!(ret, "\n  {}", std::string::String::from_utf8_lossy(&text)).unwrap();
+                                                }
+                                                dns::api::DnsRecord::Srv { priority, weight, port, target } => {
+                                                    write!(ret, "\n  {}:{} (priority {}, weight {})", target, port, priority, weight).unwrap();
+                                                }
+                                                dns::api::DnsRecord::Cname(target) => {
+                                                    write!(ret, "\n  {}", target).unwrap();
+                                                }
+                                                other => {
+                                                    write!(ret, "\n  {:?}", other).unwrap();
+                                                }
+                                            }
+                                        }
+                                    }
+                                    Err(e) => {
+                                        write!(ret, "DNS lookup error: {:?}", e).unwrap();
+                                    }
+                                }
+                            } else {
+                                write!(ret, "usage: net dns {} name", kind).unwrap();
+                            }
+                        }
+                        Some("upstream") => {
+                            if let Some(mode) = tokens.next() {
+                                // the servers are given as pairs of address and name; without any, the ones set before are kept
+                                let rest: Vec<&str> = tokens.by_ref().collect();
+                                let mut servers = Vec::new();
+                                for pair in rest.chunks(2) {
+                                    match dns::api::TlsServer::parse(&pair.join(" ")) {
+                                        Some(server) => servers.push(server),
+                                        None => {
+                                            write!(ret, "Invalid DNS-over-TLS server: {}", pair.join(" ")).unwrap();
+                                            return Ok(Some(ret));
+                                        }
+                                    }
+                                }
+                                if servers.is_empty() {
+                                    servers = self.dns.upstream()?.1;
+                                }
+                                match dns::api::UpstreamMode::from_name(mode) {
+                                    Some(mode) => match self.dns.set_upstream(mode, &servers) {
+                                        Ok(_) => write!(ret, "DNS upstream set to {}", mode.name()).unwrap(),
+                                        Err(e) => write!(ret, "Couldn't set DNS upstream: {:?}", e).unwrap(),
+                                    },
+                                    None => {
+                                        write!(ret, "usage: net dns upstream [udp|tls|tls-fallback] [addr name]...").unwrap();
+                                    }
+                                }
+                            } else {
+                                let (mode, servers) = self.dns.upstream()?;
+                                write!(ret, "DNS upstream: {}", mode.name()).unwrap();
+                                for server in servers {
+                                    write!(ret, "\n  {} {}", server.addr, server.name).unwrap();
+                                }
+                            }
+                        }
+                        Some(name) => {
+                            match self.dns.lookup(name) {
+                                Ok(ipaddr) => {
+                                    write!(ret, "DNS resolved {}->{:?}", name, ipaddr).unwrap();
+                                }
+                                Err(e) => {
+                                    write!(ret, "DNS lookup error: {:?}", e).unwrap();
+                                }
+                            }
+                        }
+                        None => {
+                            write!(ret, "usage: net dns [cache|flush|cname|txt|srv] name").unwrap();
+                        }
                     }
                 }
+                "sntp" => {
+                    let sntp = sntp::Sntp::new(&env.xns)?;
+                    match tokens.next() {
+                        Some("sync") => {
+                            sntp.sync()?;
+                            write!(ret, "SNTP sync requested").unwrap();
+                        }
+                        Some("server") => {
+                            if let Some(server) = tokens.next() {
+                                match sntp.set_server(server) {
+                                    Ok(_) => write!(ret, "SNTP server set to {}", server).unwrap(),
+                                    Err(e) => write!(ret, "Couldn't set SNTP server: {:?}", e).unwrap(),
+                                }
+                            } else {
+                                write!(ret, "Usage: net sntp server pool.ntp.org").unwrap();
+                            }
+                        }
+                        Some("utc") => {
+                            // offsets are given as +hh:mm or -hh:mm, e.g. +08:00 or -03:30
+                            let offset = tokens.next().and_then(|offset| {
+                                let (sign, hhmm) = match offset.strip_prefix('-') {
+                                    Some(hhmm) => (-1, hhmm),
+                                    None => (1, offset.strip_prefix('+').unwrap_or(offset)),
+                                };
+                                let (hh, mm) = hhmm.split_once(':').unwrap_or((hhmm, "0"));
+                                let (hh, mm) = (hh.parse::<i32>().ok()?, mm.parse::<i32>().ok()?);
+                                if mm >= 60 {
+                                    return None;
+                                }
+                                Some(sign * (hh * 3600 + mm * 60))
+                            });
+                            if let Some(offset) = offset {
+                                match sntp.set_utc_offset(offset) {
+                                    Ok(_) => write!(ret, "UTC offset set to {} seconds", offset).unwrap(),
+                                    Err(e) => write!(ret, "Couldn't set UTC offset: {:?}", e).unwrap(),
+                                }
+                            } else {
+                                write!(ret, "Usage: net sntp utc +08:00").unwrap();
+                            }
+                        }
+                        _ => {
+                            let status = sntp.status()?;
+                            match status.last_sync {
+                                Some(secs) => write!(ret, "last sync: {} (UTC, Unix time)\noffset: {} ms, delay: {} ms\n",
+                                    secs, status.offset_ms, status.delay_ms).unwrap(),
+                                None => write!(ret, "not synced yet\n").unwrap(),
+                            }
+                            let sign = if status.utc_offset < 0 { '-' } else { '+' };
+                            let offset = status.utc_offset.abs();
+                            write!(ret, "server: {}\nUTC offset: {}{:02}:{:02}\nfailures: {}",
+                                status.server,
+                                sign, offset / 3600, (offset / 60) % 60,
+                                status.failures,
+                            ).unwrap();
+                        }
+                    }
+                }
+                "sockets" => {
+                    let (list, total) = net::NetManager::new().socket_list()?;
+                    write!(ret, "{} sockets open, at most {} per process", total, net::MAX_SOCKETS_PER_PROCESS).unwrap();
+                    for socket in list.iter() {
+                        let kind = match socket.kind {
+                            net::SocketKind::TcpStream => "tcp",
+                            net::SocketKind::TcpListener => "tcp-listen",
+                            net::SocketKind::UdpSocket => "udp",
+                            net::SocketKind::StdTcpStream => "std-tcp",
+                        };
+                        let pid = socket.pid.map(|pid| pid.get().to_string()).unwrap_or_else(|| "?".to_string());
+                        let remote = socket.remote.map(|addr| format!(" -> {}", addr)).unwrap_or_default();
+                        let state = socket.tcp_state.map(|state| format!(" {}", state)).unwrap_or_default();
+                        write!(ret, "\npid {} {} :{}{}{}", pid, kind, socket.local_port, remote, state).unwrap();
+                    }
+                }
+                #[cfg(any(target_os = "none", target_os = "xous"))]
+                "ipv6" => {
+                    let conf = net::NetManager::new().get_ipv6_config();
+                    let fmt_addr = |addr: Option<[u8; 16]>| match addr {
+                        Some(a) => format!("{}", std::net::Ipv6Addr::from(a)),
+                        None => "none".to_string(),
+                    };
+                    write!(ret, "link-local: {}\nslaac: {}\nstatic: {}/{}\ngateway: {}",
+                        fmt_addr(conf.link_local),
+                        fmt_addr(conf.slaac),
+                        fmt_addr(conf.static_addr), conf.static_prefix_len,
+                        fmt_addr(conf.gateway),
+                    ).unwrap();
+                }
                 #[cfg(any(target_os = "none", target_os = "xous"))]
                 "ping" => {
                     if let Some(name) = tokens.next() {
@@ -7,7 +7,7 @@ pub struct PddbCmd {
 impl PddbCmd {
     pub fn new(_xns: &xous_names::XousNames) -> PddbCmd {
         PddbCmd {
-            pddb: pddb::Pddb::new(),
+            pddb: pddb::Pddb::new().expect("couldn't connect to the PDDB"),
         }
     }
 }
@@ -18,7 +18,7 @@ impl<'a> ShellCmdApi<'a> for PddbCmd {
     fn process(&mut self, args: String::<1024>, _env: &mut CommonEnv) -> Result<Option<String::<1024>>, xous::Error> {
         use core::fmt::Write;
         let mut ret = String::<1024>::new();
-        let helpstring = "pddb [basislist] [dictlist] [keylist] [query]";
+        let helpstring = "pddb [basislist] [dictlist [filter]] [keylist dict [filter]] [query] [check] [check repair]";
 
         let mut tokens = args.as_str().unwrap().split(' ');
         if let Some(sub_cmd) = tokens.next() {
@@ -76,7 +76,7 @@ impl<'a> ShellCmdApi<'a> for PddbCmd {
                 }
                 "keylist" => {
                     if let Some(dict) = tokens.next() {
-                        match self.pddb.list_keys(dict, None) {
+                        match self.pddb.list_keys_matching(dict, tokens.next(), None) {
                             Ok(list) => {
                                 let checked_len = if list.len() > 6 {
                                     write!(ret, "First 6 keys of {}:", list.len()).unwrap();
@@ -103,7 +103,7 @@ impl<'a> ShellCmdApi<'a> for PddbCmd {
                     }
                 }
                 "dictlist" => {
-                    match self.pddb.list_dict(None) {
+                    match self.pddb.list_dict_matching(tokens.next(), None) {
                         Ok(list) => {
                             let checked_len = if list.len() > 6 {
                                 write!(ret, "First 6 dicts of {}:", list.len()).unwrap();
@@ -126,6 +126,27 @@ impl<'a> ShellCmdApi<'a> for PddbCmd {
                         Err(_) => write!(ret, "Error encountered listing dictionaries").ok().unwrap_or(()),
                     }
                 }
+                "check" => {
+                    let repair = tokens.next() == Some("repair");
+                    match self.pddb.check(repair) {
+                        Ok(report) => {
+                            write!(ret, "Checked {} bases, {} pages\n", report.bases, report.pages).unwrap();
+                            if report.problems() == 0 {
+                                write!(ret, "No problems found").unwrap();
+                            } else {
+                                write!(ret, "Bad pages: {}\nStale PTEs: {}\nOrphaned pages: {}\nDangling keys: {}\nFastSpace conflicts: {}\n",
+                                    report.bad_pages, report.stale_ptes, report.orphaned_pages,
+                                    report.dangling_keys, report.fastspace_conflicts).unwrap();
+                                if repair {
+                                    write!(ret, "Problems repaired").unwrap();
+                                } else {
+                                    write!(ret, "Run 'pddb check repair' to fix").unwrap();
+                                }
+                            }
+                        }
+                        Err(e) => write!(ret, "Check failed: {:?}", e).unwrap(),
+                    }
+                }
                 _ => {
                     write!(ret, "{}", helpstring).unwrap();
                 }
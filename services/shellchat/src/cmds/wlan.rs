@@ -24,6 +24,8 @@ wlan shell command:
         and password, otherwise NOP
 - leave: if joined, disconnect from AP
 - status: get wlan radio status (power state? connected? AP info?)
+- priority N ...: set the priority of saved network ... to N; higher priority networks are joined first
+- hidden on|off ...: mark saved network ... as hidden, so it's tried even when a scan doesn't find it
 */
 impl<'a> ShellCmdApi<'a> for Wlan {
     cmd_api!(wlan); // inserts boilerplate for command API
@@ -34,7 +36,7 @@ impl<'a> ShellCmdApi<'a> for Wlan {
         env: &mut CommonEnv,
     ) -> Result<Option<String<1024>>, xous::Error> {
         let mut ret = String::<1024>::new();
-        let helpstring = "wlan [on] [off] [setssid ...] [setpass ...] [join] [leave] [status] [save] [known]";
+        let helpstring = "wlan [on] [off] [setssid ...] [setpass ...] [join] [leave] [status] [save] [known] [priority N ...] [hidden on|off ...]";
         let mut show_help = false;
 
         let mut tokens = args.as_str().unwrap().split(' ');
@@ -84,7 +86,7 @@ impl<'a> ShellCmdApi<'a> for Wlan {
                     };
                 }
                 "save" => {
-                    let mut pddb = pddb::Pddb::new();
+                    let mut pddb = pddb::Pddb::new().expect("couldn't connect to the PDDB");
                     if let Some(ssid) = &self.current_ssid {
                         if let Some(pass) = &self.current_pass {
                             match pddb.get(
@@ -121,12 +123,14 @@ impl<'a> ShellCmdApi<'a> for Wlan {
                     }
                 }
                 "known" => {
-                    let mut pddb = pddb::Pddb::new();
+                    let mut pddb = pddb::Pddb::new().expect("couldn't connect to the PDDB");
                     match pddb.list_keys(net::AP_DICT_NAME, None) {
                         Ok(list) => {
-                            write!(ret, "Saved network configs:\n").unwrap();
+                            write!(ret, "Saved network configs:").unwrap();
                             for item in list.iter() {
-                                write!(ret, "- {}", item).ok(); // whatever, maybe we have too many?
+                                let network = net::SavedNetwork::read(&mut pddb, item);
+                                write!(ret, "\n- {} (priority {}{})", item, network.priority,
+                                    if network.hidden {", hidden"} else {""}).ok(); // whatever, maybe we have too many?
                             }
                         }
                         Err(e) => {
@@ -134,6 +138,32 @@ impl<'a> ShellCmdApi<'a> for Wlan {
                         }
                     }
                 }
+                "priority" => {
+                    let priority = tokens.next().and_then(|p| p.parse::<u8>().ok());
+                    let mut ssid = String::<1024>::new();
+                    join_tokens(&mut ssid, &mut tokens);
+                    match priority {
+                        Some(priority) if ssid.len() > 0 => {
+                            update_network(&mut ret, ssid.as_str().unwrap(), |network| network.priority = priority);
+                        }
+                        _ => write!(ret, "usage: wlan priority <0-255> <ssid>").unwrap(),
+                    }
+                }
+                "hidden" => {
+                    let hidden = match tokens.next() {
+                        Some("on") => Some(true),
+                        Some("off") => Some(false),
+                        _ => None,
+                    };
+                    let mut ssid = String::<1024>::new();
+                    join_tokens(&mut ssid, &mut tokens);
+                    match hidden {
+                        Some(hidden) if ssid.len() > 0 => {
+                            update_network(&mut ret, ssid.as_str().unwrap(), |network| network.hidden = hidden);
+                        }
+                        _ => write!(ret, "usage: wlan hidden on|off <ssid>").unwrap(),
+                    }
+                }
                 "join" => {
                     let _ = match env.com.wlan_join() {
                         Ok(_) => {
@@ -173,6 +203,24 @@ impl<'a> ShellCmdApi<'a> for Wlan {
     }
 }
 
+/// Changes the connection manager's settings for the saved network `ssid`
+fn update_network(ret: &mut String<1024>, ssid: &str, update: impl FnOnce(&mut net::SavedNetwork)) {
+    let mut pddb = pddb::Pddb::new().expect("couldn't connect to the PDDB");
+    match pddb.list_keys(net::AP_DICT_NAME, None) {
+        Ok(list) if list.iter().any(|item| item == ssid) => {
+            let mut network = net::SavedNetwork::read(&mut pddb, ssid);
+            update(&mut network);
+            match network.write(&mut pddb, ssid) {
+                Ok(_) => write!(ret, "{}: priority {}{}", ssid, network.priority,
+                    if network.hidden {", hidden"} else {""}).unwrap(),
+                Err(e) => write!(ret, "PDDB error storing network settings: {:?}", e).unwrap(),
+            }
+        }
+        Ok(_) => write!(ret, "{} is not a saved network; use `save` first", ssid).unwrap(),
+        Err(e) => write!(ret, "PDDB error accessing network configs: {:?}", e).unwrap(),
+    }
+}
+
 /**
 Join an iterator of string tokens with spaces.
 
@@ -0,0 +1,61 @@
+#[allow(dead_code)]
+pub(crate) const SERVER_NAME_SNTP: &str = "_SNTP time sync_";
+
+/// Longest server name that can be configured
+pub const SNTP_SERVER_LEN: usize = 64;
+/// The server that is used unless another one is configured
+pub const SNTP_DEFAULT_SERVER: &str = "pool.ntp.org";
+
+/// PDDB dictionary holding the settings, each stored as text
+pub const SNTP_DICT: &str = "net.sntp";
+pub const SNTP_KEY_SERVER: &str = "server";
+/// Offset of local time from UTC, in seconds
+pub const SNTP_KEY_UTC_OFFSET: &str = "utc_offset";
+
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug)]
+pub(crate) enum Opcode {
+    /// Sync now, if there is a network connection
+    Sync = 0,
+    /// Fetch an `SntpStatus`
+    GetStatus = 1,
+    /// Set the server to sync with, as a `String<SNTP_SERVER_LEN>`
+    SetServer = 2,
+    /// Set the offset of local time from UTC, in seconds; arg1 is an `i32`. The RTC is adjusted immediately.
+    SetUtcOffset = 3,
+
+    /// used internally: wifi status updates from the connection manager
+    WifiStats = 4,
+    /// used internally: periodically checks if a sync is due
+    Poll = 5,
+
+    Quit = 6,
+}
+
+#[derive(Debug, Copy, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct SntpStatus {
+    /// UTC time of the last successful sync, in seconds since the Unix epoch
+    pub last_sync: Option<u64>,
+    /// How far the RTC was behind the server's time when it was last synced, in milliseconds.
+    /// The RTC only keeps whole seconds, so this is accurate to about a second.
+    pub offset_ms: i64,
+    /// Round-trip delay to the server at the last sync, in milliseconds
+    pub delay_ms: u32,
+    /// The server that is synced with
+    pub server: xous_ipc::String<SNTP_SERVER_LEN>,
+    /// Offset of local time, which is what the RTC keeps, from UTC, in seconds
+    pub utc_offset: i32,
+    /// Failed sync attempts since the last successful one
+    pub failures: u32,
+}
+impl Default for SntpStatus {
+    fn default() -> Self {
+        SntpStatus {
+            last_sync: None,
+            offset_ms: 0,
+            delay_ms: 0,
+            server: xous_ipc::String::from_str(SNTP_DEFAULT_SERVER),
+            utc_offset: 0,
+            failures: 0,
+        }
+    }
+}
@@ -0,0 +1,98 @@
+pub mod api;
+pub use api::*;
+
+use num_traits::ToPrimitive;
+use std::io::{Error, ErrorKind};
+use xous::{send_message, Message, CID};
+use xous_ipc::{Buffer, String};
+
+/// Keeps the RTC in sync with an SNTP server.
+///
+/// The server syncs on its own whenever the connection manager reports a new IP configuration, and
+/// periodically after that; this handle is for checking on it and changing its settings.
+#[derive(Debug)]
+pub struct Sntp {
+    conn: CID,
+}
+impl Sntp {
+    pub fn new(xns: &xous_names::XousNames) -> Result<Self, xous::Error> {
+        REFCOUNT.fetch_add(1, Ordering::Relaxed);
+        let conn = xns
+            .request_connection_blocking(crate::api::SERVER_NAME_SNTP)
+            .expect("Can't connect to SNTP server");
+        Ok(Sntp { conn })
+    }
+    pub fn status(&self) -> Result<SntpStatus, xous::Error> {
+        let mut buf =
+            Buffer::into_buf(SntpStatus::default()).or(Err(xous::Error::InternalError))?;
+        buf.lend_mut(self.conn, Opcode::GetStatus.to_u32().unwrap())?;
+        buf.to_original::<SntpStatus, _>()
+            .or(Err(xous::Error::InternalError))
+    }
+    /// Requests a sync; it happens in the background, so check `status()` for the outcome.
+    pub fn sync(&self) -> Result<(), xous::Error> {
+        send_message(
+            self.conn,
+            Message::new_scalar(Opcode::Sync.to_usize().unwrap(), 0, 0, 0, 0),
+        )
+        .map(|_| ())
+    }
+    /// Sets the server to sync with. The setting is kept in the PDDB.
+    pub fn set_server(&self, server: &str) -> std::io::Result<()> {
+        if server.is_empty() || server.len() > SNTP_SERVER_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid SNTP server name",
+            ));
+        }
+        let buf = Buffer::into_buf(String::<SNTP_SERVER_LEN>::from_str(server)).or(Err(
+            Error::new(ErrorKind::Other, "can't send to SNTP server"),
+        ))?;
+        buf.lend(self.conn, Opcode::SetServer.to_u32().unwrap())
+            .or(Err(Error::new(
+                ErrorKind::Other,
+                "can't send to SNTP server",
+            )))
+            .map(|_| ())
+    }
+    /// Sets the offset of local time from UTC, in seconds. The RTC keeps local time, so it is moved
+    /// by the difference to the previous offset. The setting is kept in the PDDB.
+    pub fn set_utc_offset(&self, offset_secs: i32) -> std::io::Result<()> {
+        // the furthest time zones are UTC-12 and UTC+14
+        if !(-12 * 3600..=14 * 3600).contains(&offset_secs) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "UTC offset out of range",
+            ));
+        }
+        send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::SetUtcOffset.to_usize().unwrap(),
+                offset_secs as usize,
+                0,
+                0,
+                0,
+            ),
+        )
+        .or(Err(Error::new(
+            ErrorKind::Other,
+            "can't send to SNTP server",
+        )))
+        .map(|_| ())
+    }
+}
+
+use core::sync::atomic::{AtomicU32, Ordering};
+static REFCOUNT: AtomicU32 = AtomicU32::new(0);
+impl Drop for Sntp {
+    fn drop(&mut self) {
+        // the connection to the server side must be reference counted, so that multiple instances of this object within
+        // a single process do not end up de-allocating the CID on other threads before they go out of scope.
+        if REFCOUNT.fetch_sub(1, Ordering::Relaxed) == 1 {
+            unsafe {
+                xous::disconnect(self.conn).unwrap();
+            }
+        }
+    }
+}
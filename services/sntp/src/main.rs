@@ -0,0 +1,393 @@
+#![cfg_attr(target_os = "none", no_std)]
+#![cfg_attr(target_os = "none", no_main)]
+
+mod api;
+use api::*;
+mod ntp;
+
+use num_traits::*;
+use xous::{msg_blocking_scalar_unpack, msg_scalar_unpack, Message};
+use xous_ipc::{Buffer, String};
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::thread;
+
+/// How often to check whether a sync is due
+const POLL_INTERVAL_MS: usize = 60_000;
+/// How long a successful sync lasts before the clock is synced again
+const RESYNC_INTERVAL_MS: u64 = 6 * 60 * 60 * 1000;
+/// Failed syncs are retried with an exponential backoff, up to this many poll intervals apart
+const MAX_BACKOFF_POLLS: u64 = 64;
+/// Requests sent per sync before giving up on the server
+const ATTEMPTS: usize = 3;
+const REPLY_TIMEOUT_MS: u64 = 3_000;
+
+struct Syncer {
+    socket: net::UdpSocket,
+    netmgr: net::NetManager,
+    dns: dns::Dns,
+    trng: trng::Trng,
+    llio: llio::Llio,
+    rtc: llio::Rtc,
+    tt: ticktimer_server::Ticktimer,
+    pddb: pddb::Pddb,
+    status: SntpStatus,
+    /// set once the settings have been read out of the PDDB
+    settings_loaded: bool,
+    /// whether a UTC offset has been configured; if not, the first sync infers it from the RTC
+    utc_offset_known: bool,
+    /// ticktimer time at which the next sync is due
+    next_sync_ms: u64,
+}
+
+impl Syncer {
+    fn new(xns: &xous_names::XousNames) -> Syncer {
+        let trng = trng::Trng::new(&xns).unwrap();
+        let local_port = (49152 + trng.get_u32().unwrap() % 16384) as u16;
+        let mut socket = net::UdpSocket::bind_xous(
+            format!("127.0.0.1:{}", local_port),
+            Some(ntp::NTP_PACKET_LEN as u16),
+        )
+        .expect("couldn't create socket for SNTP");
+        socket
+            .set_read_timeout(Some(net::Duration::from_millis(REPLY_TIMEOUT_MS)))
+            .unwrap();
+        socket.set_nonblocking(false).unwrap();
+        Syncer {
+            socket,
+            netmgr: net::NetManager::new(),
+            dns: dns::Dns::new(&xns).unwrap(),
+            trng,
+            llio: llio::Llio::new(&xns),
+            rtc: llio::Rtc::new(&xns),
+            tt: ticktimer_server::Ticktimer::new().unwrap(),
+            pddb: pddb::Pddb::new().expect("couldn't connect to the PDDB"),
+            status: SntpStatus::default(),
+            settings_loaded: false,
+            utc_offset_known: false,
+            next_sync_ms: 0,
+        }
+    }
+
+    fn read_setting(&mut self, key: &str) -> Option<std::string::String> {
+        let mut setting = self
+            .pddb
+            .get(SNTP_DICT, key, None, false, false, None, Some(|| {}))
+            .ok()?;
+        let mut value = std::string::String::new();
+        setting.read_to_string(&mut value).ok()?;
+        Some(value)
+    }
+    /// The settings can only be read once the PDDB is mounted; until then, the defaults are used.
+    fn load_settings(&mut self) {
+        if self.settings_loaded || !self.pddb.is_mounted() {
+            return;
+        }
+        if let Some(server) = self.read_setting(SNTP_KEY_SERVER) {
+            self.status.server = String::from_str(server.trim());
+        }
+        if let Some(offset) = self.read_setting(SNTP_KEY_UTC_OFFSET) {
+            match offset.trim().parse::<i32>() {
+                Ok(offset) => {
+                    self.status.utc_offset = offset;
+                    self.utc_offset_known = true;
+                }
+                Err(_) => log::warn!("ignoring invalid UTC offset setting: {}", offset),
+            }
+        }
+        self.settings_loaded = true;
+    }
+    fn save_setting(&mut self, key: &str, value: &str) {
+        if !self.pddb.is_mounted() {
+            log::warn!("PDDB not mounted, {} setting will not be kept", key);
+            return;
+        }
+        // writing into an existing key doesn't truncate it, so start from scratch
+        self.pddb.delete_key(SNTP_DICT, key, None, false).ok();
+        match self.pddb.get(
+            SNTP_DICT,
+            key,
+            None,
+            true,
+            true,
+            Some(value.len()),
+            Some(|| {}),
+        ) {
+            Ok(mut setting) => {
+                if let Err(e) = setting
+                    .write_all(value.as_bytes())
+                    .and_then(|_| setting.flush())
+                {
+                    log::error!("couldn't save {} setting: {:?}", key, e);
+                }
+            }
+            Err(e) => log::error!("couldn't save {} setting: {:?}", key, e),
+        }
+    }
+
+    fn rtc_unix_secs(&self) -> Option<i64> {
+        self.llio
+            .read_rtc_blocking()
+            .ok()
+            .map(|dt| ntp::unix_from_datetime(&dt))
+    }
+    fn set_rtc_unix_secs(&self, secs: i64) -> Result<()> {
+        let dt = ntp::datetime_from_unix(secs).ok_or_else(|| {
+            Error::new(ErrorKind::InvalidData, "time is outside of the RTC's range")
+        })?;
+        self.rtc
+            .set_rtc(dt)
+            .or(Err(Error::new(ErrorKind::Other, "couldn't set the RTC")))
+    }
+
+    fn set_server(&mut self, server: &str) {
+        self.load_settings();
+        self.status.server = String::from_str(server);
+        self.save_setting(SNTP_KEY_SERVER, server);
+        self.next_sync_ms = 0;
+    }
+    fn set_utc_offset(&mut self, offset: i32) {
+        self.load_settings();
+        let delta = offset as i64 - self.status.utc_offset as i64;
+        if delta != 0 {
+            if let Some(rtc) = self.rtc_unix_secs() {
+                if let Err(e) = self.set_rtc_unix_secs(rtc + delta) {
+                    log::error!("couldn't move the RTC to the new UTC offset: {:?}", e);
+                }
+            }
+        }
+        self.status.utc_offset = offset;
+        self.utc_offset_known = true;
+        self.save_setting(SNTP_KEY_UTC_OFFSET, &offset.to_string());
+    }
+
+    fn is_connected(&self) -> bool {
+        self.netmgr.get_ipv4_config().is_some()
+    }
+    fn is_due(&self) -> bool {
+        self.tt.elapsed_ms() >= self.next_sync_ms
+    }
+
+    /// Syncs the RTC with the server, and schedules the next sync depending on how that went.
+    fn sync(&mut self) {
+        self.load_settings();
+        match self.try_sync() {
+            Ok(()) => {
+                self.status.failures = 0;
+                self.next_sync_ms = self.tt.elapsed_ms() + RESYNC_INTERVAL_MS;
+            }
+            Err(e) => {
+                log::warn!("SNTP sync with {} failed: {:?}", self.status.server, e);
+                self.status.failures = self.status.failures.saturating_add(1);
+                let backoff = (1u64 << self.status.failures.min(6)).min(MAX_BACKOFF_POLLS);
+                self.next_sync_ms = self.tt.elapsed_ms() + backoff * POLL_INTERVAL_MS as u64;
+            }
+        }
+    }
+
+    fn try_sync(&mut self) -> Result<()> {
+        let server = self
+            .status
+            .server
+            .as_str()
+            .unwrap_or(SNTP_DEFAULT_SERVER)
+            .to_string();
+        let ipaddr = self.dns.lookup(&server).map_err(|e| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("couldn't resolve {}: {:?}", server, e),
+            )
+        })?;
+        let addr = SocketAddr::new(IpAddr::from(ipaddr), ntp::NTP_PORT);
+        let mut result = Err(Error::new(ErrorKind::TimedOut, "no reply from SNTP server"));
+        for _ in 0..ATTEMPTS {
+            result = self.exchange(&addr);
+            match &result {
+                Ok(_) => break,
+                // the server asked us to go away, so don't press it
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => break,
+                Err(e) => log::debug!("SNTP request to {:?} failed: {:?}", addr, e),
+            }
+        }
+        let (sample, received_ms) = result?;
+        let utc_ms = sample.utc_ms + (self.tt.elapsed_ms() - received_ms);
+        let rtc_secs = self.rtc_unix_secs();
+
+        if !self.utc_offset_known {
+            // the RTC has been keeping local time, most likely set by hand; keep to its time zone, rounded to
+            // a quarter of an hour, unless it's so far off that it clearly was never set
+            const QUARTER_HOUR_MS: i64 = 15 * 60 * 1000;
+            let offset = rtc_secs
+                .map(|rtc| {
+                    (rtc * 1000 - utc_ms as i64 + QUARTER_HOUR_MS / 2).div_euclid(QUARTER_HOUR_MS)
+                })
+                .map(|quarters| (quarters * QUARTER_HOUR_MS / 1000) as i32)
+                .filter(|offset| (-12 * 3600..=14 * 3600).contains(offset))
+                .unwrap_or(0);
+            log::info!("no UTC offset configured, using {} seconds", offset);
+            self.status.utc_offset = offset;
+            self.utc_offset_known = true;
+            self.save_setting(SNTP_KEY_UTC_OFFSET, &offset.to_string());
+        }
+        let utc_offset_ms = self.status.utc_offset as i64 * 1000;
+        if let Some(rtc) = rtc_secs {
+            self.status.offset_ms = utc_ms as i64 + utc_offset_ms - rtc * 1000;
+        }
+
+        // the RTC only counts whole seconds, so set it right as the next second starts
+        let local_ms = utc_ms as i64 + utc_offset_ms;
+        let wait_ms = 1000 - local_ms.rem_euclid(1000);
+        self.tt.sleep_ms(wait_ms as usize).unwrap();
+        self.set_rtc_unix_secs((local_ms + wait_ms) / 1000)?;
+
+        self.status.last_sync = Some((utc_ms + wait_ms as u64) / 1000);
+        self.status.delay_ms = sample.delay_ms;
+        log::info!(
+            "synced RTC with {} ({:?}): offset {} ms, delay {} ms",
+            server,
+            addr,
+            self.status.offset_ms,
+            sample.delay_ms
+        );
+        Ok(())
+    }
+
+    /// Sends one request to `addr`, returning the sample and the ticktimer time the reply arrived at
+    fn exchange(&mut self, addr: &SocketAddr) -> Result<(ntp::Sample, u64)> {
+        let nonce = self.trng.get_u64().or(Err(Error::new(
+            ErrorKind::Other,
+            "couldn't get random number",
+        )))?;
+        let sent_ms = self.tt.elapsed_ms();
+        self.socket.send_to(&ntp::request(nonce), addr)?;
+        let mut reply = [0u8; ntp::NTP_PACKET_LEN];
+        let (len, src) = self.socket.recv_from(&mut reply)?;
+        let received_ms = self.tt.elapsed_ms();
+        if src != *addr {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "SNTP reply from an unexpected address",
+            ));
+        }
+        Ok((
+            ntp::parse_reply(&reply[..len], nonce, sent_ms, received_ms)?,
+            received_ms,
+        ))
+    }
+}
+
+#[xous::xous_main]
+fn xmain() -> ! {
+    log_server::init_wait().unwrap();
+    log::set_max_level(log::LevelFilter::Info);
+    log::info!("my PID is {}", xous::process::id());
+
+    let xns = xous_names::XousNames::new().unwrap();
+    let sntp_sid = xns
+        .register_name(api::SERVER_NAME_SNTP, None)
+        .expect("can't register server");
+    log::trace!("registered with NS -- {:?}", sntp_sid);
+    let sntp_cid = xous::connect(sntp_sid).unwrap();
+
+    let mut syncer = Syncer::new(&xns);
+    // the connection manager tells us when we get an address, which is the time to sync
+    let mut wifi_mgr = net::NetManager::new();
+    wifi_mgr
+        .wifi_state_subscribe(sntp_cid, Opcode::WifiStats.to_u32().unwrap())
+        .expect("couldn't subscribe to wifi state updates");
+    let mut dhcp_bound = false;
+
+    // build a thread that checks in every now and then, to keep the clock synced
+    thread::spawn({
+        move || {
+            let tt = ticktimer_server::Ticktimer::new().unwrap();
+            loop {
+                tt.sleep_ms(POLL_INTERVAL_MS).unwrap();
+                xous::send_message(
+                    sntp_cid,
+                    Message::new_scalar(Opcode::Poll.to_usize().unwrap(), 0, 0, 0, 0),
+                )
+                .expect("couldn't poll SNTP");
+            }
+        }
+    });
+
+    log::trace!("ready to accept requests");
+    loop {
+        let mut msg = xous::receive_message(sntp_sid).unwrap();
+        match FromPrimitive::from_usize(msg.body.id()) {
+            Some(Opcode::Sync) => msg_scalar_unpack!(msg, _, _, _, _, {
+                if syncer.is_connected() {
+                    syncer.sync();
+                } else {
+                    log::info!("not connected, can't sync");
+                }
+            }),
+            Some(Opcode::Poll) => msg_scalar_unpack!(msg, _, _, _, _, {
+                if syncer.is_due() && syncer.is_connected() {
+                    syncer.sync();
+                }
+            }),
+            Some(Opcode::WifiStats) => {
+                let buffer =
+                    unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                let status = com::WlanStatus::from_ipc(
+                    buffer.to_original::<com::WlanStatusIpc, _>().unwrap(),
+                );
+                let bound = status.ipv4.dhcp == com_rs_ref::DhcpState::Bound;
+                if bound && !dhcp_bound {
+                    // a new network may be on the other side of the world, so don't wait for the sync to come due.
+                    // The update is lent to us by the subscription thread, so release it before syncing.
+                    xous::try_send_message(
+                        sntp_cid,
+                        Message::new_scalar(Opcode::Sync.to_usize().unwrap(), 0, 0, 0, 0),
+                    )
+                    .ok();
+                }
+                dhcp_bound = bound;
+            }
+            Some(Opcode::GetStatus) => {
+                let mut buffer = unsafe {
+                    Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap())
+                };
+                syncer.load_settings();
+                buffer
+                    .replace(syncer.status)
+                    .expect("couldn't return SNTP status");
+            }
+            Some(Opcode::SetServer) => {
+                let buffer =
+                    unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                let server = buffer.to_original::<String<SNTP_SERVER_LEN>, _>().unwrap();
+                match server.as_str() {
+                    Ok(server) if !server.is_empty() => {
+                        log::info!("SNTP server set to {}", server);
+                        syncer.set_server(server);
+                    }
+                    _ => log::error!("invalid SNTP server name"),
+                }
+            }
+            Some(Opcode::SetUtcOffset) => msg_blocking_scalar_unpack!(msg, offset, _, _, _, {
+                let offset = offset as i32;
+                log::info!("UTC offset set to {} seconds", offset);
+                syncer.set_utc_offset(offset);
+                xous::return_scalar(msg.sender, 0).unwrap();
+            }),
+            Some(Opcode::Quit) => {
+                log::warn!("got quit!");
+                break;
+            }
+            None => {
+                log::error!("couldn't convert opcode: {:?}", msg);
+            }
+        }
+    }
+    // clean up our program
+    log::trace!("main loop exit, destroying servers");
+    wifi_mgr.wifi_state_unsubscribe().ok();
+    xns.unregister_server(sntp_sid).unwrap();
+    xous::destroy_server(sntp_sid).unwrap();
+    log::trace!("quitting");
+    xous::terminate_process(0)
+}
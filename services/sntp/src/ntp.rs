@@ -0,0 +1,209 @@
+//! SNTP packets (RFC 4330) and conversions between Unix time and the RTC's calendar time.
+
+use llio::{DateTime, Weekday};
+use std::io::{Error, ErrorKind, Result};
+
+pub(crate) const NTP_PORT: u16 = 123;
+pub(crate) const NTP_PACKET_LEN: usize = 48;
+/// Seconds from the NTP epoch (1900) to the Unix epoch (1970)
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+const VERSION: u8 = 4;
+const LEAP_UNSYNCHRONIZED: u8 = 3;
+
+/// Builds a client request. `nonce` goes in the transmit timestamp, where a server echoes it back as the
+/// originate timestamp; we have no trustworthy time to put there, and it lets stray replies be told apart.
+pub(crate) fn request(nonce: u64) -> [u8; NTP_PACKET_LEN] {
+    let mut pkt = [0u8; NTP_PACKET_LEN];
+    pkt[0] = (VERSION << 3) | MODE_CLIENT;
+    pkt[40..48].copy_from_slice(&nonce.to_be_bytes());
+    pkt
+}
+
+/// Converts an NTP timestamp to milliseconds since the Unix epoch. Timestamps with the top bit clear are
+/// taken to be in NTP era 1, which starts in 2036.
+fn timestamp_ms(ts: &[u8]) -> u64 {
+    let mut secs = u32::from_be_bytes([ts[0], ts[1], ts[2], ts[3]]) as u64;
+    let frac = u32::from_be_bytes([ts[4], ts[5], ts[6], ts[7]]) as u64;
+    if secs & 0x8000_0000 == 0 {
+        secs += 1 << 32;
+    }
+    (secs - NTP_UNIX_OFFSET) * 1000 + ((frac * 1000) >> 32)
+}
+
+/// The outcome of one exchange with a server
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Sample {
+    /// The server's estimate of the time when the reply arrived, in milliseconds since the Unix epoch
+    pub utc_ms: u64,
+    /// Round-trip delay, not counting the time the server took to reply
+    pub delay_ms: u32,
+}
+
+/// Checks a server's `reply` to the request carrying `nonce`, which was sent at local time `sent_ms` and
+/// received at `received_ms` (in any monotonic milliseconds), and works out the time from it.
+pub(crate) fn parse_reply(
+    reply: &[u8],
+    nonce: u64,
+    sent_ms: u64,
+    received_ms: u64,
+) -> Result<Sample> {
+    if reply.len() < NTP_PACKET_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, "SNTP reply too short"));
+    }
+    if reply[24..32] != nonce.to_be_bytes() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "SNTP reply doesn't match the request",
+        ));
+    }
+    let leap = reply[0] >> 6;
+    let mode = reply[0] & 0x7;
+    let stratum = reply[1];
+    if mode != MODE_SERVER {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "SNTP reply isn't from a server",
+        ));
+    }
+    if stratum == 0 {
+        // a "kiss-o'-death", telling us to go away; the reason is in the reference ID
+        let code = String::from_utf8_lossy(&reply[12..16]).to_string();
+        return Err(Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("SNTP server sent kiss code {}", code),
+        ));
+    }
+    if stratum > 15 || leap == LEAP_UNSYNCHRONIZED || reply[40..48] == [0; 8] {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "SNTP server isn't synchronized",
+        ));
+    }
+    let receive = timestamp_ms(&reply[32..40]);
+    let transmit = timestamp_ms(&reply[40..48]);
+    let round_trip = received_ms.saturating_sub(sent_ms);
+    let delay_ms = round_trip.saturating_sub(transmit.saturating_sub(receive));
+    Ok(Sample {
+        utc_ms: transmit + delay_ms / 2,
+        delay_ms: delay_ms as u32,
+    })
+}
+
+/// Days since the Unix epoch of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Converts seconds since the Unix epoch to an RTC date and time. The RTC can only hold years 2000 to 2099.
+pub(crate) fn datetime_from_unix(secs: i64) -> Option<DateTime> {
+    let days = secs.div_euclid(86400);
+    let secs_of_day = secs.rem_euclid(86400);
+    // civil_from_days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    if !(2000..=2099).contains(&year) {
+        return None;
+    }
+    // 1970-01-01 was a Thursday
+    let weekday = match (days + 4).rem_euclid(7) {
+        0 => Weekday::Sunday,
+        1 => Weekday::Monday,
+        2 => Weekday::Tuesday,
+        3 => Weekday::Wednesday,
+        4 => Weekday::Thursday,
+        5 => Weekday::Friday,
+        _ => Weekday::Saturday,
+    };
+    Some(DateTime {
+        seconds: (secs_of_day % 60) as u8,
+        minutes: (secs_of_day / 60 % 60) as u8,
+        hours: (secs_of_day / 3600) as u8,
+        days: day as u8,
+        months: month as u8,
+        years: (year - 2000) as u8,
+        weekday,
+    })
+}
+
+/// Converts an RTC date and time to seconds since the Unix epoch
+pub(crate) fn unix_from_datetime(dt: &DateTime) -> i64 {
+    days_from_civil(2000 + dt.years as i64, dt.months as u32, dt.days as u32) * 86400
+        + dt.hours as i64 * 3600
+        + dt.minutes as i64 * 60
+        + dt.seconds as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calendar() {
+        // 2022-03-01 12:34:56 UTC, a Tuesday, just after a non-leap February
+        let dt = datetime_from_unix(1_646_138_096).unwrap();
+        assert_eq!((dt.years, dt.months, dt.days), (22, 3, 1));
+        assert_eq!((dt.hours, dt.minutes, dt.seconds), (12, 34, 56));
+        assert!(matches!(dt.weekday, Weekday::Tuesday));
+        assert_eq!(unix_from_datetime(&dt), 1_646_138_096);
+        // 2024-02-29, a leap day
+        let dt = datetime_from_unix(1_709_164_800).unwrap();
+        assert_eq!((dt.years, dt.months, dt.days), (24, 2, 29));
+        assert!(matches!(dt.weekday, Weekday::Thursday));
+        assert!(datetime_from_unix(0).is_none());
+        assert!(datetime_from_unix(4_102_444_800).is_none()); // 2100-01-01
+    }
+
+    #[test]
+    fn reply() {
+        let nonce = 0x0123_4567_89ab_cdef;
+        let req = request(nonce);
+        assert_eq!(req[0], 0x23);
+        let mut reply = [0u8; NTP_PACKET_LEN];
+        reply[0] = 0x24; // no leap warning, version 4, server
+        reply[1] = 2;
+        reply[24..32].copy_from_slice(&req[40..48]);
+        // received at 2022-03-01 12:34:56.250, answered 10 ms later
+        let secs = (1_646_138_096 + NTP_UNIX_OFFSET) as u32;
+        reply[32..36].copy_from_slice(&secs.to_be_bytes());
+        reply[36..40].copy_from_slice(&0x4000_0000u32.to_be_bytes());
+        reply[40..44].copy_from_slice(&secs.to_be_bytes());
+        reply[44..48].copy_from_slice(&0x428f_5c29u32.to_be_bytes());
+        let sample = parse_reply(&reply, nonce, 1000, 1110).unwrap();
+        assert_eq!(
+            sample,
+            Sample {
+                utc_ms: 1_646_138_096_310,
+                delay_ms: 100
+            }
+        );
+
+        assert!(parse_reply(&reply, nonce + 1, 1000, 1110).is_err());
+        reply[1] = 0;
+        assert_eq!(
+            parse_reply(&reply, nonce, 1000, 1110).unwrap_err().kind(),
+            ErrorKind::ConnectionRefused
+        );
+    }
+
+    #[test]
+    fn era_rollover() {
+        // second 1 of NTP era 1 is 2036-02-07 06:28:17 UTC
+        assert_eq!(timestamp_ms(&[0, 0, 0, 1, 0, 0, 0, 0]), 2_085_978_497_000);
+    }
+}
@@ -605,12 +605,22 @@ fn xmain() -> ! {
         Very important to track who has access to the SPINOR server, and limit access. Access to this server is essential for persistent rootkits.
         Here is the list of servers allowed to access, and why:
           - shellchat (for testing ONLY, remove once done)
-          - suspend/resume (for suspend locking/unlocking calls)
+          - suspend/resume (our own susres thread, for suspend locking/unlocking calls)
           - keystore
           - PDDB
           - keyboard (for updating the key map setting, which needs to be loaded upstream of the PDDB)
     */
-    let spinor_sid = xns.register_name(api::SERVER_NAME_SPINOR, Some(5)).expect("can't register server");
+    let spinor_sid = xns.register_name_with_acl(
+        api::SERVER_NAME_SPINOR,
+        Some(5),
+        &[
+            xous_names::api::AclEntry::process("spinor"),
+            xous_names::api::AclEntry::process("shellchat"),
+            xous_names::api::AclEntry::process("keyboard"),
+            xous_names::api::AclEntry::process("root-keys"),
+            xous_names::api::AclEntry::process("pddb"),
+        ],
+    ).expect("can't register server");
     log::trace!("registered with NS -- {:?}", spinor_sid);
 
     let handler_conn = xous::connect(spinor_sid).expect("couldn't create interrupt handler callback connection");
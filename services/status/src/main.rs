@@ -406,12 +406,21 @@ fn xmain() -> ! {
                         write!(&mut battstats_tv, "{:.3}W{}{:.2}V {}%", wattage, sign, stats.voltage as f32 / 1000.0, stats.soc).unwrap();
                     } else {
                         if let Some(ssid) = wifi_status.ssid {
-                            write!(
-                                &mut battstats_tv,
-                                "{} -{}dBm",
-                                ssid.name.as_str().unwrap_or("UTF-8 Erorr"),
-                                ssid.rssi,
-                            ).unwrap();
+                            if wifi_status.connectivity == Connectivity::CaptivePortal {
+                                write!(
+                                    &mut battstats_tv,
+                                    "{} {}",
+                                    ssid.name.as_str().unwrap_or("UTF-8 Erorr"),
+                                    t!("stats.captive_portal", xous::LANG),
+                                ).unwrap();
+                            } else {
+                                write!(
+                                    &mut battstats_tv,
+                                    "{} -{}dBm",
+                                    ssid.name.as_str().unwrap_or("UTF-8 Erorr"),
+                                    ssid.rssi,
+                                ).unwrap();
+                            }
                         } else {
                             write!(
                                 &mut battstats_tv,
@@ -0,0 +1,264 @@
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::{Aes128Gcm, Key, Nonce};
+use sha2::{Digest, Sha256};
+use std::io::{Error, ErrorKind, Result};
+use zeroize::Zeroize;
+
+/// Length of a SHA-256 hash, which is also the length of every secret in the key schedule
+pub(crate) const HASH_LEN: usize = 32;
+const KEY_LEN: usize = 16;
+const IV_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+pub(crate) fn sha256(data: &[u8]) -> [u8; HASH_LEN] {
+    let mut hash = [0u8; HASH_LEN];
+    hash.copy_from_slice(&Sha256::digest(data));
+    hash
+}
+
+/// HMAC-SHA256 (RFC 2104) over the concatenation of `data`
+pub(crate) fn hmac_sha256(key: &[u8], data: &[&[u8]]) -> [u8; HASH_LEN] {
+    const BLOCK_LEN: usize = 64;
+    let mut block_key = [0u8; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        block_key[..HASH_LEN].copy_from_slice(&sha256(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+    let mut pad = [0u8; BLOCK_LEN];
+    for (p, k) in pad.iter_mut().zip(block_key.iter()) {
+        *p = k ^ 0x36;
+    }
+    let mut inner = Sha256::new();
+    inner.update(&pad);
+    for d in data {
+        inner.update(d);
+    }
+    let inner_hash = inner.finalize();
+    for (p, k) in pad.iter_mut().zip(block_key.iter()) {
+        *p = k ^ 0x5c;
+    }
+    let mut outer = Sha256::new();
+    outer.update(&pad);
+    outer.update(&inner_hash);
+    block_key.zeroize();
+    pad.zeroize();
+    let mut mac = [0u8; HASH_LEN];
+    mac.copy_from_slice(&outer.finalize());
+    mac
+}
+
+/// HKDF-Extract (RFC 5869)
+pub(crate) fn hkdf_extract(salt: &[u8], ikm: &[u8]) -> [u8; HASH_LEN] {
+    hmac_sha256(salt, &[ikm])
+}
+
+/// HKDF-Expand (RFC 5869)
+pub(crate) fn hkdf_expand(prk: &[u8], info: &[u8], okm: &mut [u8]) {
+    let mut t = [0u8; HASH_LEN];
+    for (i, chunk) in okm.chunks_mut(HASH_LEN).enumerate() {
+        let counter = [i as u8 + 1];
+        t = if i == 0 {
+            hmac_sha256(prk, &[info, &counter])
+        } else {
+            hmac_sha256(prk, &[&t, info, &counter])
+        };
+        chunk.copy_from_slice(&t[..chunk.len()]);
+    }
+    t.zeroize();
+}
+
+/// HKDF-Expand-Label (RFC 8446 section 7.1)
+pub(crate) fn hkdf_expand_label(secret: &[u8], label: &str, context: &[u8], okm: &mut [u8]) {
+    let mut info = Vec::with_capacity(4 + 6 + label.len() + context.len());
+    info.extend_from_slice(&(okm.len() as u16).to_be_bytes());
+    info.push((6 + label.len()) as u8);
+    info.extend_from_slice(b"tls13 ");
+    info.extend_from_slice(label.as_bytes());
+    info.push(context.len() as u8);
+    info.extend_from_slice(context);
+    hkdf_expand(secret, &info, okm);
+}
+
+/// Derive-Secret (RFC 8446 section 7.1); `transcript_hash` is the hash of the relevant handshake messages
+pub(crate) fn derive_secret(secret: &[u8], label: &str, transcript_hash: &[u8]) -> [u8; HASH_LEN] {
+    let mut out = [0u8; HASH_LEN];
+    hkdf_expand_label(secret, label, transcript_hash, &mut out);
+    out
+}
+
+/// The verify_data of a Finished message sent with the traffic secret `base_key`
+pub(crate) fn finished_mac(base_key: &[u8], transcript_hash: &[u8]) -> [u8; HASH_LEN] {
+    let mut finished_key = [0u8; HASH_LEN];
+    hkdf_expand_label(base_key, "finished", &[], &mut finished_key);
+    let mac = hmac_sha256(&finished_key, &[transcript_hash]);
+    finished_key.zeroize();
+    mac
+}
+
+/// Protects records in one direction with TLS_AES_128_GCM_SHA256.
+pub(crate) struct RecordKeys {
+    secret: [u8; HASH_LEN],
+    cipher: Aes128Gcm,
+    iv: [u8; IV_LEN],
+    seq: u64,
+}
+impl RecordKeys {
+    pub(crate) fn new(secret: &[u8; HASH_LEN]) -> RecordKeys {
+        let mut key = [0u8; KEY_LEN];
+        let mut iv = [0u8; IV_LEN];
+        hkdf_expand_label(secret, "key", &[], &mut key);
+        hkdf_expand_label(secret, "iv", &[], &mut iv);
+        let cipher = Aes128Gcm::new(Key::from_slice(&key));
+        key.zeroize();
+        RecordKeys {
+            secret: *secret,
+            cipher,
+            iv,
+            seq: 0,
+        }
+    }
+    /// Moves on to the next generation of traffic secret, as requested by a KeyUpdate message
+    pub(crate) fn update(&mut self) {
+        let mut next = [0u8; HASH_LEN];
+        hkdf_expand_label(&self.secret, "traffic upd", &[], &mut next);
+        *self = RecordKeys::new(&next);
+        next.zeroize();
+    }
+    fn nonce(&mut self) -> Result<[u8; IV_LEN]> {
+        let mut nonce = self.iv;
+        for (n, s) in nonce[IV_LEN - 8..]
+            .iter_mut()
+            .zip(self.seq.to_be_bytes().iter())
+        {
+            *n ^= s;
+        }
+        // the sequence number must never wrap; the connection has to be abandoned before that happens
+        self.seq = self.seq.checked_add(1).ok_or(Error::new(
+            ErrorKind::Other,
+            "TLS sequence number exhausted",
+        ))?;
+        Ok(nonce)
+    }
+    /// Encrypts `data` of the given content type into a complete TLSCiphertext record, header included.
+    pub(crate) fn seal(&mut self, content_type: u8, data: &[u8]) -> Result<Vec<u8>> {
+        let mut inner = Vec::with_capacity(data.len() + 1);
+        inner.extend_from_slice(data);
+        inner.push(content_type);
+        let len = (inner.len() + TAG_LEN) as u16;
+        let header = [
+            crate::CONTENT_APPLICATION_DATA,
+            3,
+            3,
+            (len >> 8) as u8,
+            len as u8,
+        ];
+        let nonce = self.nonce()?;
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &inner,
+                    aad: &header,
+                },
+            )
+            .map_err(|_| Error::new(ErrorKind::Other, "TLS record encryption failed"))?;
+        inner.zeroize();
+        let mut record = Vec::with_capacity(header.len() + ciphertext.len());
+        record.extend_from_slice(&header);
+        record.extend_from_slice(&ciphertext);
+        Ok(record)
+    }
+    /// Decrypts the payload of a TLSCiphertext record, returning the real content type and the content.
+    pub(crate) fn open(&mut self, header: &[u8], payload: &[u8]) -> Result<(u8, Vec<u8>)> {
+        let nonce = self.nonce()?;
+        let mut inner = self
+            .cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: payload,
+                    aad: header,
+                },
+            )
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "TLS record failed authentication"))?;
+        // the content type is the last non-zero byte; anything after it is padding
+        while let Some(byte) = inner.pop() {
+            if byte != 0 {
+                return Ok((byte, inner));
+            }
+        }
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            "TLS record has no content type",
+        ))
+    }
+}
+impl Drop for RecordKeys {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+        self.iv.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hmac_rfc4231_case2() {
+        let mac = hmac_sha256(b"Jefe", &[b"what do ya want ", b"for nothing?"]);
+        assert_eq!(
+            mac,
+            [
+                0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26, 0x08, 0x95,
+                0x75, 0xc7, 0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83, 0x9d, 0xec, 0x58, 0xb9,
+                0x64, 0xec, 0x38, 0x43,
+            ]
+        );
+    }
+
+    #[test]
+    fn hkdf_rfc5869_case1() {
+        let ikm = [0x0bu8; 22];
+        let salt: Vec<u8> = (0..=0x0c).collect();
+        let info: Vec<u8> = (0xf0..=0xf9).collect();
+        let prk = hkdf_extract(&salt, &ikm);
+        assert_eq!(
+            prk,
+            [
+                0x07, 0x77, 0x09, 0x36, 0x2c, 0x2e, 0x32, 0xdf, 0x0d, 0xdc, 0x3f, 0x0d, 0xc4, 0x7b,
+                0xba, 0x63, 0x90, 0xb6, 0xc7, 0x3b, 0xb5, 0x0f, 0x9c, 0x31, 0x22, 0xec, 0x84, 0x4a,
+                0xd7, 0xc2, 0xb3, 0xe5,
+            ]
+        );
+        let mut okm = [0u8; 42];
+        hkdf_expand(&prk, &info, &mut okm);
+        assert_eq!(
+            okm[..],
+            [
+                0x3c, 0xb2, 0x5f, 0x25, 0xfa, 0xac, 0xd5, 0x7a, 0x90, 0x43, 0x4f, 0x64, 0xd0, 0x36,
+                0x2f, 0x2a, 0x2d, 0x2d, 0x0a, 0x90, 0xcf, 0x1a, 0x5a, 0x4c, 0x5d, 0xb0, 0x2d, 0x56,
+                0xec, 0xc4, 0xc5, 0xbf, 0x34, 0x00, 0x72, 0x08, 0xd5, 0xb8, 0x87, 0x18, 0x58, 0x65,
+            ][..]
+        );
+    }
+
+    #[test]
+    fn record_roundtrip() {
+        let secret = [0x42u8; HASH_LEN];
+        let mut tx = RecordKeys::new(&secret);
+        let mut rx = RecordKeys::new(&secret);
+        for msg in [&b"hello"[..], &b""[..], &[0u8; 100][..]].iter() {
+            let record = tx.seal(crate::CONTENT_APPLICATION_DATA, msg).unwrap();
+            let (content_type, data) = rx.open(&record[..5], &record[5..]).unwrap();
+            assert_eq!(content_type, crate::CONTENT_APPLICATION_DATA);
+            assert_eq!(&data[..], *msg);
+        }
+        // a record replayed out of sequence must not authenticate
+        let record = tx.seal(crate::CONTENT_APPLICATION_DATA, b"once").unwrap();
+        let mut replay = RecordKeys::new(&secret);
+        assert!(replay.open(&record[..5], &record[5..]).is_err());
+    }
+}
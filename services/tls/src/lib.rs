@@ -0,0 +1,884 @@
+//! A TLS 1.3 client (RFC 8446).
+//!
+//! TLS runs in the caller's process, on top of any `Read + Write` stream; typically a `net::TcpStream`:
+//!
+//! ```ignore
+//! let mut tls = tls::Tls::new(&xns);
+//! let mut stream = tls.connect_tcp("example.com", 443)?;
+//! write!(stream, "GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")?;
+//! ```
+//!
+//! To keep the footprint small, exactly one configuration is implemented: the TLS_AES_128_GCM_SHA256
+//! cipher suite, which every TLS 1.3 server has to support, with an x25519 key exchange. Note that
+//! x25519 is only recommended by RFC 8446; the group every server has to support is secp256r1, which
+//! isn't implemented. The few servers that don't take x25519 answer with a HelloRetryRequest for another
+//! group, and connecting to them fails with `ErrorKind::Unsupported`.
+//!
+//! Servers can authenticate with RSA (2048 bits and up) or ECDSA P-256 keys, and certificate chains can
+//! be signed with RSA (PKCS#1 v1.5 or PSS) or ECDSA P-256. Session resumption, 0-RTT and client
+//! certificates are not supported.
+//!
+//! Servers are authenticated against the root certificates and per-host pins in the `CertStore`, which is
+//! kept in the PDDB. Randomness comes from the `trng`. Certificate validity periods are checked against the
+//! RTC, which `sntp` keeps in sync, unless the time has been supplied with `Tls::set_time()`. If the time
+//! isn't known, only pinned hosts can be connected to.
+
+mod crypto;
+mod store;
+mod x509;
+pub use store::*;
+
+use crypto::*;
+use sha2::{Digest, Sha256};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
+use x509::{Certificate, SignatureScheme};
+use zeroize::Zeroize;
+
+pub(crate) const CONTENT_CHANGE_CIPHER_SPEC: u8 = 20;
+pub(crate) const CONTENT_ALERT: u8 = 21;
+pub(crate) const CONTENT_HANDSHAKE: u8 = 22;
+pub(crate) const CONTENT_APPLICATION_DATA: u8 = 23;
+
+const HS_CLIENT_HELLO: u8 = 1;
+const HS_SERVER_HELLO: u8 = 2;
+const HS_NEW_SESSION_TICKET: u8 = 4;
+const HS_ENCRYPTED_EXTENSIONS: u8 = 8;
+const HS_CERTIFICATE: u8 = 11;
+const HS_CERTIFICATE_REQUEST: u8 = 13;
+const HS_CERTIFICATE_VERIFY: u8 = 15;
+const HS_FINISHED: u8 = 20;
+const HS_KEY_UPDATE: u8 = 24;
+
+const EXT_SERVER_NAME: u16 = 0;
+const EXT_SUPPORTED_GROUPS: u16 = 10;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 13;
+const EXT_SUPPORTED_VERSIONS: u16 = 43;
+const EXT_KEY_SHARE: u16 = 51;
+
+const TLS_AES_128_GCM_SHA256: u16 = 0x1301;
+const GROUP_X25519: u16 = 0x001d;
+const TLS13: u16 = 0x0304;
+
+const ALERT_CLOSE_NOTIFY: u8 = 0;
+
+/// The RTC keeps local time, which is at most 14 hours off UTC
+const RTC_SLACK_SECS: i64 = 14 * 3600;
+/// The RTC reads earlier than this (2022-01-01) only if it was never set
+const RTC_MIN_SECS: i64 = 1_640_995_200;
+
+const CONNECT_TIMEOUT_MS: u64 = 5000;
+const IO_TIMEOUT_MS: u64 = 10_000;
+
+/// Largest record payload that may be received: 2^14 bytes of plaintext, plus up to 256 bytes of expansion
+const MAX_RECORD_LEN: usize = 16384 + 256;
+/// Largest plaintext that can be put into a single record
+const MAX_FRAGMENT_LEN: usize = 16384;
+
+/// The random value of a ServerHello that is actually a HelloRetryRequest (RFC 8446 section 4.1.3)
+const HELLO_RETRY_REQUEST: [u8; 32] = [
+    0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91,
+    0xc2, 0xa2, 0x11, 0x16, 0x7a, 0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8, 0x33, 0x9c,
+];
+
+fn protocol_error(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// A cursor for decoding TLS structures
+struct Reader<'a> {
+    data: &'a [u8],
+}
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data }
+    }
+    fn is_empty(&self) -> bool {
+        self.data.len() == 0
+    }
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(protocol_error("truncated TLS message"));
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+    fn u16(&mut self) -> Result<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+    fn u24(&mut self) -> Result<usize> {
+        let b = self.bytes(3)?;
+        Ok(((b[0] as usize) << 16) | ((b[1] as usize) << 8) | b[2] as usize)
+    }
+    fn vec8(&mut self) -> Result<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.bytes(len)
+    }
+    fn vec16(&mut self) -> Result<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+    fn vec24(&mut self) -> Result<&'a [u8]> {
+        let len = self.u24()?;
+        self.bytes(len)
+    }
+}
+
+fn put_u16(buf: &mut Vec<u8>, val: u16) {
+    buf.extend_from_slice(&val.to_be_bytes());
+}
+fn put_vec16(buf: &mut Vec<u8>, data: &[u8]) {
+    put_u16(buf, data.len() as u16);
+    buf.extend_from_slice(data);
+}
+fn put_extension(buf: &mut Vec<u8>, ext_type: u16, data: &[u8]) {
+    put_u16(buf, ext_type);
+    put_vec16(buf, data);
+}
+/// Wraps a handshake message body with its header
+fn handshake_message(msg_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut msg = vec![
+        msg_type,
+        (body.len() >> 16) as u8,
+        (body.len() >> 8) as u8,
+        body.len() as u8,
+    ];
+    msg.extend_from_slice(body);
+    msg
+}
+
+/// Creates TLS connections.
+pub struct Tls {
+    trng: trng::Trng,
+    llio: llio::Llio,
+    store: CertStore,
+    now: Option<i64>,
+}
+impl Tls {
+    pub fn new(xns: &xous_names::XousNames) -> Tls {
+        Tls {
+            trng: trng::Trng::new(xns).expect("couldn't connect to TRNG"),
+            llio: llio::Llio::new(xns),
+            store: CertStore::new(),
+            now: None,
+        }
+    }
+    /// The store of trusted roots and pins that servers are authenticated against
+    pub fn store(&self) -> &CertStore {
+        &self.store
+    }
+    /// Sets the current UTC time, in seconds since the epoch, for checking certificate validity periods.
+    /// With `None`, the time is read from the RTC again.
+    pub fn set_time(&mut self, unix_secs: Option<u64>) {
+        self.now = unix_secs.map(|secs| secs as i64);
+    }
+    /// Whether the current time is known. Hosts that aren't pinned can't be authenticated without it.
+    pub fn time_known(&self) -> bool {
+        self.now().is_some()
+    }
+    /// The current time in seconds since the epoch, and how far off it may be
+    fn now(&self) -> Option<(i64, i64)> {
+        if let Some(now) = self.now {
+            return Some((now, 0));
+        }
+        let dt = self.llio.read_rtc_blocking().ok()?;
+        if !(1..=12).contains(&dt.months) || !(1..=31).contains(&dt.days) {
+            return None;
+        }
+        let secs = x509::days_from_civil(2000 + dt.years as i64, dt.months as i64, dt.days as i64)
+            * 86400
+            + dt.hours as i64 * 3600
+            + dt.minutes as i64 * 60
+            + dt.seconds as i64;
+        if secs < RTC_MIN_SECS {
+            return None;
+        }
+        Some((secs, RTC_SLACK_SECS))
+    }
+
+    /// Resolves `host`, opens a TCP connection to it on `port`, and performs a TLS handshake over it.
+    /// The name is resolved through libstd, so that the DNS server can itself use this crate.
+    pub fn connect_tcp(&mut self, host: &str, port: u16) -> Result<TlsStream<net::TcpStream>> {
+        let mut tcp = net::TcpStream::connect_xous(
+            (host, port),
+            Some(net::Duration::from_millis(CONNECT_TIMEOUT_MS)),
+            None,
+        )?;
+        tcp.set_read_timeout(Some(net::Duration::from_millis(IO_TIMEOUT_MS)))?;
+        tcp.set_write_timeout(Some(net::Duration::from_millis(IO_TIMEOUT_MS)))?;
+        self.connect(host, tcp)
+    }
+
+    /// Performs a TLS handshake with `host` over `stream`, which must already be connected to it.
+    /// `host` is also what the server's certificate is checked against.
+    pub fn connect<S: Read + Write>(&mut self, host: &str, stream: S) -> Result<TlsStream<S>> {
+        let mut tls = TlsStream::new(stream);
+        match self.handshake(&mut tls, host) {
+            Ok(()) => Ok(tls),
+            Err(e) => {
+                log::warn!("TLS handshake with {} failed: {:?}", host, e);
+                // let the server know why we're bailing out; it doesn't matter if this fails too
+                let alert = match e.kind() {
+                    ErrorKind::PermissionDenied => 42, // bad_certificate
+                    ErrorKind::Unsupported => 40,      // handshake_failure
+                    _ => 50,                           // decode_error
+                };
+                tls.write_record(CONTENT_ALERT, &[2, alert]).ok();
+                Err(e)
+            }
+        }
+    }
+
+    fn handshake<S: Read + Write>(&mut self, tls: &mut TlsStream<S>, host: &str) -> Result<()> {
+        let secret = EphemeralSecret::new(&mut self.trng);
+        let public = PublicKey::from(&secret);
+        let mut random = [0u8; 32];
+        rand_core::RngCore::fill_bytes(&mut self.trng, &mut random);
+        let client_hello = Tls::client_hello(host, &random, public.as_bytes());
+        tls.handshake(
+            &client_hello,
+            |server_share| secret.diffie_hellman(server_share),
+            |chain| self.authenticate(host, chain),
+        )?;
+        log::debug!("TLS handshake with {} complete", host);
+        Ok(())
+    }
+
+    fn client_hello(host: &str, random: &[u8; 32], key_share: &[u8; 32]) -> Vec<u8> {
+        let mut body = Vec::new();
+        put_u16(&mut body, 0x0303); // legacy_version
+        body.extend_from_slice(random);
+        body.push(0); // legacy_session_id
+        put_vec16(&mut body, &TLS_AES_128_GCM_SHA256.to_be_bytes());
+        body.extend_from_slice(&[1, 0]); // legacy_compression_methods
+
+        let mut extensions = Vec::new();
+        // SNI is only sent for DNS names (RFC 6066 section 3)
+        if host.parse::<std::net::IpAddr>().is_err() {
+            let host = host.trim_end_matches('.');
+            let mut server_name = vec![0]; // host_name
+            put_vec16(&mut server_name, host.as_bytes());
+            let mut server_name_list = Vec::new();
+            put_vec16(&mut server_name_list, &server_name);
+            put_extension(&mut extensions, EXT_SERVER_NAME, &server_name_list);
+        }
+        let mut groups = Vec::new();
+        put_vec16(&mut groups, &GROUP_X25519.to_be_bytes());
+        put_extension(&mut extensions, EXT_SUPPORTED_GROUPS, &groups);
+        let mut schemes = Vec::new();
+        for scheme in SignatureScheme::TLS_SUPPORTED.iter() {
+            put_u16(&mut schemes, *scheme);
+        }
+        let mut signature_algorithms = Vec::new();
+        put_vec16(&mut signature_algorithms, &schemes);
+        put_extension(
+            &mut extensions,
+            EXT_SIGNATURE_ALGORITHMS,
+            &signature_algorithms,
+        );
+        let mut versions = vec![2];
+        put_u16(&mut versions, TLS13);
+        put_extension(&mut extensions, EXT_SUPPORTED_VERSIONS, &versions);
+        let mut share = Vec::new();
+        put_u16(&mut share, GROUP_X25519);
+        put_vec16(&mut share, key_share);
+        let mut client_shares = Vec::new();
+        put_vec16(&mut client_shares, &share);
+        put_extension(&mut extensions, EXT_KEY_SHARE, &client_shares);
+        put_vec16(&mut body, &extensions);
+
+        handshake_message(HS_CLIENT_HELLO, &body)
+    }
+
+    /// Checks a ServerHello, returning the server's x25519 key share
+    fn parse_server_hello(body: &[u8]) -> Result<[u8; 32]> {
+        let mut reader = Reader::new(body);
+        reader.u16()?; // legacy_version
+        if reader.bytes(32)? == HELLO_RETRY_REQUEST {
+            // we only offer x25519, so the server is asking for something we don't have (most likely
+            // secp256r1, see the crate documentation)
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "server doesn't support x25519, and no other key exchange is implemented",
+            ));
+        }
+        if reader.vec8()?.len() != 0 {
+            return Err(protocol_error(
+                "server echoed a session ID that wasn't sent",
+            ));
+        }
+        if reader.u16()? != TLS_AES_128_GCM_SHA256 || reader.u8()? != 0 {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "server chose an unsupported cipher suite",
+            ));
+        }
+        let mut version = None;
+        let mut key_share = None;
+        let mut extensions = Reader::new(reader.vec16()?);
+        while !extensions.is_empty() {
+            let ext_type = extensions.u16()?;
+            let mut data = Reader::new(extensions.vec16()?);
+            match ext_type {
+                EXT_SUPPORTED_VERSIONS => version = Some(data.u16()?),
+                EXT_KEY_SHARE => {
+                    if data.u16()? != GROUP_X25519 {
+                        return Err(Error::new(
+                            ErrorKind::Unsupported,
+                            "server chose an unsupported group",
+                        ));
+                    }
+                    let share = data.vec16()?;
+                    if share.len() != 32 {
+                        return Err(protocol_error("malformed x25519 key share"));
+                    }
+                    let mut key = [0u8; 32];
+                    key.copy_from_slice(share);
+                    key_share = Some(key);
+                }
+                _ => {}
+            }
+        }
+        if version != Some(TLS13) {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "server doesn't support TLS 1.3",
+            ));
+        }
+        key_share.ok_or_else(|| protocol_error("server sent no key share"))
+    }
+
+    /// Extracts the DER-encoded certificates of a Certificate message, leaf first
+    fn parse_certificate(body: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let mut reader = Reader::new(body);
+        reader.vec8()?; // certificate_request_context
+        let mut list = Reader::new(reader.vec24()?);
+        let mut certificates = Vec::new();
+        while !list.is_empty() {
+            certificates.push(list.vec24()?.to_vec());
+            list.vec16()?; // extensions
+        }
+        if certificates.len() == 0 {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "server sent no certificate",
+            ));
+        }
+        Ok(certificates)
+    }
+
+    /// Checks that the server's certificate chain is trusted for `host`: either the host is pinned
+    /// and the leaf has the pinned key, or the chain leads to one of the trusted roots.
+    ///
+    /// A pin is kept per host, so it already ties the key to the host: the names and validity period
+    /// in a pinned certificate aren't checked, which is what lets a self-signed certificate be pinned,
+    /// and lets pinned hosts be reached before the time is known.
+    fn authenticate(&self, host: &str, chain_der: &[Vec<u8>]) -> Result<()> {
+        if let Some(pin) = self.store.get_pin(host.trim_end_matches('.'))? {
+            return if spki_pin(&chain_der[0])? == pin {
+                Ok(())
+            } else {
+                Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    "server key doesn't match the pinned key",
+                ))
+            };
+        }
+        let (now, slack) = self.now().ok_or_else(|| {
+            Error::new(
+                ErrorKind::PermissionDenied,
+                "the time isn't known, so the certificate can't be checked",
+            )
+        })?;
+        let mut chain = Vec::new();
+        for der in chain_der.iter() {
+            chain.push(Certificate::parse(der)?);
+        }
+        let roots_der = self.store.roots()?;
+        let mut roots = Vec::new();
+        for der in roots_der.iter() {
+            match Certificate::parse(der) {
+                Ok(root) => roots.push(root),
+                Err(e) => log::warn!("skipping unparseable root certificate: {:?}", e),
+            }
+        }
+        x509::verify_chain(&chain, &roots, host, now, slack)
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b.iter())
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+/// An established TLS connection over `S`.
+pub struct TlsStream<S: Read + Write> {
+    stream: S,
+    read_keys: Option<RecordKeys>,
+    write_keys: Option<RecordKeys>,
+    /// handshake messages that have been received, but not yet processed
+    handshake_buf: Vec<u8>,
+    /// application data that has been received, but not yet read
+    plaintext: Vec<u8>,
+    plaintext_pos: usize,
+    peer_certificates: Vec<Vec<u8>>,
+    /// set once the server has sent close_notify
+    closed: bool,
+}
+
+impl<S: Read + Write> TlsStream<S> {
+    fn new(stream: S) -> TlsStream<S> {
+        TlsStream {
+            stream,
+            read_keys: None,
+            write_keys: None,
+            handshake_buf: Vec::new(),
+            plaintext: Vec::new(),
+            plaintext_pos: 0,
+            peer_certificates: Vec::new(),
+            closed: false,
+        }
+    }
+
+    /// Runs the client side of the handshake, from sending `client_hello` up to sending our Finished.
+    /// `key_agreement` turns the server's x25519 key share into the shared secret, and `authenticate`
+    /// checks that the server's certificate chain (DER-encoded, leaf first) is trusted.
+    fn handshake(
+        &mut self,
+        client_hello: &[u8],
+        key_agreement: impl FnOnce(&PublicKey) -> SharedSecret,
+        authenticate: impl FnOnce(&[Vec<u8>]) -> Result<()>,
+    ) -> Result<()> {
+        let mut transcript = Sha256::new();
+        transcript.update(client_hello);
+        self.write_record(CONTENT_HANDSHAKE, client_hello)?;
+
+        // ServerHello
+        let server_hello = self.read_handshake()?;
+        if server_hello[0] != HS_SERVER_HELLO {
+            return Err(protocol_error("expected ServerHello"));
+        }
+        transcript.update(&server_hello);
+        let server_share = Tls::parse_server_hello(&server_hello[4..])?;
+        let shared = key_agreement(&PublicKey::from(server_share));
+        if shared.as_bytes().iter().all(|&b| b == 0) {
+            return Err(protocol_error("server sent a low-order x25519 key"));
+        }
+
+        // key schedule, up to the handshake traffic secrets
+        let zeros = [0u8; HASH_LEN];
+        let mut early_secret = hkdf_extract(&zeros, &zeros);
+        let empty_hash = sha256(&[]);
+        let mut derived = derive_secret(&early_secret, "derived", &empty_hash);
+        let mut handshake_secret = hkdf_extract(&derived, shared.as_bytes());
+        let hello_hash = transcript.clone().finalize();
+        let mut client_hs_secret = derive_secret(&handshake_secret, "c hs traffic", &hello_hash);
+        let mut server_hs_secret = derive_secret(&handshake_secret, "s hs traffic", &hello_hash);
+        self.read_keys = Some(RecordKeys::new(&server_hs_secret));
+        self.write_keys = Some(RecordKeys::new(&client_hs_secret));
+
+        // EncryptedExtensions: nothing we asked for needs an answer
+        let encrypted_extensions = self.read_handshake()?;
+        if encrypted_extensions[0] != HS_ENCRYPTED_EXTENSIONS {
+            return Err(protocol_error("expected EncryptedExtensions"));
+        }
+        transcript.update(&encrypted_extensions);
+
+        // optional CertificateRequest, then Certificate
+        let mut msg = self.read_handshake()?;
+        let mut certificate_request_context: Option<Vec<u8>> = None;
+        if msg[0] == HS_CERTIFICATE_REQUEST {
+            transcript.update(&msg);
+            certificate_request_context = Some(Reader::new(&msg[4..]).vec8()?.to_vec());
+            msg = self.read_handshake()?;
+        }
+        if msg[0] != HS_CERTIFICATE {
+            return Err(protocol_error("expected Certificate"));
+        }
+        transcript.update(&msg);
+        self.peer_certificates = Tls::parse_certificate(&msg[4..])?;
+
+        // CertificateVerify proves the server holds the key of the certificate
+        let verify = self.read_handshake()?;
+        if verify[0] != HS_CERTIFICATE_VERIFY {
+            return Err(protocol_error("expected CertificateVerify"));
+        }
+        let mut signed = vec![0x20u8; 64];
+        signed.extend_from_slice(b"TLS 1.3, server CertificateVerify\0");
+        signed.extend_from_slice(&transcript.clone().finalize());
+        let mut reader = Reader::new(&verify[4..]);
+        let scheme = SignatureScheme::from_tls(reader.u16()?).ok_or_else(|| {
+            Error::new(
+                ErrorKind::Unsupported,
+                "unsupported CertificateVerify signature scheme",
+            )
+        })?;
+        let signature = reader.vec16()?;
+        let leaf = Certificate::parse(&self.peer_certificates[0])?;
+        leaf.verify_signature(scheme, &signed, signature)?;
+        transcript.update(&verify);
+
+        // ...and the certificate has to be one we trust
+        authenticate(&self.peer_certificates)?;
+
+        // server Finished
+        let finished = self.read_handshake()?;
+        if finished[0] != HS_FINISHED {
+            return Err(protocol_error("expected Finished"));
+        }
+        let expected = finished_mac(&server_hs_secret, &transcript.clone().finalize());
+        if !constant_time_eq(&finished[4..], &expected) {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "server Finished doesn't verify",
+            ));
+        }
+        transcript.update(&finished);
+
+        // the application traffic secrets are derived from the transcript up to the server Finished
+        derived = derive_secret(&handshake_secret, "derived", &empty_hash);
+        let mut master_secret = hkdf_extract(&derived, &zeros);
+        let server_finished_hash = transcript.clone().finalize();
+        let mut client_ap_secret =
+            derive_secret(&master_secret, "c ap traffic", &server_finished_hash);
+        let mut server_ap_secret =
+            derive_secret(&master_secret, "s ap traffic", &server_finished_hash);
+
+        // client authentication isn't supported: answer a CertificateRequest with an empty Certificate
+        if let Some(context) = certificate_request_context {
+            let mut body = vec![context.len() as u8];
+            body.extend_from_slice(&context);
+            body.extend_from_slice(&[0, 0, 0]);
+            let certificate = handshake_message(HS_CERTIFICATE, &body);
+            transcript.update(&certificate);
+            self.write_record(CONTENT_HANDSHAKE, &certificate)?;
+        }
+        let client_finished = handshake_message(
+            HS_FINISHED,
+            &finished_mac(&client_hs_secret, &transcript.clone().finalize()),
+        );
+        self.write_record(CONTENT_HANDSHAKE, &client_finished)?;
+
+        self.read_keys = Some(RecordKeys::new(&server_ap_secret));
+        self.write_keys = Some(RecordKeys::new(&client_ap_secret));
+
+        for secret in [
+            &mut early_secret,
+            &mut derived,
+            &mut handshake_secret,
+            &mut client_hs_secret,
+            &mut server_hs_secret,
+            &mut master_secret,
+            &mut client_ap_secret,
+            &mut server_ap_secret,
+        ]
+        .iter_mut()
+        {
+            secret.zeroize();
+        }
+        Ok(())
+    }
+
+    /// The DER-encoded certificate chain presented by the server, leaf first
+    pub fn peer_certificates(&self) -> &[Vec<u8>] {
+        &self.peer_certificates
+    }
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+    /// Tells the server that we're done sending. The underlying stream stays open, so any
+    /// data the server still has in flight can be read.
+    pub fn close(&mut self) -> Result<()> {
+        self.write_record(CONTENT_ALERT, &[1, ALERT_CLOSE_NOTIFY])?;
+        self.stream.flush()
+    }
+
+    fn write_record(&mut self, content_type: u8, data: &[u8]) -> Result<()> {
+        match self.write_keys.as_mut() {
+            Some(keys) => {
+                let record = keys.seal(content_type, data)?;
+                self.stream.write_all(&record)
+            }
+            None => {
+                // the initial ClientHello is sent with the TLS 1.0 record version, for compatibility
+                let mut record = vec![
+                    content_type,
+                    3,
+                    1,
+                    (data.len() >> 8) as u8,
+                    data.len() as u8,
+                ];
+                record.extend_from_slice(data);
+                self.stream.write_all(&record)
+            }
+        }
+    }
+
+    /// Reads the next record, returning its content type and content. Alerts are turned into errors,
+    /// except for close_notify, which is returned as is.
+    fn read_record(&mut self) -> Result<(u8, Vec<u8>)> {
+        loop {
+            let mut header = [0u8; 5];
+            self.stream.read_exact(&mut header)?;
+            let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+            if len > MAX_RECORD_LEN {
+                return Err(protocol_error("TLS record too long"));
+            }
+            let mut payload = vec![0u8; len];
+            self.stream.read_exact(&mut payload)?;
+            let (content_type, content) = match (header[0], self.read_keys.as_mut()) {
+                // middlebox compatibility mode may have the server send a ChangeCipherSpec; it means nothing
+                (CONTENT_CHANGE_CIPHER_SPEC, _) => continue,
+                (CONTENT_APPLICATION_DATA, Some(keys)) => keys.open(&header, &payload)?,
+                (content_type, None) if content_type != CONTENT_APPLICATION_DATA => {
+                    (content_type, payload)
+                }
+                _ => return Err(protocol_error("unexpected TLS record type")),
+            };
+            if content_type == CONTENT_ALERT {
+                match content.get(1) {
+                    Some(&ALERT_CLOSE_NOTIFY) => {}
+                    Some(description) => {
+                        return Err(Error::new(
+                            ErrorKind::ConnectionAborted,
+                            format!("TLS alert {} from server", description),
+                        ))
+                    }
+                    None => return Err(protocol_error("malformed TLS alert")),
+                }
+            }
+            return Ok((content_type, content));
+        }
+    }
+
+    /// Returns the next complete handshake message, header included
+    fn read_handshake(&mut self) -> Result<Vec<u8>> {
+        loop {
+            if self.handshake_buf.len() >= 4 {
+                let len = Reader::new(&self.handshake_buf[1..4]).u24()?;
+                if self.handshake_buf.len() >= 4 + len {
+                    let rest = self.handshake_buf.split_off(4 + len);
+                    return Ok(std::mem::replace(&mut self.handshake_buf, rest));
+                }
+            }
+            match self.read_record()? {
+                (CONTENT_HANDSHAKE, content) => self.handshake_buf.extend_from_slice(&content),
+                (CONTENT_ALERT, _) => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "server closed the connection",
+                    ))
+                }
+                _ => return Err(protocol_error("unexpected record during handshake")),
+            }
+        }
+    }
+
+    /// Deals with the handshake messages that can arrive after the handshake
+    fn process_post_handshake(&mut self) -> Result<()> {
+        while self.handshake_buf.len() >= 4 {
+            let len = Reader::new(&self.handshake_buf[1..4]).u24()?;
+            if self.handshake_buf.len() < 4 + len {
+                break;
+            }
+            let msg = self.read_handshake()?;
+            match msg[0] {
+                // session resumption isn't supported, so tickets are of no use
+                HS_NEW_SESSION_TICKET => {}
+                HS_KEY_UPDATE => {
+                    if let Some(keys) = self.read_keys.as_mut() {
+                        keys.update();
+                    }
+                    if msg.get(4) == Some(&1) {
+                        // update_requested: the server wants us to update our keys as well
+                        self.write_record(
+                            CONTENT_HANDSHAKE,
+                            &handshake_message(HS_KEY_UPDATE, &[0]),
+                        )?;
+                        if let Some(keys) = self.write_keys.as_mut() {
+                            keys.update();
+                        }
+                    }
+                }
+                _ => return Err(protocol_error("unexpected post-handshake message")),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read + Write> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        while self.plaintext_pos >= self.plaintext.len() {
+            if self.closed {
+                return Ok(0);
+            }
+            match self.read_record()? {
+                (CONTENT_APPLICATION_DATA, content) => {
+                    self.plaintext = content;
+                    self.plaintext_pos = 0;
+                }
+                (CONTENT_HANDSHAKE, content) => {
+                    self.handshake_buf.extend_from_slice(&content);
+                    self.process_post_handshake()?;
+                }
+                (CONTENT_ALERT, _) => self.closed = true,
+                _ => return Err(protocol_error("unexpected TLS record")),
+            }
+        }
+        let len = buf.len().min(self.plaintext.len() - self.plaintext_pos);
+        buf[..len].copy_from_slice(&self.plaintext[self.plaintext_pos..self.plaintext_pos + len]);
+        self.plaintext_pos += len;
+        Ok(len)
+    }
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let len = buf.len().min(MAX_FRAGMENT_LEN);
+        self.write_record(CONTENT_APPLICATION_DATA, &buf[..len])?;
+        Ok(len)
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: Read + Write> Drop for TlsStream<S> {
+    fn drop(&mut self) {
+        self.plaintext.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x25519_dalek::StaticSecret;
+    use x509::tests::{hex, ROOT};
+
+    // A TLS 1.3 session with `openssl s_server -tls1_3 -rev`, which sends back each line reversed,
+    // using a certificate for example.com issued by the test root. The client's random was all 0x5a,
+    // and its x25519 secret all 0x42.
+    const CLIENT_RECORDS: &str = "\
+        16030100900100008c03035a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a0000021301\
+        0100006100000010000e00000b6578616d706c652e636f6d000a00040002001d000d0010000e04030804080508060401\
+        05010601002b0003020304003300260024001d0020132c442be010fbd57e72603328aa76e71fccc1503aae219327d14d\
+        9c9993f472170303003588e867e6cc07eb419719001391a76394eb943901a84cade7bd72f1d6182da75936ec2da54c62\
+        1fffad32a474b0288fc957e3be10ee170303001756b29292868aece67cb1c10555dff62cdae19c99d0c986";
+    const SERVER_RECORDS: &str = "\
+        160303005a02000056030337246da155efba8270c08c92d6f5638f88a7fdbd95165dd483096ef4770a9eba0013010000\
+        2e002b0002030400330024001d0020be89cdff8c1adb707b2d78ebfda1a12680be7621ab2f4477dc40b1acade5ec3814\
+        03030001011703030017d94490f07e21ed9582293d58be4f1ab179ed1479c314d917030301c9badb0161f75aac0d9c0a\
+        eae23bfae0ccb00be8cb81ac7fefcde87e9b96a9599a73fcbe2282e3b95faf3f28b4fc5f8d7995b5f693b235676aa03f\
+        fe983ba1db8841fd52d02a51fdbcc8ae5f25dcdf6f434386c8c02c6ea17a89a2b86eac5cb0bebf49e585c25ce4dd425e\
+        b348f5800c630436e265bdb51b0325ac7451d4122ea39621db7277f845fddae8f7b7cc86cb06ad90e22881c31b44c7f5\
+        65d7dc42dbb1070166ea85753f84fa0b285890d19be66fa9140e0df7a5e886addb70863307da63d9f653e36811e07241\
+        bf8933ede87589b89c097fa06fb22b8899f6c704538ec88c73a3e49128f52f97bb8df7e6ed527b2de7136df30af35147\
+        5b90fbfa7631c7e5cacde03ee33090a89a5a66d80fb21152608b5a9ffe425837d33120ad3fdadcdb2b542c1cdbe7bac0\
+        a66b2b2861114ea1bf1a913d3341913e13312e934811c7a1d3b32f68f3af77c594d762b382d436f8a080357d9a53ed8f\
+        b7559a20825fb27b36f8c5f0d6f29cd60ce346464c656c63eb761dc63b8ac0fee4dc4e21de6bc7d59c4678112959c642\
+        c3dee29d3ebc30284300bc38dca27f24cd48b5864e1ac7071a876146bf4e294af6d9a9f1f1d58b9131ff14942f59827b\
+        2a6227c049e2de2c63f5f275399dca170303005ffb17e0f84fee861e1d2d57644044fb5f23f3b155805cf3795968b39b\
+        935546292304b74a4471921163af011de97d47d91dca10c2d04e4a42282b4ec358e2f6f97bb0a105085666aae6ebf0e2\
+        b728b9afe1c2eba9f2e72a67bc9b77a123ab3b1703030035c122e71452f3ee8462da32e60197f9730854b8fe9ed7f500\
+        5c76715fa74463109eab5d24b84c16341614a45a9264043b9a79db51ef17030300eaf0f786c863dbfc37d2a5146a08bc\
+        e4b56e05c4da52fc80c9934b49f9b0520e1ada96379424edca60fd29b3487455791c1ed10ec4b3d52139620d1523e2fe\
+        a17acccedb7dfce9d6724a7252a91819ef4223d33205a325ac3c455025da513001aef6b193314861b34bb62a1f5f2f68\
+        9fcfd7c81e2c2507bf7def87066a1fddf0f8ee6fad08e3692d6f156f7fb595aa0abfbb67a3288cf2a8ca97cda2107ae8\
+        e0147ab4723bfb7ff2186c5e4c29207a9b24c70537b336d69d9a4d959361a16d22c17cd16699f052a3cb37408cdd593d\
+        254ed5023167ad70e989af437a7bff9ed0bc5e34cade8061a46ded8617030300ea63a29c5943e329d04420454def9e6a\
+        6c1e4f167ea683127e2092575a58302c54ebeb435503cb7da7291a50ac6b43df68506517ccdb7e664f3c077c6fffce47\
+        a64ea45f68c8c08427f807d00b8e5f8e142cb4ea258a21bf7483b4df33585b8327f012dc34947f8507726699e5a374d6\
+        f5886353458a52f2c120c521f20551366bcba300ff360704e9710477c214fa1aa21cc5b44551b5924fc7f6f2ddd45583\
+        18e47ff16ed16274368d791b8a27567416b93ddabfd4238d67e43b34bf9b2f1d2c2843b79b4178492223ee1109face4b\
+        a5b1386b2698a8e01998a4e9e605f00873e0880815fd035c3be18017030300170ff28a345d3e4a6d55f253fdc92be3d4\
+        8f2fdeeb08a689";
+
+    /// Plays back what the server sent, and keeps what the client sends
+    struct Replay {
+        incoming: std::io::Cursor<Vec<u8>>,
+        outgoing: Vec<u8>,
+    }
+    impl Read for Replay {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            self.incoming.read(buf)
+        }
+    }
+    impl Write for Replay {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.outgoing.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn replay(server_records: Vec<u8>, host: &str, now: i64) -> Result<TlsStream<Replay>> {
+        let mut tls = TlsStream::new(Replay {
+            incoming: std::io::Cursor::new(server_records),
+            outgoing: Vec::new(),
+        });
+        let secret = StaticSecret::from([0x42; 32]);
+        let client_hello = Tls::client_hello(
+            "example.com",
+            &[0x5a; 32],
+            PublicKey::from(&secret).as_bytes(),
+        );
+        let root = hex(ROOT);
+        tls.handshake(
+            &client_hello,
+            |server_share| secret.diffie_hellman(server_share),
+            |chain_der| {
+                let mut chain = Vec::new();
+                for der in chain_der.iter() {
+                    chain.push(Certificate::parse(der)?);
+                }
+                x509::verify_chain(&chain, &[Certificate::parse(&root)?], host, now, 0)
+            },
+        )?;
+        Ok(tls)
+    }
+
+    #[test]
+    fn openssl_handshake() {
+        let mut tls = replay(hex(SERVER_RECORDS), "example.com", 1_700_000_000).unwrap();
+        assert_eq!(tls.peer_certificates().len(), 1);
+        tls.write_all(b"hello\n").unwrap();
+        let mut reply = [0u8; 6];
+        tls.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"olleh\n");
+        assert_eq!(tls.get_ref().outgoing, hex(CLIENT_RECORDS));
+    }
+
+    #[test]
+    fn openssl_handshake_tampered() {
+        assert!(replay(hex(SERVER_RECORDS), "example.org", 1_700_000_000).is_err());
+        assert!(replay(hex(SERVER_RECORDS), "example.com", 2_300_000_000).is_err());
+
+        // the server's flight is ServerHello, ChangeCipherSpec, EncryptedExtensions, Certificate,
+        // CertificateVerify and Finished, each in a record of its own
+        let records = hex(SERVER_RECORDS);
+        let mut start = 0;
+        for _ in 0..6 {
+            let len = u16::from_be_bytes([records[start + 3], records[start + 4]]) as usize;
+            if records[start] != CONTENT_CHANGE_CIPHER_SPEC {
+                let mut tampered = records.clone();
+                tampered[start + 5 + 10] ^= 1;
+                assert!(replay(tampered, "example.com", 1_700_000_000).is_err());
+            }
+            start += 5 + len;
+        }
+    }
+}
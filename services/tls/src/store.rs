@@ -0,0 +1,117 @@
+use crate::crypto::sha256;
+use crate::x509::Certificate;
+use std::cell::RefCell;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+/// PDDB dictionary holding the trusted root certificates, DER-encoded, keyed by the hex SHA-256 of the certificate
+pub const TLS_ROOTS_DICT: &str = "tls.roots";
+/// PDDB dictionary holding the pinned keys, keyed by host name
+pub const TLS_PINS_DICT: &str = "tls.pins";
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Computes the pin of a DER-encoded certificate: the SHA-256 of its SubjectPublicKeyInfo, as used by HPKP.
+/// Pinning the key rather than the certificate lets a host renew its certificate without breaking the pin.
+pub fn spki_pin(cert_der: &[u8]) -> Result<[u8; 32]> {
+    Ok(sha256(Certificate::parse(cert_der)?.spki))
+}
+
+/// The root certificates and per-host pins used to authenticate servers, as stored in the PDDB.
+/// The stores of all the currently unlocked bases are consulted; changes go to the most recently unlocked one.
+pub struct CertStore {
+    pddb: RefCell<pddb::Pddb>,
+}
+impl CertStore {
+    pub fn new() -> CertStore {
+        CertStore {
+            pddb: RefCell::new(pddb::Pddb::new().expect("couldn't connect to the PDDB")),
+        }
+    }
+
+    fn read_key(&self, dict: &str, key: &str) -> Result<Vec<u8>> {
+        let mut pddb = self.pddb.borrow_mut();
+        let mut entry = pddb.get(dict, key, None, false, false, None, Some(|| {}))?;
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        Ok(data)
+    }
+    fn write_key(&self, dict: &str, key: &str, data: &[u8]) -> Result<()> {
+        let mut pddb = self.pddb.borrow_mut();
+        // writing into an existing key doesn't truncate it, so start from scratch
+        match pddb.delete_key(dict, key, None, false) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let mut entry = pddb.get(dict, key, None, true, true, Some(data.len()), Some(|| {}))?;
+        entry.write_all(data)?;
+        entry.flush()
+    }
+    fn list(&self, dict: &str) -> Result<Vec<String>> {
+        match self.pddb.borrow_mut().list_keys(dict, None) {
+            Ok(keys) => Ok(keys),
+            // the dictionary only comes into existence with its first key
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Adds a DER-encoded certificate to the trusted roots, returning the name it's stored under.
+    pub fn add_root(&self, cert_der: &[u8]) -> Result<String> {
+        Certificate::parse(cert_der)?;
+        let name = hex(&sha256(cert_der));
+        self.write_key(TLS_ROOTS_DICT, &name, cert_der)?;
+        Ok(name)
+    }
+    pub fn remove_root(&self, name: &str) -> Result<()> {
+        self.pddb
+            .borrow_mut()
+            .delete_key(TLS_ROOTS_DICT, name, None, false)
+    }
+    /// Names of the trusted root certificates
+    pub fn list_roots(&self) -> Result<Vec<String>> {
+        self.list(TLS_ROOTS_DICT)
+    }
+    /// DER encodings of the trusted root certificates
+    pub fn roots(&self) -> Result<Vec<Vec<u8>>> {
+        let mut roots = Vec::new();
+        for name in self.list_roots()? {
+            roots.push(self.read_key(TLS_ROOTS_DICT, &name)?);
+        }
+        Ok(roots)
+    }
+
+    /// Pins `host` to a key, given as the SHA-256 of its SubjectPublicKeyInfo (see `spki_pin()`).
+    /// A pinned host is only trusted if it presents that key, but then it's trusted whether or not
+    /// its certificate chains up to a trusted root, so self-signed servers can be used. The names and
+    /// validity period of its certificate aren't checked either: the pin is what ties the key to `host`.
+    pub fn pin(&self, host: &str, spki_sha256: &[u8; 32]) -> Result<()> {
+        self.write_key(TLS_PINS_DICT, &host.to_ascii_lowercase(), spki_sha256)
+    }
+    pub fn unpin(&self, host: &str) -> Result<()> {
+        self.pddb
+            .borrow_mut()
+            .delete_key(TLS_PINS_DICT, &host.to_ascii_lowercase(), None, false)
+    }
+    pub fn get_pin(&self, host: &str) -> Result<Option<[u8; 32]>> {
+        match self.read_key(TLS_PINS_DICT, &host.to_ascii_lowercase()) {
+            Ok(data) if data.len() == 32 => {
+                let mut pin = [0u8; 32];
+                pin.copy_from_slice(&data);
+                Ok(Some(pin))
+            }
+            Ok(_) => Err(Error::new(
+                ErrorKind::InvalidData,
+                "stored pin is corrupted",
+            )),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    /// Hosts that have a pinned key
+    pub fn list_pins(&self) -> Result<Vec<String>> {
+        self.list(TLS_PINS_DICT)
+    }
+}
@@ -0,0 +1,933 @@
+//! Just enough X.509 to validate a server's certificate chain: DER parsing of the certificate
+//! fields that matter for path validation, and RSA/ECDSA signature verification.
+
+use num_bigint::BigUint;
+use p256::ecdsa::signature::Verifier;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::io::{Error, ErrorKind, Result};
+
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_VERSION: u8 = 0xa0;
+const TAG_EXTENSIONS: u8 = 0xa3;
+const TAG_SAN_DNS_NAME: u8 = 0x82;
+const TAG_SAN_IP_ADDRESS: u8 = 0x87;
+
+const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const OID_SHA256_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+const OID_SHA384_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c];
+const OID_SHA512_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x0f];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+const OID_BASIC_CONSTRAINTS: &[u8] = &[0x55, 0x1d, 0x13];
+const OID_EXT_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x25];
+const OID_SERVER_AUTH: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x01];
+
+/// Bits of the key usage extension, numbered as in RFC 5280 section 4.2.1.3
+const KEY_USAGE_DIGITAL_SIGNATURE: u16 = 1 << 0;
+const KEY_USAGE_KEY_CERT_SIGN: u16 = 1 << 5;
+
+/// Longest chain that will be walked, not counting the root
+const MAX_CHAIN_DEPTH: usize = 6;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// A cursor over a run of DER-encoded values
+struct Der<'a> {
+    data: &'a [u8],
+}
+impl<'a> Der<'a> {
+    fn new(data: &'a [u8]) -> Der<'a> {
+        Der { data }
+    }
+    fn is_empty(&self) -> bool {
+        self.data.len() == 0
+    }
+    fn peek_tag(&self) -> Option<u8> {
+        self.data.first().copied()
+    }
+    /// Returns the tag, the contents, and the complete encoding of the next value
+    fn next(&mut self) -> Result<(u8, &'a [u8], &'a [u8])> {
+        let data = self.data;
+        if data.len() < 2 {
+            return Err(invalid("truncated DER value"));
+        }
+        let tag = data[0];
+        let (len, header_len): (usize, usize) = match data[1] {
+            len if len < 0x80 => (len as usize, 2),
+            0x81 => (
+                *data.get(2).ok_or(invalid("truncated DER length"))? as usize,
+                3,
+            ),
+            0x82 => {
+                let len = data.get(2..4).ok_or(invalid("truncated DER length"))?;
+                (((len[0] as usize) << 8) | len[1] as usize, 4)
+            }
+            0x83 => {
+                let len = data.get(2..5).ok_or(invalid("truncated DER length"))?;
+                (
+                    ((len[0] as usize) << 16) | ((len[1] as usize) << 8) | len[2] as usize,
+                    5,
+                )
+            }
+            _ => return Err(invalid("unsupported DER length")),
+        };
+        let total = header_len
+            .checked_add(len)
+            .ok_or(invalid("DER length overflow"))?;
+        if data.len() < total {
+            return Err(invalid("truncated DER value"));
+        }
+        self.data = &data[total..];
+        Ok((tag, &data[header_len..total], &data[..total]))
+    }
+    /// Returns the contents of the next value, which must have the given tag
+    fn expect(&mut self, tag: u8) -> Result<&'a [u8]> {
+        match self.next()? {
+            (t, contents, _) if t == tag => Ok(contents),
+            _ => Err(invalid("unexpected DER tag")),
+        }
+    }
+    /// Returns the contents of the next value if it has the given tag
+    fn optional(&mut self, tag: u8) -> Result<Option<&'a [u8]>> {
+        if self.peek_tag() == Some(tag) {
+            Ok(Some(self.expect(tag)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// The contents of a BIT STRING, which must not have any unused bits
+fn bit_string(contents: &[u8]) -> Result<&[u8]> {
+    match contents.split_first() {
+        Some((0, bits)) => Ok(bits),
+        _ => Err(invalid("unsupported BIT STRING")),
+    }
+}
+
+/// The bits set in a key usage BIT STRING; bit `n` of the result is the `n`th named bit
+fn key_usage(contents: &[u8]) -> Result<u16> {
+    match contents.split_first() {
+        Some((&unused, bits)) if unused < 8 && bits.len() <= 2 => {
+            let mut usage = 0;
+            for n in 0..bits.len() * 8 {
+                if bits[n / 8] & (0x80 >> (n % 8)) != 0 {
+                    usage |= 1 << n;
+                }
+            }
+            Ok(usage)
+        }
+        _ => Err(invalid("malformed key usage")),
+    }
+}
+
+/// Strips the sign-padding off an unsigned INTEGER
+fn unsigned_integer(contents: &[u8]) -> &[u8] {
+    let mut contents = contents;
+    while contents.len() > 1 && contents[0] == 0 {
+        contents = &contents[1..];
+    }
+    contents
+}
+
+/// Days since the epoch for a proleptic Gregorian date (from Howard Hinnant's `days_from_civil`)
+pub(crate) fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Parses an X.509 Time into seconds since the epoch
+fn parse_time(tag: u8, contents: &[u8]) -> Result<i64> {
+    let text = std::str::from_utf8(contents).map_err(|_| invalid("malformed time"))?;
+    let (year, rest) = match tag {
+        TAG_UTC_TIME if text.len() == 13 => {
+            let yy: i64 = text[..2].parse().map_err(|_| invalid("malformed time"))?;
+            (if yy >= 50 { 1900 + yy } else { 2000 + yy }, &text[2..])
+        }
+        TAG_GENERALIZED_TIME if text.len() == 15 => (
+            text[..4].parse().map_err(|_| invalid("malformed time"))?,
+            &text[4..],
+        ),
+        _ => return Err(invalid("unsupported time format")),
+    };
+    if !rest.ends_with('Z') || !rest[..10].bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid("malformed time"));
+    }
+    let field = |i: usize| -> i64 { rest[i..i + 2].parse().unwrap() };
+    let days = days_from_civil(year, field(0), field(2));
+    Ok(days * 86400 + field(4) * 3600 + field(6) * 60 + field(8))
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum HashAlg {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+impl HashAlg {
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            HashAlg::Sha256 => Sha256::digest(data).to_vec(),
+            HashAlg::Sha384 => Sha384::digest(data).to_vec(),
+            HashAlg::Sha512 => Sha512::digest(data).to_vec(),
+        }
+    }
+    fn len(&self) -> usize {
+        match self {
+            HashAlg::Sha256 => 32,
+            HashAlg::Sha384 => 48,
+            HashAlg::Sha512 => 64,
+        }
+    }
+    /// DER encoding of the DigestInfo that precedes the hash in a PKCS#1 v1.5 signature
+    fn digest_info_prefix(&self) -> &'static [u8] {
+        match self {
+            HashAlg::Sha256 => &[
+                0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+                0x01, 0x05, 0x00, 0x04, 0x20,
+            ],
+            HashAlg::Sha384 => &[
+                0x30, 0x41, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+                0x02, 0x05, 0x00, 0x04, 0x30,
+            ],
+            HashAlg::Sha512 => &[
+                0x30, 0x51, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+                0x03, 0x05, 0x00, 0x04, 0x40,
+            ],
+        }
+    }
+}
+
+/// The signature schemes that can be verified
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum SignatureScheme {
+    RsaPkcs1(HashAlg),
+    RsaPss(HashAlg),
+    EcdsaP256Sha256,
+}
+impl SignatureScheme {
+    /// Maps a TLS SignatureScheme code point, as used in CertificateVerify. PKCS#1 v1.5 signatures
+    /// aren't allowed in a TLS 1.3 CertificateVerify, so they aren't mapped.
+    pub(crate) fn from_tls(code: u16) -> Option<SignatureScheme> {
+        match code {
+            0x0403 => Some(SignatureScheme::EcdsaP256Sha256),
+            0x0804 => Some(SignatureScheme::RsaPss(HashAlg::Sha256)),
+            0x0805 => Some(SignatureScheme::RsaPss(HashAlg::Sha384)),
+            0x0806 => Some(SignatureScheme::RsaPss(HashAlg::Sha512)),
+            _ => None,
+        }
+    }
+    /// The code points advertised in the signature_algorithms extension; this covers certificate signatures too
+    pub(crate) const TLS_SUPPORTED: [u16; 7] =
+        [0x0403, 0x0804, 0x0805, 0x0806, 0x0401, 0x0501, 0x0601];
+
+    fn from_oid(oid: &[u8]) -> Option<SignatureScheme> {
+        match oid {
+            OID_SHA256_WITH_RSA => Some(SignatureScheme::RsaPkcs1(HashAlg::Sha256)),
+            OID_SHA384_WITH_RSA => Some(SignatureScheme::RsaPkcs1(HashAlg::Sha384)),
+            OID_SHA512_WITH_RSA => Some(SignatureScheme::RsaPkcs1(HashAlg::Sha512)),
+            OID_ECDSA_WITH_SHA256 => Some(SignatureScheme::EcdsaP256Sha256),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum PublicKey<'a> {
+    Rsa {
+        modulus: &'a [u8],
+        exponent: &'a [u8],
+    },
+    EcP256(&'a [u8]),
+    Unsupported,
+}
+
+/// The fields of a certificate that are needed to validate it
+#[derive(Debug)]
+pub(crate) struct Certificate<'a> {
+    /// the complete encoding of the certificate
+    pub(crate) der: &'a [u8],
+    /// the complete encoding of the TBSCertificate, which is what the issuer signed
+    tbs: &'a [u8],
+    signature_oid: &'a [u8],
+    signature: &'a [u8],
+    /// complete encoding of the issuer Name, for comparison against the subject of other certificates
+    issuer: &'a [u8],
+    subject: &'a [u8],
+    not_before: i64,
+    not_after: i64,
+    /// complete encoding of the SubjectPublicKeyInfo
+    pub(crate) spki: &'a [u8],
+    public_key: PublicKey<'a>,
+    dns_names: Vec<&'a [u8]>,
+    ip_addresses: Vec<&'a [u8]>,
+    is_ca: bool,
+    /// the most intermediate certificates a CA allows below itself, if it sets a limit
+    path_len: Option<usize>,
+    /// the bits of the key usage extension, if there is one
+    key_usage: Option<u16>,
+    /// whether the extended key usage allows TLS server authentication; without the extension, anything goes
+    server_auth: bool,
+}
+
+impl<'a> Certificate<'a> {
+    pub(crate) fn parse(der: &'a [u8]) -> Result<Certificate<'a>> {
+        let mut outer = Der::new(der);
+        let (tag, contents, full) = outer.next()?;
+        if tag != TAG_SEQUENCE || !outer.is_empty() {
+            return Err(invalid("certificate is not a single SEQUENCE"));
+        }
+        let der = full;
+        let mut cert = Der::new(contents);
+        let (tag, tbs_contents, tbs) = cert.next()?;
+        if tag != TAG_SEQUENCE {
+            return Err(invalid("malformed TBSCertificate"));
+        }
+        let mut alg = Der::new(cert.expect(TAG_SEQUENCE)?);
+        let signature_oid = alg.expect(TAG_OID)?;
+        let signature = bit_string(cert.expect(TAG_BIT_STRING)?)?;
+
+        let mut fields = Der::new(tbs_contents);
+        fields.optional(TAG_VERSION)?;
+        fields.expect(TAG_INTEGER)?; // serial number
+        fields.expect(TAG_SEQUENCE)?; // signature algorithm, repeated from the outside
+        let (_, _, issuer) = fields.next()?;
+        let mut validity = Der::new(fields.expect(TAG_SEQUENCE)?);
+        let (tag, contents, _) = validity.next()?;
+        let not_before = parse_time(tag, contents)?;
+        let (tag, contents, _) = validity.next()?;
+        let not_after = parse_time(tag, contents)?;
+        let (_, _, subject) = fields.next()?;
+        let (tag, spki_contents, spki) = fields.next()?;
+        if tag != TAG_SEQUENCE {
+            return Err(invalid("malformed SubjectPublicKeyInfo"));
+        }
+        let public_key = Certificate::parse_public_key(spki_contents)?;
+
+        let mut dns_names = Vec::new();
+        let mut ip_addresses = Vec::new();
+        let mut is_ca = false;
+        let mut path_len = None;
+        let mut key_usage_bits = None;
+        let mut server_auth = true;
+        while !fields.is_empty() {
+            let (tag, contents, _) = fields.next()?;
+            if tag != TAG_EXTENSIONS {
+                continue; // issuerUniqueID, subjectUniqueID
+            }
+            let mut extensions = Der::new(Der::new(contents).expect(TAG_SEQUENCE)?);
+            while !extensions.is_empty() {
+                let mut extension = Der::new(extensions.expect(TAG_SEQUENCE)?);
+                let oid = extension.expect(TAG_OID)?;
+                let critical = extension
+                    .optional(TAG_BOOLEAN)?
+                    .map(|b| b.iter().any(|&x| x != 0))
+                    .unwrap_or(false);
+                let value = extension.expect(TAG_OCTET_STRING)?;
+                match oid {
+                    OID_SUBJECT_ALT_NAME => {
+                        let mut names = Der::new(Der::new(value).expect(TAG_SEQUENCE)?);
+                        while !names.is_empty() {
+                            match names.next()? {
+                                (TAG_SAN_DNS_NAME, name, _) => dns_names.push(name),
+                                (TAG_SAN_IP_ADDRESS, addr, _) => ip_addresses.push(addr),
+                                _ => {}
+                            }
+                        }
+                    }
+                    OID_BASIC_CONSTRAINTS => {
+                        let mut constraints = Der::new(Der::new(value).expect(TAG_SEQUENCE)?);
+                        is_ca = constraints
+                            .optional(TAG_BOOLEAN)?
+                            .map(|b| b.iter().any(|&x| x != 0))
+                            .unwrap_or(false);
+                        path_len = constraints.optional(TAG_INTEGER)?.map(|n| {
+                            unsigned_integer(n).iter().fold(0usize, |len, &b| {
+                                len.saturating_mul(256).saturating_add(b as usize)
+                            })
+                        });
+                    }
+                    OID_KEY_USAGE => {
+                        key_usage_bits = Some(key_usage(Der::new(value).expect(TAG_BIT_STRING)?)?);
+                    }
+                    OID_EXT_KEY_USAGE => {
+                        let mut purposes = Der::new(Der::new(value).expect(TAG_SEQUENCE)?);
+                        server_auth = false;
+                        while !purposes.is_empty() {
+                            if purposes.expect(TAG_OID)? == OID_SERVER_AUTH {
+                                server_auth = true;
+                            }
+                        }
+                    }
+                    // anything else critical has to be understood, so the certificate can't be used
+                    _ if critical => {
+                        return Err(invalid("certificate has an unsupported critical extension"));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(Certificate {
+            der,
+            tbs,
+            signature_oid,
+            signature,
+            issuer,
+            subject,
+            not_before,
+            not_after,
+            spki,
+            public_key,
+            dns_names,
+            ip_addresses,
+            is_ca,
+            path_len,
+            key_usage: key_usage_bits,
+            server_auth,
+        })
+    }
+
+    fn parse_public_key(spki_contents: &'a [u8]) -> Result<PublicKey<'a>> {
+        let mut spki = Der::new(spki_contents);
+        let mut alg = Der::new(spki.expect(TAG_SEQUENCE)?);
+        let key_oid = alg.expect(TAG_OID)?;
+        let key = bit_string(spki.expect(TAG_BIT_STRING)?)?;
+        match key_oid {
+            OID_RSA_ENCRYPTION => {
+                let mut rsa = Der::new(Der::new(key).expect(TAG_SEQUENCE)?);
+                let modulus = unsigned_integer(rsa.expect(TAG_INTEGER)?);
+                let exponent = unsigned_integer(rsa.expect(TAG_INTEGER)?);
+                Ok(PublicKey::Rsa { modulus, exponent })
+            }
+            OID_EC_PUBLIC_KEY if alg.optional(TAG_OID)? == Some(OID_PRIME256V1) => {
+                Ok(PublicKey::EcP256(key))
+            }
+            _ => Ok(PublicKey::Unsupported),
+        }
+    }
+
+    /// Whether the certificate is valid at `now`, which may be off by up to `slack` seconds
+    fn valid_at(&self, now: i64, slack: i64) -> bool {
+        self.not_before <= now + slack && now - slack <= self.not_after
+    }
+
+    /// Whether the path length constraint, if there is one, allows `intermediates` certificates below this one
+    fn path_len_allows(&self, intermediates: usize) -> bool {
+        self.path_len.map_or(true, |max| intermediates <= max)
+    }
+
+    /// Whether the key usage, if there is one, has all of the `bits`
+    fn key_usage_allows(&self, bits: u16) -> bool {
+        self.key_usage.map_or(true, |usage| usage & bits == bits)
+    }
+
+    /// Checks that `message` was signed by this certificate's key
+    pub(crate) fn verify_signature(
+        &self,
+        scheme: SignatureScheme,
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<()> {
+        let ok = match (&self.public_key, scheme) {
+            (PublicKey::Rsa { modulus, exponent }, SignatureScheme::RsaPkcs1(hash)) => {
+                rsa_verify_pkcs1(modulus, exponent, hash, message, signature)
+            }
+            (PublicKey::Rsa { modulus, exponent }, SignatureScheme::RsaPss(hash)) => {
+                rsa_verify_pss(modulus, exponent, hash, message, signature)
+            }
+            (PublicKey::EcP256(point), SignatureScheme::EcdsaP256Sha256) => {
+                match (
+                    p256::ecdsa::VerifyingKey::from_sec1_bytes(point),
+                    p256::ecdsa::Signature::from_der(signature),
+                ) {
+                    (Ok(key), Ok(signature)) => key.verify(message, &signature).is_ok(),
+                    _ => false,
+                }
+            }
+            (PublicKey::Unsupported, _) => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "unsupported public key type",
+                ));
+            }
+            _ => false,
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(Error::new(ErrorKind::PermissionDenied, "bad signature"))
+        }
+    }
+
+    /// Checks that this certificate was issued by `issuer`
+    fn issued_by(&self, issuer: &Certificate) -> Result<()> {
+        let scheme = SignatureScheme::from_oid(self.signature_oid).ok_or(Error::new(
+            ErrorKind::Unsupported,
+            "unsupported certificate signature algorithm",
+        ))?;
+        issuer.verify_signature(scheme, self.tbs, self.signature)
+    }
+
+    /// Checks whether this certificate is for `host`, which is a DNS name or an IP address
+    fn matches_host(&self, host: &str) -> bool {
+        if let Ok(ip) = host.parse::<std::net::IpAddr>() {
+            let octets = match ip {
+                std::net::IpAddr::V4(v4) => v4.octets().to_vec(),
+                std::net::IpAddr::V6(v6) => v6.octets().to_vec(),
+            };
+            return self.ip_addresses.iter().any(|&addr| addr == &octets[..]);
+        }
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.dns_names
+            .iter()
+            .any(|name| match std::str::from_utf8(name) {
+                Ok(name) => dns_name_matches(&name.to_ascii_lowercase(), &host),
+                Err(_) => false,
+            })
+    }
+}
+
+/// Matches a (lower-case) host name against a DNS name from a certificate. A wildcard is only honored
+/// as the complete left-most label, and it only stands in for a single label.
+fn dns_name_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => match host.split_once('.') {
+            Some((label, rest)) => label.len() > 0 && rest == suffix && suffix.contains('.'),
+            None => false,
+        },
+        None => pattern == host,
+    }
+}
+
+/// RSA public operation: returns the signature representative as a big-endian byte string the length of the modulus
+fn rsa_public(modulus: &[u8], exponent: &[u8], signature: &[u8]) -> Option<Vec<u8>> {
+    // keys shorter than 2048 bits are no longer trustworthy
+    if modulus.len() < 256 || modulus.len() > 512 || signature.len() != modulus.len() {
+        return None;
+    }
+    let n = BigUint::from_bytes_be(modulus);
+    let s = BigUint::from_bytes_be(signature);
+    if s >= n {
+        return None;
+    }
+    let m = s
+        .modpow(&BigUint::from_bytes_be(exponent), &n)
+        .to_bytes_be();
+    let mut em = vec![0u8; modulus.len() - m.len()];
+    em.extend_from_slice(&m);
+    Some(em)
+}
+
+/// RSASSA-PKCS1-v1_5 verification (RFC 8017 section 8.2.2)
+fn rsa_verify_pkcs1(
+    modulus: &[u8],
+    exponent: &[u8],
+    hash: HashAlg,
+    message: &[u8],
+    signature: &[u8],
+) -> bool {
+    let em = match rsa_public(modulus, exponent, signature) {
+        Some(em) => em,
+        None => return false,
+    };
+    let prefix = hash.digest_info_prefix();
+    let t_len = prefix.len() + hash.len();
+    if em.len() < t_len + 11 {
+        return false;
+    }
+    let mut expected = vec![0x00, 0x01];
+    expected.resize(em.len() - t_len - 1, 0xff);
+    expected.push(0x00);
+    expected.extend_from_slice(prefix);
+    expected.extend_from_slice(&hash.digest(message));
+    em == expected
+}
+
+/// MGF1 mask generation (RFC 8017 appendix B.2.1)
+fn mgf1(hash: HashAlg, seed: &[u8], len: usize) -> Vec<u8> {
+    let mut mask = Vec::with_capacity(len + hash.len());
+    let mut counter = 0u32;
+    while mask.len() < len {
+        let mut block = seed.to_vec();
+        block.extend_from_slice(&counter.to_be_bytes());
+        mask.extend_from_slice(&hash.digest(&block));
+        counter += 1;
+    }
+    mask.truncate(len);
+    mask
+}
+
+/// RSASSA-PSS verification (RFC 8017 section 8.1.2), with MGF1 on the same hash and a salt as long
+/// as the hash, as required by TLS 1.3.
+fn rsa_verify_pss(
+    modulus: &[u8],
+    exponent: &[u8],
+    hash: HashAlg,
+    message: &[u8],
+    signature: &[u8],
+) -> bool {
+    let em = match rsa_public(modulus, exponent, signature) {
+        Some(em) => em,
+        None => return false,
+    };
+    let mod_bits = modulus.len() * 8 - modulus[0].leading_zeros() as usize;
+    let em_bits = mod_bits - 1;
+    let em_len = (em_bits + 7) / 8;
+    // EM is one byte shorter than the modulus when the modulus length is a multiple of 8 bits
+    if em.len() > em_len && em[0] != 0 {
+        return false;
+    }
+    let em = &em[em.len() - em_len..];
+    let h_len = hash.len();
+    let s_len = h_len;
+    if em_len < h_len + s_len + 2 || em[em_len - 1] != 0xbc {
+        return false;
+    }
+    let (masked_db, h) = em[..em_len - 1].split_at(em_len - h_len - 1);
+    let unused_bits = 8 * em_len - em_bits;
+    let top_mask = 0xffu8.checked_shr(unused_bits as u32).unwrap_or(0);
+    if masked_db[0] & !top_mask != 0 {
+        return false;
+    }
+    let mut db: Vec<u8> = masked_db
+        .iter()
+        .zip(mgf1(hash, h, masked_db.len()).iter())
+        .map(|(a, b)| a ^ b)
+        .collect();
+    db[0] &= top_mask;
+    let ps_len = em_len - h_len - s_len - 2;
+    if db[..ps_len].iter().any(|&b| b != 0) || db[ps_len] != 0x01 {
+        return false;
+    }
+    let salt = &db[ps_len + 1..];
+    let mut m_prime = vec![0u8; 8];
+    m_prime.extend_from_slice(&hash.digest(message));
+    m_prime.extend_from_slice(salt);
+    hash.digest(&m_prime) == h
+}
+
+/// Checks that `chain` (leaf first, as sent by the server) is a valid certificate chain for `host`,
+/// that ends in one of the trusted `roots`. `now` is the time in seconds since the epoch, which may
+/// be off by up to `slack` seconds.
+pub(crate) fn verify_chain(
+    chain: &[Certificate],
+    roots: &[Certificate],
+    host: &str,
+    now: i64,
+    slack: i64,
+) -> Result<()> {
+    let leaf = chain.first().ok_or(invalid("server sent no certificate"))?;
+    if !leaf.matches_host(host) {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "certificate is not valid for this host",
+        ));
+    }
+    if !leaf.key_usage_allows(KEY_USAGE_DIGITAL_SIGNATURE) {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "certificate's key may not be used for signatures",
+        ));
+    }
+    let mut current = leaf;
+    for depth in 0..=MAX_CHAIN_DEPTH {
+        if !current.valid_at(now, slack) {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "certificate has expired or is not yet valid",
+            ));
+        }
+        // the extended key usage of a CA limits what the certificates it issues may be used for
+        if !current.server_auth {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "certificate is not for TLS servers",
+            ));
+        }
+        if depth > 0 && !(current.is_ca && current.key_usage_allows(KEY_USAGE_KEY_CERT_SIGN)) {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "certificate issued by a non-CA certificate",
+            ));
+        }
+        // the leaf doesn't count towards a path length constraint, only the intermediates do
+        if depth > 0 && !current.path_len_allows(depth - 1) {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "certificate chain is longer than its CA allows",
+            ));
+        }
+        // a certificate that is itself trusted ends the chain
+        if roots.iter().any(|root| root.der == current.der) {
+            return Ok(());
+        }
+        // a root is held to the same validity period and path length constraint as the rest of the chain;
+        // if several roots issued `current` (a renewed root keeps its name and key), any good one will do
+        let mut rejected = None;
+        for root in roots.iter().filter(|root| {
+            root.subject == current.issuer
+                && root.key_usage_allows(KEY_USAGE_KEY_CERT_SIGN)
+                && current.issued_by(root).is_ok()
+        }) {
+            rejected = Some(if !root.valid_at(now, slack) {
+                "trusted root has expired or is not yet valid"
+            } else if !root.path_len_allows(depth) {
+                "certificate chain is longer than its root allows"
+            } else {
+                return Ok(());
+            });
+        }
+        if let Some(msg) = rejected {
+            return Err(Error::new(ErrorKind::PermissionDenied, msg));
+        }
+        match chain[1..]
+            .iter()
+            .find(|cert| cert.subject == current.issuer && current.issued_by(cert).is_ok())
+        {
+            Some(issuer) => current = issuer,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    "certificate isn't issued by a trusted root",
+                ))
+            }
+        }
+    }
+    Err(Error::new(
+        ErrorKind::PermissionDenied,
+        "certificate chain is too long",
+    ))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) const ROOT: &str = "\
+        3082017230820118a003020102021464b81c77bf9a8a5f089eff5746dca853a1646597300a06082a8648ce3d04030230\
+        173115301306035504030c0c586f75732054657374204341301e170d3232303130313030303030305a170d3432303130\
+        313030303030305a30173115301306035504030c0c586f757320546573742043413059301306072a8648ce3d02010608\
+        2a8648ce3d03010703420004ebd89cb1a461b1a2454ebe7c29e43584bfef61aba0246718494fee570c191a6bd8b0d9af\
+        836088126be54e143675f7a8f332b245cff64dad5f26e9528658d922a3423040300f0603551d130101ff040530030101\
+        ff300e0603551d0f0101ff040403020106301d0603551d0e041604148cd1903e9acd19869af22731ea3ab83360dd6b6f\
+        300a06082a8648ce3d0403020348003045022100a62e7d915e643d08ad8b6e386b270a015d90ba853dbd840bc02eaf63\
+        e02174ef022017b3606eff895986ad0712e5bf434c48124dc6fcd2b75ce31f8b18ad79478e92";
+    const LEAF: &str = "\
+        308201a73082014ea003020102020102300a06082a8648ce3d04030230173115301306035504030c0c586f7573205465\
+        7374204341301e170d3232303130313030303030305a170d3432303130313030303030305a3016311430120603550403\
+        0c0b6578616d706c652e636f6d3059301306072a8648ce3d020106082a8648ce3d0301070342000427be6eef61a4a79a\
+        9724ea998e609c274f89ccf16daa7acf6f36c48ad896ddb0086c04bca2e38bf93a4d8e381127e29d05bc37f68adae30c\
+        58bccda9995d9043a3818b30818830090603551d1304023000300e0603551d0f0101ff04040302078030130603551d25\
+        040c300a06082b0601050507030130160603551d11040f300d820b6578616d706c652e636f6d301d0603551d0e041604\
+        14d358103dfe18e65d616253668e3fddce4b03e67e301f0603551d230418301680148cd1903e9acd19869af22731ea3a\
+        b83360dd6b6f300a06082a8648ce3d0403020347003044022065a58e1eee52709901690b8af2b890ece1d6456d8f2f3a\
+        bc28e7250cdc96c1e5022032c65772dfd865100f076b4923de0b53cb5db5a187c82008b50fc3d29fd03fab";
+    const CLIENT_LEAF: &str = "\
+        308201a93082014ea003020102020103300a06082a8648ce3d04030230173115301306035504030c0c586f7573205465\
+        7374204341301e170d3232303130313030303030305a170d3432303130313030303030305a3016311430120603550403\
+        0c0b6578616d706c652e636f6d3059301306072a8648ce3d020106082a8648ce3d0301070342000427be6eef61a4a79a\
+        9724ea998e609c274f89ccf16daa7acf6f36c48ad896ddb0086c04bca2e38bf93a4d8e381127e29d05bc37f68adae30c\
+        58bccda9995d9043a3818b30818830090603551d1304023000300e0603551d0f0101ff04040302078030130603551d25\
+        040c300a06082b0601050507030230160603551d11040f300d820b6578616d706c652e636f6d301d0603551d0e041604\
+        14d358103dfe18e65d616253668e3fddce4b03e67e301f0603551d230418301680148cd1903e9acd19869af22731ea3a\
+        b83360dd6b6f300a06082a8648ce3d0403020349003046022100ea0b54958258bcdc1b91b5d337c852f03a3881151566\
+        27111c0199db9e9b5cb00221008ea1db59afec47c802d5fb35db9d54147d2016d5e9e27cec427e98dfb51f54bf";
+    const INTERMEDIATE: &str = "\
+        3082018930820130a003020102020105300a06082a8648ce3d04030230173115301306035504030c0c586f7573205465\
+        7374204341301e170d3232303130313030303030305a170d3432303130313030303030305a3021311f301d0603550403\
+        0c16586f7573205465737420496e7465726d6564696174653059301306072a8648ce3d020106082a8648ce3d03010703\
+        420004e62b0ffc4127e21d3870900ea0c441c856abd5266cdfb56887ee8a63c7f877871220cf747c0b15e29f68d95302\
+        b99e0383781bbacc9b20cff9b0f3016f102d23a3633061300f0603551d130101ff040530030101ff300e0603551d0f01\
+        01ff040403020204301d0603551d0e041604146298bdec523d87787d1af5f7c43dcbd573be79a0301f0603551d230418\
+        301680148cd1903e9acd19869af22731ea3ab83360dd6b6f300a06082a8648ce3d04030203470030440220693e254a4c\
+        bf64662853b320804a0103fce043f6850e7bbc4c87c37651eaa5c90220676ac705144a7625877631beb6c80c277c213e\
+        91c0de2e38fd78e8be11c2f5b1";
+    const INTERMEDIATE_NO_CERT_SIGN: &str = "\
+        3082018a30820130a003020102020106300a06082a8648ce3d04030230173115301306035504030c0c586f7573205465\
+        7374204341301e170d3232303130313030303030305a170d3432303130313030303030305a3021311f301d0603550403\
+        0c16586f7573205465737420496e7465726d6564696174653059301306072a8648ce3d020106082a8648ce3d03010703\
+        420004e62b0ffc4127e21d3870900ea0c441c856abd5266cdfb56887ee8a63c7f877871220cf747c0b15e29f68d95302\
+        b99e0383781bbacc9b20cff9b0f3016f102d23a3633061300f0603551d130101ff040530030101ff300e0603551d0f01\
+        01ff040403020780301d0603551d0e041604146298bdec523d87787d1af5f7c43dcbd573be79a0301f0603551d230418\
+        301680148cd1903e9acd19869af22731ea3ab83360dd6b6f300a06082a8648ce3d040302034800304502207bce1de03e\
+        edc5f17f26ba0fa1f3641fd70f74e788665bb4591eb8d47bb346e2022100a714112a27fd02da8108329d7b345703efed\
+        a0c734b2df345ab6c459b7265154";
+    const INTERMEDIATE_LEAF: &str = "\
+        308201b330820158a003020102020107300a06082a8648ce3d0403023021311f301d06035504030c16586f7573205465\
+        737420496e7465726d656469617465301e170d3232303130313030303030305a170d3432303130313030303030305a30\
+        163114301206035504030c0b6578616d706c652e636f6d3059301306072a8648ce3d020106082a8648ce3d0301070342\
+        000427be6eef61a4a79a9724ea998e609c274f89ccf16daa7acf6f36c48ad896ddb0086c04bca2e38bf93a4d8e381127\
+        e29d05bc37f68adae30c58bccda9995d9043a3818b30818830090603551d1304023000300e0603551d0f0101ff040403\
+        02078030130603551d25040c300a06082b0601050507030130160603551d11040f300d820b6578616d706c652e636f6d\
+        301d0603551d0e04160414d358103dfe18e65d616253668e3fddce4b03e67e301f0603551d230418301680146298bdec\
+        523d87787d1af5f7c43dcbd573be79a0300a06082a8648ce3d0403020349003046022100dd312d0a40c6228cd934571d\
+        a4d0115d80519f0c607a30cb6638c26a821a18dd022100f54596a2b6929d8afd51d30fb81b3a08cbec7fb3d32085b8b1\
+        04eb651ccb88c8";
+    // a root that allows no intermediates, and expires on 2030-01-01, before the certificates it issued
+    const CONSTRAINED_ROOT: &str = "\
+        308201463081eda003020102020108300a06082a8648ce3d04030230193117301506035504030c0e586f757320546573\
+        742043412032301e170d3232303130313030303030305a170d3330303130313030303030305a30193117301506035504\
+        030c0e586f7573205465737420434120323059301306072a8648ce3d020106082a8648ce3d030107034200045c8ae3fc\
+        6740c407c270beda70f63d5278670c40c82def6443d4fa81b515cca237ff97ee4a2fd2d3c973a8de5cfa14979f4ca7c5\
+        3b863e573a8af85a7d0ce076a326302430120603551d130101ff040830060101ff020100300e0603551d0f0101ff0404\
+        03020106300a06082a8648ce3d0403020348003045022100bb70d9b7f49d98006ed6b204e7c2ea91c980f07aaf017061\
+        8168e8dc34de9d5102203e92927905e405a1401bf463672a0b5c8c9964e345be1c1964b78c08c2d1168b";
+    const CONSTRAINED_ROOT_LEAF: &str = "\
+        308201673082010ea003020102020109300a06082a8648ce3d04030230193117301506035504030c0e586f7573205465\
+        73742043412032301e170d3232303130313030303030305a170d3432303130313030303030305a301631143012060355\
+        04030c0b6578616d706c652e636f6d3059301306072a8648ce3d020106082a8648ce3d030107034200049764a1779e12\
+        d66cfdf8bf06954e360e776631fcaabad809930e0b6fea506305af641f9b829fd62337c2795f9cca8ecd71d144381e07\
+        a1a6372a2597cc2950a4a34a304830090603551d1304023000300e0603551d0f0101ff04040302078030130603551d25\
+        040c300a06082b0601050507030130160603551d11040f300d820b6578616d706c652e636f6d300a06082a8648ce3d04\
+        0302034700304402202740485b2e6dcfe6bf50840130b0e05d4f5c6fe7b55f2fb7f5428c78abe2333902207fb6e8f9b1\
+        bea02880e18961d5b113e65f0eea17ea327c092a827a236efb6750";
+    const CONSTRAINED_ROOT_INTERMEDIATE: &str = "\
+        3082014d3081f4a00302010202010a300a06082a8648ce3d04030230193117301506035504030c0e586f757320546573\
+        742043412032301e170d3232303130313030303030305a170d3432303130313030303030305a30233121301f06035504\
+        030c18586f7573205465737420496e7465726d65646961746520323059301306072a8648ce3d020106082a8648ce3d03\
+        010703420004f162d3664194acde3ca6d902d1638d088dda77ea804252a0c8cda1ce7ef78683e8945c7177fe17f4a51e\
+        7da8beff95bd69f32e804ba62231576ada26a750a360a3233021300f0603551d130101ff040530030101ff300e060355\
+        1d0f0101ff040403020106300a06082a8648ce3d0403020348003045022100c7095f0824f1254a398a3405904da0bf9d\
+        25b20e45c6668ea3d3a659f00e000d02203729b70d1ee9d463289a5225dd72f494105fcc8f2dde65dc8cfdb2a6a50354\
+        50";
+    const CONSTRAINED_ROOT_INTERMEDIATE_LEAF: &str = "\
+        3082017230820118a00302010202010b300a06082a8648ce3d04030230233121301f06035504030c18586f7573205465\
+        737420496e7465726d6564696174652032301e170d3232303130313030303030305a170d343230313031303030303030\
+        5a30163114301206035504030c0b6578616d706c652e636f6d3059301306072a8648ce3d020106082a8648ce3d030107\
+        034200049764a1779e12d66cfdf8bf06954e360e776631fcaabad809930e0b6fea506305af641f9b829fd62337c2795f\
+        9cca8ecd71d144381e07a1a6372a2597cc2950a4a34a304830090603551d1304023000300e0603551d0f0101ff040403\
+        02078030130603551d25040c300a06082b0601050507030130160603551d11040f300d820b6578616d706c652e636f6d\
+        300a06082a8648ce3d04030203480030450220782485cb31882ae06604220231cd421fb57c61a1243062f7ab7e52927d\
+        8c75dd022100c663fad1af64c4e7b605d80cc355401d0e136aada7875d2b86361f3fade2a065";
+
+    pub(crate) fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(dns_name_matches("example.com", "example.com"));
+        assert!(dns_name_matches("*.example.com", "www.example.com"));
+        assert!(!dns_name_matches("*.example.com", "example.com"));
+        assert!(!dns_name_matches("*.example.com", "a.b.example.com"));
+        assert!(!dns_name_matches("*.com", "example.com"));
+        assert!(!dns_name_matches("www.example.com", "example.com"));
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(parse_time(TAG_UTC_TIME, b"700101000000Z").unwrap(), 0);
+        assert_eq!(
+            parse_time(TAG_UTC_TIME, b"220301120000Z").unwrap(),
+            1646136000
+        );
+        assert_eq!(
+            parse_time(TAG_GENERALIZED_TIME, b"20491231235959Z").unwrap(),
+            2524607999
+        );
+        assert!(parse_time(TAG_UTC_TIME, b"2203011200Z").is_err());
+    }
+
+    #[test]
+    fn chains() {
+        let (root, leaf) = (hex(ROOT), hex(LEAF));
+        let (intermediate, intermediate_leaf) = (hex(INTERMEDIATE), hex(INTERMEDIATE_LEAF));
+        let roots = [Certificate::parse(&root).unwrap()];
+        let chain = [Certificate::parse(&leaf).unwrap()];
+        // all the test certificates are valid from 2022-01-01 until 2042-01-01
+        let now = 1_700_000_000;
+        assert!(verify_chain(&chain, &roots, "example.com", now, 0).is_ok());
+        assert!(verify_chain(&chain, &roots, "example.com.", now, 0).is_ok());
+        assert!(verify_chain(&chain, &roots, "example.org", now, 0).is_err());
+        assert!(verify_chain(&[], &roots, "example.com", now, 0).is_err());
+        assert!(verify_chain(&chain, &[], "example.com", now, 0).is_err());
+
+        let expired = 2_272_147_200 + 3600;
+        assert!(verify_chain(&chain, &roots, "example.com", expired, 0).is_err());
+        assert!(verify_chain(&chain, &roots, "example.com", expired, 7200).is_ok());
+        let early = 1_640_995_200 - 3600;
+        assert!(verify_chain(&chain, &roots, "example.com", early, 0).is_err());
+        assert!(verify_chain(&chain, &roots, "example.com", early, 7200).is_ok());
+
+        let chain = [
+            Certificate::parse(&intermediate_leaf).unwrap(),
+            Certificate::parse(&intermediate).unwrap(),
+        ];
+        assert!(verify_chain(&chain, &roots, "example.com", now, 0).is_ok());
+        assert!(verify_chain(&chain[..1], &roots, "example.com", now, 0).is_err());
+    }
+
+    #[test]
+    fn constrained_roots() {
+        let (root, leaf, intermediate, intermediate_leaf) = (
+            hex(CONSTRAINED_ROOT),
+            hex(CONSTRAINED_ROOT_LEAF),
+            hex(CONSTRAINED_ROOT_INTERMEDIATE),
+            hex(CONSTRAINED_ROOT_INTERMEDIATE_LEAF),
+        );
+        let roots = [Certificate::parse(&root).unwrap()];
+        let now = 1_700_000_000;
+        let chain = [Certificate::parse(&leaf).unwrap()];
+        assert!(verify_chain(&chain, &roots, "example.com", now, 0).is_ok());
+        // the leaf is still valid in 2035, but its root isn't
+        let root_expired = 2_051_222_400;
+        assert!(verify_chain(&chain, &roots, "example.com", root_expired, 0).is_err());
+
+        // the root's path length constraint of 0 leaves no room for an intermediate
+        let chain = [
+            Certificate::parse(&intermediate_leaf).unwrap(),
+            Certificate::parse(&intermediate).unwrap(),
+        ];
+        assert!(verify_chain(&chain, &roots, "example.com", now, 0).is_err());
+        // though trusting the intermediate itself ends the chain before it reaches the root
+        let roots = [Certificate::parse(&intermediate).unwrap()];
+        assert!(verify_chain(&chain, &roots, "example.com", now, 0).is_ok());
+    }
+
+    #[test]
+    fn key_usages() {
+        assert_eq!(
+            key_usage(&[0x01, 0x06]).unwrap(),
+            KEY_USAGE_KEY_CERT_SIGN | 1 << 6
+        );
+        assert_eq!(
+            key_usage(&[0x07, 0x80]).unwrap(),
+            KEY_USAGE_DIGITAL_SIGNATURE
+        );
+
+        let (root, client_leaf) = (hex(ROOT), hex(CLIENT_LEAF));
+        let roots = [Certificate::parse(&root).unwrap()];
+        let now = 1_700_000_000;
+        // a certificate for TLS clients can't be used by a server
+        let chain = [Certificate::parse(&client_leaf).unwrap()];
+        assert!(verify_chain(&chain, &roots, "example.com", now, 0).is_err());
+        // nor can a certificate that isn't allowed to sign certificates issue one
+        let (intermediate, intermediate_leaf) =
+            (hex(INTERMEDIATE_NO_CERT_SIGN), hex(INTERMEDIATE_LEAF));
+        let chain = [
+            Certificate::parse(&intermediate_leaf).unwrap(),
+            Certificate::parse(&intermediate).unwrap(),
+        ];
+        assert!(verify_chain(&chain, &roots, "example.com", now, 0).is_err());
+    }
+}
@@ -1,6 +1,9 @@
 #[allow(dead_code)]
 pub const AUTHENTICATE_TIMEOUT: u32 = 10_000; // time in ms that a process has to respond to an authentication request
 
+/// The most entries a server's access-control list can hold
+pub const MAX_ACL_ENTRIES: usize = 8;
+
 #[derive(num_derive::FromPrimitive, num_derive::ToPrimitive)]
 #[non_exhaustive]
 #[repr(C)]
@@ -8,10 +11,13 @@ pub(crate) enum Opcode {
     /// Create a new server with the given name and return its SID.
     Register = 0,
 
-    /// Create a connection to the target server.
+    /// Create a connection to the target server. Returns a `CID`, or an `AuthenticateRequest` if the server's
+    /// trusted connections are used up and it takes authenticated ones. Anything else returns `Failure`,
+    /// including a name that isn't registered yet; that used to return a placeholder `AuthenticateRequest`.
     Lookup = 1,
 
-    /// Create an authenticated connection to the target server.
+    /// Create an authenticated connection to the target server, by answering the challenge in the
+    /// `AuthenticateRequest` returned from a `Lookup`. Takes an `AuthenticatedLookup`.
     AuthenticatedLookup = 2,
 
     /// unregister a server, given its cryptographically unique SID.
@@ -76,6 +82,30 @@ pub(crate) enum Return {
 pub(crate) struct Registration {
     pub name: xous_ipc::String<64>,
     pub conn_limit: Option<u32>,
+    /// An Ed25519 public key. Once the `conn_limit` connections are made, further connections are only
+    /// brokered for processes that prove they hold the matching private key.
+    pub auth_key: Option<[u8; 32]>,
+    /// If set, only these processes may connect
+    pub acl: Option<[Option<AclEntry>; MAX_ACL_ENTRIES]>,
+}
+
+/// A process that may connect to a server registered with `register_name_with_acl()`
+#[derive(Debug, Copy, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub enum AclEntry {
+    /// The process with this PID
+    Pid(u8),
+    /// The process with this name. Processes are named by the loader's `PNam` tag, after the program
+    /// they run, so unlike a server name, no other process can take the name over.
+    Process(xous_ipc::String<64>),
+}
+#[allow(dead_code)]
+impl AclEntry {
+    pub fn pid(pid: xous::PID) -> AclEntry {
+        AclEntry::Pid(pid.get())
+    }
+    pub fn process(name: &str) -> AclEntry {
+        AclEntry::Process(xous_ipc::String::from_str(name))
+    }
 }
 
 #[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
@@ -88,7 +118,7 @@ pub(crate) struct Disconnect {
 pub(crate) struct AuthenticatedLookup {
     pub name: xous_ipc::String<64>,
     pub pubkey_id: [u8; 20], // 160-bit pubkey ID encoded in network order (big endian)
-    pub response: [u32; 8],
+    pub signature: [u8; 64], // Ed25519 signature over `auth_message()`
 }
 
 #[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
@@ -96,7 +126,18 @@ pub(crate) struct AuthenticatedLookup {
 pub(crate) struct AuthenticateRequest {
     pub name: xous_ipc::String<64>, // a copy of the originally requested lookup
     pub pubkey_id: [u8; 20],        // 160-bit pubkey ID encoded in network order (big endian)
-    pub challenge: [u32; 4],
+    pub challenge: [u32; 8],
+}
+
+/// What's signed in answer to an `AuthenticateRequest`. The name is included, so that an answer can't be
+/// used for any other server.
+pub(crate) fn auth_message(name: &str, challenge: &[u32; 8]) -> Vec<u8> {
+    let mut message = Vec::from(&b"xous-names authenticate"[..]);
+    for word in challenge.iter() {
+        message.extend_from_slice(&word.to_be_bytes());
+    }
+    message.extend_from_slice(name.as_bytes());
+    message
 }
 
 //////////////////////////////////////////////////////////////////////////////////////////////
@@ -0,0 +1,233 @@
+//! Ed25519 (RFC 8032) checks for the signatures made in answer to an authentication request.
+//!
+//! The name server can't use the hardware SHA-512 and curve25519 engines that the workspace patches in
+//! for `sha2` 0.9 and `curve25519-dalek` 3, as those engines are servers that have to be looked up through
+//! us. So `ed25519-dalek` is built here on `sha2` 0.10 and `curve25519-dalek` 4, which the patches don't
+//! apply to, and which run in plain software. Only public values pass through this code.
+//!
+//! Verification is strict: S must be reduced, R must be the canonical encoding of [S]B - [k]A, and
+//! neither R nor the key may be of small order. Keys must also be canonically encoded. A key of small
+//! order would accept forged signatures, as [k]A vanishes for any k, so such keys can't be registered.
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use sha2::{Digest, Sha512};
+
+/// Decodes a public key, which must be canonically encoded, and outside the small subgroup of order 8
+fn decode_key(public_key: &[u8; 32]) -> Option<VerifyingKey> {
+    let key = VerifyingKey::from_bytes(public_key).ok()?;
+    // decompression takes y modulo p, and ignores the sign bit when x = 0
+    if key.to_edwards().compress().as_bytes() != public_key || key.is_weak() {
+        return None;
+    }
+    Some(key)
+}
+
+/// Whether `public_key` can be used to check signatures
+pub(crate) fn is_valid_key(public_key: &[u8; 32]) -> bool {
+    decode_key(public_key).is_some()
+}
+
+/// The 160-bit ID of a public key, as given in an `AuthenticateRequest`: the start of its SHA-512 hash
+pub(crate) fn key_id(public_key: &[u8; 32]) -> [u8; 20] {
+    let mut id = [0u8; 20];
+    id.copy_from_slice(&Sha512::digest(public_key)[..20]);
+    id
+}
+
+/// Checks an Ed25519 signature, per RFC 8032 section 5.1.7 (without the cofactor)
+pub(crate) fn verify(public_key: &[u8; 32], message: &[u8], signature: &[u8; 64]) -> bool {
+    match decode_key(public_key) {
+        Some(key) => key.verify_strict(message, &Signature::from_bytes(signature)).is_ok(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn key(hex: &str) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&unhex(hex));
+        key
+    }
+
+    fn signature(hex: &str) -> [u8; 64] {
+        let mut signature = [0u8; 64];
+        signature.copy_from_slice(&unhex(hex));
+        signature
+    }
+
+    /// The public key of test 1 of RFC 8032 section 7.1
+    const KEY_1: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+
+    #[test]
+    fn rfc8032_vectors() {
+        // all of RFC 8032 section 7.1: tests 1, 2, 3, 1024 and SHA(abc)
+        let vectors = [
+            (
+                KEY_1,
+                "",
+                "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+                 5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+            ),
+            (
+                "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+                "72",
+                "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+                 085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+            ),
+            (
+                "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+                "af82",
+                "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac\
+                 18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+            ),
+            (
+                "278117fc144c72340f67d0f2316e8386ceffbf2b2428c9c51fef7c597f1d426e",
+                MESSAGE_1024,
+                "0aab4c900501b3e24d7cdf4663326a3a87df5e4843b2cbdb67cbf6e460fec350\
+                 aa5371b1508f9f4528ecea23c436d94b5e8fcd4f681e30a6ac00a9704a188a03",
+            ),
+            (
+                "ec172b93ad5e563bf4932c70e1245034c35467ef2efd4d64ebf819683467e2bf",
+                "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+                 2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f",
+                "dc2a4459e7369633a52b1bf277839a00201009a3efbf3ecb69bea2186c26b589\
+                 09351fc9ac90b3ecfdfbc7c66431e0303dca179c138ac17ad9bef1177331a704",
+            ),
+        ];
+        for (public_key, message, signature_hex) in vectors.iter() {
+            let public_key = key(public_key);
+            let message = unhex(message);
+            let signature_bytes = signature(signature_hex);
+
+            assert!(is_valid_key(&public_key));
+            assert!(verify(&public_key, &message, &signature_bytes));
+
+            let mut other_message = message.clone();
+            other_message.push(0);
+            assert!(!verify(&public_key, &other_message, &signature_bytes));
+            for &index in [0, 31, 32, 63].iter() {
+                let mut tampered = signature_bytes;
+                tampered[index] ^= 0x04;
+                assert!(!verify(&public_key, &message, &tampered));
+            }
+        }
+        assert_eq!(unhex(MESSAGE_1024).len(), 1023);
+        // a y coordinate with no x to go with it
+        let mut not_a_point = [0u8; 32];
+        not_a_point[0] = 2;
+        assert!(!is_valid_key(&not_a_point));
+    }
+
+    #[test]
+    fn malleable_signatures_are_rejected() {
+        // signatures of "Test" with the key of RFC 8032 test 1. The second has L added to S, and the third
+        // has the top bit of S set; both would pass if S were reduced before it's used.
+        let public_key = key(KEY_1);
+        let message = b"Test";
+        let valid = "0358333856a92f93b519f4dcef9876f94215d8dd719f42220291ee2acef6eab6\
+                     6ac1112ab0da59995107b3422157bc8efbd15baf8f6d8bb12a4142748c32490b";
+        assert!(verify(&public_key, message, &signature(valid)));
+        for s in [
+            "57950787ca3d6cf127a4aae5ff509ba3fbd15baf8f6d8bb12a4142748c32491b",
+            "6ac1112ab0da59995107b3422157bc8efbd15baf8f6d8bb12a4142748c32498b",
+            // L itself, and all ones
+            "edd3f55c1a631258d69cf7a2def9de1400000000000000000000000000000010",
+            "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+        ]
+        .iter()
+        {
+            let malleated = signature(&format!("{}{}", &valid[..64], s));
+            assert!(!verify(&public_key, message, &malleated), "{}", s);
+        }
+        assert!(!verify(&public_key, message, &[0; 64]));
+        assert!(!verify(&public_key, message, &[0xff; 64]));
+    }
+
+    #[test]
+    fn non_canonical_encodings_are_rejected() {
+        // signatures of "Test" with the key of RFC 8032 test 1, made with R the identity. The first has R in
+        // its canonical encoding; the second encodes y as p + 1, and the third sets the sign bit of x = 0.
+        // Each S is good for its R, so a verifier that decoded R instead of comparing encodings would pass
+        // the last two. The identity is of small order, so strict verification refuses even the first.
+        let public_key = key(KEY_1);
+        let message = b"Test";
+        for identity_r in [
+            "0100000000000000000000000000000000000000000000000000000000000000\
+             cf6fc35cc9dbd6bd1aaf7755067ff04ac70cb9c8b11420a6fef12bceed0d0d0e",
+            "eeffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff7f\
+             614036698b5a483da72597c141c833789cbda197991001a5bd4dc0e6807cef05",
+            "0100000000000000000000000000000000000000000000000000000000000080\
+             d08e645f5a9fec8b9466676b573c68bfe83c7a749518c6602afb4402088b5909",
+        ]
+        .iter()
+        {
+            assert!(!verify(&public_key, message, &signature(identity_r)));
+        }
+        // a point of large order with y = 3, encoded as p + 3
+        let non_canonical_key =
+            key("f0ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff7f");
+        assert!(!is_valid_key(&non_canonical_key));
+        let mut canonical_key = [0u8; 32];
+        canonical_key[0] = 3;
+        assert!(is_valid_key(&canonical_key));
+    }
+
+    #[test]
+    fn small_order_keys_are_rejected() {
+        // the eight points of order dividing 8
+        for point in [
+            "0100000000000000000000000000000000000000000000000000000000000000",
+            "ecffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff7f",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "0000000000000000000000000000000000000000000000000000000000000080",
+            "26e8958fc2b227b045c3f489f2ef98f0d5dfac05d3c63339b13802886d53fc05",
+            "26e8958fc2b227b045c3f489f2ef98f0d5dfac05d3c63339b13802886d53fc85",
+            "c7176a703d4dd84fba3c0b760d10670f2a2053fa2c39ccc64ec7fd7792ac037a",
+            "c7176a703d4dd84fba3c0b760d10670f2a2053fa2c39ccc64ec7fd7792ac03fa",
+        ]
+        .iter()
+        {
+            assert!(!is_valid_key(&key(point)), "{}", point);
+        }
+        // with the identity as the key, R = the identity and S = 0 would sign anything
+        let mut forged = [0u8; 64];
+        forged[0] = 1;
+        let identity = key("0100000000000000000000000000000000000000000000000000000000000000");
+        assert!(!verify(&identity, b"anything", &forged));
+    }
+
+    /// The message of test 1024 of RFC 8032 section 7.1
+    const MESSAGE_1024: &str = "\
+        08b8b2b733424243760fe426a4b54908632110a66c2f6591eabd3345e3e4eb98fa6e264bf09efe12ee50f8f54e9f77b1\
+        e355f6c50544e23fb1433ddf73be84d879de7c0046dc4996d9e773f4bc9efe5738829adb26c81b37c93a1b270b20329d\
+        658675fc6ea534e0810a4432826bf58c941efb65d57a338bbd2e26640f89ffbc1a858efcb8550ee3a5e1998bd177e93a\
+        7363c344fe6b199ee5d02e82d522c4feba15452f80288a821a579116ec6dad2b3b310da903401aa62100ab5d1a36553e\
+        06203b33890cc9b832f79ef80560ccb9a39ce767967ed628c6ad573cb116dbefefd75499da96bd68a8a97b928a8bbc10\
+        3b6621fcde2beca1231d206be6cd9ec7aff6f6c94fcd7204ed3455c68c83f4a41da4af2b74ef5c53f1d8ac70bdcb7ed1\
+        85ce81bd84359d44254d95629e9855a94a7c1958d1f8ada5d0532ed8a5aa3fb2d17ba70eb6248e594e1a2297acbbb39d\
+        502f1a8c6eb6f1ce22b3de1a1f40cc24554119a831a9aad6079cad88425de6bde1a9187ebb6092cf67bf2b13fd65f270\
+        88d78b7e883c8759d2c4f5c65adb7553878ad575f9fad878e80a0c9ba63bcbcc2732e69485bbc9c90bfbd62481d9089b\
+        eccf80cfe2df16a2cf65bd92dd597b0707e0917af48bbb75fed413d238f5555a7a569d80c3414a8d0859dc65a46128ba\
+        b27af87a71314f318c782b23ebfe808b82b0ce26401d2e22f04d83d1255dc51addd3b75a2b1ae0784504df543af8969b\
+        e3ea7082ff7fc9888c144da2af58429ec96031dbcad3dad9af0dcbaaaf268cb8fcffead94f3c7ca495e056a9b47acdb7\
+        51fb73e666c6c655ade8297297d07ad1ba5e43f1bca32301651339e22904cc8c42f58c30c04aafdb038dda0847dd988d\
+        cda6f3bfd15c4b4c4525004aa06eeff8ca61783aacec57fb3d1f92b0fe2fd1a85f6724517b65e614ad6808d6f6ee34df\
+        f7310fdc82aebfd904b01e1dc54b2927094b2db68d6f903b68401adebf5a7e08d78ff4ef5d63653a65040cf9bfd4aca7\
+        984a74d37145986780fc0b16ac451649de6188a7dbdf191f64b5fc5e2ab47b57f7f7276cd419c17a3ca8e1b939ae49e4\
+        88acba6b965610b5480109c8b17b80e1b7b750dfc7598d5d5011fd2dcc5600a32ef5b52a1ecc820e308aa342721aac09\
+        43bf6686b64b2579376504ccc493d97e6aed3fb0f9cd71a43dd497f01f17c0e2cb3797aa2a2f256656168e6c496afc5f\
+        b93246f6b1116398a346f1a641f3b041e989f7914f90cc2c7fff357876e506b50d334ba77c225bc307ba537152f3f161\
+        0e4eafe595f6d9d90d11faa933a15ef1369546868a7f3a45a96768d40fd9d03412c091c6315cf4fde7cb68606937380d\
+        b2eaaa707b4c4185c32eddcdd306705e4dc1ffc872eeee475a64dfac86aba41c0618983f8741c5ef68d3a101e8a3b8ca\
+        c60c905c15fc910840b94c00a0b9d0";
+}
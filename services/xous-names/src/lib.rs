@@ -4,7 +4,7 @@
 
 pub mod api;
 
-use api::Disconnect;
+use api::{AclEntry, AuthenticatedLookup, Disconnect, MAX_ACL_ENTRIES};
 use core::fmt::Write;
 use num_traits::ToPrimitive;
 use xous_ipc::{Buffer, String};
@@ -56,10 +56,54 @@ impl XousNames {
         &self,
         name: &str,
         max_conns: Option<u32>,
+    ) -> Result<xous::SID, xous::Error> {
+        self.register(name, max_conns, None, None)
+    }
+
+    /// Registers a server that, once its `max_conns` trusted connections are made, will take further
+    /// connections from processes that can sign with the private key of `auth_key` (an Ed25519 public key).
+    /// See `request_authenticated_connection()`. With `max_conns` of `Some(0)`, every connection has to be
+    /// authenticated.
+    pub fn register_name_with_auth(
+        &self,
+        name: &str,
+        max_conns: Option<u32>,
+        auth_key: [u8; 32],
+    ) -> Result<xous::SID, xous::Error> {
+        self.register(name, max_conns, Some(auth_key), None)
+    }
+
+    /// Registers a server that only the processes in `acl` may connect to, up to `max_conns` connections
+    /// in all. Connection attempts from any other process are denied, and logged by the name server.
+    /// At most `MAX_ACL_ENTRIES` entries can be given.
+    pub fn register_name_with_acl(
+        &self,
+        name: &str,
+        max_conns: Option<u32>,
+        acl: &[AclEntry],
+    ) -> Result<xous::SID, xous::Error> {
+        if acl.len() > MAX_ACL_ENTRIES {
+            return Err(xous::Error::OutOfMemory);
+        }
+        let mut entries = [None; MAX_ACL_ENTRIES];
+        for (slot, entry) in entries.iter_mut().zip(acl.iter()) {
+            *slot = Some(*entry);
+        }
+        self.register(name, max_conns, None, Some(entries))
+    }
+
+    fn register(
+        &self,
+        name: &str,
+        max_conns: Option<u32>,
+        auth_key: Option<[u8; 32]>,
+        acl: Option<[Option<AclEntry>; MAX_ACL_ENTRIES]>,
     ) -> Result<xous::SID, xous::Error> {
         let mut registration = api::Registration {
             name: String::<64>::new(),
             conn_limit: max_conns,
+            auth_key,
+            acl,
         };
         // could also do String::from_str() but in this case we want things to fail if the string is too long.
         write!(registration.name, "{}", name).expect("name probably too long");
@@ -93,7 +137,7 @@ impl XousNames {
 
         match buf.to_original().unwrap() {
             api::Return::CID((cid, token)) => Ok((cid, token)),
-            // api::Return::AuthenticateRequest(_) => Err(xous::Error::AccessDenied),
+            api::Return::AuthenticateRequest(_) => Err(xous::Error::AccessDenied),
             _ => Err(xous::Error::ServerNotFound),
         }
     }
@@ -123,7 +167,7 @@ impl XousNames {
 
         match buf.to_original().unwrap() {
             api::Return::CID((cid, _)) => Ok(cid),
-            // api::Return::AuthenticateRequest(_) => Err(xous::Error::AccessDenied),
+            api::Return::AuthenticateRequest(_) => Err(xous::Error::AccessDenied),
             _ => Err(xous::Error::ServerNotFound),
         }
     }
@@ -164,10 +208,48 @@ impl XousNames {
         }
     }
 
-    // todo:
-    // pub fn authenticated_connection(&self, name: &str, key: Authkey)
-    // this function will create an authenticated connection, if such are allowed
-    // it's intended for use by dynamically-loaded third-party apps. As of Xous 0.8 this isn't supported, so it's just a "todo"
+    /// Connects to a server registered with `register_name_with_auth()`, which is intended for use by
+    /// dynamically-loaded third-party apps. If the name server asks for authentication, `sign` is called with
+    /// the ID of the key it wants, and the message to sign; it returns the Ed25519 signature, or `None` if it
+    /// doesn't hold that key. Servers that don't ask for authentication are connected to as usual.
+    pub fn request_authenticated_connection<F>(
+        &self,
+        name: &str,
+        sign: F,
+    ) -> Result<xous::CID, xous::Error>
+    where
+        F: FnOnce(&[u8; 20], &[u8]) -> Option<[u8; 64]>,
+    {
+        let mut lookup_name = xous_ipc::String::<64>::new();
+        write!(lookup_name, "{}", name).expect("name problably too long");
+        let mut buf = Buffer::into_buf(lookup_name).or(Err(xous::Error::InternalError))?;
+        buf.lend_mut(self.conn, api::Opcode::Lookup.to_u32().unwrap())
+            .or(Err(xous::Error::InternalError))?;
+
+        let request = match buf.to_original().unwrap() {
+            api::Return::CID((cid, _)) => return Ok(cid),
+            api::Return::AuthenticateRequest(request) => request,
+            _ => return Err(xous::Error::ServerNotFound),
+        };
+        let message = api::auth_message(name, &request.challenge);
+        let signature = sign(&request.pubkey_id, &message).ok_or(xous::Error::AccessDenied)?;
+        let lookup = AuthenticatedLookup {
+            name: request.name,
+            pubkey_id: request.pubkey_id,
+            signature,
+        };
+        let mut buf = Buffer::into_buf(lookup).or(Err(xous::Error::InternalError))?;
+        buf.lend_mut(
+            self.conn,
+            api::Opcode::AuthenticatedLookup.to_u32().unwrap(),
+        )
+        .or(Err(xous::Error::InternalError))?;
+
+        match buf.to_original().unwrap() {
+            api::Return::CID((cid, _)) => Ok(cid),
+            _ => Err(xous::Error::AccessDenied),
+        }
+    }
 }
 
 use core::sync::atomic::{AtomicU32, Ordering};
@@ -3,6 +3,7 @@
 
 mod api;
 use api::*;
+mod ed25519;
 
 use num_traits::FromPrimitive;
 use xous::{msg_blocking_scalar_unpack, MessageEnvelope};
@@ -26,6 +27,9 @@ enum ConnectError {
 
     /// The message was not a mutable memory message
     InvalidMessageType = 4,
+
+    /// The server's access-control list doesn't include the process
+    AccessDenied = 5,
 }
 
 #[derive(PartialEq)]
@@ -95,6 +99,14 @@ mod implementation {
     }
 }
 
+/// Whether the loader named process `pid` `name`
+#[cfg(not(test))]
+fn process_name_is(pid: xous::PID, name: &str) -> bool {
+    xous::process_name_is(pid, name).unwrap_or(false)
+}
+#[cfg(test)]
+use tests::process_name_is;
+
 /*
 SlowMap is a stand-in implementation for a HashMap from the Heapless crate that has proven to be unsafe,
 and leaking data between entries. It's called "SlowMap" because it's slow: accesses are O(N). That
@@ -109,9 +121,10 @@ struct Connection {
     pub sid: xous::SID,
     pub current_conns: u32, // number of unauthenticated (inherentely trusted) connections
     pub max_conns: Option<u32>, // if None, unlimited connections allowed
-    pub _allow_authenticate: bool,
-    pub _auth_conns: u32,        // number of authenticated connections
+    pub auth_key: Option<[u8; 32]>, // if set, connections past max_conns are allowed to holders of the private key
+    pub auth_conns: u32,            // number of authenticated connections
     pub token: Option<[u32; 4]>, // a random number that must be presented to allow for disconnection for single-connection servers
+    pub acl: Option<[Option<AclEntry>; MAX_ACL_ENTRIES]>, // if set, only these processes may connect
 }
 #[derive(Debug)]
 struct CheckedHashMap {
@@ -128,6 +141,8 @@ impl CheckedHashMap {
         name: XousServerName,
         sid: xous::SID,
         max_conns: Option<u32>,
+        auth_key: Option<[u8; 32]>,
+        acl: Option<[Option<AclEntry>; MAX_ACL_ENTRIES]>,
     ) -> Result<(), xous::Error> {
         let token = if max_conns == Some(1) {
             // for the special case of 1-connection servers, provision a one-time use token for disconnects
@@ -145,9 +160,10 @@ impl CheckedHashMap {
                 sid,
                 current_conns: 0,
                 max_conns,
-                _allow_authenticate: false, // for now, we don't support authenticated connections
-                _auth_conns: 0,
+                auth_key,
+                auth_conns: 0,
                 token,
+                acl,
             },
         );
         Ok(())
@@ -174,7 +190,30 @@ impl CheckedHashMap {
         self.map.contains_key(name)
     }
 
-    pub fn connect(&mut self, name: &XousServerName) -> (Option<xous::SID>, Option<[u32; 4]>) {
+    /// Whether `pid` may connect to `name`. Servers registered without an access-control list take anyone.
+    pub fn allows(&self, name: &XousServerName, pid: xous::PID) -> bool {
+        let acl = match self.map.get(name).and_then(|entry| entry.acl) {
+            Some(acl) => acl,
+            None => return true,
+        };
+        acl.iter().flatten().any(|allowed| match allowed {
+            AclEntry::Pid(allowed_pid) => *allowed_pid == pid.get(),
+            AclEntry::Process(process) => process
+                .as_str()
+                .map(|process| process_name_is(pid, process))
+                .unwrap_or(false),
+        })
+    }
+
+    pub fn connect(
+        &mut self,
+        name: &XousServerName,
+        pid: xous::PID,
+    ) -> (Option<xous::SID>, Option<[u32; 4]>) {
+        if !self.allows(name, pid) {
+            log::warn!("connection to '{}' denied for process {:?}", name, pid);
+            return (None, None);
+        }
         if let Some(entry) = self.map.get_mut(name) {
             match entry.max_conns {
                 // single-connection case
@@ -205,6 +244,34 @@ impl CheckedHashMap {
         }
     }
 
+    /// The key that a process must prove it holds to connect to `name`, once its trusted connections are used up
+    pub fn auth_key(&self, name: &XousServerName) -> Option<[u8; 32]> {
+        self.map.get(name).and_then(|entry| entry.auth_key)
+    }
+
+    /// Makes a connection for a process that has proven it holds the server's key. These don't count against
+    /// the server's connection limit, but the server's access-control list still applies.
+    pub fn connect_authenticated(
+        &mut self,
+        name: &XousServerName,
+        pid: xous::PID,
+    ) -> Option<xous::SID> {
+        if !self.allows(name, pid) {
+            log::warn!(
+                "authenticated connection to '{}' denied for process {:?}",
+                name,
+                pid
+            );
+            return None;
+        }
+        let entry = self.map.get_mut(name)?;
+        if entry.auth_key.is_none() {
+            return None;
+        }
+        entry.auth_conns += 1;
+        Some(entry.sid)
+    }
+
     pub fn trusted_init_done(&self) -> bool {
         let mut trusted_done = true;
         for (name, entry) in self.map.iter() {
@@ -294,9 +361,19 @@ fn blocking_connect(
         sender_pid
     );
 
+    // A process left out of the server's access-control list would otherwise wait forever
+    if !name_table.allows(&name, sender_pid) {
+        log::warn!(
+            "BlockingConnect to '{}' denied for process {:?}",
+            name,
+            sender_pid
+        );
+        return Err(ConnectError::AccessDenied);
+    }
+
     // If the server already exists, attempt to make the connection. The connection can
     // only succeed if the
-    if let (Some(server_sid), token) = name_table.connect(&name) {
+    if let (Some(server_sid), token) = name_table.connect(&name, sender_pid) {
         log::trace!("Found entry in the table (sid: {:?}, token: {:?}) -- attempting to call connect_for_process()", server_sid, token);
         let result = xous::connect_for_process(sender_pid, server_sid);
         if let Ok(xous::Result::ConnectionID(connection_id)) = result {
@@ -326,6 +403,75 @@ fn blocking_connect(
     Ok(ConnectSuccess::Wait)
 }
 
+/// How many authentication challenges are kept at once. Challenges are single-use, and the oldest is
+/// dropped to make room for a new one.
+const MAX_PENDING_CHALLENGES: usize = 32;
+
+/// Fills `words` from the kernel TRNG. The TRNG server can't be used here: its crate depends on this one,
+/// and it looks itself up through us. Fresh server IDs are the kernel's TRNG output, handed out only to
+/// the name server, so they're drawn as entropy without registering anything.
+fn trng_fill(words: &mut [u32]) -> Result<(), xous::Error> {
+    for chunk in words.chunks_mut(4) {
+        let entropy = xous::create_server_id()?.to_array();
+        chunk.copy_from_slice(&entropy[..chunk.len()]);
+    }
+    Ok(())
+}
+
+/// A challenge that's been issued to a process, and not yet answered
+struct PendingChallenge {
+    pid: xous::PID,
+    name: XousServerName,
+    challenge: [u32; 8],
+}
+
+/// Issues a fresh challenge to `pid` for `name`, replacing any it already had. The challenge is drawn
+/// straight from the kernel TRNG; if it can't be reached, no challenge is issued.
+fn issue_challenge(
+    pending: &mut Vec<PendingChallenge>,
+    pid: xous::PID,
+    name: XousServerName,
+) -> Result<[u32; 8], xous::Error> {
+    let mut challenge = [0u32; 8];
+    trng_fill(&mut challenge)?;
+    pending.retain(|p| !(p.pid == pid && p.name == name));
+    if pending.len() >= MAX_PENDING_CHALLENGES {
+        pending.remove(0);
+    }
+    pending.push(PendingChallenge {
+        pid,
+        name,
+        challenge,
+    });
+    Ok(challenge)
+}
+
+/// Checks the answer to a challenge, which can only be tried once. Returns the SID of the server if the
+/// answer is good.
+fn check_challenge(
+    pending: &mut Vec<PendingChallenge>,
+    name_table: &mut CheckedHashMap,
+    pid: xous::PID,
+    lookup: &AuthenticatedLookup,
+) -> Option<xous::SID> {
+    let name = XousServerName::from_str(lookup.name.as_str().ok()?);
+    let index = pending
+        .iter()
+        .position(|p| p.pid == pid && p.name == name)?;
+    let challenge = pending.remove(index).challenge;
+    let key = name_table.auth_key(&name)?;
+    if ed25519::key_id(&key) != lookup.pubkey_id
+        || !ed25519::verify(
+            &key,
+            &auth_message(name.to_str(), &challenge),
+            &lookup.signature,
+        )
+    {
+        return None;
+    }
+    name_table.connect_authenticated(&name, pid)
+}
+
 fn respond_connect_error(mut msg: MessageEnvelope, result: ConnectError) {
     let mem = msg.body.memory_message_mut().unwrap();
     let s = unsafe {
@@ -371,6 +517,7 @@ fn xmain() -> ! {
     // this limits the number of available servers to be requested to 128...!
     //let mut name_table = FnvIndexMap::<XousServerName, xous::SID, 128>::new();
     let mut name_table = CheckedHashMap::new();
+    let mut pending_challenges: Vec<PendingChallenge> = Vec::new();
 
     info!("started");
     loop {
@@ -392,11 +539,21 @@ fn xmain() -> ! {
                 let mut should_connect = false;
 
                 log::trace!("registration request for '{}'", name);
-                if !name_table.contains_key(&name) {
+                let valid_key = registration
+                    .auth_key
+                    .map(|key| ed25519::is_valid_key(&key))
+                    .unwrap_or(true);
+                if !name_table.contains_key(&name) && valid_key {
                     let new_sid =
                         xous::create_server_id().expect("create server failed, maybe OOM?");
                     name_table
-                        .insert(name, new_sid, registration.conn_limit)
+                        .insert(
+                            name,
+                            new_sid,
+                            registration.conn_limit,
+                            registration.auth_key,
+                            registration.acl,
+                        )
                         .expect("register name failure, maybe out of HashMap capacity?");
                     log::trace!("request successful, SID is {:?}", new_sid);
                     should_connect = true;
@@ -473,6 +630,10 @@ fn xmain() -> ! {
                 }
             }
             Some(api::Opcode::Lookup) => {
+                let sender_pid = msg
+                    .sender
+                    .pid()
+                    .expect("can't extract sender PID on Lookup");
                 let mem = msg.body.memory_message_mut().unwrap();
                 let mut buffer = unsafe { Buffer::from_memory_message_mut(mem) };
                 let name_string = buffer.to_original::<String<64>, _>().unwrap();
@@ -483,11 +644,7 @@ fn xmain() -> ! {
                 );
                 log::trace!("Lookup request for '{}'", name);
                 let response: api::Return;
-                if let (Some(server_sid), token) = name_table.connect(&name) {
-                    let sender_pid = msg
-                        .sender
-                        .pid()
-                        .expect("can't extract sender PID on Lookup");
+                if let (Some(server_sid), token) = name_table.connect(&name, sender_pid) {
                     match xous::connect_for_process(sender_pid, server_sid)
                         .expect("can't broker connection")
                     {
@@ -504,36 +661,77 @@ fn xmain() -> ! {
                             response = api::Return::Failure
                         }
                     }
+                } else if !name_table.allows(&name, sender_pid) {
+                    // a process left out of the access-control list gets a flat denial
+                    d11ctimeout.deterministic_busy_wait();
+                    response = api::Return::Failure
+                } else if let Some(key) = name_table.auth_key(&name) {
+                    // the trusted connections are used up, but the server takes authenticated ones
+                    log::trace!("Lookup of '{}' needs authentication", name);
+                    response = match issue_challenge(
+                        &mut pending_challenges,
+                        sender_pid,
+                        name,
+                    ) {
+                        Ok(challenge) => api::Return::AuthenticateRequest(AuthenticateRequest {
+                            name: String::<64>::from_str(name.to_str()),
+                            pubkey_id: ed25519::key_id(&key),
+                            challenge,
+                        }),
+                        Err(e) => {
+                            error!("couldn't draw a challenge from the TRNG: {:?}", e);
+                            api::Return::Failure
+                        }
+                    }
                 } else {
                     log::debug!("Can't find request '{}' in table, dumping table:", name);
                     for (_name, conn) in name_table.map.iter() {
                         log::debug!("{:?}", conn);
                     }
-                    // no authenticate remedy currently supported, but we'd put that code somewhere around here eventually.
-                    let (c1, c2, c3, c4) = xous::create_server_id().unwrap().to_u32();
-                    let auth_request = AuthenticateRequest {
-                        name: String::<64>::from_str(
-                            name_string
-                                .as_str()
-                                .expect("couldn't convert server name to string"),
-                        ),
-                        pubkey_id: [0; 20], // placeholder
-                        challenge: [c1, c2, c3, c4],
-                    };
                     d11ctimeout.hosted_delay();
-                    response = api::Return::AuthenticateRequest(auth_request) // this code just exists to exercise the return path
+                    response = api::Return::Failure
                 }
                 buffer
                     .replace(response)
                     .expect("Lookup can't serialize return value");
             }
             Some(api::Opcode::AuthenticatedLookup) => {
+                let sender_pid = msg
+                    .sender
+                    .pid()
+                    .expect("can't extract sender PID on AuthenticatedLookup");
                 let mem = msg.body.memory_message_mut().unwrap();
-                let buffer = unsafe { Buffer::from_memory_message_mut(mem) };
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(mem) };
                 let auth_lookup: AuthenticatedLookup = buffer.to_original().unwrap();
-                info!("AuthenticatedLookup request {:?}", auth_lookup);
-                error!("AuthenticatedLookup not yet implemented");
-                unimplemented!("AuthenticatedLookup not yet implemented");
+                log::trace!("AuthenticatedLookup request for '{}'", auth_lookup.name);
+                let response = match check_challenge(
+                    &mut pending_challenges,
+                    &mut name_table,
+                    sender_pid,
+                    &auth_lookup,
+                ) {
+                    Some(server_sid) => match xous::connect_for_process(sender_pid, server_sid)
+                        .expect("can't broker connection")
+                    {
+                        xous::Result::ConnectionID(connection_id) => {
+                            info!(
+                                "authenticated connection to '{}' for process {:?}",
+                                auth_lookup.name, sender_pid
+                            );
+                            api::Return::CID((connection_id, None))
+                        }
+                        _ => api::Return::Failure,
+                    },
+                    None => {
+                        // a failed authentication is treated as a flat denial
+                        info!("authentication failed, waiting for deterministic timeout");
+                        d11ctimeout.deterministic_busy_wait();
+                        api::Return::Failure
+                    }
+                };
+                buffer
+                    .replace(response)
+                    .expect("AuthenticatedLookup can't serialize return value");
             }
             Some(api::Opcode::TrustedInitDone) => {
                 if name_table.trusted_init_done() {
@@ -566,3 +764,89 @@ fn xmain() -> ! {
     log::trace!("quitting");
     xous::terminate_process(0);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stands in for the kernel's process names: PIDs 3 and 4 are `root-keys` and `pddb`
+    pub(super) fn process_name_is(pid: xous::PID, name: &str) -> bool {
+        match pid.get() {
+            3 => name == "root-keys",
+            4 => name == "pddb",
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn access_control_lists() {
+        let pid = |pid| xous::PID::new(pid).unwrap();
+        let keys = XousServerName::from_str("keys");
+        let storage = XousServerName::from_str("storage");
+        let mut acl = [None; MAX_ACL_ENTRIES];
+        acl[0] = Some(AclEntry::process("root-keys"));
+        acl[1] = Some(AclEntry::pid(pid(7)));
+
+        let mut name_table = CheckedHashMap::new();
+        name_table
+            .insert(keys, xous::SID::from_u32(1, 2, 3, 4), None, None, None)
+            .unwrap();
+        name_table
+            .insert(
+                storage,
+                xous::SID::from_u32(5, 6, 7, 8),
+                Some(2),
+                None,
+                Some(acl),
+            )
+            .unwrap();
+
+        // servers without a list take anyone
+        assert!(name_table.allows(&keys, pid(9)));
+        assert!(name_table.allows(&storage, pid(3)));
+        assert!(name_table.allows(&storage, pid(7)));
+        assert!(!name_table.allows(&storage, pid(4)));
+        // denials don't use up any of the connections
+        assert_eq!(name_table.connect(&storage, pid(9)), (None, None));
+        assert!(name_table.connect(&storage, pid(3)).0.is_some());
+        assert!(name_table.connect(&storage, pid(7)).0.is_some());
+    }
+
+    #[test]
+    fn access_control_lists_survive_reregistration() {
+        let pid = |pid| xous::PID::new(pid).unwrap();
+        let keys = XousServerName::from_str("keys");
+        let storage = XousServerName::from_str("storage");
+        let mut acl = [None; MAX_ACL_ENTRIES];
+        acl[0] = Some(AclEntry::process("root-keys"));
+
+        let mut name_table = CheckedHashMap::new();
+        name_table
+            .insert(keys, xous::SID::from_u32(1, 2, 3, 4), None, None, None)
+            .unwrap();
+        name_table
+            .insert(
+                storage,
+                xous::SID::from_u32(5, 6, 7, 8),
+                None,
+                None,
+                Some(acl),
+            )
+            .unwrap();
+        assert!(name_table.allows(&storage, pid(3)));
+        assert!(!name_table.allows(&storage, pid(9)));
+
+        // the keys server goes away, and another process registers its name
+        name_table.remove(xous::SID::from_u32(1, 2, 3, 4));
+        name_table
+            .insert(keys, xous::SID::from_u32(9, 9, 9, 9), None, None, None)
+            .unwrap();
+
+        // the list names processes, not servers, so the new owner of the name gets nothing,
+        // and the process that used to have it keeps its access
+        assert!(!name_table.allows(&storage, pid(9)));
+        assert_eq!(name_table.connect(&storage, pid(9)), (None, None));
+        assert!(name_table.allows(&storage, pid(3)));
+        assert!(name_table.connect(&storage, pid(3)).0.is_some());
+    }
+}
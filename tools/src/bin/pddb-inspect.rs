@@ -0,0 +1,659 @@
+//! Offline inspection of PDDB images, as dumped by `PddbOs::dbg_dump()` in hosted mode.
+//!
+//! This reproduces the page table scan of `pt_scan_key()` for every basis that we have a key for,
+//! then walks the basis root, the dictionaries and the key descriptors, decrypting them along the way.
+//! Anything that doesn't decrypt or doesn't add up is reported, so the tool can be used to debug
+//! corrupted images without a device.
+//!
+//! Basis keys come either from the `.key` file written next to the image by `dbg_dump()`, or are
+//! derived from a basis name and password given on the command line. The system basis key is
+//! wrapped by the device root keys, so it is only available through the `.key` file.
+
+use aes::cipher::{generic_array::GenericArray, BlockDecrypt, NewBlockCipher};
+use aes::{Aes256, Block};
+use aes_gcm_siv::aead::{Aead, NewAead, Payload};
+use aes_gcm_siv::{Aes256GcmSiv, Key, Nonce};
+use clap::{crate_version, App, Arg};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// share the exact hash and KDF code with the PDDB itself
+#[allow(dead_code)]
+#[path = "../../../services/pddb/src/backend/murmur3.rs"]
+mod murmur3;
+use murmur3::murmur3_32;
+#[allow(dead_code)]
+#[path = "../../../services/pddb/src/backend/bcrypt.rs"]
+mod bcrypt;
+mod api {
+    pub(crate) const PASSWORD_LEN: usize = 72;
+}
+
+// these mirror the constants in services/pddb/src/api.rs and services/pddb/src/backend
+const PAGE_SIZE: usize = 4096;
+const VPAGE_SIZE: usize = 4064;
+const JOURNAL_LEN: usize = 4;
+const NONCE_LEN: usize = 12;
+const PTE_LEN: usize = 16;
+const MBBB_PAGES: usize = 10;
+const FSCB_PAGES: usize = 16;
+const KCOM_CT_LEN: usize = 4004;
+const AES_KEYSIZE: usize = 32;
+const WRAPPED_AES_KEYSIZE: usize = AES_KEYSIZE + 8;
+const BASIS_NAME_LEN: usize = 64;
+const PDDB_MAGIC: [u8; 4] = [0x50, 0x44, 0x44, 0x42];
+const PDDB_VERSION: u32 = 0x00_00_01_01;
+const BCRYPT_COST: u32 = 7;
+const DICT_VSIZE: u64 = 0xFE_0000;
+const DICT_MAXCOUNT: usize = 16383;
+const DK_STRIDE: usize = 127;
+const DK_PER_VPAGE: usize = VPAGE_SIZE / DK_STRIDE;
+const KEY_MAXCOUNT: usize = 131_071;
+const SMALL_POOL_END: u64 = 0x0000_007E_FF02_0000;
+const SYSTEM_BASIS: &str = ".System";
+
+struct PddbImage {
+    data: Vec<u8>,
+    pt_len: usize,
+    key_base: usize,
+    mbbb_base: usize,
+    data_base: usize,
+    dna: u64,
+}
+
+impl PddbImage {
+    fn new(data: Vec<u8>, dna: u64) -> Self {
+        // same layout as PddbOs::new(): page table, static crypto data, MBBB, FSCB, then data
+        let pt_len = (data.len() / PAGE_SIZE) * PTE_LEN;
+        let key_base = page_align(pt_len);
+        let mbbb_base = key_base + PAGE_SIZE;
+        let fscb_base = mbbb_base + MBBB_PAGES * PAGE_SIZE;
+        PddbImage {
+            data,
+            pt_len,
+            key_base,
+            mbbb_base,
+            data_base: fscb_base + FSCB_PAGES * PAGE_SIZE,
+            dna,
+        }
+    }
+
+    fn aad(&self, basis_name: &str) -> Vec<u8> {
+        let mut aad = Vec::<u8>::new();
+        aad.extend_from_slice(basis_name.as_bytes());
+        aad.extend_from_slice(&PDDB_VERSION.to_le_bytes());
+        aad.extend_from_slice(&self.dna.to_le_bytes());
+        aad
+    }
+
+    fn phys_page(&self, page_number: usize) -> Option<&[u8]> {
+        let start = self.data_base + page_number * PAGE_SIZE;
+        self.data.get(start..start + PAGE_SIZE)
+    }
+
+    fn mbbb_retrieve(&self) -> Option<&[u8]> {
+        let blank = [0xffu8; PTE_LEN];
+        self.data[self.mbbb_base..self.mbbb_base + MBBB_PAGES * PAGE_SIZE]
+            .chunks(PAGE_SIZE)
+            .find(|page| page[..PTE_LEN] != blank)
+    }
+
+    /// returns the page with the journal revision still on top
+    fn decrypt_page(&self, cipher: &Aes256GcmSiv, aad: &[u8], page_number: usize) -> Option<Vec<u8>> {
+        let ct = self.phys_page(page_number)?;
+        cipher
+            .decrypt(
+                Nonce::from_slice(&ct[..NONCE_LEN]),
+                Payload {
+                    aad,
+                    msg: &ct[NONCE_LEN..],
+                },
+            )
+            .ok()
+    }
+
+    /// mirrors `data_decrypt_page_with_commit()`, which is used for the basis root page
+    fn decrypt_page_with_commit(&self, key: &[u8; AES_KEYSIZE], aad: &[u8], page_number: usize) -> Option<Vec<u8>> {
+        let ct = self.phys_page(page_number)?;
+        let nonce = &ct[..NONCE_LEN];
+        let ct_total = &ct[NONCE_LEN..];
+        let kcom_nonce: [u8; 32] = ct_total[KCOM_CT_LEN..KCOM_CT_LEN + 32].try_into().unwrap();
+        let kcom_stored = &ct_total[KCOM_CT_LEN + 32..KCOM_CT_LEN + 64];
+        let mut ct_plus_mac = ct_total[..KCOM_CT_LEN].to_vec();
+        ct_plus_mac.extend_from_slice(&ct_total[KCOM_CT_LEN + 64..]);
+
+        let (kenc, kcom) = kcom_func(key, &kcom_nonce);
+        if kcom[..] != kcom_stored[..] {
+            return None;
+        }
+        let cipher = Aes256GcmSiv::new(Key::from_slice(&kenc));
+        cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    aad,
+                    msg: &ct_plus_mac,
+                },
+            )
+            .ok()
+    }
+
+    /// Reproduces `pt_scan_key()`: returns the virtual to physical page map of a basis.
+    fn pt_scan_key(&self, key: &[u8; AES_KEYSIZE], basis_name: &str) -> HashMap<u64, usize> {
+        let ecb = Aes256::new(GenericArray::from_slice(key));
+        let gcm = Aes256GcmSiv::new(Key::from_slice(key));
+        let aad = self.aad(basis_name);
+        let blank = [0xffu8; PTE_LEN];
+        let mut map = HashMap::<u64, usize>::new();
+        for (page_index, pt_page) in self.data[..self.pt_len].chunks(PAGE_SIZE).enumerate() {
+            let clean_page = if pt_page[..PTE_LEN] == blank {
+                self.mbbb_retrieve().unwrap_or(pt_page)
+            } else {
+                pt_page
+            };
+            for (index, candidate) in clean_page.chunks(PTE_LEN).enumerate() {
+                let mut block = Block::clone_from_slice(candidate);
+                ecb.decrypt_block(&mut block);
+                let nonce = u32::from_le_bytes(block[8..12].try_into().unwrap());
+                if u32::from_le_bytes(block[12..16].try_into().unwrap()) != murmur3_32(&block[..12], nonce) {
+                    continue;
+                }
+                let mut vaddr = [0u8; 8];
+                vaddr[..7].copy_from_slice(&block[..7]);
+                let vaddr = u64::from_le_bytes(vaddr);
+                let page_number = page_index * PAGE_SIZE / PTE_LEN + index;
+                if let Some(&prev) = map.get(&vaddr) {
+                    // journal conflict: the newest copy of the page wins
+                    let journal = |pn| {
+                        self.decrypt_page(&gcm, &aad, pn)
+                            .map(|d| u32::from_le_bytes(d[..JOURNAL_LEN].try_into().unwrap()))
+                    };
+                    match (journal(prev), journal(page_number)) {
+                        (Some(prev_j), Some(new_j)) => {
+                            if new_j > prev_j {
+                                map.insert(vaddr, page_number);
+                            } else if new_j == prev_j {
+                                log::warn!("duplicate blocks with the same journal revision at va {:x}", vaddr);
+                            }
+                        }
+                        (None, Some(_)) => {
+                            map.insert(vaddr, page_number);
+                        }
+                        _ => (),
+                    }
+                } else {
+                    map.insert(vaddr, page_number);
+                }
+            }
+        }
+        map
+    }
+
+    /// Mirrors `basis_derive_key()`. The salt base comes from the static crypto data page of the image.
+    fn basis_derive_key(&self, basis_name: &str, password: &str) -> [u8; AES_KEYSIZE] {
+        use digest::Digest;
+        use sha2::{FallbackStrategy, Sha512Trunc256};
+
+        let salt_base = &self.data[self.key_base + 4 + WRAPPED_AES_KEYSIZE..self.key_base + PAGE_SIZE];
+        let mut bname_copy = [0u8; BASIS_NAME_LEN];
+        for (src, dst) in basis_name.bytes().zip(bname_copy.iter_mut()) {
+            *dst = src;
+        }
+        let mut plaintext_pw = [0u8; api::PASSWORD_LEN + 1];
+        for (src, dst) in password.bytes().zip(plaintext_pw[..api::PASSWORD_LEN].iter_mut()) {
+            *dst = src;
+        }
+        let mut hasher = Sha512Trunc256::new_with_strategy(FallbackStrategy::SoftwareOnly);
+        hasher.update(salt_base);
+        hasher.update(&bname_copy);
+        hasher.update(&plaintext_pw);
+        let salt = hasher.finalize();
+
+        let mut hashed_password = [0u8; 24];
+        bcrypt::bcrypt(BCRYPT_COST, &salt[..16], password, &mut hashed_password);
+        let mut expander = Sha512Trunc256::new_with_strategy(FallbackStrategy::SoftwareOnly);
+        expander.update(hashed_password);
+        expander.finalize().into()
+    }
+}
+
+fn kcom_func(key: &[u8; AES_KEYSIZE], nonce_com: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    use digest::Digest;
+    use sha2::{FallbackStrategy, Sha512Trunc256};
+
+    let mut h_enc = Sha512Trunc256::new_with_strategy(FallbackStrategy::SoftwareOnly);
+    h_enc.update(key);
+    h_enc.update([0x43, 0x6f, 0x6, 0xd6, 0xd, 0x69, 0x74, 0x01, 0x01]);
+    h_enc.update(nonce_com);
+    let mut h_com = Sha512Trunc256::new_with_strategy(FallbackStrategy::SoftwareOnly);
+    h_com.update(key);
+    h_com.update([0x43, 0x6f, 0x6, 0xd6, 0xd, 0x69, 0x74, 0x01, 0x02]);
+    h_com.update(nonce_com);
+    (h_enc.finalize().into(), h_com.finalize().into())
+}
+
+fn page_align(addr: usize) -> usize {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// names are stored as a length byte followed by the name itself
+fn name_at(data: &[u8], offset: usize, max_len: usize) -> String {
+    let len = (data[offset] as usize).min(max_len - 1);
+    String::from_utf8_lossy(&data[offset + 1..offset + 1 + len]).to_string()
+}
+
+struct KeyRecord {
+    descriptor_index: usize,
+    start: u64,
+    len: u64,
+    reserved: u64,
+    flags: u32,
+    age: u32,
+    data: Option<Vec<u8>>,
+}
+
+struct DictRecord {
+    index: usize,
+    flags: u32,
+    age: u32,
+    num_keys: u32,
+    free_key_index: u32,
+    keys: BTreeMap<String, KeyRecord>,
+}
+
+struct BasisRecord {
+    name: String,
+    age: u32,
+    num_dicts: u32,
+    pages: usize,
+    dicts: BTreeMap<String, DictRecord>,
+}
+
+/// Walks a basis the same way `BasisCacheEntry::mount()` and `DictCacheEntry::fill()` do, recording
+/// anything that is inconsistent in `problems`.
+fn read_basis(img: &PddbImage, name: &str, key: &[u8; AES_KEYSIZE], problems: &mut Vec<String>) -> Option<BasisRecord> {
+    let v2p = img.pt_scan_key(key, name);
+    if v2p.is_empty() {
+        problems.push(format!("{}: no page table entries found; wrong key?", name));
+        return None;
+    }
+    let aad = img.aad(name);
+    let root_pp = match v2p.get(&(VPAGE_SIZE as u64)) {
+        Some(&pp) => pp,
+        None => {
+            problems.push(format!("{}: basis root page is not mapped", name));
+            return None;
+        }
+    };
+    let root = match img.decrypt_page_with_commit(key, &aad, root_pp) {
+        Some(root) => root,
+        None => {
+            problems.push(format!("{}: basis root @ pp {:x} did not decrypt", name, root_pp));
+            return None;
+        }
+    };
+    // journal, then BasisRoot: magic, version, age, num_dictionaries, name
+    let r = &root[JOURNAL_LEN..];
+    if r[..4] != PDDB_MAGIC {
+        problems.push(format!("{}: basis root has bad magic {:x?}", name, &r[..4]));
+    }
+    if u32_at(r, 4) != PDDB_VERSION {
+        problems.push(format!("{}: basis root has version {:x}, expected {:x}", name, u32_at(r, 4), PDDB_VERSION));
+    }
+    let mut basis = BasisRecord {
+        name: name_at(r, 16, BASIS_NAME_LEN),
+        age: u32_at(r, 8),
+        num_dicts: u32_at(r, 12),
+        pages: v2p.len(),
+        dicts: BTreeMap::new(),
+    };
+    if basis.name != name {
+        problems.push(format!("{}: basis root records the name '{}'", name, basis.name));
+    }
+
+    let cipher = Aes256GcmSiv::new(Key::from_slice(key));
+    for index in 1..=DICT_MAXCOUNT {
+        if basis.dicts.len() >= basis.num_dicts as usize {
+            break;
+        }
+        let dict_vaddr = index as u64 * DICT_VSIZE;
+        let pp = match v2p.get(&dict_vaddr) {
+            Some(&pp) => pp,
+            None => continue,
+        };
+        let page = match img.decrypt_page(&cipher, &aad, pp) {
+            Some(page) => page,
+            None => {
+                problems.push(format!("{}: dictionary #{} @ pp {:x} did not decrypt", name, index, pp));
+                continue;
+            }
+        };
+        // journal, then Dictionary: flags, age, num_keys, free_key_index, name
+        let d = &page[JOURNAL_LEN..];
+        let dict_name = name_at(d, 16, DK_STRIDE - 16);
+        let mut dict = DictRecord {
+            index,
+            flags: u32_at(d, 0),
+            age: u32_at(d, 4),
+            num_keys: u32_at(d, 8),
+            free_key_index: u32_at(d, 12),
+            keys: BTreeMap::new(),
+        };
+        if dict.flags & 1 == 0 {
+            // not a valid dictionary, e.g. one that was deleted but whose page is still mapped
+            continue;
+        }
+        read_keys(img, &cipher, &aad, &v2p, name, &dict_name, &mut dict, problems);
+        basis.dicts.insert(dict_name, dict);
+    }
+    if basis.dicts.len() != basis.num_dicts as usize {
+        problems.push(format!(
+            "{}: basis root records {} dictionaries, but {} were found",
+            name,
+            basis.num_dicts,
+            basis.dicts.len()
+        ));
+    }
+    Some(basis)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn read_keys(
+    img: &PddbImage,
+    cipher: &Aes256GcmSiv,
+    aad: &[u8],
+    v2p: &HashMap<u64, usize>,
+    basis_name: &str,
+    dict_name: &str,
+    dict: &mut DictRecord,
+    problems: &mut Vec<String>,
+) {
+    let mut index_page: Option<(u64, Vec<u8>)> = None;
+    let mut try_entry = 1;
+    while try_entry < KEY_MAXCOUNT && dict.keys.len() < dict.num_keys as usize {
+        let vaddr = dict.index as u64 * DICT_VSIZE + (try_entry / DK_PER_VPAGE) as u64 * VPAGE_SIZE as u64;
+        if index_page.as_ref().map(|(va, _)| *va) != Some(vaddr) {
+            let page = v2p.get(&vaddr).and_then(|&pp| img.decrypt_page(cipher, aad, pp));
+            match page {
+                Some(page) => index_page = Some((vaddr, page)),
+                None => {
+                    // unallocated (or undecryptable) descriptor page: skip the whole page
+                    if v2p.contains_key(&vaddr) {
+                        problems.push(format!("{}:{}: key descriptor page @ va {:x} did not decrypt", basis_name, dict_name, vaddr));
+                    }
+                    index_page = None;
+                    try_entry = (try_entry / DK_PER_VPAGE + 1) * DK_PER_VPAGE;
+                    continue;
+                }
+            }
+        }
+        let page = &index_page.as_ref().unwrap().1;
+        // journal, then KeyDescriptor: start, len, reserved, flags, age, name
+        let offset = JOURNAL_LEN + (try_entry % DK_PER_VPAGE) * DK_STRIDE;
+        let k = &page[offset..offset + DK_STRIDE];
+        let flags = u32_at(k, 24);
+        if flags & 1 != 0 {
+            let key_name = name_at(k, 32, DK_STRIDE - 32);
+            let mut key = KeyRecord {
+                descriptor_index: try_entry,
+                start: u64_at(k, 0),
+                len: u64_at(k, 8),
+                reserved: u64_at(k, 16),
+                flags,
+                age: u32_at(k, 28),
+                data: None,
+            };
+            if key.len > key.reserved {
+                problems.push(format!("{}:{}:{}: length {} exceeds reservation {}", basis_name, dict_name, key_name, key.len, key.reserved));
+            }
+            key.data = read_key_data(img, cipher, aad, v2p, &key, basis_name, dict_name, &key_name, problems);
+            dict.keys.insert(key_name, key);
+        }
+        try_entry += 1;
+    }
+    if dict.keys.len() != dict.num_keys as usize {
+        problems.push(format!(
+            "{}:{}: dictionary records {} keys, but {} were found",
+            basis_name,
+            dict_name,
+            dict.num_keys,
+            dict.keys.len()
+        ));
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn read_key_data(
+    img: &PddbImage,
+    cipher: &Aes256GcmSiv,
+    aad: &[u8],
+    v2p: &HashMap<u64, usize>,
+    key: &KeyRecord,
+    basis_name: &str,
+    dict_name: &str,
+    key_name: &str,
+    problems: &mut Vec<String>,
+) -> Option<Vec<u8>> {
+    let mut data = Vec::<u8>::with_capacity(key.len as usize);
+    let mut addr = key.start;
+    while addr < key.start + key.len {
+        let vpage = (addr / VPAGE_SIZE as u64) * VPAGE_SIZE as u64;
+        let page_offset = (addr % VPAGE_SIZE as u64) as usize;
+        let page = match v2p.get(&vpage) {
+            Some(&pp) => img.decrypt_page(cipher, aad, pp),
+            None => {
+                problems.push(format!("{}:{}:{}: data page @ va {:x} is not mapped", basis_name, dict_name, key_name, vpage));
+                return None;
+            }
+        };
+        let page = match page {
+            Some(page) => page,
+            None => {
+                problems.push(format!("{}:{}:{}: data page @ va {:x} did not decrypt", basis_name, dict_name, key_name, vpage));
+                return None;
+            }
+        };
+        let remaining = (key.start + key.len - addr) as usize;
+        let available = VPAGE_SIZE - page_offset;
+        let chunk = remaining.min(available);
+        data.extend_from_slice(&page[JOURNAL_LEN + page_offset..JOURNAL_LEN + page_offset + chunk]);
+        addr += chunk as u64;
+    }
+    Some(data)
+}
+
+fn read_key_file(path: &Path) -> Result<Vec<(String, [u8; AES_KEYSIZE])>, Box<dyn std::error::Error>> {
+    let raw = std::fs::read(path)?;
+    if raw.len() < 4 {
+        return Err(format!("{} is too short to be a key file", path.display()).into());
+    }
+    let count = u32_at(&raw, 0) as usize;
+    const RECORD_LEN: usize = BASIS_NAME_LEN + AES_KEYSIZE;
+    if raw.len() < 4 + count * RECORD_LEN {
+        return Err(format!("{} is truncated: expected {} keys", path.display(), count).into());
+    }
+    let mut keys = Vec::new();
+    for record in raw[4..4 + count * RECORD_LEN].chunks(RECORD_LEN) {
+        let name_end = record[..BASIS_NAME_LEN].iter().position(|&b| b == 0).unwrap_or(BASIS_NAME_LEN);
+        let name = String::from_utf8_lossy(&record[..name_end]).to_string();
+        keys.push((name, record[BASIS_NAME_LEN..].try_into().unwrap()));
+    }
+    Ok(keys)
+}
+
+fn print_preview(data: &[u8], indent: &str) {
+    const PREVIEW_LEN: usize = 32;
+    let preview = &data[..data.len().min(PREVIEW_LEN)];
+    let extra = if data.len() > PREVIEW_LEN { "..." } else { "" };
+    let hex: Vec<String> = preview.iter().map(|b| format!("{:02x}", b)).collect();
+    let txt: String = preview.iter().map(|&b| if (0x20..0x7f).contains(&b) { b as char } else { '.' }).collect();
+    println!("{}hex: {}{}", indent, hex.join(""), extra);
+    println!("{}txt: {}{}", indent, txt, extra);
+}
+
+fn print_basis(basis: &BasisRecord, verbose: bool) {
+    println!("Basis '{}': age {}, {} dictionaries, {} pages mapped", basis.name, basis.age, basis.num_dicts, basis.pages);
+    for (dict_name, dict) in basis.dicts.iter() {
+        println!(
+            "  Dict '{}': index {}, age {}, flags {:x}, {} keys, free key index {}",
+            dict_name, dict.index, dict.age, dict.flags, dict.num_keys, dict.free_key_index
+        );
+        for (key_name, key) in dict.keys.iter() {
+            println!(
+                "    Key '{}': {}/{} bytes ({} pool @ {:x}), descriptor {}, age {}, flags {:x}{}",
+                key_name,
+                key.len,
+                key.reserved,
+                if key.start < SMALL_POOL_END { "small" } else { "large" },
+                key.start,
+                key.descriptor_index,
+                key.age,
+                key.flags,
+                if key.data.is_none() { ", DATA UNREADABLE" } else { "" }
+            );
+            if verbose {
+                if let Some(data) = &key.data {
+                    print_preview(data, "      ");
+                }
+            }
+        }
+    }
+}
+
+/// file names are derived from PDDB names, which may contain path separators
+fn sanitize(name: &str) -> String {
+    name.chars().map(|c| if c == '/' || c == '\\' || c == '\0' { '_' } else { c }).collect()
+}
+
+fn extract_basis(basis: &BasisRecord, dir: &Path) -> std::io::Result<usize> {
+    let mut count = 0;
+    for (dict_name, dict) in basis.dicts.iter() {
+        let dict_dir: PathBuf = [dir, Path::new(&sanitize(&basis.name)), Path::new(&sanitize(dict_name))].iter().collect();
+        std::fs::create_dir_all(&dict_dir)?;
+        for (key_name, key) in dict.keys.iter() {
+            if let Some(data) = &key.data {
+                let mut f = std::fs::File::create(dict_dir.join(sanitize(key_name)))?;
+                f.write_all(data)?;
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+    let matches = App::new("pddb-inspect")
+        .version(crate_version!())
+        .about("Inspect and extract the contents of PDDB images dumped by the hosted-mode PDDB")
+        .arg(
+            Arg::with_name("image")
+                .long("image")
+                .help("PDDB image to inspect")
+                .value_name("image")
+                .takes_value(true)
+                .default_value("tools/pddb-images/pddb.bin"),
+        )
+        .arg(
+            Arg::with_name("keys")
+                .long("keys")
+                .help("basis key file written by dbg_dump [default: the image path, with a .key extension]")
+                .value_name("key file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("basis")
+                .long("basis")
+                .help("a basis to unlock with a password, as name:password. May be repeated.")
+                .value_name("name:password")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("dna")
+                .long("dna")
+                .help("the device DNA the image was created with")
+                .value_name("dna")
+                .takes_value(true)
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("extract")
+                .long("extract")
+                .help("write every readable key to <dir>/<basis>/<dict>/<key>")
+                .value_name("dir")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .long("verbose")
+                .short("v")
+                .help("show a preview of every key's data"),
+        )
+        .get_matches();
+
+    let image_path = Path::new(matches.value_of("image").unwrap());
+    let dna = {
+        let dna = matches.value_of("dna").unwrap();
+        if let Some(hex) = dna.strip_prefix("0x") {
+            u64::from_str_radix(hex, 16)?
+        } else {
+            dna.parse::<u64>()?
+        }
+    };
+    let img = PddbImage::new(std::fs::read(image_path)?, dna);
+    println!("Image {}: {} bytes, data region @ {:x}", image_path.display(), img.data.len(), img.data_base);
+
+    let mut keys = Vec::<(String, [u8; AES_KEYSIZE])>::new();
+    let key_path = match matches.value_of("keys") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => Some(image_path.with_extension("key")).filter(|p| p.exists()),
+    };
+    if let Some(path) = key_path {
+        keys.extend(read_key_file(&path)?);
+    }
+    if let Some(bases) = matches.values_of("basis") {
+        for spec in bases {
+            let (name, password) = spec.split_once(':').ok_or("--basis must be given as name:password")?;
+            keys.push((name.to_string(), img.basis_derive_key(name, password)));
+        }
+    }
+    if keys.is_empty() {
+        return Err("no basis keys: supply a key file with --keys, or passwords with --basis".into());
+    }
+    // the system basis first, then in the order given
+    keys.sort_by_key(|(name, _)| name != SYSTEM_BASIS);
+
+    let mut problems = Vec::<String>::new();
+    for (name, key) in keys.iter() {
+        if let Some(basis) = read_basis(&img, name, key, &mut problems) {
+            print_basis(&basis, matches.is_present("verbose"));
+            if let Some(dir) = matches.value_of("extract") {
+                let count = extract_basis(&basis, Path::new(dir))?;
+                println!("Extracted {} keys from '{}' to {}", count, basis.name, dir);
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        println!("No problems found.");
+        Ok(())
+    } else {
+        println!("{} problems found:", problems.len());
+        for problem in problems.iter() {
+            println!("  {}", problem);
+        }
+        std::process::exit(1);
+    }
+}
@@ -802,6 +802,10 @@ pub enum Result {
         Option<MemorySize>, /* valid */
     ),
 
+    /// Part of the name of a process: the length of the whole name in bytes, then
+    /// the bytes of the name from the requested offset, packed little-endian
+    ProcessName(usize, usize, usize, usize, usize, usize, usize),
+
     UnknownResult(usize, usize, usize, usize, usize, usize, usize),
 }
 
@@ -858,6 +862,9 @@ impl Result {
                 0,
                 0,
             ],
+            Result::ProcessName(len, a1, a2, a3, a4, a5, a6) => {
+                [19, *len, *a1, *a2, *a3, *a4, *a5, *a6]
+            }
             Result::UnknownResult(arg1, arg2, arg3, arg4, arg5, arg6, arg7) => {
                 [usize::MAX, *arg1, *arg2, *arg3, *arg4, *arg5, *arg6, *arg7]
             }
@@ -934,6 +941,7 @@ impl Result {
             16 => Result::RetryCall,
             17 => Result::None,
             18 => Result::MemoryReturned(MemorySize::new(src[1]), MemorySize::new(src[2])),
+            19 => Result::ProcessName(src[1], src[2], src[3], src[4], src[5], src[6], src[7]),
             _ => Result::UnknownResult(src[0], src[1], src[2], src[3], src[4], src[5], src[6]),
         }
     }
@@ -425,6 +425,11 @@ pub enum SysCall {
         usize, /* stack pointer */
     ),
 
+    /// Fetch part of the name the loader gave to a process, starting at the
+    /// given byte offset. Names can be longer than the registers a result
+    /// carries, so they are read out in pieces.
+    GetProcessName(PID, usize /* offset */),
+
     /// This syscall does not exist. It captures all possible
     /// arguments so detailed analysis can be performed.
     Invalid(usize, usize, usize, usize, usize, usize, usize),
@@ -468,6 +473,7 @@ pub enum SysCallNumber {
     Disconnect = 35,
     JoinThread = 36,
     SetExceptionHandler = 37,
+    GetProcessName = 38,
     Invalid,
 }
 
@@ -511,6 +517,7 @@ impl SysCallNumber {
             35 => Disconnect,
             36 => JoinThread,
             37 => SetExceptionHandler,
+            38 => GetProcessName,
             _ => Invalid,
         }
     }
@@ -852,6 +859,16 @@ impl SysCall {
                 0,
                 0,
             ],
+            SysCall::GetProcessName(pid, offset) => [
+                SysCallNumber::GetProcessName as usize,
+                pid.get() as usize,
+                *offset,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
             SysCall::Invalid(a1, a2, a3, a4, a5, a6, a7) => [
                 SysCallNumber::Invalid as usize,
                 *a1,
@@ -1015,6 +1032,9 @@ impl SysCall {
             SysCallNumber::Disconnect => SysCall::Disconnect(a1 as _),
             SysCallNumber::JoinThread => SysCall::JoinThread(a1 as _),
             SysCallNumber::SetExceptionHandler => SysCall::SetExceptionHandler(a1 as _, a2 as _),
+            SysCallNumber::GetProcessName => {
+                SysCall::GetProcessName(PID::new(a1 as _).ok_or(Error::InvalidSyscall)?, a2)
+            }
             SysCallNumber::Invalid => SysCall::Invalid(a1, a2, a3, a4, a5, a6, a7),
         })
     }
@@ -1740,6 +1760,41 @@ pub fn join_thread(tid: TID) -> core::result::Result<usize, Error> {
         }
     })
 }
+/// Check whether the loader gave the process `pid` the name `name`. Process
+/// names are fixed when the system image is built, so unlike server names
+/// they can't be claimed by whichever process happens to register first.
+///
+/// # Errors
+///
+/// * **ProcessNotFound**: The process does not exist, or was given no name.
+pub fn process_name_is(pid: PID, name: &str) -> core::result::Result<bool, Error> {
+    let name = name.as_bytes();
+    let mut offset = 0;
+    loop {
+        let (len, words) = match rsyscall(SysCall::GetProcessName(pid, offset))? {
+            Result::ProcessName(len, a1, a2, a3, a4, a5, a6) => (len, [a1, a2, a3, a4, a5, a6]),
+            _ => return Err(Error::InternalError),
+        };
+        if len != name.len() {
+            return Ok(false);
+        }
+        for word in words.iter() {
+            for byte in word.to_le_bytes().iter() {
+                if offset >= len {
+                    return Ok(true);
+                }
+                if *byte != name[offset] {
+                    return Ok(false);
+                }
+                offset += 1;
+            }
+        }
+        if offset >= len {
+            return Ok(true);
+        }
+    }
+}
+
 /* https://github.com/betrusted-io/xous-core/issues/90
 static EXCEPTION_HANDLER: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
 fn handle_exception(exception_type: usize, arg1: usize, arg2: usize) -> isize {
@@ -64,6 +64,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         "jtag",
         "net",
         "dns",
+        "sntp",
         "pddb",
         "modals",
     ];
@@ -316,6 +317,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             ),
             false,
         )?},
+        Some("net-sim") => {
+            generate_app_menus(&Vec::<String>::new());
+            run(false, &hw_pkgs, Some(&["--features", "net/hosted-sim"]), false)?
+        },
         Some("run") => {
             let mut args = env::args();
             args.nth(1);
@@ -520,6 +525,7 @@ Various debug configurations:
  pddb-dev                PDDB testing only for live hardware
  pddb-hosted             PDDB testing in a hosted environment
  pddb-ci                 PDDB config for CI testing (eg: TRNG->deterministic for reproducible errors)
+ net-sim                 hosted mode, with network clients on the Net server's simulated network instead of the host's
  ffi-test                builds an image for testing C-FFI bindings and integration
  tts                     builds an image with text to speech support via externally linked C executable
 "